@@ -0,0 +1,135 @@
+/* diosix write-protected ranges for dirty page tracking
+ *
+ * At each checkpoint of a capsule's dirty log, its RAM is divided into
+ * the clean ranges that are write-protected to catch the first write to
+ * each page. The capsule's code isn't always writeable: with W^X, code
+ * that isn't also marked writeable is read-only, can never be dirtied,
+ * and so is left out of the clean ranges. Code that is also writeable
+ * must still be tracked, so it gets clean ranges of its own that are
+ * marked executable, and the hypervisor protects them as read-and-execute
+ * rather than read-only. Otherwise the capsule couldn't run that code
+ * while its changes are being tracked.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+
+/* a part of a capsule's RAM holding code */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CodeSegment
+{
+    pub base: usize,
+    pub size: usize,
+    pub writeable: bool     /* true if the code can also be written to */
+}
+
+/* a write-protected part of a capsule's RAM not yet written to */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CleanRange
+{
+    pub base: usize,
+    pub size: usize,
+    pub executable: bool    /* true if it holds code, and so must stay executable */
+}
+
+/* divide a capsule's RAM into clean ranges, split at the edges of its code
+   => base, size = the capsule's RAM
+      code = the parts of the RAM holding code, in any order. any part outside the RAM is ignored
+   <= the clean ranges, in address order */
+pub fn clean_ranges(base: usize, size: usize, code: &[CodeSegment]) -> Vec<CleanRange>
+{
+    let mut code: Vec<CodeSegment> = code.to_vec();
+    code.sort_unstable_by_key(|segment| segment.base);
+
+    let mut clean = Vec::new();
+    let end = base + size;
+    let mut start = base;
+    for segment in code
+    {
+        /* clip the segment to what's left of the RAM */
+        let segment_start = core::cmp::max(segment.base, start);
+        let segment_end = core::cmp::min(segment.base.saturating_add(segment.size), end);
+        if segment_start >= segment_end
+        {
+            continue;
+        }
+
+        if segment_start > start
+        {
+            clean.push(CleanRange { base: start, size: segment_start - start, executable: false });
+        }
+
+        /* read-only code can't be dirtied, so it doesn't need protecting */
+        if segment.writeable == true
+        {
+            clean.push(CleanRange { base: segment_start, size: segment_end - segment_start, executable: true });
+        }
+        start = segment_end;
+    }
+
+    if end > start
+    {
+        clean.push(CleanRange { base: start, size: end - start, executable: false });
+    }
+    clean
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn code(base: usize, size: usize, writeable: bool) -> CodeSegment
+    {
+        CodeSegment { base, size, writeable }
+    }
+
+    fn range(base: usize, size: usize, executable: bool) -> CleanRange
+    {
+        CleanRange { base, size, executable }
+    }
+
+    #[test]
+    fn protects_all_of_ram_without_code()
+    {
+        assert_eq!(clean_ranges(0x8000_0000, 0x10_0000, &[]), [range(0x8000_0000, 0x10_0000, false)]);
+    }
+
+    #[test]
+    fn leaves_out_read_only_code()
+    {
+        let clean = clean_ranges(0x8000_0000, 0x10_0000, &[code(0x8000_2000, 0x3000, false)]);
+        assert_eq!(clean, [range(0x8000_0000, 0x2000, false), range(0x8000_5000, 0xf_b000, false)]);
+    }
+
+    #[test]
+    fn keeps_writeable_code_executable()
+    {
+        let clean = clean_ranges(0x8000_0000, 0x10_0000, &[code(0x8000_0000, 0x4000, true)]);
+        assert_eq!(clean, [range(0x8000_0000, 0x4000, true), range(0x8000_4000, 0xf_c000, false)]);
+    }
+
+    #[test]
+    fn never_makes_code_non_executable()
+    {
+        /* W^X code and writeable code together, out of order, overlapping, and running off the end of the RAM */
+        let segments = [code(0x8000_8000, 0x2000, true), code(0x8000_1000, 0x2000, false),
+                        code(0x8000_2000, 0x2000, true), code(0x800f_f000, 0x4000, true)];
+        let clean = clean_ranges(0x8000_0000, 0x10_0000, &segments);
+
+        for segment in segments.iter()
+        {
+            for r in clean.iter().filter(|r| r.base < segment.base + segment.size && segment.base < r.base + r.size)
+            {
+                assert!(r.executable, "{:x?} overlaps code {:x?} without being executable", r, segment);
+            }
+        }
+
+        assert_eq!(clean, [range(0x8000_0000, 0x1000, false), range(0x8000_3000, 0x1000, true),
+                           range(0x8000_4000, 0x4000, false), range(0x8000_8000, 0x2000, true),
+                           range(0x8000_a000, 0xf_5000, false), range(0x800f_f000, 0x1000, true)]);
+    }
+}
@@ -0,0 +1,1044 @@
+/* diosix flattened device tree reader
+ *
+ * The platform code parses the host's device tree into the devices it
+ * drives itself. The hypervisor also needs to look up things the
+ * platform doesn't describe, such as spare serial ports to pass through
+ * to capsules and the ISA extensions the CPU cores implement. This reads
+ * those straight out of the flattened device tree blob, without copying
+ * it or allocating memory.
+ *
+ * When the platform code can't make sense of the whole of a vendor's
+ * tree, the hypervisor can take out the nodes it chokes on and try
+ * again, or, if the tree is beyond saving, describe a minimal machine
+ * of its own. Those copies and new blobs are made here too.
+ *
+ * Vendor device trees aren't always well formed, so nothing here
+ * trusts the blob: every offset and length is checked before use, and
+ * a walk of the tree stops at the first token that doesn't make sense
+ * rather than panic. A node's reg property is only decoded once its
+ * parent's #address-cells and #size-cells are known to be sane.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use super::Error;
+
+/* the blob's header fields, as byte offsets, and its magic number */
+const HEADER_MAGIC: usize = 0;
+const HEADER_TOTAL_SIZE: usize = 4;
+const HEADER_STRUCT_OFFSET: usize = 8;
+const HEADER_STRINGS_OFFSET: usize = 12;
+const HEADER_VERSION: usize = 20;
+const HEADER_STRINGS_SIZE: usize = 32;
+const HEADER_STRUCT_SIZE: usize = 36;
+const HEADER_SIZE: usize = 40;
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/* the earliest version of the format that records the size of the structure block */
+const FDT_VERSION_MIN: u32 = 17;
+
+/* structure block tokens */
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/* deepest nesting of nodes that will be walked */
+pub const FDT_DEPTH_MAX: usize = 16;
+
+/* a node's children are assumed to have these many address and size cells if it doesn't say */
+const ADDRESS_CELLS_DEFAULT: u32 = 2;
+const SIZE_CELLS_DEFAULT: u32 = 1;
+
+/* most cells a reg address or size can have and still fit in 64 bits */
+const CELLS_MAX: u32 = 2;
+
+/* read a big-endian 32-bit word from the given byte offset, if it's in bounds */
+fn be32(bytes: &[u8], offset: usize) -> Option<u32>
+{
+    let end = offset.checked_add(4)?;
+    let word = bytes.get(offset..end)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/* round a byte offset up to the next 32-bit word */
+fn align4(offset: usize) -> Option<usize>
+{
+    Some(offset.checked_add(3)? & !3)
+}
+
+/* <= the zero-terminated string at the given offset, and the offset of the byte after its terminator */
+fn cstring(bytes: &[u8], offset: usize) -> Option<(&str, usize)>
+{
+    let rest = bytes.get(offset..)?;
+    let len = rest.iter().position(|b| *b == 0)?;
+    match core::str::from_utf8(&rest[..len])
+    {
+        Ok(s) => Some((s, offset + len + 1)),
+        Err(_) => None
+    }
+}
+
+/* a device tree blob, checked enough to walk */
+#[derive(Clone, Copy)]
+pub struct Fdt<'a>
+{
+    structs: &'a [u8],
+    strings: &'a [u8]
+}
+
+impl<'a> Fdt<'a>
+{
+    /* check the header of a device tree blob
+       => blob = bytes of the blob, which may run on past its end
+       <= the blob's tree, or an error code */
+    pub fn new(blob: &'a [u8]) -> Result<Fdt<'a>, Error>
+    {
+        let header = |offset| be32(blob, offset).ok_or(Error::DeviceTreeBadHeader);
+
+        if blob.len() < HEADER_SIZE || header(HEADER_MAGIC)? != FDT_MAGIC || header(HEADER_VERSION)? < FDT_VERSION_MIN
+        {
+            return Err(Error::DeviceTreeBadHeader);
+        }
+
+        let total = header(HEADER_TOTAL_SIZE)? as usize;
+        let blob = blob.get(..total).ok_or(Error::DeviceTreeBadHeader)?;
+
+        let block = |offset: usize, size: usize| match offset.checked_add(size)
+        {
+            Some(end) => blob.get(offset..end).ok_or(Error::DeviceTreeBadHeader),
+            None => Err(Error::DeviceTreeBadHeader)
+        };
+
+        Ok(Fdt
+        {
+            structs: block(header(HEADER_STRUCT_OFFSET)? as usize, header(HEADER_STRUCT_SIZE)? as usize)?,
+            strings: block(header(HEADER_STRINGS_OFFSET)? as usize, header(HEADER_STRINGS_SIZE)? as usize)?
+        })
+    }
+
+    /* <= every node in the tree, in the order they appear in the blob, starting with the root.
+          the walk stops early at anything malformed: use check() to find out if it did */
+    pub fn nodes(&self) -> Nodes<'a>
+    {
+        Nodes
+        {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            cells: [(ADDRESS_CELLS_DEFAULT, SIZE_CELLS_DEFAULT); FDT_DEPTH_MAX + 1],
+            status: Walk::Running
+        }
+    }
+
+    /* walk the whole tree to make sure it's well formed
+       <= Ok if it is, or the error that stopped the walk */
+    pub fn check(&self) -> Result<(), Error>
+    {
+        let mut nodes = self.nodes();
+        while nodes.next().is_some() {}
+        match nodes.status
+        {
+            Walk::Error(e) => Err(e),
+            _ => Ok(())
+        }
+    }
+
+    /* find a node by its full path, eg: /soc/serial@10000000. a path component
+       without a unit address matches a node of that name with any unit address
+       <= the first matching node, or None if there isn't one */
+    pub fn find(&self, path: &str) -> Option<Node<'a>>
+    {
+        let component = |index| path.split('/').filter(|c| c.len() > 0).nth(index);
+        let wanted = path.split('/').filter(|c| c.len() > 0).count();
+        let mut matched = 0;
+
+        for node in self.nodes()
+        {
+            if node.depth == 0
+            {
+                match wanted
+                {
+                    0 => return Some(node),
+                    _ => continue
+                }
+            }
+
+            /* the walk has left the subtree of the last node matched */
+            if node.depth <= matched
+            {
+                matched = node.depth - 1;
+            }
+
+            if node.depth == matched + 1
+            {
+                if let Some(c) = component(matched)
+                {
+                    if node.name == c || (c.contains('@') == false && node.unit_name() == c)
+                    {
+                        matched = node.depth;
+                        if matched == wanted
+                        {
+                            return Some(node);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /* <= every node compatible with the given string, in the order they appear */
+    pub fn find_compatible<'s>(&self, compatible: &'s str) -> impl Iterator<Item = Node<'a>> + 's
+        where 'a: 's
+    {
+        self.nodes().filter(move |node| node.is_compatible(compatible))
+    }
+
+    /* <= the node with the given phandle, or None if there isn't one */
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>>
+    {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /* find the CPU cores an interrupt controller's contexts are wired to, from its interrupts-extended
+       property. each pair in the property names a core's local interrupt controller and the interrupt
+       raised there, and contexts are numbered by the order of the pairs
+       => controller = node of the interrupt controller, such as a PLIC or CLINT
+          cause = local interrupt wanted, such as 3 for machine software or 11 for machine external
+       <= iterator of the hardware ID of each core and the context that raises the wanted interrupt in it */
+    pub fn hart_contexts<'s>(&'s self, controller: &Node<'a>, cause: u32) -> impl Iterator<Item = (u64, usize)> + 's
+    {
+        let phandles = controller.property_cells("interrupts-extended").into_iter().flatten().step_by(2);
+        let causes = controller.property_cells("interrupts-extended").into_iter().flatten().skip(1).step_by(2);
+
+        phandles.zip(causes).enumerate()
+            .filter(move |(_, (_, local))| *local == cause)
+            .filter_map(move |(context, (phandle, _))| self.hart_of_controller(phandle).map(|hart| (hart, context)))
+    }
+
+    /* <= the hardware ID of the CPU core whose local interrupt controller has the given phandle */
+    fn hart_of_controller(&self, phandle: u32) -> Option<u64>
+    {
+        let mut hart = None;
+        for node in self.nodes()
+        {
+            match (node.depth, node.unit_name())
+            {
+                (2, "cpu") => hart = node.reg().ok().and_then(|mut reg| reg.next()).map(|(id, _)| id),
+                (2, _) => hart = None,
+                (3, "interrupt-controller") if node.phandle() == Some(phandle) => return hart,
+                _ => ()
+            }
+        }
+        None
+    }
+
+    /* <= the name at the given offset into the strings block */
+    fn string(&self, offset: usize) -> Option<&'a str>
+    {
+        match cstring(self.strings, offset)
+        {
+            Some((s, _)) => Some(s),
+            None => None
+        }
+    }
+}
+
+/* how far a walk of the tree has got */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Walk
+{
+    Running,
+    Finished,
+    Error(Error)
+}
+
+/* walk the nodes of a tree */
+pub struct Nodes<'a>
+{
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    cells: [(u32, u32); FDT_DEPTH_MAX + 1], /* (#address-cells, #size-cells) each depth's nodes are described with */
+    status: Walk
+}
+
+impl<'a> Nodes<'a>
+{
+    /* stop the walk because the blob is malformed */
+    fn fail(&mut self, error: Error) -> Option<Node<'a>>
+    {
+        self.status = Walk::Error(error);
+        None
+    }
+}
+
+impl<'a> Iterator for Nodes<'a>
+{
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>>
+    {
+        if self.status != Walk::Running
+        {
+            return None;
+        }
+
+        loop
+        {
+            let token = match be32(self.fdt.structs, self.offset)
+            {
+                Some(t) => t,
+                None => return self.fail(Error::DeviceTreeBadStructure)
+            };
+            self.offset = self.offset + 4;
+
+            match token
+            {
+                FDT_BEGIN_NODE =>
+                {
+                    let start = self.offset - 4;
+                    let (name, after) = match cstring(self.fdt.structs, self.offset)
+                    {
+                        Some(n) => n,
+                        None => return self.fail(Error::DeviceTreeBadStructure)
+                    };
+                    self.offset = match align4(after)
+                    {
+                        Some(o) => o,
+                        None => return self.fail(Error::DeviceTreeBadStructure)
+                    };
+
+                    /* nodes can only nest so deep */
+                    if self.depth >= FDT_DEPTH_MAX
+                    {
+                        return self.fail(Error::DeviceTreeBadStructure);
+                    }
+
+                    let node = Node
+                    {
+                        fdt: self.fdt,
+                        start,
+                        props: self.offset,
+                        name,
+                        depth: self.depth,
+                        cells: self.cells[self.depth]
+                    };
+
+                    /* the node's children use the cell counts it declares */
+                    self.cells[self.depth + 1] = (node.property_u32("#address-cells").unwrap_or(ADDRESS_CELLS_DEFAULT),
+                                                  node.property_u32("#size-cells").unwrap_or(SIZE_CELLS_DEFAULT));
+                    self.depth = self.depth + 1;
+                    return Some(node);
+                },
+
+                FDT_END_NODE => match self.depth
+                {
+                    0 => return self.fail(Error::DeviceTreeBadStructure),
+                    d => self.depth = d - 1
+                },
+
+                FDT_PROP =>
+                {
+                    let len = match be32(self.fdt.structs, self.offset)
+                    {
+                        Some(l) => l as usize,
+                        None => return self.fail(Error::DeviceTreeBadStructure)
+                    };
+                    self.offset = match self.offset.checked_add(8 + len).and_then(align4)
+                    {
+                        Some(o) if o <= self.fdt.structs.len() => o,
+                        _ => return self.fail(Error::DeviceTreeBadStructure)
+                    };
+                },
+
+                FDT_NOP => (),
+
+                FDT_END =>
+                {
+                    self.status = match self.depth
+                    {
+                        0 => Walk::Finished,
+                        _ => Walk::Error(Error::DeviceTreeBadStructure)
+                    };
+                    return None;
+                },
+
+                _ => return self.fail(Error::DeviceTreeBadStructure)
+            }
+        }
+    }
+}
+
+/* a node in the tree */
+#[derive(Clone, Copy)]
+pub struct Node<'a>
+{
+    fdt: Fdt<'a>,
+    start: usize,       /* offset into the structure block of the node's begin token */
+    props: usize,       /* offset into the structure block of the node's first property */
+    name: &'a str,
+    depth: usize,       /* zero for the root node */
+    cells: (u32, u32)   /* #address-cells and #size-cells declared by the node's parent */
+}
+
+impl<'a> Node<'a>
+{
+    /* <= the node's full name, eg: serial@10000000, which is empty for the root */
+    pub fn name(&self) -> &'a str { self.name }
+
+    /* <= the node's name without its unit address, eg: serial */
+    pub fn unit_name(&self) -> &'a str
+    {
+        match self.name.find('@')
+        {
+            Some(at) => &self.name[..at],
+            None => self.name
+        }
+    }
+
+    /* <= true if this and the given node are the same node of the same tree */
+    pub fn is_same(&self, other: &Node) -> bool
+    {
+        self.props == other.props && self.fdt.structs.as_ptr() == other.fdt.structs.as_ptr()
+    }
+
+    /* <= where the node starts in the tree's structure block, which identifies it in
+          the tree and in any copy of the tree made by prune() */
+    pub fn offset(&self) -> usize { self.start }
+
+    /* <= how deeply the node is nested, which is zero for the root */
+    pub fn depth(&self) -> usize { self.depth }
+
+    /* <= the node's properties as (name, value) pairs */
+    pub fn properties(&self) -> Properties<'a>
+    {
+        Properties { fdt: self.fdt, offset: self.props }
+    }
+
+    /* <= the value of the named property, or None if the node doesn't have it */
+    pub fn property(&self, name: &str) -> Option<&'a [u8]>
+    {
+        match self.properties().find(|(n, _)| *n == name)
+        {
+            Some((_, value)) => Some(value),
+            None => None
+        }
+    }
+
+    /* <= the named property as a single 32-bit cell, or None if it's missing or not one cell */
+    pub fn property_u32(&self, name: &str) -> Option<u32>
+    {
+        let value = self.property(name)?;
+        match value.len()
+        {
+            4 => be32(value, 0),
+            _ => None
+        }
+    }
+
+    /* <= the named property as a list of 32-bit cells, or None if it's missing or not whole cells */
+    pub fn property_cells(&self, name: &str) -> Option<impl Iterator<Item = u32> + 'a>
+    {
+        let value = self.property(name)?;
+        match value.len() & 3
+        {
+            0 => Some(value.chunks(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))),
+            _ => None
+        }
+    }
+
+    /* <= the named property as a string, or None if it's missing or not a string */
+    pub fn property_str(&self, name: &str) -> Option<&'a str>
+    {
+        match cstring(self.property(name)?, 0)
+        {
+            Some((s, _)) => Some(s),
+            None => None
+        }
+    }
+
+    /* <= the named property as a list of strings, which is empty if it's missing */
+    pub fn property_strs(&self, name: &str) -> impl Iterator<Item = &'a str>
+    {
+        let value = self.property(name).unwrap_or(&[]);
+        let value = match value.last()
+        {
+            Some(0) => &value[..value.len() - 1],
+            _ => &[]
+        };
+        value.split(|b| *b == 0).filter_map(|s| core::str::from_utf8(s).ok()).filter(|s| s.len() > 0)
+    }
+
+    /* <= true if the node's compatible property lists the given string */
+    pub fn is_compatible(&self, compatible: &str) -> bool
+    {
+        self.property_strs("compatible").any(|c| c == compatible)
+    }
+
+    /* <= true if the node's status says it can be used, which it does if it has no status */
+    pub fn is_enabled(&self) -> bool
+    {
+        match self.property_str("status")
+        {
+            Some(status) => status == "okay" || status == "ok",
+            None => true
+        }
+    }
+
+    /* <= the node's phandle, or None if it doesn't have one */
+    pub fn phandle(&self) -> Option<u32>
+    {
+        match self.property_u32("phandle")
+        {
+            Some(p) => Some(p),
+            None => self.property_u32("linux,phandle")
+        }
+    }
+
+    /* decode the node's reg property into (address, size) pairs using its parent's cell counts.
+       the cell counts are checked first, so a tree that claims more cells than fit in 64 bits,
+       or a reg that isn't a whole number of entries, is rejected rather than misread
+       <= the entries, which is empty if there's no reg property, or an error code */
+    pub fn reg(&self) -> Result<Reg<'a>, Error>
+    {
+        let (address_cells, size_cells) = self.cells;
+        if address_cells == 0 || address_cells > CELLS_MAX || size_cells > CELLS_MAX
+        {
+            return Err(Error::DeviceTreeBadCells);
+        }
+
+        let value = self.property("reg").unwrap_or(&[]);
+        let entry = ((address_cells + size_cells) * 4) as usize;
+        if (value.len() / entry) * entry != value.len()
+        {
+            return Err(Error::DeviceTreeBadCells);
+        }
+
+        Ok(Reg { value, address_cells, size_cells })
+    }
+
+    /* <= the #address-cells and #size-cells the node's reg property is described with */
+    pub fn reg_cells(&self) -> (u32, u32) { self.cells }
+}
+
+/* walk a node's properties */
+pub struct Properties<'a>
+{
+    fdt: Fdt<'a>,
+    offset: usize
+}
+
+impl<'a> Iterator for Properties<'a>
+{
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a str, &'a [u8])>
+    {
+        loop
+        {
+            match be32(self.fdt.structs, self.offset)?
+            {
+                FDT_NOP => self.offset = self.offset + 4,
+                FDT_PROP =>
+                {
+                    let len = be32(self.fdt.structs, self.offset + 4)? as usize;
+                    let name = self.fdt.string(be32(self.fdt.structs, self.offset + 8)? as usize)?;
+                    let start = self.offset + 12;
+                    let value = self.fdt.structs.get(start..start.checked_add(len)?)?;
+                    self.offset = align4(start + len)?;
+                    return Some((name, value));
+                },
+                _ => return None
+            }
+        }
+    }
+}
+
+/* walk the (address, size) entries of a reg property */
+pub struct Reg<'a>
+{
+    value: &'a [u8],
+    address_cells: u32,
+    size_cells: u32
+}
+
+impl<'a> Reg<'a>
+{
+    /* take a number made of the given count of cells off the front of the property */
+    fn take(&mut self, cells: u32) -> u64
+    {
+        let mut number = 0;
+        for _ in 0..cells
+        {
+            number = (number << 32) | be32(self.value, 0).unwrap_or(0) as u64;
+            self.value = &self.value[4..];
+        }
+        number
+    }
+}
+
+impl<'a> Iterator for Reg<'a>
+{
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)>
+    {
+        if self.value.len() == 0
+        {
+            return None;
+        }
+
+        let address = self.take(self.address_cells);
+        let size = self.take(self.size_cells);
+        Some((address, size))
+    }
+}
+
+/* check a RISC-V ISA string, such as rv64imafdc_zicsr_zicbom_sstc, for an extension
+   => isa = ISA string from a CPU core's riscv,isa property
+      extension = single-letter or multi-letter extension name, in any case
+   <= true if the string includes the extension */
+pub fn isa_has_extension(isa: &str, extension: &str) -> bool
+{
+    let lower = |s: &str, c: usize| s.as_bytes().get(c).map(|b| b.to_ascii_lowercase());
+    if lower(isa, 0) != Some(b'r') || lower(isa, 1) != Some(b'v')
+    {
+        return false;
+    }
+
+    /* skip the base's width, eg: 64 */
+    let isa = &isa[2..];
+    let isa = isa.trim_start_matches(|c: char| c.is_ascii_digit());
+    let mut parts = isa.split('_');
+    let letters = parts.next().unwrap_or("");
+
+    if extension.len() == 1
+    {
+        let wanted = extension.as_bytes()[0].to_ascii_lowercase();
+
+        /* G is shorthand for IMAFD */
+        return letters.bytes().map(|b| b.to_ascii_lowercase())
+            .any(|b| b == wanted || (b == b'g' && b"imafd".contains(&wanted)));
+    }
+
+    parts.any(|part| part.eq_ignore_ascii_case(extension))
+}
+
+/* check whether a CPU core node describes an ISA extension, in either its
+   riscv,isa-extensions list or its older riscv,isa string
+   => cpu = CPU core node
+      extension = single-letter or multi-letter extension name
+   <= true if the core implements the extension */
+pub fn cpu_has_extension(cpu: &Node, extension: &str) -> bool
+{
+    if cpu.property_strs("riscv,isa-extensions").any(|e| e.eq_ignore_ascii_case(extension))
+    {
+        return true;
+    }
+
+    match cpu.property_str("riscv,isa")
+    {
+        Some(isa) => isa_has_extension(isa, extension),
+        None => false
+    }
+}
+
+/* build a device tree blob one token at a time. nodes are begun and ended in the order they
+   nest, and each node's properties must come before its children. the blob has an empty
+   memory reservation block */
+pub struct Writer
+{
+    structs: Vec<u8>,
+    strings: Vec<u8>
+}
+
+impl Writer
+{
+    pub fn new() -> Writer
+    {
+        Writer { structs: Vec::new(), strings: Vec::new() }
+    }
+
+    fn word(&mut self, word: u32) -> &mut Writer
+    {
+        self.structs.extend_from_slice(&word.to_be_bytes());
+        self
+    }
+
+    fn pad(&mut self)
+    {
+        while self.structs.len() & 3 != 0
+        {
+            self.structs.push(0);
+        }
+    }
+
+    /* start a node, which is the root if it's the first */
+    pub fn begin(&mut self, name: &str) -> &mut Writer
+    {
+        self.word(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+        self
+    }
+
+    /* finish the node started last */
+    pub fn end(&mut self) -> &mut Writer
+    {
+        self.word(FDT_END_NODE)
+    }
+
+    /* add a property to the node started last, as raw bytes, as big-endian cells, or as a string */
+    pub fn prop(&mut self, name: &str, value: &[u8]) -> &mut Writer
+    {
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.word(FDT_PROP).word(value.len() as u32).word(offset);
+        self.structs.extend_from_slice(value);
+        self.pad();
+        self
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Writer
+    {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes().to_vec()).collect();
+        self.prop(name, &value)
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) -> &mut Writer
+    {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.prop(name, &bytes)
+    }
+
+    /* <= the finished blob */
+    pub fn blob(&mut self) -> Vec<u8>
+    {
+        self.word(FDT_END);
+        let structs_at = HEADER_SIZE + 16; /* leave room for an empty memory reservation block */
+        let strings_at = structs_at + self.structs.len();
+        let total = strings_at + self.strings.len();
+
+        let mut blob = Vec::new();
+        for word in [FDT_MAGIC, total as u32, structs_at as u32, strings_at as u32, HEADER_SIZE as u32,
+                     17, 16, 0, self.strings.len() as u32, self.structs.len() as u32].iter()
+        {
+            blob.extend_from_slice(&word.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structs);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/* make a copy of a blob with the given nodes, and everything beneath them, taken out. the nodes are
+   overwritten with NOP tokens, so the rest of the blob keeps its layout and its nodes their offsets
+   => blob = bytes of the blob, which may run on past its end
+      offsets = offsets of the nodes to take out, from Node::offset()
+   <= the pruned copy, or an error code if the blob is malformed */
+pub fn prune(blob: &[u8], offsets: &[usize]) -> Result<Vec<u8>, Error>
+{
+    let fdt = Fdt::new(blob)?;
+    fdt.check()?;
+
+    /* Fdt::new() has checked these */
+    let total = be32(blob, HEADER_TOTAL_SIZE).ok_or(Error::DeviceTreeBadHeader)? as usize;
+    let structs_at = be32(blob, HEADER_STRUCT_OFFSET).ok_or(Error::DeviceTreeBadHeader)? as usize;
+    let mut copy = blob[..total].to_vec();
+
+    let mut pruned_to = 0;
+    for node in fdt.nodes()
+    {
+        if node.start < pruned_to || offsets.contains(&node.start) == false
+        {
+            continue;
+        }
+
+        pruned_to = subtree_end(fdt.structs, node.props).ok_or(Error::DeviceTreeBadStructure)?;
+        for word in (node.start..pruned_to).step_by(4)
+        {
+            let at = structs_at + word;
+            copy[at..at + 4].copy_from_slice(&FDT_NOP.to_be_bytes());
+        }
+    }
+
+    Ok(copy)
+}
+
+/* <= the offset just past the end token of the node whose properties start at the given offset */
+fn subtree_end(structs: &[u8], props: usize) -> Option<usize>
+{
+    let mut offset = props;
+    let mut depth = 1;
+    loop
+    {
+        let token = be32(structs, offset)?;
+        offset = offset + 4;
+        match token
+        {
+            FDT_BEGIN_NODE =>
+            {
+                let (_, after) = cstring(structs, offset)?;
+                offset = align4(after)?;
+                depth = depth + 1;
+            },
+            FDT_END_NODE =>
+            {
+                depth = depth - 1;
+                if depth == 0
+                {
+                    return Some(offset);
+                }
+            },
+            FDT_PROP =>
+            {
+                let len = be32(structs, offset)? as usize;
+                offset = offset.checked_add(8 + len).and_then(align4)?;
+            },
+            FDT_NOP => (),
+            _ => return None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use alloc::vec::Vec;
+
+    /* a small machine with two serial ports and a CPU core */
+    fn machine() -> Vec<u8>
+    {
+        let mut b = Writer::new();
+        b.begin("").prop_cells("#address-cells", &[2]).prop_cells("#size-cells", &[2]);
+          b.begin("chosen").prop_str("stdout-path", "/soc/serial@10000000").end();
+          b.begin("cpus").prop_cells("#address-cells", &[1]).prop_cells("#size-cells", &[0]);
+            b.begin("cpu@0").prop_cells("reg", &[0]).prop_str("riscv,isa", "rv64imafdc_zicsr_zicbom_sstc");
+              b.begin("interrupt-controller").prop_cells("phandle", &[1]).end();
+            b.end();
+          b.end();
+          b.begin("soc").prop_cells("#address-cells", &[2]).prop_cells("#size-cells", &[2]);
+            b.begin("serial@10000000").prop("compatible", b"ns16550a\0").prop_cells("reg", &[0, 0x1000_0000, 0, 0x100])
+             .prop_cells("interrupts", &[10]).end();
+            b.begin("serial@10001000").prop("compatible", b"snps,dw-apb-uart\0ns16550a\0")
+             .prop_cells("reg", &[0, 0x1000_1000, 0, 0x100]).prop_str("status", "disabled").end();
+          b.end();
+        b.end();
+        b.blob()
+    }
+
+    #[test]
+    fn rejects_bad_headers()
+    {
+        let mut blob = machine();
+        assert!(Fdt::new(&blob).is_ok());
+        assert_eq!(Fdt::new(&blob[..HEADER_SIZE - 1]).err(), Some(Error::DeviceTreeBadHeader));
+        assert_eq!(Fdt::new(&blob[..blob.len() - 1]).err(), Some(Error::DeviceTreeBadHeader));
+
+        blob[0] = 0;
+        assert_eq!(Fdt::new(&blob).err(), Some(Error::DeviceTreeBadHeader));
+    }
+
+    #[test]
+    fn walks_nodes_in_order()
+    {
+        let blob = machine();
+        let fdt = Fdt::new(&blob).unwrap();
+        let names: Vec<&str> = fdt.nodes().map(|n| n.name()).collect();
+        assert_eq!(names, vec!["", "chosen", "cpus", "cpu@0", "interrupt-controller", "soc", "serial@10000000", "serial@10001000"]);
+        assert_eq!(fdt.check(), Ok(()));
+    }
+
+    #[test]
+    fn finds_nodes_by_path_and_phandle()
+    {
+        let blob = machine();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.find("/").unwrap().depth(), 0);
+        assert_eq!(fdt.find("/soc/serial@10001000").unwrap().name(), "serial@10001000");
+        assert_eq!(fdt.find("/cpus/cpu").unwrap().name(), "cpu@0");
+        assert!(fdt.find("/soc/serial@20000000").is_none());
+        assert!(fdt.find("/serial@10000000").is_none());
+        assert_eq!(fdt.find_phandle(1).unwrap().name(), "interrupt-controller");
+        assert_eq!(fdt.find("/chosen").unwrap().property_str("stdout-path"), Some("/soc/serial@10000000"));
+    }
+
+    #[test]
+    fn maps_interrupt_contexts_to_cores()
+    {
+        let mut b = Writer::new();
+        b.begin("").prop_cells("#address-cells", &[2]).prop_cells("#size-cells", &[2]);
+          b.begin("cpus").prop_cells("#address-cells", &[1]).prop_cells("#size-cells", &[0]);
+            b.begin("cpu@0").prop_cells("reg", &[0]);
+              b.begin("interrupt-controller").prop_cells("phandle", &[1]).end();
+            b.end();
+            b.begin("cpu@5").prop_cells("reg", &[5]);
+              b.begin("interrupt-controller").prop_cells("phandle", &[2]).end();
+            b.end();
+          b.end();
+          b.begin("clint@2000000").prop_cells("interrupts-extended", &[1, 3, 1, 7, 2, 3, 2, 7, 9, 3]).end();
+          b.begin("plic@c000000").prop_cells("interrupts-extended", &[1, 11, 1, 9, 2, 11, 2, 9]).end();
+        b.end();
+        let blob = b.blob();
+        let fdt = Fdt::new(&blob).unwrap();
+
+        let clint = fdt.find("/clint").unwrap();
+        let soft: Vec<(u64, usize)> = fdt.hart_contexts(&clint, 3).collect();
+        assert_eq!(soft, vec![(0, 0), (5, 2)]); /* phandle 9 isn't a core's controller */
+        let timer: Vec<(u64, usize)> = fdt.hart_contexts(&clint, 7).collect();
+        assert_eq!(timer, vec![(0, 1), (5, 3)]);
+
+        let plic = fdt.find("/plic").unwrap();
+        let external: Vec<(u64, usize)> = fdt.hart_contexts(&plic, 11).collect();
+        assert_eq!(external, vec![(0, 0), (5, 2)]);
+        assert_eq!(fdt.hart_contexts(&fdt.find("/cpus").unwrap(), 11).count(), 0);
+    }
+
+    #[test]
+    fn reads_properties()
+    {
+        let blob = machine();
+        let fdt = Fdt::new(&blob).unwrap();
+        let uarts: Vec<Node> = fdt.find_compatible("ns16550a").collect();
+        assert_eq!(uarts.len(), 2);
+        assert_eq!(uarts[0].property_u32("interrupts"), Some(10));
+        assert!(uarts[0].is_enabled());
+        assert!(uarts[1].is_enabled() == false);
+        assert_eq!(uarts[1].property_strs("compatible").collect::<Vec<&str>>(), vec!["snps,dw-apb-uart", "ns16550a"]);
+        assert_eq!(uarts[0].reg().unwrap().collect::<Vec<(u64, u64)>>(), vec![(0x1000_0000, 0x100)]);
+
+        let cpu = fdt.find("/cpus/cpu@0").unwrap();
+        assert_eq!(cpu.reg_cells(), (1, 0));
+        assert_eq!(cpu.reg().unwrap().collect::<Vec<(u64, u64)>>(), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn checks_reg_cells()
+    {
+        let mut b = Writer::new();
+        b.begin("").prop_cells("#address-cells", &[3]).prop_cells("#size-cells", &[1]);
+          b.begin("pci@0").prop_cells("reg", &[0, 0, 0, 0x1000]).end();
+          b.begin("soc").prop_cells("#address-cells", &[1]).prop_cells("#size-cells", &[1]);
+            b.begin("uart@100").prop_cells("reg", &[0x100, 0x10, 0x200]).end();
+            b.begin("uart@300").prop_cells("reg", &[0x300, 0x10, 0x400, 0x10]).end();
+          b.end();
+        b.end();
+        let blob = b.blob();
+        let fdt = Fdt::new(&blob).unwrap();
+
+        assert_eq!(fdt.find("/pci@0").unwrap().reg().err(), Some(Error::DeviceTreeBadCells));
+        assert_eq!(fdt.find("/soc/uart@100").unwrap().reg().err(), Some(Error::DeviceTreeBadCells));
+        assert_eq!(fdt.find("/soc/uart@300").unwrap().reg().unwrap().collect::<Vec<(u64, u64)>>(),
+                   vec![(0x300, 0x10), (0x400, 0x10)]);
+    }
+
+    #[test]
+    fn stops_at_malformed_structure()
+    {
+        /* a node that's never closed */
+        let mut b = Writer::new();
+        b.begin("").begin("soc");
+        let blob = b.blob();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.nodes().count(), 2);
+        assert_eq!(fdt.check(), Err(Error::DeviceTreeBadStructure));
+
+        /* a property that runs off the end of the structure block */
+        let mut b = Writer::new();
+        b.begin("").word(FDT_PROP).word(0x1000).word(0).end();
+        let blob = b.blob();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.check(), Err(Error::DeviceTreeBadStructure));
+        assert_eq!(fdt.find("/").unwrap().property("anything"), None);
+    }
+
+    #[test]
+    fn survives_random_blobs()
+    {
+        /* corrupt a good blob one byte at a time. the walk must never panic */
+        let good = machine();
+        let mut seed: u32 = 0x1234_5678;
+        for _ in 0..2000
+        {
+            let mut blob = good.clone();
+            for _ in 0..4
+            {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let index = (seed as usize >> 8) % blob.len();
+                blob[index] = (seed >> 24) as u8;
+            }
+
+            if let Ok(fdt) = Fdt::new(&blob)
+            {
+                for node in fdt.nodes()
+                {
+                    let _ = node.properties().count();
+                    let _ = node.is_compatible("ns16550a");
+                    if let Ok(reg) = node.reg()
+                    {
+                        let _ = reg.count();
+                    }
+                }
+                let _ = fdt.check();
+                let _ = fdt.find("/soc/serial");
+            }
+        }
+    }
+
+    #[test]
+    fn parses_isa_strings()
+    {
+        assert!(isa_has_extension("rv64imafdc_zicsr_zicbom_sstc", "sstc"));
+        assert!(isa_has_extension("rv64imafdc_zicsr_zicbom_sstc", "Zicbom"));
+        assert!(isa_has_extension("RV64GC", "d"));
+        assert!(isa_has_extension("rv64imac", "c"));
+        assert!(isa_has_extension("rv64imac", "f") == false);
+        assert!(isa_has_extension("rv64imac_zicsr", "zicbo") == false);
+        assert!(isa_has_extension("rv64imac_sstc", "s") == false);
+        assert!(isa_has_extension("", "i") == false);
+
+        let blob = machine();
+        let fdt = Fdt::new(&blob).unwrap();
+        let cpu = fdt.find("/cpus/cpu@0").unwrap();
+        assert!(cpu_has_extension(&cpu, "sstc"));
+        assert!(cpu_has_extension(&cpu, "zkr") == false);
+    }
+
+    #[test]
+    fn prunes_nodes_and_their_children()
+    {
+        let blob = machine();
+        let fdt = Fdt::new(&blob).unwrap();
+        let cpus = fdt.find("/cpus").unwrap().offset();
+        let serial = fdt.find("/soc/serial@10001000").unwrap().offset();
+
+        let pruned = prune(&blob, &[cpus, serial]).unwrap();
+        assert_eq!(pruned.len(), blob.len());
+        let fdt = Fdt::new(&pruned).unwrap();
+        assert!(fdt.check().is_ok());
+        assert!(fdt.find("/cpus").is_none());
+        assert!(fdt.find("/cpus/cpu@0/interrupt-controller").is_none());
+        assert!(fdt.find("/soc/serial@10001000").is_none());
+
+        /* what's left keeps its place and its properties */
+        let kept = fdt.find("/soc/serial@10000000").unwrap();
+        assert_eq!(kept.offset(), Fdt::new(&blob).unwrap().find("/soc/serial@10000000").unwrap().offset());
+        assert_eq!(kept.reg().unwrap().next(), Some((0x1000_0000, 0x100)));
+        assert_eq!(fdt.find("/chosen").unwrap().property_str("stdout-path"), Some("/soc/serial@10000000"));
+
+        /* nothing to take out leaves the blob as it was, and malformed blobs are refused */
+        assert_eq!(prune(&blob, &[]).unwrap(), blob);
+        assert!(prune(&blob[..blob.len() - 1], &[]).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+/* diosix hypercall argument checks
+ *
+ * Capsules pass addresses, sizes, and counts to the hypervisor in
+ * registers, and a bad one is the easiest way for a capsule to get the
+ * hypervisor to read or write memory it shouldn't. So every buffer a
+ * hypercall is given is checked the same way here: it must not be
+ * empty, nor bigger than the limit set by the hypercall, nor wrap around
+ * the top of the address space. It must lie entirely within one of the
+ * calling capsule's memory windows, and if the hypervisor is going to
+ * write to it, it must not cover any part of the capsule's memory that
+ * the capsule can't write to itself, such as its read-only code. Arrays
+ * must also be aligned for their elements, and their sizes in bytes are
+ * worked out without overflowing.
+ *
+ * The capsule's memory is described through the AddressSpace trait,
+ * which the hypervisor implements from the capsule's mappings. decode()
+ * takes a hypercall's raw argument registers and a list of the
+ * parameters it expects, and checks the lot. It must never panic,
+ * whatever it's given, and anything it accepts must lie within the
+ * capsule's memory. That makes it a good target for fuzzing on the
+ * host: the tests below feed it random registers and parameters, and
+ * an external fuzzer, such as cargo-fuzz, can drive it the same way.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use super::Error;
+
+/* what the hypervisor will do with a buffer */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access
+{
+    Read,
+    Write
+}
+
+/* a run of a capsule's memory that's contiguous in host memory */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Window
+{
+    pub base: usize,    /* capsule address of the start of the window */
+    pub size: usize,    /* size of the window in bytes */
+    pub host: usize     /* host address of the start of the window */
+}
+
+/* the memory of the capsule that made a hypercall */
+pub trait AddressSpace
+{
+    /* <= the window containing the given capsule address, or None if it's outside the capsule's memory */
+    fn window(&self, addr: usize) -> Option<Window>;
+
+    /* <= true if the capsule can't write to any of the given range of capsule addresses, such as its code */
+    fn is_read_only(&self, addr: usize, size: usize) -> bool;
+}
+
+/* what a hypercall expects in its argument registers */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Param
+{
+    /* any value, such as an ID, which is checked by whatever uses it. takes one register */
+    Value,
+
+    /* a value below the given limit, such as a button or direction. takes one register */
+    Choice(usize),
+
+    /* capsule address and size in bytes of a buffer of at most max bytes. takes two registers */
+    Bytes { max: usize, access: Access },
+
+    /* capsule address and number of elements of an array of at most max elements, each
+       size bytes long and aligned to align bytes. takes two registers */
+    Array { size: usize, align: usize, max: usize, access: Access }
+}
+
+/* a checked hypercall argument */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Arg
+{
+    Value(usize),
+    Buffer { host: usize, size: usize } /* host address and size in bytes */
+}
+
+/* check a buffer a capsule has passed to the hypervisor
+   => space = memory of the capsule that passed the buffer
+      addr = capsule address of the buffer
+      size = size of the buffer in bytes
+      max = largest size allowed, in bytes
+      access = what the hypervisor will do with the buffer
+   <= host address of the buffer, or an error code */
+pub fn bytes<S: AddressSpace>(space: &S, addr: usize, size: usize, max: usize, access: Access) -> Result<usize, Error>
+{
+    if size == 0 || size > max
+    {
+        return Err(Error::ArgBadLength);
+    }
+
+    let last = match addr.checked_add(size - 1)
+    {
+        Some(last) => last,
+        None => return Err(Error::ArgBadPointer)
+    };
+
+    /* don't trust the window any more than the buffer */
+    let window = match space.window(addr)
+    {
+        Some(window) if window.size > 0 => window,
+        _ => return Err(Error::ArgBadPointer)
+    };
+
+    let window_last = match window.base.checked_add(window.size - 1)
+    {
+        Some(window_last) => window_last,
+        None => return Err(Error::ArgBadPointer)
+    };
+
+    if addr < window.base || last > window_last || window.host.checked_add(window.size - 1).is_none()
+    {
+        return Err(Error::ArgBadPointer);
+    }
+
+    if access == Access::Write && space.is_read_only(addr, size) == true
+    {
+        return Err(Error::ArgReadOnly);
+    }
+
+    Ok(window.host + (addr - window.base))
+}
+
+/* check an array a capsule has passed to the hypervisor
+   => space = memory of the capsule that passed the array
+      addr = capsule address of the array
+      count = number of elements in the array
+      size = size of each element in bytes
+      align = alignment of each element in bytes, a power of two
+      max = most elements allowed
+      access = what the hypervisor will do with the array
+   <= host address of the array, or an error code */
+pub fn array<S: AddressSpace>(space: &S, addr: usize, count: usize, size: usize, align: usize, max: usize, access: Access) -> Result<usize, Error>
+{
+    if count == 0 || count > max
+    {
+        return Err(Error::ArgBadLength);
+    }
+
+    if align == 0 || align.is_power_of_two() == false || addr & (align - 1) != 0
+    {
+        return Err(Error::ArgMisaligned);
+    }
+
+    let total = match count.checked_mul(size)
+    {
+        Some(total) => total,
+        None => return Err(Error::ArgBadLength)
+    };
+
+    let host = bytes(space, addr, total, usize::MAX, access)?;
+
+    /* a window that starts on an odd host address would misalign the array for the hypervisor */
+    if host & (align - 1) != 0
+    {
+        return Err(Error::ArgMisaligned);
+    }
+
+    Ok(host)
+}
+
+/* check a hypercall's argument registers
+   => space = memory of the capsule that made the hypercall
+      params = what the hypercall expects, in order
+      raw = the hypercall's argument registers, in order
+   <= the checked arguments, one per parameter, or an error code */
+pub fn decode<S: AddressSpace>(space: &S, params: &[Param], raw: &[usize]) -> Result<Vec<Arg>, Error>
+{
+    let mut args = Vec::with_capacity(params.len());
+    let mut registers = raw.iter().copied();
+
+    for param in params
+    {
+        let first = match registers.next()
+        {
+            Some(value) => value,
+            None => return Err(Error::ArgMissing)
+        };
+
+        let arg = match *param
+        {
+            Param::Value => Arg::Value(first),
+            Param::Choice(limit) => match first < limit
+            {
+                true => Arg::Value(first),
+                false => return Err(Error::ArgBadValue)
+            },
+            Param::Bytes { max, access } =>
+            {
+                let size = match registers.next()
+                {
+                    Some(size) => size,
+                    None => return Err(Error::ArgMissing)
+                };
+
+                Arg::Buffer { host: bytes(space, first, size, max, access)?, size }
+            },
+            Param::Array { size, align, max, access } =>
+            {
+                let count = match registers.next()
+                {
+                    Some(count) => count,
+                    None => return Err(Error::ArgMissing)
+                };
+
+                let host = array(space, first, count, size, align, max, access)?;
+                Arg::Buffer { host, size: count * size }
+            }
+        };
+
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /* a capsule with two windows of memory. the first page of the first window is read-only */
+    struct Capsule
+    {
+        windows: Vec<Window>,
+        read_only: (usize, usize)
+    }
+
+    impl AddressSpace for Capsule
+    {
+        fn window(&self, addr: usize) -> Option<Window>
+        {
+            self.windows.iter().find(|w| addr >= w.base && addr - w.base < w.size).copied()
+        }
+
+        fn is_read_only(&self, addr: usize, size: usize) -> bool
+        {
+            addr < self.read_only.0 + self.read_only.1 && addr + size > self.read_only.0
+        }
+    }
+
+    fn capsule() -> Capsule
+    {
+        Capsule
+        {
+            windows: vec![ Window { base: 0x8000_0000, size: 0x4000, host: 0x9000_0000 },
+                           Window { base: 0x8000_4000, size: 0x1000, host: 0x7000_0000 } ],
+            read_only: (0x8000_0000, 0x1000)
+        }
+    }
+
+    #[test]
+    fn bytes_translates_and_bounds()
+    {
+        let c = capsule();
+        assert_eq!(bytes(&c, 0x8000_1010, 0x10, 0x100, Access::Write), Ok(0x9000_1010));
+        assert_eq!(bytes(&c, 0x8000_1010, 0, 0x100, Access::Read), Err(Error::ArgBadLength));
+        assert_eq!(bytes(&c, 0x8000_1010, 0x101, 0x100, Access::Read), Err(Error::ArgBadLength));
+        assert_eq!(bytes(&c, 0x1000, 0x10, 0x100, Access::Read), Err(Error::ArgBadPointer));
+        assert_eq!(bytes(&c, usize::MAX - 4, 0x10, 0x100, Access::Read), Err(Error::ArgBadPointer));
+    }
+
+    #[test]
+    fn bytes_stay_in_one_window()
+    {
+        /* the windows are next to each other in the capsule but not in the host */
+        let c = capsule();
+        assert_eq!(bytes(&c, 0x8000_3ff0, 0x10, 0x100, Access::Read), Ok(0x9000_3ff0));
+        assert_eq!(bytes(&c, 0x8000_3ff0, 0x20, 0x100, Access::Read), Err(Error::ArgBadPointer));
+    }
+
+    #[test]
+    fn read_only_memory_is_only_read()
+    {
+        let c = capsule();
+        assert_eq!(bytes(&c, 0x8000_0100, 0x10, 0x100, Access::Read), Ok(0x9000_0100));
+        assert_eq!(bytes(&c, 0x8000_0100, 0x10, 0x100, Access::Write), Err(Error::ArgReadOnly));
+        assert_eq!(bytes(&c, 0x8000_0ff0, 0x20, 0x100, Access::Write), Err(Error::ArgReadOnly));
+    }
+
+    #[test]
+    fn arrays_are_aligned_and_sized_without_overflow()
+    {
+        let c = capsule();
+        assert_eq!(array(&c, 0x8000_1000, 4, 8, 8, 16, Access::Read), Ok(0x9000_1000));
+        assert_eq!(array(&c, 0x8000_1004, 4, 8, 8, 16, Access::Read), Err(Error::ArgMisaligned));
+        assert_eq!(array(&c, 0x8000_1000, 17, 8, 8, 16, Access::Read), Err(Error::ArgBadLength));
+        assert_eq!(array(&c, 0x8000_1000, usize::MAX / 4, 8, 8, usize::MAX, Access::Read), Err(Error::ArgBadLength));
+        assert_eq!(array(&c, 0x8000_1000, 4, 8, 3, 16, Access::Read), Err(Error::ArgMisaligned));
+    }
+
+    #[test]
+    fn decode_checks_every_argument()
+    {
+        let c = capsule();
+        let params = [ Param::Value, Param::Choice(2), Param::Bytes { max: 0x100, access: Access::Write } ];
+        assert_eq!(decode(&c, &params, &[7, 1, 0x8000_4000, 0x10]),
+            Ok(vec![ Arg::Value(7), Arg::Value(1), Arg::Buffer { host: 0x7000_0000, size: 0x10 } ]));
+        assert_eq!(decode(&c, &params, &[7, 2, 0x8000_4000, 0x10]), Err(Error::ArgBadValue));
+        assert_eq!(decode(&c, &params, &[7, 1, 0x8000_4000]), Err(Error::ArgMissing));
+    }
+
+    /* feed decode() random registers and parameters, near the windows' edges as well as
+       anywhere, and check that nothing it accepts strays outside the capsule's memory */
+    #[test]
+    fn decode_survives_random_arguments()
+    {
+        let c = capsule();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move ||
+        {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+
+        let edges = [ 0, 1, 0x10, 0xfff, 0x1000, 0x4000, 0x8000_0000, 0x8000_0ff8, 0x8000_3ff8,
+                      0x8000_4000, 0x8000_4ff8, 0x8000_5000, usize::MAX / 8, usize::MAX - 7, usize::MAX ];
+
+        for _ in 0..100000
+        {
+            let mut value = || match random() % 3
+            {
+                0 => random(),
+                1 => edges[random() % edges.len()],
+                _ => edges[random() % edges.len()].wrapping_add(random() % 0x20)
+            };
+
+            let access = match value() & 1 { 0 => Access::Read, _ => Access::Write };
+            let params: Vec<Param> = (0..(value() % 4)).map(|_| match value() % 4
+            {
+                0 => Param::Value,
+                1 => Param::Choice(value()),
+                2 => Param::Bytes { max: value(), access },
+                _ => Param::Array { size: value() % 32, align: 1 << (value() % 5), max: value(), access }
+            }).collect();
+            let raw: Vec<usize> = (0..(value() % 8)).map(|_| value()).collect();
+
+            if let Ok(args) = decode(&c, &params, &raw)
+            {
+                for arg in args
+                {
+                    if let Arg::Buffer { host, size } = arg
+                    {
+                        assert!(c.windows.iter().any(|w| host >= w.host && host - w.host <= w.size - size),
+                            "host buffer 0x{:x} size 0x{:x} outside the capsule", host, size);
+                        assert!(access == Access::Read || host + size <= 0x9000_0000 || host >= 0x9000_1000,
+                            "host buffer 0x{:x} size 0x{:x} is read-only", host, size);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1179 @@
+/* diosix heap engine
+ *
+ * Simple heap manager. Each physical CPU core has its own heap, and the
+ * hypervisor's global allocator allocates from the running core's heap,
+ * so this code is single threaded per heap and lock-free. Blocks are
+ * freed atomically, so any core can free a block back to its owner.
+ *
+ * A heap is a single-linked list of blocks, each with a header giving
+ * its size and whether it's free or in use. A heap starts with one free
+ * block covering the fixed area it's given. When it runs out, it asks
+ * its source of memory for a temporary block, and gives temporary
+ * blocks back once they're entirely free again. The source of memory,
+ * and anywhere the heap reports problems to, is up to the Memory trait,
+ * which the hypervisor implements with its physical memory manager.
+ *
+ * With the integritychecks feature, each block header starts with a
+ * guard word. An overrun from the block below will destroy this word
+ * before reaching the header's list link, so the list can be walked and
+ * checked safely.
+ *
+ * With the memorypoison feature, in-use blocks are bracketed by canary
+ * words, checked when they're freed and when asked.
+ *
+ * With the heapaudit feature, each block records the tag of whoever
+ * allocated it, and how many bytes they asked for.
+ *
+ * With the heapguard feature, which brings in the memorypoison canaries,
+ * each block is stamped with the owner that allocated it and the heap's
+ * allocation generation at the time, and freed blocks are quarantined
+ * until HEAP_QUARANTINE more blocks have been allocated from the heap.
+ * Freeing a block twice in that time finds it free and is reported,
+ * rather than freeing whatever was allocated in its place. Blocks are
+ * only taken out of quarantine early if the heap would otherwise run out
+ * of memory. The owners and generations of in-use blocks are checked
+ * alongside their canaries.
+ *
+ * (c) Chris Williams, 2019-2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::mem;
+use core::ptr;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::Error;
+
+/* where a heap gets more memory from, and tells of problems */
+pub trait Memory: Default
+{
+    /* => size = minimum number of bytes needed
+       <= base address and size of a block of fresh memory to add to the heap, or None if there's none */
+    fn grow(&mut self, size: usize) -> Option<(usize, usize)>;
+
+    /* take back a block of memory handed out by grow(), now the heap no longer needs it
+       <= true if it was taken back, or false to leave it in the heap */
+    fn release(&mut self, base: usize, size: usize) -> bool;
+
+    /* an in-use block's canaries have been overwritten. only called with the memorypoison feature
+       => block = the damaged block
+          leading = the leading canary's value if it was overwritten, or None if it was the trailing canary */
+    fn damaged(&self, _block: BlockReport, _leading: Option<usize>) {}
+
+    /* a block has been freed twice, or its header no longer adds up. only called with the heapguard feature */
+    fn misused(&self, _misuse: Misuse) {}
+
+    /* <= ID of the owner allocating from this heap, such as its physical core. only called with the heapguard feature */
+    fn owner(&self) -> usize { 0 }
+
+    /* a block has been allocated or freed. only called with the heapaudit feature
+       => tag = tag of whoever allocated the block
+          requested = bytes they asked for */
+    fn tagged(&self, _tag: usize, _requested: usize) {}
+    fn untagged(&self, _tag: usize, _requested: usize) {}
+}
+
+/* different states each recognized heap block can be in */
+#[derive(PartialEq, Debug, Clone, Copy)]
+#[repr(usize)]
+enum HeapBlockMagic
+{
+    Free     = 0x0deadded,
+    InUse    = 0x0d10c0de,
+    BadMagic = 0xabad1dea
+}
+
+impl HeapBlockMagic
+{
+    pub fn from_usize(value: usize) -> Self
+    {
+        match value
+        {
+            0x0deadded => Self::Free,
+            0x0d10c0de => Self::InUse,
+            _ => Self::BadMagic
+        }
+    }
+}
+
+/* source of a heap block */
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum HeapSource
+{
+    Fixed,      /* the area the heap was given at startup */
+    Temporary   /* taken from the heap's source of memory */
+}
+
+/* to avoid fragmentation, allocate in block sizes of this multiple, including header */
+const HEAP_BLOCK_SIZE: usize = 128;
+
+/* describe the layout of a heap block */
+#[repr(C)]
+pub struct HeapBlock
+{
+    /* with the integritychecks feature, guard the header against overruns from the block below */
+    #[cfg(feature = "integritychecks")]
+    guard: usize,
+    /* heap is a single-link-list to keep it simple and safe */
+    next: Option<*mut HeapBlock>,
+    /* size of this block *including* header */
+    size: usize,
+    /* define block state using magic words */
+    magic: AtomicUsize,
+    /* define the source of the memory */
+    source: HeapSource,
+    /* with the memorypoison feature, record the number of bytes requested so the
+    trailing canary can be found, and place a leading canary right before the contents */
+    #[cfg(feature = "memorypoison")]
+    requested: usize,
+    /* with the heapaudit feature, record the tag of whoever allocated the block and the number of bytes they asked for */
+    #[cfg(feature = "heapaudit")]
+    tag: usize,
+    #[cfg(feature = "heapaudit")]
+    tagged: usize,
+    /* with the heapguard feature, record the owner that allocated the block, the heap's
+    generation when it did, and the generation at which the owner found the block freed */
+    #[cfg(feature = "heapguard")]
+    owner: usize,
+    #[cfg(feature = "heapguard")]
+    generation: usize,
+    #[cfg(feature = "heapguard")]
+    freed: usize,
+    #[cfg(feature = "memorypoison")]
+    canary: usize
+    /* block contents follows... */
+}
+
+/* used to perform integrity checks */
+const HEAP_MAGIC: usize = 0xcafed00d;
+
+/* with the integritychecks feature, every heap block header starts with this word */
+#[cfg(feature = "integritychecks")]
+const HEAP_BLOCK_GUARD: usize = 0x9a4dbeef;
+
+/* with the memorypoison feature, in-use heap blocks are bracketed by this word
+to catch overruns and underruns. the canaries are checked on free and on request */
+#[cfg(feature = "memorypoison")]
+const HEAP_CANARY: usize = 0x5afec0de;
+
+/* tag given to allocations until a tag is set */
+pub const HEAP_TAG_UNTAGGED: usize = 0;
+
+/* with the heapguard feature, freed blocks aren't reused until this many more blocks have been allocated from their heap */
+#[cfg(feature = "heapguard")]
+pub const HEAP_QUARANTINE: usize = 64;
+
+/* with the heapguard feature, a block freed by any core is stamped with this until its owner next sees it */
+#[cfg(feature = "heapguard")]
+const HEAP_FREED_UNSEEN: usize = usize::MAX;
+
+/* a heap block as described in reports of damage and misuse. the owner and generation
+   are only known with the heapguard feature, and are those of its last allocation */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BlockReport
+{
+    pub base: usize,                /* address of the block's header */
+    pub size: usize,                /* size of the block in bytes, including its header */
+    pub requested: usize,           /* bytes asked for when it was allocated */
+    pub owner: Option<usize>,       /* owner that allocated it */
+    pub generation: Option<usize>   /* its heap's allocation generation when it was allocated */
+}
+
+/* misuse of a heap block found with the heapguard feature */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Misuse
+{
+    DoubleFree(BlockReport),            /* block freed while already free */
+    WrongOwner(BlockReport, usize),     /* in-use block claims to belong to another owner than its heap's, given here */
+    BadGeneration(BlockReport, usize)   /* in-use block allocated after its heap's current generation, given here */
+}
+
+/* damage found in a heap's structure */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Damage
+{
+    Heap(usize),    /* heap structure overwritten, with the value found */
+
+    /* block header's guard overwritten: the block's address, the value found, and the address and
+    allocator's tag of the in-use block below it, if any, which most likely overran it. the tag is
+    HEAP_TAG_UNTAGGED without the heapaudit feature */
+    Block(usize, usize, Option<(usize, usize)>)
+}
+
+/* a heap, drawing extra memory from M. initialize it in place with init() */
+#[repr(C)]
+pub struct Heap<M: Memory>
+{
+    /* magic to ensure heap structure hasn't been overwritten */
+    magic: usize,
+    /* pointer to list of in-use and freed blocks */
+    block_list_head: *mut HeapBlock,
+    /* stash a copy of the block header size here */
+    block_header_size: usize,
+    /* with the heapaudit feature, the tag given to allocations */
+    #[cfg(feature = "heapaudit")]
+    tag: usize,
+    /* with the heapguard feature, the number of blocks allocated so far */
+    #[cfg(feature = "heapguard")]
+    generation: usize,
+    /* where to get more memory from */
+    memory: M
+}
+
+/* describe a heap by its totals */
+pub struct HeapStats
+{
+    pub free_total: usize,      /* total free space in bytes */
+    pub alloc_total: usize,     /* total bytes allocated */
+    pub largest_free: usize,    /* largest single free block in bytes */
+    pub largest_alloc: usize    /* largest allocated block in bytes */
+}
+
+/* pretty print the heap's stats */
+impl<M: Memory> fmt::Debug for Heap<M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let stats = self.calculate_stats();
+
+        write!(f, "size: {} alloc'd {} free {} largest alloc'd {} largest free {} magic 0x{:x}",
+            stats.alloc_total + stats.free_total,
+            stats.alloc_total, stats.free_total,
+            stats.largest_alloc, stats.largest_free, self.magic)
+    }
+}
+
+impl<M: Memory> Heap<M>
+{
+    /* initialize this heap area. start off with one giant block
+    covering all of free space, from which other blocks will be carved.
+    this initial block is assumed to be a fixed area of memory.
+    the heap itself may be uninitialized memory before this is called
+    => start = pointer to start of heap area
+       size = number of available bytes in heap */
+    pub fn init(&mut self, start: *mut HeapBlock, size: usize)
+    {
+        /* start with a free block covering the available space */
+        unsafe
+        {
+            let block = start;
+            ptr::write(&mut (*block).magic, AtomicUsize::new(HeapBlockMagic::Free as usize));
+            (*block).size = size;
+            (*block).next = None;
+            (*block).source = HeapSource::Fixed;
+            #[cfg(feature = "integritychecks")]
+            {
+                (*block).guard = HEAP_BLOCK_GUARD;
+            }
+            #[cfg(feature = "heapguard")]
+            {
+                self.generation = 0;
+                (*block).freed = self.generation.wrapping_sub(HEAP_QUARANTINE);
+            }
+
+            self.magic = HEAP_MAGIC;
+            self.block_header_size = mem::size_of::<HeapBlock>();
+            self.block_list_head = block;
+            ptr::write(&mut self.memory, M::default());
+            #[cfg(feature = "heapaudit")]
+            {
+                self.tag = HEAP_TAG_UNTAGGED;
+            }
+        }
+    }
+
+    /* <= the heap's magic word, which is HEAP_MAGIC unless the heap has been overwritten */
+    pub fn magic(&self) -> usize { self.magic }
+
+    /* get or change the tag given to new allocations */
+    #[cfg(feature = "heapaudit")]
+    pub fn tag(&self) -> usize { self.tag }
+    #[cfg(feature = "heapaudit")]
+    pub fn set_tag(&mut self, tag: usize) { self.tag = tag; }
+
+    /* insert a free memory block at the head of the list
+    => base = base address of the memory block to add
+       size = total size of the block, including header that will be automatically added
+    <= OK or error code */
+    pub fn insert_free(&mut self, base: usize, size: usize) -> Result<(), Error>
+    {
+        unsafe
+        {
+            /* craft free block from scratch */
+            let block = base as *mut HeapBlock;
+            ptr::write(&mut (*block).magic, AtomicUsize::new(HeapBlockMagic::Free as usize));
+            (*block).size = size;
+            (*block).next = Some(self.block_list_head);
+            (*block).source = HeapSource::Temporary;
+            #[cfg(feature = "integritychecks")]
+            {
+                (*block).guard = HEAP_BLOCK_GUARD;
+            }
+
+            /* fresh memory has never been allocated, so there's nothing to quarantine */
+            #[cfg(feature = "heapguard")]
+            {
+                (*block).freed = self.generation.wrapping_sub(HEAP_QUARANTINE);
+            }
+
+            /* add the free block to the start of the list */
+            self.block_list_head = block;
+        }
+
+        Ok(())
+    }
+
+    /* free a previously allocated block
+    => to_free = pointer previously returned by alloc()
+    <= OK or failure code */
+    pub fn free<T>(&mut self, to_free: *mut T) -> Result<(), Error>
+    {
+        /* convert this into a raw pointer so we can find the heap block header */
+        let mut ptr = to_free as usize;
+        ptr = ptr - self.block_header_size;
+        let block = ptr as *mut HeapBlock;
+
+        unsafe
+        {
+            /* we should be the only one writing to this metadata, though there
+            will be readers, hence the split in reading and writing */
+            match HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst))
+            {
+                HeapBlockMagic::InUse =>
+                {
+                    #[cfg(feature = "memorypoison")]
+                    self.check_block_canaries(block);
+                    #[cfg(feature = "heapaudit")]
+                    self.memory.untagged((*block).tag, (*block).tagged);
+
+                    /* the block may belong to another core's heap, so leave it to the owner to start its quarantine */
+                    #[cfg(feature = "heapguard")]
+                    {
+                        (*block).freed = HEAP_FREED_UNSEEN;
+                    }
+
+                    (*block).magic.store(HeapBlockMagic::Free as usize, Ordering::SeqCst);
+                    Ok(())
+                },
+                /* if it's not in use, or bad magic, then bail out */
+                HeapBlockMagic::Free =>
+                {
+                    #[cfg(feature = "heapguard")]
+                    self.memory.misused(Misuse::DoubleFree(self.report(block)));
+                    Err(Error::HeapNotInUse)
+                },
+                HeapBlockMagic::BadMagic => Err(Error::HeapBadMagic)
+            }
+        }
+    }
+
+    /* allocate memory for the given object type. the returned pointer skips
+    the heap block header, pointing to the available space,
+    just like malloc() on other platforms.
+    => T = type of object to allocate memory for
+       num = number of objects to allocate for
+    <= pointer to memory, or error code */
+    pub fn alloc<T>(&mut self, num: usize) -> Result<*mut T, Error>
+    {
+        if num == 0
+        {
+            return Err(Error::HeapBadSize);
+        }
+
+        /* perform integrity check */
+        #[cfg(feature = "integritychecks")]
+        {
+            if self.magic != HEAP_MAGIC
+            {
+                return Err(Error::HeapCorrupted);
+            }
+        }
+
+        let mut done = false;
+        let mut extended = false;
+
+        /* calculate size of block required, including header, rounded up to
+        nearest whole heap block multiple */
+        let mut size_req = (mem::size_of::<T>() * num) + self.block_header_size;
+
+        /* leave room for the trailing canary */
+        #[cfg(feature = "memorypoison")]
+        {
+            size_req = size_req + mem::size_of::<usize>();
+        }
+
+        size_req = ((size_req / HEAP_BLOCK_SIZE) + 1) * HEAP_BLOCK_SIZE;
+
+        /* scan all blocks for first free fit */
+        let mut search_block = self.block_list_head;
+        unsafe
+        {
+            while !done
+            {
+                if self.is_available(search_block) == true && (*search_block).size >= size_req
+                {
+                    /* we've got a winner. if the found block is equal size, or only a few bytes
+                    larger than the required size, then take the whole block */
+                    if ((*search_block).size - size_req) < HEAP_BLOCK_SIZE
+                    {
+                        (*search_block).magic.store(HeapBlockMagic::InUse as usize, Ordering::SeqCst);
+                        #[cfg(feature = "memorypoison")]
+                        self.set_canaries(search_block, mem::size_of::<T>() * num);
+                        #[cfg(feature = "heapaudit")]
+                        self.tag_block(search_block, mem::size_of::<T>() * num);
+                        #[cfg(feature = "heapguard")]
+                        self.stamp_block(search_block);
+                        let found_ptr = (search_block as usize) + self.block_header_size;
+                        return Result::Ok(found_ptr as *mut T);
+                    }
+                    else
+                    {
+                        /* carve the end of a large-enough free block off to make a new block.
+                        then add this new block to the start of the list */
+                        (*search_block).size = (*search_block).size - size_req;
+
+                        /* skip to the new (shorter) end of the free block */
+                        let mut found_ptr = (search_block as usize) + (*search_block).size;
+
+                        /* set metadata for newly allocated block */
+                        let alloc_block = found_ptr as *mut HeapBlock;
+                        ptr::write(&mut (*alloc_block).magic, AtomicUsize::new(HeapBlockMagic::InUse as usize));
+                        (*alloc_block).next  = Some(self.block_list_head);
+                        (*alloc_block).size  = size_req;
+                        (*alloc_block).source = (*search_block).source;
+                        #[cfg(feature = "integritychecks")]
+                        {
+                            (*alloc_block).guard = HEAP_BLOCK_GUARD;
+                        }
+                        #[cfg(feature = "memorypoison")]
+                        self.set_canaries(alloc_block, mem::size_of::<T>() * num);
+                        #[cfg(feature = "heapaudit")]
+                        self.tag_block(alloc_block, mem::size_of::<T>() * num);
+                        #[cfg(feature = "heapguard")]
+                        self.stamp_block(alloc_block);
+
+                        /* point the head of the list at new block */
+                        self.block_list_head = alloc_block;
+
+                        /* adjust pointer to skip the header of our new block, and we're done */
+                        found_ptr = found_ptr + self.block_header_size;
+                        return Result::Ok(found_ptr as *mut T);
+                    }
+                }
+
+                /* make sure we don't run off the end of the list.
+                also, attempt to consolidate neighboring blocks to make
+                more bytes available and reduce fragmentation. do this
+                after we've tried searching for available blocks */
+                match (*search_block).next
+                {
+                    None => if self.consolidate() < HEAP_BLOCK_SIZE
+                    {
+                        if extended == false
+                        {
+                            /* if we can't squeeze any more bytes out of the list
+                            then grab a chunk of memory from the heap's source
+                            and add it to the free list */
+                            let (base, size) = match self.memory.grow(size_req)
+                            {
+                                Some(more) => more,
+                                None =>
+                                {
+                                    /* rather than run out of memory, reuse blocks held back to catch double frees */
+                                    #[cfg(feature = "heapguard")]
+                                    {
+                                        if self.lift_quarantine() == true
+                                        {
+                                            search_block = self.block_list_head;
+                                            continue;
+                                        }
+                                    }
+                                    return Result::Err(Error::HeapNoFreeMem);
+                                }
+                            };
+
+                            if self.insert_free(base, size).is_ok()
+                            {
+                                extended = true;
+
+                                /* start the search over, starting with the new block */
+                                search_block = self.block_list_head;
+                            }
+                            else
+                            {
+                                /* if we couldn't insert free block, give up */
+                                done = true;
+                            }
+                        }
+                        else
+                        {
+                            /* can't squeeze any more out of list and we've tried allocating more
+                            memory. reuse any blocks held back to catch double frees, or else give up
+                            at this point, though we shouldn't really end up here */
+                            #[cfg(feature = "heapguard")]
+                            {
+                                if self.lift_quarantine() == true
+                                {
+                                    search_block = self.block_list_head;
+                                    continue;
+                                }
+                            }
+                            done = true;
+                        }
+                    }
+                    else
+                    {
+                        /* start the search over */
+                        search_block = self.block_list_head;
+                    },
+                    Some(n) => search_block = n
+                };
+            }
+        }
+
+        return Result::Err(Error::HeapNoFreeMem);
+    }
+
+    /* give back any free temporary blocks of memory that are no longer needed */
+    pub fn return_unused(&mut self)
+    {
+        /* ensure all blocks are gathered up */
+        loop
+        {
+            if self.consolidate() < HEAP_BLOCK_SIZE
+            {
+                break;
+            }
+        }
+
+        /* search for unused temporary blocks to return */
+        let mut block = self.block_list_head;
+        let mut prev_block: Option<*mut HeapBlock> = None;
+        unsafe
+        {
+            loop
+            {
+                /* take a copy of the link now: the block's header can't be read once it's released */
+                let next = (*block).next;
+                let mut returned = false;
+
+                match ((*block).source, self.is_available(block))
+                {
+                    /* remove the block from the single-linked list if it was successfully released.
+                    the source may refuse blocks it can't take back, such as ones that aren't whole regions.
+                    quarantined blocks are kept so that freeing them again is caught here */
+                    (HeapSource::Temporary, true) =>
+                    {
+                        if self.memory.release(block as usize, (*block).size) == true
+                        {
+                            /* delink the block - do not touch the contents of the
+                            released block: it may be handed to another CPU core
+                            at any time. once release() returns true, it's gone
+                            as far as this heap is concerned. */
+                            match (prev_block, next)
+                            {
+                                (Some(b), _) => (*b).next = next,
+                                (None, Some(n)) => self.block_list_head = n,
+                                (None, None) => ()
+                            };
+                            returned = true;
+                        }
+                    },
+
+                    (_, _) => ()
+                }
+
+                match next
+                {
+                    Some(n) =>
+                    {
+                        /* a returned block is no longer in the list so can't be anyone's predecessor */
+                        if returned == false
+                        {
+                            prev_block = Some(block);
+                        }
+                        block = n;
+                    }
+                    None => break
+                };
+            }
+        }
+    }
+
+    /* pass once over the heap and try to merge adjacent free blocks
+    <= size of the largest block seen, in bytes including header */
+    fn consolidate(&mut self) -> usize
+    {
+        let mut largest_merged_block: usize = 0;
+
+        let mut block = self.block_list_head;
+        unsafe
+        {
+            /* can't merge if we're the last block in the list */
+            while (*block).next.is_some()
+            {
+                let next = (*block).next.unwrap();
+                if self.is_available(block) == true && self.is_available(next) == true &&
+                    (*block).source == (*next).source
+                {
+                    let target_ptr = (block as usize) + (*block).size;
+                    if target_ptr == next as usize
+                    {
+                        /* we're adjacent, we're both free, and we can merge */
+                        let merged_size = (*block).size + (*next).size;
+                        if merged_size > largest_merged_block
+                        {
+                            largest_merged_block = merged_size;
+                        }
+                        (*block).size = merged_size;
+                        (*block).next = (*next).next;
+                    }
+                }
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => break,
+                };
+            }
+
+            /* catch corner case of there being two free blocks: the first on the
+            list is higher than the last block on the list, and they are both free */
+            if self.is_available(self.block_list_head) == true
+            {
+                match (*self.block_list_head).next
+                {
+                    Some(next) =>
+                    {
+                        if self.is_available(next) == true && (*next).source == (*self.block_list_head).source
+                        {
+                            if (next as usize) + (*next).size == self.block_list_head as usize
+                            {
+                                (*next).size = (*next).size + (*self.block_list_head).size;
+                                self.block_list_head = next;
+                                if (*next).size > largest_merged_block
+                                {
+                                    largest_merged_block = (*next).size;
+                                }
+                            }
+                        }
+                    },
+                    _ => ()
+                }
+            }
+        }
+
+        return largest_merged_block;
+    }
+
+    /* <= true if the block is free and can be handed out. with the heapguard feature,
+    freed blocks are held back until HEAP_QUARANTINE more blocks have been allocated */
+    unsafe fn is_available(&mut self, block: *mut HeapBlock) -> bool
+    {
+        if HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)) != HeapBlockMagic::Free
+        {
+            return false;
+        }
+
+        #[cfg(feature = "heapguard")]
+        {
+            /* start the quarantine of blocks freed since this heap last looked */
+            if (*block).freed == HEAP_FREED_UNSEEN
+            {
+                (*block).freed = self.generation;
+            }
+            if self.generation.wrapping_sub((*block).freed) < HEAP_QUARANTINE
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /* stamp a newly allocated block with its owner and generation, and move on a generation
+    => block = block being allocated */
+    #[cfg(feature = "heapguard")]
+    unsafe fn stamp_block(&mut self, block: *mut HeapBlock)
+    {
+        (*block).owner = self.memory.owner();
+        (*block).generation = self.generation;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /* release every quarantined block for reuse
+    <= true if any blocks were released */
+    #[cfg(feature = "heapguard")]
+    fn lift_quarantine(&mut self) -> bool
+    {
+        let mut lifted = false;
+        let mut block = self.block_list_head;
+        unsafe
+        {
+            loop
+            {
+                if HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free &&
+                    self.is_available(block) == false
+                {
+                    (*block).freed = self.generation.wrapping_sub(HEAP_QUARANTINE);
+                    lifted = true;
+                }
+
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => break
+                };
+            }
+        }
+
+        lifted
+    }
+
+    /* describe a block for a report of damage or misuse */
+    #[cfg(feature = "memorypoison")]
+    unsafe fn report(&self, block: *mut HeapBlock) -> BlockReport
+    {
+        BlockReport
+        {
+            base: block as usize,
+            size: (*block).size,
+            requested: (*block).requested,
+            #[cfg(feature = "heapguard")]
+            owner: Some((*block).owner),
+            #[cfg(not(feature = "heapguard"))]
+            owner: None,
+            #[cfg(feature = "heapguard")]
+            generation: Some((*block).generation),
+            #[cfg(not(feature = "heapguard"))]
+            generation: None
+        }
+    }
+
+    /* check an in-use block belongs to this heap's owner and was allocated before now, reporting it if not
+    <= true if the block's stamps add up, or false if not */
+    #[cfg(feature = "heapguard")]
+    unsafe fn check_block_stamps(&self, block: *mut HeapBlock) -> bool
+    {
+        let owner = self.memory.owner();
+        if (*block).owner != owner
+        {
+            self.memory.misused(Misuse::WrongOwner(self.report(block), owner));
+            return false;
+        }
+        if (*block).generation >= self.generation
+        {
+            self.memory.misused(Misuse::BadGeneration(self.report(block), self.generation));
+            return false;
+        }
+
+        true
+    }
+
+    /* blame the heap's current tag for a newly allocated block
+    => block = block being allocated
+       requested = number of bytes requested by the allocation */
+    #[cfg(feature = "heapaudit")]
+    unsafe fn tag_block(&self, block: *mut HeapBlock, requested: usize)
+    {
+        (*block).tag = self.tag;
+        (*block).tagged = requested;
+        self.memory.tagged(self.tag, requested);
+    }
+
+    /* arm the canaries of a newly allocated block
+    => block = block being allocated
+       requested = number of bytes requested by the allocation */
+    #[cfg(feature = "memorypoison")]
+    unsafe fn set_canaries(&self, block: *mut HeapBlock, requested: usize)
+    {
+        (*block).requested = requested;
+        (*block).canary = HEAP_CANARY;
+        let trailing = ((block as usize) + self.block_header_size + requested) as *mut usize;
+        trailing.write_unaligned(HEAP_CANARY);
+    }
+
+    /* check the canaries of an in-use block, reporting either that has been overwritten
+    <= true if the canaries are intact, or false if not */
+    #[cfg(feature = "memorypoison")]
+    unsafe fn check_block_canaries(&self, block: *mut HeapBlock) -> bool
+    {
+        let requested = (*block).requested;
+        let trailing = ((block as usize) + self.block_header_size + requested) as *const usize;
+
+        if (*block).canary != HEAP_CANARY
+        {
+            self.memory.damaged(self.report(block), Some((*block).canary));
+            return false;
+        }
+        if requested > (*block).size || trailing.read_unaligned() != HEAP_CANARY
+        {
+            self.memory.damaged(self.report(block), None);
+            return false;
+        }
+
+        true
+    }
+
+    /* check the canaries of every in-use block in this heap and, with the heapguard feature,
+    that each belongs to this heap's owner and was allocated before now. call this on the heap's owner
+    <= number of blocks found with overwritten canaries or stamps that don't add up */
+    #[cfg(feature = "memorypoison")]
+    pub fn check_canaries(&self) -> usize
+    {
+        let mut damaged = 0;
+        let mut block = self.block_list_head;
+        unsafe
+        {
+            loop
+            {
+                if HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)) == HeapBlockMagic::InUse
+                {
+                    #[cfg(feature = "heapguard")]
+                    let intact = self.check_block_canaries(block) && self.check_block_stamps(block);
+                    #[cfg(not(feature = "heapguard"))]
+                    let intact = self.check_block_canaries(block);
+
+                    if intact == false
+                    {
+                        damaged = damaged + 1;
+                    }
+                }
+
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => break
+                };
+            }
+        }
+
+        damaged
+    }
+
+    /* check this heap's structure and the guard word of every block header, in list order.
+    a block's guard is checked before its link to the next block is followed
+    <= Ok if intact, or a description of the first damage found */
+    #[cfg(feature = "integritychecks")]
+    pub fn check_integrity(&self) -> Result<(), Damage>
+    {
+        if self.magic != HEAP_MAGIC
+        {
+            return Err(Damage::Heap(self.magic));
+        }
+
+        let mut block = self.block_list_head;
+        unsafe
+        {
+            loop
+            {
+                if (*block).guard != HEAP_BLOCK_GUARD
+                {
+                    return Err(self.describe_damaged_block(block));
+                }
+
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => return Ok(())
+                };
+            }
+        }
+    }
+
+    /* find the in-use block sitting right below a damaged block header, which most likely overran it.
+    only blocks before the damaged one in the list are searched, as its link can't be trusted
+    => damaged = block with the overwritten header
+    <= description of the damage */
+    #[cfg(feature = "integritychecks")]
+    unsafe fn describe_damaged_block(&self, damaged: *mut HeapBlock) -> Damage
+    {
+        let mut block = self.block_list_head;
+        while block != damaged
+        {
+            if (block as usize) + (*block).size == damaged as usize &&
+                HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)) == HeapBlockMagic::InUse
+            {
+                #[cfg(feature = "heapaudit")]
+                let tag = (*block).tag;
+                #[cfg(not(feature = "heapaudit"))]
+                let tag = HEAP_TAG_UNTAGGED;
+                return Damage::Block(damaged as usize, (*damaged).guard, Some((block as usize, tag)));
+            }
+
+            match (*block).next
+            {
+                Some(n) => block = n,
+                None => break
+            };
+        }
+
+        Damage::Block(damaged as usize, (*damaged).guard, None)
+    }
+
+    /* generate a block of statistics describing the heap. blocks with bad magic aren't counted */
+    pub fn calculate_stats(&self) -> HeapStats
+    {
+        let mut free_total = 0;
+        let mut alloc_total = 0;
+        let mut largest_free = 0;
+        let mut largest_alloc = 0;
+
+        let mut done = false;
+        let mut block = self.block_list_head;
+        unsafe
+        {
+            while !done
+            {
+                let size = (*block).size;
+                match HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst))
+                {
+                    HeapBlockMagic::InUse =>
+                    {
+                        alloc_total = alloc_total + size;
+                        if size > largest_alloc
+                        {
+                            largest_alloc = size;
+                        }
+                    },
+                    HeapBlockMagic::Free =>
+                    {
+                        free_total = free_total + size;
+                        if size > largest_free
+                        {
+                            largest_free = size;
+                        }
+                    },
+                    HeapBlockMagic::BadMagic => ()
+                };
+
+                match (*block).next
+                {
+                    None => done = true,
+                    Some(b) => block = b
+                };
+            }
+        }
+
+        HeapStats
+        {
+            free_total,
+            alloc_total,
+            largest_alloc,
+            largest_free
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::vec::Vec;
+    use std::boxed::Box;
+    use std::mem::MaybeUninit;
+    #[cfg(feature = "heapguard")]
+    use std::cell::RefCell;
+
+    /* owner of the test heaps */
+    #[cfg(feature = "heapguard")]
+    const HOST_OWNER: usize = 7;
+
+    /* hand out leaked host memory, keeping count of what's given out and taken back,
+    unless told to refuse, and with the heapguard feature, note any misuse reported */
+    #[derive(Default)]
+    struct HostMemory
+    {
+        grown: usize,
+        released: usize,
+        refuse: bool,
+        #[cfg(feature = "heapguard")]
+        misuses: RefCell<Vec<Misuse>>
+    }
+
+    impl Memory for HostMemory
+    {
+        fn grow(&mut self, size: usize) -> Option<(usize, usize)>
+        {
+            if self.refuse == true
+            {
+                return None;
+            }
+
+            self.grown = self.grown + 1;
+            let area: &'static mut [u128] = Box::leak(std::vec![0u128; size / 16].into_boxed_slice());
+            Some((area.as_mut_ptr() as usize, size))
+        }
+
+        fn release(&mut self, _base: usize, _size: usize) -> bool
+        {
+            self.released = self.released + 1;
+            true
+        }
+
+        #[cfg(feature = "heapguard")]
+        fn misused(&self, misuse: Misuse)
+        {
+            self.misuses.borrow_mut().push(misuse);
+        }
+
+        #[cfg(feature = "heapguard")]
+        fn owner(&self) -> usize
+        {
+            HOST_OWNER
+        }
+    }
+
+    /* create a heap with the given number of bytes of fixed memory */
+    fn heap(size: usize) -> Box<Heap<HostMemory>>
+    {
+        let area: &'static mut [u128] = Box::leak(std::vec![0u128; size / 16].into_boxed_slice());
+        let mut heap: Box<MaybeUninit<Heap<HostMemory>>> = Box::new(MaybeUninit::uninit());
+        unsafe
+        {
+            (*heap.as_mut_ptr()).init(area.as_mut_ptr() as *mut HeapBlock, size);
+            Box::from_raw(Box::into_raw(heap) as *mut Heap<HostMemory>)
+        }
+    }
+
+    #[test]
+    fn alloc_and_free()
+    {
+        let mut heap = heap(4096);
+        let a = heap.alloc::<u32>(2).unwrap();
+        let b = heap.alloc::<u32>(2).unwrap();
+        assert_ne!(a, b);
+        unsafe
+        {
+            a.write_bytes(0xff, 2);
+            b.write_bytes(0xee, 2);
+            assert_eq!(*a.add(1), u32::MAX);
+        }
+
+        assert_eq!(heap.calculate_stats().alloc_total, 2 * HEAP_BLOCK_SIZE);
+        heap.free(a).unwrap();
+        heap.free(b).unwrap();
+        assert_eq!(heap.calculate_stats().alloc_total, 0);
+        assert_eq!(heap.calculate_stats().free_total, 4096);
+    }
+
+    #[test]
+    fn refuses_bad_frees_and_sizes()
+    {
+        let mut heap = heap(4096);
+        let a = heap.alloc::<u8>(16).unwrap();
+        heap.free(a).unwrap();
+        assert_eq!(heap.free(a), Err(Error::HeapNotInUse));
+        assert_eq!(heap.alloc::<u8>(0), Err(Error::HeapBadSize));
+    }
+
+    /* with the heapguard feature, freed blocks are quarantined and the heap grows instead */
+    #[test]
+    #[cfg(not(feature = "heapguard"))]
+    fn reuses_freed_space()
+    {
+        let mut heap = heap(1024);
+        let mut blocks = Vec::new();
+        for _ in 0..20
+        {
+            let block = heap.alloc::<u8>(64).unwrap();
+            heap.free(block).unwrap();
+            blocks.push(block);
+        }
+
+        assert_eq!(heap.memory.grown, 0);
+        assert_eq!(heap.calculate_stats().free_total, 1024);
+    }
+
+    #[test]
+    fn grows_and_returns_temporary_memory()
+    {
+        let mut heap = heap(1024);
+        let big = heap.alloc::<u8>(8192).unwrap();
+        assert_eq!(heap.memory.grown, 1);
+
+        heap.free(big).unwrap();
+        #[cfg(feature = "heapguard")]
+        heap.lift_quarantine();
+        heap.return_unused();
+        assert_eq!(heap.memory.released, 1);
+        assert_eq!(heap.calculate_stats().free_total, 1024);
+    }
+
+    /* <= the header of an allocated block */
+    #[cfg(feature = "heapguard")]
+    fn header<T>(heap: &Heap<HostMemory>, ptr: *mut T) -> *mut HeapBlock
+    {
+        (ptr as usize - heap.block_header_size) as *mut HeapBlock
+    }
+
+    #[test]
+    #[cfg(feature = "heapguard")]
+    fn quarantines_freed_blocks()
+    {
+        let mut heap = heap(32768);
+        let a = heap.alloc::<u8>(16).unwrap();
+        heap.free(a).unwrap();
+
+        /* the block is held back, so freeing it again after another allocation is caught */
+        let b = heap.alloc::<u8>(16).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(heap.free(a), Err(Error::HeapNotInUse));
+        match heap.memory.misuses.borrow().as_slice()
+        {
+            [Misuse::DoubleFree(report)] =>
+            {
+                assert_eq!(report.base, header(&heap, a) as usize);
+                assert_eq!(report.requested, 16);
+                assert_eq!(report.owner, Some(HOST_OWNER));
+                assert_eq!(report.generation, Some(0));
+            },
+            other => panic!("unexpected misuse reports: {:?}", other)
+        }
+
+        /* and reused once enough blocks have been allocated since it was freed, counting b */
+        for _ in 1..HEAP_QUARANTINE
+        {
+            assert_ne!(heap.alloc::<u8>(16).unwrap(), a);
+        }
+        assert_eq!(heap.alloc::<u8>(16).unwrap(), a);
+        assert_eq!(heap.memory.grown, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "heapguard")]
+    fn lifts_quarantine_rather_than_run_out()
+    {
+        let mut heap = heap(1024);
+        heap.memory.refuse = true;
+        for _ in 0..20
+        {
+            let block = heap.alloc::<u8>(64).unwrap();
+            heap.free(block).unwrap();
+        }
+
+        assert_eq!(heap.calculate_stats().free_total, 1024);
+        assert_eq!(heap.memory.misuses.borrow().len(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "heapguard")]
+    fn reports_blocks_with_bad_stamps()
+    {
+        let mut heap = heap(4096);
+        let a = heap.alloc::<u8>(16).unwrap();
+        let b = heap.alloc::<u8>(16).unwrap();
+        assert_eq!(heap.check_canaries(), 0);
+
+        unsafe
+        {
+            (*header(&heap, a)).owner = HOST_OWNER + 1;
+            (*header(&heap, b)).generation = HEAP_QUARANTINE;
+        }
+        assert_eq!(heap.check_canaries(), 2);
+
+        let misuses = heap.memory.misuses.borrow();
+        assert!(misuses.iter().any(|m| matches!(m, Misuse::WrongOwner(r, HOST_OWNER) if r.base == header(&heap, a) as usize)));
+        assert!(misuses.iter().any(|m| matches!(m, Misuse::BadGeneration(r, 2) if r.base == header(&heap, b) as usize)));
+    }
+}
@@ -0,0 +1,78 @@
+/* diosix hypervisor core algorithms
+ *
+ * The data structures at the heart of the hypervisor that don't need
+ * to touch the hardware to do their job: the sorted list of free
+ * physical memory regions, the per-core heap's block list, the queues
+ * of virtual cores waiting to run, the capsule lifecycle state
+ * machine, the virtio queues shared by device models, the checks
+ * made on the buffers capsules pass in hypercalls, the reader of the
+ * host's flattened device tree, the bounded list of poisoned memory
+ * ranges, and the write-protected ranges of dirty page tracking.
+ * Anything they need from the platform or the rest of the hypervisor,
+ * such as more memory for the heap or the current time, is asked for
+ * through a small trait that the hypervisor implements.
+ *
+ * That keeps this crate free of platform code, so it can be built and
+ * unit tested on the host with cargo test, as well as being built into
+ * the hypervisor for its bare-metal targets:
+ *
+ * cd src/hvalgo && cargo test
+ *
+ * This crate is no_std, though it needs an allocator.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+#![cfg_attr(not(test), no_std)]
+
+/* this code follows the hypervisor's style, which spells out assignments, comparisons, and matches in full */
+#![allow(clippy::assign_op_pattern, clippy::bool_comparison, clippy::needless_return, clippy::single_match,
+    clippy::collapsible_if, clippy::collapsible_match, clippy::len_zero, clippy::new_without_default)]
+
+extern crate alloc;
+
+pub mod regions;
+pub mod heap;
+pub mod queues;
+pub mod lifecycle;
+pub mod virtqueue;
+pub mod hcargs;
+pub mod fdt;
+pub mod poison;
+pub mod dirty;
+
+/* how things can go wrong. the hypervisor converts these into its own error codes */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error
+{
+    /* region list errors */
+    RegionNoMatch,
+    RegionCollision,
+
+    /* heap errors */
+    HeapBadSize,
+    HeapNoFreeMem,
+    HeapNotInUse,
+    HeapBadMagic,
+    HeapCorrupted,
+
+    /* virtio queue errors */
+    VirtqueueBadLayout,
+    VirtqueueBadDescriptor,
+    VirtqueueBadIndex,
+
+    /* hypercall argument errors */
+    ArgMissing,
+    ArgBadValue,
+    ArgBadLength,
+    ArgBadPointer,
+    ArgMisaligned,
+    ArgReadOnly,
+
+    /* device tree errors */
+    DeviceTreeBadHeader,
+    DeviceTreeBadStructure,
+    DeviceTreeBadCells
+}
@@ -0,0 +1,134 @@
+/* diosix capsule lifecycle state machine
+ *
+ * A capsule moves between a small number of states as it's paused,
+ * restarted, and killed. Every change is made by applying an event to
+ * the capsule's lifecycle, which looks up the event in a table of
+ * allowed transitions. Events that aren't allowed in the capsule's
+ * current state are refused and leave the state as it is.
+ *
+ * Some events are allowed to leave a capsule in the state it's already
+ * in. This is because each of a capsule's virtual cores applies the same
+ * event as it notices the capsule is dying, restarting, or pausing.
+ *
+ * The hypervisor wraps this with its error codes and its listeners for
+ * state changes.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CapsuleState
+{
+    Valid,      /* ok to run */
+    Dying,      /* remove vcores and kill when there are none left */
+    Restarting, /* remove vcores and recreate vcores with initial params */
+    Paused      /* park vcores with their state intact until resumed or killed */
+}
+
+/* things that can happen to a capsule */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Event
+{
+    Kill,       /* tear the capsule down */
+    Restart,    /* tear down the capsule's vcores so they can be recreated */
+    Restarted,  /* the capsule's vcores have been recreated */
+    Pause,      /* freeze the capsule, such as when it crashes */
+    Resume      /* unfreeze the capsule */
+}
+
+/* every allowed transition: the state a capsule must be in, the event, and the state it moves to */
+const TRANSITIONS: [(CapsuleState, Event, CapsuleState); 9] =
+[
+    (CapsuleState::Valid,       Event::Kill,        CapsuleState::Dying),
+    (CapsuleState::Paused,      Event::Kill,        CapsuleState::Dying),
+    (CapsuleState::Dying,       Event::Kill,        CapsuleState::Dying),
+    (CapsuleState::Valid,       Event::Restart,     CapsuleState::Restarting),
+    (CapsuleState::Restarting,  Event::Restart,     CapsuleState::Restarting),
+    (CapsuleState::Restarting,  Event::Restarted,   CapsuleState::Valid),
+    (CapsuleState::Valid,       Event::Pause,       CapsuleState::Paused),
+    (CapsuleState::Paused,      Event::Pause,       CapsuleState::Paused),
+    (CapsuleState::Paused,      Event::Resume,      CapsuleState::Valid)
+];
+
+/* a change of state made by an event */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Transition
+{
+    pub from: CapsuleState,
+    pub to: CapsuleState
+}
+
+/* a capsule's current state, which can only be changed by applying events */
+pub struct Lifecycle
+{
+    state: CapsuleState
+}
+
+impl Lifecycle
+{
+    /* capsules start out ready to run */
+    pub fn new() -> Lifecycle
+    {
+        Lifecycle { state: CapsuleState::Valid }
+    }
+
+    /* <= the capsule's current state */
+    pub fn state(&self) -> CapsuleState { self.state }
+
+    /* apply an event to a capsule, changing its state if the event is allowed
+       => event = what's happening to the capsule
+       <= the transition made, or None if the event isn't allowed in the current state */
+    pub fn apply(&mut self, event: Event) -> Option<Transition>
+    {
+        let to = match TRANSITIONS.iter().find(|(from, on, _)| *from == self.state && *on == event)
+        {
+            Some((_, _, to)) => *to,
+            None => return None
+        };
+
+        let transition = Transition { from: self.state, to };
+        self.state = to;
+        Some(transition)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn dying_capsules_stay_dead()
+    {
+        let mut lifecycle = Lifecycle::new();
+        assert_eq!(lifecycle.apply(Event::Kill), Some(Transition { from: CapsuleState::Valid, to: CapsuleState::Dying }));
+        assert_eq!(lifecycle.apply(Event::Kill), Some(Transition { from: CapsuleState::Dying, to: CapsuleState::Dying }));
+        assert_eq!(lifecycle.apply(Event::Restart), None);
+        assert_eq!(lifecycle.apply(Event::Resume), None);
+        assert_eq!(lifecycle.state(), CapsuleState::Dying);
+    }
+
+    #[test]
+    fn restart_completes_once()
+    {
+        let mut lifecycle = Lifecycle::new();
+        assert!(lifecycle.apply(Event::Restart).is_some());
+        assert!(lifecycle.apply(Event::Restart).is_some());
+        assert_eq!(lifecycle.apply(Event::Restarted), Some(Transition { from: CapsuleState::Restarting, to: CapsuleState::Valid }));
+        assert_eq!(lifecycle.apply(Event::Restarted), None);
+    }
+
+    #[test]
+    fn paused_capsules_resume_or_die()
+    {
+        let mut lifecycle = Lifecycle::new();
+        assert_eq!(lifecycle.apply(Event::Resume), None);
+        assert!(lifecycle.apply(Event::Pause).is_some());
+        assert_eq!(lifecycle.apply(Event::Restart), None);
+        assert_eq!(lifecycle.apply(Event::Resume), Some(Transition { from: CapsuleState::Paused, to: CapsuleState::Valid }));
+        assert!(lifecycle.apply(Event::Pause).is_some());
+        assert_eq!(lifecycle.apply(Event::Kill), Some(Transition { from: CapsuleState::Paused, to: CapsuleState::Dying }));
+    }
+}
@@ -0,0 +1,198 @@
+/* diosix bounded list of poisoned memory ranges
+ *
+ * With the memorypoison feature, freed physical memory is filled with a
+ * known pattern, and the pattern is checked when the memory is next
+ * allocated. The ranges that hold the pattern are listed here. This
+ * list is consulted while the physical memory allocator's locks are
+ * held, and the heap may be asking that allocator for more memory at
+ * the time, so the list lives in a fixed-size array and never allocates.
+ *
+ * Ranges that touch are merged as they're added. When the list is full,
+ * or a range being taken would split one in two and there's no room
+ * for the second half, the range that doesn't fit simply isn't tracked:
+ * its poison goes unchecked, which loses coverage but never raises a
+ * false alarm.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+/* most ranges tracked at once */
+pub const POISONED_RANGES_MAX: usize = 64;
+
+/* the poisoned ranges, each as base and end addresses, held in the first count entries */
+pub struct PoisonedRanges
+{
+    ranges: [(usize, usize); POISONED_RANGES_MAX],
+    count: usize
+}
+
+impl PoisonedRanges
+{
+    /* create an empty list */
+    pub const fn new() -> PoisonedRanges
+    {
+        PoisonedRanges
+        {
+            ranges: [(0, 0); POISONED_RANGES_MAX],
+            count: 0
+        }
+    }
+
+    /* <= number of ranges being tracked */
+    pub fn tracked(&self) -> usize { self.count }
+
+    /* start tracking a range, merging it with any it touches
+       => base, end = bounds of the poisoned range
+       <= true if the range is tracked, or false if the list is full */
+    pub fn add(&mut self, base: usize, end: usize) -> bool
+    {
+        if base >= end
+        {
+            return true;
+        }
+
+        for index in 0..self.count
+        {
+            let (b, e) = self.ranges[index];
+            if end == b
+            {
+                self.ranges[index] = (base, e);
+                return true;
+            }
+            if base == e
+            {
+                self.ranges[index] = (b, end);
+                return true;
+            }
+        }
+
+        if self.count == POISONED_RANGES_MAX
+        {
+            return false;
+        }
+        self.ranges[self.count] = (base, end);
+        self.count = self.count + 1;
+        true
+    }
+
+    /* stop tracking the parts of any ranges that fall within the given bounds, passing each
+       part to the given function to check first. whatever lies outside the bounds stays tracked
+       => base, end = bounds of the memory being reused
+          check = called with the base and end of each poisoned part within the bounds */
+    pub fn take<F: FnMut(usize, usize)>(&mut self, base: usize, end: usize, mut check: F)
+    {
+        let mut index = 0;
+        while index < self.count
+        {
+            let (b, e) = self.ranges[index];
+            let lower = core::cmp::max(b, base);
+            let upper = core::cmp::min(e, end);
+            if lower >= upper
+            {
+                index = index + 1;
+                continue;
+            }
+
+            check(lower, upper);
+
+            match (b < lower, upper < e)
+            {
+                (true, true) =>
+                {
+                    /* split in two. the upper half is dropped if there's no room for it */
+                    self.ranges[index] = (b, lower);
+                    if self.count < POISONED_RANGES_MAX
+                    {
+                        self.ranges[self.count] = (upper, e);
+                        self.count = self.count + 1;
+                    }
+                    index = index + 1;
+                },
+                (true, false) =>
+                {
+                    self.ranges[index] = (b, lower);
+                    index = index + 1;
+                },
+                (false, true) =>
+                {
+                    self.ranges[index] = (upper, e);
+                    index = index + 1;
+                },
+                (false, false) =>
+                {
+                    /* fill the gap with the last range, and check that one next */
+                    self.count = self.count - 1;
+                    self.ranges[index] = self.ranges[self.count];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn taken(list: &mut PoisonedRanges, base: usize, end: usize) -> Vec<(usize, usize)>
+    {
+        let mut parts = Vec::new();
+        list.take(base, end, |b, e| parts.push((b, e)));
+        parts.sort();
+        parts
+    }
+
+    #[test]
+    fn merges_touching_ranges()
+    {
+        let mut list = PoisonedRanges::new();
+        assert!(list.add(0x1000, 0x2000));
+        assert!(list.add(0x2000, 0x3000));
+        assert!(list.add(0x0800, 0x1000));
+        assert_eq!(list.tracked(), 1);
+        assert_eq!(taken(&mut list, 0, 0x10000), [(0x0800, 0x3000)]);
+        assert_eq!(list.tracked(), 0);
+    }
+
+    #[test]
+    fn keeps_what_lies_outside()
+    {
+        let mut list = PoisonedRanges::new();
+        list.add(0x1000, 0x5000);
+        assert_eq!(taken(&mut list, 0x2000, 0x3000), [(0x2000, 0x3000)]);
+        assert_eq!(list.tracked(), 2);
+        assert_eq!(taken(&mut list, 0x0000, 0x10000), [(0x1000, 0x2000), (0x3000, 0x5000)]);
+        assert_eq!(taken(&mut list, 0x0000, 0x10000), []);
+    }
+
+    #[test]
+    fn takes_every_overlapping_range()
+    {
+        let mut list = PoisonedRanges::new();
+        list.add(0x1000, 0x2000);
+        list.add(0x3000, 0x4000);
+        list.add(0x5000, 0x6000);
+        assert_eq!(taken(&mut list, 0x1800, 0x5800), [(0x1800, 0x2000), (0x3000, 0x4000), (0x5000, 0x5800)]);
+        assert_eq!(taken(&mut list, 0, 0x10000), [(0x1000, 0x1800), (0x5800, 0x6000)]);
+    }
+
+    #[test]
+    fn stays_bounded_when_full()
+    {
+        let mut list = PoisonedRanges::new();
+        for n in 0..POISONED_RANGES_MAX
+        {
+            assert!(list.add(n * 0x2000, (n * 0x2000) + 0x1000));
+        }
+        assert!(list.add(0x1000000, 0x1001000) == false);
+        assert_eq!(list.tracked(), POISONED_RANGES_MAX);
+
+        /* splitting a range with no room to spare drops the upper half rather than growing */
+        assert_eq!(taken(&mut list, 0x400, 0x800), [(0x400, 0x800)]);
+        assert_eq!(list.tracked(), POISONED_RANGES_MAX);
+        assert_eq!(taken(&mut list, 0, 0x1000), [(0, 0x400)]);
+    }
+}
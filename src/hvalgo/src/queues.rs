@@ -0,0 +1,464 @@
+/* diosix virtual CPU core scheduling queues
+ *
+ * Which waiting virtual core runs next is left to a policy, which keeps
+ * a queue of waiting virtual cores in whatever order it sees fit. The
+ * policies here work with anything that can describe itself as a
+ * virtual core through the Schedulable trait, and get the time and
+ * their tunables through the Environment trait, so that they can be
+ * tested on the host. The hypervisor's scheduler picks the policy and
+ * moves virtual cores between physical cores' queues.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::collections::vec_deque::VecDeque;
+
+/* how a waiting virtual core is to be treated */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Urgency
+{
+    High,
+    Normal,
+    Deadline    /* guaranteed a budget of CPU time every period, then scheduled as Normal */
+}
+
+/* what a policy needs to know about a virtual core */
+pub trait Schedulable: Send
+{
+    /* the kind of physical core a virtual core can prefer to run on */
+    type Class: Copy + PartialEq;
+
+    fn urgency(&self) -> Urgency;
+    fn capsule_id(&self) -> usize;
+    fn vcore_id(&self) -> usize;
+    fn class(&self) -> Self::Class;
+
+    /* <= timer ticks of a deadline virtual core's budget left in its current period, or None if it has no deadline */
+    fn deadline_budget_left(&mut self, now: u64, frequency: u64) -> Option<u64>;
+
+    /* <= timer value when a deadline virtual core's current period ends, or None if it has no deadline */
+    fn deadline_period_end(&self, frequency: u64) -> Option<u64>;
+}
+
+/* what a policy needs to know about the world */
+pub trait Environment: Send
+{
+    /* <= the current timer value and the timer's frequency, or None if there's no timer */
+    fn timer_now(&self) -> Option<(u64, u64)>;
+
+    /* <= number of timeslices high priority virtual cores can run back to back before a normal one gets a turn */
+    fn high_priority_run(&self) -> u64;
+}
+
+/* a set of queues of virtual cores waiting to run, and the rules for picking the next one */
+pub trait Policy<T: Schedulable>: Send
+{
+    /* add the given virtual core to the waiting queues */
+    fn queue(&mut self, to_queue: T);
+
+    /* remove the virtual core that should run next, or None if none are waiting */
+    fn dequeue(&mut self) -> Option<T>;
+
+    /* remove the virtual core that should run next on a physical core of the given class.
+    policies that don't take core classes into account can pick the next one as usual */
+    fn dequeue_preferring(&mut self, _class: T::Class) -> Option<T>
+    {
+        self.dequeue()
+    }
+
+    /* remove a waiting virtual core belonging to the given capsule, or None if none are waiting.
+    this is used to co-schedule gang vcores, so it should ignore the policy's usual order */
+    fn dequeue_capsule(&mut self, cid: usize) -> Option<T>;
+
+    /* remove the given waiting virtual core, or None if it isn't waiting. this is used to
+    switch in a virtual core that's been sent an IPI, so it should ignore the policy's usual order */
+    fn dequeue_vcore(&mut self, cid: usize, vid: usize) -> Option<T>;
+
+    /* <= the total number of virtual cores queued */
+    fn total_queued(&self) -> usize;
+}
+
+/* maintain a simple two-level round-robin scheduler per physical CPU core. we can make it more fancy later.
+the hypervisor tries to dish out physical CPU time fairly among capsules, and let the
+capsule supervisors work out how best to allocate their time to userspace code.
+picking the next virtual CPU core to run should be O(1) or as close as possible to it.
+deadline virtual cores with budget left are picked first, earliest period end first,
+which costs a scan of the deadline queue. that queue is expected to be short */
+pub struct RoundRobin<T: Schedulable, E: Environment>
+{
+    deadline: VecDeque<T>,
+    high: VecDeque<T>,
+    low: VecDeque<T>,
+    high_timeslices: u64,
+    environment: E
+}
+
+impl<T: Schedulable, E: Environment> RoundRobin<T, E>
+{
+    /* initialize a new set of scheduler queues */
+    pub fn new(environment: E) -> RoundRobin<T, E>
+    {
+        RoundRobin
+        {
+            deadline: VecDeque::new(),
+            high: VecDeque::new(),
+            low: VecDeque::new(),
+            high_timeslices: 0,
+            environment
+        }
+    }
+
+    /* account for a virtual core about to run: running a normal virtual core resets the count
+    of timeslices spent on high priority virtual cores, and running a high priority one adds to it */
+    pub fn ran(&mut self, urgency: Urgency)
+    {
+        match urgency
+        {
+            Urgency::Normal => self.high_timeslices = 0,
+            Urgency::High => self.high_timeslices = self.high_timeslices + 1,
+            Urgency::Deadline => ()
+        };
+    }
+
+    /* <= true if a normal virtual core has been waiting for too long behind high priority ones */
+    fn normal_starved(&self) -> bool
+    {
+        self.high_timeslices > self.environment.high_priority_run()
+    }
+
+    /* remove the deadline virtual core with budget left whose period ends soonest.
+    deadline virtual cores that have used up their budgets are moved to the normal queue
+    until they are next queued. returns selected virtual core or None if none are eligible */
+    fn dequeue_deadline(&mut self) -> Option<T>
+    {
+        if self.deadline.len() == 0
+        {
+            return None;
+        }
+
+        /* without a timer, budgets can't be tracked, so treat them all as normal */
+        let (now, frequency) = match self.environment.timer_now()
+        {
+            Some(t) => t,
+            None => return self.deadline.pop_front()
+        };
+
+        let mut earliest: Option<(usize, u64)> = None;
+        let mut index = 0;
+        while index < self.deadline.len()
+        {
+            let vcore = &mut self.deadline[index];
+            match vcore.deadline_budget_left(now, frequency)
+            {
+                Some(left) if left > 0 =>
+                {
+                    let end = vcore.deadline_period_end(frequency).unwrap_or(u64::MAX);
+                    match earliest
+                    {
+                        Some((_, earliest_end)) if earliest_end <= end => (),
+                        _ => earliest = Some((index, end))
+                    }
+                    index = index + 1;
+                },
+                _ => if let Some(exhausted) = self.deadline.remove(index)
+                {
+                    self.low.push_back(exhausted);
+                }
+            }
+        }
+
+        match earliest
+        {
+            Some((index, _)) => self.deadline.remove(index),
+            None => None
+        }
+    }
+}
+
+impl<T: Schedulable, E: Environment> Policy<T> for RoundRobin<T, E>
+{
+    /* add the given virtual core to the appropriate waiting queue. put it to the back
+    so that other virtual cores get a chance to run */
+    fn queue(&mut self, to_queue: T)
+    {
+        match to_queue.urgency()
+        {
+            Urgency::High => self.high.push_back(to_queue),
+            Urgency::Normal => self.low.push_back(to_queue),
+            Urgency::Deadline => self.deadline.push_back(to_queue)
+        }
+    }
+
+    /* remove a virtual core from the waiting list queues, selected by priority with safeguards to
+    prevent CPU time starvation. Returns selected virtual core or None for no other virtual cores waiting */
+    fn dequeue(&mut self) -> Option<T>
+    {
+        /* deadline virtual cores with budget left take precedence */
+        if let Some(t) = self.dequeue_deadline()
+        {
+            return Some(t);
+        }
+
+        /* has a normal virtual core been waiting for ages? */
+        if self.normal_starved()
+        {
+            match self.low.pop_front()
+            {
+                Some(t) => return Some(t),
+                None => ()
+            };
+        }
+
+        /* check the high priority queue for anything waiting.
+        if not, then try the normal priority queue */
+        match self.high.pop_front()
+        {
+            Some(t) => Some(t),
+            None => self.low.pop_front()
+        }
+    }
+
+    /* remove a virtual core from the waiting list queues, as dequeue() does, though within each priority
+    pick one that prefers the given class of physical core, if any. deadline virtual cores are picked by
+    their deadlines alone. returns selected virtual core or None for no other virtual cores waiting */
+    fn dequeue_preferring(&mut self, class: T::Class) -> Option<T>
+    {
+        if let Some(t) = self.dequeue_deadline()
+        {
+            return Some(t);
+        }
+
+        /* follow dequeue()'s order to avoid starving normal virtual cores */
+        let mut order = match self.normal_starved()
+        {
+            true => [&mut self.low, &mut self.high],
+            false => [&mut self.high, &mut self.low]
+        };
+
+        for queue in order.iter_mut()
+        {
+            if let Some(index) = queue.iter().position(|v| v.class() == class)
+            {
+                return queue.remove(index);
+            }
+        }
+
+        self.dequeue()
+    }
+
+    /* remove the first waiting virtual core belonging to the given capsule, regardless of priority.
+    this is used to co-schedule gang vcores. returns the virtual core or None if none are waiting */
+    fn dequeue_capsule(&mut self, cid: usize) -> Option<T>
+    {
+        for queue in [&mut self.deadline, &mut self.high, &mut self.low].iter_mut()
+        {
+            if let Some(index) = queue.iter().position(|v| v.capsule_id() == cid)
+            {
+                return queue.remove(index);
+            }
+        }
+
+        None
+    }
+
+    fn dequeue_vcore(&mut self, cid: usize, vid: usize) -> Option<T>
+    {
+        for queue in [&mut self.deadline, &mut self.high, &mut self.low].iter_mut()
+        {
+            if let Some(index) = queue.iter().position(|v| v.capsule_id() == cid && v.vcore_id() == vid)
+            {
+                return queue.remove(index);
+            }
+        }
+
+        None
+    }
+
+    /* return the total number of virtual cores queued */
+    fn total_queued(&self) -> usize
+    {
+        self.deadline.len() + self.high.len() + self.low.len()
+    }
+}
+
+/* run virtual cores strictly in the order they were queued, regardless of priority. deadline
+virtual cores get no guarantees, so this is mostly useful as a baseline to compare others with */
+pub struct Fifo<T: Schedulable>
+{
+    waiting: VecDeque<T>
+}
+
+impl<T: Schedulable> Fifo<T>
+{
+    pub fn new() -> Fifo<T>
+    {
+        Fifo { waiting: VecDeque::new() }
+    }
+}
+
+impl<T: Schedulable> Policy<T> for Fifo<T>
+{
+    fn queue(&mut self, to_queue: T)
+    {
+        self.waiting.push_back(to_queue);
+    }
+
+    fn dequeue(&mut self) -> Option<T>
+    {
+        self.waiting.pop_front()
+    }
+
+    fn dequeue_capsule(&mut self, cid: usize) -> Option<T>
+    {
+        match self.waiting.iter().position(|v| v.capsule_id() == cid)
+        {
+            Some(index) => self.waiting.remove(index),
+            None => None
+        }
+    }
+
+    fn dequeue_vcore(&mut self, cid: usize, vid: usize) -> Option<T>
+    {
+        match self.waiting.iter().position(|v| v.capsule_id() == cid && v.vcore_id() == vid)
+        {
+            Some(index) => self.waiting.remove(index),
+            None => None
+        }
+    }
+
+    fn total_queued(&self) -> usize
+    {
+        self.waiting.len()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /* a virtual core with a budget of ticks left and a period end, if it has a deadline */
+    struct Vcore
+    {
+        cid: usize,
+        vid: usize,
+        urgency: Urgency,
+        efficient: bool,
+        deadline: Option<(u64, u64)>
+    }
+
+    impl Vcore
+    {
+        fn new(cid: usize, urgency: Urgency) -> Vcore
+        {
+            Vcore { cid, vid: 0, urgency, efficient: false, deadline: None }
+        }
+    }
+
+    impl Schedulable for Vcore
+    {
+        type Class = bool;
+        fn urgency(&self) -> Urgency { self.urgency }
+        fn capsule_id(&self) -> usize { self.cid }
+        fn vcore_id(&self) -> usize { self.vid }
+        fn class(&self) -> bool { self.efficient }
+        fn deadline_budget_left(&mut self, _now: u64, _frequency: u64) -> Option<u64> { self.deadline.map(|(left, _)| left) }
+        fn deadline_period_end(&self, _frequency: u64) -> Option<u64> { self.deadline.map(|(_, end)| end) }
+    }
+
+    struct World;
+
+    impl Environment for World
+    {
+        fn timer_now(&self) -> Option<(u64, u64)> { Some((0, 1000)) }
+        fn high_priority_run(&self) -> u64 { 2 }
+    }
+
+    fn next(queues: &mut dyn Policy<Vcore>) -> Option<usize>
+    {
+        queues.dequeue().map(|v| v.cid)
+    }
+
+    #[test]
+    fn high_priority_first()
+    {
+        let mut queues = RoundRobin::new(World);
+        queues.queue(Vcore::new(1, Urgency::Normal));
+        queues.queue(Vcore::new(2, Urgency::High));
+        queues.queue(Vcore::new(3, Urgency::High));
+
+        assert_eq!(queues.total_queued(), 3);
+        assert_eq!(next(&mut queues), Some(2));
+        assert_eq!(next(&mut queues), Some(3));
+        assert_eq!(next(&mut queues), Some(1));
+        assert_eq!(next(&mut queues), None);
+    }
+
+    #[test]
+    fn normal_priority_not_starved()
+    {
+        let mut queues = RoundRobin::new(World);
+        queues.queue(Vcore::new(1, Urgency::Normal));
+        queues.queue(Vcore::new(2, Urgency::High));
+        for _ in 0..3
+        {
+            queues.ran(Urgency::High);
+        }
+
+        assert_eq!(next(&mut queues), Some(1));
+        queues.ran(Urgency::Normal);
+        assert_eq!(next(&mut queues), Some(2));
+    }
+
+    #[test]
+    fn earliest_deadline_with_budget_first()
+    {
+        let mut queues = RoundRobin::new(World);
+        let mut late = Vcore::new(1, Urgency::Deadline);
+        late.deadline = Some((10, 200));
+        let mut soon = Vcore::new(2, Urgency::Deadline);
+        soon.deadline = Some((10, 100));
+        let mut exhausted = Vcore::new(3, Urgency::Deadline);
+        exhausted.deadline = Some((0, 50));
+        queues.queue(Vcore::new(4, Urgency::High));
+        queues.queue(late);
+        queues.queue(exhausted);
+        queues.queue(soon);
+
+        assert_eq!(next(&mut queues), Some(2));
+        assert_eq!(next(&mut queues), Some(1));
+        assert_eq!(next(&mut queues), Some(4));
+        assert_eq!(next(&mut queues), Some(3));
+    }
+
+    #[test]
+    fn preferred_class_within_priority()
+    {
+        let mut queues = RoundRobin::new(World);
+        queues.queue(Vcore::new(1, Urgency::High));
+        let mut efficient = Vcore::new(2, Urgency::High);
+        efficient.efficient = true;
+        queues.queue(efficient);
+
+        assert_eq!(queues.dequeue_preferring(true).map(|v| v.cid), Some(2));
+        assert_eq!(queues.dequeue_preferring(true).map(|v| v.cid), Some(1));
+    }
+
+    #[test]
+    fn dequeue_by_capsule_and_vcore()
+    {
+        let mut queues = Fifo::new();
+        queues.queue(Vcore::new(1, Urgency::High));
+        let mut second = Vcore::new(2, Urgency::Normal);
+        second.vid = 1;
+        queues.queue(second);
+        queues.queue(Vcore::new(2, Urgency::Normal));
+
+        assert_eq!(queues.dequeue_vcore(2, 1).map(|v| v.vid), Some(1));
+        assert_eq!(queues.dequeue_capsule(2).map(|v| v.vid), Some(0));
+        assert!(queues.dequeue_capsule(2).is_none());
+        assert_eq!(next(&mut queues), Some(1));
+    }
+}
@@ -0,0 +1,236 @@
+/* diosix sorted list of free memory regions
+ *
+ * The physical memory manager keeps its free memory as a list of
+ * regions sorted by base address, lowest first, so that neighbouring
+ * regions can be merged back together once they're freed. The list
+ * keeps a count of the bytes in its regions. It doesn't care what a
+ * region is, as long as it has a base and size, and can be cut down to
+ * new bounds while keeping whatever else it carries, such as whether
+ * its memory can be scrubbed.
+ *
+ * (c) Chris Williams, 2019-2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use super::Error;
+
+/* a contiguous range of memory that can be kept in a list */
+pub trait Extent: Copy
+{
+    fn base(&self) -> usize;
+    fn size(&self) -> usize;
+
+    /* <= a copy of this extent moved to the given bounds, keeping its other attributes */
+    fn with_bounds(&self, base: usize, size: usize) -> Self;
+
+    fn end(&self) -> usize { self.base() + self.size() }
+}
+
+/* implement a sorted list of regions, keeping count of the bytes in them */
+pub struct SortedRegions<R: Extent>
+{
+    regions: Vec<R>,
+    free: usize
+}
+
+impl<R: Extent> SortedRegions<R>
+{
+    /* create an empty list */
+    pub fn new() -> SortedRegions<R>
+    {
+        SortedRegions
+        {
+            regions: Vec::new(),
+            free: 0
+        }
+    }
+
+    /* return the total number of bytes in the list's regions */
+    pub fn free(&self) -> usize { self.free }
+
+    /* return the size in bytes of the largest region in the list, or zero if it's empty */
+    pub fn largest(&self) -> usize
+    {
+        self.regions.iter().map(|r| r.size()).max().unwrap_or(0)
+    }
+
+    /* find a region that has a size equal to or greater than the required size.
+       if one is found, remove the region and return it. if one can't be found,
+       return an error code. */
+    pub fn find(&mut self, required_size: usize) -> Result<R, Error>
+    {
+        for index in 0..self.regions.len()
+        {
+            if self.regions[index].size() >= required_size
+            {
+                /* remove from the list and return */
+                self.free = self.free - self.regions[index].size();
+                return Ok(self.regions.remove(index));
+            }
+        }
+
+        Err(Error::RegionNoMatch) /* can't find a region large enough */
+    }
+
+    /* find a region that contains at least required_size bytes between the given
+       lower and upper addresses. if one is found, carve out and return
+       required_size bytes from within those bounds, returning any remainder to the list.
+       if one can't be found, return an error code */
+    pub fn find_within(&mut self, required_size: usize, lower: usize, upper: usize) -> Result<R, Error>
+    {
+        for index in 0..self.regions.len()
+        {
+            let candidate = self.regions[index];
+            let base = if candidate.base() > lower { candidate.base() } else { lower };
+            let end = if candidate.end() < upper { candidate.end() } else { upper };
+
+            if end > base && end - base >= required_size
+            {
+                /* split off everything below the usable base, then everything above the carved-out block */
+                let found = self.regions.remove(index);
+                self.free = self.free - found.size();
+                let below = found.with_bounds(found.base(), base - found.base());
+                let carved = found.with_bounds(base, required_size);
+                let above = found.with_bounds(base + required_size, found.end() - (base + required_size));
+                self.insert(below)?;
+                self.insert(above)?;
+                return Ok(carved);
+            }
+        }
+
+        Err(Error::RegionNoMatch)
+    }
+
+    /* insert a region into the list, sorted by base addresses, lowest first */
+    pub fn insert(&mut self, to_insert: R) -> Result<(), Error>
+    {
+        /* ignore zero-size inserts */
+        if to_insert.size() == 0
+        {
+            return Ok(())
+        }
+
+        for index in 0..self.regions.len()
+        {
+            if to_insert.end() <= self.regions[index].base()
+            {
+                self.free = self.free + to_insert.size();
+                self.regions.insert(index, to_insert);
+                return Ok(())
+            }
+
+            /* check to make sure we're not adding a region that will collide with another */
+            if to_insert.base() >= self.regions[index].base() && to_insert.base() < self.regions[index].end()
+            {
+                return Err(Error::RegionCollision);
+            }
+        }
+
+        /* insert at the end: region greater than all others */
+        self.free = self.free + to_insert.size();
+        self.regions.push(to_insert);
+        Ok(())
+    }
+
+    /* merge all adjoining free regions. this requires the list to be sorted by base address ascending */
+    pub fn merge(&mut self)
+    {
+        let mut cursor = 0;
+        loop
+        {
+            /* prevent search from going out of bounds */
+            if (cursor + 1) >= self.regions.len()
+            {
+                break;
+            }
+
+            if self.regions[cursor].end() == self.regions[cursor + 1].base()
+            {
+                /* absorb the next region's size into this region */
+                let absorbed = self.regions.remove(cursor + 1).size();
+                let region = self.regions[cursor];
+                self.regions[cursor] = region.with_bounds(region.base(), region.size() + absorbed);
+            }
+            else
+            {
+                /* move onto next region */
+                cursor = cursor + 1;
+            }
+        }
+    }
+
+    /* <= the regions in the list, lowest first */
+    pub fn iter(&self) -> impl Iterator<Item = &R>
+    {
+        self.regions.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    struct Span(usize, usize);
+
+    impl Extent for Span
+    {
+        fn base(&self) -> usize { self.0 }
+        fn size(&self) -> usize { self.1 }
+        fn with_bounds(&self, base: usize, size: usize) -> Span { Span(base, size) }
+    }
+
+    #[test]
+    fn insert_sorts_and_counts()
+    {
+        let mut list = SortedRegions::new();
+        list.insert(Span(0x3000, 0x1000)).unwrap();
+        list.insert(Span(0x1000, 0x1000)).unwrap();
+        list.insert(Span(0x0, 0)).unwrap();
+
+        assert_eq!(list.iter().copied().collect::<Vec<Span>>(), [Span(0x1000, 0x1000), Span(0x3000, 0x1000)]);
+        assert_eq!(list.free(), 0x2000);
+        assert_eq!(list.insert(Span(0x3800, 0x100)), Err(Error::RegionCollision));
+    }
+
+    #[test]
+    fn find_removes_first_fit()
+    {
+        let mut list = SortedRegions::new();
+        list.insert(Span(0x1000, 0x1000)).unwrap();
+        list.insert(Span(0x4000, 0x4000)).unwrap();
+
+        assert_eq!(list.find(0x2000), Ok(Span(0x4000, 0x4000)));
+        assert_eq!(list.free(), 0x1000);
+        assert_eq!(list.find(0x2000), Err(Error::RegionNoMatch));
+    }
+
+    #[test]
+    fn find_within_carves_and_returns_remainder()
+    {
+        let mut list = SortedRegions::new();
+        list.insert(Span(0x0, 0x10000)).unwrap();
+
+        assert_eq!(list.find_within(0x1000, 0x4000, 0x8000), Ok(Span(0x4000, 0x1000)));
+        assert_eq!(list.iter().copied().collect::<Vec<Span>>(), [Span(0x0, 0x4000), Span(0x5000, 0xb000)]);
+        assert_eq!(list.free(), 0xf000);
+        assert_eq!(list.find_within(0x1000, 0x4000, 0x4800), Err(Error::RegionNoMatch));
+    }
+
+    #[test]
+    fn merge_joins_neighbours()
+    {
+        let mut list = SortedRegions::new();
+        list.insert(Span(0x0, 0x1000)).unwrap();
+        list.insert(Span(0x1000, 0x1000)).unwrap();
+        list.insert(Span(0x3000, 0x1000)).unwrap();
+        list.merge();
+
+        assert_eq!(list.iter().copied().collect::<Vec<Span>>(), [Span(0x0, 0x2000), Span(0x3000, 0x1000)]);
+        assert_eq!(list.largest(), 0x2000);
+        assert_eq!(list.free(), 0x3000);
+    }
+}
@@ -0,0 +1,505 @@
+/* diosix virtio split virtqueue
+ *
+ * Every virtio device model moves data through the same queues, so they
+ * share this one implementation of the split ring layout from the virtio
+ * 1.1 specification, section 2.6. A queue is three areas of the guest's
+ * memory: the descriptor table, the available ring the guest fills with
+ * chains of descriptors for the device, and the used ring the device
+ * fills as it finishes with them.
+ *
+ * The guest can write anything into these areas at any time, so nothing
+ * read from them is trusted: every buffer is checked against the guest's
+ * memory through the GuestMemory trait before it's handed to a device
+ * model, chains are limited to the queue's size to catch loops, and a
+ * guest that claims to have made more chains available than the queue
+ * can hold is refused. Indirect descriptors aren't supported yet.
+ *
+ * Device models that can't keep up apply backpressure by leaving chains
+ * in the available ring. They can ask the guest not to notify them while
+ * they're busy, and re-enable notifications when they're ready for more,
+ * checking for chains that arrived in the meantime. Likewise, the guest
+ * can ask not to be interrupted when chains are used, either with a flag
+ * or, with the event index feature, by saying which chain it wants to
+ * hear about next.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use alloc::vec::Vec;
+use super::Error;
+
+/* largest queue size allowed by the split ring layout */
+pub const QUEUE_SIZE_MAX: u16 = 32768;
+
+/* descriptor flags */
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/* ring flags: the guest doesn't want interrupts, and the device doesn't want notifications */
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/* the areas' alignments, and the sizes of their entries and headers */
+const DESC_ALIGN: usize = 16;
+const AVAIL_ALIGN: usize = 2;
+const USED_ALIGN: usize = 4;
+const DESC_SIZE: usize = 16;
+const RING_HEADER_SIZE: usize = 4;
+const AVAIL_ENTRY_SIZE: usize = 2;
+const USED_ENTRY_SIZE: usize = 8;
+const EVENT_SIZE: usize = 2;
+
+/* access to the memory of the guest that owns a queue */
+pub trait GuestMemory
+{
+    /* => addr = guest physical address of a buffer
+          size = its size in bytes, which is never zero
+          write = true if the device will write to the buffer, or false if it will only read it
+       <= host address of the buffer, or None if any of it lies outside the guest's memory
+          or the guest can't let the device write to it */
+    fn translate(&self, addr: u64, size: usize, write: bool) -> Option<usize>;
+}
+
+/* a buffer in a chain, by its host address and size in bytes */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Buffer
+{
+    pub base: usize,
+    pub size: usize
+}
+
+/* a chain of descriptors made available by the guest. the device reads the readable buffers
+   and writes to the writable ones, then hands the chain back with push() */
+#[derive(Debug)]
+pub struct Chain
+{
+    pub head: u16,              /* index of the chain's first descriptor */
+    pub readable: Vec<Buffer>,  /* buffers for the device to read, in order */
+    pub writable: Vec<Buffer>   /* buffers for the device to write, in order */
+}
+
+impl Chain
+{
+    /* <= total bytes the device can write to this chain */
+    pub fn writable_size(&self) -> usize
+    {
+        self.writable.iter().fold(0, |total, b| total + b.size)
+    }
+}
+
+/* counts of a queue's activity */
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Stats
+{
+    pub popped: u64,        /* chains taken from the available ring */
+    pub pushed: u64,        /* chains returned through the used ring */
+    pub written: u64,       /* bytes the device said it wrote to returned chains */
+    pub bad_chains: u64,    /* chains refused for breaking the rules */
+    pub interrupts: u64,    /* times push() said the guest should be interrupted */
+    pub suppressed: u64,    /* times the guest asked not to be interrupted */
+    pub backoffs: u64       /* times the device stopped taking chains while some were waiting */
+}
+
+/* one split virtqueue shared with a guest */
+pub struct SplitQueue
+{
+    size: u16,
+    desc: usize,            /* host addresses of the descriptor table... */
+    avail: usize,           /* ...available ring... */
+    used: usize,            /* ...and used ring */
+    event_idx: bool,        /* true if the guest and device negotiated VIRTIO_F_EVENT_IDX */
+    last_avail: u16,        /* next entry in the available ring to take */
+    used_idx: u16,          /* next entry in the used ring to fill */
+    signalled_used: u16,    /* used index when push() last checked whether to interrupt the guest */
+    stats: Stats
+}
+
+impl SplitQueue
+{
+    /* set up a queue from the areas the guest has given the device
+       => memory = the guest's memory
+          size = number of entries in the queue, a power of two
+          desc, avail, used = guest physical addresses of the descriptor table, available ring, and used ring
+          event_idx = true if VIRTIO_F_EVENT_IDX was negotiated
+       <= queue, or an error code if the size or any area is bad */
+    pub fn new<M: GuestMemory>(memory: &M, size: u16, desc: u64, avail: u64, used: u64, event_idx: bool) -> Result<SplitQueue, Error>
+    {
+        if size == 0 || size > QUEUE_SIZE_MAX || size.is_power_of_two() == false
+        {
+            return Err(Error::VirtqueueBadLayout);
+        }
+
+        let entries = size as usize;
+        let area = |addr: u64, align: usize, bytes: usize, write: bool| -> Result<usize, Error>
+        {
+            if addr & (align as u64 - 1) != 0
+            {
+                return Err(Error::VirtqueueBadLayout);
+            }
+            memory.translate(addr, bytes, write).ok_or(Error::VirtqueueBadLayout)
+        };
+
+        /* the device only reads the descriptor table and available ring, and writes to the used ring */
+        let desc = area(desc, DESC_ALIGN, entries * DESC_SIZE, false)?;
+        let avail = area(avail, AVAIL_ALIGN, RING_HEADER_SIZE + (entries * AVAIL_ENTRY_SIZE) + EVENT_SIZE, false)?;
+        let used = area(used, USED_ALIGN, RING_HEADER_SIZE + (entries * USED_ENTRY_SIZE) + EVENT_SIZE, true)?;
+
+        Ok(SplitQueue
+        {
+            size, desc, avail, used, event_idx,
+            last_avail: 0,
+            used_idx: 0,
+            signalled_used: 0,
+            stats: Stats::default()
+        })
+    }
+
+    /* <= number of entries in the queue */
+    pub fn size(&self) -> u16 { self.size }
+
+    /* <= counts of the queue's activity so far */
+    pub fn stats(&self) -> Stats { self.stats }
+
+    /* read and write the rings. the guest may change them at any time, so use volatile accesses */
+    fn read_u16(&self, addr: usize) -> u16 { unsafe { ptr::read_volatile(addr as *const u16) } }
+    fn write_u16(&self, addr: usize, value: u16) { unsafe { ptr::write_volatile(addr as *mut u16, value) } }
+    fn write_u32(&self, addr: usize, value: u32) { unsafe { ptr::write_volatile(addr as *mut u32, value) } }
+
+    fn avail_flags(&self) -> u16 { self.read_u16(self.avail) }
+    fn avail_idx(&self) -> u16 { self.read_u16(self.avail + 2) }
+    fn used_event(&self) -> u16 { self.read_u16(self.avail + RING_HEADER_SIZE + (self.size as usize * AVAIL_ENTRY_SIZE)) }
+    fn avail_event_addr(&self) -> usize { self.used + RING_HEADER_SIZE + (self.size as usize * USED_ENTRY_SIZE) }
+
+    /* <= number of chains the guest has made available and the device hasn't taken, or an error code
+          if the guest claims more than the queue can hold */
+    pub fn pending(&self) -> Result<u16, Error>
+    {
+        let waiting = self.avail_idx().wrapping_sub(self.last_avail);
+        match waiting > self.size
+        {
+            true => Err(Error::VirtqueueBadIndex),
+            false => Ok(waiting)
+        }
+    }
+
+    /* take the next chain of descriptors the guest has made available, checking every buffer
+       <= Some chain, None if there are none waiting, or an error code if the next one is bad.
+          a bad chain is consumed, so the guest can't wedge the queue with it, and should be
+          returned with push() and zero bytes written */
+    pub fn pop<M: GuestMemory>(&mut self, memory: &M) -> Result<Option<Chain>, Error>
+    {
+        if self.pending()? == 0
+        {
+            return Ok(None);
+        }
+
+        /* read the ring entry only after seeing the index that covers it */
+        fence(Ordering::Acquire);
+        let slot = self.avail + RING_HEADER_SIZE + ((self.last_avail % self.size) as usize * AVAIL_ENTRY_SIZE);
+        let head = self.read_u16(slot);
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        match self.walk(memory, head)
+        {
+            Ok(chain) =>
+            {
+                self.stats.popped = self.stats.popped + 1;
+                Ok(Some(chain))
+            },
+            Err(e) =>
+            {
+                self.stats.bad_chains = self.stats.bad_chains + 1;
+                Err(e)
+            }
+        }
+    }
+
+    /* follow a chain of descriptors from its head, checking each buffer against the guest's memory.
+       readable buffers must all come before writable ones, and the chain can't be longer than the queue */
+    fn walk<M: GuestMemory>(&self, memory: &M, head: u16) -> Result<Chain, Error>
+    {
+        let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+
+        for _ in 0..self.size
+        {
+            if index >= self.size
+            {
+                return Err(Error::VirtqueueBadDescriptor);
+            }
+
+            let entry = self.desc + (index as usize * DESC_SIZE);
+            let (addr, size, flags, next) = unsafe
+            {
+                (ptr::read_volatile(entry as *const u64),
+                 ptr::read_volatile((entry + 8) as *const u32) as usize,
+                 ptr::read_volatile((entry + 12) as *const u16),
+                 ptr::read_volatile((entry + 14) as *const u16))
+            };
+
+            let write = flags & VIRTQ_DESC_F_WRITE != 0;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 || size == 0 || (write == false && chain.writable.len() > 0)
+            {
+                return Err(Error::VirtqueueBadDescriptor);
+            }
+
+            let base = memory.translate(addr, size, write).ok_or(Error::VirtqueueBadDescriptor)?;
+            match write
+            {
+                true => chain.writable.push(Buffer { base, size }),
+                false => chain.readable.push(Buffer { base, size })
+            }
+
+            if flags & VIRTQ_DESC_F_NEXT == 0
+            {
+                return Ok(chain);
+            }
+            index = next;
+        }
+
+        /* the chain is longer than the queue, so it must loop */
+        Err(Error::VirtqueueBadDescriptor)
+    }
+
+    /* hand a chain back to the guest through the used ring
+       => head = index of the chain's first descriptor, from its Chain
+          written = number of bytes the device wrote to the chain's writable buffers
+       <= true if the guest should be interrupted, or false if it asked not to be */
+    pub fn push(&mut self, head: u16, written: u32) -> bool
+    {
+        let slot = self.used + RING_HEADER_SIZE + ((self.used_idx % self.size) as usize * USED_ENTRY_SIZE);
+        self.write_u32(slot, head as u32);
+        self.write_u32(slot + 4, written);
+
+        /* the guest must see the entry before the index that covers it */
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        self.write_u16(self.used + 2, self.used_idx);
+
+        self.stats.pushed = self.stats.pushed + 1;
+        self.stats.written = self.stats.written + written as u64;
+
+        /* make sure the guest's request for interrupts is read after the index is published */
+        fence(Ordering::SeqCst);
+        let interrupt = match self.event_idx
+        {
+            /* interrupt if the used index has passed the event the guest asked for since the last check */
+            true =>
+            {
+                let event = self.used_event();
+                let old = self.signalled_used;
+                self.signalled_used = self.used_idx;
+                self.used_idx.wrapping_sub(event).wrapping_sub(1) < self.used_idx.wrapping_sub(old)
+            },
+            false => self.avail_flags() & VIRTQ_AVAIL_F_NO_INTERRUPT == 0
+        };
+
+        match interrupt
+        {
+            true => self.stats.interrupts = self.stats.interrupts + 1,
+            false => self.stats.suppressed = self.stats.suppressed + 1
+        };
+        interrupt
+    }
+
+    /* ask the guest not to notify the device of new chains, such as while the device is busy
+       or is applying backpressure. the guest may notify the device anyway */
+    pub fn disable_notifications(&mut self)
+    {
+        if self.event_idx == false
+        {
+            self.write_u16(self.used, VIRTQ_USED_F_NO_NOTIFY);
+        }
+
+        /* with event indexes, the device only hears about the chain it asked for last */
+        if matches!(self.pending(), Ok(waiting) if waiting > 0)
+        {
+            self.stats.backoffs = self.stats.backoffs + 1;
+        }
+    }
+
+    /* ask the guest to notify the device of new chains again, once the device is ready for more
+       <= true if chains arrived while notifications were disabled, which the device must take
+          now as the guest won't notify it about them */
+    pub fn enable_notifications(&mut self) -> Result<bool, Error>
+    {
+        match self.event_idx
+        {
+            true => self.write_u16(self.avail_event_addr(), self.last_avail),
+            false => self.write_u16(self.used, 0)
+        };
+
+        /* check for chains only after the guest can see notifications are wanted */
+        fence(Ordering::SeqCst);
+        Ok(self.pending()? > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::vec;
+
+    /* a guest with a little memory starting at guest physical address zero. the last page is read-only */
+    const GUEST_SIZE: usize = 0x4000;
+    const READ_ONLY: u64 = 0x3000;
+    const SIZE: u16 = 4;
+    const DESC: u64 = 0x0;
+    const AVAIL: u64 = 0x100;
+    const USED: u64 = 0x200;
+
+    struct Guest
+    {
+        ram: vec::Vec<u64>
+    }
+
+    impl Guest
+    {
+        fn new() -> Guest { Guest { ram: vec![0; GUEST_SIZE / 8] } }
+        fn host(&self, addr: u64) -> usize { self.ram.as_ptr() as usize + addr as usize }
+        fn poke<T>(&self, addr: u64, value: T) { unsafe { ptr::write_unaligned(self.host(addr) as *mut T, value) } }
+        fn peek<T: Copy>(&self, addr: u64) -> T { unsafe { ptr::read_unaligned(self.host(addr) as *const T) } }
+
+        /* fill in a descriptor */
+        fn describe(&self, index: u16, addr: u64, size: u32, flags: u16, next: u16)
+        {
+            let entry = DESC + (index as u64 * 16);
+            self.poke(entry, addr);
+            self.poke(entry + 8, size);
+            self.poke(entry + 12, flags);
+            self.poke(entry + 14, next);
+        }
+
+        /* make a chain available to the device */
+        fn offer(&self, head: u16)
+        {
+            let idx: u16 = self.peek(AVAIL + 2);
+            self.poke(AVAIL + 4 + ((idx % SIZE) as u64 * 2), head);
+            self.poke(AVAIL + 2, idx.wrapping_add(1));
+        }
+    }
+
+    impl GuestMemory for Guest
+    {
+        fn translate(&self, addr: u64, size: usize, write: bool) -> Option<usize>
+        {
+            let end = addr.checked_add(size as u64)?;
+            match end <= GUEST_SIZE as u64 && (write == false || end <= READ_ONLY)
+            {
+                true => Some(self.host(addr)),
+                false => None
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_bad_layouts()
+    {
+        let guest = Guest::new();
+        assert!(SplitQueue::new(&guest, 3, DESC, AVAIL, USED, false).is_err());
+        assert!(SplitQueue::new(&guest, SIZE, DESC + 8, AVAIL, USED, false).is_err());
+        assert!(SplitQueue::new(&guest, SIZE, DESC, AVAIL, READ_ONLY, false).is_err());
+        assert!(SplitQueue::new(&guest, SIZE, DESC, AVAIL, GUEST_SIZE as u64, false).is_err());
+        assert!(SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, false).is_ok());
+    }
+
+    #[test]
+    fn pops_chains_and_pushes_them_back()
+    {
+        let guest = Guest::new();
+        let mut queue = SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, false).unwrap();
+        assert!(queue.pop(&guest).unwrap().is_none());
+
+        guest.describe(1, 0x1000, 0x10, VIRTQ_DESC_F_NEXT, 2);
+        guest.describe(2, 0x2000, 0x80, VIRTQ_DESC_F_WRITE, 0);
+        guest.offer(1);
+
+        let chain = queue.pop(&guest).unwrap().unwrap();
+        assert_eq!(chain.head, 1);
+        assert_eq!(chain.readable, [Buffer { base: guest.host(0x1000), size: 0x10 }]);
+        assert_eq!(chain.writable, [Buffer { base: guest.host(0x2000), size: 0x80 }]);
+        assert_eq!(chain.writable_size(), 0x80);
+
+        assert!(queue.push(chain.head, 0x20));
+        assert_eq!(guest.peek::<u16>(USED + 2), 1);
+        assert_eq!(guest.peek::<u32>(USED + 4), 1);
+        assert_eq!(guest.peek::<u32>(USED + 8), 0x20);
+        assert_eq!(queue.stats().written, 0x20);
+    }
+
+    #[test]
+    fn refuses_bad_chains()
+    {
+        let guest = Guest::new();
+        let mut queue = SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, false).unwrap();
+
+        /* writable buffer in read-only memory */
+        guest.describe(0, READ_ONLY, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        guest.offer(0);
+        assert_eq!(queue.pop(&guest).unwrap_err(), Error::VirtqueueBadDescriptor);
+
+        /* readable buffer after a writable one */
+        guest.describe(0, 0x1000, 0x10, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        guest.describe(1, 0x1000, 0x10, 0, 0);
+        guest.offer(0);
+        assert_eq!(queue.pop(&guest).unwrap_err(), Error::VirtqueueBadDescriptor);
+
+        /* chain that loops back on itself */
+        guest.describe(0, 0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        guest.describe(1, 0x1000, 0x10, VIRTQ_DESC_F_NEXT, 0);
+        guest.offer(0);
+        assert_eq!(queue.pop(&guest).unwrap_err(), Error::VirtqueueBadDescriptor);
+        assert_eq!(queue.stats().bad_chains, 3);
+
+        /* more chains claimed than the queue holds */
+        guest.poke(AVAIL + 2, 100u16);
+        assert_eq!(queue.pop(&guest).unwrap_err(), Error::VirtqueueBadIndex);
+    }
+
+    #[test]
+    fn suppresses_interrupts_by_flag()
+    {
+        let guest = Guest::new();
+        let mut queue = SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, false).unwrap();
+        guest.poke(AVAIL, VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!queue.push(0, 0));
+        assert_eq!(queue.stats().suppressed, 1);
+    }
+
+    #[test]
+    fn interrupts_at_used_event()
+    {
+        let guest = Guest::new();
+        let mut queue = SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, true).unwrap();
+
+        /* guest wants to hear once the second chain is used */
+        guest.poke(AVAIL + 4 + (SIZE as u64 * 2), 1u16);
+        assert!(!queue.push(0, 0));
+        assert!(queue.push(1, 0));
+        assert!(!queue.push(2, 0));
+    }
+
+    #[test]
+    fn backpressure_catches_chains_that_arrive_while_disabled()
+    {
+        let guest = Guest::new();
+        let mut queue = SplitQueue::new(&guest, SIZE, DESC, AVAIL, USED, false).unwrap();
+
+        guest.describe(0, 0x1000, 0x10, 0, 0);
+        guest.offer(0);
+        queue.disable_notifications();
+        assert_eq!(guest.peek::<u16>(USED), VIRTQ_USED_F_NO_NOTIFY);
+        assert_eq!(queue.stats().backoffs, 1);
+
+        assert_eq!(queue.enable_notifications(), Ok(true));
+        assert_eq!(guest.peek::<u16>(USED), 0);
+        assert!(queue.pop(&guest).unwrap().is_some());
+        assert_eq!(queue.enable_notifications(), Ok(false));
+    }
+}
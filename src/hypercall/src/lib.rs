@@ -0,0 +1,1360 @@
+/* diosix hypercall ABI definitions
+ *
+ * Values and structures passed between capsules and the hypervisor
+ * through its hypercalls, defined once here so that the hypervisor and
+ * the bundled supervisor and services can't drift out of sync. Each
+ * side should use these rather than keep its own copies of the
+ * numbers. Changing any of them changes the hypercall ABI.
+ *
+ * That includes the hypercall numbers themselves and the result codes
+ * they return. Capsules make hypercalls through the SBI, passing
+ * SBI_EXTENSION in a7, the number of the call in a6, and its parameters
+ * in a0 to a5. The hypervisor decodes the numbers into the calls listed
+ * here and returns the result code in a0 and any values in a1 to a3.
+ * The ABI version and capability covering each call are checked against
+ * the same table on both sides.
+ *
+ * This crate is no_std and allocates nothing.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+#![no_std]
+
+/* ABI versioning, capability negotiation, and hypervisor identification */
+pub mod abi
+{
+    /* range of hypercall ABI versions implemented by the hypervisor */
+    pub const ABI_VERSION_MIN: usize = 1;
+    pub const ABI_VERSION_MAX: usize = 2;
+
+    /* the ABI assumed for capsules that don't negotiate: the original console and timer calls */
+    pub const ABI_VERSION_DEFAULT: usize = 1;
+
+    /* capability bits reported to guests */
+    pub const CAP_CONSOLE: usize         = 1 << 0; /* read and write own console buffer */
+    pub const CAP_TIMER: usize           = 1 << 1; /* program supervisor timer IRQs */
+    pub const CAP_CONSOLE_SERVICE: usize = 1 << 2; /* access other capsules' console buffers */
+    pub const CAP_HV_LOG: usize          = 1 << 3; /* read the hypervisor's log */
+    pub const CAP_DEVICE_IRQ: usize      = 1 << 4; /* claim and complete passed-through device interrupts */
+    pub const CAP_MANAGE: usize          = 1 << 5; /* inspect, resume, and kill other capsules */
+    pub const CAP_SHMEM: usize           = 1 << 6; /* grant parts of own memory to other capsules */
+    pub const CAP_VIRTIO: usize          = 1 << 7; /* has virtio device models to probe for */
+
+    /* capabilities that can be granted under each ABI version. version 1 only has the original
+       console and timer calls, and the rest arrived in version 2 */
+    pub const CAPS_V1: usize = CAP_CONSOLE | CAP_TIMER;
+    pub const CAPS_V2: usize = CAPS_V1 | CAP_CONSOLE_SERVICE | CAP_HV_LOG | CAP_DEVICE_IRQ | CAP_MANAGE | CAP_SHMEM | CAP_VIRTIO;
+
+    /* the identify hypercall's leaf numbers */
+    pub const IDENTIFY_LEAF_SIGNATURE: usize = 0;  /* <= HYPERVISOR_SIGNATURE, highest leaf supported */
+    pub const IDENTIFY_LEAF_VERSION: usize   = 1;  /* <= packed hypervisor version, packed supported ABI range */
+    pub const IDENTIFY_LEAF_ABI: usize       = 2;  /* <= ABI version in use, capability bitmap for that version */
+    pub const IDENTIFY_LEAF_MAX: usize = IDENTIFY_LEAF_ABI;
+
+    /* "diosix" in ASCII, stored little-endian, so guests can tell they're running on this hypervisor */
+    pub const HYPERVISOR_SIGNATURE: usize = 0x7869_736f_6964;
+
+    /* compatible string for the /hypervisor node in guests' device trees */
+    pub const HYPERVISOR_COMPATIBLE: &str = "diosix";
+
+    /* pack a version number into a word: major << 32 | minor << 16 | patch */
+    pub fn pack_version(major: usize, minor: usize, patch: usize) -> usize
+    {
+        (major << 32) | ((minor & 0xffff) << 16) | (patch & 0xffff)
+    }
+
+    /* unpack a word created by pack_version into its major, minor, and patch numbers */
+    pub fn unpack_version(packed: usize) -> (usize, usize, usize)
+    {
+        (packed >> 32, (packed >> 16) & 0xffff, packed & 0xffff)
+    }
+
+    /* pack a range of ABI versions into a word: min << 16 | max */
+    pub fn pack_abi_range(min: usize, max: usize) -> usize
+    {
+        (min << 16) | (max & 0xffff)
+    }
+
+    /* unpack a word created by pack_abi_range into its lowest and highest versions */
+    pub fn unpack_abi_range(packed: usize) -> (usize, usize)
+    {
+        (packed >> 16, packed & 0xffff)
+    }
+
+    /* <= the capabilities that can be granted to a capsule using the given ABI version */
+    pub fn capabilities_of_version(version: usize) -> usize
+    {
+        match version
+        {
+            0 => 0,
+            1 => CAPS_V1,
+            _ => CAPS_V2
+        }
+    }
+
+    /* what a capsule needs before it can make a hypercall */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Requirement
+    {
+        version: usize,     /* ABI version the call arrived in */
+        capability: usize   /* capability bit covering the call, or 0 if the call's open to all */
+    }
+
+    impl Requirement
+    {
+        pub const fn new(version: usize, capability: usize) -> Requirement
+        {
+            Requirement { version, capability }
+        }
+
+        /* <= true if a capsule using the given ABI version, with the given capabilities, can make the call */
+        pub fn is_met(&self, version: usize, capabilities: usize) -> bool
+        {
+            version >= self.version && (capabilities & self.capability) == self.capability
+        }
+    }
+}
+
+/* hypercall numbers and the results they return */
+pub mod call
+{
+    use crate::abi::{Requirement, CAP_CONSOLE, CAP_TIMER, CAP_CONSOLE_SERVICE, CAP_DEVICE_IRQ, CAP_MANAGE, CAP_SHMEM};
+
+    /* SBI extension ID that selects the hypervisor's own calls, from the firmware-specific range */
+    pub const SBI_EXTENSION: usize = 0x0A00_0000;
+
+    /* every hypercall, by the number a capsule passes to make it. numbers are never
+       reused or reordered: new calls are added to the end. the calls in the original
+       ABI come first */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Call
+    {
+        Yield = 0,
+        Terminate = 1,
+        Restart = 2,
+        RegisterService = 3,
+        OutputChar = 4,
+        InputChar = 5,
+        ConsoleBufferWriteChar = 6,
+        ConsoleBufferReadChar = 7,
+        HypervisorBufferReadChar = 8,
+        TimerIRQAt = 9,
+        NegotiateABI = 10,
+        Identify = 11,
+        TimerAdd = 12,
+        TimerCancel = 13,
+        TimerFiredNext = 14,
+        MetricsRead = 15,
+        MetricsReadSystem = 16,
+        TelemetryRead = 17,
+        GuestLog = 18,
+        GuestPanic = 19,
+        CapsulePanicMessage = 20,
+        CapsulePanicRegisters = 21,
+        CapsuleCrashDump = 22,
+        CapsuleReadRegisters = 23,
+        CapsuleReadMemory = 24,
+        InventoryRead = 25,
+        IPISend = 26,
+        IPIClaim = 27,
+        SerialLinkPutc = 28,
+        SerialLinkGetc = 29,
+        ConsoleBufferOverflows = 30,
+        ConsoleEncoding = 31,
+        ConsoleInputMode = 32,
+        TraceReadChar = 33,
+        SelfTestRun = 34,
+        ExternalIRQClaim = 35,
+        CapsuleCrashedNext = 36,
+        CapsuleResume = 37,
+        CapsuleKillPaused = 38,
+        CapsuleWorkingSet = 39,
+        WarmReboot = 40,
+        HostReset = 41,
+        MemoryPressureSubscribe = 42,
+        GrantCreate = 43,
+        GrantAccept = 44,
+        GrantRelease = 45,
+        GrantRevoke = 46,
+        DirtyLogStart = 47,
+        DirtyLogRead = 48,
+        DirtyLogStop = 49,
+        MMIOMap = 50,
+        MMIOUnmap = 51,
+        TemplateQuiesce = 52,
+        TemplateMark = 53,
+        TemplateUnmark = 54,
+        TemplateClone = 55,
+        CapsuleQuiesce = 56,
+        CapsuleQuiesceStatus = 57,
+        CapsuleThaw = 58,
+        QuiesceDone = 59,
+        CapsulePressButton = 60,
+        ButtonCollect = 61,
+        MeasurementRead = 62,
+        SecretRead = 63,
+        SettingRead = 64,
+        SettingWrite = 65,
+        SettingVerbosity = 66,
+        SettingsSave = 67,
+        GpioSetDirection = 68,
+        GpioWrite = 69,
+        GpioRead = 70,
+        CapsuleSnapshot = 71,
+        CapsuleBootConfirm = 72,
+        CapsuleSelectBootImage = 73,
+        SelectService = 74,
+        TransferOffer = 75,
+        TransferNext = 76,
+        TransferAccept = 77,
+        BounceMap = 78,
+        BounceUnmap = 79,
+        TimeMonotonic = 80,
+        TimeWallClock = 81,
+        TimeSetOffset = 82,
+        RegisterServiceName = 83,
+        DeregisterServiceName = 84,
+        LookupServiceName = 85,
+        StreamListen = 86,
+        StreamConnect = 87,
+        StreamAccept = 88,
+        StreamSend = 89,
+        StreamRecv = 90,
+        StreamClose = 91,
+        ExternalIRQComplete = 92
+    }
+
+    /* number of hypercalls */
+    pub const CALLS: usize = Call::ExternalIRQComplete as usize + 1;
+
+    /* every hypercall in number order */
+    const ALL: [Call; CALLS] =
+    [
+        Call::Yield, Call::Terminate, Call::Restart, Call::RegisterService,
+        Call::OutputChar, Call::InputChar, Call::ConsoleBufferWriteChar, Call::ConsoleBufferReadChar,
+        Call::HypervisorBufferReadChar, Call::TimerIRQAt, Call::NegotiateABI, Call::Identify,
+        Call::TimerAdd, Call::TimerCancel, Call::TimerFiredNext, Call::MetricsRead,
+        Call::MetricsReadSystem, Call::TelemetryRead, Call::GuestLog, Call::GuestPanic,
+        Call::CapsulePanicMessage, Call::CapsulePanicRegisters, Call::CapsuleCrashDump, Call::CapsuleReadRegisters,
+        Call::CapsuleReadMemory, Call::InventoryRead, Call::IPISend, Call::IPIClaim,
+        Call::SerialLinkPutc, Call::SerialLinkGetc, Call::ConsoleBufferOverflows, Call::ConsoleEncoding,
+        Call::ConsoleInputMode, Call::TraceReadChar, Call::SelfTestRun, Call::ExternalIRQClaim,
+        Call::CapsuleCrashedNext, Call::CapsuleResume, Call::CapsuleKillPaused, Call::CapsuleWorkingSet,
+        Call::WarmReboot, Call::HostReset, Call::MemoryPressureSubscribe, Call::GrantCreate,
+        Call::GrantAccept, Call::GrantRelease, Call::GrantRevoke, Call::DirtyLogStart,
+        Call::DirtyLogRead, Call::DirtyLogStop, Call::MMIOMap, Call::MMIOUnmap,
+        Call::TemplateQuiesce, Call::TemplateMark, Call::TemplateUnmark, Call::TemplateClone,
+        Call::CapsuleQuiesce, Call::CapsuleQuiesceStatus, Call::CapsuleThaw, Call::QuiesceDone,
+        Call::CapsulePressButton, Call::ButtonCollect, Call::MeasurementRead, Call::SecretRead,
+        Call::SettingRead, Call::SettingWrite, Call::SettingVerbosity, Call::SettingsSave,
+        Call::GpioSetDirection, Call::GpioWrite, Call::GpioRead, Call::CapsuleSnapshot,
+        Call::CapsuleBootConfirm, Call::CapsuleSelectBootImage, Call::SelectService, Call::TransferOffer,
+        Call::TransferNext, Call::TransferAccept, Call::BounceMap, Call::BounceUnmap,
+        Call::TimeMonotonic, Call::TimeWallClock, Call::TimeSetOffset, Call::RegisterServiceName,
+        Call::DeregisterServiceName, Call::LookupServiceName, Call::StreamListen, Call::StreamConnect,
+        Call::StreamAccept, Call::StreamSend, Call::StreamRecv, Call::StreamClose,
+        Call::ExternalIRQComplete
+    ];
+
+    impl Call
+    {
+        /* <= hypercall with the given number, or None if there's no such call */
+        pub fn from_usize(value: usize) -> Option<Call>
+        {
+            ALL.get(value).copied()
+        }
+
+        /* <= the hypercall's name, as recorded in hypercall traces */
+        pub fn name(&self) -> &'static str
+        {
+            match self
+            {
+                Call::Yield => "yield",
+                Call::Terminate => "terminate",
+                Call::Restart => "restart",
+                Call::RegisterService => "register_service",
+                Call::OutputChar => "output_char",
+                Call::InputChar => "input_char",
+                Call::ConsoleBufferWriteChar => "console_buffer_write_char",
+                Call::ConsoleBufferReadChar => "console_buffer_read_char",
+                Call::HypervisorBufferReadChar => "hypervisor_buffer_read_char",
+                Call::TimerIRQAt => "timer_irq_at",
+                Call::NegotiateABI => "negotiate_abi",
+                Call::Identify => "identify",
+                Call::TimerAdd => "timer_add",
+                Call::TimerCancel => "timer_cancel",
+                Call::TimerFiredNext => "timer_fired_next",
+                Call::MetricsRead => "metrics_read",
+                Call::MetricsReadSystem => "metrics_read_system",
+                Call::TelemetryRead => "telemetry_read",
+                Call::GuestLog => "guest_log",
+                Call::GuestPanic => "guest_panic",
+                Call::CapsulePanicMessage => "capsule_panic_message",
+                Call::CapsulePanicRegisters => "capsule_panic_registers",
+                Call::CapsuleCrashDump => "capsule_crash_dump",
+                Call::CapsuleReadRegisters => "capsule_read_registers",
+                Call::CapsuleReadMemory => "capsule_read_memory",
+                Call::InventoryRead => "inventory_read",
+                Call::IPISend => "ipi_send",
+                Call::IPIClaim => "ipi_claim",
+                Call::SerialLinkPutc => "serial_link_putc",
+                Call::SerialLinkGetc => "serial_link_getc",
+                Call::ConsoleBufferOverflows => "console_buffer_overflows",
+                Call::ConsoleEncoding => "console_encoding",
+                Call::ConsoleInputMode => "console_input_mode",
+                Call::TraceReadChar => "trace_read_char",
+                Call::SelfTestRun => "self_test_run",
+                Call::ExternalIRQClaim => "external_irq_claim",
+                Call::CapsuleCrashedNext => "capsule_crashed_next",
+                Call::CapsuleResume => "capsule_resume",
+                Call::CapsuleKillPaused => "capsule_kill_paused",
+                Call::CapsuleWorkingSet => "capsule_working_set",
+                Call::WarmReboot => "warm_reboot",
+                Call::HostReset => "host_reset",
+                Call::MemoryPressureSubscribe => "memory_pressure_subscribe",
+                Call::GrantCreate => "grant_create",
+                Call::GrantAccept => "grant_accept",
+                Call::GrantRelease => "grant_release",
+                Call::GrantRevoke => "grant_revoke",
+                Call::DirtyLogStart => "dirty_log_start",
+                Call::DirtyLogRead => "dirty_log_read",
+                Call::DirtyLogStop => "dirty_log_stop",
+                Call::MMIOMap => "mmio_map",
+                Call::MMIOUnmap => "mmio_unmap",
+                Call::TemplateQuiesce => "template_quiesce",
+                Call::TemplateMark => "template_mark",
+                Call::TemplateUnmark => "template_unmark",
+                Call::TemplateClone => "template_clone",
+                Call::CapsuleQuiesce => "capsule_quiesce",
+                Call::CapsuleQuiesceStatus => "capsule_quiesce_status",
+                Call::CapsuleThaw => "capsule_thaw",
+                Call::QuiesceDone => "quiesce_done",
+                Call::CapsulePressButton => "capsule_press_button",
+                Call::ButtonCollect => "button_collect",
+                Call::MeasurementRead => "measurement_read",
+                Call::SecretRead => "secret_read",
+                Call::SettingRead => "setting_read",
+                Call::SettingWrite => "setting_write",
+                Call::SettingVerbosity => "setting_verbosity",
+                Call::SettingsSave => "settings_save",
+                Call::GpioSetDirection => "gpio_set_direction",
+                Call::GpioWrite => "gpio_write",
+                Call::GpioRead => "gpio_read",
+                Call::CapsuleSnapshot => "capsule_snapshot",
+                Call::CapsuleBootConfirm => "capsule_boot_confirm",
+                Call::CapsuleSelectBootImage => "capsule_select_boot_image",
+                Call::SelectService => "select_service",
+                Call::TransferOffer => "transfer_offer",
+                Call::TransferNext => "transfer_next",
+                Call::TransferAccept => "transfer_accept",
+                Call::BounceMap => "bounce_map",
+                Call::BounceUnmap => "bounce_unmap",
+                Call::TimeMonotonic => "time_monotonic",
+                Call::TimeWallClock => "time_wall_clock",
+                Call::TimeSetOffset => "time_set_offset",
+                Call::RegisterServiceName => "register_service_name",
+                Call::DeregisterServiceName => "deregister_service_name",
+                Call::LookupServiceName => "lookup_service_name",
+                Call::StreamListen => "stream_listen",
+                Call::StreamConnect => "stream_connect",
+                Call::StreamAccept => "stream_accept",
+                Call::StreamSend => "stream_send",
+                Call::StreamRecv => "stream_recv",
+                Call::StreamClose => "stream_close",
+                Call::ExternalIRQComplete => "external_irq_complete"
+            }
+        }
+
+        /* <= the ABI version the call arrived in and the capability covering it. calls that check
+           the caller's properties themselves, such as changing settings, aren't covered by a capability */
+        pub fn requirement(&self) -> Requirement
+        {
+            match self
+            {
+                /* the original calls. these can always be made, even before negotiating */
+                Call::Yield | Call::Terminate | Call::Restart | Call::RegisterService |
+                Call::ConsoleBufferWriteChar | Call::ConsoleBufferReadChar |
+                Call::HypervisorBufferReadChar | Call::NegotiateABI | Call::Identify => Requirement::new(1, 0),
+                Call::OutputChar | Call::InputChar => Requirement::new(1, CAP_CONSOLE),
+                Call::TimerIRQAt => Requirement::new(1, CAP_TIMER),
+                Call::TimerAdd | Call::TimerCancel | Call::TimerFiredNext => Requirement::new(2, CAP_TIMER),
+                Call::ConsoleBufferOverflows | Call::ConsoleEncoding => Requirement::new(2, CAP_CONSOLE_SERVICE),
+                Call::ConsoleInputMode => Requirement::new(2, CAP_CONSOLE),
+                Call::ExternalIRQClaim | Call::ExternalIRQComplete => Requirement::new(2, CAP_DEVICE_IRQ),
+                Call::GrantCreate | Call::GrantAccept | Call::GrantRelease | Call::GrantRevoke => Requirement::new(2, CAP_SHMEM),
+
+                /* inspecting and controlling other capsules */
+                Call::MetricsReadSystem | Call::TelemetryRead | Call::CapsulePanicMessage |
+                Call::CapsulePanicRegisters | Call::CapsuleCrashDump | Call::CapsuleReadRegisters |
+                Call::CapsuleReadMemory | Call::InventoryRead | Call::CapsuleCrashedNext |
+                Call::CapsuleResume | Call::CapsuleKillPaused | Call::CapsuleWorkingSet | Call::WarmReboot |
+                Call::DirtyLogStart | Call::DirtyLogRead | Call::DirtyLogStop | Call::TemplateMark |
+                Call::TemplateUnmark | Call::CapsuleQuiesce | Call::CapsuleQuiesceStatus |
+                Call::CapsuleThaw | Call::CapsulePressButton | Call::CapsuleSnapshot => Requirement::new(2, CAP_MANAGE),
+
+                /* everything else arrived in version 2 */
+                _ => Requirement::new(2, 0)
+            }
+        }
+    }
+
+    /* how a hypercall turned out, returned to the capsule that made it */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ActionResult
+    {
+        Success = 0,    /* the call was carried out */
+        Failed = 1,     /* the hypervisor couldn't complete the call */
+        Denied = 2,     /* the capsule isn't allowed to make the call */
+        BadParams = 3,  /* the call's parameters were invalid */
+        Retry = 4       /* the call can't be carried out yet, so try again later */
+    }
+
+    impl ActionResult
+    {
+        /* <= result with the given number, or None if there's no such result */
+        pub fn from_usize(value: usize) -> Option<ActionResult>
+        {
+            match value
+            {
+                0 => Some(ActionResult::Success),
+                1 => Some(ActionResult::Failed),
+                2 => Some(ActionResult::Denied),
+                3 => Some(ActionResult::BadParams),
+                4 => Some(ActionResult::Retry),
+                _ => None
+            }
+        }
+    }
+}
+
+/* virtual interrupts raised by the hypervisor. these are numbered beyond any physical IRQ */
+pub mod irq
+{
+    /* a capsule's device tree has been rewritten */
+    pub const VIRQ_DEVICE_TREE_CHANGED: usize = 0x10000;
+
+    /* data is waiting on a capsule's serial link */
+    pub const VIRQ_SERIAL_LINK_DATA: usize = 0x10001;
+
+    /* a service a capsule can use has moved to a standby capsule */
+    pub const VIRQ_SERVICE_FAILOVER: usize = 0x10002;
+
+    /* the host is about to be rebooted or powered off */
+    pub const VIRQ_HOST_RESET: usize = 0x10003;
+
+    /* a stream connection has arrived, or has data or room waiting, or has been closed by its peer */
+    pub const VIRQ_STREAM: usize = 0x10004;
+
+    /* the host's memory pressure level has changed */
+    pub const VIRQ_MEMORY_PRESSURE: usize = 0x10005;
+
+    /* a memory grant a capsule accepted has been revoked by, or died with, its granter */
+    pub const VIRQ_GRANT_REVOKED: usize = 0x10006;
+
+    /* a capsule is asked to flush its filesystems and pause its devices ready for a snapshot */
+    pub const VIRQ_QUIESCE: usize = 0x10007;
+
+    /* a capsule that quiesced itself can carry on as normal */
+    pub const VIRQ_THAW: usize = 0x10008;
+
+    /* a capsule's virtual power button has been pressed, or it's been asked to reboot */
+    pub const VIRQ_BUTTON: usize = 0x10009;
+
+    /* interrupts of the virtual devices declared in capsules' manifests are numbered from here */
+    pub const VIRQ_DEVICE_BASE: usize = 0x11000;
+    pub const VIRQ_DEVICE_COUNT: usize = 0x1000;
+}
+
+/* counters that can be read through the metrics hypercalls */
+pub mod metrics
+{
+    /* counters kept for each capsule */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Counter
+    {
+        TimerRequests = 0,  /* timers armed by the capsule */
+        TimerClamped,       /* timers pushed back to respect the capsule's minimum timer interval */
+        TimerCoalesced,     /* timer IRQs delivered alongside another rather than separately */
+        TimerIRQs,          /* timer IRQs raised for the capsule */
+        BootRegions,        /* microseconds spent allocating and mapping the capsule's memory when it last started */
+        BootImage,          /* microseconds spent loading the capsule's image when it last started */
+        BootDeviceTree,     /* microseconds spent generating the capsule's device tree when it last started */
+        BootFirstSchedule,  /* microseconds between the capsule's vcores being queued and first running when it last started */
+        TrapThrottles,      /* times the capsule was held back for trapping into the hypervisor too often */
+        ConsoleOverflows,   /* console output bytes dropped or refused because the capsule's buffer was full */
+        GuestTime,          /* microseconds the capsule's vcores have run in guest mode */
+        HypercallTime,      /* microseconds the hypervisor has spent handling the capsule's hypercalls */
+        FaultTime,          /* microseconds the hypervisor has spent emulating the capsule's instructions and handling its faults */
+        DeviceModelTime     /* microseconds the capsule's device models have spent emulating its devices */
+    }
+
+    /* number of counters kept per capsule */
+    pub const COUNTERS: usize = Counter::DeviceModelTime as usize + 1;
+
+    impl Counter
+    {
+        /* <= counter with the given number, or None if there's no such counter */
+        pub fn from_usize(value: usize) -> Option<Counter>
+        {
+            match value
+            {
+                0 => Some(Counter::TimerRequests),
+                1 => Some(Counter::TimerClamped),
+                2 => Some(Counter::TimerCoalesced),
+                3 => Some(Counter::TimerIRQs),
+                4 => Some(Counter::BootRegions),
+                5 => Some(Counter::BootImage),
+                6 => Some(Counter::BootDeviceTree),
+                7 => Some(Counter::BootFirstSchedule),
+                8 => Some(Counter::TrapThrottles),
+                9 => Some(Counter::ConsoleOverflows),
+                10 => Some(Counter::GuestTime),
+                11 => Some(Counter::HypercallTime),
+                12 => Some(Counter::FaultTime),
+                13 => Some(Counter::DeviceModelTime),
+                _ => None
+            }
+        }
+
+        /* <= short name of the counter, for telemetry and logs */
+        pub fn name(&self) -> &'static str
+        {
+            match self
+            {
+                Counter::TimerRequests => "timer_requests",
+                Counter::TimerClamped => "timer_clamped",
+                Counter::TimerCoalesced => "timer_coalesced",
+                Counter::TimerIRQs => "timer_irqs",
+                Counter::BootRegions => "boot_regions_us",
+                Counter::BootImage => "boot_image_us",
+                Counter::BootDeviceTree => "boot_device_tree_us",
+                Counter::BootFirstSchedule => "boot_first_schedule_us",
+                Counter::TrapThrottles => "trap_throttles",
+                Counter::ConsoleOverflows => "console_overflows",
+                Counter::GuestTime => "guest_time_us",
+                Counter::HypercallTime => "hypercall_time_us",
+                Counter::FaultTime => "fault_time_us",
+                Counter::DeviceModelTime => "device_model_time_us"
+            }
+        }
+    }
+
+    /* system-wide counters, kept across capsules' lifetimes */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum SystemCounter
+    {
+        LeakedRegions = 0,  /* physical memory regions not returned when their capsule was torn down */
+        LeakedBytes         /* total size of those regions */
+    }
+
+    /* number of system-wide counters */
+    pub const SYSTEM_COUNTERS: usize = SystemCounter::LeakedBytes as usize + 1;
+
+    impl SystemCounter
+    {
+        /* <= counter with the given number, or None if there's no such counter */
+        pub fn from_usize(value: usize) -> Option<SystemCounter>
+        {
+            match value
+            {
+                0 => Some(SystemCounter::LeakedRegions),
+                1 => Some(SystemCounter::LeakedBytes),
+                _ => None
+            }
+        }
+
+        /* <= short name of the counter, for telemetry and logs */
+        pub fn name(&self) -> &'static str
+        {
+            match self
+            {
+                SystemCounter::LeakedRegions => "leaked_regions",
+                SystemCounter::LeakedBytes => "leaked_bytes"
+            }
+        }
+    }
+}
+
+/* DMA bounce buffers for devices that can't be confined by an IOMMU */
+pub mod bounce
+{
+    /* which way a device moves the data in a bounced buffer */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Direction
+    {
+        ToDevice = 0,       /* the device reads the buffer */
+        FromDevice = 1,     /* the device writes the buffer */
+        Bidirectional = 2   /* the device reads and writes the buffer */
+    }
+
+    impl Direction
+    {
+        /* <= direction with the given number, or None if there's no such direction */
+        pub fn from_usize(value: usize) -> Option<Direction>
+        {
+            match value
+            {
+                0 => Some(Direction::ToDevice),
+                1 => Some(Direction::FromDevice),
+                2 => Some(Direction::Bidirectional),
+                _ => None
+            }
+        }
+    }
+}
+
+/* rebooting and powering off the host */
+pub mod power
+{
+    /* what a management capsule can ask to happen to the host */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Reset
+    {
+        Reboot = 0,     /* reset the host and boot it again */
+        PowerOff = 1    /* turn the host off */
+    }
+
+    impl Reset
+    {
+        /* <= reset type with the given number, or None if there's no such type */
+        pub fn from_usize(value: usize) -> Option<Reset>
+        {
+            match value
+            {
+                0 => Some(Reset::Reboot),
+                1 => Some(Reset::PowerOff),
+                _ => None
+            }
+        }
+    }
+}
+
+/* virtual buttons a management capsule can press on a guest's behalf */
+pub mod button
+{
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Button
+    {
+        Power = 0,      /* the guest should shut down */
+        Reboot = 1      /* the guest should restart */
+    }
+
+    impl Button
+    {
+        /* <= button with the given number, or None if there's no such button */
+        pub fn from_usize(value: usize) -> Option<Button>
+        {
+            match value
+            {
+                0 => Some(Button::Power),
+                1 => Some(Button::Reboot),
+                _ => None
+            }
+        }
+
+        /* <= this button's bit in the set of presses returned to a guest */
+        pub fn mask(&self) -> usize
+        {
+            1 << (*self as usize)
+        }
+    }
+}
+
+/* tracepoints in the hypervisor, switched on and off with the tracepoints setting */
+pub mod tracepoint
+{
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Tracepoint
+    {
+        Schedule = 0,       /* a physical core picks a virtual core to run, or finds none */
+        ContextSwitch = 1,  /* a physical core switches between virtual cores */
+        Lifecycle = 2,      /* a capsule changes state */
+        Hypercall = 3       /* a capsule makes a hypercall */
+    }
+
+    /* number of tracepoints */
+    pub const TRACEPOINTS: usize = Tracepoint::Hypercall as usize + 1;
+
+    impl Tracepoint
+    {
+        /* <= tracepoint with the given number, or None if there's no such tracepoint */
+        pub fn from_usize(value: usize) -> Option<Tracepoint>
+        {
+            match value
+            {
+                0 => Some(Tracepoint::Schedule),
+                1 => Some(Tracepoint::ContextSwitch),
+                2 => Some(Tracepoint::Lifecycle),
+                3 => Some(Tracepoint::Hypercall),
+                _ => None
+            }
+        }
+
+        /* <= this tracepoint's bit in the tracepoints setting */
+        pub fn mask(&self) -> u64
+        {
+            1 << (*self as u64)
+        }
+
+        /* <= short name of the tracepoint, for the trace ring */
+        pub fn name(&self) -> &'static str
+        {
+            match self
+            {
+                Tracepoint::Schedule => "schedule",
+                Tracepoint::ContextSwitch => "context_switch",
+                Tracepoint::Lifecycle => "lifecycle",
+                Tracepoint::Hypercall => "hypercall"
+            }
+        }
+    }
+}
+
+/* the hypervisor's live settings, adjusted by privileged capsules */
+pub mod settings
+{
+    /* number of settings */
+    pub const SETTINGS: usize = 5;
+
+    /* settings that can be changed while the hypervisor is running */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Setting
+    {
+        HousekeepingPeriod = 0, /* milliseconds between rounds of housekeeping */
+        Timeslice = 1,          /* milliseconds a virtual core runs before the next scheduling decision */
+        HighPriorityRun = 2,    /* high-priority timeslices run in a row before a normal-priority one gets a turn */
+        Parking = 3,            /* 1 to park idle physical cores, or 0 to keep them all running */
+        Tracepoints = 4         /* mask of tracepoints recording into the trace ring, if they're built in */
+    }
+
+    impl Setting
+    {
+        /* <= setting with the given number, or None if there's no such setting */
+        pub fn from_usize(value: usize) -> Option<Setting>
+        {
+            match value
+            {
+                0 => Some(Setting::HousekeepingPeriod),
+                1 => Some(Setting::Timeslice),
+                2 => Some(Setting::HighPriorityRun),
+                3 => Some(Setting::Parking),
+                4 => Some(Setting::Tracepoints),
+                _ => None
+            }
+        }
+
+        /* <= the setting's name, as used by the hypervisor's command prompt and persistent store */
+        pub fn name(&self) -> &'static str
+        {
+            match self
+            {
+                Setting::HousekeepingPeriod => "housekeeping_ms",
+                Setting::Timeslice => "timeslice_ms",
+                Setting::HighPriorityRun => "high_priority_run",
+                Setting::Parking => "parking",
+                Setting::Tracepoints => "tracepoints"
+            }
+        }
+    }
+
+    /* how much a part of the hypervisor writes to its debug log */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Verbosity
+    {
+        Alerts = 0, /* only alerts */
+        Debug = 1   /* alerts and debugging information, in debug builds */
+    }
+
+    impl Verbosity
+    {
+        /* <= verbosity with the given number, or None if there's no such verbosity */
+        pub fn from_usize(value: usize) -> Option<Verbosity>
+        {
+            match value
+            {
+                0 => Some(Verbosity::Alerts),
+                1 => Some(Verbosity::Debug),
+                _ => None
+            }
+        }
+    }
+}
+
+/* host memory pressure reported to capsules */
+pub mod memory
+{
+    /* how short the host is of free physical memory */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Pressure
+    {
+        Normal = 0,     /* plenty of free memory */
+        Low = 1,        /* free memory is running low: release what can easily be spared */
+        Critical = 2    /* the host is close to or has failed to find memory: release all that can be spared */
+    }
+
+    impl Pressure
+    {
+        /* <= pressure level with the given number, or None if there's no such level */
+        pub fn from_usize(value: usize) -> Option<Pressure>
+        {
+            match value
+            {
+                0 => Some(Pressure::Normal),
+                1 => Some(Pressure::Low),
+                2 => Some(Pressure::Critical),
+                _ => None
+            }
+        }
+    }
+}
+
+/* grants of part of a capsule's memory to another capsule or the hypervisor */
+pub mod grant
+{
+    /* grantee to pass in place of a capsule ID to grant memory to the hypervisor */
+    pub const GRANTEE_HYPERVISOR: usize = usize::MAX;
+
+    /* granted memory must start and end on these boundaries, in bytes */
+    pub const GRANT_ALIGNMENT: usize = 4096;
+
+    /* what the grantee can do with the memory */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Access
+    {
+        ReadOnly = 0,   /* the grantee can only read the memory */
+        ReadWrite = 1   /* the grantee can read and write the memory */
+    }
+
+    impl Access
+    {
+        /* <= access with the given number, or None if there's no such access */
+        pub fn from_usize(value: usize) -> Option<Access>
+        {
+            match value
+            {
+                0 => Some(Access::ReadOnly),
+                1 => Some(Access::ReadWrite),
+                _ => None
+            }
+        }
+    }
+}
+
+/* physical MMIO ranges mapped directly into trusted capsules */
+pub mod mmio
+{
+    /* mapped ranges must start and end on these boundaries, in bytes */
+    pub const MMIO_ALIGNMENT: usize = 4096;
+}
+
+/* tracking changes to capsules' memory for snapshots and migration */
+pub mod dirty
+{
+    /* each bit of a dirty log covers this many bytes of the capsule's main RAM */
+    pub const DIRTY_PAGE_SIZE: usize = 4096;
+}
+
+/* booted capsules frozen as templates for fast cloning */
+pub mod template
+{
+    /* start clones at the entry point the template was loaded with, rather than one of its choosing */
+    pub const TEMPLATE_ENTRY_ORIGINAL: usize = 0;
+}
+
+/* asking capsules to quiesce for consistent snapshots */
+pub mod quiesce
+{
+    /* how far a capsule asked to quiesce has got */
+    pub const QUIESCE_PENDING: usize = 0;  /* yet to respond */
+    pub const QUIESCE_DONE: usize = 1;     /* quiesced itself */
+    pub const QUIESCE_FORCED: usize = 2;   /* didn't respond in time, so was paused */
+}
+
+/* capsule measurements and the secrets sealed to them */
+pub mod sealed
+{
+    /* size of a capsule's measurement in bytes: a SHA-256 digest */
+    pub const MEASUREMENT_SIZE: usize = 32;
+
+    /* a sealed secret is laid out as this magic, the measurement of the capsule it's
+       sealed to, a random nonce, the encrypted secret, and then an authentication tag */
+    pub const SEALED_MAGIC: &[u8; 8] = b"DXSEAL1\0";
+    pub const SEALED_NONCE_SIZE: usize = 16;
+    pub const SEALED_TAG_SIZE: usize = 32;
+}
+
+/* interrupts sent between a capsule's virtual cores */
+pub mod ipi
+{
+    /* send to every virtual core in the capsule except the sender */
+    pub const IPI_ALL_OTHERS: usize = usize::MAX;
+}
+
+/* general-purpose I/O lines handed to capsules */
+pub mod gpio
+{
+    /* whether a line is read or driven */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Direction
+    {
+        Input = 0,  /* read the line's level, such as a button */
+        Output = 1  /* drive the line's level, such as an LED */
+    }
+
+    impl Direction
+    {
+        /* <= direction with the given number, or None if there's no such direction */
+        pub fn from_usize(value: usize) -> Option<Direction>
+        {
+            match value
+            {
+                0 => Some(Direction::Input),
+                1 => Some(Direction::Output),
+                _ => None
+            }
+        }
+    }
+}
+
+/* console output encodings */
+pub mod console
+{
+    /* how a capsule's console bytes should be interpreted */
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum Encoding
+    {
+        UTF8 = 0,   /* validate as UTF-8, replacing malformed sequences */
+        Raw = 1     /* pass bytes through untouched */
+    }
+
+    impl Encoding
+    {
+        /* <= encoding with the given number, or None if there's no such encoding */
+        pub fn from_usize(value: usize) -> Option<Encoding>
+        {
+            match value
+            {
+                0 => Some(Encoding::UTF8),
+                1 => Some(Encoding::Raw),
+                _ => None
+            }
+        }
+    }
+
+    /* how a capsule's console input is delivered */
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum InputMode
+    {
+        Raw = 0,    /* hand over each byte as it arrives */
+        Cooked = 1  /* echo input and allow editing, handing over whole lines */
+    }
+
+    impl InputMode
+    {
+        /* <= input mode with the given number, or None if there's no such mode */
+        pub fn from_usize(value: usize) -> Option<InputMode>
+        {
+            match value
+            {
+                0 => Some(InputMode::Raw),
+                1 => Some(InputMode::Cooked),
+                _ => None
+            }
+        }
+    }
+
+    /* bytes of a capsule's console output held for the console service, unless set otherwise */
+    pub const OUTPUT_BUFFER_DEFAULT: usize = 64 * 1024;
+
+    /* what happens when a capsule writes to a full console output buffer */
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum Overflow
+    {
+        DropOldest = 0, /* discard the oldest buffered byte to make room */
+        DropNewest = 1, /* discard the byte being written */
+        Block = 2       /* refuse the byte, asking the capsule to write it again later */
+    }
+
+    impl Overflow
+    {
+        /* <= overflow policy with the given number, or None if there's no such policy */
+        pub fn from_usize(value: usize) -> Option<Overflow>
+        {
+            match value
+            {
+                0 => Some(Overflow::DropOldest),
+                1 => Some(Overflow::DropNewest),
+                2 => Some(Overflow::Block),
+                _ => None
+            }
+        }
+    }
+}
+
+/* records written to the hypervisor's log by guests */
+pub mod guestlog
+{
+    /* how important a record is */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Severity
+    {
+        Error = 0,
+        Warning = 1,
+        Info = 2,
+        Debug = 3
+    }
+
+    impl Severity
+    {
+        /* <= severity with the given number, or None if there's no such severity */
+        pub fn from_usize(value: usize) -> Option<Severity>
+        {
+            match value
+            {
+                0 => Some(Severity::Error),
+                1 => Some(Severity::Warning),
+                2 => Some(Severity::Info),
+                3 => Some(Severity::Debug),
+                _ => None
+            }
+        }
+    }
+}
+
+/* capsule summaries copied to management services */
+pub mod capsule
+{
+    /* bytes of a capsule's name included in its summary. longer names are truncated, shorter are zero padded */
+    pub const SUMMARY_NAME_LEN: usize = 32;
+
+    /* capsule states reported in summaries */
+    pub const STATE_VALID: usize = 0;
+    pub const STATE_DYING: usize = 1;
+    pub const STATE_RESTARTING: usize = 2;
+    pub const STATE_PAUSED: usize = 3;
+
+    /* summary of a capsule, as laid out in the management service's memory */
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct CapsuleSummary
+    {
+        id: usize,
+        state: usize,       /* one of the STATE_* values */
+        vcores: usize,      /* number of virtual cores the capsule has */
+        memory: usize,      /* bytes of physical RAM mapped into the capsule */
+        name: [u8; SUMMARY_NAME_LEN]
+    }
+
+    impl CapsuleSummary
+    {
+        /* create a summary of a capsule. its name is truncated to SUMMARY_NAME_LEN bytes */
+        pub fn new(id: usize, state: usize, vcores: usize, memory: usize, name: &[u8]) -> CapsuleSummary
+        {
+            let mut padded = [0; SUMMARY_NAME_LEN];
+            let length = core::cmp::min(name.len(), SUMMARY_NAME_LEN);
+            padded[..length].copy_from_slice(&name[..length]);
+
+            CapsuleSummary { id, state, vcores, memory, name: padded }
+        }
+
+        /* create an empty summary for a capsule to pass to the hypervisor to fill in */
+        pub fn empty() -> CapsuleSummary
+        {
+            CapsuleSummary::new(0, STATE_VALID, 0, 0, &[])
+        }
+
+        pub fn id(&self) -> usize { self.id }
+        pub fn state(&self) -> usize { self.state }
+        pub fn vcores(&self) -> usize { self.vcores }
+        pub fn memory(&self) -> usize { self.memory }
+
+        /* return a short description of the capsule's state */
+        pub fn state_name(&self) -> &'static str
+        {
+            match self.state
+            {
+                STATE_VALID => "valid",
+                STATE_DYING => "dying",
+                STATE_RESTARTING => "restarting",
+                STATE_PAUSED => "paused",
+                _ => "unknown"
+            }
+        }
+
+        /* return the bytes of the capsule's name, without its zero padding */
+        pub fn name_bytes(&self) -> &[u8]
+        {
+            let length = self.name.iter().position(|c| *c == 0).unwrap_or(SUMMARY_NAME_LEN);
+            &self.name[..length]
+        }
+    }
+}
+
+/* stream connections to services, and a small client for using them from guests */
+pub mod stream
+{
+    /* maximum number of bytes waiting to be read from each direction of a connection.
+       a single send or receive moves no more than this */
+    pub const STREAM_BUFFER_MAX: usize = 16 * 1024;
+
+    /* why a stream call failed */
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Error
+    {
+        WouldBlock, /* nothing to read yet */
+        Closed,     /* the peer closed the connection */
+        Denied,     /* not allowed to use the service */
+        BadParams,  /* no such connection or service, or a bad buffer */
+        Failed      /* the hypervisor couldn't complete the call */
+    }
+
+    /* the stream hypercalls, implemented by the guest to suit its environment using the
+       Stream calls' numbers in call::Call and the platform's calling convention. a send takes as many bytes as
+       there's room for and returns how many, which may be zero. a receive returns how many
+       bytes it read, or zero if the peer closed the connection, or WouldBlock if there's
+       nothing yet. a failed send to a closed connection should be reported as Closed */
+    pub trait Calls
+    {
+        fn connect(&self, name: &str) -> Result<usize, Error>;
+        fn send(&self, connection: usize, data: &[u8]) -> Result<usize, Error>;
+        fn recv(&self, connection: usize, buffer: &mut [u8]) -> Result<usize, Error>;
+        fn close(&self, connection: usize) -> Result<(), Error>;
+
+        /* wait for VIRQ_STREAM, or just return to poll if interrupts aren't being used */
+        fn wait(&self);
+    }
+
+    /* an open connection, closed when dropped */
+    pub struct Stream<C: Calls>
+    {
+        calls: C,
+        id: usize,
+        open: bool
+    }
+
+    impl<C: Calls> Stream<C>
+    {
+        /* connect to a named service
+           => calls = the guest's stream hypercalls
+              name = name of the service, such as acme.storage
+           <= the open connection, or an error code */
+        pub fn connect(calls: C, name: &str) -> Result<Stream<C>, Error>
+        {
+            let id = calls.connect(name)?;
+            Ok(Stream { calls, id, open: true })
+        }
+
+        /* take charge of a connection accepted by a listening service
+           => calls = the guest's stream hypercalls
+              id = connection's ID
+           <= the open connection */
+        pub fn accepted(calls: C, id: usize) -> Stream<C>
+        {
+            Stream { calls, id, open: true }
+        }
+
+        /* <= the connection's ID, to match it up with others accepted by a service */
+        pub fn id(&self) -> usize { self.id }
+
+        /* send all the given bytes, waiting for the receiver to make room as needed
+           => data = bytes to send
+           <= Ok for success, or an error code */
+        #[allow(clippy::assign_op_pattern)]
+        pub fn write_all(&mut self, data: &[u8]) -> Result<(), Error>
+        {
+            let mut sent = 0;
+            while sent < data.len()
+            {
+                let end = core::cmp::min(data.len(), sent + STREAM_BUFFER_MAX);
+                match self.calls.send(self.id, &data[sent..end])?
+                {
+                    0 => self.calls.wait(),
+                    count => sent = sent + count
+                }
+            }
+            Ok(())
+        }
+
+        /* receive at least one byte, waiting until some arrive
+           => buffer = where to store the bytes
+           <= number of bytes received, which is zero if the peer closed the connection, or an error code */
+        #[allow(clippy::len_zero)]
+        pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error>
+        {
+            if buffer.len() == 0
+            {
+                return Ok(0);
+            }
+
+            loop
+            {
+                let end = core::cmp::min(buffer.len(), STREAM_BUFFER_MAX);
+                match self.calls.recv(self.id, &mut buffer[..end])
+                {
+                    Err(Error::WouldBlock) => self.calls.wait(),
+                    result => return result
+                }
+            }
+        }
+
+        /* fill the given buffer, waiting for bytes to arrive as needed
+           => buffer = where to store the bytes
+           <= Ok for success, or Closed if the peer closed the connection before the buffer was filled */
+        #[allow(clippy::assign_op_pattern)]
+        pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error>
+        {
+            let mut received = 0;
+            while received < buffer.len()
+            {
+                match self.read(&mut buffer[received..])?
+                {
+                    0 => return Err(Error::Closed),
+                    count => received = received + count
+                }
+            }
+            Ok(())
+        }
+
+        /* close the connection, reporting any error
+           <= Ok for success, or an error code */
+        pub fn close(mut self) -> Result<(), Error>
+        {
+            self.open = false;
+            self.calls.close(self.id)
+        }
+    }
+
+    impl<C: Calls> Drop for Stream<C>
+    {
+        #[allow(clippy::bool_comparison)]
+        fn drop(&mut self)
+        {
+            if self.open == true
+            {
+                let _ = self.calls.close(self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_comparison)]
+mod tests
+{
+    use super::abi::*;
+    use super::call::{Call, ActionResult, CALLS};
+
+    #[test]
+    fn packs_versions()
+    {
+        assert_eq!(unpack_version(pack_version(1, 2, 3)), (1, 2, 3));
+        assert_eq!(unpack_abi_range(pack_abi_range(ABI_VERSION_MIN, ABI_VERSION_MAX)), (ABI_VERSION_MIN, ABI_VERSION_MAX));
+    }
+
+    #[test]
+    fn grants_capabilities_by_version()
+    {
+        assert_eq!(capabilities_of_version(0), 0);
+        assert_eq!(capabilities_of_version(ABI_VERSION_DEFAULT), CAP_CONSOLE | CAP_TIMER);
+        assert!(capabilities_of_version(2) & CAP_VIRTIO != 0);
+        assert_eq!(capabilities_of_version(ABI_VERSION_MAX + 1), capabilities_of_version(ABI_VERSION_MAX));
+        assert_eq!(CAPS_V2 & CAPS_V1, CAPS_V1);
+    }
+
+    #[test]
+    fn checks_requirements()
+    {
+        let open_v1 = Requirement::new(1, 0);
+        let console = Requirement::new(1, CAP_CONSOLE);
+        let manage = Requirement::new(2, CAP_MANAGE);
+
+        assert!(open_v1.is_met(1, 0));
+        assert!(open_v1.is_met(0, 0) == false);
+        assert!(console.is_met(1, CAPS_V1));
+        assert!(console.is_met(1, CAP_TIMER) == false);
+
+        /* a new call needs both the newer ABI and the capability */
+        assert!(manage.is_met(2, CAP_MANAGE | CAP_CONSOLE));
+        assert!(manage.is_met(1, CAP_MANAGE) == false);
+        assert!(manage.is_met(2, CAPS_V2 & !CAP_MANAGE) == false);
+    }
+
+    #[test]
+    fn numbers_calls_uniquely()
+    {
+        for number in 0..CALLS
+        {
+            let call = Call::from_usize(number).unwrap();
+            assert_eq!(call as usize, number);
+            assert!(call.name().is_empty() == false);
+        }
+        assert_eq!(Call::from_usize(CALLS), None);
+
+        /* the original ABI's numbers are fixed */
+        assert_eq!(Call::Yield as usize, 0);
+        assert_eq!(Call::TimerIRQAt as usize, 9);
+        assert_eq!(Call::Identify as usize, 11);
+    }
+
+    #[test]
+    fn names_calls_distinctly()
+    {
+        for first in 0..CALLS
+        {
+            for second in (first + 1)..CALLS
+            {
+                assert!(Call::from_usize(first).unwrap().name() != Call::from_usize(second).unwrap().name());
+            }
+        }
+    }
+
+    #[test]
+    fn gates_calls_by_version_and_capability()
+    {
+        /* a capsule that never negotiates can still make the original calls */
+        let default = capabilities_of_version(ABI_VERSION_DEFAULT);
+        assert!(Call::Yield.requirement().is_met(ABI_VERSION_DEFAULT, default));
+        assert!(Call::NegotiateABI.requirement().is_met(ABI_VERSION_DEFAULT, 0));
+        assert!(Call::OutputChar.requirement().is_met(ABI_VERSION_DEFAULT, default));
+        assert!(Call::TimerIRQAt.requirement().is_met(ABI_VERSION_DEFAULT, default));
+
+        /* but not the newer ones */
+        assert!(Call::TimerAdd.requirement().is_met(ABI_VERSION_DEFAULT, default) == false);
+        assert!(Call::StreamConnect.requirement().is_met(ABI_VERSION_DEFAULT, default) == false);
+
+        /* and calls covered by a capability need it, whatever the version */
+        assert!(Call::CapsuleResume.requirement().is_met(ABI_VERSION_MAX, CAPS_V2 & !CAP_MANAGE) == false);
+        assert!(Call::CapsuleResume.requirement().is_met(ABI_VERSION_MAX, CAP_MANAGE));
+        assert!(Call::GrantCreate.requirement().is_met(ABI_VERSION_MAX, CAP_SHMEM));
+        assert!(Call::ExternalIRQClaim.requirement().is_met(ABI_VERSION_MAX, CAP_CONSOLE) == false);
+        assert!(Call::ExternalIRQComplete.requirement().is_met(ABI_VERSION_MAX, CAP_DEVICE_IRQ));
+
+        /* every call can be made by a capsule with the newest ABI and every capability */
+        for number in 0..CALLS
+        {
+            assert!(Call::from_usize(number).unwrap().requirement().is_met(ABI_VERSION_MAX, CAPS_V2));
+        }
+    }
+
+    #[test]
+    fn numbers_results()
+    {
+        for result in [ActionResult::Success, ActionResult::Failed, ActionResult::Denied, ActionResult::BadParams, ActionResult::Retry].iter()
+        {
+            assert_eq!(ActionResult::from_usize(*result as usize), Some(*result));
+        }
+        assert_eq!(ActionResult::Success as usize, 0);
+        assert_eq!(ActionResult::from_usize(5), None);
+    }
+
+    #[test]
+    fn identifies_hypervisor()
+    {
+        /* the signature spells diosix when its bytes are read in memory order */
+        assert_eq!(&HYPERVISOR_SIGNATURE.to_le_bytes()[..6], b"diosix");
+        assert_eq!(IDENTIFY_LEAF_MAX, IDENTIFY_LEAF_ABI);
+    }
+}
@@ -0,0 +1,166 @@
+/* diosix A/B guest image trials with automatic rollback
+ *
+ * When a capsule is switched to a different boot image from the DMFS
+ * image, the new image is put on trial, much like a firmware update.
+ * Once the capsule has restarted into the new image, the image must
+ * signal it's up and running with the boot confirm hypercall within
+ * TRIAL_TIMEOUT_SECONDS. If it doesn't, or the capsule restarts more
+ * than TRIAL_ATTEMPTS_MAX times without confirming, such as when it's
+ * stuck in a crash loop, the capsule is restarted into the image it
+ * was running before the switch.
+ *
+ * Trials are held in the hypervisor's memory, and so don't survive
+ * the host being rebooted.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID};
+use super::scheduler;
+use super::pcore;
+
+/* seconds a newly booted image has to confirm it's running before it's rolled back */
+const TRIAL_TIMEOUT_SECONDS: u64 = 60;
+
+/* times a capsule can start a new image without it confirming before it's rolled back */
+const TRIAL_ATTEMPTS_MAX: usize = 3;
+
+/* a capsule's switch to a new boot image that hasn't yet been confirmed */
+struct Trial
+{
+    previous: String,       /* image to fall back to */
+    candidate: String,      /* image on trial */
+    attempts: usize,        /* times the candidate has been started, or 0 if it's yet to start */
+    started: u64            /* timer value when the candidate was last started */
+}
+
+lazy_static!
+{
+    static ref TRIALS: Mutex<HashMap<CapsuleID, Trial>> = Mutex::new("boot image trials", HashMap::new());
+}
+
+/* put a capsule's newly selected boot image on trial. the trial begins when the capsule next restarts
+   => cid = capsule switching images
+      previous = name of the image the capsule is running
+      candidate = name of the image the capsule will run after it restarts */
+pub fn begin(cid: CapsuleID, previous: &str, candidate: &str)
+{
+    let mut trials = TRIALS.lock();
+
+    /* if the capsule is replacing an image still on trial, fall back to the last one known to work */
+    let previous = match trials.get(&cid)
+    {
+        Some(trial) if trial.attempts > 0 => trial.previous.clone(),
+        _ => String::from(previous)
+    };
+
+    /* switching back to the image that's known to work ends the trial */
+    if previous == candidate
+    {
+        trials.remove(&cid);
+        return;
+    }
+
+    trials.insert(cid, Trial { previous, candidate: String::from(candidate), attempts: 0, started: 0 });
+}
+
+/* note a capsule is restarting, and decide whether its image on trial should be abandoned.
+   call this before the capsule's new boot image, if any, is loaded
+   => cid = capsule restarting
+   <= name of the image to roll back to, or None to carry on */
+pub fn restarting(cid: CapsuleID) -> Option<String>
+{
+    let mut trials = TRIALS.lock();
+    let trial = match trials.get_mut(&cid)
+    {
+        Some(t) => t,
+        None => return None
+    };
+
+    if trial.attempts >= TRIAL_ATTEMPTS_MAX
+    {
+        hvalert!("Capsule {} restarted {} times without confirming boot image {}: rolling back to {}",
+            cid, trial.attempts, trial.candidate, trial.previous);
+        return trials.remove(&cid).map(|t| t.previous);
+    }
+
+    trial.attempts = trial.attempts + 1;
+    trial.started = match scheduler::timer_now()
+    {
+        Some((now, _)) => now,
+        None => 0
+    };
+    None
+}
+
+/* mark the currently running capsule's boot image as good, ending any trial.
+   <= Ok for success, or an error code */
+pub fn confirm() -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let mut trials = TRIALS.lock();
+
+    /* an image can only be confirmed once it's actually running */
+    if let Some(trial) = trials.get(&cid)
+    {
+        if trial.attempts > 0
+        {
+            hvalert!("Capsule {} confirmed boot image {}", cid, trial.candidate);
+            trials.remove(&cid);
+        }
+    }
+    Ok(())
+}
+
+/* roll back any images that haven't been confirmed in time. call this regularly from the boot core */
+pub fn housekeeper()
+{
+    let (now, frequency) = match scheduler::timer_now()
+    {
+        Some(t) => t,
+        None => return
+    };
+
+    let mut expired = Vec::new();
+    {
+        let mut trials = TRIALS.lock();
+        trials.retain(|&cid, trial|
+        {
+            if trial.attempts > 0 && now.saturating_sub(trial.started) >= TRIAL_TIMEOUT_SECONDS * frequency
+            {
+                hvalert!("Capsule {} didn't confirm boot image {} within {} seconds: rolling back to {}",
+                    cid, trial.candidate, TRIAL_TIMEOUT_SECONDS, trial.previous);
+                expired.push((cid, trial.previous.clone()));
+                return false;
+            }
+            true
+        });
+    }
+
+    /* don't hold the trials lock while restarting the capsules */
+    for (cid, previous) in expired
+    {
+        if let Err(_e) = capsule::restart_with_image(cid, previous)
+        {
+            hvalert!("Can't roll back capsule {}: {}", cid, error::report(&_e));
+        }
+    }
+}
+
+/* forget a capsule's trial when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    TRIALS.lock().remove(&cid);
+}
@@ -0,0 +1,271 @@
+/* diosix hypercall ABI versioning and capability negotiation
+ *
+ * Guests can negotiate the version of the hypercall ABI they
+ * were built for, and in return receive a bitmap of the
+ * capabilities available to them. This allows the hypervisor
+ * to evolve its hypercalls without breaking supervisor binaries
+ * bundled in older DMFS images. Capsules that never negotiate
+ * are assumed to use ABI_VERSION_DEFAULT.
+ *
+ * Each hypercall arrived in a particular ABI version, and most are
+ * covered by a capability. Calls from a newer ABI than the capsule
+ * agreed, or that need a capability it wasn't given, are refused
+ * before they're carried out. Capabilities are worked out once,
+ * when the ABI is agreed, so checking them costs a table lookup.
+ *
+ * Before negotiating, a guest can use the identify hypercall,
+ * which works like x86's CPUID, to check it's running on diosix
+ * and find out which version of the hypervisor it's running on.
+ * The same details are in the /hypervisor node of its device tree.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::pcore;
+use super::devmodel;
+use super::syscalls::Action;
+
+pub type ABIVersion = usize;
+pub type Capabilities = usize;
+
+/* the ABI's versions, capability bits, and identify leaves are shared with the services */
+pub use hypercall::abi::{ABI_VERSION_MIN, ABI_VERSION_MAX, ABI_VERSION_DEFAULT};
+pub use hypercall::abi::{CAP_CONSOLE, CAP_TIMER, CAP_CONSOLE_SERVICE, CAP_HV_LOG, CAP_DEVICE_IRQ, CAP_MANAGE, CAP_SHMEM, CAP_VIRTIO};
+use hypercall::abi::capabilities_of_version;
+use hypercall::call::Call;
+pub use hypercall::abi::{IDENTIFY_LEAF_SIGNATURE, IDENTIFY_LEAF_VERSION, IDENTIFY_LEAF_ABI, IDENTIFY_LEAF_MAX};
+pub use hypercall::abi::{HYPERVISOR_SIGNATURE, HYPERVISOR_COMPATIBLE};
+
+lazy_static!
+{
+    /* ABI versions agreed with each capsule, and the capabilities each was given */
+    static ref NEGOTIATED: Mutex<HashMap<CapsuleID, (ABIVersion, Capabilities)>> = Mutex::new("hypercall ABI table", HashMap::new());
+}
+
+/* agree a hypercall ABI version with the currently running capsule
+   => requested = the newest ABI version the capsule understands
+   <= agreed ABI version and the capsule's capability bitmap, or an error code */
+pub fn negotiate(requested: ABIVersion) -> Result<(ABIVersion, Capabilities), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(id) => id,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* can't serve a capsule that's older than anything we support */
+    if requested < ABI_VERSION_MIN
+    {
+        return Err(Cause::ABIVersionUnsupported);
+    }
+
+    /* settle on the newest version both sides understand */
+    let agreed = if requested > ABI_VERSION_MAX { ABI_VERSION_MAX } else { requested };
+    let caps = capabilities(cid, agreed)?;
+    NEGOTIATED.lock().insert(cid, (agreed, caps));
+
+    Ok((agreed, caps))
+}
+
+/* return the hypervisor's version packed into a word: major << 32 | minor << 16 | patch */
+pub fn packed_version() -> usize
+{
+    let major = env!("CARGO_PKG_VERSION_MAJOR").parse::<usize>().unwrap_or(0);
+    let minor = env!("CARGO_PKG_VERSION_MINOR").parse::<usize>().unwrap_or(0);
+    let patch = env!("CARGO_PKG_VERSION_PATCH").parse::<usize>().unwrap_or(0);
+    hypercall::abi::pack_version(major, minor, patch)
+}
+
+/* describe the hypervisor to the currently running capsule, like x86's CPUID instruction.
+   this can be called before or without negotiating an ABI version
+   => leaf = the IDENTIFY_LEAF_* number of the details wanted
+   <= pair of words describing the hypervisor, or an error code */
+pub fn identify(leaf: usize) -> Result<(usize, usize), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(id) => id,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    match leaf
+    {
+        IDENTIFY_LEAF_SIGNATURE => Ok((HYPERVISOR_SIGNATURE, IDENTIFY_LEAF_MAX)),
+        IDENTIFY_LEAF_VERSION => Ok((packed_version(), hypercall::abi::pack_abi_range(ABI_VERSION_MIN, ABI_VERSION_MAX))),
+        IDENTIFY_LEAF_ABI => Ok(agreed(cid)),
+        _ => Err(Cause::ABIBadLeaf)
+    }
+}
+
+/* <= the ABI version in use by the given capsule and the capabilities it has under it.
+   capsules that haven't negotiated have the default version's capabilities */
+fn agreed(cid: CapsuleID) -> (ABIVersion, Capabilities)
+{
+    match NEGOTIATED.lock().get(&cid)
+    {
+        Some(&agreed) => agreed,
+        None => (ABI_VERSION_DEFAULT, capabilities_of_version(ABI_VERSION_DEFAULT))
+    }
+}
+
+/* check the currently running capsule can make the given hypercall under the ABI it agreed
+   => action = hypercall decoded from the capsule's registers
+   <= true if the call can go ahead, or false to refuse it */
+pub fn permitted(action: &Action) -> bool
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) =>
+        {
+            let (version, caps) = agreed(cid);
+            call(action).requirement().is_met(version, caps)
+        },
+        None => false
+    }
+}
+
+/* <= the numbered hypercall that was decoded into the given action. the ABI version
+   and capability needed to make each call are listed with its number */
+pub fn call(action: &Action) -> Call
+{
+    match action
+    {
+        Action::Yield => Call::Yield,
+        Action::NegotiateABI(..) => Call::NegotiateABI,
+        Action::Identify(..) => Call::Identify,
+        Action::Terminate => Call::Terminate,
+        Action::Restart => Call::Restart,
+        Action::TimerIRQAt(..) => Call::TimerIRQAt,
+        Action::TimerAdd(..) => Call::TimerAdd,
+        Action::TimerCancel(..) => Call::TimerCancel,
+        Action::TimerFiredNext => Call::TimerFiredNext,
+        Action::MetricsRead(..) => Call::MetricsRead,
+        Action::MetricsReadSystem(..) => Call::MetricsReadSystem,
+        Action::TelemetryRead(..) => Call::TelemetryRead,
+        Action::GuestLog(..) => Call::GuestLog,
+        Action::GuestPanic(..) => Call::GuestPanic,
+        Action::CapsulePanicMessage(..) => Call::CapsulePanicMessage,
+        Action::CapsulePanicRegisters(..) => Call::CapsulePanicRegisters,
+        Action::CapsuleCrashDump(..) => Call::CapsuleCrashDump,
+        Action::CapsuleReadRegisters(..) => Call::CapsuleReadRegisters,
+        Action::CapsuleReadMemory(..) => Call::CapsuleReadMemory,
+        Action::InventoryRead(..) => Call::InventoryRead,
+        Action::IPISend(..) => Call::IPISend,
+        Action::IPIClaim => Call::IPIClaim,
+        Action::SerialLinkPutc(..) => Call::SerialLinkPutc,
+        Action::SerialLinkGetc(..) => Call::SerialLinkGetc,
+        Action::OutputChar(..) => Call::OutputChar,
+        Action::InputChar => Call::InputChar,
+        Action::ConsoleBufferWriteChar(..) => Call::ConsoleBufferWriteChar,
+        Action::ConsoleBufferOverflows(..) => Call::ConsoleBufferOverflows,
+        Action::ConsoleBufferReadChar => Call::ConsoleBufferReadChar,
+        Action::ConsoleEncoding(..) => Call::ConsoleEncoding,
+        Action::ConsoleInputMode(..) => Call::ConsoleInputMode,
+        Action::HypervisorBufferReadChar => Call::HypervisorBufferReadChar,
+        Action::TraceReadChar => Call::TraceReadChar,
+        Action::SelfTestRun => Call::SelfTestRun,
+        Action::ExternalIRQClaim => Call::ExternalIRQClaim,
+        Action::CapsuleCrashedNext => Call::CapsuleCrashedNext,
+        Action::CapsuleResume(..) => Call::CapsuleResume,
+        Action::CapsuleKillPaused(..) => Call::CapsuleKillPaused,
+        Action::CapsuleWorkingSet(..) => Call::CapsuleWorkingSet,
+        Action::WarmReboot => Call::WarmReboot,
+        Action::HostReset(..) => Call::HostReset,
+        Action::MemoryPressureSubscribe => Call::MemoryPressureSubscribe,
+        Action::GrantCreate(..) => Call::GrantCreate,
+        Action::GrantAccept(..) => Call::GrantAccept,
+        Action::GrantRelease(..) => Call::GrantRelease,
+        Action::GrantRevoke(..) => Call::GrantRevoke,
+        Action::DirtyLogStart(..) => Call::DirtyLogStart,
+        Action::DirtyLogRead(..) => Call::DirtyLogRead,
+        Action::DirtyLogStop(..) => Call::DirtyLogStop,
+        Action::MMIOMap(..) => Call::MMIOMap,
+        Action::MMIOUnmap(..) => Call::MMIOUnmap,
+        Action::TemplateQuiesce(..) => Call::TemplateQuiesce,
+        Action::TemplateMark(..) => Call::TemplateMark,
+        Action::TemplateUnmark(..) => Call::TemplateUnmark,
+        Action::TemplateClone(..) => Call::TemplateClone,
+        Action::CapsuleQuiesce(..) => Call::CapsuleQuiesce,
+        Action::CapsuleQuiesceStatus(..) => Call::CapsuleQuiesceStatus,
+        Action::CapsuleThaw(..) => Call::CapsuleThaw,
+        Action::QuiesceDone => Call::QuiesceDone,
+        Action::CapsulePressButton(..) => Call::CapsulePressButton,
+        Action::ButtonCollect => Call::ButtonCollect,
+        Action::MeasurementRead(..) => Call::MeasurementRead,
+        Action::SecretRead(..) => Call::SecretRead,
+        Action::SettingRead(..) => Call::SettingRead,
+        Action::SettingWrite(..) => Call::SettingWrite,
+        Action::SettingVerbosity(..) => Call::SettingVerbosity,
+        Action::SettingsSave => Call::SettingsSave,
+        Action::GpioSetDirection(..) => Call::GpioSetDirection,
+        Action::GpioWrite(..) => Call::GpioWrite,
+        Action::GpioRead(..) => Call::GpioRead,
+        Action::CapsuleSnapshot(..) => Call::CapsuleSnapshot,
+        Action::CapsuleBootConfirm => Call::CapsuleBootConfirm,
+        Action::CapsuleSelectBootImage(..) => Call::CapsuleSelectBootImage,
+        Action::SelectService(..) => Call::SelectService,
+        Action::TransferOffer(..) => Call::TransferOffer,
+        Action::TransferNext(..) => Call::TransferNext,
+        Action::TransferAccept(..) => Call::TransferAccept,
+        Action::BounceMap(..) => Call::BounceMap,
+        Action::BounceUnmap(..) => Call::BounceUnmap,
+        Action::TimeMonotonic => Call::TimeMonotonic,
+        Action::TimeWallClock => Call::TimeWallClock,
+        Action::TimeSetOffset(..) => Call::TimeSetOffset,
+        Action::RegisterService(..) => Call::RegisterService,
+        Action::RegisterServiceName(..) => Call::RegisterServiceName,
+        Action::DeregisterServiceName(..) => Call::DeregisterServiceName,
+        Action::LookupServiceName(..) => Call::LookupServiceName,
+        Action::StreamListen(..) => Call::StreamListen,
+        Action::StreamConnect(..) => Call::StreamConnect,
+        Action::StreamAccept => Call::StreamAccept,
+        Action::StreamSend(..) => Call::StreamSend,
+        Action::StreamRecv(..) => Call::StreamRecv,
+        Action::StreamClose(..) => Call::StreamClose,
+        Action::ExternalIRQComplete(..) => Call::ExternalIRQComplete
+    }
+}
+
+/* forget the ABI agreed with a capsule, so it must negotiate again.
+   call this when a capsule is destroyed or restarted */
+pub fn forget(cid: CapsuleID)
+{
+    NEGOTIATED.lock().remove(&cid);
+}
+
+/* work out the capabilities available to a capsule under the given ABI version */
+fn capabilities(cid: CapsuleID, version: ABIVersion) -> Result<Capabilities, Cause>
+{
+    /* any capsule can use its own console and timer, and grant its own memory */
+    let mut caps = CAP_CONSOLE | CAP_TIMER | CAP_SHMEM;
+
+    if capsule::has_property(cid, CapsuleProperty::ConsoleRead)? || capsule::has_property(cid, CapsuleProperty::ConsoleWrite)?
+    {
+        caps = caps | CAP_CONSOLE_SERVICE;
+    }
+    if capsule::has_property(cid, CapsuleProperty::HvLogRead)?
+    {
+        caps = caps | CAP_HV_LOG;
+    }
+    if capsule::get_serial_ports(cid)?.len() > 0
+    {
+        caps = caps | CAP_DEVICE_IRQ;
+    }
+    if capsule::has_property(cid, CapsuleProperty::ManageCapsules)?
+    {
+        caps = caps | CAP_MANAGE;
+    }
+    if devmodel::get_windows(cid).len() > 0
+    {
+        caps = caps | CAP_VIRTIO;
+    }
+
+    /* drop those that arrived after the given version of the ABI */
+    Ok(caps & capabilities_of_version(version))
+}
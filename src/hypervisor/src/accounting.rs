@@ -0,0 +1,197 @@
+/* diosix per-capsule time accounting
+ *
+ * Keep track of how long each capsule's virtual cores run in guest
+ * mode, and separately, how long the hypervisor spends working on the
+ * capsule's behalf: handling its hypercalls, emulating its instructions
+ * and handling its faults, and running its device models. A guest that
+ * uses little CPU time itself can still cost the host a lot if it keeps
+ * the hypervisor busy, and these figures show up such guests.
+ *
+ * Each physical core notes the time as it enters the hypervisor to
+ * handle a trap, and charges the time since it last returned to a guest
+ * to that guest's capsule as guest time. When it's done with the trap,
+ * it charges the time spent handling it to the capsule that trapped,
+ * minus any time already charged to the capsule's device models. Time
+ * spent on interrupts and scheduling isn't charged to any capsule.
+ *
+ * The times are kept in timer ticks, and published as per-capsule
+ * metrics, in microseconds, by the housekeeper. They're therefore up to
+ * a housekeeping period out of date.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::capsule::{self, CapsuleID};
+use super::pcore::{PhysicalCore, PhysicalCoreID};
+use super::scheduler;
+use super::metrics::{self, Counter};
+
+/* what a capsule's time was spent on */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Activity
+{
+    Guest = 0,      /* running the capsule's code in guest mode */
+    Hypercall,      /* handling the capsule's hypercalls */
+    Fault,          /* emulating the capsule's instructions and handling its faults */
+    DeviceModel     /* running the capsule's device models */
+}
+
+const ACTIVITIES: usize = Activity::DeviceModel as usize + 1;
+
+/* metrics each activity is published as */
+const COUNTERS: [Counter; ACTIVITIES] = [ Counter::GuestTime, Counter::HypercallTime, Counter::FaultTime, Counter::DeviceModelTime ];
+
+/* what a physical core is timing */
+#[derive(Clone, Copy)]
+struct Core
+{
+    trap: Option<(CapsuleID, u64)>,     /* capsule whose trap is being handled, and when it trapped */
+    nested: u64,                        /* ticks of the trap already charged to device models */
+    resumed: Option<(CapsuleID, u64)>   /* capsule the core last returned to, and when */
+}
+
+lazy_static!
+{
+    static ref CORES: Mutex<HashMap<PhysicalCoreID, Core>> = Mutex::new("time accounting cores", HashMap::new());
+    static ref TICKS: Mutex<HashMap<CapsuleID, [u64; ACTIVITIES]>> = Mutex::new("time accounting", HashMap::new());
+}
+
+/* <= timer value now in exact ticks, or None if there's no timer */
+fn now() -> Option<u64>
+{
+    scheduler::timer_now().map(|(now, _)| now)
+}
+
+/* add time to what a capsule has spent on an activity */
+fn charge(cid: CapsuleID, activity: Activity, ticks: u64)
+{
+    if ticks > 0
+    {
+        let mut times = TICKS.lock();
+        let entry = times.entry(cid).or_insert([0; ACTIVITIES]);
+        entry[activity as usize] = entry[activity as usize].wrapping_add(ticks);
+    }
+}
+
+/* note this physical core has entered the hypervisor to handle a trap or interrupt,
+   charging the capsule it was running for its time in guest mode. call at the start of the IRQ handler */
+pub fn enter()
+{
+    let now = match now()
+    {
+        Some(now) => now,
+        None => return
+    };
+    let cid = PhysicalCore::get_capsule_id();
+
+    let resumed =
+    {
+        let mut cores = CORES.lock();
+        let core = cores.entry(PhysicalCore::get_id()).or_insert(Core { trap: None, nested: 0, resumed: None });
+        core.trap = cid.map(|cid| (cid, now));
+        core.nested = 0;
+        core.resumed.take()
+    };
+
+    if let Some((resumed, since)) = resumed
+    {
+        charge(resumed, Activity::Guest, now.saturating_sub(since));
+    }
+}
+
+/* note this physical core is about to leave the hypervisor, charging the capsule that trapped for its handling.
+   call at the end of the IRQ handler
+   => activity = what the trap was handled for, or None if it isn't charged to the capsule, such as an interrupt */
+pub fn leave(activity: Option<Activity>)
+{
+    let now = match now()
+    {
+        Some(now) => now,
+        None => return
+    };
+
+    /* the core may have switched to another capsule's vcore while handling the trap */
+    let resumed = PhysicalCore::get_capsule_id().map(|cid| (cid, now));
+
+    let handled =
+    {
+        let mut cores = CORES.lock();
+        let core = cores.entry(PhysicalCore::get_id()).or_insert(Core { trap: None, nested: 0, resumed: None });
+        core.resumed = resumed;
+        match core.trap.take()
+        {
+            Some((cid, since)) => Some((cid, now.saturating_sub(since).saturating_sub(core.nested))),
+            None => None
+        }
+    };
+
+    if let (Some(activity), Some((cid, ticks))) = (activity, handled)
+    {
+        charge(cid, activity, ticks);
+    }
+}
+
+/* run part of a capsule's device model, charging the capsule for the time it takes
+   => cid = capsule the device model belongs to
+      f = code to run
+   <= whatever f returns */
+pub fn device_model<R, F: FnOnce() -> R>(cid: CapsuleID, f: F) -> R
+{
+    let start = now();
+    let result = f();
+
+    if let (Some(start), Some(end)) = (start, now())
+    {
+        let ticks = end.saturating_sub(start);
+        charge(cid, Activity::DeviceModel, ticks);
+
+        /* don't charge this time twice if it's part of handling a trap */
+        if let Some(core) = CORES.lock().get_mut(&PhysicalCore::get_id())
+        {
+            if core.trap.is_some()
+            {
+                core.nested = core.nested.saturating_add(ticks);
+            }
+        }
+    }
+
+    result
+}
+
+/* publish capsules' times as metrics, in microseconds, and drop the times of capsules that
+   no longer exist. call this regularly from the boot core */
+pub fn housekeeper()
+{
+    let frequency = match scheduler::timer_now()
+    {
+        Some((_, frequency)) if frequency > 0 => frequency,
+        _ => return
+    };
+
+    let times: Vec<(CapsuleID, [u64; ACTIVITIES])> = TICKS.lock().iter().map(|(cid, ticks)| (*cid, *ticks)).collect();
+    for (cid, ticks) in times
+    {
+        if capsule::get_state(cid).is_none()
+        {
+            forget(cid);
+            continue;
+        }
+
+        for (activity, ticks) in ticks.iter().enumerate()
+        {
+            let micros = (*ticks as u128 * 1000000 / frequency as u128) as u64;
+            metrics::set(cid, COUNTERS[activity], micros);
+        }
+    }
+}
+
+/* discard a capsule's times when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    TICKS.lock().remove(&cid);
+}
@@ -0,0 +1,260 @@
+/* diosix built-in administration commands
+ *
+ * Let the host be administered over the debug port on systems without
+ * a console service capsule. Typing the escape sequence, escape then a
+ * colon, brings up a prompt that accepts one line-based command:
+ *
+ *   list                   list the capsules and their states
+ *   start <name>           create a capsule from the named DMFS executable
+ *   stop <id>              kill the given capsule
+ *   restart <id>           restart the given capsule
+ *   metrics <id>           show the given capsule's activity counters
+ *   loglevel <severity>    only keep guest log records at least this important:
+ *                          error, warning, info, or debug
+ *   settings               list the hypervisor's live settings
+ *   set <name> <value>     change a live setting, eg: set timeslice_ms 20, or
+ *                          a module's debug log verbosity, eg: set log.capsule alerts
+ *   save                   keep the live settings for the next boot, if the host
+ *                          has a persistent store
+ *   heap                   list each module's live heap allocations, if the
+ *                          hypervisor was built with the heapaudit feature
+ *   help                   list these commands
+ *
+ * Escape or ctrl-c abandons the command. Keypresses are read by the
+ * status screen's housekeeping, which passes them here first.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::lock::Mutex;
+use super::capsule::{self, CapsuleID};
+use super::manifest;
+use super::metrics::{self, Counter, COUNTERS};
+use super::guestlog::{self, Severity};
+use super::error::{self, Cause};
+use super::settings;
+#[cfg(feature = "heapaudit")]
+use super::heap;
+
+/* escape then this character brings up the prompt */
+const ESCAPE: char = '\x1b';
+const ESCAPE_COMMAND: char = ':';
+
+/* keys that abandon a command */
+const CANCEL: char = '\x03';
+
+/* longest command line accepted, in characters */
+const COMMAND_MAX_LEN: usize = 80;
+
+/* what the keypresses are currently doing */
+#[derive(Clone, Copy, PartialEq)]
+enum Mode
+{
+    Idle,       /* passed on to the other hotkeys */
+    Escaped,    /* escape seen, waiting to see if the command character follows */
+    Command     /* building a command line */
+}
+
+struct Prompt
+{
+    mode: Mode,
+    line: String
+}
+
+lazy_static!
+{
+    static ref PROMPT: Mutex<Prompt> = Mutex::new("admin command prompt", Prompt { mode: Mode::Idle, line: String::new() });
+}
+
+/* handle a keypress from the debug port
+   => c = character typed
+   <= true if the keypress was used here, or false to pass it on to the other hotkeys */
+pub fn input(c: char) -> bool
+{
+    let command =
+    {
+        let mut prompt = PROMPT.lock();
+        match (prompt.mode, c)
+        {
+            (Mode::Idle, ESCAPE) =>
+            {
+                prompt.mode = Mode::Escaped;
+                return true;
+            },
+            (Mode::Idle, _) => return false,
+
+            (Mode::Escaped, ESCAPE_COMMAND) =>
+            {
+                prompt.mode = Mode::Command;
+                prompt.line.clear();
+                hvprint!("\r\ndiosix> ");
+                return true;
+            },
+            (Mode::Escaped, _) =>
+            {
+                prompt.mode = Mode::Idle;
+                return false;
+            },
+
+            (Mode::Command, ESCAPE) | (Mode::Command, CANCEL) =>
+            {
+                prompt.mode = Mode::Idle;
+                hvprintln!(" [cancelled]");
+                return true;
+            },
+            (Mode::Command, '\r') | (Mode::Command, '\n') =>
+            {
+                prompt.mode = Mode::Idle;
+                hvprintln!("");
+                prompt.line.clone()
+            },
+            (Mode::Command, '\x7f') | (Mode::Command, '\x08') =>
+            {
+                if prompt.line.pop().is_some()
+                {
+                    hvprint!("\x08 \x08");
+                }
+                return true;
+            },
+            (Mode::Command, c) =>
+            {
+                if c.is_control() == false && prompt.line.chars().count() < COMMAND_MAX_LEN
+                {
+                    prompt.line.push(c);
+                    hvprint!("{}", c);
+                }
+                return true;
+            }
+        }
+    };
+
+    /* don't hold the prompt's lock while carrying out the command */
+    if let Err(e) = execute(&command)
+    {
+        hvprintln!("Failed: {}", error::report(&e));
+    }
+    true
+}
+
+/* carry out a command line
+   => line = the command and its parameters, separated by whitespace
+   <= Ok for success, or an error code */
+fn execute(line: &str) -> Result<(), Cause>
+{
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match (words.get(0), words.get(1))
+    {
+        (None, _) => (),
+
+        (Some(&"list"), None) =>
+        {
+            hvprintln!("{:>6} {:<10} {:>6}  {}", "ID", "STATE", "VCORES", "NAME");
+            for summary in capsule::snapshot()
+            {
+                hvprintln!("{:>6} {:<10} {:>6}  {}", summary.id(), summary.state_name(), summary.vcores(),
+                    String::from_utf8_lossy(summary.name_bytes()));
+            }
+        },
+
+        (Some(&"start"), Some(name)) =>
+        {
+            let cid = manifest::start_executable(name)?;
+            hvprintln!("Started {} as capsule {}", name, cid);
+        },
+
+        (Some(&"stop"), Some(id)) =>
+        {
+            capsule::kill(parse_id(id)?)?;
+            hvprintln!("Stopping capsule {}", id);
+        },
+
+        (Some(&"restart"), Some(id)) =>
+        {
+            capsule::request_restart(parse_id(id)?)?;
+            hvprintln!("Restarting capsule {}", id);
+        },
+
+        (Some(&"metrics"), Some(id)) =>
+        {
+            let cid = parse_id(id)?;
+            if capsule::get_state(cid).is_none()
+            {
+                return Err(Cause::CapsuleBadID);
+            }
+
+            let counts = metrics::snapshot(cid).unwrap_or([0; COUNTERS]);
+            for (index, count) in counts.iter().enumerate()
+            {
+                if let Some(counter) = Counter::from_usize(index)
+                {
+                    hvprintln!("{:>20} {:?}", count, counter);
+                }
+            }
+        },
+
+        (Some(&"loglevel"), Some(level)) =>
+        {
+            guestlog::set_level(match *level
+            {
+                "error" => Severity::Error,
+                "warning" => Severity::Warning,
+                "info" => Severity::Info,
+                "debug" => Severity::Debug,
+                _ => return Err(Cause::AdminBadCommand)
+            });
+            hvprintln!("Guest log level set to {}", level);
+        },
+
+        (Some(&"settings"), None) =>
+        {
+            for line in settings::list().lines()
+            {
+                hvprintln!("{}", line);
+            }
+        },
+
+        (Some(&"set"), Some(name)) =>
+        {
+            let value = words.get(2).ok_or(Cause::AdminBadCommand)?;
+            settings::set_by_name(name, value)?;
+            hvprintln!("Set {} to {}", name, value);
+        },
+
+        (Some(&"save"), None) =>
+        {
+            settings::save()?;
+            hvprintln!("Settings saved for the next boot");
+        },
+
+        (Some(&"heap"), None) =>
+        {
+            #[cfg(feature = "heapaudit")]
+            {
+                hvprintln!("{:>8} {:>10}  {}", "BLOCKS", "BYTES", "MODULE");
+                for (module, blocks, bytes) in heap::audit()
+                {
+                    hvprintln!("{:>8} {:>10}  {}", blocks, bytes, module);
+                }
+            }
+
+            #[cfg(not(feature = "heapaudit"))]
+            hvprintln!("Heap auditing needs the hypervisor to be built with the heapaudit feature");
+        },
+
+        (Some(&"help"), None) => hvprintln!("Commands: list, start <name>, stop <id>, restart <id>, metrics <id>, loglevel <error|warning|info|debug>, settings, set <name> <value>, save, heap"),
+
+        (Some(_), _) => return Err(Cause::AdminBadCommand)
+    }
+
+    Ok(())
+}
+
+/* <= capsule ID written in decimal, or an error code if it's malformed */
+fn parse_id(word: &str) -> Result<CapsuleID, Cause>
+{
+    word.parse::<CapsuleID>().map_err(|_| Cause::AdminBadCommand)
+}
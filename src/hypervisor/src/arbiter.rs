@@ -0,0 +1,279 @@
+/* diosix debug port arbiter
+ *
+ * The hypervisor's debug output and the output of capsules with the
+ * console_write property share the host's debug port, typically a UART.
+ * Written as it arrives, a burst of debug output can hold the port for
+ * long enough to stall a capsule's console, and a capsule's characters
+ * land in the middle of the hypervisor's lines and vice versa.
+ *
+ * Instead, each source of output queues it here in a channel of its own,
+ * and the channels take turns on the port. Each turn is limited to the
+ * source's quota of bytes, and ends early at the end of a line where
+ * possible, so that lines aren't broken up. If a source's turn ends in the
+ * middle of a line, the port moves to a fresh line before the next source
+ * writes. The hypervisor gets a larger quota than each capsule. Alerts
+ * don't wait their turn: they're written before anything else. Each call
+ * to pump() writes a limited number of bytes, besides alerts, so that
+ * whichever physical core is pumping isn't held up for long.
+ *
+ * A capsule whose channel is full has its console write refused with a
+ * retry error, so that it can try again once its output has drained,
+ * rather than losing it. The hypervisor's own debug output waits in the
+ * debug queue, as it does when the port isn't ready.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::capsule::CapsuleID;
+use super::hardware;
+
+/* most bytes of output each kind of source can have waiting */
+const ALERT_PENDING_MAX: usize = 16 * 1024;
+const HYPERVISOR_PENDING_MAX: usize = 16 * 1024;
+const CAPSULE_PENDING_MAX: usize = 4 * 1024;
+
+/* most bytes each kind of source can write in one turn */
+const HYPERVISOR_QUOTA: usize = 512;
+const CAPSULE_QUOTA: usize = 128;
+
+/* most bytes of non-alert output written by one call to pump() */
+const PUMP_BUDGET: usize = 1024;
+
+/* written to move the port to a fresh line when a source's turn ends mid-line */
+const LINE_BREAK: &str = "\r\n";
+
+/* where output comes from */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source
+{
+    Alert,
+    Hypervisor,
+    Capsule(CapsuleID)
+}
+
+/* output waiting from one source */
+struct Channel
+{
+    source: Source,
+    pending: String
+}
+
+impl Channel
+{
+    /* <= most bytes this channel can have waiting, and write in one turn */
+    fn limits(&self) -> (usize, usize)
+    {
+        match self.source
+        {
+            Source::Alert => (ALERT_PENDING_MAX, ALERT_PENDING_MAX),
+            Source::Hypervisor => (HYPERVISOR_PENDING_MAX, HYPERVISOR_QUOTA),
+            Source::Capsule(_) => (CAPSULE_PENDING_MAX, CAPSULE_QUOTA)
+        }
+    }
+
+    /* <= number of bytes to write in this channel's next turn: up to its quota,
+          cut after the last line ending within that if there is one */
+    fn next_turn(&self) -> usize
+    {
+        let (_, quota) = self.limits();
+        if self.pending.len() <= quota
+        {
+            return self.pending.len();
+        }
+
+        let mut end = quota;
+        while self.pending.is_char_boundary(end) == false
+        {
+            end = end - 1;
+        }
+
+        match self.pending[..end].rfind('\n')
+        {
+            Some(newline) => newline + 1,
+            None => end
+        }
+    }
+}
+
+struct Arbiter
+{
+    alerts: Channel,
+    channels: Vec<Channel>,     /* the hypervisor's channel, then any capsules', in turn order */
+    turn: usize,                /* index into channels of the next to take a turn */
+    last: Option<Source>,       /* source that last wrote to the port */
+    mid_line: bool              /* true if the port was left in the middle of a line */
+}
+
+impl Arbiter
+{
+    fn new() -> Arbiter
+    {
+        Arbiter
+        {
+            alerts: Channel { source: Source::Alert, pending: String::new() },
+            channels: vec![Channel { source: Source::Hypervisor, pending: String::new() }],
+            turn: 0,
+            last: None,
+            mid_line: false
+        }
+    }
+
+    fn channel(&mut self, source: Source) -> &mut Channel
+    {
+        if source == Source::Alert
+        {
+            return &mut self.alerts;
+        }
+
+        let index = match self.channels.iter().position(|c| c.source == source)
+        {
+            Some(index) => index,
+            None =>
+            {
+                self.channels.push(Channel { source, pending: String::new() });
+                self.channels.len() - 1
+            }
+        };
+        &mut self.channels[index]
+    }
+
+    /* write text from the given source to the port, moving to a fresh line first if another source left it mid-line
+       <= true if written, or false if the port isn't ready */
+    fn emit(&mut self, source: Source, text: &str) -> bool
+    {
+        if self.mid_line == true && self.last != Some(source)
+        {
+            if hardware::write_debug_string(LINE_BREAK) == false
+            {
+                return false;
+            }
+            self.mid_line = false;
+        }
+
+        if hardware::write_debug_string(text) == false
+        {
+            return false;
+        }
+
+        self.last = Some(source);
+        self.mid_line = text.ends_with('\n') == false;
+        true
+    }
+}
+
+lazy_static!
+{
+    static ref ARBITER: Mutex<Arbiter> = Mutex::new("debug port arbiter", Arbiter::new());
+}
+
+/* queue output for the debug port. alerts that don't fit push out the oldest alerts
+   => source = where the output comes from
+      text = output to write
+   <= true if queued, or false if the source's channel is too full to take it */
+pub fn write(source: Source, text: &str) -> bool
+{
+    let mut arbiter = ARBITER.lock();
+    let channel = arbiter.channel(source);
+    let (pending_max, _) = channel.limits();
+
+    if channel.pending.len() + text.len() > pending_max
+    {
+        if source != Source::Alert
+        {
+            return false;
+        }
+
+        /* keep the newest alerts, without splitting a character */
+        let mut to_drop = core::cmp::min((channel.pending.len() + text.len()) - pending_max, channel.pending.len());
+        while channel.pending.is_char_boundary(to_drop) == false
+        {
+            to_drop = to_drop + 1;
+        }
+        channel.pending.drain(..to_drop);
+    }
+
+    channel.pending.push_str(text);
+    true
+}
+
+/* give waiting output its turns on the debug port, alerts first. this doesn't wait if another
+   physical core is already doing this, nor does it wait for the port if it isn't ready */
+pub fn pump()
+{
+    pump_with_budget(PUMP_BUDGET);
+}
+
+/* write out any waiting alerts, and nothing else */
+pub fn pump_alerts()
+{
+    pump_with_budget(0);
+}
+
+/* => budget = most bytes of non-alert output to write */
+fn pump_with_budget(mut budget: usize)
+{
+    if ARBITER.is_locked() == true
+    {
+        return;
+    }
+    let mut arbiter = ARBITER.lock();
+
+    if arbiter.alerts.pending.len() > 0
+    {
+        let alerts = core::mem::take(&mut arbiter.alerts.pending);
+        if arbiter.emit(Source::Alert, alerts.as_str()) == false
+        {
+            arbiter.alerts.pending = alerts;
+            return;
+        }
+    }
+
+    let mut idle = 0;
+    while budget > 0 && idle < arbiter.channels.len()
+    {
+        if arbiter.turn >= arbiter.channels.len()
+        {
+            arbiter.turn = 0;
+        }
+        let index = arbiter.turn;
+        arbiter.turn = arbiter.turn + 1;
+
+        let length = arbiter.channels[index].next_turn();
+        if length == 0
+        {
+            idle = idle + 1;
+            continue;
+        }
+        idle = 0;
+
+        let source = arbiter.channels[index].source;
+        let text: String = arbiter.channels[index].pending.drain(..length).collect();
+        if arbiter.emit(source, text.as_str()) == false
+        {
+            /* put the output back and try again later */
+            arbiter.channels[index].pending.insert_str(0, text.as_str());
+            return;
+        }
+        budget = budget.saturating_sub(length);
+    }
+}
+
+/* <= true if the given source has no output waiting */
+pub fn is_drained(source: Source) -> bool
+{
+    let mut arbiter = ARBITER.lock();
+    arbiter.channel(source).pending.len() == 0
+}
+
+/* discard a capsule's channel and any output it had waiting when the capsule is destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    let mut arbiter = ARBITER.lock();
+    arbiter.channels.retain(|c| c.source != Source::Capsule(cid));
+    arbiter.turn = 0;
+}
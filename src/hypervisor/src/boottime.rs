@@ -0,0 +1,157 @@
+/* diosix capsule boot-time accounting
+ *
+ * Time how long each stage of bringing up a capsule takes: allocating
+ * and mapping its physical memory, generating its device tree, copying
+ * and relocating its image, and then waiting for the scheduler to first
+ * run one of its virtual cores. When the capsule is first scheduled, the
+ * breakdown is written to the debug log and stored in the capsule's
+ * metrics counters, so that management services can spot regressions in
+ * guest boot latency and see which stage is to blame.
+ *
+ * Restarted capsules are timed too. Only the stages carried out again,
+ * such as loading a newly selected boot image, are counted.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::capsule::CapsuleID;
+use super::metrics::{self, Counter};
+use super::scheduler;
+
+/* stages of bringing up a capsule */
+#[derive(Clone, Copy)]
+pub enum Stage
+{
+    Regions = 0,    /* allocating and mapping physical memory */
+    Image,          /* copying and relocating the capsule's image */
+    DeviceTree,     /* generating the capsule's device tree */
+    FirstSchedule   /* waiting for a virtual core to be first scheduled */
+}
+
+const STAGES: usize = Stage::FirstSchedule as usize + 1;
+
+/* the metrics counters the stages are reported through, in stage order */
+const STAGE_COUNTERS: [Counter; STAGES] = [Counter::BootRegions, Counter::BootImage, Counter::BootDeviceTree, Counter::BootFirstSchedule];
+
+/* a capsule being brought up */
+struct Boot
+{
+    ticks: [u64; STAGES],       /* timer ticks spent in each stage */
+    queued_at: Option<u64>      /* when the capsule's vcores were queued to run, or None if not yet */
+}
+
+lazy_static!
+{
+    static ref BOOTS: Mutex<HashMap<CapsuleID, Boot>> = Mutex::new("capsule boot times", HashMap::new());
+}
+
+/* number of capsules with vcores queued that haven't yet been scheduled. this lets context
+   switches skip the lock when there's nothing to report */
+static AWAITING: AtomicUsize = AtomicUsize::new(0);
+
+/* note the start of a stage
+   <= timer value to pass to record() at the end of the stage, or None if there's no timer */
+pub fn start() -> Option<u64>
+{
+    match scheduler::timer_now()
+    {
+        Some((now, _)) => Some(now),
+        None => None
+    }
+}
+
+/* account for a stage of bringing up a capsule. stages carried out more than once are summed
+   => cid = capsule being brought up
+      stage = the stage that's finished
+      started = value returned by start() at the beginning of the stage */
+pub fn record(cid: CapsuleID, stage: Stage, started: Option<u64>)
+{
+    if let (Some(started), Some(now)) = (started, start())
+    {
+        let mut boots = BOOTS.lock();
+        let boot = boots.entry(cid).or_insert(Boot { ticks: [0; STAGES], queued_at: None });
+        boot.ticks[stage as usize] = boot.ticks[stage as usize] + now.saturating_sub(started);
+    }
+}
+
+/* note that a capsule's virtual cores have been queued to run. the wait until one of them is
+   first scheduled is the final stage of bringing the capsule up
+   => cid = capsule being brought up */
+pub fn queued(cid: CapsuleID)
+{
+    if let Some(now) = start()
+    {
+        let mut boots = BOOTS.lock();
+        let boot = boots.entry(cid).or_insert(Boot { ticks: [0; STAGES], queued_at: None });
+        if boot.queued_at.is_none()
+        {
+            AWAITING.fetch_add(1, Ordering::SeqCst);
+        }
+        boot.queued_at = Some(now);
+    }
+}
+
+/* note that a capsule is about to run on this physical core. if that completes its bring up,
+   report how long each stage took. call this from context switches
+   => cid = capsule about to run */
+pub fn scheduled(cid: CapsuleID)
+{
+    if AWAITING.load(Ordering::Relaxed) == 0
+    {
+        return;
+    }
+
+    let (now, frequency) = match scheduler::timer_now()
+    {
+        Some(t) => t,
+        None => return
+    };
+
+    let mut boot = match BOOTS.lock().remove(&cid)
+    {
+        Some(b) => b,
+        None => return
+    };
+
+    let queued_at = match boot.queued_at
+    {
+        Some(q) => q,
+        None =>
+        {
+            /* still being built, so put it back */
+            BOOTS.lock().insert(cid, boot);
+            return;
+        }
+    };
+    AWAITING.fetch_sub(1, Ordering::SeqCst);
+    boot.ticks[Stage::FirstSchedule as usize] = now.saturating_sub(queued_at);
+
+    /* report the breakdown in microseconds */
+    let mut micros = [0; STAGES];
+    for (stage, ticks) in boot.ticks.iter().enumerate()
+    {
+        micros[stage] = (ticks * 1000000) / frequency;
+        metrics::set(cid, STAGE_COUNTERS[stage], micros[stage]);
+    }
+
+    hvdebug!("Capsule {} boot time {} us: memory {} us, image {} us, device tree {} us, first schedule {} us",
+        cid, micros.iter().sum::<u64>(), micros[Stage::Regions as usize], micros[Stage::Image as usize],
+        micros[Stage::DeviceTree as usize], micros[Stage::FirstSchedule as usize]);
+}
+
+/* discard a capsule's timings if it's destroyed before it's scheduled */
+pub fn forget(cid: CapsuleID)
+{
+    if let Some(boot) = BOOTS.lock().remove(&cid)
+    {
+        if boot.queued_at.is_some()
+        {
+            AWAITING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
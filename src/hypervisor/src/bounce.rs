@@ -0,0 +1,276 @@
+/* diosix DMA bounce buffers for devices without an IOMMU
+ *
+ * A passed-through device that can DMA but doesn't sit behind an IOMMU
+ * can't be confined to its capsule's memory. Rather than refuse such
+ * devices, the capsule's paravirtualized block and network drivers can
+ * ask the hypervisor to bounce their I/O through DMA-safe memory that it
+ * allocates on the capsule's behalf, much like Linux's swiotlb.
+ *
+ * Before starting a device operation, the driver maps its buffer. The
+ * hypervisor allocates a DMA-safe bounce buffer of the same size, copies
+ * the capsule's data into it if the device is going to read it, and
+ * returns the bounce buffer's physical address to program into the
+ * device. Once the operation completes, the driver unmaps the buffer,
+ * and the hypervisor copies any data the device wrote back into the
+ * capsule's buffer and frees the bounce buffer. Caches are flushed and
+ * invalidated around the device's accesses for non-coherent platforms.
+ *
+ * The copies cost performance, so they're only offered to capsules with
+ * at least one device whose DMA can't be confined. Each capsule's use
+ * of bounce buffers is capped, and its buffers are freed when it's
+ * destroyed or restarted. A capsule's bytes are reserved against its
+ * cap before its bounce buffer is allocated, so that calls made at
+ * the same time on different cores can't together exceed the cap.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::passthrough;
+use super::physmem::{self, Region};
+use super::pcore;
+use super::hcargs::{self, Access};
+
+/* which way the device moves the data, shared with the capsules' drivers */
+pub use hypercall::bounce::Direction;
+
+pub type BounceID = usize;
+
+/* largest buffer that can be bounced in one go, in bytes */
+const BOUNCE_SIZE_MAX: PhysMemSize = 64 * 1024;
+
+/* most bytes of bounce buffers a capsule can hold at any one time */
+const BOUNCE_BYTES_MAX: PhysMemSize = 1024 * 1024;
+
+/* a capsule's buffer being bounced */
+struct Bounce
+{
+    owner: CapsuleID,
+    buffer: usize,          /* address of the capsule's buffer in the capsule */
+    size: PhysMemSize,      /* bytes reserved for the buffer against the capsule's cap */
+    direction: Direction,
+    region: Region          /* DMA-safe memory the device uses instead */
+}
+
+/* the buffers being bounced, and the bytes each capsule has reserved for its buffers */
+struct Bounces
+{
+    buffers: HashMap<BounceID, Bounce>,
+    held: HashMap<CapsuleID, PhysMemSize>
+}
+
+impl Bounces
+{
+    fn new() -> Bounces
+    {
+        Bounces { buffers: HashMap::new(), held: HashMap::new() }
+    }
+
+    /* reserve bytes against a capsule's cap before allocating a bounce buffer
+       => cid = capsule to reserve the bytes for
+          size = number of bytes to reserve
+       <= Ok for success, or an error code if the capsule would hold too much */
+    fn reserve(&mut self, cid: CapsuleID, size: PhysMemSize) -> Result<(), Cause>
+    {
+        let held = self.held.entry(cid).or_insert(0);
+        if *held + size > BOUNCE_BYTES_MAX
+        {
+            return Err(Cause::BounceTooMany);
+        }
+        *held = *held + size;
+        Ok(())
+    }
+
+    /* give back bytes reserved by a capsule, once its bounce buffer is freed or couldn't be allocated */
+    fn unreserve(&mut self, cid: CapsuleID, size: PhysMemSize)
+    {
+        if let Some(held) = self.held.get_mut(&cid)
+        {
+            *held = held.saturating_sub(size);
+            if *held == 0
+            {
+                self.held.remove(&cid);
+            }
+        }
+    }
+}
+
+lazy_static!
+{
+    static ref BOUNCES: Mutex<Bounces> = Mutex::new("DMA bounce buffers", Bounces::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/* copy bytes between physical addresses. neither range may overlap the other */
+fn copy(from: PhysMemBase, to: PhysMemBase, size: PhysMemSize)
+{
+    let source = unsafe { slice::from_raw_parts(from as *const u8, size) };
+    let target = unsafe { slice::from_raw_parts_mut(to as *mut u8, size) };
+    target.copy_from_slice(source);
+}
+
+/* bounce a buffer in the currently running capsule through DMA-safe memory before a device operation
+   => buffer, size = address and size in bytes of the buffer in the capsule
+      direction = whether the device will read the buffer, write it, or both
+   <= ID of the bounce buffer and the physical address to program into the device, or an error code */
+pub fn map(buffer: usize, size: PhysMemSize, direction: Direction) -> Result<(BounceID, PhysMemBase), Cause>
+{
+    let _tag = heaptag!();
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* capsules whose devices are confined by an IOMMU can DMA directly into their own memory */
+    if passthrough::get_devices(cid).iter().any(|device| device.iommu().is_none()) == false
+    {
+        return Err(Cause::BounceNotNeeded);
+    }
+
+    if size == 0 || size > BOUNCE_SIZE_MAX
+    {
+        return Err(Cause::BounceBadSize);
+    }
+
+    /* check the buffer before committing any memory to it. the device's data is copied back into it later */
+    let access = match direction
+    {
+        Direction::ToDevice => Access::Read,
+        _ => Access::Write
+    };
+    let source = hcargs::buffer(cid, buffer, size, BOUNCE_SIZE_MAX, access)?;
+
+    /* check and take the capsule's share in one go, then allocate the memory without holding up other cores.
+       the region may be rounded up, so what's reserved is whatever was asked for */
+    BOUNCES.lock().reserve(cid, size)?;
+    let region = match physmem::alloc_dma_region(size)
+    {
+        Ok(region) => region,
+        Err(e) =>
+        {
+            BOUNCES.lock().unreserve(cid, size);
+            return Err(e);
+        }
+    };
+
+    if direction != Direction::FromDevice
+    {
+        copy(source, region.base(), size);
+        region.flush_cache();
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let base = region.base();
+    BOUNCES.lock().buffers.insert(id, Bounce { owner: cid, buffer, size, direction, region });
+    Ok((id, base))
+}
+
+/* finish with a bounce buffer after a device operation, copying what the device wrote back into the capsule
+   => id = bounce buffer to release, which must belong to the currently running capsule
+      size = number of bytes the device wrote, which are copied back
+   <= Ok for success, or an error code */
+pub fn unmap(id: BounceID, size: PhysMemSize) -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let bounce =
+    {
+        let mut bounces = BOUNCES.lock();
+        match bounces.buffers.get(&id)
+        {
+            Some(b) if b.owner == cid => (),
+            _ => return Err(Cause::BounceBadID)
+        }
+        let bounce = bounces.buffers.remove(&id).unwrap();
+        bounces.unreserve(cid, bounce.size);
+        bounce
+    };
+
+    let result = match (bounce.direction, core::cmp::min(size, bounce.region.size()))
+    {
+        (Direction::ToDevice, _) | (_, 0) => Ok(()),
+        (_, size) =>
+        {
+            /* discard any stale cached copy of what the device wrote before reading it.
+               the capsule's buffer is checked again in case its memory has changed */
+            bounce.region.invalidate_cache();
+            hcargs::buffer(cid, bounce.buffer, size, BOUNCE_SIZE_MAX, Access::Write).map(|target| copy(bounce.region.base(), target, size))
+        }
+    };
+
+    physmem::dealloc_region(bounce.region)?;
+    result
+}
+
+/* free a capsule's bounce buffers when it's destroyed or restarted. its devices should be
+   stopped first so they don't DMA into memory that's been handed to someone else */
+pub fn release(cid: CapsuleID)
+{
+    let freed: Vec<Region> =
+    {
+        let mut bounces = BOUNCES.lock();
+        let ids: Vec<BounceID> = bounces.buffers.iter().filter(|(_, b)| b.owner == cid).map(|(id, _)| *id).collect();
+        let freed = ids.iter().filter_map(|id| bounces.buffers.remove(id)).map(|b| b.region).collect();
+        bounces.held.remove(&cid);
+        freed
+    };
+
+    for region in freed
+    {
+        if let Err(_e) = physmem::dealloc_region(region)
+        {
+            hvalert!("Failed to free bounce buffer of capsule {}: {:?}", cid, _e);
+        }
+    }
+}
+
+#[test_case]
+fn test_reserve_enforces_cap()
+{
+    let mut bounces = Bounces::new();
+    assert_eq!(bounces.reserve(1, BOUNCE_BYTES_MAX - BOUNCE_SIZE_MAX).is_ok(), true);
+    assert_eq!(bounces.reserve(1, BOUNCE_SIZE_MAX).is_ok(), true);
+
+    /* the capsule is at its cap, but others aren't held back by it */
+    assert_eq!(bounces.reserve(1, 1).is_err(), true);
+    assert_eq!(bounces.reserve(2, BOUNCE_SIZE_MAX).is_ok(), true);
+}
+
+#[test_case]
+fn test_unreserve_frees_share()
+{
+    let mut bounces = Bounces::new();
+    assert_eq!(bounces.reserve(1, BOUNCE_BYTES_MAX).is_ok(), true);
+    bounces.unreserve(1, BOUNCE_SIZE_MAX);
+    assert_eq!(bounces.reserve(1, BOUNCE_SIZE_MAX).is_ok(), true);
+    assert_eq!(bounces.reserve(1, 1).is_err(), true);
+
+    /* giving back everything forgets the capsule, and giving back too much can't go negative */
+    bounces.unreserve(1, BOUNCE_BYTES_MAX);
+    assert_eq!(bounces.held.contains_key(&1), false);
+    bounces.unreserve(1, BOUNCE_SIZE_MAX);
+    assert_eq!(bounces.reserve(1, BOUNCE_BYTES_MAX).is_ok(), true);
+}
+
+#[test_case]
+fn test_failed_reserve_takes_nothing()
+{
+    let mut bounces = Bounces::new();
+    assert_eq!(bounces.reserve(1, BOUNCE_BYTES_MAX + 1).is_err(), true);
+    assert_eq!(bounces.reserve(1, BOUNCE_BYTES_MAX).is_ok(), true);
+}
@@ -0,0 +1,294 @@
+/* diosix board bring-up mode
+ *
+ * Getting diosix going on a new board is easier when the hypervisor
+ * does as little as possible. When built with the bringup feature, the
+ * boot core parses the host's device tree and brings up just the debug
+ * UART and the timer. It doesn't unpack the manifest or create any
+ * capsules, and the other physical cores are left idle. Instead, the
+ * boot core runs a diagnostic prompt on the debug port that accepts:
+ *
+ *   peek <addr> [<words>]  print 32-bit words of physical memory, up to 256 at a time
+ *   poke <addr> <value>    write a 32-bit word to physical memory
+ *   dt                     list the nodes and properties of the host's device tree
+ *   csr                    list this core's machine-level control and status registers
+ *   timer                  show the timer's frequency and current value
+ *   help                   list these commands
+ *
+ * Addresses and values are in hex, with or without a 0x prefix, and
+ * addresses must be word aligned. Peeks and pokes are carried out as-is,
+ * so touching a hole in the memory map or a register that changes when
+ * it's read does whatever it would do on the bare board.
+ *
+ * The device tree is listed by walking the blob directly rather than
+ * with the devicetree crate, so trees the hypervisor can't parse in full
+ * can still be inspected.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::hardware;
+use super::machine;
+use super::error::{self, Cause};
+
+const PROMPT: &str = "bringup> ";
+
+/* longest command line accepted, in characters */
+const COMMAND_MAX_LEN: usize = 80;
+
+/* most words printed by one peek, and how many go on each line */
+const PEEK_MAX_WORDS: usize = 256;
+const PEEK_WORDS_PER_LINE: usize = 4;
+
+/* flattened device tree header and structure block tokens */
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/* longest property value listed in full, in bytes */
+const DT_PROPERTY_MAX_SHOWN: usize = 64;
+
+/* run the diagnostic prompt on the debug port. call from the boot core once the hardware is up
+   => dtb = the host's device tree blob
+   <= never returns */
+pub fn run(dtb: &[u8])
+{
+    hvprintln!("Diosix {} board bring-up mode: manifest and capsules skipped", env!("CARGO_PKG_VERSION"));
+    if let Err(e) = execute("timer", dtb)
+    {
+        hvprintln!("Timer unavailable: {}", error::report(&e));
+    }
+    hvprintln!("Type help for a list of commands");
+    hvprint!("{}", PROMPT);
+
+    let mut line = String::new();
+    loop
+    {
+        debughousekeeper!();
+
+        let c = match hardware::read_debug_char()
+        {
+            Some(c) => c,
+            None => continue
+        };
+
+        match c
+        {
+            '\r' | '\n' =>
+            {
+                hvprintln!("");
+                if let Err(e) = execute(&line, dtb)
+                {
+                    hvprintln!("Failed: {}", error::report(&e));
+                }
+                line.clear();
+                hvprint!("{}", PROMPT);
+            },
+            '\x7f' | '\x08' =>
+            {
+                if line.pop().is_some()
+                {
+                    hvprint!("\x08 \x08");
+                }
+            },
+            c =>
+            {
+                if c.is_control() == false && line.chars().count() < COMMAND_MAX_LEN
+                {
+                    line.push(c);
+                    hvprint!("{}", c);
+                }
+            }
+        }
+    }
+}
+
+/* carry out a command line
+   => line = the command and its parameters, separated by whitespace
+      dtb = the host's device tree blob
+   <= Ok for success, or an error code */
+fn execute(line: &str, dtb: &[u8]) -> Result<(), Cause>
+{
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match (words.get(0), words.get(1), words.get(2))
+    {
+        (None, _, _) => (),
+
+        (Some(&"peek"), Some(addr), count) =>
+        {
+            let addr = parse_addr(addr)?;
+            let count = match count
+            {
+                Some(count) => count.parse::<usize>().map_err(|_| Cause::AdminBadCommand)?,
+                None => 1
+            };
+            if count == 0 || count > PEEK_MAX_WORDS
+            {
+                return Err(Cause::AdminBadCommand);
+            }
+
+            for row in (0..count).step_by(PEEK_WORDS_PER_LINE)
+            {
+                let base = addr.checked_add(row * 4).ok_or(Cause::AdminBadCommand)?;
+                let mut text = format!("{:016x}:", base);
+                for word in 0..core::cmp::min(PEEK_WORDS_PER_LINE, count - row)
+                {
+                    let value = unsafe { core::ptr::read_volatile((base + word * 4) as *const u32) };
+                    text.push_str(&format!(" {:08x}", value));
+                }
+                hvprintln!("{}", text);
+                debughousekeeper!();
+            }
+        },
+
+        (Some(&"poke"), Some(addr), Some(value)) =>
+        {
+            let addr = parse_addr(addr)?;
+            let value = u32::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| Cause::AdminBadCommand)?;
+            unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+            hvprintln!("{:016x}: {:08x}", addr, value);
+        },
+
+        (Some(&"dt"), None, _) => dump_dt(dtb)?,
+
+        (Some(&"csr"), None, _) =>
+        {
+            for (name, value) in machine::control_registers().iter()
+            {
+                hvprintln!("{:>10} {:016x}", name, value);
+            }
+        },
+
+        (Some(&"timer"), None, _) =>
+        {
+            match (hardware::scheduler_get_timer_frequency(), hardware::scheduler_get_timer_now())
+            {
+                (Some(frequency), Some(now)) =>
+                    hvprintln!("Timer: {} Hz, now {} ticks", frequency, now.to_exact(frequency)),
+                (_, _) => return Err(Cause::SchedNoTimer)
+            }
+        },
+
+        (Some(&"help"), None, _) => hvprintln!("Commands: peek <addr> [<words>], poke <addr> <value>, dt, csr, timer"),
+
+        (Some(_), _, _) => return Err(Cause::AdminBadCommand)
+    }
+
+    Ok(())
+}
+
+/* <= word-aligned address written in hex, or an error code if it's malformed or misaligned */
+fn parse_addr(word: &str) -> Result<usize, Cause>
+{
+    match usize::from_str_radix(word.trim_start_matches("0x"), 16)
+    {
+        Ok(addr) if addr % 4 == 0 => Ok(addr),
+        _ => Err(Cause::AdminBadCommand)
+    }
+}
+
+/* <= big-endian 32-bit word at offset in the blob, or None if it's past the end */
+fn read_be32(blob: &[u8], offset: usize) -> Option<u32>
+{
+    let bytes = blob.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/* <= zero-terminated string at offset in the blob, and its length without the terminator */
+fn read_string(blob: &[u8], offset: usize) -> Option<(&str, usize)>
+{
+    let bytes = blob.get(offset..)?;
+    let length = bytes.iter().position(|&b| b == 0)?;
+    Some((core::str::from_utf8(&bytes[..length]).unwrap_or("?"), length))
+}
+
+/* list every node and property in a flattened device tree blob, stopping at the first malformed part
+   => dtb = the host's device tree blob
+   <= Ok for success, or an error code if the blob is malformed */
+fn dump_dt(dtb: &[u8]) -> Result<(), Cause>
+{
+    if dtb.len() < FDT_HEADER_SIZE || read_be32(dtb, 0) != Some(FDT_MAGIC)
+    {
+        return Err(Cause::DeviceTreeBad);
+    }
+
+    let structs = read_be32(dtb, 8).ok_or(Cause::DeviceTreeBad)? as usize;
+    let strings = read_be32(dtb, 12).ok_or(Cause::DeviceTreeBad)? as usize;
+    hvprintln!("Device tree: {} bytes, version {}", dtb.len(), read_be32(dtb, 20).unwrap_or(0));
+
+    let mut offset = structs;
+    let mut depth = 0;
+    loop
+    {
+        let token = read_be32(dtb, offset).ok_or(Cause::DeviceTreeBad)?;
+        offset = offset + 4;
+
+        match token
+        {
+            FDT_BEGIN_NODE =>
+            {
+                let (name, length) = read_string(dtb, offset).ok_or(Cause::DeviceTreeBad)?;
+                hvprintln!("{:indent$}{} {{", "", if name.len() == 0 { "/" } else { name }, indent = depth * 2);
+                offset = offset + ((length + 1 + 3) & !3);
+                depth = depth + 1;
+            },
+            FDT_END_NODE =>
+            {
+                depth = depth.checked_sub(1).ok_or(Cause::DeviceTreeBad)?;
+                hvprintln!("{:indent$}}}", "", indent = depth * 2);
+            },
+            FDT_PROP =>
+            {
+                let length = read_be32(dtb, offset).ok_or(Cause::DeviceTreeBad)? as usize;
+                let name_offset = read_be32(dtb, offset + 4).ok_or(Cause::DeviceTreeBad)? as usize;
+                let (name, _) = read_string(dtb, strings.saturating_add(name_offset)).ok_or(Cause::DeviceTreeBad)?;
+                let value = dtb.get(offset + 8..(offset + 8).saturating_add(length)).ok_or(Cause::DeviceTreeBad)?;
+                hvprintln!("{:indent$}{} = {}", "", name, describe_property(value), indent = depth * 2);
+                offset = offset + 8 + ((length + 3) & !3);
+            },
+            FDT_NOP => (),
+            FDT_END => return Ok(()),
+            _ => return Err(Cause::DeviceTreeBad)
+        }
+
+        /* the tree can be bigger than the debug queue */
+        debughousekeeper!();
+    }
+}
+
+/* <= a device tree property's value as text if it's printable strings, or as hex bytes if not */
+fn describe_property(value: &[u8]) -> String
+{
+    if value.len() == 0
+    {
+        return String::from("<empty>");
+    }
+
+    let printable = value.last() == Some(&0) && value[0] != 0 &&
+        value.iter().all(|&b| b == 0 || (b >= 0x20 && b < 0x7f));
+    if printable == true
+    {
+        let strings: Vec<String> = value[..value.len() - 1].split(|&b| b == 0)
+            .map(|s| format!("\"{}\"", String::from_utf8_lossy(s))).collect();
+        return strings.join(", ");
+    }
+
+    let mut text = String::from("[");
+    for byte in value.iter().take(DT_PROPERTY_MAX_SHOWN)
+    {
+        text.push_str(&format!(" {:02x}", byte));
+    }
+    if value.len() > DT_PROPERTY_MAX_SHOWN
+    {
+        text.push_str(&format!(" ...{} more", value.len() - DT_PROPERTY_MAX_SHOWN));
+    }
+    text.push_str(" ]");
+    text
+}
@@ -0,0 +1,195 @@
+/* diosix virtual power and reboot buttons
+ *
+ * Let a management capsule press a guest's virtual power button, or
+ * ask it to reboot, much as ACPI button events do on a PC, so that a
+ * well-behaved guest can shut down or restart cleanly rather than be
+ * killed out from under its filesystems.
+ *
+ * Pressing a button records the press and sends the guest VIRQ_BUTTON.
+ * The guest collects its presses by hypercall, and then exits, or
+ * restarts itself, in its own time. The management capsule can give the
+ * guest a grace period in milliseconds: if the guest is still running
+ * when that runs out, the hypervisor finishes the job, killing the guest
+ * if its power button was pressed, or else restarting it. Without a
+ * grace period, the guest is left to it.
+ *
+ * When the host is rebooted or powered off, the other capsules have the
+ * matching button pressed as well as being sent VIRQ_HOST_RESET, so that
+ * guests that only understand button presses shut down cleanly too. The
+ * host's own grace period applies to them.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID, CapsuleProperty, CapsuleState};
+use super::passthrough::{self, DeviceIRQ};
+use super::scheduler;
+use super::pcore;
+use platform::timer::TimerValue;
+
+/* the buttons, shared with the capsules */
+pub use hypercall::button::Button;
+
+/* virtual interrupt raised when one of a guest's buttons is pressed */
+pub const VIRQ_BUTTON: DeviceIRQ = hypercall::irq::VIRQ_BUTTON;
+
+/* longest grace period a guest can be given, in milliseconds */
+const GRACE_PERIOD_MAX: u64 = 60 * 1000;
+
+/* presses waiting for a guest, and what to do if it ignores them */
+struct Pressed
+{
+    presses: usize,                 /* mask of buttons pressed and not yet collected */
+    enforce: Option<(Button, u64)>  /* button to act on, and timer value in exact ticks when its grace period ends */
+}
+
+lazy_static!
+{
+    static ref PRESSED: Mutex<HashMap<CapsuleID, Pressed>> = Mutex::new("virtual button presses", HashMap::new());
+}
+
+/* press one of a running guest's buttons
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule whose button to press
+      button = button to press
+      grace = milliseconds to give the guest to act on it before the hypervisor does, or 0 to leave it to the guest
+   <= Ok for success, or an error code */
+pub fn press(cid: CapsuleID, button: Button, grace: u64) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match capsule::get_state(cid)
+    {
+        Some(CapsuleState::Valid) => (),
+        Some(_) => return Err(Cause::ButtonNotRunning),
+        None => return Err(Cause::CapsuleBadID)
+    }
+
+    let deadline = match (grace, scheduler::timer_now())
+    {
+        (0, _) | (_, None) => None, /* without a timer, the guest can't be timed out */
+        (grace, Some((now, frequency))) =>
+            Some(now + TimerValue::Milliseconds(core::cmp::min(grace, GRACE_PERIOD_MAX)).to_exact(frequency))
+    };
+
+    record(cid, button, deadline);
+    hvdebug!("Capsule {} {:?} button pressed with {} ms grace", cid, button, grace);
+    Ok(())
+}
+
+/* press a button on every capsule bar the one that asked for it, such as when the host is reset.
+   the host reset's grace period covers them, so no deadline is set here
+   => button = button to press
+      requester = capsule that asked for the reset, if any */
+pub fn press_all(button: Button, requester: Option<CapsuleID>)
+{
+    for summary in capsule::snapshot()
+    {
+        if Some(summary.id()) != requester
+        {
+            record(summary.id(), button, None);
+        }
+    }
+}
+
+/* note a press and interrupt the guest. the power button's deadline wins over a reboot's */
+fn record(cid: CapsuleID, button: Button, deadline: Option<u64>)
+{
+    {
+        let mut pressed = PRESSED.lock();
+        let entry = pressed.entry(cid).or_insert(Pressed { presses: 0, enforce: None });
+        entry.presses = entry.presses | button.mask();
+
+        if let Some(deadline) = deadline
+        {
+            entry.enforce = match entry.enforce
+            {
+                Some((Button::Power, earlier)) if button == Button::Reboot => Some((Button::Power, earlier)),
+                Some((existing, earlier)) if existing == button => Some((button, core::cmp::min(earlier, deadline))),
+                _ => Some((button, deadline))
+            };
+        }
+    }
+
+    passthrough::raise_virtual_irq(cid, VIRQ_BUTTON);
+}
+
+/* collect the buttons pressed for the currently running capsule since it last asked
+   <= mask of buttons pressed, or an error code */
+pub fn collect() -> Result<usize, Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* collecting a press doesn't cancel its deadline: the guest must still act on it */
+    match PRESSED.lock().get_mut(&cid)
+    {
+        Some(pressed) =>
+        {
+            let presses = pressed.presses;
+            pressed.presses = 0;
+            Ok(presses)
+        },
+        None => Ok(0)
+    }
+}
+
+/* kill or restart guests that haven't acted on their buttons in time. call this regularly from the boot core */
+pub fn housekeeper()
+{
+    let now = match scheduler::timer_now()
+    {
+        Some((now, _)) => now,
+        None => return
+    };
+
+    let mut expired = Vec::new();
+    for (cid, pressed) in PRESSED.lock().iter_mut()
+    {
+        if let Some((button, deadline)) = pressed.enforce
+        {
+            if now >= deadline
+            {
+                pressed.enforce = None;
+                expired.push((*cid, button));
+            }
+        }
+    }
+
+    /* don't hold the presses lock while acting on the capsules */
+    for (cid, button) in expired
+    {
+        let result = match button
+        {
+            Button::Power =>
+            {
+                hvalert!("Capsule {} didn't shut down in time after its power button was pressed: killing it", cid);
+                capsule::kill(cid)
+            },
+            Button::Reboot =>
+            {
+                hvalert!("Capsule {} didn't restart in time after being asked to: restarting it", cid);
+                capsule::request_restart(cid)
+            }
+        };
+
+        if let Err(_e) = result
+        {
+            hvalert!("Can't act on capsule {}'s {:?} button: {}", cid, button, error::report(&_e));
+        }
+    }
+}
+
+/* discard a capsule's button presses when it's restarted or destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    PRESSED.lock().remove(&cid);
+}
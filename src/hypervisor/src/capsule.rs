@@ -6,25 +6,71 @@
  */
 
 use core::sync::atomic::{AtomicUsize, Ordering};
-use super::lock::Mutex;
+use super::lock::{Mutex, LockStats};
 use hashbrown::hash_map::HashMap;
 use hashbrown::hash_map::Entry::{Occupied, Vacant};
 use hashbrown::hash_set::HashSet;
 use alloc::vec::Vec;
+use alloc::collections::vec_deque::VecDeque;
 use alloc::string::{String, ToString};
 use platform::cpu::{Entry, CPUcount};
-use platform::physmem::PhysMemBase;
-use super::error::Cause;
-use super::physmem;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
+use super::error::{self, Cause};
+use super::physmem::{self, ZeroPolicy};
 use super::virtmem::Mapping;
-use super::vcore::{self, Priority, VirtualCoreID};
+use super::vcore::{self, Priority, Deadline, VirtualCore, VirtualCoreID};
+use super::scheduler;
 use super::service::{self, ServiceType, SelectService};
-use super::pcore;
+use super::pcore::{self, CoreClass};
 use super::hardware;
 use super::debug;
+use super::passthrough;
+use super::abi;
+use super::transfer;
+use super::virtdt;
+use super::trace;
+use super::qos;
+use super::manifest;
+use super::guestlog;
+use super::seriallink;
+use super::gpio;
+use super::throttle;
+use super::stream;
+use super::pressure;
+use super::grant;
+use super::telemetry;
+use super::measure;
+use super::vipi;
+use super::mmio;
+use super::dirty;
+use super::template;
+use super::quiesce;
+use super::button;
+use super::arbiter::{self, Source};
+use super::message;
+use super::crashdump;
+use super::inspect;
+use super::devmodel;
+use super::metrics;
+use super::console;
+use super::vdevice::DeviceSpec;
+use super::wss;
+use super::identity;
+use super::guestpanic;
+use super::abboot;
+use super::boottime;
+use super::clock;
+use super::bounce;
+use super::accounting;
+use super::hcargs::{self, Access};
+use hvalgo::hcargs::Window;
 
 pub type CapsuleID = usize;
 
+/* protection windows 0 and 1 are taken by the capsule's main RAM and the hypervisor,
+   so a capsule's extra memory and devices are granted access via windows numbered from here */
+const FIRST_EXTRA_WINDOW: usize = 2;
+
 /* arbitrarily allow up to CAPSULES_MAX capsules in a system at any one time */
 const CAPSULES_MAX: usize = 1000000;
 
@@ -43,11 +89,18 @@ lazy_static!
     /* set of capsules to restart */
     static ref TO_RESTART: Mutex<HashSet<CapsuleID>> = Mutex::new("capsule restart list", HashSet::new());
 
+    /* virtual cores of paused capsules, held out of the scheduling queues with their state intact */
+    static ref PARKED: Mutex<HashMap<CapsuleID, Vec<VirtualCore>>> = Mutex::new("parked vcore table", HashMap::new());
+
+    /* capsules that were paused after crashing, awaiting inspection by a management capsule */
+    static ref CRASHED: Mutex<VecDeque<CapsuleID>> = Mutex::new("crashed capsule list", VecDeque::new());
+
     /* maintain collective input and output system console buffers for capsules.
        the console system service capsule (ServiceConsole) will read from
-       STDOUT to display capsules' text, and will write to STDIN to inject characters into capsules */
-    static ref STDIN: Mutex<HashMap<CapsuleID, Vec<char>>> = Mutex::new("capsule STDIN table", HashMap::new());
-    static ref STDOUT: Mutex<HashMap<CapsuleID, Vec<char>>> = Mutex::new("capsule STDOUT table", HashMap::new());
+       STDOUT to display capsules' text, and will write to STDIN to inject characters into capsules.
+       the buffers hold raw bytes in each capsule's console encoding */
+    static ref STDIN: Mutex<HashMap<CapsuleID, Vec<u8>>> = Mutex::new("capsule STDIN table", HashMap::new());
+    static ref STDOUT: Mutex<HashMap<CapsuleID, console::OutputBuffer>> = Mutex::new("capsule STDOUT table", HashMap::new());
 }
 
 /* perform housekeeping duties on idle physical CPU cores */
@@ -59,13 +112,68 @@ macro_rules! capsulehousekeeper
 /* empty the waiting list of capsules to restart and recreate their vcores */
 pub fn restart_awaiting()
 {
+    /* capsules that need their protection windows reapplied once the capsule table is unlocked */
+    let mut to_reenforce = Vec::new();
+
     for cid in TO_RESTART.lock().drain()
     {
         if let Some(c) = CAPSULES.lock().get_mut(&cid)
         {
             /* capsule is ready to roll again, call this before injecting
             virtual cores into the scheduling queues */
-            c.set_state_valid();
+            if let Err(_e) = c.transition(Event::Restarted)
+            {
+                hvalert!("BUG: Capsule {} waiting to restart but can't be ({})", cid, error::report(&_e));
+                continue;
+            }
+
+            /* the restarted guest must negotiate its hypercall ABI afresh,
+            and any bulk transfers it offered are abandoned */
+            abi::forget(cid);
+            transfer::cancel(cid);
+            bounce::release(cid);
+            passthrough::reset_irqs(cid);
+            to_reenforce.extend(grant::forget(cid));
+            if mmio::forget(cid) == true
+            {
+                to_reenforce.push(cid);
+            }
+            dirty::invalidate(cid);
+            quiesce::forget(cid);
+            button::forget(cid);
+            guestpanic::rearm(cid);
+
+            /* fall back to the previous image if a new one on trial keeps failing */
+            if let Some(previous) = abboot::restarting(cid)
+            {
+                c.set_boot_image(previous);
+            }
+
+            /* swap in a newly selected image, if any, before recreating the vcores */
+            if let Some(name) = c.take_boot_image()
+            {
+                match c.get_memory_mappings().first().and_then(|m| m.get_physical())
+                {
+                    Some(ram) => match manifest::reload_image(cid, ram, &name)
+                    {
+                        Ok(entry) =>
+                        {
+                            /* wipe any RAM allocated apart from the main RAM, as the reload did to the rest */
+                            for mapping in c.get_memory_mappings().iter().filter(|m| m.is_scattered())
+                            {
+                                if let Some(mut piece) = mapping.get_physical()
+                                {
+                                    piece.zero();
+                                }
+                            }
+                            c.set_init_entry(entry);
+                            c.set_name(name.clone());
+                        },
+                        Err(_e) => hvalert!("Failed to load boot image {} into capsule {}: {}", name, cid, error::report(&_e))
+                    },
+                    None => hvalert!("Can't load boot image {} into capsule {}: no RAM", name, cid)
+                }
+            }
 
             /* TODO: if the capsule is corrupt, it'll crash again. support
             a hard reset if the capsule can't start */
@@ -74,21 +182,24 @@ pub fn restart_awaiting()
             {
                 if let Err(_e) = add_vcore(cid, *vid, params.entry, params.dtb, params.prio)
                 {
-                    hvalert!("Failed to restart capsule {} vcore {}: {:?}", cid, vid, _e);
+                    hvalert!("Failed to restart capsule {} vcore {}: {}", cid, vid, error::report(&_e));
                 }
             }
+            boottime::queued(cid);
         }
     }
-}
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub enum CapsuleState
-{
-    Valid,      /* ok to run */
-    Dying,      /* remove vcores and kill when there are none left */
-    Restarting  /* remove vcores and recreate vcores with initial params */
+    for cid in to_reenforce
+    {
+        reenforce(cid);
+    }
 }
 
+/* capsules' states are changed by applying lifecycle events */
+pub use super::lifecycle::CapsuleState;
+use super::lifecycle::{self, Lifecycle, Event, Transition};
+use super::machine;
+
 /* record the initialization parameters for a virtual core
    so it can be recreated and restarted */
 pub struct VcoreInit
@@ -98,14 +209,235 @@ pub struct VcoreInit
     prio: Priority
 }
 
-#[derive(PartialEq, Eq, Hash, Debug)]
+/* version of the property namespace understood by this hypervisor. properties can be written with
+   a version prefix, eg: v1:console_write, so that a manifest written for a later set of properties
+   is rejected rather than misread. properties without a prefix are assumed to be in this version */
+pub const PROPERTY_NAMESPACE_VERSION: usize = 1;
+
+/* a property's parser, given the value written after its name as name=value, or None if
+   there isn't one. returns the property, or None if the value can't be understood */
+type PropertyParser = fn(Option<&str>) -> Option<CapsuleProperty>;
+
+/* every property in this version of the namespace, by name. parsing a property and telling
+   a known property with a bad value from an unknown one both use this table */
+const PROPERTIES: [(&str, PropertyParser); 39] =
+[
+    /* restart the capsule if it crashes (as opposed to exits cleanly) */
+    ("auto_crash_restart", |value| flag(value, CapsuleProperty::AutoCrashRestart)),
+
+    /* freeze the capsule if it crashes so it can be inspected */
+    ("pause_on_crash", |value| flag(value, CapsuleProperty::PauseOnCrash)),
+
+    /* allow the capsule to manage others, reboot or power off the host, change the
+       hypervisor's settings, and reach peripherals' registers without passthrough */
+    ("manage_capsules", |value| flag(value, CapsuleProperty::ManageCapsules)),
+    ("host_reset", |value| flag(value, CapsuleProperty::HostReset)),
+    ("host_settings", |value| flag(value, CapsuleProperty::HostSettings)),
+    ("mmio_map", |value| flag(value, CapsuleProperty::MapMMIO)),
+
+    /* console related properties */
+    ("service_console", |value| flag(value, CapsuleProperty::ServiceConsole)),
+    ("console_write", |value| flag(value, CapsuleProperty::ConsoleWrite)),
+    ("console_read", |value| flag(value, CapsuleProperty::ConsoleRead)),
+    ("hv_log_read", |value| flag(value, CapsuleProperty::HvLogRead)),
+
+    /* allow the capsule to run self-tests */
+    ("self_test", |value| flag(value, CapsuleProperty::SelfTest)),
+
+    /* co-schedule the capsule's vcores */
+    ("gang_schedule", |value| flag(value, CapsuleProperty::GangSchedule)),
+
+    /* keep the capsule from writing to its code or running its data */
+    ("wx_protect", |value| flag(value, CapsuleProperty::WXProtect)),
+
+    /* hypercall tracing properties */
+    ("trace_hypercalls", |value| flag(value, CapsuleProperty::TraceHypercalls)),
+    ("trace_read", |value| flag(value, CapsuleProperty::TraceRead)),
+
+    /* hand a physical serial port to the capsule, join it to a virtual serial link
+       shared with one other capsule, or hand it a host GPIO line */
+    ("uart_passthrough", |value| number(value).map(CapsuleProperty::SerialPort)),
+    ("serial_link", |value| number(value).map(CapsuleProperty::SerialLink)),
+    ("gpio", |value| number(value).map(CapsuleProperty::GpioLine)),
+
+    /* rate limit the capsule's timers */
+    ("timer_min_interval", |value| number(value).map(CapsuleProperty::TimerMinInterval)),
+
+    /* throttle the capsule if it keeps faulting or needing instructions emulated */
+    ("trap_limit", |value| number(value).map(CapsuleProperty::TrapLimit)),
+
+    /* give the capsule a wall-clock time to start from if the host can't tell it the time */
+    ("rtc_epoch", |value| number(value).map(CapsuleProperty::RTCEpoch)),
+
+    /* profile the capsule's memory accesses to estimate its working set */
+    ("wss_sample", |value| number(value).map(CapsuleProperty::WSSSample)),
+
+    /* put the capsule's device tree at the top of its RAM, at random in the top half of its RAM,
+       or at the given number of kilobytes from the start of its RAM */
+    ("dtb_placement", |value| match value
+    {
+        Some(v) if v.eq_ignore_ascii_case("top") => Some(CapsuleProperty::DTBPlacement(virtdt::Placement::Top)),
+        Some(v) if v.eq_ignore_ascii_case("random") => Some(CapsuleProperty::DTBPlacement(virtdt::Placement::Random)),
+        v => number::<usize>(v).and_then(|kb| kb.checked_mul(1024))
+            .map(|offset| CapsuleProperty::DTBPlacement(virtdt::Placement::Offset(offset)))
+    }),
+
+    /* run the capsule on the system's performance or efficiency cores, where it has both */
+    ("core_class", |value| match value
+    {
+        Some(v) if v.eq_ignore_ascii_case("performance") => Some(CapsuleProperty::CoreClass(CoreClass::Performance)),
+        Some(v) if v.eq_ignore_ascii_case("efficiency") => Some(CapsuleProperty::CoreClass(CoreClass::Efficiency)),
+        _ => None
+    }),
+
+    /* define how the capsule's console output is encoded: utf8 (the default) or raw */
+    ("console_encoding", |value| match value
+    {
+        Some(v) if v.eq_ignore_ascii_case("utf8") || v.eq_ignore_ascii_case("utf-8") => Some(CapsuleProperty::ConsoleEncoding(console::Encoding::UTF8)),
+        Some(v) if v.eq_ignore_ascii_case("raw") => Some(CapsuleProperty::ConsoleEncoding(console::Encoding::Raw)),
+        _ => None
+    }),
+
+    /* cap the capsule's buffered console output, in bytes */
+    ("console_buffer", |value| number(value).map(CapsuleProperty::ConsoleBuffer)),
+
+    /* drop the oldest (the default) or newest console output when the buffer's full, or block the capsule */
+    ("console_overflow", |value| match value
+    {
+        Some(v) if v.eq_ignore_ascii_case("drop_oldest") => Some(CapsuleProperty::ConsoleOverflow(console::Overflow::DropOldest)),
+        Some(v) if v.eq_ignore_ascii_case("drop_newest") => Some(CapsuleProperty::ConsoleOverflow(console::Overflow::DropNewest)),
+        Some(v) if v.eq_ignore_ascii_case("block") => Some(CapsuleProperty::ConsoleOverflow(console::Overflow::Block)),
+        _ => None
+    }),
+
+    /* emulate a device for the capsule using a device model plugin from the DMFS image */
+    ("device_model", |value| match value
+    {
+        Some(v) if v.len() > 0 => Some(CapsuleProperty::DeviceModel(String::from(v))),
+        _ => None
+    }),
+
+    /* give the capsule a virtual device declared as kind,option=value,... */
+    ("device", |value| value.and_then(DeviceSpec::parse).map(CapsuleProperty::Device)),
+
+    /* guarantee the capsule's vcores budget milliseconds of CPU time every period milliseconds,
+       written as deadline=period:budget */
+    ("deadline", |value|
+    {
+        let mut times = value?.splitn(2, ':');
+        match (times.next(), times.next())
+        {
+            (Some(period), Some(budget)) => match (period.trim().parse::<u64>(), budget.trim().parse::<u64>())
+            {
+                (Ok(period), Ok(budget)) => Some(CapsuleProperty::Deadline(Deadline { period, budget })),
+                (_, _) => None
+            },
+            (_, _) => None
+        }
+    }),
+
+    /* define when to zero the capsule's memory: always, on_free, or never.
+       never is only for trusted capsules that can safely reuse stale data */
+    ("zero_memory", |value| match value
+    {
+        Some(v) if v.eq_ignore_ascii_case("always") => Some(CapsuleProperty::ZeroMemory(ZeroPolicy::Always)),
+        Some(v) if v.eq_ignore_ascii_case("on_free") => Some(CapsuleProperty::ZeroMemory(ZeroPolicy::OnFree)),
+        Some(v) if v.eq_ignore_ascii_case("never") => Some(CapsuleProperty::ZeroMemory(ZeroPolicy::Never)),
+        _ => None
+    }),
+
+    /* partition the last-level cache and memory bandwidth, as percentages */
+    ("cache_share", |value| number(value).map(CapsuleProperty::CacheShare)),
+    ("bandwidth_share", |value| number(value).map(CapsuleProperty::BandwidthShare)),
+
+    /* service access control lists */
+    ("service_restrict", |value| service_type(value).map(CapsuleProperty::ServiceRestrict)),
+    ("service_access", |value| service_type(value).map(CapsuleProperty::ServiceAccess)),
+
+    /* start the capsule as a replacement for the given service's owner if that dies */
+    ("standby_for", |value| service_type(value).map(CapsuleProperty::StandbyFor)),
+
+    /* named services the capsule may register, restrict, and look up, eg: acme.storage or acme.* */
+    ("service_name", |value| name_pattern(value).map(CapsuleProperty::ServiceName)),
+    ("service_name_restrict", |value| name_pattern(value).map(CapsuleProperty::ServiceNameRestrict)),
+    ("service_name_access", |value| name_pattern(value).map(CapsuleProperty::ServiceNameAccess))
+];
+
+/* <= the given flag property if it was written without a value, or None if it has one */
+fn flag(value: Option<&str>, property: CapsuleProperty) -> Option<CapsuleProperty>
+{
+    match value
+    {
+        None => Some(property),
+        Some(_) => None
+    }
+}
+
+/* <= a property's value as a number, or None if it hasn't got one or it isn't a number */
+fn number<T: core::str::FromStr>(value: Option<&str>) -> Option<T>
+{
+    value?.parse::<T>().ok()
+}
+
+/* <= the service type named by a property's value, or None if it doesn't name one */
+fn service_type(value: Option<&str>) -> Option<ServiceType>
+{
+    service::name_to_service_type(value?).ok()
+}
+
+/* <= a property's value as a service name pattern, or None if it isn't a valid pattern */
+fn name_pattern(value: Option<&str>) -> Option<String>
+{
+    let value = value?;
+    match service::check_name_pattern(value)
+    {
+        Ok(_) => Some(String::from(value)),
+        Err(_) => None
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum CapsuleProperty
 {
     AutoCrashRestart,   /* restart this capsule when it crashes */
+    PauseOnCrash,       /* freeze this capsule for inspection when it crashes */
+    ManageCapsules,     /* allow capsule to inspect, resume and kill other capsules */
+    HostReset,          /* allow capsule to reboot or power off the whole host */
+    HostSettings,       /* allow capsule to change the hypervisor's live settings */
+    MapMMIO,            /* allow capsule to map physical MMIO ranges directly */
     ServiceConsole,     /* allow capsule to handle abstracted system console */
     ConsoleWrite,       /* allow capsule to write out to the console */
     ConsoleRead,        /* allow capsule to read the console */
-    HvLogRead           /* allow capsule to read the hypervisor's debug log */
+    HvLogRead,          /* allow capsule to read the hypervisor's debug log */
+    SerialPort(usize),  /* pass the given physical serial port through to the capsule */
+    SerialLink(usize),  /* join the capsule to the given virtual serial link */
+    GpioLine(usize),    /* give the capsule the given host GPIO line */
+    DeviceModel(String), /* give the capsule an emulated device using the named device model plugin */
+    Device(DeviceSpec), /* give the capsule the declared virtual device */
+    ServiceRestrict(ServiceType), /* only let capsules granted access use this capsule's service */
+    ServiceAccess(ServiceType),   /* allow capsule to use the given restricted service */
+    StandbyFor(ServiceType),      /* hold the capsule back until the owner of this service dies, then take it over */
+    ServiceName(String),          /* allow capsule to register services with names matching this pattern */
+    ServiceNameRestrict(String),  /* only let capsules granted access look up this capsule's services matching this pattern */
+    ServiceNameAccess(String),    /* allow capsule to look up restricted named services matching this pattern */
+    Deadline(Deadline), /* run the capsule's vcores in the deadline class with the given period and budget */
+    ZeroMemory(ZeroPolicy), /* control when the capsule's memory is zeroed */
+    TraceHypercalls,    /* record the capsule's hypercalls in the trace ring */
+    TraceRead,          /* allow capsule to read the hypercall trace ring */
+    CacheShare(usize),  /* reserve this percentage of the last-level cache for the capsule */
+    BandwidthShare(usize), /* reserve this percentage of memory bandwidth for the capsule */
+    SelfTest,           /* allow capsule to run the hypervisor's self-tests */
+    GangSchedule,       /* try to run the capsule's vcores at the same time on separate physical cores */
+    WXProtect,          /* make the capsule's code read-only and the rest of its RAM non-executable */
+    TimerMinInterval(u64), /* don't fire the capsule's timers sooner than this many microseconds after they're armed */
+    TrapLimit(u64),     /* throttle the capsule if it traps into the hypervisor more than this many times a second */
+    RTCEpoch(u64),      /* on hosts without an RTC, start the capsule's wall-clock time at this many seconds since 1970 */
+    ConsoleEncoding(console::Encoding), /* how the capsule's console bytes should be interpreted */
+    ConsoleBuffer(usize), /* hold at most this many bytes of the capsule's console output for the console service */
+    ConsoleOverflow(console::Overflow), /* what to do when the capsule writes to a full console output buffer */
+    WSSSample(usize),   /* estimate the capsule's working set by sampling this many pages per period */
+    CoreClass(CoreClass), /* prefer to run the capsule's vcores on this class of physical core */
+    DTBPlacement(virtdt::Placement) /* where to put the capsule's device tree in its RAM */
 }
 
 impl CapsuleProperty
@@ -120,45 +452,126 @@ impl CapsuleProperty
         }
     }
 
+    /* return true if this property can be granted to a guest OS. guests must not
+       be given any special rights though they can be given hardware to drive */
+    pub fn guest_allowed(&self) -> bool
+    {
+        match self
+        {
+            CapsuleProperty::SerialPort(_) => true,
+            CapsuleProperty::SerialLink(_) => true,
+            CapsuleProperty::GpioLine(_) => true,
+            CapsuleProperty::DeviceModel(_) => true,
+            CapsuleProperty::Device(_) => true,
+            CapsuleProperty::ServiceAccess(_) => true,
+            CapsuleProperty::ServiceNameAccess(_) => true,
+            CapsuleProperty::Deadline(_) => true,
+
+            /* a guest must not be handed memory that may contain another capsule's data */
+            CapsuleProperty::ZeroMemory(ZeroPolicy::Never) => false,
+            CapsuleProperty::ZeroMemory(_) => true,
+            CapsuleProperty::TraceHypercalls => true,
+            CapsuleProperty::CacheShare(_) => true,
+            CapsuleProperty::BandwidthShare(_) => true,
+            CapsuleProperty::GangSchedule => true,
+            CapsuleProperty::WXProtect => true,
+            CapsuleProperty::TimerMinInterval(_) => true,
+            CapsuleProperty::TrapLimit(_) => true,
+            CapsuleProperty::RTCEpoch(_) => true,
+            CapsuleProperty::ConsoleEncoding(_) => true,
+            CapsuleProperty::ConsoleBuffer(_) => true,
+            CapsuleProperty::ConsoleOverflow(_) => true,
+            CapsuleProperty::WSSSample(_) => true,
+            CapsuleProperty::CoreClass(_) => true,
+            CapsuleProperty::DTBPlacement(_) => true,
+            _ => false
+        }
+    }
+
     /* convert a property string into an CapsuleProperty, or None if not possible */
     pub fn string_to_property(property: &String) -> Option<CapsuleProperty>
     {
-        /* restart the capsule if it crashes (as opposed to exits cleanly) */
-        if property.eq_ignore_ascii_case("auto_crash_restart")
+        CapsuleProperty::parse(property).ok()
+    }
+
+    /* convert a property string into a CapsuleProperty, explaining why if that's not possible
+       => property = property string, optionally prefixed with its namespace version, eg: v1:self_test
+       <= property, or an error code */
+    pub fn parse(property: &String) -> Result<CapsuleProperty, Cause>
+    {
+        let mut body = property.trim();
+
+        /* strip off and check any version prefix */
+        let mut parts = body.splitn(2, ':');
+        if let (Some(prefix), Some(rest)) = (parts.next(), parts.next())
         {
-            return Some(CapsuleProperty::AutoCrashRestart);
+            let digits = prefix.get(1..).unwrap_or("");
+            if (prefix.starts_with('v') || prefix.starts_with('V')) && digits.len() > 0 && digits.chars().all(|c| c.is_ascii_digit())
+            {
+                if digits.parse::<usize>() != Ok(PROPERTY_NAMESPACE_VERSION)
+                {
+                    return Err(Cause::CapsulePropertyBadVersion);
+                }
+                body = rest.trim();
+            }
         }
 
-        /* console related properties */
-        if property.eq_ignore_ascii_case("service_console")
+        if let Some(property) = CapsuleProperty::parse_unversioned(body)
         {
-            return Some(CapsuleProperty::ServiceConsole);
+            return Ok(property);
         }
-        if property.eq_ignore_ascii_case("console_write")
+
+        /* tell apart a known property with a bad value from a property we've never heard of */
+        let name = body.splitn(2, '=').next().unwrap_or("").trim();
+        match PROPERTIES.iter().any(|(known, _)| known.eq_ignore_ascii_case(name))
         {
-            return Some(CapsuleProperty::ConsoleWrite);
+            true => Err(Cause::CapsulePropertyBadValue),
+            false => Err(Cause::CapsulePropertyUnknown)
         }
-        if property.eq_ignore_ascii_case("console_read")
+    }
+
+    /* convert a property string without a version prefix into an CapsuleProperty, or None if not possible */
+    fn parse_unversioned(property: &str) -> Option<CapsuleProperty>
+    {
+        let (name, value) = match split_property(property)
         {
-            return Some(CapsuleProperty::ConsoleRead);
-        }
-        if property.eq_ignore_ascii_case("hv_log_read")
+            Some((name, value)) => (name, Some(value)),
+            None => (property, None)
+        };
+
+        match PROPERTIES.iter().find(|(known, _)| known.eq_ignore_ascii_case(name))
         {
-            return Some(CapsuleProperty::HvLogRead);
+            Some((_, parse)) => parse(value),
+            None => None
         }
+    }
+}
 
-        None
+/* split a name=value property string into its name and value, or None if there's no value */
+fn split_property(property: &str) -> Option<(&str, &str)>
+{
+    let mut parts = property.splitn(2, '=');
+    match (parts.next(), parts.next())
+    {
+        (Some(name), Some(value)) => Some((name.trim(), value.trim())),
+        (_, _) => None
     }
 }
 
+/* summary of a capsule copied into a management service's memory. the layout is shared with the services */
+pub use hypercall::capsule::CapsuleSummary;
+
 struct Capsule
 {
-    state: CapsuleState,                     /* define whether this capsule is alive, dying or restarting */
+    id: CapsuleID,                           /* this capsule's ID, for reporting problems during teardown */
+    lifecycle: Lifecycle,                    /* define whether this capsule is alive, dying, restarting, or paused */
     properties: HashSet<CapsuleProperty>,    /* set of properties and rights assigned to this capsule */
     max_vpcus: CPUcount,
     vcores: HashSet<VirtualCoreID>,          /* set of virtual core IDs assigned to this capsule */
     init: HashMap<VirtualCoreID, VcoreInit>, /* map of vcore IDs to vcore initialization paramters */
     memory: Vec<Mapping>,                    /* map capsule supervisor virtual addresses to host physical addresses */
+    boot_image: Option<String>,              /* DMFS asset to load when the capsule next restarts, or None to rerun the current one */
+    name: String                             /* name of the DMFS asset the capsule is running, for management services */
 }
 
 impl Capsule
@@ -167,7 +580,7 @@ impl Capsule
     => properties = properties granted to this capsules, or None
        max_vpcus = maximum virtual CPU cores for this capsule
     <= capsule object, or error code */
-    pub fn new(property_strings: Option<Vec<String>>, max_vpcus: CPUcount) -> Result<Capsule, Cause>
+    pub fn new(id: CapsuleID, property_strings: Option<Vec<String>>, max_vpcus: CPUcount) -> Result<Capsule, Cause>
     {
         /* turn a possible list of property strings into list of official properties */
         let mut properties = HashSet::new();
@@ -184,15 +597,37 @@ impl Capsule
 
         Ok(Capsule
         {
-            state: CapsuleState::Valid,
+            id,
+            lifecycle: Lifecycle::new(),
             properties,
             max_vpcus,
             vcores: HashSet::new(),
             init: HashMap::new(),
-            memory: Vec::new()
+            memory: Vec::new(),
+            boot_image: None,
+            name: String::new()
         })
     }
 
+    /* record the name of the image the capsule is running */
+    pub fn set_name(&mut self, name: String) { self.name = name; }
+
+    /* summarize the capsule for management services */
+    pub fn summarize(&self, cid: CapsuleID) -> CapsuleSummary
+    {
+        let state = match self.get_state()
+        {
+            CapsuleState::Valid => hypercall::capsule::STATE_VALID,
+            CapsuleState::Dying => hypercall::capsule::STATE_DYING,
+            CapsuleState::Restarting => hypercall::capsule::STATE_RESTARTING,
+            CapsuleState::Paused => hypercall::capsule::STATE_PAUSED
+        };
+
+        CapsuleSummary::new(cid, state, self.count_vcores(),
+            self.memory.iter().filter_map(|m| m.get_physical()).map(|r| r.size()).sum(),
+            self.name.as_bytes())
+    }
+
     /* add a mapping to this capsule */
     pub fn set_memory_mapping(&mut self, to_add: Mapping)
     {
@@ -208,6 +643,243 @@ impl Capsule
         self.properties.contains(&property)
     }
 
+    /* return the indexes of the physical serial ports to pass through to this capsule */
+    pub fn get_serial_ports(&self) -> Vec<usize>
+    {
+        let mut ports = Vec::new();
+        for property in &self.properties
+        {
+            if let CapsuleProperty::SerialPort(index) = property
+            {
+                ports.push(*index);
+            }
+        }
+        ports
+    }
+
+    /* return the numbers of the virtual serial links this capsule should join */
+    pub fn get_serial_links(&self) -> Vec<usize>
+    {
+        let mut links = Vec::new();
+        for property in &self.properties
+        {
+            if let CapsuleProperty::SerialLink(link) = property
+            {
+                links.push(*link);
+            }
+        }
+        links
+    }
+
+    /* return the host GPIO lines this capsule should be given, in the order they were declared */
+    pub fn get_gpio_lines(&self) -> Vec<usize>
+    {
+        let mut lines = Vec::new();
+        for property in &self.properties
+        {
+            if let CapsuleProperty::GpioLine(line) = property
+            {
+                lines.push(*line);
+            }
+        }
+        lines
+    }
+
+    /* return the minimum interval requested between arming this capsule's timers and their firing, in microseconds */
+    pub fn get_timer_min_interval(&self) -> Option<u64>
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::TimerMinInterval(us) = property
+            {
+                return Some(*us);
+            }
+        }
+        None
+    }
+
+    /* return the number of traps a second this capsule can make before it's throttled, or 0 for no limit */
+    pub fn get_trap_limit(&self) -> u64
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::TrapLimit(traps) = property
+            {
+                return *traps;
+            }
+        }
+        throttle::TRAP_LIMIT_DEFAULT
+    }
+
+    /* return the wall-clock time, in seconds since 1970, this capsule starts from on hosts without an RTC, or None for none */
+    pub fn get_rtc_epoch(&self) -> Option<u64>
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::RTCEpoch(seconds) = property
+            {
+                return Some(*seconds);
+            }
+        }
+        None
+    }
+
+    /* return the number of pages to sample per period to estimate this capsule's working set, or None if not profiled */
+    pub fn get_wss_sample(&self) -> Option<usize>
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::WSSSample(pages) = property
+            {
+                return Some(*pages);
+            }
+        }
+        None
+    }
+
+    /* return the encoding of this capsule's console bytes */
+    pub fn get_console_encoding(&self) -> console::Encoding
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::ConsoleEncoding(encoding) = property
+            {
+                return *encoding;
+            }
+        }
+        console::Encoding::UTF8
+    }
+
+    /* return a new, empty console output buffer sized and set up as this capsule's properties ask */
+    pub fn new_console_buffer(&self) -> console::OutputBuffer
+    {
+        let mut limit = console::OUTPUT_BUFFER_DEFAULT;
+        let mut policy = console::Overflow::DropOldest;
+        for property in &self.properties
+        {
+            match property
+            {
+                CapsuleProperty::ConsoleBuffer(bytes) => limit = *bytes,
+                CapsuleProperty::ConsoleOverflow(overflow) => policy = *overflow,
+                _ => ()
+            }
+        }
+        console::OutputBuffer::new(limit, policy)
+    }
+
+    /* return the names of the device models this capsule should be given */
+    pub fn get_device_models(&self) -> Vec<String>
+    {
+        let mut models = Vec::new();
+        for property in &self.properties
+        {
+            if let CapsuleProperty::DeviceModel(name) = property
+            {
+                models.push(name.clone());
+            }
+        }
+        models
+    }
+
+    /* return the virtual devices declared for this capsule */
+    pub fn get_devices(&self) -> Vec<DeviceSpec>
+    {
+        let mut devices = Vec::new();
+        for property in &self.properties
+        {
+            if let CapsuleProperty::Device(spec) = property
+            {
+                devices.push(spec.clone());
+            }
+        }
+        devices
+    }
+
+    /* return the patterns of the service names this capsule has been granted */
+    pub fn get_service_names(&self) -> service::NameRights
+    {
+        let mut rights = service::NameRights { register: Vec::new(), restrict: Vec::new(), access: Vec::new() };
+        for property in &self.properties
+        {
+            match property
+            {
+                CapsuleProperty::ServiceName(pattern) => rights.register.push(pattern.clone()),
+                CapsuleProperty::ServiceNameRestrict(pattern) => rights.restrict.push(pattern.clone()),
+                CapsuleProperty::ServiceNameAccess(pattern) => rights.access.push(pattern.clone()),
+                _ => ()
+            }
+        }
+        rights
+    }
+
+    /* return where to put this capsule's device tree, which is at random unless requested otherwise */
+    pub fn get_dtb_placement(&self) -> virtdt::Placement
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::DTBPlacement(placement) = property
+            {
+                return *placement;
+            }
+        }
+        virtdt::Placement::Random
+    }
+
+    /* return the class of physical core this capsule's vcores should prefer, if one was requested */
+    pub fn get_core_class(&self) -> Option<CoreClass>
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::CoreClass(class) = property
+            {
+                return Some(*class);
+            }
+        }
+        None
+    }
+
+    /* return the deadline scheduling parameters requested for this capsule's vcores, if any */
+    pub fn get_deadline(&self) -> Option<Deadline>
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::Deadline(deadline) = property
+            {
+                return Some(*deadline);
+            }
+        }
+        None
+    }
+
+    /* return the percentages of last-level cache and memory bandwidth to reserve for this capsule */
+    pub fn get_qos_shares(&self) -> (usize, usize)
+    {
+        let (mut cache, mut bandwidth) = (0, 0);
+        for property in &self.properties
+        {
+            match property
+            {
+                CapsuleProperty::CacheShare(percent) => cache = *percent,
+                CapsuleProperty::BandwidthShare(percent) => bandwidth = *percent,
+                _ => ()
+            }
+        }
+        (cache, bandwidth)
+    }
+
+    /* return when this capsule's memory should be zeroed */
+    pub fn get_zero_policy(&self) -> ZeroPolicy
+    {
+        for property in &self.properties
+        {
+            if let CapsuleProperty::ZeroMemory(policy) = property
+            {
+                return *policy;
+            }
+        }
+        ZeroPolicy::Default
+    }
+
     /* return the maximum number of virtual cores allowed by this capsule */
     pub fn get_max_vcores(&self) -> CPUcount { self.max_vpcus }
 
@@ -234,6 +906,21 @@ impl Capsule
         self.init.iter()
     }
 
+    /* point every virtual core at a new entry point, for when the capsule's image is replaced */
+    pub fn set_init_entry(&mut self, entry: Entry)
+    {
+        for (_, params) in self.init.iter_mut()
+        {
+            params.entry = entry;
+        }
+    }
+
+    /* select the DMFS asset to load the next time this capsule restarts */
+    pub fn set_boot_image(&mut self, name: String) { self.boot_image = Some(name); }
+
+    /* remove and return the DMFS asset selected for this capsule's restart, if any */
+    pub fn take_boot_image(&mut self) -> Option<String> { self.boot_image.take() }
+
     /* remove a virtual core ID from the capsule's list */
     pub fn remove_vcore(&mut self, id: VirtualCoreID)
     {
@@ -262,38 +949,32 @@ impl Capsule
     }
 
     /* return this capsule's state */
-    pub fn get_state(&self) -> &CapsuleState { &self.state }
+    pub fn get_state(&self) -> CapsuleState { self.lifecycle.state() }
 
-    /* mark this capsule as dying. returns true if this is possible.
-    only valid or dying capsules can die */
-    pub fn set_state_dying(&mut self) -> bool
+    /* change this capsule's state by applying a lifecycle event
+    <= the transition made, or an error code if the event isn't allowed in the capsule's current state */
+    pub fn transition(&mut self, event: Event) -> Result<Transition, Cause>
     {
-        match self.state
-        {
-            CapsuleState::Dying => (),
-            CapsuleState::Valid => self.state = CapsuleState::Dying,
-            _ => return false
-        }
-
-        true
+        self.lifecycle.apply(self.id, event)
     }
+}
 
-    /* mark this capsule as restarting. returns true if this is possible.
-    only valid or restarting capsules can restart */
-    pub fn set_state_restarting(&mut self) -> bool
-    {
-        match self.state
-        {
-            CapsuleState::Restarting => (),
-            CapsuleState::Valid => self.state = CapsuleState::Restarting,
-            _ => return false
-        }
+/* keep track of capsules changing state. called with the capsule table locked */
+fn lifecycle_changed(cid: CapsuleID, from: CapsuleState, to: CapsuleState)
+{
+    hvdebug!("Capsule {} changed state from {:?} to {:?}", cid, from, to);
 
-        true
+    /* tell management capsules there's a crashed capsule to look at */
+    if to == CapsuleState::Paused
+    {
+        CRASHED.lock().push_back(cid);
     }
+}
 
-    /* mark the capsule's state as valid */
-    pub fn set_state_valid(&mut self) { self.state = CapsuleState::Valid; }
+/* start tracking capsules' state changes. call once during boot */
+pub fn init()
+{
+    lifecycle::listen(lifecycle_changed);
 }
 
 /* handle the destruction of a capsule */
@@ -301,18 +982,39 @@ impl Drop for Capsule
 {
     fn drop(&mut self)
     {
-        /* free up memory... */
+        /* free up memory, scrubbing it if required, and account for every region.
+           a region that can't be freed is lost until the host is rebooted */
+        let policy = self.get_zero_policy();
+        let (mut mapped, mut freed, mut retained) = (0, 0, 0);
         for mapping in self.memory.clone()
         {
             if let Some(r) = mapping.get_physical()
             {
-                match physmem::dealloc_region(r)
+                mapped = mapped + r.size();
+                if mapping.is_retained() == true
+                {
+                    retained = retained + r.size();
+                    continue;
+                }
+
+                match physmem::dealloc_region_policy(r, policy)
                 {
-                    Err(e) => hvalert!("Error during capsule {:p} teardown: {:?}", &self, e),
-                    Ok(_) => ()
+                    Err(e) =>
+                    {
+                        hvalert!("Capsule {} teardown leaked region 0x{:x} size 0x{:x}: {}",
+                            self.id, r.base(), r.size(), error::report(&e));
+                        metrics::count_leak(r.size());
+                    },
+                    Ok(_) => freed = freed + r.size()
                 };
             }
         }
+
+        if freed + retained != mapped
+        {
+            hvalert!("Capsule {} teardown: {} of {} bytes of RAM not returned ({} bytes deliberately retained)",
+                self.id, mapped - freed - retained, mapped, retained);
+        }
     }
 }
 
@@ -367,7 +1069,10 @@ pub fn create(properties: Option<Vec<String>>, max_vcores: CPUcount) -> Result<C
             Vacant(_) =>
             {
                 /* insert our new capsule */
-                capsules.insert(new_id, Capsule::new(properties, max_vcores)?);
+                capsules.insert(new_id, Capsule::new(new_id, properties, max_vcores)?);
+
+                /* give it a unique machine ID for its device tree */
+                identity::provision(new_id);
 
                 /* we're all done here */
                 return Ok(new_id);
@@ -377,144 +1082,813 @@ pub fn create(properties: Option<Vec<String>>, max_vcores: CPUcount) -> Result<C
     }
 }
 
-/* destroy the given virtualcore within the given capsule.
-   when the capsule is out of vcores, destroy it.
-   see destroy_current() for more details */
-fn destroy(cid: CapsuleID, vid: VirtualCoreID) -> Result<(), Cause>
+/* create a new blank capsule with the same properties, name, and maximum number of virtual
+   cores as an existing one. it gets its own ID and unique machine ID, and nothing else
+   => template = capsule to copy
+   <= CapsuleID for the new capsule, or an error code */
+pub fn duplicate(template: CapsuleID) -> Result<CapsuleID, Cause>
+{
+    let (properties, max_vcores, name) = match CAPSULES.lock().get(&template)
+    {
+        Some(c) => (c.properties.clone(), c.max_vpcus, c.name.clone()),
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let new_id = create(None, max_vcores)?;
+    match CAPSULES.lock().get_mut(&new_id)
+    {
+        Some(c) =>
+        {
+            c.properties = properties;
+            c.set_name(name);
+        },
+        None => return Err(Cause::CapsuleBadID)
+    }
+    Ok(new_id)
+}
+
+/* record the name of the image the given capsule is running */
+pub fn set_name(cid: CapsuleID, name: &str) -> Result<(), Cause>
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) =>
+        {
+            c.set_name(String::from(name));
+            Ok(())
+        },
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return the accounting of the capsule table's lock */
+pub fn lock_stats() -> LockStats
+{
+    CAPSULES.stats()
+}
+
+/* return a consistent summary of every capsule, gathered in one go under the capsule table's lock */
+pub fn snapshot() -> Vec<CapsuleSummary>
+{
+    CAPSULES.lock().iter().map(|(cid, c)| c.summarize(*cid)).collect()
+}
+
+/* copy a consistent summary of every capsule into the running capsule's memory. the summaries
+   are gathered in one go under the capsule table's lock, and then copied out without holding it,
+   so a slow copy can't hold up the rest of the system. requires manage_capsules
+   => buffer = address of the array of CapsuleSummary structures to fill in the running capsule
+      count = number of entries in the array
+   <= total number of capsules, which may be more than were copied, or an error code */
+pub fn copy_snapshot(buffer: usize, count: usize) -> Result<usize, Cause>
+{
+    let caller = get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+
+    let snapshot = snapshot();
+
+    let to_copy = core::cmp::min(count, snapshot.len());
+    if to_copy > 0
+    {
+        let base = hcargs::array::<CapsuleSummary>(caller, buffer, to_copy, snapshot.len(), Access::Write)?;
+        let target = unsafe { core::slice::from_raw_parts_mut(base as *mut CapsuleSummary, to_copy) };
+        target.copy_from_slice(&snapshot[..to_copy]);
+    }
+
+    Ok(snapshot.len())
+}
+
+/* destroy the given virtualcore within the given capsule.
+   when the capsule is out of vcores, destroy it.
+   see destroy_current() for more details */
+fn destroy(cid: CapsuleID, vid: VirtualCoreID) -> Result<(), Cause>
+{
+    /* reapplying protection windows locks the capsule table, so wait until it's unlocked */
+    let mut to_reenforce = Vec::new();
+    let result = destroy_locked(cid, vid, &mut to_reenforce);
+    for id in to_reenforce
+    {
+        reenforce(id);
+    }
+    result
+}
+
+/* destroy the given virtualcore within the given capsule with the capsule table locked.
+   => cid, vid = capsule and vcore to destroy
+      to_reenforce = list to add capsules to whose protection windows must be reapplied
+   <= Ok for success, or an error code */
+fn destroy_locked(cid: CapsuleID, vid: VirtualCoreID, to_reenforce: &mut Vec<CapsuleID>) -> Result<(), Cause>
+{
+    /* make sure this capsule is dying */
+    let mut lock = CAPSULES.lock();
+    if let Some(victim) = CAPSULES.lock().get_mut(&cid)
+    {
+        match victim.transition(Event::Kill)
+        {
+            Ok(_) =>
+            {
+                /* remove this current vcore ID from the capsule's
+                hash table. also mark the vcore as doomed, meaning
+                it will be dropped when it's context switched out */
+                victim.remove_vcore(vid);
+                pcore::PhysicalCore::this().doom_vcore();
+
+                /* are there any vcores remaining? */
+                if victim.count_vcores() == 0
+                {
+                    /* if not then deregister any and all services
+                       belonging to this capsule */
+                    service::deregister(SelectService::AllServices, cid)?;
+
+                    /* and lock away any physical devices it was given */
+                    passthrough::release(cid);
+                    bounce::release(cid);
+                    abi::forget(cid);
+                    transfer::cancel(cid);
+                    virtdt::forget(cid);
+                    trace::forget(cid);
+                    qos::release(cid);
+                    guestlog::forget(cid);
+                    seriallink::detach(cid);
+                    gpio::detach(cid);
+                    devmodel::detach(cid);
+                    metrics::forget(cid);
+                    accounting::forget(cid);
+                    console::forget(cid);
+                    wss::forget(cid);
+                    identity::forget(cid);
+                    guestpanic::forget(cid);
+                    crashdump::forget(cid);
+                    inspect::forget(cid);
+                    abboot::forget(cid);
+                    boottime::forget(cid);
+                    clock::forget(cid);
+                    throttle::forget(cid);
+                    stream::forget(cid);
+                    pressure::forget(cid);
+                    to_reenforce.extend(grant::forget(cid));
+                    telemetry::forget(cid);
+                    measure::forget(cid);
+                    vipi::forget(cid);
+                    if mmio::forget(cid) == true
+                    {
+                        to_reenforce.push(cid);
+                    }
+                    dirty::forget(cid);
+                    template::forget(cid);
+                    quiesce::forget(cid);
+                    arbiter::forget(cid);
+                    button::forget(cid);
+                    
+                    /* next, remove this capsule
+                    from the global hash table, which should
+                    trigger the final teardown via drop */
+                    lock.remove(&cid);
+                    hvdebug!("Completed termination of capsule {}", cid);
+                }
+
+                return Ok(());
+            },
+            Err(e) => return Err(e)
+        }
+    }
+    else
+    {
+        Err(Cause::CapsuleBadID)
+    }
+}
+
+/* mark the currently running capsule as dying,
+   or continue to kill off the capsule. each vcore
+   should call this when it realizes the capsule
+   is dying so that the current vcore can be removed.
+   it can be called multiple times per vcore.
+   when there are no vcores left, its RAM
+   and any other resources will be deallocated.
+   when the vcore count drops to zero, it will drop.
+   it's on the caller of destroy_capsule() to reschedule
+   another vcore to run.
+   <= Ok for success, or an error code
+*/
+pub fn destroy_current() -> Result<(), Cause>
+{
+    let (cid, vid) = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => (id.capsuleid, id.vcoreid),
+        None =>
+        {
+            hvalert!("BUG: Can't find currently running capsule to destroy");
+            return Err(Cause::CapsuleBadID);
+        }
+    };
+
+    destroy(cid, vid)
+}
+
+/* remove the given virtual core from the capsule and mark it as restarting.
+   see restart_current() for more details */
+fn restart(cid: CapsuleID, vid: VirtualCoreID) -> Result<(), Cause>
+{ 
+    /* make sure this capsule is restarting */
+    let mut lock = CAPSULES.lock();
+
+    if let Some(victim) = lock.get_mut(&cid)
+    {
+        match victim.transition(Event::Restart)
+        {
+            Ok(_) =>
+            {
+                /* remove this current vcore ID from the capsule's
+                hash table. also mark the vcore as doomed, meaning
+                it will be dropped when it's context switched out */
+                victim.remove_vcore(vid);
+                pcore::PhysicalCore::this().doom_vcore();
+
+                /* are there any vcores remaining? */
+                if victim.count_vcores() == 0
+                {
+                    /* no vcores left so add this capsule to the restart set */
+                    TO_RESTART.lock().insert(cid);
+                }
+
+                return Ok(());
+            },
+
+            Err(e) => return Err(e)
+        }
+    }
+    else
+    {
+        Err(Cause::CapsuleBadID)
+    }
+}
+
+/* recreate and restart the currently running capsule, if possible.
+   it can be called multiple times per vcore. each vcore should call
+   this within the capsule when it realizes the capsule is restarting.
+   when all vcores have call this function, the capsule will restart proper.
+   it's on the caller of restart_current() to reschedule another vcore to run.
+   <= Ok for success, or an error code
+*/
+pub fn restart_current() -> Result<(), Cause>
+{
+    let (cid, vid) = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => (id.capsuleid, id.vcoreid),
+        None =>
+        {
+            hvalert!("BUG: Can't find currently running capsule to restart");
+            return Err(Cause::CapsuleBadID);
+        }
+    };
+
+    restart(cid, vid)
+}
+
+/* freeze the currently running capsule, or continue to freeze it.
+   each vcore should call this when it realizes the capsule is paused
+   so that it can be parked with its state intact. when the capsule
+   is first paused, management capsules are notified. it's on the caller to
+   reschedule another vcore to run.
+   <= Ok for success, or an error code */
+pub fn pause_current() -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(id) => id,
+        None =>
+        {
+            hvalert!("BUG: Can't find currently running capsule to pause");
+            return Err(Cause::CapsuleBadID);
+        }
+    };
+
+    let transition = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(capsule) => capsule.transition(Event::Pause)?,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* the vcore will be handed to park_vcore() when it's switched out */
+    pcore::PhysicalCore::this().park_vcore();
+
+    if transition.from != CapsuleState::Paused
+    {
+        park_all_vcores(cid);
+    }
+    Ok(())
+}
+
+/* pause the currently running capsule at its own request, such as when it's ready to be used
+   as a template. unlike pause_current(), management capsules aren't told it has crashed.
+   it's on the caller to reschedule another vcore to run.
+   <= Ok for success, or an error code */
+pub fn quiesce_current() -> Result<(), Cause>
+{
+    pause_current()?;
+    if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+    {
+        CRASHED.lock().retain(|crashed| *crashed != cid);
+    }
+    Ok(())
+}
+
+/* pause a capsule from outside it, such as when it hasn't quiesced in time for a snapshot.
+   its vcores park themselves when they next find it's paused. as with quiesce_current(),
+   management capsules aren't told it has crashed
+   => cid = capsule to pause
+   <= Ok for success, or an error code */
+pub fn pause(cid: CapsuleID) -> Result<(), Cause>
+{
+    let transition = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(capsule) => capsule.transition(Event::Pause)?,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    CRASHED.lock().retain(|crashed| *crashed != cid);
+    if transition.from != CapsuleState::Paused
+    {
+        park_all_vcores(cid);
+    }
+    Ok(())
+}
+
+/* park every vcore of a newly paused capsule rather than wait for each to next be scheduled.
+   those in the global queue are parked now, and each physical core is told to park those
+   waiting in its own queue and switch out any it's running
+   => cid = paused capsule */
+fn park_all_vcores(cid: CapsuleID)
+{
+    scheduler::park_queued(cid);
+
+    match message::Message::new(message::Recipient::send_to_all(), message::MessageContent::Pause(cid))
+    {
+        Ok(msg) => if let Err(_e) = message::send(msg)
+        {
+            hvalert!("Failed to tell physical cores to park capsule {}: {:?}", cid, _e);
+        },
+        Err(_e) => hvalert!("Failed to create message to park capsule {}: {:?}", cid, _e)
+    }
+}
+
+/* hold a switched-out virtual core of a paused capsule until the capsule is resumed or killed */
+pub fn park_vcore(vcore: VirtualCore)
+{
+    let cid = vcore.get_capsule_id();
+    let mut parked = PARKED.lock();
+    match parked.get_mut(&cid)
+    {
+        Some(list) => list.push(vcore),
+        None =>
+        {
+            let mut list = Vec::new();
+            list.push(vcore);
+            parked.insert(cid, list);
+        }
+    }
+}
+
+/* return the parked virtual cores of a capsule to the scheduling queues */
+fn unpark_vcores(cid: CapsuleID)
+{
+    if let Some(list) = PARKED.lock().remove(&cid)
+    {
+        for vcore in list
+        {
+            scheduler::queue(vcore);
+        }
+    }
+}
+
+/* return the ID of the next crashed capsule awaiting inspection, or an error.
+   *** the currently running capsule must have the manage_capsules property *** */
+pub fn next_crashed() -> Result<CapsuleID, Cause>
+{
+    current_has_property(CapsuleProperty::ManageCapsules)?;
+    match CRASHED.lock().pop_front()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBufferEmpty)
+    }
+}
+
+/* resume a paused capsule, rescheduling its virtual cores from where they stopped.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to resume
+   <= Ok for success, or an error code */
+pub fn resume(cid: CapsuleID) -> Result<(), Cause>
+{
+    current_has_property(CapsuleProperty::ManageCapsules)?;
+
+    /* a template must stay as it is while capsules are cloned from it */
+    if template::is_template(cid) == true
+    {
+        return Err(Cause::TemplateInUse);
+    }
+
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(capsule) => capsule.transition(Event::Resume)?,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* a capsule that quiesced itself to be a template is carrying on instead */
+    template::forget(cid);
+    inspect::forget(cid);
+    unpark_vcores(cid);
+    Ok(())
+}
+
+/* kill every capsule, such as during a warm reboot. their vcores tear themselves down via
+   the usual dying path when next scheduled. capsules that are restarting can't be killed
+   until they're running again, so call this repeatedly until it returns zero
+   <= number of capsules remaining */
+pub fn kill_all() -> usize
+{
+    let mut paused = Vec::new();
+    let remaining =
+    {
+        let mut capsules = CAPSULES.lock();
+        for (cid, capsule) in capsules.iter_mut()
+        {
+            if let Ok(transition) = capsule.transition(Event::Kill)
+            {
+                if transition.from == CapsuleState::Paused
+                {
+                    paused.push(*cid);
+                }
+            }
+        }
+        capsules.len()
+    };
+
+    /* release paused capsules' vcores so they can die */
+    for cid in paused
+    {
+        unpark_vcores(cid);
+    }
+    CRASHED.lock().clear();
+
+    remaining
+}
+
+/* kill a paused capsule. its vcores are released from parking so they can
+   each tear themselves down via the usual dying path when next scheduled.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to kill
+   <= Ok for success, or an error code */
+pub fn kill_paused(cid: CapsuleID) -> Result<(), Cause>
+{
+    current_has_property(CapsuleProperty::ManageCapsules)?;
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(capsule) =>
+        {
+            if capsule.get_state() != CapsuleState::Paused
+            {
+                return Err(Cause::CapsuleNotPaused);
+            }
+            capsule.transition(Event::Kill)?;
+        },
+        None => return Err(Cause::CapsuleBadID)
+    }
+
+    unpark_vcores(cid);
+    Ok(())
+}
+
+/* kill a capsule, such as at an administrator's request. its vcores tear themselves down
+   via the usual dying path when next scheduled, and paused vcores are released to do so
+   => cid = capsule to kill
+   <= Ok for success, or an error code */
+pub fn kill(cid: CapsuleID) -> Result<(), Cause>
+{
+    let paused = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(capsule) => capsule.transition(Event::Kill)?.from == CapsuleState::Paused,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    if paused == true
+    {
+        unpark_vcores(cid);
+    }
+    Ok(())
+}
+
+/* restart a running capsule, such as at an administrator's request.
+   the capsule's vcores notice it's restarting when they're next scheduled
+   => cid = capsule to restart
+   <= Ok for success, or an error code */
+pub fn request_restart(cid: CapsuleID) -> Result<(), Cause>
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) => match c.transition(Event::Restart)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e)
+        },
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* maximum length of a boot image name passed in by a capsule */
+const BOOT_IMAGE_NAME_MAX: usize = 256;
+
+/* select a different DMFS executable to load into a capsule the next time it restarts,
+   such as when switching between A and B firmware images. the capsule keeps its
+   properties, RAM, and device tree. a capsule can select its own next image, and
+   capsules with the manage_capsules property can select any capsule's image
+   => target = capsule to change, or usize::MAX for the currently running capsule
+      name, length = address and size in bytes of the asset's name in the running capsule
+   <= Ok for success, or an error code */
+pub fn select_boot_image(target: CapsuleID, name: usize, length: usize) -> Result<(), Cause>
+{
+    let caller = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let target = match target
+    {
+        usize::MAX => caller,
+        cid if cid == caller => cid,
+        cid =>
+        {
+            current_has_property(CapsuleProperty::ManageCapsules)?;
+            cid
+        }
+    };
+
+    if length > BOOT_IMAGE_NAME_MAX
+    {
+        return Err(Cause::ManifestNoSuchAsset);
+    }
+
+    /* copy the name out of the caller's memory and make sure the asset can be run */
+    let base = hcargs::buffer(caller, name, length, BOOT_IMAGE_NAME_MAX, Access::Read)?;
+    let bytes = unsafe { core::slice::from_raw_parts(base as *const u8, length) };
+    let name = match core::str::from_utf8(bytes)
+    {
+        Ok(s) => s.to_string(),
+        Err(_) => return Err(Cause::ManifestNoSuchAsset)
+    };
+    manifest::check_executable(&name)?;
+
+    match CAPSULES.lock().get_mut(&target)
+    {
+        Some(c) =>
+        {
+            /* the new image must prove itself or be rolled back */
+            abboot::begin(target, &c.name, &name);
+            c.set_boot_image(name);
+            Ok(())
+        },
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* restart a running capsule into the given DMFS executable, such as when rolling back a failed image.
+   the capsule's vcores notice it's restarting when they're next scheduled
+   => cid = capsule to restart
+      name = name of the executable to load into it
+   <= Ok for success, or an error code */
+pub fn restart_with_image(cid: CapsuleID, name: String) -> Result<(), Cause>
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) =>
+        {
+            c.transition(Event::Restart)?;
+            c.set_boot_image(name);
+            Ok(())
+        },
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return Some(true) if capsule currently running on this physical core
+   should be frozen if it crashes, Some(false) if not, or None
+   if this physical core isn't running a capsule */
+pub fn is_current_pause_on_crash() -> Option<bool>
+{
+    match get_capsule_id_if_property(CapsuleProperty::PauseOnCrash)
+    {
+        Ok(_) => Some(true),
+        Err(Cause::CapsulePropertyNotFound) => Some(false),
+        Err(_) => None
+    }
+}
+
+/* return the given capsule's maximum number of virtual cores, identified by ID, or None for not found */
+pub fn get_max_vcores(cid: CapsuleID) -> Result<CPUcount, Cause>
+{
+    match CAPSULES.lock().entry(cid)
+    {
+        Occupied(capsule) => Ok(capsule.get().get_max_vcores()),
+        Vacant(_) => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return the initialization parameters of each of the given capsule's virtual cores, or an error code */
+pub fn get_vcore_inits(cid: CapsuleID) -> Result<Vec<(VirtualCoreID, Entry, Priority)>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(capsule) => Ok(capsule.iter_init().map(|(vid, params)| (*vid, params.entry, params.prio)).collect()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return true if the given capsule has the given property, false if not, or an error code */
+pub fn has_property(cid: CapsuleID, property: CapsuleProperty) -> Result<bool, Cause>
+{
+    match CAPSULES.lock().entry(cid)
+    {
+        Occupied(capsule) => Ok(capsule.get().has_property(property)),
+        Vacant(_) => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return the number of vcores in the given capsule if they should be gang scheduled, or None if not */
+pub fn get_gang_size(cid: CapsuleID) -> Option<usize>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) if c.has_property(CapsuleProperty::GangSchedule) => Some(c.count_vcores()),
+        _ => None
+    }
+}
+
+/* return the percentages of last-level cache and memory bandwidth to reserve for the given capsule */
+pub fn get_qos_shares(cid: CapsuleID) -> Result<(usize, usize), Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_qos_shares()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return when the given capsule's memory should be zeroed, or an error code */
+pub fn get_zero_policy(cid: CapsuleID) -> Result<ZeroPolicy, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_zero_policy()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return a copy of the given capsule's memory mappings, or an error code */
+pub fn get_memory_mappings(cid: CapsuleID) -> Result<Vec<Mapping>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_memory_mappings()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* return the deadline scheduling parameters requested for the given capsule's vcores, if any */
+pub fn get_deadline(cid: CapsuleID) -> Result<Option<Deadline>, Cause>
 {
-    /* make sure this capsule is dying */
-    let mut lock = CAPSULES.lock();
-    if let Some(victim) = CAPSULES.lock().get_mut(&cid)
+    match CAPSULES.lock().get(&cid)
     {
-        match victim.set_state_dying()
-        {
-            true =>
-            {
-                /* remove this current vcore ID from the capsule's
-                hash table. also mark the vcore as doomed, meaning
-                it will be dropped when it's context switched out */
-                victim.remove_vcore(vid);
-                pcore::PhysicalCore::this().doom_vcore();
-
-                /* are there any vcores remaining? */
-                if victim.count_vcores() == 0
-                {
-                    /* if not then deregister any and all services
-                       belonging to this capsule */
-                    service::deregister(SelectService::AllServices, cid)?;
-                    
-                    /* next, remove this capsule
-                    from the global hash table, which should
-                    trigger the final teardown via drop */
-                    lock.remove(&cid);
-                    hvdebug!("Completed termination of capsule {}", cid);
-                }
+        Some(c) => Ok(c.get_deadline()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-                return Ok(());
-            },
-            false => return Err(Cause::CapsuleCantDie)
-        }
+/* return the indexes of the physical serial ports to pass through to the given capsule */
+pub fn get_serial_ports(cid: CapsuleID) -> Result<Vec<usize>, Cause>
+{
+    match CAPSULES.lock().entry(cid)
+    {
+        Occupied(capsule) => Ok(capsule.get().get_serial_ports()),
+        Vacant(_) => Err(Cause::CapsuleBadID)
     }
-    else
+}
+
+/* return the numbers of the virtual serial links the given capsule should join */
+pub fn get_serial_links(cid: CapsuleID) -> Result<Vec<usize>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
     {
-        Err(Cause::CapsuleBadID)
+        Some(c) => Ok(c.get_serial_links()),
+        None => Err(Cause::CapsuleBadID)
     }
 }
 
-/* mark the currently running capsule as dying,
-   or continue to kill off the capsule. each vcore
-   should call this when it realizes the capsule
-   is dying so that the current vcore can be removed.
-   it can be called multiple times per vcore.
-   when there are no vcores left, its RAM
-   and any other resources will be deallocated.
-   when the vcore count drops to zero, it will drop.
-   it's on the caller of destroy_capsule() to reschedule
-   another vcore to run.
-   <= Ok for success, or an error code
-*/
-pub fn destroy_current() -> Result<(), Cause>
+/* return the host GPIO lines the given capsule should be given, in the order they were declared */
+pub fn get_gpio_lines(cid: CapsuleID) -> Result<Vec<usize>, Cause>
 {
-    let (cid, vid) = match pcore::PhysicalCore::this().get_virtualcore_id()
+    match CAPSULES.lock().get(&cid)
     {
-        Some(id) => (id.capsuleid, id.vcoreid),
-        None =>
-        {
-            hvalert!("BUG: Can't find currently running capsule to destroy");
-            return Err(Cause::CapsuleBadID);
-        }
-    };
+        Some(c) => Ok(c.get_gpio_lines()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-    destroy(cid, vid)
+/* return the number of traps a second the given capsule can make before it's throttled, 0 for no limit, or an error code */
+pub fn get_trap_limit(cid: CapsuleID) -> Result<u64, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_trap_limit()),
+        None => Err(Cause::CapsuleBadID)
+    }
 }
 
-/* remove the given virtual core from the capsule and mark it as restarting.
-   see restart_current() for more details */
-fn restart(cid: CapsuleID, vid: VirtualCoreID) -> Result<(), Cause>
-{ 
-    /* make sure this capsule is restarting */
-    let mut lock = CAPSULES.lock();
+/* return the wall-clock time, in seconds since 1970, the given capsule starts from on hosts without an RTC,
+   None for none, or an error code */
+pub fn get_rtc_epoch(cid: CapsuleID) -> Result<Option<u64>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_rtc_epoch()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-    if let Some(victim) = lock.get_mut(&cid)
+/* return the number of pages to sample per period to estimate the given capsule's working set, or None if not profiled */
+pub fn get_wss_sample(cid: CapsuleID) -> Result<Option<usize>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
     {
-        match victim.set_state_restarting()
-        {
-            true =>
-            {
-                /* remove this current vcore ID from the capsule's
-                hash table. also mark the vcore as doomed, meaning
-                it will be dropped when it's context switched out */
-                victim.remove_vcore(vid);
-                pcore::PhysicalCore::this().doom_vcore();
+        Some(c) => Ok(c.get_wss_sample()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-                /* are there any vcores remaining? */
-                if victim.count_vcores() == 0
-                {
-                    /* no vcores left so add this capsule to the restart set */
-                    TO_RESTART.lock().insert(cid);
-                }
+/* return the encoding of the given capsule's console bytes, or an error if the capsule doesn't exist */
+pub fn get_console_encoding(cid: CapsuleID) -> Result<console::Encoding, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_console_encoding()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-                return Ok(());
-            },
+/* return the minimum timer interval requested by the given capsule, in microseconds, or None for the default */
+pub fn get_timer_min_interval(cid: CapsuleID) -> Result<Option<u64>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_timer_min_interval()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-            false => return Err(Cause::CapsuleCantRestart)
-        }
+/* return the names of the device models the given capsule should be given */
+pub fn get_device_models(cid: CapsuleID) -> Result<Vec<String>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_device_models()),
+        None => Err(Cause::CapsuleBadID)
     }
-    else
+}
+
+/* return the virtual devices declared for the given capsule, or an error code */
+pub fn get_devices(cid: CapsuleID) -> Result<Vec<DeviceSpec>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
     {
-        Err(Cause::CapsuleBadID)
+        Some(c) => Ok(c.get_devices()),
+        None => Err(Cause::CapsuleBadID)
     }
 }
 
-/* recreate and restart the currently running capsule, if possible.
-   it can be called multiple times per vcore. each vcore should call
-   this within the capsule when it realizes the capsule is restarting.
-   when all vcores have call this function, the capsule will restart proper.
-   it's on the caller of restart_current() to reschedule another vcore to run.
-   <= Ok for success, or an error code
-*/
-pub fn restart_current() -> Result<(), Cause>
+/* return where to put the given capsule's device tree, or an error code */
+pub fn get_dtb_placement(cid: CapsuleID) -> Result<virtdt::Placement, Cause>
 {
-    let (cid, vid) = match pcore::PhysicalCore::this().get_virtualcore_id()
+    match CAPSULES.lock().get(&cid)
     {
-        Some(id) => (id.capsuleid, id.vcoreid),
-        None =>
-        {
-            hvalert!("BUG: Can't find currently running capsule to restart");
-            return Err(Cause::CapsuleBadID);
-        }
-    };
+        Some(c) => Ok(c.get_dtb_placement()),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
-    restart(cid, vid)
+/* return the class of physical core the given capsule's vcores should prefer, if one was requested, or an error code */
+pub fn get_core_class(cid: CapsuleID) -> Result<Option<CoreClass>, Cause>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Ok(c.get_core_class()),
+        None => Err(Cause::CapsuleBadID)
+    }
 }
 
-/* return the given capsule's maximum number of virtual cores, identified by ID, or None for not found */
-pub fn get_max_vcores(cid: CapsuleID) -> Result<CPUcount, Cause>
+/* return the patterns of the service names the given capsule has been granted, or an error code */
+pub fn get_service_names(cid: CapsuleID) -> Result<service::NameRights, Cause>
 {
-    match CAPSULES.lock().entry(cid)
+    match CAPSULES.lock().get(&cid)
     {
-        Occupied(capsule) => Ok(capsule.get().get_max_vcores()),
-        Vacant(_) => Err(Cause::CapsuleBadID)
+        Some(c) => Ok(c.get_service_names()),
+        None => Err(Cause::CapsuleBadID)
     }
 }
 
@@ -523,7 +1897,7 @@ pub fn get_state(cid: CapsuleID) -> Option<CapsuleState>
 {
     match CAPSULES.lock().entry(cid)
     {
-        Occupied(capsule) => Some(capsule.get().state),
+        Occupied(capsule) => Some(capsule.get().get_state()),
         Vacant(_) => None
     }
 }
@@ -601,61 +1975,95 @@ pub fn is_service_allowed(cid: CapsuleID, stype: ServiceType) -> Result<bool, Ca
     }
 }
 
-/* write a character to the user as the currently running capsule.
+/* write a byte to the user as the currently running capsule.
    this will either be buffered and accessed later by the user interface
    to display to the user, or this is the user interface capsule
-   and we'll pass its output onto the hardware */
-pub fn putc(character: char) -> Result<(), Cause>
+   and we'll pass its output onto the hardware, rendered in its console encoding */
+pub fn putc(byte: u8) -> Result<(), Cause>
 {
-    let cid = match pcore::PhysicalCore::get_capsule_id()
+    match pcore::PhysicalCore::get_capsule_id()
     {
-        Some(c) => c,
-        None => return Err(Cause::CapsuleBadID)
-    };
+        Some(cid) => write_output(cid, byte),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
 
+/* write a byte to the user as the given capsule, as putc() does for the running capsule
+   => cid = capsule to write as
+      byte = byte to write
+   <= Ok for success, or an error code */
+fn write_output(cid: CapsuleID, byte: u8) -> Result<(), Cause>
+{
     /* find the capsule we're going to write into */
     match CAPSULES.lock().get_mut(&cid)
     {
         Some(capsule) =>
         {
-            /* if this capsule can write straight to the hardware, then use that */
+            /* if this capsule can write straight to the hardware, then take turns on it with
+               the hypervisor and other capsules. if the capsule's turns can't keep up, it must retry */
             if (*capsule).has_property(CapsuleProperty::ConsoleWrite)
             {
-                if hardware::write_debug_string(character.to_string().as_str()) == false
+                let text = console::render(cid, capsule.get_console_encoding(), byte);
+                if text.len() > 0 && arbiter::write(Source::Capsule(cid), text.as_str()) == false
                 {
-                    return Err(Cause::CapsuleBufferWriteFailed);
+                    return Err(Cause::CapsuleBufferFull);
                 }
             }
             else
             {
                 /* either add to the capsule's output buffer, or create a new buffer */
                 let mut stdout = STDOUT.lock();
-                match stdout.get_mut(&cid)
+                let buffer = stdout.entry(cid).or_insert_with(|| capsule.new_console_buffer());
+                let overflows = buffer.overflows();
+                let result = buffer.push(byte);
+                if buffer.overflows() != overflows
                 {
-                    Some(entry) => entry.push(character),
-                    None =>
-                    {
-                        let mut v = Vec::new();
-                        v.push(character);
-                        stdout.insert(cid, v);
-                    }
+                    metrics::set(cid, metrics::Counter::ConsoleOverflows, buffer.overflows());
                 }
+                return result;
             }
         },
         None => return Err(Cause::CapsuleBadID)
     }
 
+    /* write out the capsule's output, if it's the capsule's turn, now the capsule list is unlocked */
+    arbiter::pump();
     Ok(())
 }
 
-/* read a character from the user for the currently running capsule.
+/* append bytes to a capsule's output buffer for the user interface to display,
+   as if the capsule had written them itself, eg: to report why the capsule died.
+   if the capsule writes straight to the hardware, the bytes are dropped
+   => cid = capsule to write as
+      bytes = bytes to append */
+pub fn append_output(cid: CapsuleID, bytes: &[u8])
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(capsule) =>
+        {
+            if capsule.has_property(CapsuleProperty::ConsoleWrite) == true
+            {
+                return;
+            }
+
+            let mut stdout = STDOUT.lock();
+            let buffer = stdout.entry(cid).or_insert_with(|| capsule.new_console_buffer());
+            buffer.append(bytes);
+            metrics::set(cid, metrics::Counter::ConsoleOverflows, buffer.overflows());
+        },
+        None => ()
+    }
+}
+
+/* read a byte from the user for the currently running capsule.
    this will either read from the capsule's buffer that's filled
    by the user interface capsule, or this is the user interface
    capsule and we'll read the input from the hardware.
    this call does not block
-   <= returns read character or an error code
+   <= returns read byte or an error code
 */
-pub fn getc() -> Result<char, Cause>
+pub fn getc() -> Result<u8, Cause>
 {
     let cid = match pcore::PhysicalCore::get_capsule_id()
     {
@@ -663,6 +2071,32 @@ pub fn getc() -> Result<char, Cause>
         None => return Err(Cause::CapsuleBadID)
     };
 
+    if console::get_input_mode(cid) == console::InputMode::Raw
+    {
+        return read_input(cid);
+    }
+
+    /* in cooked mode, run the waiting input through the capsule's line editor,
+       echoing as it goes, until a whole line is ready or the input runs dry */
+    loop
+    {
+        if let Some(byte) = console::take_cooked(cid)
+        {
+            return Ok(byte);
+        }
+
+        for echo in console::cook(cid, read_input(cid)?)
+        {
+            write_output(cid, echo)?;
+        }
+    }
+}
+
+/* read a byte from the user for the given capsule, as getc() does for the running capsule, without any line editing
+   => cid = capsule to read for
+   <= returns read byte or an error code */
+fn read_input(cid: CapsuleID) -> Result<u8, Cause>
+{
     /* find the capsule we're trying to read from */
     match CAPSULES.lock().get_mut(&cid)
     {
@@ -671,9 +2105,10 @@ pub fn getc() -> Result<char, Cause>
             /* if this capsule can read direct from the hardware, then let it */
             if capsule.has_property(CapsuleProperty::ConsoleRead)
             {
+                /* the debug port delivers one byte at a time */
                 return match hardware::read_debug_char()
                 {
-                    Some(c) => Ok(c),
+                    Some(c) => Ok(c as u32 as u8),
                     None => Err(Cause::CapsuleBufferEmpty)
                 };
             }
@@ -696,10 +2131,26 @@ pub fn getc() -> Result<char, Cause>
     }
 }
 
-/* write the given character to the given capsule's input buffer.
+/* switch the currently running capsule's console input between raw and cooked
+   => mode = input mode to use from now on
+   <= Ok for success, or an error code */
+pub fn set_console_input_mode(mode: console::InputMode) -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(c) => c,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let encoding = get_console_encoding(cid)?;
+    console::set_input_mode(cid, mode, encoding);
+    Ok(())
+}
+
+/* write the given byte to the given capsule's input buffer.
     *** the currently running capsule must have the console_write property ***
 */
-pub fn console_putc(character: char, cid: CapsuleID) -> Result<(), Cause>
+pub fn console_putc(byte: u8, cid: CapsuleID) -> Result<(), Cause>
 {
     current_has_property(CapsuleProperty::ConsoleWrite)?;
 
@@ -708,15 +2159,15 @@ pub fn console_putc(character: char, cid: CapsuleID) -> Result<(), Cause>
     {
         Occupied(_) =>
         {
-            /* insert character into capsule's stdin buffer */
+            /* insert byte into capsule's stdin buffer */
             let mut stdin = STDIN.lock();
             match stdin.entry(cid)
             {
-                Occupied(mut array) => array.get_mut().push(character),
+                Occupied(mut array) => array.get_mut().push(byte),
                 Vacant(fresh) =>
                 {
                     let mut array = Vec::new();
-                    array.push(character);
+                    array.push(byte);
                     fresh.insert(array);
                 }
             }
@@ -726,25 +2177,46 @@ pub fn console_putc(character: char, cid: CapsuleID) -> Result<(), Cause>
     }
 }
 
-/* get the next available character from the capsules' output buffers
+/* get the next available byte from the capsules' output buffers.
+   use get_console_encoding() to find out how to interpret each capsule's bytes
    *** the currently running capsule must have the console_read property ***
-   <= the capsule ID and character read from its buffer, or an error
+   <= the capsule ID and byte read from its buffer, or an error
 */
-pub fn console_getc() -> Result<(char, CapsuleID), Cause>
+pub fn console_getc() -> Result<(u8, CapsuleID), Cause>
 {
     current_has_property(CapsuleProperty::ConsoleRead)?;
 
-    /* loop through capsule IDs in stdout hast table in search of a character */
-    for (cid, array) in STDOUT.lock().iter_mut()
+    /* loop through capsule IDs in stdout hast table in search of a byte */
+    for (cid, buffer) in STDOUT.lock().iter_mut()
     {
-        if array.len() > 0
+        if let Some(byte) = buffer.pop()
         {
-            return Ok((array.remove(0), *cid));
+            return Ok((byte, *cid));
         }
     }
     Err(Cause::CapsuleBufferEmpty)
 }
 
+/* return how many bytes of the given capsule's console output have been dropped or refused
+   because its buffer was full, so that the console service can show the user
+   *** the currently running capsule must have the console_read property ***
+   => cid = capsule to check
+   <= number of bytes lost, or an error */
+pub fn console_overflows(cid: CapsuleID) -> Result<u64, Cause>
+{
+    current_has_property(CapsuleProperty::ConsoleRead)?;
+    if get_state(cid).is_none()
+    {
+        return Err(Cause::CapsuleBadID);
+    }
+
+    Ok(match STDOUT.lock().get(&cid)
+    {
+        Some(buffer) => buffer.overflows(),
+        None => 0
+    })
+}
+
 /* return a character from the hypervisor's log output, or an error.
    *** the currently running capsule must have the hv_log_read property *** */
 pub fn hypervisor_getc() -> Result<char, Cause>
@@ -757,6 +2229,70 @@ pub fn hypervisor_getc() -> Result<char, Cause>
     }
 }
 
+/* allocate a block of DMA-safe physical memory and grant it to a capsule.
+   the block is identity mapped and freed when the capsule is destroyed
+   => cid = ID of capsule to receive the memory
+      size = number of bytes required
+   <= physical base address of the block, or an error code */
+pub fn grant_dma_memory(cid: CapsuleID, size: PhysMemSize) -> Result<PhysMemBase, Cause>
+{
+    let region = physmem::alloc_dma_region(size)?;
+    let mut mapping = Mapping::new();
+    mapping.set_physical(region);
+    mapping.identity_mapping()?;
+
+    if let Err(e) = map_memory(cid, mapping)
+    {
+        physmem::dealloc_region(region)?;
+        return Err(e);
+    }
+
+    /* let the capsule know about its new memory */
+    if let Err(_e) = virtdt::regenerate(cid)
+    {
+        hvdebug!("Couldn't republish device tree for capsule {} after granting DMA memory: {:?}", cid, _e);
+    }
+
+    Ok(region.base())
+}
+
+/* describe a capsule's memory for checking the buffers it passes in hypercalls. see hcargs.rs
+   => cid = ID of capsule to describe
+   <= the capsule's memory windows and the parts of them it can't write to, or an error code */
+pub fn get_address_space(cid: CapsuleID) -> Result<hcargs::Space, Cause>
+{
+    let (mut windows, mut read_only) = (Vec::new(), Vec::new());
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => for mapping in c.get_memory_mappings()
+        {
+            let region = match mapping.get_physical()
+            {
+                Some(region) => region,
+                None => continue
+            };
+
+            if let Some(base) = mapping.physical_to_virtual(region.base())
+            {
+                windows.push(Window { base, size: region.size(), host: region.base() });
+            }
+
+            for (seg_base, seg_size, permissions) in mapping.get_physical_segments()
+            {
+                match (permissions, mapping.physical_to_virtual(seg_base))
+                {
+                    (AccessPermissions::ReadExecute, Some(base)) | (AccessPermissions::Read, Some(base)) =>
+                        read_only.push((base, seg_size)),
+                    (_, _) => ()
+                }
+            }
+        },
+        None => return Err(Cause::CapsuleBadID)
+    }
+
+    Ok(hcargs::Space::new(windows, read_only))
+}
+
 /* add a memory mapping to a capsule
    cid = ID of capsule to add the mapping to
    to_map = memory mapping object to add
@@ -764,14 +2300,73 @@ pub fn hypervisor_getc() -> Result<char, Cause>
 */
 pub fn map_memory(cid: CapsuleID, to_map: Mapping) -> Result<(), Cause>
 {
-    if let Occupied(mut c) = CAPSULES.lock().entry(cid)
+    let ranges: Vec<(PhysMemBase, PhysMemSize)> = if let Occupied(mut c) = CAPSULES.lock().entry(cid)
     {
         c.get_mut().set_memory_mapping(to_map);
-        return Ok(());
+        c.get().get_memory_mappings().iter()
+            .filter_map(|mapping| mapping.get_physical())
+            .map(|region| (region.base(), region.size()))
+            .collect()
     }
     else
     {
         return Err(Cause::CapsuleBadID);
+    };
+
+    /* let the capsule's passed-through devices DMA into its new memory, and no further */
+    passthrough::confine_dma(cid, &ranges)
+}
+
+/* give parts of a capsule's main RAM their own access permissions, such as to make its code read-only.
+   they take effect the next time the capsule's protection is enforced
+   => cid = capsule to update
+      segments = list of offset into the RAM, size in bytes, and permissions of each part,
+                 or an empty list to make all of the RAM readable, writeable, and executable
+   <= Ok for success, or an error code */
+pub fn set_code_segments(cid: CapsuleID, segments: &[(PhysMemSize, PhysMemSize, AccessPermissions)]) -> Result<(), Cause>
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) => match c.memory.first_mut()
+        {
+            Some(mapping) => mapping.set_segments(segments),
+            None => Err(Cause::VirtMemPhysNotSet)
+        },
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* <= true if the given physical address is in a part of the capsule's main RAM it can't write to, such as its code */
+pub fn is_read_only(cid: CapsuleID, addr: PhysMemBase) -> bool
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => match c.memory.first()
+        {
+            Some(mapping) => mapping.get_physical_segments().iter().any(|(base, size, permissions)|
+                addr >= *base && addr < *base + *size && matches!(permissions, AccessPermissions::ReadExecute)),
+            None => false
+        },
+        None => false
+    }
+}
+
+/* have every physical core running the given capsule reapply its protection windows,
+   so that memory it has gained or lost access to, such as grants, takes effect straight away */
+pub fn reenforce(cid: CapsuleID)
+{
+    if pcore::PhysicalCore::get_capsule_id() == Some(cid)
+    {
+        enforce(cid);
+    }
+
+    match message::Message::new(message::Recipient::send_to_all(), message::MessageContent::Reenforce(cid))
+    {
+        Ok(msg) => if let Err(_e) = message::send(msg)
+        {
+            hvalert!("Failed to tell physical cores to reprotect capsule {}: {:?}", cid, _e);
+        },
+        Err(_e) => hvalert!("Failed to create message to reprotect capsule {}: {:?}", cid, _e)
     }
 }
 
@@ -785,8 +2380,12 @@ pub fn map_memory(cid: CapsuleID, to_map: Mapping) -> Result<(), Cause>
 */
 pub fn enforce(id: CapsuleID) -> bool
 {
-    /* this is a filthy hardcode hack that I hate but it's needed for now */
+    /* the first mapping is the capsule's main RAM. any others, such as DMA-safe
+       buffers and RAM that had to be allocated in pieces, are granted through
+       extra protection windows */
     let mut index = 0;
+    let mut window = FIRST_EXTRA_WINDOW;
+    let mut wx = false;
 
     match CAPSULES.lock().entry(id)
     {
@@ -798,15 +2397,51 @@ pub fn enforce(id: CapsuleID) -> bool
                 {
                     if index == 0
                     {
-                        r.grant_access();
+                        /* with W^X, the RAM is writeable but not executable, save for the image's code */
+                        let segments = mapping.get_physical_segments();
+                        wx = segments.len() > 0;
+                        match wx
+                        {
+                            false => r.grant_access(AccessPermissions::ReadWriteExecute),
+                            true => r.grant_access(AccessPermissions::ReadWrite)
+                        }
+                        for (base, size, permissions) in segments
+                        {
+                            machine::protect_window(window, base, base + size, permissions);
+                            window = window + 1;
+                        }
                     }
                     else
                     {
-                        hvalert!("BUG: Capsule {} has more than one physical RAM region", id);
+                        /* scattered pieces of the capsule's RAM are executable like the rest of it, unless it has W^X */
+                        match mapping.is_scattered() && wx == false
+                        {
+                            true => r.grant_window_access(window, AccessPermissions::ReadWriteExecute),
+                            false => r.grant_window_access(window, AccessPermissions::ReadWrite)
+                        }
+                        window = window + 1;
                     }
                     index = index + 1;
                 }
             }
+
+            /* open up any passed-through devices' MMIO spaces */
+            let window = passthrough::enforce(id, window);
+
+            /* and any memory other capsules have granted it */
+            let window = grant::enforce(id, window);
+
+            /* and any MMIO ranges it has mapped directly */
+            let window = mmio::enforce(id, window);
+
+            /* and write-protect the clean parts of its RAM if its changes are being tracked */
+            let window = dirty::enforce(id, window, wx == false);
+
+            /* and close off any pages sampled to estimate its working set */
+            wss::enforce(id, window);
+
+            /* and apply its cache and memory bandwidth partition */
+            qos::enforce(id);
             return true
         },
         _ => false
@@ -0,0 +1,229 @@
+/* diosix RISC-V capacity and bandwidth QoS register interface (CBQRI) controllers
+ *
+ * Last-level caches and memory controllers that implement CBQRI let
+ * software limit which cache capacity blocks each resource control ID
+ * (RCID) may allocate into, and reserve memory bandwidth for each RCID.
+ * The controllers are found in the host's device tree and driven here.
+ * CPU cores tag their memory accesses with an RCID held in the srmcfg
+ * register, provided by the Ssqosid extension.
+ *
+ * Every controller of a kind is programmed alike. The capacity blocks
+ * and bandwidth units on offer are those of the first controller of each
+ * kind found. A host without controllers, or whose cores lack Ssqosid,
+ * can't partition its cache and bandwidth, and tagging cores is skipped.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use super::lock::Mutex;
+use hvalgo::fdt::{self, Fdt};
+
+/* compatible strings of the controllers this code can drive */
+pub const CAPACITY_COMPATIBLE: [&str; 2] = [ "riscv,cbqri-capacity", "riscv,cbqri-cache" ];
+pub const BANDWIDTH_COMPATIBLE: [&str; 1] = [ "riscv,cbqri-bandwidth" ];
+
+/* register layout common to both kinds of controller, as offsets from its base address */
+const REG_CAPABILITIES: usize = 0x00;
+const REG_ALLOC_CTL: usize = 0x18;
+
+/* capacity controller's bitmask of blocks an RCID may allocate into, one bit per block */
+const REG_CC_BLOCK_MASK: usize = 0x20;
+
+/* bandwidth controller's reservation and share of unreserved bandwidth for an RCID */
+const REG_BC_BW_ALLOC: usize = 0x20;
+
+/* fields of the capabilities registers */
+const CAP_BLOCKS_SHIFT: usize = 8;      /* capacity blocks, or bandwidth blocks */
+const CAP_BLOCKS_MASK: u64 = 0xffff;
+const CAP_MRBWB_SHIFT: usize = 32;      /* most bandwidth blocks that can be reserved */
+const CAP_MRBWB_MASK: u64 = 0xffff;
+
+/* fields of the allocation control registers */
+const ALLOC_OP_CONFIG_LIMIT: u64 = 1;
+const ALLOC_RCID_SHIFT: usize = 8;
+const ALLOC_RCID_MASK: u64 = 0xfff;
+const ALLOC_STATUS_SHIFT: usize = 32;
+const ALLOC_STATUS_MASK: u64 = 0x7f;
+const ALLOC_STATUS_SUCCESS: u64 = 1;
+const ALLOC_BUSY: u64 = 1 << 39;
+
+/* fields of the bandwidth allocation register. equal weights share out unreserved bandwidth evenly */
+const BW_ALLOC_RBWB_MASK: u64 = 0xffff;
+const BW_ALLOC_MWEIGHT_SHIFT: usize = 20;
+const BW_ALLOC_MWEIGHT: u64 = 1;
+
+/* times to check a controller has finished an operation before giving up on it */
+const BUSY_POLLS_MAX: usize = 100000;
+
+/* the srmcfg CSR, holding the RCID in its low 12 bits */
+const CSR_SRMCFG_RCID_MASK: usize = 0xfff;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind
+{
+    Capacity,
+    Bandwidth
+}
+
+#[derive(Clone, Copy)]
+struct Controller
+{
+    kind: Kind,
+    base: usize,
+    blocks: usize,      /* capacity blocks, or bandwidth blocks that can be reserved */
+    mask_words: usize   /* 64-bit words in a capacity controller's block mask */
+}
+
+impl Controller
+{
+    fn read(&self, offset: usize) -> u64
+    {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u64) }
+    }
+
+    fn write(&self, offset: usize, value: u64)
+    {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u64, value) }
+    }
+
+    /* apply the limit staged in the controller's registers to the given RCID
+       <= true for success, or false if the controller refused or didn't finish */
+    fn config_limit(&self, rcid: usize) -> bool
+    {
+        self.write(REG_ALLOC_CTL, ALLOC_OP_CONFIG_LIMIT | ((rcid as u64 & ALLOC_RCID_MASK) << ALLOC_RCID_SHIFT));
+        for _ in 0..BUSY_POLLS_MAX
+        {
+            let ctl = self.read(REG_ALLOC_CTL);
+            if ctl & ALLOC_BUSY == 0
+            {
+                return (ctl >> ALLOC_STATUS_SHIFT) & ALLOC_STATUS_MASK == ALLOC_STATUS_SUCCESS;
+            }
+        }
+        false
+    }
+}
+
+lazy_static!
+{
+    /* the host's capacity and bandwidth controllers */
+    static ref CONTROLLERS: Mutex<Vec<Controller>> = Mutex::new("CBQRI controllers", Vec::new());
+}
+
+/* set if every CPU core has the srmcfg register */
+static SSQOSID: AtomicBool = AtomicBool::new(false);
+
+/* find the host's capacity and bandwidth controllers, and check its CPU cores can tag
+   their accesses, in the host's device tree. call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let mut controllers = Vec::new();
+    for node in fdt.nodes().filter(|n| n.is_enabled())
+    {
+        let kind = if CAPACITY_COMPATIBLE.iter().any(|c| node.is_compatible(c))
+        {
+            Kind::Capacity
+        }
+        else if BANDWIDTH_COMPATIBLE.iter().any(|c| node.is_compatible(c))
+        {
+            Kind::Bandwidth
+        }
+        else
+        {
+            continue;
+        };
+
+        if let Some((base, _)) = node.reg().ok().and_then(|mut reg| reg.next())
+        {
+            let mut controller = Controller { kind, base: base as usize, blocks: 0, mask_words: 0 };
+            let capabilities = controller.read(REG_CAPABILITIES);
+            let blocks = ((capabilities >> CAP_BLOCKS_SHIFT) & CAP_BLOCKS_MASK) as usize;
+            match kind
+            {
+                Kind::Capacity =>
+                {
+                    controller.blocks = blocks;
+                    controller.mask_words = (blocks + 63) / 64;
+                },
+                Kind::Bandwidth => controller.blocks = ((capabilities >> CAP_MRBWB_SHIFT) & CAP_MRBWB_MASK) as usize
+            }
+            controllers.push(controller);
+        }
+    }
+
+    let cpus: Vec<bool> = fdt.nodes()
+        .filter(|n| n.depth() == 2 && n.unit_name() == "cpu" && n.is_enabled())
+        .map(|n| fdt::cpu_has_extension(&n, "ssqosid")).collect();
+    let tagging = cpus.len() > 0 && cpus.iter().all(|has| *has == true);
+    SSQOSID.store(tagging, Ordering::SeqCst);
+
+    hvdebug!("Found {} CBQRI controllers, CPU cores {} tag accesses", controllers.len(), if tagging { "can" } else { "can't" });
+    *(CONTROLLERS.lock()) = controllers;
+}
+
+/* <= the number of cache capacity blocks and reservable bandwidth units on offer,
+      or None if the host can't partition its cache and bandwidth */
+pub fn resources() -> Option<(usize, usize)>
+{
+    let controllers = CONTROLLERS.lock();
+    if controllers.len() == 0 || SSQOSID.load(Ordering::SeqCst) == false
+    {
+        return None;
+    }
+
+    let first = |kind| controllers.iter().find(|c| c.kind == kind).map(|c| c.blocks).unwrap_or(0);
+    Some((first(Kind::Capacity), first(Kind::Bandwidth)))
+}
+
+/* limit an RCID to the given cache capacity blocks and reserve it the given bandwidth on every controller
+   => rcid = resource control ID to configure
+      cache_mask = bitmask of cache capacity blocks the ID may use. 0 leaves its cache limit unchanged
+      bandwidth = bandwidth units reserved for the ID
+   <= true for success, or false if a controller refused */
+pub fn set_allocation(rcid: usize, cache_mask: usize, bandwidth: usize) -> bool
+{
+    let mut success = true;
+    for controller in CONTROLLERS.lock().iter()
+    {
+        match controller.kind
+        {
+            Kind::Capacity => if cache_mask != 0
+            {
+                /* only the first word of the mask is used: a capsule can be given up to 64 blocks */
+                for word in 0..controller.mask_words
+                {
+                    let value = if word == 0 { cache_mask as u64 } else { 0 };
+                    controller.write(REG_CC_BLOCK_MASK + (word * 8), value);
+                }
+                if controller.config_limit(rcid) == false
+                {
+                    success = false;
+                }
+            },
+            Kind::Bandwidth =>
+            {
+                controller.write(REG_BC_BW_ALLOC, (bandwidth as u64 & BW_ALLOC_RBWB_MASK) | (BW_ALLOC_MWEIGHT << BW_ALLOC_MWEIGHT_SHIFT));
+                if controller.config_limit(rcid) == false
+                {
+                    success = false;
+                }
+            }
+        }
+    }
+    success
+}
+
+/* tag this physical CPU core's memory accesses with the given RCID, if it can
+   => rcid = resource control ID to use */
+pub fn set_qos_id(rcid: usize)
+{
+    if SSQOSID.load(Ordering::SeqCst) == true
+    {
+        unsafe { asm!("csrw 0x181, {0}", in(reg) rcid & CSR_SRMCFG_RCID_MASK) };
+    }
+}
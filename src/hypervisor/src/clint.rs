@@ -0,0 +1,137 @@
+/* diosix RISC-V machine software interrupts
+ *
+ * Physical cores interrupt each other to check their mailboxes by
+ * raising machine software interrupts. These are raised by writing 1
+ * to the target core's msip register in the host's CLINT, or in an
+ * ACLINT MSWI device, which has the same layout, and cleared by the
+ * target writing 0 to it. A host may have more than one ACLINT MSWI
+ * device, each serving a group of cores.
+ *
+ * As with the PLIC, each device's msip registers are numbered by the
+ * order of the pairs in its interrupts-extended property. A machine
+ * software interrupt is number 3.
+ *
+ * Hosts with an ACLINT SSWI device can also raise supervisor software
+ * interrupts on other cores, for the virtual cores they're running,
+ * without interrupting the hypervisor there. Writing 1 to the target
+ * core's setssip register sets its supervisor software interrupt
+ * pending bit, which the target clears itself. Its setssip registers
+ * are laid out like msip registers, and numbered by the pairs that
+ * name supervisor software interrupt number 1.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use alloc::vec::Vec;
+use super::lock::Mutex;
+use hvalgo::fdt::Fdt;
+use super::pcore::{PhysicalCore, HartID};
+
+/* compatible strings of the devices this code can drive */
+pub const COMPATIBLE: [&str; 3] = [ "riscv,clint0", "sifive,clint0", "riscv,aclint-mswi" ];
+
+/* compatible string of the devices that raise supervisor software interrupts */
+pub const SSWI_COMPATIBLE: &str = "riscv,aclint-sswi";
+
+/* interrupt numbers of software interrupts in a core's local interrupt controller */
+const SUPERVISOR_SOFT: u32 = 1;
+const MACHINE_SOFT: u32 = 3;
+
+/* size of each core's msip and setssip register */
+const MSIP_STRIDE: usize = 4;
+
+lazy_static!
+{
+    /* address of the msip register of each core's hardware ID */
+    static ref MSIP: Mutex<Vec<(HartID, usize)>> = Mutex::new("machine software interrupt registers", Vec::new());
+
+    /* address of the setssip register of each core's hardware ID, on hosts with an SSWI device */
+    static ref SETSSIP: Mutex<Vec<(HartID, usize)>> = Mutex::new("supervisor software interrupt registers", Vec::new());
+}
+
+/* <= the per-core registers of every enabled device compatible with any of the given strings,
+   numbered by the pairs naming the given interrupt in their interrupts-extended properties
+   => fdt = host's device tree */
+fn find_registers(fdt: &Fdt, compatible: &[&str], irq: u32) -> Vec<(HartID, usize)>
+{
+    let mut registers = Vec::new();
+    for node in fdt.nodes().filter(|n| n.is_enabled() && compatible.iter().any(|c| n.is_compatible(c)))
+    {
+        if let Some((base, _)) = node.reg().ok().and_then(|mut reg| reg.next())
+        {
+            for (hart, context) in fdt.hart_contexts(&node, irq)
+            {
+                registers.push((hart as HartID, base as usize + (context * MSIP_STRIDE)));
+            }
+        }
+    }
+    registers
+}
+
+/* find the msip and any setssip registers of the host's cores in the host's device tree.
+   call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let registers = find_registers(fdt, &COMPATIBLE, MACHINE_SOFT);
+    hvdebug!("Found machine software interrupt registers for {} physical cores", registers.len());
+    *(MSIP.lock()) = registers;
+
+    let registers = find_registers(fdt, &[ SSWI_COMPATIBLE ], SUPERVISOR_SOFT);
+    if registers.len() > 0
+    {
+        hvdebug!("Found supervisor software interrupt registers for {} physical cores", registers.len());
+    }
+    *(SETSSIP.lock()) = registers;
+}
+
+/* <= address of the given core's msip register, if it has one */
+fn msip_of(hart: HartID) -> Option<usize>
+{
+    MSIP.lock().iter().find(|(h, _)| *h == hart).map(|(_, addr)| *addr)
+}
+
+/* raise a machine software interrupt on the given core
+   => hart = hardware ID of the core to interrupt
+   <= true if the interrupt was raised, or false if the core has no msip register */
+pub fn interrupt(hart: HartID) -> bool
+{
+    match msip_of(hart)
+    {
+        Some(addr) =>
+        {
+            unsafe { ptr::write_volatile(addr as *mut u32, 1) };
+            true
+        },
+        None => false
+    }
+}
+
+/* raise a supervisor software interrupt on the given core, for whatever it's running in supervisor mode
+   => hart = hardware ID of the core to interrupt
+   <= true if the interrupt was raised, or false if the core has no setssip register */
+pub fn interrupt_supervisor(hart: HartID) -> bool
+{
+    match SETSSIP.lock().iter().find(|(h, _)| *h == hart).map(|(_, addr)| *addr)
+    {
+        Some(addr) =>
+        {
+            unsafe { ptr::write_volatile(addr as *mut u32, 1) };
+            true
+        },
+        None => false
+    }
+}
+
+/* clear this physical core's machine software interrupt. call before checking the mailbox,
+   so that a message sent while the mailbox is being processed raises the interrupt again */
+pub fn acknowledge()
+{
+    if let Some(addr) = msip_of(PhysicalCore::get_hart_id())
+    {
+        unsafe { ptr::write_volatile(addr as *mut u32, 0) };
+    }
+}
@@ -0,0 +1,153 @@
+/* diosix time service for capsules
+ *
+ * Give capsules a monotonic nanosecond counter and, if the host has
+ * a real-time clock, the wall-clock time, so that guests without RTC
+ * drivers can still timestamp their logs sensibly.
+ *
+ * The monotonic counter is derived from the scheduler's timer and
+ * starts from zero when the host powers up. The RTC is only read once,
+ * during boot, to find the wall-clock time at a given timer value. The
+ * wall-clock time is thereafter calculated from the timer, which is
+ * quicker and cheaper than reading the RTC on every request.
+ *
+ * On hosts without an RTC, a capsule's manifest can give it a starting
+ * wall-clock time with the rtc_epoch property. The capsule's clock then
+ * reads that time when the capsule is created, and runs on from there
+ * with the monotonic counter.
+ *
+ * Each capsule can adjust its view of the wall-clock time with an offset,
+ * such as to follow a time zone or correct for drift from a network time
+ * source, without affecting other capsules. The offset survives restarts
+ * and is discarded when the capsule is destroyed.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::hardware;
+use super::scheduler;
+
+const NANOSECONDS_PER_SECOND: u128 = 1000000000;
+
+/* wall-clock time and monotonic counter values read together during boot */
+#[derive(Clone, Copy)]
+struct Epoch
+{
+    wall: u64,      /* nanoseconds since 1970-01-01 00:00:00 UTC */
+    monotonic: u64  /* monotonic counter at the same moment */
+}
+
+lazy_static!
+{
+    static ref EPOCH: Mutex<Option<Epoch>> = Mutex::new("wall-clock epoch", None);
+    static ref EPOCHS: Mutex<HashMap<CapsuleID, Epoch>> = Mutex::new("capsule wall-clock epochs", HashMap::new());
+    static ref OFFSETS: Mutex<HashMap<CapsuleID, i64>> = Mutex::new("capsule wall-clock offsets", HashMap::new());
+}
+
+/* read the host's real-time clock, if it has one, to start keeping wall-clock time.
+   call on the boot core once the hardware has been discovered */
+pub fn init()
+{
+    let (wall, monotonic) = match (hardware::get_wall_clock(), monotonic())
+    {
+        (Some(wall), Ok(monotonic)) => (wall, monotonic),
+        (_, _) => return
+    };
+
+    *(EPOCH.lock()) = Some(Epoch { wall, monotonic });
+    hvdebug!("Wall-clock time is {} seconds since the Unix epoch", wall / NANOSECONDS_PER_SECOND as u64);
+}
+
+/* start a capsule's wall-clock time from the given time, if the host has no RTC. call when the capsule
+   is created. the time keeps running across restarts, so later calls for the same capsule are ignored
+   => cid = ID of the capsule
+      seconds = seconds since 1970-01-01 00:00:00 UTC the capsule's clock reads now
+   <= Ok for success, or an error code if there's no timer */
+pub fn set_epoch(cid: CapsuleID, seconds: u64) -> Result<(), Cause>
+{
+    if EPOCH.lock().is_some()
+    {
+        return Ok(());
+    }
+
+    let epoch = Epoch { wall: seconds.saturating_mul(NANOSECONDS_PER_SECOND as u64), monotonic: monotonic()? };
+    EPOCHS.lock().entry(cid).or_insert(epoch);
+    Ok(())
+}
+
+/* <= the epoch the given capsule's wall-clock time is calculated from, if it has one */
+fn get_epoch(cid: CapsuleID) -> Option<Epoch>
+{
+    match *(EPOCH.lock())
+    {
+        Some(e) => Some(e),
+        None => EPOCHS.lock().get(&cid).copied()
+    }
+}
+
+/* <= true if wall-clock time is available to the given capsule */
+pub fn has_wall_clock(cid: CapsuleID) -> bool
+{
+    get_epoch(cid).is_some()
+}
+
+/* <= nanoseconds since the host powered up, or an error code if there's no timer */
+pub fn monotonic() -> Result<u64, Cause>
+{
+    match scheduler::timer_now()
+    {
+        Some((now, frequency)) => Ok(((now as u128 * NANOSECONDS_PER_SECOND) / frequency as u128) as u64),
+        None => Err(Cause::ClockNoTimer)
+    }
+}
+
+/* return the wall-clock time as seen by the given capsule, including its offset
+   => cid = ID of the capsule asking
+   <= nanoseconds since 1970-01-01 00:00:00 UTC, or an error code */
+pub fn wall_clock(cid: CapsuleID) -> Result<u64, Cause>
+{
+    let epoch = match get_epoch(cid)
+    {
+        Some(e) => e,
+        None => return Err(Cause::ClockNoWallClock)
+    };
+
+    let host = epoch.wall + monotonic()?.saturating_sub(epoch.monotonic);
+    let offset = *(OFFSETS.lock().get(&cid).unwrap_or(&0));
+    match offset < 0
+    {
+        true => Ok(host.saturating_sub((-(offset as i128)) as u64)),
+        false => Ok(host.saturating_add(offset as u64))
+    }
+}
+
+/* set the offset the given capsule adds to the host's wall-clock time
+   => cid = ID of the capsule
+      offset = signed number of nanoseconds to add
+   <= Ok for success, or an error code */
+pub fn set_offset(cid: CapsuleID, offset: i64) -> Result<(), Cause>
+{
+    if has_wall_clock(cid) == false
+    {
+        return Err(Cause::ClockNoWallClock);
+    }
+
+    match offset
+    {
+        0 => OFFSETS.lock().remove(&cid),
+        _ => OFFSETS.lock().insert(cid, offset)
+    };
+    Ok(())
+}
+
+/* discard a capsule's offset and epoch when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    OFFSETS.lock().remove(&cid);
+    EPOCHS.lock().remove(&cid);
+}
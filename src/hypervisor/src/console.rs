@@ -0,0 +1,418 @@
+/* diosix capsule console text encoding and line editing
+ *
+ * Capsules write their console output as a stream of raw bytes, which
+ * the hypervisor buffers as-is for the console service. Each capsule's
+ * stream is expected to be in the encoding given by its
+ * console_encoding property: utf8, the default, or raw.
+ *
+ * When a capsule allowed to write straight to the host's debug port does
+ * so, its UTF-8 output is validated as it goes: multi-byte characters
+ * split across writes are held until they're complete, and malformed
+ * sequences are replaced with U+FFFD. Raw output is written byte for
+ * byte, with non-ASCII bytes shown as Latin-1. The console service can look up each capsule's encoding
+ * so that it can render the capsule's buffered output correctly.
+ *
+ * Capsules normally read their console input a byte at a time, as it
+ * arrives. Tiny guests without a terminal stack can instead switch their
+ * input into cooked mode, in which the hypervisor echoes what's typed,
+ * handles backspace, and only hands over input a whole line at a time,
+ * once return is pressed. This is enough for a simple supervisor binary
+ * to prompt the user and read a reply.
+ *
+ * Buffered output is capped for each capsule, at 64KiB unless its
+ * console_buffer property says otherwise, so that a chatty capsule
+ * can't eat the hypervisor's heap while the console service is slow to
+ * drain it, or isn't running. Its console_overflow property picks what
+ * happens when the buffer is full: the oldest output is discarded, the
+ * default; the new output is discarded; or the capsule's write is
+ * refused with a retry error so it can try again once there's room.
+ * Bytes lost or refused are counted for the console service to show.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::collections::vec_deque::VecDeque;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+
+/* how a capsule's console bytes should be interpreted, how its input is delivered,
+   and what happens when its output overflows. the values are shared with the capsules and services */
+pub use hypercall::console::{Encoding, InputMode, Overflow, OUTPUT_BUFFER_DEFAULT};
+
+/* longest line, in bytes, that can be typed in cooked mode */
+const COOKED_LINE_MAX: usize = 256;
+
+/* bytes echoed to rub out the last character typed */
+const ECHO_RUBOUT: &[u8] = b"\x08 \x08";
+
+/* character written in place of malformed UTF-8 */
+const REPLACEMENT_CHAR: char = '\u{fffd}';
+
+/* reassemble UTF-8 characters from a stream of bytes */
+pub struct UTF8Decoder
+{
+    pending: [u8; 4],   /* bytes of the character being assembled */
+    have: usize,        /* number of bytes assembled so far */
+    need: usize         /* total bytes in the character, or 0 if not assembling one */
+}
+
+impl UTF8Decoder
+{
+    pub fn new() -> UTF8Decoder
+    {
+        UTF8Decoder { pending: [0; 4], have: 0, need: 0 }
+    }
+
+    /* add a byte to the stream, appending any completed or malformed character to out */
+    pub fn feed(&mut self, byte: u8, out: &mut String)
+    {
+        if self.need > 0
+        {
+            if byte & 0xc0 == 0x80
+            {
+                self.pending[self.have] = byte;
+                self.have = self.have + 1;
+                if self.have == self.need
+                {
+                    /* catch overlong encodings, surrogates, and values beyond U+10FFFF */
+                    match core::str::from_utf8(&self.pending[..self.need])
+                    {
+                        Ok(s) => out.push_str(s),
+                        Err(_) => out.push(REPLACEMENT_CHAR)
+                    }
+                    self.need = 0;
+                }
+                return;
+            }
+
+            /* the character was cut short. give up on it and start afresh with this byte */
+            out.push(REPLACEMENT_CHAR);
+            self.need = 0;
+        }
+
+        let need = match byte
+        {
+            0x00..=0x7f =>
+            {
+                out.push(byte as char);
+                return;
+            },
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ =>
+            {
+                out.push(REPLACEMENT_CHAR);
+                return;
+            }
+        };
+
+        self.pending[0] = byte;
+        self.have = 1;
+        self.need = need;
+    }
+}
+
+/* edit a cooked-mode capsule's input a line at a time */
+pub struct LineEditor
+{
+    encoding: Encoding,     /* so that backspace can remove whole UTF-8 characters */
+    line: Vec<u8>,          /* line being typed */
+    ready: VecDeque<u8>,    /* completed lines waiting to be read */
+    after_cr: bool          /* true if the last byte was a carriage return */
+}
+
+impl LineEditor
+{
+    pub fn new(encoding: Encoding) -> LineEditor
+    {
+        LineEditor { encoding, line: Vec::new(), ready: VecDeque::new(), after_cr: false }
+    }
+
+    /* add a byte of raw input, appending whatever should be echoed back to out.
+       return or newline completes the line, backspace or delete removes the last
+       character, and other control characters are ignored */
+    pub fn feed(&mut self, byte: u8, out: &mut Vec<u8>)
+    {
+        let after_cr = self.after_cr;
+        self.after_cr = byte == b'\r';
+
+        match byte
+        {
+            /* treat CR LF from the terminal as a single end of line */
+            b'\n' if after_cr => (),
+            b'\r' | b'\n' =>
+            {
+                self.ready.extend(self.line.drain(..));
+                self.ready.push_back(b'\n');
+                out.extend_from_slice(b"\r\n");
+            },
+            0x08 | 0x7f => if self.line.len() > 0
+            {
+                /* don't leave part of a multi-byte character behind */
+                while let Some(removed) = self.line.pop()
+                {
+                    if self.encoding == Encoding::Raw || removed & 0xc0 != 0x80
+                    {
+                        break;
+                    }
+                }
+                out.extend_from_slice(ECHO_RUBOUT);
+            },
+            b'\t' | 0x20..=0x7e | 0x80..=0xff => if self.line.len() < COOKED_LINE_MAX
+            {
+                self.line.push(byte);
+                out.push(byte);
+            }
+            else
+            {
+                out.push(0x07); /* ring the bell when the line is full */
+            },
+            _ => ()
+        }
+    }
+
+    /* <= next byte of a completed line, or None if no line is ready */
+    pub fn take(&mut self) -> Option<u8>
+    {
+        self.ready.pop_front()
+    }
+}
+
+/* a capsule's console output waiting for the console service */
+pub struct OutputBuffer
+{
+    bytes: VecDeque<u8>,
+    limit: usize,       /* most bytes held at once */
+    policy: Overflow,   /* what to do when a byte is written to a full buffer */
+    overflows: u64      /* bytes dropped or refused because the buffer was full */
+}
+
+impl OutputBuffer
+{
+    pub fn new(limit: usize, policy: Overflow) -> OutputBuffer
+    {
+        OutputBuffer { bytes: VecDeque::new(), limit, policy, overflows: 0 }
+    }
+
+    /* add a byte written by the capsule, applying its overflow policy if the buffer is full
+       <= Ok if the byte was buffered or dropped by policy, or CapsuleBufferFull if it was refused */
+    pub fn push(&mut self, byte: u8) -> Result<(), Cause>
+    {
+        if self.bytes.len() >= self.limit
+        {
+            self.overflows = self.overflows.wrapping_add(1);
+            match self.policy
+            {
+                Overflow::DropOldest => { self.bytes.pop_front(); },
+                Overflow::DropNewest => return Ok(()),
+                Overflow::Block => return Err(Cause::CapsuleBufferFull)
+            }
+        }
+
+        /* a zero-sized buffer holds nothing */
+        if self.limit > 0
+        {
+            self.bytes.push_back(byte);
+        }
+        Ok(())
+    }
+
+    /* add bytes written by the hypervisor on the capsule's behalf. these can't be
+       retried, so they're dropped rather than refused if the policy is to block */
+    pub fn append(&mut self, bytes: &[u8])
+    {
+        for byte in bytes
+        {
+            let _ = self.push(*byte);
+        }
+    }
+
+    /* <= oldest buffered byte, or None if the buffer is empty */
+    pub fn pop(&mut self) -> Option<u8>
+    {
+        self.bytes.pop_front()
+    }
+
+    /* <= bytes dropped or refused since the buffer was created */
+    pub fn overflows(&self) -> u64
+    {
+        self.overflows
+    }
+}
+
+lazy_static!
+{
+    /* decoders for capsules writing UTF-8 straight to the debug port */
+    static ref DECODERS: Mutex<HashMap<CapsuleID, UTF8Decoder>> = Mutex::new("console UTF-8 decoders", HashMap::new());
+
+    /* line editors of capsules with cooked console input */
+    static ref EDITORS: Mutex<HashMap<CapsuleID, LineEditor>> = Mutex::new("console line editors", HashMap::new());
+}
+
+/* convert a byte written by a capsule straight to the debug port into text to output
+   => cid = capsule writing the byte
+      encoding = capsule's console encoding
+      byte = byte written
+   <= text to output, which may be empty while a character is being assembled */
+pub fn render(cid: CapsuleID, encoding: Encoding, byte: u8) -> String
+{
+    let mut out = String::new();
+    match encoding
+    {
+        Encoding::UTF8 => DECODERS.lock().entry(cid).or_insert(UTF8Decoder::new()).feed(byte, &mut out),
+
+        /* the debug port takes text, so bytes that aren't ASCII are shown as Latin-1 */
+        Encoding::Raw => out.push(byte as char)
+    }
+    out
+}
+
+/* switch a capsule's console input between raw and cooked. any partly typed input is discarded
+   => cid = capsule to switch
+      mode = input mode to use from now on
+      encoding = capsule's console encoding */
+pub fn set_input_mode(cid: CapsuleID, mode: InputMode, encoding: Encoding)
+{
+    let mut editors = EDITORS.lock();
+    match mode
+    {
+        InputMode::Cooked => editors.insert(cid, LineEditor::new(encoding)),
+        InputMode::Raw => editors.remove(&cid)
+    };
+}
+
+/* <= how the given capsule's console input is delivered */
+pub fn get_input_mode(cid: CapsuleID) -> InputMode
+{
+    match EDITORS.lock().contains_key(&cid)
+    {
+        true => InputMode::Cooked,
+        false => InputMode::Raw
+    }
+}
+
+/* pass a byte of raw input to a cooked-mode capsule's line editor
+   => cid = capsule receiving the input
+      byte = byte typed
+   <= bytes to echo back to the capsule's console output */
+pub fn cook(cid: CapsuleID, byte: u8) -> Vec<u8>
+{
+    let mut echo = Vec::new();
+    if let Some(editor) = EDITORS.lock().get_mut(&cid)
+    {
+        editor.feed(byte, &mut echo);
+    }
+    echo
+}
+
+/* <= next byte of a line completed by a cooked-mode capsule, or None if no line is ready */
+pub fn take_cooked(cid: CapsuleID) -> Option<u8>
+{
+    match EDITORS.lock().get_mut(&cid)
+    {
+        Some(editor) => editor.take(),
+        None => None
+    }
+}
+
+/* discard a capsule's decoder and line editor when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    DECODERS.lock().remove(&cid);
+    EDITORS.lock().remove(&cid);
+}
+
+#[test_case]
+fn test_utf8_decoder_multibyte()
+{
+    let mut decoder = UTF8Decoder::new();
+    let mut out = String::new();
+
+    for byte in "h\u{e9}llo \u{1f600}".as_bytes()
+    {
+        decoder.feed(*byte, &mut out);
+    }
+    assert_eq!(out.as_str(), "h\u{e9}llo \u{1f600}");
+}
+
+#[test_case]
+fn test_utf8_decoder_malformed()
+{
+    let mut decoder = UTF8Decoder::new();
+    let mut out = String::new();
+
+    /* stray continuation byte, truncated sequence, and an overlong encoding of '/' */
+    for byte in [0x80u8, b'a', 0xe2, 0x82, b'b', 0xe0, 0x80, 0xaf].iter()
+    {
+        decoder.feed(*byte, &mut out);
+    }
+    assert_eq!(out.as_str(), "\u{fffd}a\u{fffd}b\u{fffd}");
+}
+
+#[test_case]
+fn test_line_editor_lines()
+{
+    let mut editor = LineEditor::new(Encoding::UTF8);
+    let mut echo = Vec::new();
+
+    /* a line is only handed over once it's complete, and CR LF ends just the one line */
+    for byte in b"hi".iter()
+    {
+        editor.feed(*byte, &mut echo);
+    }
+    assert_eq!(editor.take(), None);
+    for byte in b"\r\nok\n".iter()
+    {
+        editor.feed(*byte, &mut echo);
+    }
+
+    let mut lines = Vec::new();
+    while let Some(byte) = editor.take()
+    {
+        lines.push(byte);
+    }
+    assert_eq!(lines.as_slice(), b"hi\nok\n");
+    assert_eq!(echo.as_slice(), b"hi\r\nok\r\n");
+}
+
+#[test_case]
+fn test_output_buffer_overflow()
+{
+    /* the oldest bytes make way for new ones, or the new ones are lost or refused */
+    let mut oldest = OutputBuffer::new(2, Overflow::DropOldest);
+    let mut newest = OutputBuffer::new(2, Overflow::DropNewest);
+    let mut block = OutputBuffer::new(2, Overflow::Block);
+    for byte in b"abc".iter()
+    {
+        assert_eq!(oldest.push(*byte).is_ok(), true);
+        assert_eq!(newest.push(*byte).is_ok(), true);
+        assert_eq!(block.push(*byte).is_ok(), *byte != b'c');
+    }
+
+    assert_eq!((oldest.pop(), oldest.pop(), oldest.overflows()), (Some(b'b'), Some(b'c'), 1));
+    assert_eq!((newest.pop(), newest.pop(), newest.overflows()), (Some(b'a'), Some(b'b'), 1));
+    assert_eq!((block.pop(), block.pop(), block.overflows()), (Some(b'a'), Some(b'b'), 1));
+}
+
+#[test_case]
+fn test_line_editor_backspace()
+{
+    let mut editor = LineEditor::new(Encoding::UTF8);
+    let mut echo = Vec::new();
+
+    /* backspace removes a whole multi-byte character, and does nothing on an empty line */
+    for byte in "a\u{e9}\x7f\x7f\x7fb\r".as_bytes()
+    {
+        editor.feed(*byte, &mut echo);
+    }
+    assert_eq!(editor.take(), Some(b'b'));
+    assert_eq!(editor.take(), Some(b'\n'));
+    assert_eq!(editor.take(), None);
+}
@@ -0,0 +1,186 @@
+/* diosix capsule crash dumps
+ *
+ * When a capsule's virtual core dies from an exception the hypervisor
+ * can't handle, its full register state is captured before the capsule
+ * is paused, restarted, or destroyed: every general-purpose register,
+ * named as in the calling convention, the control and status registers
+ * that describe the fault, and short hexdumps of the memory around the
+ * faulting instruction and the top of the stack. The dump is written to
+ * the hypervisor's log, so crashes can be triaged from the log alone.
+ *
+ * The registers are captured from those stacked on entry to the
+ * hypervisor and the trapped code's control and status registers, which
+ * are still loaded in the core. Memory is only dumped if the virtual
+ * core wasn't using address translation, as the hypervisor can't walk
+ * the guest's page tables, and only from within the capsule's own RAM.
+ *
+ * The last dump is kept with the capsule's crash record, alongside any
+ * panic report: management services can read it while the capsule is
+ * paused for inspection, and after it's restarted, until it's destroyed.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt::Write;
+use core::slice;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::pcore;
+use super::machine;
+use super::hcargs::{self, Access};
+use super::trap;
+use platform::irq::{IRQContext, IRQ};
+
+/* registers written per line */
+const REGISTERS_PER_LINE: usize = 4;
+
+/* bytes of memory dumped before and after the faulting instruction */
+const PC_DUMP_BEFORE: usize = 32;
+const PC_DUMP_AFTER: usize = 32;
+
+/* bytes of memory dumped from the stack pointer up */
+const SP_DUMP_SIZE: usize = 128;
+
+/* bytes written per line of a hexdump */
+const HEXDUMP_LINE: usize = 16;
+
+lazy_static!
+{
+    /* the last dump of each capsule that crashed */
+    static ref DUMPS: Mutex<HashMap<CapsuleID, String>> = Mutex::new("capsule crash dumps", HashMap::new());
+}
+
+/* write a set of named registers, a few to a line */
+fn registers(dump: &mut String, registers: &[(&'static str, usize)])
+{
+    for line in registers.chunks(REGISTERS_PER_LINE)
+    {
+        for (name, value) in line
+        {
+            let _ = write!(dump, " {:>8}=0x{:016x}", name, value);
+        }
+        dump.push('\n');
+    }
+}
+
+/* write a hexdump of part of a capsule's memory, or why it can't be dumped
+   => dump = text to add to
+      cid = capsule whose memory it is
+      title = what the memory is
+      start, size = address and size in bytes of the memory in the capsule */
+fn hexdump(dump: &mut String, cid: CapsuleID, title: &str, start: usize, size: usize)
+{
+    /* align to whole lines */
+    let start = start & !(HEXDUMP_LINE - 1);
+    let _ = write!(dump, " {} at 0x{:x}:\n", title, start);
+
+    let base = match hcargs::buffer(cid, start, size, size, Access::Read)
+    {
+        Ok(base) => base,
+        Err(_) =>
+        {
+            dump.push_str("   not in the capsule's RAM\n");
+            return;
+        }
+    };
+
+    let bytes = unsafe { slice::from_raw_parts(base as *const u8, size) };
+    for (index, line) in bytes.chunks(HEXDUMP_LINE).enumerate()
+    {
+        let _ = write!(dump, "   {:016x}:", start + (index * HEXDUMP_LINE));
+        for byte in line
+        {
+            let _ = write!(dump, " {:02x}", byte);
+        }
+        dump.push_str("  |");
+        for byte in line
+        {
+            dump.push(match byte.is_ascii_graphic() || *byte == b' '
+            {
+                true => *byte as char,
+                false => '.'
+            });
+        }
+        dump.push_str("|\n");
+    }
+}
+
+/* capture, log, and keep the state of the currently running virtual core as it crashes.
+   call before the capsule is paused, restarted, or destroyed
+   => exception = the fatal exception
+      context = the virtual core's saved context */
+pub fn record(exception: &IRQ, context: &IRQContext)
+{
+    let id = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => id,
+        None => return
+    };
+
+    let state = trap::crash_registers(context);
+    let mut dump = String::new();
+    let _ = write!(dump, " vcore {}.{} {:?} at 0x{:x}, stack 0x{:x}, fault address 0x{:x}\n",
+        id.capsuleid, id.vcoreid, exception.cause, exception.pc, exception.sp, machine::fault_address());
+
+    dump.push_str(" general-purpose registers:\n");
+    registers(&mut dump, state.general());
+    dump.push_str(" control and status registers:\n");
+    registers(&mut dump, state.control());
+
+    match state.translated()
+    {
+        false =>
+        {
+            hexdump(&mut dump, id.capsuleid, "code", exception.pc.saturating_sub(PC_DUMP_BEFORE), PC_DUMP_BEFORE + PC_DUMP_AFTER);
+            hexdump(&mut dump, id.capsuleid, "stack", exception.sp, SP_DUMP_SIZE);
+        },
+        true => dump.push_str(" memory not dumped: the virtual core was using address translation\n")
+    }
+
+    hvalert!("Crash dump of capsule {}:", id.capsuleid);
+    for line in dump.lines()
+    {
+        hvprintln!("[!] capsule {}:{}", id.capsuleid, line);
+    }
+
+    DUMPS.lock().insert(id.capsuleid, dump);
+}
+
+/* copy a capsule's last crash dump, as text, into the currently running capsule's memory.
+   the dump is truncated if the buffer is too small.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule whose dump is wanted
+      buffer, size = address and size in bytes of the buffer to fill in the running capsule
+   <= full length of the dump in bytes, or an error code if there's no dump */
+pub fn read(cid: CapsuleID, buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+
+    /* don't hold the dumps lock while looking up the caller's memory */
+    let dump = match DUMPS.lock().get(&cid)
+    {
+        Some(dump) => dump.clone(),
+        None => return Err(Cause::CrashDumpNotFound)
+    };
+
+    let to_copy = core::cmp::min(size, dump.len());
+    if to_copy > 0
+    {
+        let base = hcargs::buffer(caller, buffer, to_copy, dump.len(), Access::Write)?;
+        let target = unsafe { slice::from_raw_parts_mut(base as *mut u8, to_copy) };
+        target.copy_from_slice(&dump.as_bytes()[..to_copy]);
+    }
+
+    Ok(dump.len())
+}
+
+/* discard a capsule's crash dump when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    DUMPS.lock().remove(&cid);
+}
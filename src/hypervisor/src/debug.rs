@@ -10,12 +10,13 @@
 
 use super::error::Cause;
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use super::lock::Mutex;
 use alloc::vec::Vec;
 use alloc::string::String;
-use super::hardware;
 use super::service;
 use super::message;
+use super::arbiter::{self, Source};
 
 /* here's the logic for the hypervisor's debug queues
     * all the hvprint macros feed into DEBUG_QUEUE
@@ -24,11 +25,24 @@ use super::message;
       (typically a serial port) if a user interface capsule isn't running
     * the user interface capsule will drain DEBUG_LOG
     * DEBUG_LOG will have a fixed limit to avoid it chewing up too much RAM
+    * DEBUG_QUEUE has a fixed limit too, in case the debug output port is slow. when it's full,
+      its oldest output is dropped, or if the debugblock feature is active, the writer waits
+      for the queue to be copied out to the debug output port. dropped bytes are counted and
+      reported when the queue is next drained
+    * output for the system debug output port goes through the arbiter, which gives the hypervisor
+      and capsules that write straight to the port turns on it
+    * alerts skip DEBUG_QUEUE: they go straight into DEBUG_LOG and to the front of the arbiter's
+      output so they get out even when DEBUG_QUEUE is backed up
     * if the qemuprint feature is active, the system debug output port will always be the
       Qemu virt serial port regardless of what's in the host hardware's device tree
 */
 
 const DEBUG_LOG_MAX_LEN: usize = 64 * 1024; /* 64KB max length for debug log buffer */
+const DEBUG_QUEUE_MAX_LEN: usize = 16 * 1024; /* 16KB max length for debug output waiting to be drained */
+
+/* bytes of debug output dropped since the last report, and in total */
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED_TOTAL: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static!
 {
@@ -38,21 +52,35 @@ lazy_static!
 }
 
 /* top level debug macros */
-/* bad news: bug detection, failures, etc. */
+/* bad news: bug detection, failures, etc. these are also kept in the persistent store, if there is one */
 #[macro_export]
 macro_rules! hvalert
 {
-    ($fmt:expr) => (hvprintln!("[!] CPU {}: {}", $crate::pcore::PhysicalCore::get_id(), $fmt));
-    ($fmt:expr, $($arg:tt)*) => (hvprintln!(concat!("[!] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*));
+    ($fmt:expr) => ({
+        let alert = format!("[!] CPU {}: {}", $crate::pcore::PhysicalCore::get_id(), $fmt);
+        $crate::pstore::mirror(&alert);
+        $crate::debug::alert(&alert);
+    });
+    ($fmt:expr, $($arg:tt)*) => ({
+        let alert = format!(concat!("[!] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*);
+        $crate::pstore::mirror(&alert);
+        $crate::debug::alert(&alert);
+    });
 }
 
-/* only output if debug build is enabled */
+/* only output if debug build is enabled, and the calling module's verbosity allows it */
 #[macro_export]
 #[cfg(debug_assertions)]
 macro_rules! hvdebug
 {
-    ($fmt:expr) => (hvprintln!("[?] CPU {}: {}", $crate::pcore::PhysicalCore::get_id(), $fmt));
-    ($fmt:expr, $($arg:tt)*) => (hvprintln!(concat!("[?] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*));
+    ($fmt:expr) => (if $crate::settings::debug_enabled(module_path!())
+    {
+        hvprintln!("[?] CPU {}: {}", $crate::pcore::PhysicalCore::get_id(), $fmt);
+    });
+    ($fmt:expr, $($arg:tt)*) => (if $crate::settings::debug_enabled(module_path!())
+    {
+        hvprintln!(concat!("[?] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*);
+    });
 }
 
 /* silence debug if disabled */
@@ -82,6 +110,26 @@ macro_rules! hvdebugraw
     ($fmt:expr, $($arg:tt)*) => ({});
 }
 
+/* record a tracepoint in the trace ring, if it's switched on, eg:
+   hvtrace!(Lifecycle, "capsule {} killed", cid) for Tracepoint::Lifecycle */
+#[macro_export]
+#[cfg(feature = "tracepoints")]
+macro_rules! hvtrace
+{
+    ($point:ident, $($arg:tt)*) => (if $crate::trace::is_enabled($crate::trace::Tracepoint::$point)
+    {
+        $crate::trace::point($crate::trace::Tracepoint::$point, format!($($arg)*));
+    });
+}
+
+/* compile out tracepoints, and their arguments, if they're not wanted */
+#[macro_export]
+#[cfg(not(feature = "tracepoints"))]
+macro_rules! hvtrace
+{
+    ($point:ident, $($arg:tt)*) => ({});
+}
+
 /* low-level macros for hypervisor-only hvprintln and hvprint debug output routines */
 macro_rules! hvprintln
 {
@@ -141,6 +189,17 @@ impl fmt::Write for ConsoleWriter
                 unsafe { *(tx_register as *mut u32) = *c as u32 };
             }
         }
+        else if cfg!(feature = "starfiveprint")
+        {
+            let tx_register = 0x10000000; /* the JH7110's first UART's tx register in memory */
+            let status_register = 0x10000014; /* ...and its line status register, with 32-bit registers 4 bytes apart */
+            for c in s.as_bytes()
+            {
+                /* wait for the tx register to empty, which sets bit 5 of the line status */
+                while unsafe { *(status_register as *mut u32) } & (1 << 5) == 0 {}
+                unsafe { *(tx_register as *mut u32) = *c as u32 };
+            }
+        }
         else if cfg!(feature = "htifprint")
         {
             extern "C" { fn platform_write_to_htif(byte: u8); }
@@ -152,7 +211,30 @@ impl fmt::Write for ConsoleWriter
         else
         {
             /* queue the output for printing out later when ready */
-            DEBUG_QUEUE.lock().push_str(s);
+            let mut debug_queue = DEBUG_QUEUE.lock();
+            if debug_queue.len() + s.len() > DEBUG_QUEUE_MAX_LEN
+            {
+                /* try to make room by waiting for the queue to be written out. the caller
+                   holds DEBUG_LOCK so we can do this ourselves. if the hardware isn't ready,
+                   we've no choice but to fall back to dropping output */
+                if cfg!(feature = "debugblock")
+                {
+                    flush(&mut debug_queue);
+                }
+                make_room(&mut debug_queue, s.len());
+            }
+
+            /* a string longer than the whole queue keeps only its tail */
+            let mut start = s.len().saturating_sub(DEBUG_QUEUE_MAX_LEN);
+            while s.is_char_boundary(start) == false
+            {
+                start = start + 1;
+            }
+            if start > 0
+            {
+                count_dropped(start);
+            }
+            debug_queue.push_str(&s[start..]);
         }
         Ok(())
     }
@@ -177,34 +259,120 @@ pub fn drain_queue()
         *debug_lock = true;
 
         let mut debug_queue = DEBUG_QUEUE.lock();
-        let mut debug_log = DEBUG_LOG.lock();
 
-        /* copy the debug queue out to the system debug output port ourselves if there's no user interface yet */
-        if service::is_registered(service::ServiceType::ConsoleInterface) == false
+        /* let the user know if output was lost since the last time we drained the queue */
+        let dropped = DROPPED.swap(0, Ordering::SeqCst);
+        if dropped > 0
         {
-            if hardware::write_debug_string(&debug_queue) == false
-            {
-                /* we may not even know what hardware is available yet,
-                   so bail out and try again later */
-                return;
-            }
+            let report = format!("[!] Debug output full: {} bytes dropped ({} in total)\r\n",
+                dropped, DROPPED_TOTAL.load(Ordering::SeqCst));
+            make_room(&mut debug_queue, report.len());
+            debug_queue.push_str(&report);
         }
 
-        /* drain the debug queue to the log buffer so it can be fetched later by the
-           user interface service */
-        for c in debug_queue.as_str().chars()
+        flush(&mut debug_queue);
+    }
+
+    arbiter::pump();
+}
+
+/* output an alert ahead of queued debug output. use hvalert!() rather than this
+   => text = alert to output, without a line ending */
+pub fn alert(text: &str)
+{
+    /* forced output has no queue to skip */
+    if cfg!(any(feature = "qemuprint", feature = "sifiveprint", feature = "starfiveprint", feature = "htifprint"))
+    {
+        hvprintln!("{}", text);
+        return;
+    }
+
+    {
+        let mut debug_lock = DEBUG_LOCK.lock();
+        *debug_lock = true;
+
+        let line = format!("{}\r\n", text);
+        if service::is_registered(service::ServiceType::ConsoleInterface) == false
         {
-            debug_log.push(c);
+            arbiter::write(Source::Alert, line.as_str());
         }
-        debug_queue.clear();
+        append_log(&mut DEBUG_LOG.lock(), line.as_str());
+    }
+
+    arbiter::pump_alerts();
+}
 
-        /* truncate the log buffer if it's too long */
-        if debug_log.len() > DEBUG_LOG_MAX_LEN
+/* pass the debug queue to the arbiter for the system debug output port, if there's no user interface yet,
+   and into the log buffer, and then empty the queue. DEBUG_LOCK must be held by the caller
+   => debug_queue = locked debug queue
+   <= true if the queue was emptied, or false if the arbiter can't take it yet */
+fn flush(debug_queue: &mut String) -> bool
+{
+    let mut debug_log = DEBUG_LOG.lock();
+
+    /* hand the debug queue to the arbiter for the system debug output port ourselves if there's no user interface yet */
+    if service::is_registered(service::ServiceType::ConsoleInterface) == false
+    {
+        if arbiter::write(Source::Hypervisor, &debug_queue) == false
         {
-            let to_truncate = debug_log.len() - DEBUG_LOG_MAX_LEN;
-            debug_log.drain(0..to_truncate);
+            /* the port is backed up, or we may not even know what hardware
+               is available yet, so bail out and try again later */
+            arbiter::pump();
+            return false;
         }
     }
+
+    /* drain the debug queue to the log buffer so it can be fetched later by the
+       user interface service */
+    append_log(&mut debug_log, &debug_queue);
+    debug_queue.clear();
+    true
+}
+
+/* add output to the log buffer, truncating the buffer if it gets too long
+   => debug_log = locked log buffer
+      text = output to add */
+fn append_log(debug_log: &mut Vec<char>, text: &str)
+{
+    for c in text.chars()
+    {
+        debug_log.push(c);
+    }
+
+    if debug_log.len() > DEBUG_LOG_MAX_LEN
+    {
+        let to_truncate = debug_log.len() - DEBUG_LOG_MAX_LEN;
+        debug_log.drain(0..to_truncate);
+    }
+}
+
+/* drop the oldest output from the debug queue, if necessary, to fit in more
+   => debug_queue = locked debug queue
+      needed = number of bytes to make room for */
+fn make_room(debug_queue: &mut String, needed: usize)
+{
+    let mut to_drop = (debug_queue.len() + needed).saturating_sub(DEBUG_QUEUE_MAX_LEN);
+    if to_drop == 0
+    {
+        return;
+    }
+
+    /* don't split a character */
+    to_drop = core::cmp::min(to_drop, debug_queue.len());
+    while debug_queue.is_char_boundary(to_drop) == false
+    {
+        to_drop = to_drop + 1;
+    }
+
+    debug_queue.drain(..to_drop);
+    count_dropped(to_drop);
+}
+
+/* account for bytes of debug output that were dropped */
+fn count_dropped(bytes: usize)
+{
+    DROPPED.fetch_add(bytes, Ordering::SeqCst);
+    DROPPED_TOTAL.fetch_add(bytes, Ordering::SeqCst);
 }
 
 /* pick off the next character in the hypervisor log output buffer,
@@ -0,0 +1,502 @@
+/* diosix run-time loadable device model plugins
+ *
+ * Device models, such as virtio network, block, and entropy devices,
+ * can be shipped in the DMFS image as separate position-independent
+ * ELF objects rather than built into the hypervisor. A model is only
+ * loaded and relocated when a capsule is created with the
+ * device_model=name property, so that minimal systems don't carry
+ * models they don't use, and models can be added or dropped by
+ * rebuilding the DMFS image without recompiling the hypervisor.
+ *
+ * A plugin's ELF entry point is a function that takes no parameters and
+ * returns a pointer to its Descriptor, below. The hypervisor then creates
+ * one instance of the model per capsule that asks for it, and hands each
+ * instance a window of the capsule's physical address space. Reads and
+ * writes to that window are passed to the instance to emulate.
+ *
+ * Devices declared in a capsule's manifest with device= properties are
+ * created here too, using the model for the kind of device. These get
+ * the window and interrupt the manifest asks for, or ones chosen here,
+ * and version 2 plugins are handed the rest of the declaration, such as
+ * a block device's backing asset, and a function to raise the device's
+ * interrupt. Version 1 plugins can only emulate declared devices that
+ * need no more than a window.
+ *
+ * Plugins run with the hypervisor's privileges and so must be trusted
+ * as much as the hypervisor itself.
+ *
+ * A few models, such as the real-time clock every capsule is given,
+ * are built into the hypervisor. These are used in the same way as
+ * plugins, through the same interface, but aren't loaded from the DMFS
+ * image.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use alloc::string::String;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use super::error::{self, Cause};
+use super::capsule::CapsuleID;
+use super::physmem::Region;
+use super::manifest::{self, Backing};
+use super::passthrough::{self, DeviceIRQ};
+use super::vdevice::{DeviceSpec, VIRQ_DEVICE_BASE, VIRQ_DEVICE_COUNT};
+use super::accounting;
+use super::rtc;
+use super::machine;
+use super::message;
+
+/* oldest and newest versions of the plugin interface below. plugins built for other versions are rejected */
+pub const DEVICE_MODEL_ABI_VERSION_MIN: usize = 1;
+pub const DEVICE_MODEL_ABI_VERSION: usize = 2;
+
+/* device model windows are placed in each capsule's physical address space starting here,
+   in slots one after another. this lies outside RAM on all supported platforms */
+const WINDOW_BASE: PhysMemBase = 0x40_0000_0000;
+const WINDOW_STRIDE: PhysMemSize = 64 * 1024;
+const WINDOW_SLOTS: usize = 1024;
+
+/* longest compatible string accepted from a plugin, in bytes */
+const COMPATIBLE_MAX_LEN: usize = 64;
+
+/* a plugin's entry point returns a pointer to this structure describing the model.
+   instances are identified by whatever word the plugin's create function returns */
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor
+{
+    abi_version: usize,     /* from DEVICE_MODEL_ABI_VERSION_MIN to DEVICE_MODEL_ABI_VERSION */
+    window_size: usize,     /* bytes of address space each instance needs, up to WINDOW_STRIDE */
+    compatible: *const u8,  /* device tree compatible string for the model... */
+    compatible_len: usize,  /* ...and its length in bytes */
+    create: extern "C" fn(cid: usize) -> usize, /* return new instance for capsule cid, or usize::MAX for failure */
+    destroy: extern "C" fn(instance: usize),
+    read: extern "C" fn(instance: usize, offset: usize, width: usize) -> usize,
+    write: extern "C" fn(instance: usize, offset: usize, width: usize, value: usize)
+}
+
+/* version 2 plugins extend the descriptor with a way to create configured instances */
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DescriptorV2
+{
+    base: Descriptor,
+    create_configured: extern "C" fn(cid: usize, config: *const Config) -> usize /* as create() */
+}
+
+/* the parts of a device's declaration a version 2 plugin needs to create an instance.
+   pointers are only valid until the instance is destroyed */
+#[repr(C)]
+pub struct Config
+{
+    pub irq: usize,         /* interrupt to raise for the device, or usize::MAX for none */
+    backing: *const u8,     /* read-only contents of the asset backing the device... */
+    backing_len: usize,     /* ...and its length in bytes, or null and zero for none */
+    switch: *const u8,      /* name of the virtual switch the device is plugged into... */
+    switch_len: usize,      /* ...and its length in bytes, or null and zero for none */
+    queue_max: usize,       /* most entries in each virtqueue, or zero for the model's default */
+    rate_max: usize,        /* most requests to handle per second, or zero for no limit */
+    raise_irq: extern "C" fn(cid: usize, irq: usize) /* raise the device's interrupt in the capsule */
+}
+
+/* a loaded device model */
+struct Model
+{
+    region: Option<Region>, /* physical RAM holding the relocated plugin, or None for built-in models */
+    compatible: String,
+    window_size: PhysMemSize,
+    create: extern "C" fn(usize) -> usize,
+    destroy: extern "C" fn(usize),
+    read: extern "C" fn(usize, usize, usize) -> usize,
+    write: extern "C" fn(usize, usize, usize, usize),
+    create_configured: Option<extern "C" fn(usize, *const Config) -> usize> /* None for version 1 plugins */
+}
+
+/* an instance of a model attached to a capsule */
+struct Instance
+{
+    model: String,      /* name of the model */
+    state: usize,       /* plugin's handle for this instance */
+    window: PhysMemBase, /* start of the instance's window in the capsule's address space */
+    irq: Option<DeviceIRQ>, /* the instance's interrupt, if it has one */
+    queue_max: Option<usize>, /* most entries in each of the instance's virtqueues, if limited */
+    _backing: Option<Backing> /* asset backing the instance, kept while the plugin may read it */
+}
+
+/* a device model instance's window, as described to its capsule */
+pub struct Window
+{
+    pub base: PhysMemBase,
+    pub size: PhysMemSize,
+    pub compatible: String,
+    pub irq: Option<DeviceIRQ>,
+    pub queue_max: Option<usize>
+}
+
+lazy_static!
+{
+    /* device models loaded from the DMFS image, by name */
+    static ref MODELS: Mutex<HashMap<String, Model>> = Mutex::new("device models", HashMap::new());
+
+    /* instances of device models attached to each capsule */
+    static ref INSTANCES: Mutex<HashMap<CapsuleID, Vec<Instance>>> = Mutex::new("device model instances", HashMap::new());
+}
+
+/* load and relocate the named device model from the DMFS image, if it isn't already loaded */
+fn load(name: &str) -> Result<(), Cause>
+{
+    let mut models = MODELS.lock();
+    if models.contains_key(name)
+    {
+        return Ok(());
+    }
+
+    /* built-in models need nothing loading */
+    if let Some(model) = builtin(name)
+    {
+        models.insert(String::from(name), model);
+        return Ok(());
+    }
+
+    let (region, entry) = manifest::load_device_model(name)?;
+
+    /* the plugin's code must be fetched fresh, here before it's described, and on any
+       other core before it emulates an access for an instance */
+    machine::sync_instructions();
+    if let Err(_e) = message::send(message::Message::new(message::Recipient::send_to_all(), message::MessageContent::SyncInstructions)?)
+    {
+        hvalert!("Failed to tell physical CPU cores about device model {}'s code: {}", name, error::report(&_e));
+    }
+
+    let describe: extern "C" fn() -> *const Descriptor = unsafe { core::mem::transmute(entry) };
+    let descriptor = describe();
+
+    /* the descriptor must lie within the plugin */
+    if within(&region, descriptor as usize, core::mem::size_of::<Descriptor>()) == false
+    {
+        return Err(Cause::DeviceModelBadABI);
+    }
+
+    let create_configured = match unsafe { (*descriptor).abi_version }
+    {
+        2 if within(&region, descriptor as usize, core::mem::size_of::<DescriptorV2>()) =>
+            Some(unsafe { (*(descriptor as *const DescriptorV2)).create_configured }),
+        _ => None
+    };
+
+    let descriptor = unsafe { *descriptor };
+    if descriptor.abi_version < DEVICE_MODEL_ABI_VERSION_MIN || descriptor.abi_version > DEVICE_MODEL_ABI_VERSION
+        || (descriptor.abi_version >= 2 && create_configured.is_none()) || descriptor.window_size > WINDOW_STRIDE
+    {
+        hvalert!("Device model {} uses unsupported ABI version {} or window size 0x{:x}",
+            name, descriptor.abi_version, descriptor.window_size);
+        return Err(Cause::DeviceModelBadABI);
+    }
+
+    /* copy the compatible string out of the plugin, so long as it's within the plugin too */
+    let compatible_end = (descriptor.compatible as usize).checked_add(descriptor.compatible_len);
+    if descriptor.compatible_len > COMPATIBLE_MAX_LEN || (descriptor.compatible as usize) < region.base() ||
+        compatible_end.map_or(true, |end| end > region.end())
+    {
+        return Err(Cause::DeviceModelBadABI);
+    }
+    let compatible = unsafe { slice::from_raw_parts(descriptor.compatible, descriptor.compatible_len) };
+    let compatible = String::from_utf8_lossy(compatible).into_owned();
+
+    hvdebug!("Loaded device model {} ({}) at 0x{:x}", name, compatible, region.base());
+
+    models.insert(String::from(name), Model
+    {
+        region: Some(region),
+        compatible,
+        window_size: descriptor.window_size,
+        create: descriptor.create,
+        destroy: descriptor.destroy,
+        read: descriptor.read,
+        write: descriptor.write,
+        create_configured
+    });
+
+    Ok(())
+}
+
+/* <= the named model built into the hypervisor, or None if there's no such built-in model */
+fn builtin(name: &str) -> Option<Model>
+{
+    match name
+    {
+        rtc::MODEL_NAME => Some(Model
+        {
+            region: None,
+            compatible: String::from(rtc::COMPATIBLE),
+            window_size: rtc::WINDOW_SIZE,
+            create: rtc::create,
+            destroy: rtc::destroy,
+            read: rtc::read,
+            write: rtc::write,
+            create_configured: Some(rtc::create_configured)
+        }),
+        _ => None
+    }
+}
+
+/* <= true if size bytes from addr lie entirely within the given plugin's region */
+fn within(region: &Region, addr: usize, size: usize) -> bool
+{
+    addr >= region.base() && addr.checked_add(size).map_or(false, |end| end <= region.end())
+}
+
+/* <= true if a declared device's window can start at the given address in a capsule */
+pub fn is_valid_window(base: PhysMemBase) -> bool
+{
+    base >= WINDOW_BASE && base < WINDOW_BASE + (WINDOW_SLOTS * WINDOW_STRIDE) && (base - WINDOW_BASE) % WINDOW_STRIDE == 0
+}
+
+/* raise a declared device's interrupt on behalf of its version 2 plugin */
+extern "C" fn raise_irq(cid: usize, irq: usize)
+{
+    if irq >= VIRQ_DEVICE_BASE && irq < VIRQ_DEVICE_BASE + VIRQ_DEVICE_COUNT
+    {
+        passthrough::raise_virtual_irq(cid, irq);
+    }
+}
+
+/* create an instance of the named device model for a capsule, loading the model if needed
+   => cid = capsule to attach the device to
+      name = name of the device model in the DMFS image
+   <= Ok for success, or an error code */
+pub fn attach(cid: CapsuleID, name: &str) -> Result<(), Cause>
+{
+    load(name)?;
+
+    let mut instances = INSTANCES.lock();
+    let list = instances.entry(cid).or_insert(Vec::new());
+    let window = free_window(list)?;
+
+    let state = match MODELS.lock().get(name)
+    {
+        Some(model) => (model.create)(cid),
+        None => return Err(Cause::DeviceModelNotFound)
+    };
+
+    if state == usize::MAX
+    {
+        return Err(Cause::DeviceModelCreateFailed);
+    }
+
+    list.push(Instance { model: String::from(name), state, window, irq: None, queue_max: None, _backing: None });
+    Ok(())
+}
+
+/* create a device declared in a capsule's manifest, loading its model if needed.
+   devices with fixed addresses and interrupts should be attached first so that others don't take them
+   => cid = capsule to attach the device to
+      spec = the device's declaration
+   <= Ok for success, or an error code */
+pub fn attach_declared(cid: CapsuleID, spec: &DeviceSpec) -> Result<(), Cause>
+{
+    let name = spec.model();
+    load(name)?;
+
+    let backing = match &spec.asset
+    {
+        Some(asset) => Some(manifest::get_backing(asset)?),
+        None => None
+    };
+
+    let mut instances = INSTANCES.lock();
+    let list = instances.entry(cid).or_insert(Vec::new());
+
+    let window = match spec.addr
+    {
+        Some(addr) => match list.iter().any(|instance| instance.window == addr)
+        {
+            true => return Err(hverror!(Cause::DeviceAddressInUse, "capsule {} has two devices at 0x{:x}", cid, addr)),
+            false => addr
+        },
+        None => free_window(list)?
+    };
+
+    let irq = match spec.irq
+    {
+        Some(irq) => match list.iter().any(|instance| instance.irq == Some(irq))
+        {
+            true => return Err(hverror!(Cause::DeviceIRQInUse, "capsule {} has two devices using interrupt 0x{:x}", cid, irq)),
+            false => irq
+        },
+        None => match (VIRQ_DEVICE_BASE..VIRQ_DEVICE_BASE + VIRQ_DEVICE_COUNT).find(|irq| list.iter().any(|instance| instance.irq == Some(*irq)) == false)
+        {
+            Some(irq) => irq,
+            None => return Err(Cause::DeviceIRQInUse)
+        }
+    };
+
+    let models = MODELS.lock();
+    let model = match models.get(name)
+    {
+        Some(model) => model,
+        None => return Err(Cause::DeviceModelNotFound)
+    };
+
+    let (state, irq) = match model.create_configured
+    {
+        Some(create_configured) =>
+        {
+            let (backing_ptr, backing_len) = match &backing
+            {
+                Some(backing) => (backing.as_slice().as_ptr(), backing.as_slice().len()),
+                None => (core::ptr::null(), 0)
+            };
+            let (switch_ptr, switch_len) = match &spec.switch
+            {
+                Some(switch) => (switch.as_ptr(), switch.len()),
+                None => (core::ptr::null(), 0)
+            };
+
+            let config = Config
+            {
+                irq,
+                backing: backing_ptr,
+                backing_len,
+                switch: switch_ptr,
+                switch_len,
+                queue_max: spec.queue.unwrap_or(0),
+                rate_max: spec.rate.unwrap_or(0),
+                raise_irq
+            };
+            (create_configured(cid, &config), Some(irq))
+        },
+
+        /* older plugins can't be told anything about the device, nor interrupt the capsule */
+        None =>
+        {
+            if spec.irq.is_some() || backing.is_some() || spec.switch.is_some() || spec.queue.is_some() || spec.rate.is_some()
+            {
+                hvalert!("Device model {} is too old to be configured by capsule {}'s manifest", name, cid);
+                return Err(Cause::DeviceModelBadABI);
+            }
+            ((model.create)(cid), None)
+        }
+    };
+
+    if state == usize::MAX
+    {
+        return Err(Cause::DeviceModelCreateFailed);
+    }
+
+    list.push(Instance { model: String::from(name), state, window, irq, queue_max: spec.queue, _backing: backing });
+    Ok(())
+}
+
+/* find the lowest window slot not used by any of a capsule's device model instances
+   => list = the capsule's instances
+   <= base of a free window, or an error code if there are none */
+fn free_window(list: &Vec<Instance>) -> Result<PhysMemBase, Cause>
+{
+    for slot in 0..WINDOW_SLOTS
+    {
+        let window = WINDOW_BASE + (slot * WINDOW_STRIDE);
+        if list.iter().any(|instance| instance.window == window) == false
+        {
+            return Ok(window);
+        }
+    }
+    Err(Cause::DeviceAddressInUse)
+}
+
+/* destroy all the device model instances attached to a capsule when it's destroyed.
+   loaded models stay loaded for use by other capsules */
+pub fn detach(cid: CapsuleID)
+{
+    if let Some(list) = INSTANCES.lock().remove(&cid)
+    {
+        let models = MODELS.lock();
+        for instance in list
+        {
+            if let Some(model) = models.get(&instance.model)
+            {
+                (model.destroy)(instance.state);
+            }
+        }
+    }
+}
+
+/* return the windows of the device models attached to a capsule
+   <= list of windows, one for each instance */
+pub fn get_windows(cid: CapsuleID) -> Vec<Window>
+{
+    let mut windows = Vec::new();
+    if let Some(list) = INSTANCES.lock().get(&cid)
+    {
+        let models = MODELS.lock();
+        for instance in list
+        {
+            if let Some(model) = models.get(&instance.model)
+            {
+                windows.push(Window
+                {
+                    base: instance.window,
+                    size: model.window_size,
+                    compatible: model.compatible.clone(),
+                    irq: instance.irq,
+                    queue_max: instance.queue_max
+                });
+            }
+        }
+    }
+    windows
+}
+
+/* find the instance owning the given address in a capsule's address space, and call f with
+   the instance's model, its state, and the offset of the address into its window.
+   the time f takes is charged to the capsule */
+fn with_instance<R>(cid: CapsuleID, addr: PhysMemBase, width: usize, f: impl FnOnce(&Model, usize, usize) -> R) -> Result<R, Cause>
+{
+    let instances = INSTANCES.lock();
+    let models = MODELS.lock();
+
+    for instance in instances.get(&cid).into_iter().flatten()
+    {
+        if let Some(model) = models.get(&instance.model)
+        {
+            if addr >= instance.window && addr < instance.window + model.window_size
+            {
+                let offset = addr - instance.window;
+                return match offset.checked_add(width)
+                {
+                    Some(end) if end <= model.window_size => Ok(accounting::device_model(cid, || f(model, instance.state, offset))),
+                    _ => Err(Cause::DeviceModelBadAccess)
+                };
+            }
+        }
+    }
+
+    Err(Cause::DeviceModelBadAccess)
+}
+
+/* emulate a capsule reading from a device model's window
+   => cid = capsule making the access
+      addr = physical address in the capsule being read
+      width = number of bytes being read
+   <= value read, or an error code if no device model owns the address */
+pub fn read(cid: CapsuleID, addr: PhysMemBase, width: usize) -> Result<usize, Cause>
+{
+    with_instance(cid, addr, width, |model, state, offset| (model.read)(state, offset, width))
+}
+
+/* emulate a capsule writing to a device model's window
+   => cid = capsule making the access
+      addr = physical address in the capsule being written
+      width = number of bytes being written
+      value = value being written
+   <= Ok for success, or an error code if no device model owns the address */
+pub fn write(cid: CapsuleID, addr: PhysMemBase, width: usize, value: usize) -> Result<(), Cause>
+{
+    with_instance(cid, addr, width, |model, state, offset| (model.write)(state, offset, width, value))
+}
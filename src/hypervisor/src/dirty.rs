@@ -0,0 +1,399 @@
+/* diosix capsule dirty page tracking
+ *
+ * Track which pages of a capsule's main RAM have changed since a
+ * checkpoint, so that a management service snapshotting or migrating
+ * the capsule need only copy again the pages that changed since its
+ * last pass. The service copies the capsule's RAM while it runs, then
+ * repeatedly reads and clears the dirty log and copies the pages it
+ * lists, until few enough are left to copy with the capsule briefly
+ * paused. That keeps the capsule's pause time short.
+ *
+ * Without page tables to mark pages dirty, changes are caught using
+ * physical memory protection. At each checkpoint, the capsule's RAM is
+ * write-protected with a window. The first write to a page faults into
+ * the hypervisor, which marks the page dirty, splits the write-protected
+ * range either side of it, and retries the write. Windows are scarce,
+ * so once the clean ranges outnumber the windows set aside for them,
+ * or those left over once the capsule's other memory and devices have
+ * been given theirs, the smallest is given up and all of its pages
+ * marked dirty. The clean ranges are protected as read-only, or as
+ * read-and-execute if the capsule's RAM is executable. With W^X, the
+ * clean ranges are split at the edges of the capsule's code: code that
+ * isn't also writeable can't be dirtied and is left out, and code that
+ * is stays executable in ranges of its own. See hvalgo's dirty.rs. The
+ * log is therefore exact for a few clusters of writes, and errs towards
+ * marking pages dirty, never away from it, when writes are scattered.
+ * On hosts with the hypervisor extension, the dirty bits of the
+ * capsule's stage-2 page tables can replace the windows, with the same
+ * log and interface.
+ *
+ * Writes that don't come from the capsule's own cores can't be caught
+ * this way. The hypervisor marks dirty any part of the capsule's memory
+ * it accesses on the capsule's behalf, such as to return hypercall
+ * results. DMA by passed-through devices and writes by capsules the
+ * memory has been granted to aren't seen, so capsules using either
+ * should be quiesced, or paused, before their final copy. See quiesce.rs.
+ *
+ * Other physical cores running the capsule only write-protect its RAM
+ * afresh once they've been told of a new checkpoint, and may miss writes
+ * until then. So if the capsule was running elsewhere when its log was
+ * read, the pages dirty in that log are reported again in the next one.
+ *
+ * The dirty log is a bitmap, one bit per page of the capsule's main
+ * RAM, least significant bit first, set if the page has changed.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::pcore;
+use super::hcargs::{self, Access};
+use super::machine;
+use hvalgo::dirty::{CleanRange, CodeSegment};
+
+/* granularity of tracking, shared with the capsules */
+pub use hypercall::dirty::DIRTY_PAGE_SIZE;
+
+/* most write-protected ranges a capsule can have at once. each needs its own protection window */
+const CLEAN_RANGES_MAX: usize = 4;
+
+/* what's known of the changes to a capsule's RAM since its last checkpoint */
+struct Log
+{
+    base: PhysMemBase,                      /* the capsule's main RAM */
+    size: PhysMemSize,
+    bitmap: Vec<u8>,                        /* one bit per page, set if dirty */
+    carried: Vec<u8>,                       /* pages dirty at the last checkpoint that may have been written since unseen */
+    clean: Vec<CleanRange>,                 /* write-protected ranges not yet written to */
+    code: Vec<CodeSegment>                  /* parts of the RAM holding code, if the capsule has W^X */
+}
+
+impl Log
+{
+    /* mark the pages covering the given range dirty */
+    fn mark(&mut self, base: PhysMemBase, size: PhysMemSize)
+    {
+        let first = (base - self.base) / DIRTY_PAGE_SIZE;
+        let last = (base + size - 1 - self.base) / DIRTY_PAGE_SIZE;
+        for page in first..=last
+        {
+            self.bitmap[page / 8] = self.bitmap[page / 8] | (1 << (page % 8));
+        }
+    }
+
+    /* start afresh: nothing is dirty and all of the RAM is write-protected
+       => running = true if the capsule is running on other physical cores, which may
+                    write to pages dirty until now before they protect them again */
+    fn checkpoint(&mut self, running: bool)
+    {
+        for (carried, byte) in self.carried.iter_mut().zip(self.bitmap.iter_mut())
+        {
+            *carried = match running
+            {
+                true => *byte,
+                false => 0
+            };
+            *byte = 0;
+        }
+
+        /* write-protect everything except the code that can't be written anyway */
+        self.clean = hvalgo::dirty::clean_ranges(self.base, self.size, &self.code);
+    }
+
+    /* give up the smallest clean ranges, marking them dirty, until no more than the given number are left
+       => limit = most clean ranges to keep */
+    fn fit(&mut self, limit: usize)
+    {
+        while self.clean.len() > limit
+        {
+            let smallest = match self.clean.iter().enumerate().min_by_key(|(_, range)| range.size)
+            {
+                Some((index, _)) => index,
+                None => break
+            };
+            let range = self.clean.remove(smallest);
+            self.mark(range.base, range.size);
+        }
+    }
+
+    /* mark everything dirty and stop write-protecting it, such as when the RAM has been reloaded */
+    fn invalidate(&mut self)
+    {
+        self.bitmap.iter_mut().for_each(|byte| *byte = 0xff);
+        self.clean.clear();
+    }
+}
+
+lazy_static!
+{
+    /* capsules whose changes are being tracked */
+    static ref LOGS: Mutex<HashMap<CapsuleID, Log>> = Mutex::new("dirty page logs", HashMap::new());
+}
+
+/* <= bytes needed to hold a bitmap for the given size of RAM, counting any partial last page */
+fn bitmap_size(size: PhysMemSize) -> usize
+{
+    let pages = (size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+    (pages + 7) / 8
+}
+
+/* <= true if the given capsule is running on any physical core other than this one */
+fn running_elsewhere(cid: CapsuleID) -> bool
+{
+    let this = pcore::PhysicalCore::get_id();
+    pcore::started().into_iter().any(|pid| pid != this && pcore::get_running_capsule(pid) == Some(cid))
+}
+
+/* return the base and size of a capsule's main RAM, or an error code if it has none */
+fn main_ram(cid: CapsuleID) -> Result<(PhysMemBase, PhysMemSize), Cause>
+{
+    match capsule::get_memory_mappings(cid)?.first().and_then(|m| m.get_physical())
+    {
+        Some(ram) => Ok((ram.base(), ram.size())),
+        None => Err(Cause::DirtyNoMemory)
+    }
+}
+
+/* <= the parts of a capsule's main RAM holding code, which only a capsule with W^X has, and whether each is writeable */
+fn code_segments(cid: CapsuleID) -> Vec<CodeSegment>
+{
+    match capsule::get_memory_mappings(cid)
+    {
+        Ok(mappings) => match mappings.first()
+        {
+            Some(ram) => ram.get_physical_segments().into_iter()
+                .filter_map(|(base, size, permissions)| match permissions
+                {
+                    AccessPermissions::ReadExecute => Some(CodeSegment { base, size, writeable: false }),
+                    AccessPermissions::ReadWriteExecute => Some(CodeSegment { base, size, writeable: true }),
+                    _ => None
+                })
+                .collect(),
+            None => Vec::new()
+        },
+        Err(_) => Vec::new()
+    }
+}
+
+/* start tracking changes to a capsule's RAM, or take a fresh checkpoint if already tracking it.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to track
+   <= size of its dirty log in bytes, or an error code */
+pub fn start(cid: CapsuleID) -> Result<usize, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    let (base, size) = main_ram(cid)?;
+    let code = code_segments(cid);
+
+    let mut logs = LOGS.lock();
+    let log = logs.entry(cid).or_insert(Log
+    {
+        base,
+        size,
+        bitmap: core::iter::repeat(0).take(bitmap_size(size)).collect(),
+        carried: core::iter::repeat(0).take(bitmap_size(size)).collect(),
+        clean: Vec::new(),
+        code: Vec::new()
+    });
+    log.code = code;
+    log.checkpoint(false);
+    drop(logs);
+
+    capsule::reenforce(cid);
+    Ok(bitmap_size(size))
+}
+
+/* stop tracking changes to a capsule's RAM.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to stop tracking
+   <= Ok for success, or an error code */
+pub fn stop(cid: CapsuleID) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match LOGS.lock().remove(&cid)
+    {
+        Some(_) => (),
+        None => return Err(Cause::DirtyNotTracking)
+    }
+
+    capsule::reenforce(cid);
+    Ok(())
+}
+
+/* copy a capsule's dirty log into the currently running capsule's memory and take a fresh checkpoint.
+   nothing is copied, nor the log cleared, if the buffer is too small, so call again with a buffer
+   at least as large as the size returned
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule whose log is wanted
+      buffer, size = address and size in bytes of the buffer in the running capsule
+   <= size of the dirty log in bytes, or an error code */
+pub fn read(cid: CapsuleID, buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+
+    let length = match LOGS.lock().get(&cid)
+    {
+        Some(log) => log.bitmap.len(),
+        None => return Err(Cause::DirtyNotTracking)
+    };
+
+    if size < length
+    {
+        return Ok(length);
+    }
+
+    /* look up the caller's buffer before taking the log lock, as this marks the buffer dirty
+       if the caller is tracking itself. don't lose any pages dirtied in the meantime */
+    let target = hcargs::buffer(caller, buffer, length, length, Access::Write)?;
+    let running = running_elsewhere(cid);
+    let code = code_segments(cid);
+    {
+        let mut logs = LOGS.lock();
+        let log = match logs.get_mut(&cid)
+        {
+            Some(log) => log,
+            None => return Err(Cause::DirtyNotTracking)
+        };
+
+        let target = unsafe { core::slice::from_raw_parts_mut(target as *mut u8, length) };
+        for (byte, (dirty, carried)) in target.iter_mut().zip(log.bitmap.iter().zip(log.carried.iter()))
+        {
+            *byte = dirty | carried;
+        }
+        log.code = code;
+        log.checkpoint(running);
+    }
+
+    capsule::reenforce(cid);
+    Ok(length)
+}
+
+/* mark part of a capsule's memory dirty because the hypervisor is accessing it on the
+   capsule's behalf, and so won't trip the write protection
+   => cid = capsule owning the memory
+      base, size = physical address and size in bytes of the memory */
+pub fn touched(cid: CapsuleID, base: PhysMemBase, size: PhysMemSize)
+{
+    if let Some(log) = LOGS.lock().get_mut(&cid)
+    {
+        /* ignore any part of the range outside the main RAM */
+        let start = core::cmp::max(base, log.base);
+        let end = core::cmp::min(base + size, log.base + log.size);
+        if start < end
+        {
+            log.mark(start, end - start);
+        }
+    }
+}
+
+/* write-protect the clean parts of a capsule's RAM. call this when switching to the capsule.
+   clean ranges that there aren't enough windows left for are given up and marked dirty
+   => cid = capsule about to run
+      window = first protection window free to use
+      executable = true if all of the capsule's RAM is executable, or false if it has W^X,
+                   in which case only the clean ranges holding its code are left executable
+   <= next protection window free to use */
+pub fn enforce(cid: CapsuleID, mut window: usize, executable: bool) -> usize
+{
+    if let Some(log) = LOGS.lock().get_mut(&cid)
+    {
+        log.fit(machine::PROTECTION_WINDOWS.saturating_sub(window));
+        for range in log.clean.iter()
+        {
+            let permissions = match executable || range.executable
+            {
+                true => AccessPermissions::ReadExecute,
+                false => AccessPermissions::Read
+            };
+
+            machine::protect_window(window, range.base, range.base + range.size, permissions);
+            window = window + 1;
+        }
+    }
+
+    /* close any windows left open by the previous capsule */
+    machine::clear_windows_from(window);
+    window
+}
+
+/* handle a write fault raised by the running capsule
+   => addr = physical address the capsule tried to write to
+   <= true if the fault was caused by dirty tracking and the write should be retried,
+      or false if it's a genuine fault */
+pub fn write_fault(addr: PhysMemBase) -> bool
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(c) => c,
+        None => return false
+    };
+
+    /* writes to code made read-only for W^X are genuine faults */
+    if capsule::is_read_only(cid, addr) == true
+    {
+        return false;
+    }
+
+    {
+        let mut logs = LOGS.lock();
+        let log = match logs.get_mut(&cid)
+        {
+            Some(log) => log,
+            None => return false
+        };
+
+        if addr < log.base || addr >= log.base + log.size
+        {
+            return false;
+        }
+
+        let page = addr & !(DIRTY_PAGE_SIZE - 1);
+        log.mark(page, DIRTY_PAGE_SIZE);
+
+        /* split the write-protected range either side of the page. another core may have got here
+           first, in which case the page is no longer write-protected and this core's windows are stale */
+        if let Some(index) = log.clean.iter().position(|range| page >= range.base && page < range.base + range.size)
+        {
+            let range = log.clean.remove(index);
+            if page > range.base
+            {
+                log.clean.push(CleanRange { base: range.base, size: page - range.base, ..range });
+            }
+            if page + DIRTY_PAGE_SIZE < range.base + range.size
+            {
+                let base = page + DIRTY_PAGE_SIZE;
+                log.clean.push(CleanRange { base, size: (range.base + range.size) - base, ..range });
+            }
+        }
+
+        /* give up the smallest clean ranges if there aren't enough windows to go round */
+        log.fit(CLEAN_RANGES_MAX);
+    }
+
+    /* drop the window over the page and retry the write. other cores running the capsule
+       pick up the change when they next fault on the page */
+    capsule::enforce(cid);
+    true
+}
+
+/* mark all of a capsule's RAM dirty when it's restarted, as its contents are reloaded */
+pub fn invalidate(cid: CapsuleID)
+{
+    if let Some(log) = LOGS.lock().get_mut(&cid)
+    {
+        log.invalidate();
+    }
+}
+
+/* stop tracking a capsule's RAM when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    LOGS.lock().remove(&cid);
+}
@@ -1,10 +1,142 @@
 /* diosix error codes
+ *
+ * Error codes are kept as simple values so that they can be matched on
+ * and passed around cheaply. Code that fails with details worth knowing,
+ * such as the address, size, or ID involved, can raise its error using
+ * hverror!(), which records those details, along with the subsystem and,
+ * if the errorlocation feature is enabled, the source file and line.
+ * The details are held per physical CPU core until the error is reported
+ * using report(), which prints the error code followed by its details
+ * if they were recorded for that error, or until the core next enters
+ * the hypervisor, whichever comes first.
  *
  * (c) Chris Williams, 2019-2021.
  *
  * See LICENSE for usage and copying.
  */
 
+use core::fmt::{self, Write};
+use core::mem::{discriminant, Discriminant};
+use super::pcore::PhysicalCore;
+
+/* raise an error code with details, evaluating to the error code, eg:
+   return Err(hverror!(Cause::PhysNotEnoughFreeRAM, "wanted {} bytes", size)); */
+macro_rules! hverror
+{
+    ($cause:expr, $($arg:tt)*) => ($crate::error::with_context($cause, module_path!(), format_args!($($arg)*), file!(), line!()));
+}
+
+/* most bytes of detail kept for an error. longer details are cut short */
+const DETAIL_MAX: usize = 128;
+
+/* details of the last error raised with context on a physical CPU core. these are held in a
+   fixed-size buffer in the core's own structure so raising an error never takes a lock or
+   allocates memory, as errors are raised on the paths capsules drive with their hypercalls */
+#[derive(Clone, Copy)]
+pub struct ErrorContext
+{
+    kind: Discriminant<Cause>,  /* the error these details belong to */
+    subsystem: &'static str,    /* module that raised the error */
+    detail: [u8; DETAIL_MAX],   /* parameters involved, as text */
+    detail_len: usize,          /* bytes of text in detail */
+    location: Option<(&'static str, u32)> /* source file and line, if enabled */
+}
+
+impl Write for ErrorContext
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            if self.detail_len == DETAIL_MAX
+            {
+                break;
+            }
+            self.detail[self.detail_len] = byte;
+            self.detail_len = self.detail_len + 1;
+        }
+        Ok(())
+    }
+}
+
+impl ErrorContext
+{
+    /* <= the details as text. a multi-byte character cut short at the end of the buffer is dropped */
+    fn detail(&self) -> &str
+    {
+        let bytes = &self.detail[..self.detail_len];
+        match core::str::from_utf8(bytes)
+        {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or("")
+        }
+    }
+}
+
+/* record the details of an error raised on this physical CPU core. use hverror!() rather than this directly
+   => cause = error being raised
+      subsystem = module raising the error
+      detail = description of the parameters involved
+      file, line = where in the source the error was raised
+   <= the error code, so it can be returned */
+pub fn with_context(cause: Cause, subsystem: &'static str, detail: fmt::Arguments, file: &'static str, line: u32) -> Cause
+{
+    let location = match cfg!(feature = "errorlocation")
+    {
+        true => Some((file, line)),
+        false => None
+    };
+
+    let mut context = ErrorContext { kind: discriminant(&cause), subsystem, detail: [0; DETAIL_MAX], detail_len: 0, location };
+    let _ = context.write_fmt(detail);
+    *(PhysicalCore::this().get_error_context()) = Some(context);
+    cause
+}
+
+/* discard the details of the last error raised on this physical CPU core. this is done on every
+   entry to the hypervisor so that details left over from an earlier error that was handled
+   without being reported can't be attached to a later error of the same kind */
+pub fn forget_context()
+{
+    *(PhysicalCore::this().get_error_context()) = None;
+}
+
+/* format an error code with any details recorded for it on this physical CPU core */
+pub struct Report<'a>(&'a Cause);
+
+/* describe an error for the hypervisor's log, eg: hvalert!("Failed: {}", error::report(&e)).
+   any details recorded for it are consumed */
+pub fn report(cause: &Cause) -> Report
+{
+    Report(cause)
+}
+
+impl fmt::Display for Report<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?}", self.0)?;
+
+        /* only print details if they were recorded for this kind of error, otherwise they're stale */
+        let slot = PhysicalCore::this().get_error_context();
+        match *slot
+        {
+            Some(context) if context.kind == discriminant(self.0) =>
+            {
+                *slot = None;
+                write!(f, " in {}: {}", context.subsystem, context.detail())?;
+                if let Some((file, line)) = context.location
+                {
+                    write!(f, " ({}:{})", file, line)?;
+                }
+            },
+            _ => ()
+        }
+
+        Ok(())
+    }
+}
+
 /* how things can go wrong */
 #[derive(Debug)]
 pub enum Cause
@@ -19,6 +151,22 @@ pub enum Cause
     DeviceTreeBad,
     CantCloneDevices,
     BootDeviceTreeBad,
+    DeviceTreeTooLarge,
+    DeviceTreeBadPlacement,
+    DeviceTreeOverlapsImage,
+
+    /* cache and memory bandwidth partitioning */
+    QoSNotSupported,
+    QoSBadShare,
+    QoSExhausted,
+    QoSControllerFailure,
+
+    /* device passthrough */
+    PassthroughDeviceNotFound,
+    PassthroughIRQInUse,
+    PassthroughIOMMUFailure,
+    PassthroughIRQRouteFailure,
+    PassthroughIRQNotOwned,
 
     /* physical CPU cores */
     PhysicalCoreBadID,
@@ -29,6 +177,12 @@ pub enum Cause
     ServiceAlreadyOwner,
     ServiceNotAllowed,
     ServiceNotFound,
+    ServiceAccessDenied,
+    ServiceBadName,
+
+    /* bulk data transfers */
+    TransferBadDescriptor,
+    TransferBadID,
 
     /* messages */
     MessageBadType,
@@ -39,6 +193,7 @@ pub enum Cause
     HeapNoFreeMem,
     HeapBadSize,
     HeapBadMagic,
+    HeapCorrupted,
 
     /* virtual core management */
     VirtualCoreBadID,
@@ -47,6 +202,7 @@ pub enum Cause
     /* host physical memory */
     PhysNoRAMFound,
     PhysNotEnoughFreeRAM,
+    PhysNotEnoughFreeDMARAM,
     PhysRegionTooSmall,
     PhysRegionCollision,
     PhysRegionNoMatch,
@@ -57,6 +213,8 @@ pub enum Cause
 
     /* capsule virtual memory */
     VirtMemPhysNotSet,
+    VirtMemTooManySegments,
+    VirtMemBadSegment,
 
     /* capsules */
     CapsuleIDExhaustion,
@@ -66,12 +224,27 @@ pub enum Cause
     CapsuleCantRestart,
     CapsuleBufferEmpty,
     CapsuleBufferWriteFailed,
+    CapsuleBufferFull,
     CapsuleMaxVCores,
     CapsuleBadPermissions,
     CapsulePropertyNotFound,
+    CapsulePropertyUnknown,
+    CapsulePropertyBadValue,
+    CapsulePropertyBadVersion,
+    CapsuleCantPause,
+    CapsuleNotPaused,
+    CapsuleNotRestarting,
+
+    /* hypercall ABI */
+    ABIVersionUnsupported,
+    ABIBadLeaf,
 
     /* scheduler and timer */
     SchedNoTimer,
+    SchedDeadlineBad,
+    SchedDeadlineRejected,
+    SchedTooManyTimers,
+    SchedBadTimer,
     
     /* supervisor binary loading */
     LoaderUnrecognizedCPUArch,
@@ -88,7 +261,163 @@ pub enum Cause
     LoaderSupervisorUnknownRelaType,
     LoaderBadEntry,
 
+    /* virtual serial link errors */
+    SerialLinkFull,
+    SerialLinkBadID,
+    SerialLinkNotConnected,
+    SerialLinkBufferFull,
+
+    /* device model plugin errors */
+    DeviceModelNotFound,
+    DeviceModelBadABI,
+    DeviceModelCreateFailed,
+    DeviceModelBadAccess,
+    DeviceAddressInUse,
+    DeviceIRQInUse,
+    DeviceBadBacking,
+
+    /* working set estimation errors */
+    WSSNotProfiled,
+    WSSNotReady,
+
+    /* failover errors */
+    FailoverStandbyExists,
+
+    /* warm reboot errors */
+    WarmRebootInProgress,
+
+    /* metrics errors */
+    MetricsBadCounter,
+
+    /* guest log errors */
+    GuestLogBadSeverity,
+    GuestLogTooLong,
+    GuestLogRateLimited,
+
+    /* guest panic report errors */
+    GuestPanicTooLong,
+    GuestPanicAlreadyReported,
+    GuestPanicNotFound,
+
     /* manifest errors */
     ManifestBadFS,
-    ManifestNoSuchAsset
+    ManifestNoSuchAsset,
+    ManifestNotExecutable,
+
+    /* DMA bounce buffer errors */
+    BounceNotNeeded,
+    BounceBadSize,
+    BounceBadDirection,
+    BounceTooMany,
+    BounceBadID,
+
+    /* administration command errors */
+    AdminBadCommand,
+
+    /* time service errors */
+    ClockNoTimer,
+    ClockNoWallClock,
+
+    /* host reset errors */
+    PowerResetInProgress,
+    PowerResetUnsupported,
+    PowerBadType,
+
+    /* GPIO errors */
+    GpioNoController,
+    GpioBadLine,
+    GpioLineInUse,
+    GpioBadDirection,
+    GpioFailed,
+
+    /* stream connection errors */
+    StreamBadID,
+    StreamNotListening,
+    StreamBacklogFull,
+    StreamTooMany,
+    StreamClosed,
+
+    /* memory grant errors */
+    GrantBadRange,
+    GrantBadGrantee,
+    GrantBadAccess,
+    GrantTooMany,
+    GrantBadID,
+
+    /* sealed secret errors */
+    SecretNotFound,
+    SecretNotMeasured,
+    SecretBadFormat,
+    SecretMeasurementMismatch,
+    SecretTampered,
+    SecretNoKey,
+
+    /* live settings errors */
+    SettingBadName,
+    SettingBadValue,
+    SettingNoStore,
+
+    /* crash dump errors */
+    CrashDumpNotFound,
+
+    /* paused capsule inspection errors */
+    InspectNoRegisters,
+
+    /* direct MMIO window errors */
+    MMIOBadRange,
+    MMIOInUse,
+    MMIOTooMany,
+    MMIONotMapped,
+
+    /* dirty page tracking errors */
+    DirtyNotTracking,
+    DirtyNoMemory,
+
+    /* capsule template errors */
+    TemplateNotFound,
+    TemplateInUse,
+    TemplateNotClonable,
+    TemplateBadEntry,
+
+    /* guest quiesce errors */
+    QuiesceNotRunning,
+    QuiesceInProgress,
+    QuiesceNotRequested,
+
+    /* virtual button errors */
+    ButtonBadType,
+    ButtonNotRunning,
+
+    /* virtio queue errors */
+    VirtqueueBadLayout,
+    VirtqueueBadDescriptor,
+    VirtqueueBadIndex
+}
+
+/* convert an error from the hypervisor's core algorithms into an error code */
+impl From<hvalgo::Error> for Cause
+{
+    fn from(error: hvalgo::Error) -> Cause
+    {
+        match error
+        {
+            hvalgo::Error::RegionNoMatch => Cause::PhysRegionNoMatch,
+            hvalgo::Error::RegionCollision => Cause::PhysRegionCollision,
+            hvalgo::Error::HeapBadSize => Cause::HeapBadSize,
+            hvalgo::Error::HeapNoFreeMem => Cause::HeapNoFreeMem,
+            hvalgo::Error::HeapNotInUse => Cause::HeapNotInUse,
+            hvalgo::Error::HeapBadMagic => Cause::HeapBadMagic,
+            hvalgo::Error::HeapCorrupted => Cause::HeapCorrupted,
+            hvalgo::Error::VirtqueueBadLayout => Cause::VirtqueueBadLayout,
+            hvalgo::Error::VirtqueueBadDescriptor => Cause::VirtqueueBadDescriptor,
+            hvalgo::Error::VirtqueueBadIndex => Cause::VirtqueueBadIndex,
+
+            /* the hypercalls' error mappers treat a bad buffer of any sort as bad parameters */
+            hvalgo::Error::ArgMissing | hvalgo::Error::ArgBadValue | hvalgo::Error::ArgBadLength |
+            hvalgo::Error::ArgBadPointer | hvalgo::Error::ArgMisaligned | hvalgo::Error::ArgReadOnly => Cause::TransferBadDescriptor,
+
+            hvalgo::Error::DeviceTreeBadHeader | hvalgo::Error::DeviceTreeBadStructure |
+            hvalgo::Error::DeviceTreeBadCells => Cause::DeviceTreeBad
+        }
+    }
 }
@@ -0,0 +1,109 @@
+/* diosix failover pairs for critical system services
+ *
+ * A system service in the manifest can be declared the standby for
+ * a service type using the standby_for=type property, eg:
+ * standby_for=console. Standby services aren't started at boot.
+ * Instead, if the capsule running that service type crashes and its
+ * crash policy lets it die rather than restart or pause, the standby
+ * is started and handed the dead capsule's registration for the
+ * service, including any messages queued for it.
+ *
+ * Capsules that may use the service are then sent
+ * VIRQ_SERVICE_FAILOVER so they can reselect the service and
+ * resynchronize with its new owner. This virtual interrupt is also
+ * described in each capsule's device tree.
+ *
+ * Each standby can be promoted once.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID};
+use super::service::{self, ServiceType};
+use super::passthrough::{self, DeviceIRQ};
+use super::manifest;
+
+/* virtual interrupt raised when a service a capsule can use has moved to a standby capsule */
+pub const VIRQ_SERVICE_FAILOVER: DeviceIRQ = hypercall::irq::VIRQ_SERVICE_FAILOVER;
+
+lazy_static!
+{
+    /* names of the manifest assets to start when the owner of each service type dies */
+    static ref STANDBYS: Mutex<HashMap<ServiceType, String>> = Mutex::new("failover standby table", HashMap::new());
+}
+
+/* hold back a system service from the manifest as the standby for a service type
+   => stype = service the standby takes over
+      name = name of the standby's asset in the DMFS image
+   <= Ok for success, or an error code if the service already has a standby */
+pub fn add_standby(stype: ServiceType, name: &str) -> Result<(), Cause>
+{
+    let mut standbys = STANDBYS.lock();
+    if standbys.contains_key(&stype)
+    {
+        return Err(hverror!(Cause::FailoverStandbyExists, "{:?} service already has standby {}", stype, name));
+    }
+
+    standbys.insert(stype, String::from(name));
+    Ok(())
+}
+
+/* forget all standbys, such as during a warm reboot when the manifest will declare them again */
+pub fn reset()
+{
+    STANDBYS.lock().clear();
+}
+
+/* promote the standbys for any services run by a capsule that's about to die.
+   call this before the capsule is destroyed so that its services are still registered
+   => failed = ID of the dying capsule */
+pub fn promote(failed: CapsuleID)
+{
+    for stype in service::owned_by(failed)
+    {
+        /* each standby is promoted just once */
+        let name = match STANDBYS.lock().remove(&stype)
+        {
+            Some(n) => n,
+            None => continue
+        };
+
+        let standby = match manifest::start_service(&name)
+        {
+            Ok(cid) => cid,
+            Err(_e) =>
+            {
+                hvalert!("Can't start standby {} for {:?} service: {}", name, stype, error::report(&_e));
+                continue;
+            }
+        };
+
+        if let Err(_e) = service::transfer(stype, failed, standby)
+        {
+            hvalert!("Can't hand {:?} service to standby {} (capsule {}): {}", stype, name, standby, error::report(&_e));
+            continue;
+        }
+
+        hvalert!("Capsule {} failed over its {:?} service to standby {} (capsule {})", failed, stype, name, standby);
+        notify_clients(stype, failed, standby);
+    }
+}
+
+/* tell every other capsule that can use the given service that it has a new owner */
+fn notify_clients(stype: ServiceType, failed: CapsuleID, standby: CapsuleID)
+{
+    for summary in capsule::snapshot()
+    {
+        let cid = summary.id();
+        if cid != failed && cid != standby && service::check_access(stype, cid).is_ok()
+        {
+            passthrough::raise_virtual_irq(cid, VIRQ_SERVICE_FAILOVER);
+        }
+    }
+}
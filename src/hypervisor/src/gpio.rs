@@ -0,0 +1,149 @@
+/* diosix paravirtualized GPIO for capsules
+ *
+ * Many embedded capsules just need to blink an LED or read a button.
+ * Rather than hand a capsule the whole GPIO controller, and with it
+ * every other line on the board, each capsule is given only the lines
+ * declared for it in the manifest with the gpio=N property, where N is
+ * the host's line number on the controller described in the device tree.
+ *
+ * A capsule's lines are numbered from zero in the order they were
+ * declared, and it sets each line's direction and reads or drives its
+ * level using hypercalls. No line can be given to more than one capsule.
+ * Lines start as inputs, and are returned to being inputs when their
+ * capsule is destroyed, so that nothing is left driven. The number of
+ * lines a capsule has is described in its device tree.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::hardware;
+use super::pcore;
+
+/* whether a line is read or driven, shared with the capsules */
+pub use hypercall::gpio::Direction;
+
+lazy_static!
+{
+    /* host GPIO line numbers given to each capsule, indexed by the capsule's own line numbers */
+    static ref LINES: Mutex<HashMap<CapsuleID, Vec<usize>>> = Mutex::new("capsule GPIO lines", HashMap::new());
+}
+
+/* give a capsule the host GPIO lines declared for it in the manifest
+   => cid = capsule to receive the lines
+      lines = host line numbers, in the order the capsule will number them
+   <= Ok for success, or an error code if a line doesn't exist or is already taken */
+pub fn attach(cid: CapsuleID, lines: Vec<usize>) -> Result<(), Cause>
+{
+    if lines.len() == 0
+    {
+        return Ok(());
+    }
+
+    let available = match hardware::get_gpio_line_count()
+    {
+        Some(count) => count,
+        None => return Err(Cause::GpioNoController)
+    };
+
+    let mut owners = LINES.lock();
+    for (index, line) in lines.iter().enumerate()
+    {
+        if *line >= available || lines[..index].contains(line)
+        {
+            return Err(hverror!(Cause::GpioBadLine, "capsule {} asked for GPIO line {} of {}", cid, line, available));
+        }
+
+        if owners.iter().any(|(owner, taken)| *owner != cid && taken.contains(line))
+        {
+            return Err(hverror!(Cause::GpioLineInUse, "capsule {} asked for GPIO line {}", cid, line));
+        }
+    }
+
+    for line in &lines
+    {
+        hardware::gpio_set_direction(*line, Direction::Input)?;
+    }
+
+    owners.insert(cid, lines);
+    Ok(())
+}
+
+/* take back a capsule's GPIO lines when it's destroyed, leaving them as inputs */
+pub fn detach(cid: CapsuleID)
+{
+    if let Some(lines) = LINES.lock().remove(&cid)
+    {
+        for line in lines
+        {
+            if let Err(_e) = hardware::gpio_set_direction(line, Direction::Input)
+            {
+                hvdebug!("Couldn't reset GPIO line {} released by capsule {}: {:?}", line, cid, _e);
+            }
+        }
+    }
+}
+
+/* <= number of GPIO lines the given capsule has */
+pub fn count(cid: CapsuleID) -> usize
+{
+    match LINES.lock().get(&cid)
+    {
+        Some(lines) => lines.len(),
+        None => 0
+    }
+}
+
+/* find the host line behind one of the currently running capsule's lines
+   => line = capsule's line number
+   <= host line number, or an error code */
+fn host_line(line: usize) -> Result<usize, Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    match LINES.lock().get(&cid)
+    {
+        Some(lines) => match lines.get(line)
+        {
+            Some(host) => Ok(*host),
+            None => Err(Cause::GpioBadLine)
+        },
+        None => Err(Cause::GpioBadLine)
+    }
+}
+
+/* make one of the currently running capsule's lines an input or an output
+   => line = capsule's line number
+      direction = whether the line is to be read or driven
+   <= Ok for success, or an error code */
+pub fn set_direction(line: usize, direction: Direction) -> Result<(), Cause>
+{
+    hardware::gpio_set_direction(host_line(line)?, direction)
+}
+
+/* drive one of the currently running capsule's output lines high or low
+   => line = capsule's line number
+      value = true for high, false for low
+   <= Ok for success, or an error code */
+pub fn write(line: usize, value: bool) -> Result<(), Cause>
+{
+    hardware::gpio_write(host_line(line)?, value)
+}
+
+/* read the level of one of the currently running capsule's lines
+   => line = capsule's line number
+   <= true for high, false for low, or an error code */
+pub fn read(line: usize) -> Result<bool, Cause>
+{
+    hardware::gpio_read(host_line(line)?)
+}
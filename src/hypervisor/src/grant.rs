@@ -0,0 +1,319 @@
+/* diosix memory grants between capsules and to the hypervisor
+ *
+ * A capsule can grant part of its own memory to another capsule, or to
+ * the hypervisor, for a specific purpose, such as an I/O buffer shared
+ * with a driver capsule. Unlike bulk transfers, which copy, the grantee
+ * reaches into the granter's memory directly, so every grant is tracked
+ * by the hypervisor and is only ever as wide and as long-lived as the
+ * granter allows.
+ *
+ * The granter picks a page-aligned range of its memory, the grantee, and
+ * whether the grantee can write to it, and is given a handle to pass to
+ * the grantee by some other means, such as a stream connection. A grantee
+ * capsule accepts the grant with the handle to have the range opened up
+ * in its protection windows, at the same address. Grants to the
+ * hypervisor need no accepting: hypervisor code looks up the grant by
+ * its handle each time it uses the memory.
+ *
+ * The granter can revoke a grant at any time, and a grantee capsule can
+ * release one it no longer needs. Grants made by or to a capsule are
+ * revoked automatically when it's destroyed or restarted, so a grantee
+ * can never reach memory the granter has given up. A grantee capsule is
+ * sent VIRQ_GRANT_REVOKED when a grant it accepted is revoked, and every
+ * physical core running the grantee is told to close the range.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID};
+use super::pcore;
+use super::hcargs;
+use super::passthrough::{self, DeviceIRQ};
+use super::machine;
+
+/* what a grantee can do with granted memory, shared with the capsules */
+pub use hypercall::grant::{Access, GRANTEE_HYPERVISOR, GRANT_ALIGNMENT};
+
+/* virtual interrupt raised when a grant a capsule accepted is revoked */
+pub const VIRQ_GRANT_REVOKED: DeviceIRQ = hypercall::irq::VIRQ_GRANT_REVOKED;
+
+pub type GrantID = usize;
+
+/* most grants a capsule can have made at any one time */
+const GRANTS_MAX: usize = 16;
+
+/* most grants a capsule can have accepted at any one time. each takes a protection window */
+const ACCEPTED_MAX: usize = 4;
+
+/* who can use the granted memory */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Grantee
+{
+    Capsule(CapsuleID),
+    Hypervisor
+}
+
+/* part of a capsule's memory granted to another */
+struct Grant
+{
+    granter: CapsuleID,
+    grantee: Grantee,
+    base: PhysMemBase,
+    size: PhysMemSize,
+    access: Access,
+    accepted: bool      /* set once a grantee capsule has opened the memory */
+}
+
+impl Grant
+{
+    /* <= the capsule that accepted this grant, if any */
+    fn accepted_by(&self) -> Option<CapsuleID>
+    {
+        match (self.grantee, self.accepted)
+        {
+            (Grantee::Capsule(cid), true) => Some(cid),
+            (_, _) => None
+        }
+    }
+}
+
+lazy_static!
+{
+    static ref GRANTS: Mutex<HashMap<GrantID, Grant>> = Mutex::new("memory grants", HashMap::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/* return the ID of the currently running capsule, or an error code */
+fn current_capsule() -> Result<CapsuleID, Cause>
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* tell grantee capsules they've lost the grants they accepted
+   => grantees = capsules to tell */
+fn notify_revoked(grantees: &Vec<CapsuleID>)
+{
+    for cid in grantees
+    {
+        passthrough::raise_virtual_irq(*cid, VIRQ_GRANT_REVOKED);
+    }
+}
+
+/* tell grantee capsules they've lost the grants they accepted, and close them off */
+fn revoked(grantees: Vec<CapsuleID>)
+{
+    notify_revoked(&grantees);
+    for cid in grantees
+    {
+        capsule::reenforce(cid);
+    }
+}
+
+/* grant part of the currently running capsule's memory to another capsule or the hypervisor
+   => addr, size = page-aligned address and size in bytes of the memory in the capsule
+      grantee = ID of the capsule to grant the memory to, or GRANTEE_HYPERVISOR
+      access = whether the grantee can write to the memory
+   <= ID of the grant to pass to the grantee, or an error code */
+pub fn create(addr: usize, size: PhysMemSize, grantee: usize, access: Access) -> Result<GrantID, Cause>
+{
+    let granter = current_capsule()?;
+
+    if size == 0 || addr % GRANT_ALIGNMENT != 0 || size % GRANT_ALIGNMENT != 0
+    {
+        return Err(Cause::GrantBadRange);
+    }
+
+    let grantee = match grantee
+    {
+        GRANTEE_HYPERVISOR => Grantee::Hypervisor,
+        cid if cid == granter => return Err(Cause::GrantBadGrantee),
+        cid => match capsule::get_state(cid)
+        {
+            Some(_) => Grantee::Capsule(cid),
+            None => return Err(Cause::GrantBadGrantee)
+        }
+    };
+
+    /* check the memory belongs to the granter, and that it can write to it if it's letting others do so,
+       before taking the grants lock */
+    let checked = match access
+    {
+        Access::ReadOnly => hcargs::Access::Read,
+        Access::ReadWrite => hcargs::Access::Write
+    };
+    let base = hcargs::buffer(granter, addr, size, usize::MAX, checked)?;
+
+    let mut grants = GRANTS.lock();
+    if grants.values().filter(|grant| grant.granter == granter).count() >= GRANTS_MAX
+    {
+        return Err(Cause::GrantTooMany);
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    grants.insert(id, Grant { granter, grantee, base, size, access, accepted: false });
+    Ok(id)
+}
+
+/* open up memory granted to the currently running capsule
+   => id = grant made to the capsule
+   <= physical address and size in bytes of the granted memory, or an error code */
+pub fn accept(id: GrantID) -> Result<(PhysMemBase, PhysMemSize), Cause>
+{
+    let cid = current_capsule()?;
+
+    let range =
+    {
+        let mut grants = GRANTS.lock();
+        let accepted = grants.values().filter(|grant| grant.accepted_by() == Some(cid)).count();
+
+        match grants.get_mut(&id)
+        {
+            Some(grant) if grant.grantee == Grantee::Capsule(cid) =>
+            {
+                if grant.accepted == false
+                {
+                    if accepted >= ACCEPTED_MAX
+                    {
+                        return Err(Cause::GrantTooMany);
+                    }
+                    grant.accepted = true;
+                }
+                (grant.base, grant.size)
+            },
+            _ => return Err(Cause::GrantBadID)
+        }
+    };
+
+    /* don't hold the grants lock while reapplying the capsule's protection, which takes
+       the capsules lock and then the grants lock */
+    capsule::reenforce(cid);
+    Ok(range)
+}
+
+/* give up memory granted to the currently running capsule
+   => id = grant made to the capsule
+   <= Ok for success, or an error code */
+pub fn release(id: GrantID) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+
+    let accepted = match GRANTS.lock().remove(&id)
+    {
+        Some(grant) if grant.grantee == Grantee::Capsule(cid) => grant.accepted,
+        Some(grant) =>
+        {
+            /* not the caller's to release, so put it back */
+            GRANTS.lock().insert(id, grant);
+            return Err(Cause::GrantBadID);
+        },
+        None => return Err(Cause::GrantBadID)
+    };
+
+    if accepted == true
+    {
+        capsule::reenforce(cid);
+    }
+    Ok(())
+}
+
+/* withdraw memory the currently running capsule granted
+   => id = grant made by the capsule
+   <= Ok for success, or an error code */
+pub fn revoke(id: GrantID) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+
+    let grantee = match GRANTS.lock().remove(&id)
+    {
+        Some(grant) if grant.granter == cid => grant.accepted_by(),
+        Some(grant) =>
+        {
+            /* not the caller's to revoke, so put it back */
+            GRANTS.lock().insert(id, grant);
+            return Err(Cause::GrantBadID);
+        },
+        None => return Err(Cause::GrantBadID)
+    };
+
+    revoked(grantee.into_iter().collect());
+    Ok(())
+}
+
+/* look up memory granted to the hypervisor. the grant can be revoked at any time,
+   so look it up again each time the memory is used, and don't hold on to the address
+   => id = grant made to the hypervisor
+      granter = capsule expected to have made the grant
+   <= physical address and size in bytes of the granted memory, and what can be done with it, or an error code */
+pub fn hypervisor_buffer(id: GrantID, granter: CapsuleID) -> Result<(PhysMemBase, PhysMemSize, Access), Cause>
+{
+    match GRANTS.lock().get(&id)
+    {
+        Some(grant) if grant.granter == granter && grant.grantee == Grantee::Hypervisor => Ok((grant.base, grant.size, grant.access)),
+        _ => Err(Cause::GrantBadID)
+    }
+}
+
+/* open up the memory granted to and accepted by a capsule. call this when switching to the capsule
+   => cid = capsule about to run
+      window = first protection window free to use
+   <= next protection window free to use */
+pub fn enforce(cid: CapsuleID, mut window: usize) -> usize
+{
+    for grant in GRANTS.lock().values().filter(|grant| grant.accepted_by() == Some(cid))
+    {
+        let permissions = match grant.access
+        {
+            Access::ReadOnly => AccessPermissions::Read,
+            Access::ReadWrite => AccessPermissions::ReadWrite
+        };
+
+        machine::protect_window(window, grant.base, grant.base + grant.size, permissions);
+        window = window + 1;
+    }
+
+    /* close any windows left open by the previous capsule */
+    machine::clear_windows_from(window);
+    window
+}
+
+/* revoke all grants made by or to a capsule when it's destroyed or restarted.
+   this is called with the capsule table locked, so closing off the grantees'
+   access is left to the caller, which must pass each returned capsule to
+   capsule::reenforce() once it has released that lock
+   => cid = capsule being destroyed or restarted
+   <= capsules that have lost grants they accepted */
+pub fn forget(cid: CapsuleID) -> Vec<CapsuleID>
+{
+    let mut grantees = Vec::new();
+    GRANTS.lock().retain(|_, grant| match (grant.granter == cid, grant.grantee == Grantee::Capsule(cid))
+    {
+        (true, _) =>
+        {
+            if let Some(grantee) = grant.accepted_by()
+            {
+                grantees.push(grantee);
+            }
+            false
+        },
+        (false, true) => false,
+        (false, false) => true
+    });
+
+    grantees.sort();
+    grantees.dedup();
+    notify_revoked(&grantees);
+    grantees
+}
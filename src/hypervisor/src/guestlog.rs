@@ -0,0 +1,185 @@
+/* diosix structured log records from capsules
+ *
+ * Capsules can emit log records, each with a severity, a short subsystem
+ * tag, and a message, into the hypervisor's own log stream. Records are
+ * prefixed with the capsule's ID so that one console shows an interleaved,
+ * attributed view of host and guest diagnostics.
+ *
+ * The subsystem tag is passed in a single register, packed as up to one
+ * machine word of ASCII characters, lowest byte first, padded with zeroes.
+ * The message is passed by address and length in the capsule's memory.
+ *
+ * Each capsule is rate limited so that a chatty or misbehaving guest
+ * can't drown out everyone else. Records over the limit are dropped and
+ * counted, and the count is logged when the capsule is next allowed in.
+ *
+ * Records less important than the log level, which can be changed at
+ * run time, are discarded. By default, every record is kept.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::scheduler;
+use super::pcore;
+use super::hcargs::{self, Access};
+
+/* longest message accepted in a single record, in bytes */
+const MESSAGE_MAX_LEN: usize = 256;
+
+/* each capsule may emit bursts of up to LOG_BURST_MAX records,
+   and is then allowed LOG_REFILL_PER_SECOND more records every second */
+const LOG_BURST_MAX: u64 = 32;
+const LOG_REFILL_PER_SECOND: u64 = 16;
+
+/* how important a record is. the values are shared with the services */
+pub use hypercall::guestlog::Severity;
+
+/* least important severity of record kept, as a Severity value */
+static LEVEL: AtomicUsize = AtomicUsize::new(Severity::Debug as usize);
+
+/* change the least important severity of record kept in the log
+   => level = records less important than this are discarded */
+pub fn set_level(level: Severity)
+{
+    LEVEL.store(level as usize, Ordering::SeqCst);
+}
+
+/* return the marker used in the log for the given severity */
+fn marker(severity: Severity) -> char
+{
+    match severity
+    {
+        Severity::Error => 'E',
+        Severity::Warning => 'W',
+        Severity::Info => 'I',
+        Severity::Debug => 'D'
+    }
+}
+
+/* track each capsule's allowance of log records */
+struct Allowance
+{
+    tokens: u64,    /* records the capsule can emit right now */
+    last_refill: u64, /* timer value when the allowance was last topped up */
+    dropped: usize  /* records dropped since the capsule was last allowed in */
+}
+
+lazy_static!
+{
+    static ref ALLOWANCES: Mutex<HashMap<CapsuleID, Allowance>> = Mutex::new("guest log allowances", HashMap::new());
+}
+
+/* unpack a subsystem tag from a machine word, keeping only printable characters */
+fn unpack_tag(tag: usize) -> String
+{
+    let mut text = String::new();
+    for byte in tag.to_le_bytes().iter()
+    {
+        match *byte
+        {
+            0 => break,
+            b if b.is_ascii_graphic() => text.push(b as char),
+            _ => text.push('?')
+        }
+    }
+    text
+}
+
+/* check the given capsule is allowed to emit another record, topping up its allowance as time passes
+   <= Ok with the number of records dropped since it was last allowed in, or an error code if it's over its limit */
+fn take_allowance(cid: CapsuleID) -> Result<usize, Cause>
+{
+    let (now, frequency) = match scheduler::timer_now()
+    {
+        Some(t) => t,
+        None => (0, 0)
+    };
+
+    let mut allowances = ALLOWANCES.lock();
+    let allowance = allowances.entry(cid).or_insert(Allowance { tokens: LOG_BURST_MAX, last_refill: now, dropped: 0 });
+
+    if frequency > 0
+    {
+        let earned = ((now - allowance.last_refill) * LOG_REFILL_PER_SECOND) / frequency;
+        if earned > 0
+        {
+            allowance.tokens = core::cmp::min(LOG_BURST_MAX, allowance.tokens + earned);
+            allowance.last_refill = now;
+        }
+    }
+
+    if allowance.tokens == 0
+    {
+        allowance.dropped = allowance.dropped + 1;
+        return Err(Cause::GuestLogRateLimited);
+    }
+
+    allowance.tokens = allowance.tokens - 1;
+    let dropped = allowance.dropped;
+    allowance.dropped = 0;
+    Ok(dropped)
+}
+
+/* write a structured log record from the currently running capsule into the hypervisor's log
+   => severity = how important the record is
+      tag = subsystem tag packed into a machine word
+      message, length = address and size in bytes of the message text in the capsule
+   <= Ok for success, or an error code */
+pub fn write(severity: Severity, tag: usize, message: usize, length: usize) -> Result<(), Cause>
+{
+    let _tag = heaptag!();
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    if length > MESSAGE_MAX_LEN
+    {
+        return Err(Cause::GuestLogTooLong);
+    }
+
+    /* read the message before charging the capsule's allowance */
+    let text = match length
+    {
+        0 => String::new(),
+        _ =>
+        {
+            let base = hcargs::buffer(cid, message, length, MESSAGE_MAX_LEN, Access::Read)?;
+            let bytes = unsafe { slice::from_raw_parts(base as *const u8, length) };
+
+            /* don't let guests move the cursor or otherwise upset the console */
+            String::from_utf8_lossy(bytes).chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+        }
+    };
+
+    /* quietly discard records the administrator isn't interested in */
+    if severity as usize > LEVEL.load(Ordering::SeqCst)
+    {
+        return Ok(());
+    }
+
+    let dropped = take_allowance(cid)?;
+    if dropped > 0
+    {
+        hvprintln!("[W] capsule {}: log: {} records dropped", cid, dropped);
+    }
+
+    hvprintln!("[{}] capsule {}: {}: {}", marker(severity), cid, unpack_tag(tag), text);
+    Ok(())
+}
+
+/* forget a capsule's log allowance when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    ALLOWANCES.lock().remove(&cid);
+}
@@ -0,0 +1,184 @@
+/* diosix guest panic reports
+ *
+ * A guest kernel that's about to die can report why using a hypercall,
+ * passing a panic message and, optionally, a dump of its registers as
+ * an array of machine words. The report is written to the hypervisor's
+ * log, and appended to the capsule's console output so that the console
+ * service shows it alongside the rest of the capsule's text. This means
+ * operators can see why a guest died even if its console was quiet.
+ *
+ * The report is kept with the capsule's crash record: management
+ * services can read it while the capsule is paused for inspection, and
+ * after the capsule is restarted, until the capsule is destroyed. Only
+ * one report is accepted each time the capsule runs, so a looping guest
+ * can't flood the log. A report made after a restart replaces the last.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::pcore;
+use super::hcargs::{self, Access};
+
+/* longest panic message accepted, in bytes */
+const PANIC_MESSAGE_MAX_LEN: usize = 512;
+
+/* most registers accepted in a dump */
+const PANIC_REGISTERS_MAX: usize = 64;
+
+/* a capsule's last panic report */
+struct Report
+{
+    message: String,
+    registers: Vec<usize>,
+    current: bool           /* true if made since the capsule last (re)started */
+}
+
+lazy_static!
+{
+    static ref REPORTS: Mutex<HashMap<CapsuleID, Report>> = Mutex::new("guest panic reports", HashMap::new());
+}
+
+/* accept a panic report from the currently running capsule
+   => message, length = address and size in bytes of the panic message in the capsule
+      registers, count = address and number of machine words of the register dump in the capsule,
+                         or a count of zero for no dump
+   <= Ok for success, or an error code */
+pub fn report(message: usize, length: usize, registers: usize, count: usize) -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    if length > PANIC_MESSAGE_MAX_LEN || count > PANIC_REGISTERS_MAX
+    {
+        return Err(Cause::GuestPanicTooLong);
+    }
+
+    if let Some(previous) = REPORTS.lock().get(&cid)
+    {
+        if previous.current == true
+        {
+            return Err(Cause::GuestPanicAlreadyReported);
+        }
+    }
+
+    let text: String = match length
+    {
+        0 => String::new(),
+        _ =>
+        {
+            let base = hcargs::buffer(cid, message, length, PANIC_MESSAGE_MAX_LEN, Access::Read)?;
+            let bytes = unsafe { slice::from_raw_parts(base as *const u8, length) };
+
+            /* don't let guests move the cursor or otherwise upset the console */
+            String::from_utf8_lossy(bytes).chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+        }
+    };
+
+    let dump = match count
+    {
+        0 => Vec::new(),
+        _ =>
+        {
+            let base = hcargs::array::<usize>(cid, registers, count, PANIC_REGISTERS_MAX, Access::Read)?;
+            unsafe { slice::from_raw_parts(base as *const usize, count) }.to_vec()
+        }
+    };
+
+    hvalert!("Capsule {} panicked: {}", cid, text);
+    for (row, words) in dump.chunks(4).enumerate()
+    {
+        let mut line = String::new();
+        for (column, word) in words.iter().enumerate()
+        {
+            line.push_str(&format!(" r{:02}=0x{:016x}", (row * 4) + column, word));
+        }
+        hvprintln!("[!] capsule {}:{}", cid, line);
+    }
+
+    /* pass the message on to the console service too */
+    capsule::append_output(cid, format!("\nPanic reported to hypervisor: {}\n", text).as_bytes());
+
+    REPORTS.lock().insert(cid, Report { message: text, registers: dump, current: true });
+    Ok(())
+}
+
+/* copy a capsule's last panic message into the currently running capsule's memory.
+   the message is truncated if the buffer is too small.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule whose report is wanted
+      buffer, size = address and size in bytes of the buffer to fill in the running capsule
+   <= full length of the message in bytes, or an error code if there's no report */
+pub fn read_message(cid: CapsuleID, buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+
+    /* don't hold the reports lock while looking up the caller's memory */
+    let message = match REPORTS.lock().get(&cid)
+    {
+        Some(report) => report.message.clone(),
+        None => return Err(Cause::GuestPanicNotFound)
+    };
+
+    let to_copy = core::cmp::min(size, message.len());
+    if to_copy > 0
+    {
+        let base = hcargs::buffer(caller, buffer, to_copy, message.len(), Access::Write)?;
+        let target = unsafe { slice::from_raw_parts_mut(base as *mut u8, to_copy) };
+        target.copy_from_slice(&message.as_bytes()[..to_copy]);
+    }
+
+    Ok(message.len())
+}
+
+/* copy a capsule's last panic register dump into the currently running capsule's memory.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule whose report is wanted
+      buffer, count = address and size in machine words of the array to fill in the running capsule
+   <= number of registers in the dump, or an error code if there's no report */
+pub fn read_registers(cid: CapsuleID, buffer: usize, count: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+
+    let registers = match REPORTS.lock().get(&cid)
+    {
+        Some(report) => report.registers.clone(),
+        None => return Err(Cause::GuestPanicNotFound)
+    };
+
+    let to_copy = core::cmp::min(count, registers.len());
+    if to_copy > 0
+    {
+        let base = hcargs::array::<usize>(caller, buffer, to_copy, registers.len(), Access::Write)?;
+        let target = unsafe { slice::from_raw_parts_mut(base as *mut usize, to_copy) };
+        target.copy_from_slice(&registers[..to_copy]);
+    }
+
+    Ok(registers.len())
+}
+
+/* allow a restarted capsule to report a panic again, keeping its last report until it does */
+pub fn rearm(cid: CapsuleID)
+{
+    if let Some(report) = REPORTS.lock().get_mut(&cid)
+    {
+        report.current = false;
+    }
+}
+
+/* discard a capsule's panic report when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    REPORTS.lock().remove(&cid);
+}
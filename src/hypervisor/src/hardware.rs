@@ -5,40 +5,472 @@
  * See LICENSE for usage and copying.
  */
 
+use core::sync::atomic::{AtomicBool, Ordering};
 use alloc::vec::Vec;
+use alloc::string::String;
 use super::lock::Mutex;
 use platform::devices::Devices;
 use platform::physmem::{PhysMemBase, PhysMemSize};
 use platform::timer;
+use hvalgo::fdt::{Fdt, Node};
 use super::error::Cause;
+use super::pcore::{self, PhysicalCoreID, HartID};
+use super::plic;
+use super::clint;
+use super::cbqri;
+use super::iommu;
+use super::machine;
+use super::jh7110;
+use super::hostrtc;
+use super::syscon;
+use super::hostgpio;
 
 lazy_static!
 {
     /* acquire HARDWARE before accessing any system hardware */
     static ref HARDWARE: Mutex<Option<Devices>> = Mutex::new("hardware management", None);
+
+    /* a copy of the host's device tree blob, for the details the platform code doesn't describe */
+    static ref HOST_DT: Mutex<Option<Vec<u8>>> = Mutex::new("host device tree", None);
+
+    /* indexes of the serial ports taken from the hypervisor to pass through to capsules */
+    static ref CLAIMED_SERIAL_PORTS: Mutex<Vec<usize>> = Mutex::new("claimed serial ports", Vec::new());
 }
 
+/* set if every CPU core has a Zkr entropy source to read random numbers from */
+static ENTROPY_SOURCE: AtomicBool = AtomicBool::new(false);
+
+/* compatible strings of the serial ports that can be passed through to capsules */
+const SERIAL_COMPATIBLE: [&str; 4] = [ "ns16550a", "ns16550", "snps,dw-apb-uart", "sifive,uart0" ];
+
+/* compatible strings of the reserved memory the persistent alert store can use */
+const PSTORE_COMPATIBLE: [&str; 2] = [ "diosix,pstore", "ramoops" ];
+
+/* property of the host's /chosen node holding the key for unsealing secrets, and its size in bytes */
+const SEALING_KEY_PROPERTY: &str = "diosix,sealing-key";
+const SEALING_KEY_SIZE: usize = 32;
+
+/* top-level nodes whose children describe CPU cores or memory rather than peripherals */
+const PERIPHERAL_EXCLUDED_NODES: [&str; 3] = [ "cpus", "memory", "reserved-memory" ];
+
+/* most device tree problems to list individually during partial bring-up */
+const DT_PROBLEMS_LISTED: usize = 16;
+
 /* parse_and_init
    Parse a device tree structure to create a base set of hardware devices.
    also initialize the devices so they can be used.
    call before using acquire_hardware_lock() to access HARDWARE.
+   vendor device trees aren't always well formed, so if the tree can't be
+   parsed in full, bring up whatever hardware was understood and report
+   the nodes that were skipped. if none of it can be understood, fall back
+   to a debug UART and timer at the platform's well-known addresses
    => device_tree = byte slice containing the device tree in physical memory
    <= return Ok for success, or error code on failure
 */
 pub fn parse_and_init(dtb: &[u8]) -> Result<(), Cause>
 {
-    match Devices::new(dtb)
+    /* blob is whichever version of the tree the devices were brought up from */
+    let (devices, blob) = match Devices::new(dtb)
     {
-        Ok(dt) => 
+        Ok(dt) => (dt, dtb.to_vec()),
+        Err(e) =>
         {
-            *(HARDWARE.lock()) = Some(dt);
-            return Ok(())
-        },
+            hvalert!("Unable to fully parse system Device Tree ({:?}), attempting partial bring-up", e);
+            match new_partial(dtb)
+            {
+                Ok((dt, blob, problems)) =>
+                {
+                    hvalert!("Skipped {} system Device Tree node(s) that couldn't be understood:", problems.len());
+                    for (node, problem) in problems.iter().take(DT_PROBLEMS_LISTED)
+                    {
+                        hvalert!(" {}: {}", node, problem);
+                    }
+                    if problems.len() > DT_PROBLEMS_LISTED
+                    {
+                        hvalert!(" ...and {} more", problems.len() - DT_PROBLEMS_LISTED);
+                    }
+                    (dt, blob)
+                },
+                Err(e) =>
+                {
+                    hvalert!("Unable to parse system Device Tree ({:?}), falling back to well-known devices", e);
+                    match fallback(dtb)
+                    {
+                        Some(found) => found,
+                        None => return Err(Cause::DeviceTreeBad)
+                    }
+                }
+            }
+        }
+    };
+
+    *(HARDWARE.lock()) = Some(devices);
+
+    /* keep the blob for later lookups, as the memory it was passed in may be reused */
+    *(HOST_DT.lock()) = Some(blob);
+    with_host_dt(|fdt|
+    {
+        plic::init(fdt);
+        clint::init(fdt);
+        cbqri::init(fdt);
+        iommu::init(fdt);
+        hostrtc::init(fdt);
+        syscon::init(fdt);
+        hostgpio::init(fdt);
+
+        /* any core may be asked for random numbers, so they all need an entropy source */
+        let cpus: Vec<bool> = fdt.nodes()
+            .filter(|n| n.depth() == 2 && n.unit_name() == "cpu" && n.is_enabled())
+            .map(|n| hvalgo::fdt::cpu_has_extension(&n, "zkr")).collect();
+        ENTROPY_SOURCE.store(cpus.len() > 0 && cpus.iter().all(|has| *has == true), Ordering::SeqCst);
+
+        /* cache operations are only used if every core can carry them out on blocks of the same size */
+        let blocks: Vec<Option<u32>> = fdt.nodes()
+            .filter(|n| n.depth() == 2 && n.unit_name() == "cpu" && n.is_enabled())
+            .map(|n| match hvalgo::fdt::cpu_has_extension(&n, "zicbom")
+            {
+                true => n.property_u32("riscv,cbom-block-size"),
+                false => None
+            }).collect();
+        match blocks.first()
+        {
+            Some(Some(size)) if size.is_power_of_two() && blocks.iter().all(|b| *b == Some(*size)) =>
+                machine::set_cache_block_size(*size as usize),
+            _ => machine::set_cache_block_size(0)
+        }
+
+        /* board-specific quirks, keyed off the tree's root compatible string */
+        jh7110::init(fdt);
+        Some(())
+    });
+    Ok(())
+}
+
+/* nodes under these top-level nodes describe the cores, RAM, and boot choices, and are never taken out */
+const DT_ESSENTIAL_NODES: [&str; 5] = [ "cpus", "memory", "chosen", "aliases", "reserved-memory" ];
+
+/* new_partial
+   Bring up as much of a device tree as the platform code can understand by taking out the
+   nodes it can't. nodes seen to be malformed are taken out first. if the platform code still
+   can't parse what's left, every optional device is taken out, and then each is put back
+   in turn, keeping those that can be parsed
+   => dtb = byte slice containing the device tree
+   <= the devices, the pruned tree they were brought up from, and the name of each node
+      taken out with the reason why, or an error code if the tree is beyond saving */
+fn new_partial(dtb: &[u8]) -> Result<(Devices, Vec<u8>, Vec<(String, &'static str)>), Cause>
+{
+    let fdt = Fdt::new(dtb)?;
+    let mut problems = Vec::new();
+    let mut malformed = Vec::new();
+    let mut optional = Vec::new();
+
+    /* depth of the essential or optional node being walked through, if any */
+    let mut essential_depth: Option<usize> = None;
+    let mut optional_depth: Option<usize> = None;
+    for node in fdt.nodes()
+    {
+        if let Some(depth) = essential_depth
+        {
+            if node.depth() <= depth
+            {
+                essential_depth = None;
+            }
+        }
+        if let Some(depth) = optional_depth
+        {
+            if node.depth() <= depth
+            {
+                optional_depth = None;
+            }
+        }
+
+        if node.depth() == 1 && DT_ESSENTIAL_NODES.iter().any(|n| *n == node.unit_name())
+        {
+            essential_depth = Some(node.depth());
+            continue;
+        }
+
+        /* a malformed node can go unless it's the root or a top-level essential one */
+        if node.depth() > 0
+        {
+            if let Some(problem) = malformed_node(&node)
+            {
+                problems.push((String::from(node.name()), problem));
+                malformed.push(node.offset());
+                continue;
+            }
+        }
+
+        /* devices outside the essential nodes are optional, though buses are walked into */
+        if essential_depth.is_none() && optional_depth.is_none() &&
+           node.property("compatible").is_some() && node.is_compatible("simple-bus") == false
+        {
+            optional_depth = Some(node.depth());
+            optional.push((String::from(node.name()), node.offset()));
+        }
+    }
+
+    /* optional nodes inside malformed ones go with them */
+    optional.retain(|(_, offset)| malformed.contains(offset) == false);
+
+    let blob = hvalgo::fdt::prune(dtb, &malformed)?;
+    if let Ok(dt) = Devices::new(&blob)
+    {
+        return Ok((dt, blob, problems));
+    }
+
+    /* find the optional nodes the platform code chokes on, one at a time */
+    let mut pruned = malformed.clone();
+    pruned.extend(optional.iter().map(|(_, offset)| *offset));
+    let blob = hvalgo::fdt::prune(dtb, &pruned)?;
+    let mut best = match Devices::new(&blob)
+    {
+        Ok(dt) => (dt, blob),
         Err(e) =>
         {
-            hvalert!("Unable to parse system Device Tree ({:?})", e);
+            hvalert!("Unable to parse system Device Tree even without its optional nodes ({:?})", e);
             return Err(Cause::DeviceTreeBad);
         }
+    };
+
+    for (name, offset) in optional
+    {
+        pruned.retain(|o| *o != offset);
+        let blob = hvalgo::fdt::prune(dtb, &pruned)?;
+        match Devices::new(&blob)
+        {
+            Ok(dt) => best = (dt, blob),
+            Err(_) =>
+            {
+                pruned.push(offset);
+                problems.push((name, "not understood by the platform code"));
+            }
+        }
+    }
+
+    Ok((best.0, best.1, problems))
+}
+
+/* malformed_node
+   Look for problems in a device tree node that the platform code can't be expected to cope with
+   => node = node to check
+   <= description of the problem found, or None if it seems fine */
+fn malformed_node(node: &Node) -> Option<&'static str>
+{
+    if node.reg().is_err()
+    {
+        return Some("reg property doesn't fit #address-cells and #size-cells");
+    }
+
+    for name in [ "compatible", "status", "device_type" ].iter()
+    {
+        if let Some(value) = node.property(name)
+        {
+            if value.last() != Some(&0) || core::str::from_utf8(value).is_err()
+            {
+                return Some("string property isn't a valid string");
+            }
+        }
+    }
+
+    for name in [ "interrupts", "interrupts-extended", "interrupt-parent", "phandle", "#address-cells", "#size-cells" ].iter()
+    {
+        if let Some(value) = node.property(name)
+        {
+            if value.is_empty() || value.len() % 4 != 0
+            {
+                return Some("cell property isn't a whole number of cells");
+            }
+        }
+    }
+
+    None
+}
+
+/* describe the well-known devices of a machine to fall back to when its device tree can't be parsed */
+pub struct WellKnownMachine
+{
+    pub compatible: &'static str,   /* compatible string of the machine's root node */
+    pub uart_base: u64,             /* debug UART's base address, size, and input clock in Hz... */
+    pub uart_size: u64,
+    pub uart_clock: u32,
+    pub uart_reg_shift: u32,        /* ...and the log2 of the distance between its registers */
+    pub clint_base: u64,
+    pub clint_size: u64,
+    pub ram_base: u64,
+    pub ram_size: u64,
+    pub timebase: u32,              /* timer frequency in Hz */
+    pub isa: &'static str,
+    pub first_hart: u32             /* hardware ID of the first core that can run the hypervisor */
+}
+
+/* QEMU's virt board, which is fallen back to unless the tree shows the machine is something else */
+const QEMU_VIRT: WellKnownMachine = WellKnownMachine
+{
+    compatible: "riscv-virtio",
+    uart_base: 0x1000_0000,
+    uart_size: 0x100,
+    uart_clock: 3_686_400,
+    uart_reg_shift: 0,
+    clint_base: 0x200_0000,
+    clint_size: 0x1_0000,
+    ram_base: 0x8000_0000,
+    ram_size: 128 * 1024 * 1024,
+    timebase: 10_000_000,
+    isa: "rv64imafdc",
+    first_hart: 0
+};
+
+/* CLINT interrupt causes routed to each core's interrupt controller */
+const FALLBACK_CLINT_SOFT_IRQ: u32 = 3;
+const FALLBACK_CLINT_TIMER_IRQ: u32 = 7;
+
+/* fallback
+   Describe a minimal machine with a debug UART and timer at well-known addresses.
+   the RAM, core IDs, and timer frequency are salvaged from the given tree where
+   they can be read, or else defaults are used. the well-known addresses are QEMU's
+   unless enough of the tree can be read to show it's a board with its own
+   => dtb = byte slice containing the device tree that couldn't be parsed
+   <= the devices and the tree they were brought up from, or None for failure */
+fn fallback(dtb: &[u8]) -> Option<(Devices, Vec<u8>)>
+{
+    let machine = match Fdt::new(dtb).map(|fdt| jh7110::is_board(&fdt))
+    {
+        Ok(true) => &jh7110::MACHINE,
+        _ => &QEMU_VIRT
+    };
+
+    let mut ram = (machine.ram_base, machine.ram_size);
+    let mut timebase = machine.timebase;
+    let mut harts = Vec::new();
+
+    if let Ok(fdt) = Fdt::new(dtb)
+    {
+        for node in fdt.nodes()
+        {
+            if node.depth() == 1 && node.unit_name() == "memory"
+            {
+                if let Some(region) = node.reg().ok().and_then(|mut r| r.next())
+                {
+                    ram = region;
+                }
+            }
+            if node.depth() == 1 && node.unit_name() == "cpus"
+            {
+                if let Some(frequency) = node.property_u32("timebase-frequency")
+                {
+                    timebase = frequency;
+                }
+            }
+            if node.depth() == 2 && node.unit_name() == "cpu" && node.is_enabled()
+            {
+                if let Some((id, _)) = node.reg().ok().and_then(|mut r| r.next())
+                {
+                    harts.push(id as u32);
+                }
+            }
+        }
+    }
+
+    if harts.is_empty() == true
+    {
+        harts.push(machine.first_hart);
+    }
+
+    let mut tree = hvalgo::fdt::Writer::new();
+    tree.begin("")
+        .prop_cells("#address-cells", &[2])
+        .prop_cells("#size-cells", &[2])
+        .prop_str("compatible", machine.compatible)
+        .prop_str("model", "diosix fallback machine");
+
+    tree.begin("chosen")
+        .prop_str("stdout-path", &format!("/soc/uart@{:x}", machine.uart_base))
+        .end();
+
+    /* each core's interrupt controller is given a phandle of its index plus one */
+    tree.begin("cpus")
+        .prop_cells("#address-cells", &[1])
+        .prop_cells("#size-cells", &[0])
+        .prop_cells("timebase-frequency", &[timebase]);
+    for (index, hart) in harts.iter().enumerate()
+    {
+        tree.begin(&format!("cpu@{:x}", hart))
+            .prop_str("device_type", "cpu")
+            .prop_cells("reg", &[*hart])
+            .prop_str("status", "okay")
+            .prop_str("compatible", "riscv")
+            .prop_str("riscv,isa", machine.isa)
+            .begin("interrupt-controller")
+                .prop_cells("#interrupt-cells", &[1])
+                .prop("interrupt-controller", &[])
+                .prop_str("compatible", "riscv,cpu-intc")
+                .prop_cells("phandle", &[index as u32 + 1])
+            .end()
+        .end();
+    }
+    tree.end();
+
+    tree.begin(&format!("memory@{:x}", ram.0))
+        .prop_str("device_type", "memory")
+        .prop_cells("reg", &[(ram.0 >> 32) as u32, ram.0 as u32, (ram.1 >> 32) as u32, ram.1 as u32])
+        .end();
+
+    let mut clint_irqs = Vec::new();
+    for index in 0..harts.len()
+    {
+        let phandle = index as u32 + 1;
+        clint_irqs.extend_from_slice(&[phandle, FALLBACK_CLINT_SOFT_IRQ, phandle, FALLBACK_CLINT_TIMER_IRQ]);
+    }
+
+    tree.begin("soc")
+        .prop_cells("#address-cells", &[2])
+        .prop_cells("#size-cells", &[2])
+        .prop_str("compatible", "simple-bus")
+        .prop("ranges", &[])
+        .begin(&format!("uart@{:x}", machine.uart_base))
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[(machine.uart_base >> 32) as u32, machine.uart_base as u32, (machine.uart_size >> 32) as u32, machine.uart_size as u32])
+            .prop_cells("clock-frequency", &[machine.uart_clock])
+            .prop_cells("reg-shift", &[machine.uart_reg_shift])
+            .prop_cells("reg-io-width", &[1 << machine.uart_reg_shift])
+        .end()
+        .begin(&format!("clint@{:x}", machine.clint_base))
+            .prop_str("compatible", "riscv,clint0")
+            .prop_cells("reg", &[(machine.clint_base >> 32) as u32, machine.clint_base as u32, (machine.clint_size >> 32) as u32, machine.clint_size as u32])
+            .prop_cells("interrupts-extended", &clint_irqs)
+        .end()
+    .end();
+    tree.end();
+
+    let blob = tree.blob();
+    match Devices::new(&blob)
+    {
+        Ok(dt) =>
+        {
+            hvalert!("Brought up fallback machine with {} core(s) and {} MiB of RAM", harts.len(), ram.1 >> 20);
+            Some((dt, blob))
+        },
+        Err(e) =>
+        {
+            hvalert!("Unable to bring up fallback machine ({:?})", e);
+            None
+        }
+    }
+}
+
+/* run the given function over the host's device tree
+   <= whatever the function returns, or None if the tree isn't available */
+fn with_host_dt<T>(f: impl FnOnce(&Fdt) -> Option<T>) -> Option<T>
+{
+    match &*(HOST_DT.lock())
+    {
+        Some(blob) => match Fdt::new(blob.as_slice())
+        {
+            Ok(fdt) => f(&fdt),
+            Err(_) => None
+        },
+        None => None
     }
 }
 
@@ -84,6 +516,252 @@ pub fn read_debug_char() -> Option<char>
     }   
 }
 
+/* describe an area of physical memory set aside in the host's device tree */
+pub struct MemoryArea
+{
+    pub base: PhysMemBase,
+    pub size: PhysMemSize
+}
+
+/* describe a peripheral found in the host's device tree */
+pub struct Peripheral
+{
+    pub name: String,           /* its node's name, including any unit address */
+    pub compatible: String,     /* its most specific compatible string */
+    pub base: PhysMemBase,
+    pub size: PhysMemSize,
+    pub irq: Option<usize>,
+    pub hypervisor: bool        /* true if the hypervisor drives it itself */
+}
+
+/* describe a physical serial port that could be passed through to a capsule */
+pub struct SerialPort
+{
+    pub base: PhysMemBase,
+    pub size: PhysMemSize,
+    pub irq: Option<usize>,
+    pub compatible: String,         /* the host's compatible strings, each followed by a zero byte */
+    pub reg_shift: Option<u32>,     /* log2 of the distance between its registers, and their width in bytes, */
+    pub reg_io_width: Option<u32>,  /* if the host says */
+    pub clock: Option<u32>          /* input clock in Hz, if known */
+}
+
+/* <= the host's enabled serial ports, in device tree order, which defines their indexes */
+fn serial_ports<'a>(fdt: &Fdt<'a>) -> impl Iterator<Item = Node<'a>>
+{
+    fdt.nodes().filter(|n| n.is_enabled() && SERIAL_COMPATIBLE.iter().any(|c| n.is_compatible(c)))
+}
+
+/* <= index of the serial port used for the hypervisor's debug output: the one named
+      by the tree's /chosen/stdout-path, directly or through an alias, or else the first */
+fn debug_serial_port(fdt: &Fdt) -> usize
+{
+    let path = match fdt.find("/chosen").and_then(|chosen| chosen.property_str("stdout-path"))
+    {
+        Some(path) => path.split(':').next().unwrap_or(path),
+        None => return 0
+    };
+
+    let path = match path.starts_with('/')
+    {
+        true => Some(path),
+        false => fdt.find("/aliases").and_then(|aliases| aliases.property_str(path))
+    };
+
+    match path.and_then(|p| fdt.find(p))
+    {
+        Some(console) => serial_ports(fdt).position(|port| port.is_same(&console)).unwrap_or(0),
+        None => 0
+    }
+}
+
+/* describe a serial port from its device tree node. its reg is only decoded if its bus's cell counts are sane.
+   a port clocked through a clock controller, rather than a fixed frequency, is given its board's clock if known */
+fn describe_serial_port(fdt: &Fdt, node: &Node) -> Option<SerialPort>
+{
+    let (base, size) = node.reg().ok()?.next()?;
+    let mut compatible = String::new();
+    for c in node.property_strs("compatible")
+    {
+        compatible.push_str(c);
+        compatible.push('\0');
+    }
+
+    Some(SerialPort
+    {
+        base: base as PhysMemBase,
+        size: size as PhysMemSize,
+        irq: node.property_cells("interrupts").and_then(|mut cells| cells.next()).map(|irq| irq as usize),
+        compatible,
+        reg_shift: node.property_u32("reg-shift"),
+        reg_io_width: node.property_u32("reg-io-width"),
+        clock: node.property_u32("clock-frequency").or_else(|| match jh7110::is_board(fdt)
+        {
+            true => Some(jh7110::UART_CLOCK),
+            false => None
+        })
+    })
+}
+
+/* describe a serial port available to pass through to a capsule, without claiming it
+   => index = index of the serial port among the host's, counting from zero
+   <= the port, or None if there's no such port, or it's the debug port, or it has been claimed */
+pub fn get_serial_port(index: usize) -> Option<SerialPort>
+{
+    if CLAIMED_SERIAL_PORTS.lock().contains(&index) == true
+    {
+        return None;
+    }
+
+    with_host_dt(|fdt| match debug_serial_port(fdt) == index
+    {
+        true => None,
+        false => describe_serial_port(fdt, &serial_ports(fdt).nth(index)?)
+    })
+}
+
+/* remove a serial port from the hypervisor's use so it can be passed through to a capsule.
+   the debug console's port can't be claimed this way
+   => index = index of the serial port among the host's, counting from zero
+   <= true if the port was claimed, or false if it's not available */
+pub fn claim_serial_port(index: usize) -> bool
+{
+    let mut claimed = CLAIMED_SERIAL_PORTS.lock();
+    if claimed.contains(&index) == true || get_serial_port(index).is_none()
+    {
+        return false;
+    }
+
+    claimed.push(index);
+    true
+}
+
+/* allow a device's interrupt to reach the hypervisor from the host's interrupt controller
+   => irq = device's interrupt number
+   <= Ok for success, or an error code if the interrupt can't be enabled */
+pub fn enable_external_irq(irq: usize) -> Result<(), Cause>
+{
+    match plic::enable(irq)
+    {
+        true => Ok(()),
+        false => Err(Cause::PassthroughIRQRouteFailure)
+    }
+}
+
+/* claim the highest priority external interrupt waiting for this CPU core, or None if none */
+pub fn claim_external_irq() -> Option<usize>
+{
+    plic::claim()
+}
+
+/* signal to the interrupt controller that the given external interrupt has been handled */
+pub fn complete_external_irq(irq: usize)
+{
+    plic::complete(irq)
+}
+
+/* stop the given external interrupt reaching any CPU core, or let it through again */
+pub fn mask_external_irq(irq: usize)
+{
+    plic::mask(irq)
+}
+
+pub fn unmask_external_irq(irq: usize)
+{
+    plic::unmask(irq)
+}
+
+/* raise a software interrupt on the given physical CPU core so that it checks its mailbox */
+pub fn interrupt_pcore(pcore: PhysicalCoreID)
+{
+    /* cores are interrupted by their hardware IDs */
+    if let Some(hart) = pcore::hart_of(pcore)
+    {
+        if clint::interrupt(hart) == false
+        {
+            hvdebug!("Can't interrupt physical core {}: no software interrupt register", pcore);
+        }
+    }
+}
+
+/* raise a software interrupt on each of the given physical CPU cores so that they check their mailboxes */
+pub fn interrupt_pcores(pcores: &[PhysicalCoreID])
+{
+    for pcore in pcores
+    {
+        interrupt_pcore(*pcore);
+    }
+}
+
+/* raise a supervisor-level software interrupt on the given physical CPU core, for whatever
+virtual core it's running, without interrupting the hypervisor on that core. this needs an
+ACLINT SSWI device. return true if the interrupt was raised, or false if there's no such device */
+pub fn raise_supervisor_soft_irq(pcore: PhysicalCoreID) -> bool
+{
+    let hart = match pcore::hart_of(pcore)
+    {
+        Some(hart) => hart,
+        None => return false
+    };
+
+    clint::interrupt_supervisor(hart)
+}
+
+/* return the boot arguments passed to the hypervisor in the host device tree's /chosen node, if any */
+pub fn get_boot_args() -> Option<String>
+{
+    with_host_dt(|fdt| fdt.find("/chosen")
+        .and_then(|chosen| chosen.property_str("bootargs"))
+        .map(|args| String::from(args)))
+}
+
+/* return the host's key for unsealing secrets bundled in the DMFS image, as placed by an earlier
+boot stage in the /chosen node of the host's device tree, or None if it has none or it's the wrong size */
+pub fn get_sealing_key() -> Option<[u8; SEALING_KEY_SIZE]>
+{
+    with_host_dt(|fdt| fdt.find("/chosen")
+        .and_then(|chosen| chosen.property(SEALING_KEY_PROPERTY))
+        .and_then(|key| match key.len() == SEALING_KEY_SIZE
+        {
+            true =>
+            {
+                let mut copy = [0; SEALING_KEY_SIZE];
+                copy.copy_from_slice(key);
+                Some(copy)
+            },
+            false => None
+        }))
+}
+
+/* <= true if the given CPU core's ISA string in the host's device tree includes the given extension
+   => hart = hardware ID of the core
+      extension = name of the extension, eg: sstc */
+pub fn hart_has_extension(hart: HartID, extension: &str) -> bool
+{
+    with_host_dt(|fdt| fdt.nodes()
+        .find(|n| n.depth() == 2 && n.unit_name() == "cpu" &&
+                  n.reg().ok().and_then(|mut r| r.next()).map(|(id, _)| id as HartID) == Some(hart))
+        .map(|n| hvalgo::fdt::cpu_has_extension(&n, extension)))
+    .unwrap_or(false)
+}
+
+/* return a random 64-bit value from this CPU core's entropy source,
+   or None if there isn't one or it can't provide any right now */
+pub fn get_random() -> Option<u64>
+{
+    if ENTROPY_SOURCE.load(Ordering::SeqCst) == false
+    {
+        return None;
+    }
+
+    let mut value = 0;
+    for _ in 0..4
+    {
+        value = (value << 16) | machine::read_entropy()? as u64;
+    }
+    Some(value)
+}
+
 /* return number of discovered logical CPU cores, or None if value unavailable */
 pub fn get_nr_cpu_cores() -> Option<usize>
 {
@@ -94,6 +772,81 @@ pub fn get_nr_cpu_cores() -> Option<usize>
     }
 }
 
+/* return the number of physical CPU cores per cluster of cores that share a cache, counted from
+   the first cluster of the cpu-map in the host's device tree, or None if it doesn't describe its
+   CPU topology */
+pub fn get_cpu_cluster_size() -> Option<usize>
+{
+    with_host_dt(|fdt|
+    {
+        let cluster = fdt.find("/cpus/cpu-map/cluster0").or_else(|| fdt.find("/cpus/cpu-map/socket0/cluster0"))?;
+        let cores = fdt.nodes()
+            .skip_while(|n| n.is_same(&cluster) == false)
+            .skip(1)
+            .take_while(|n| n.depth() > cluster.depth())
+            .filter(|n| n.depth() == cluster.depth() + 1 && n.unit_name().starts_with("core"))
+            .count();
+
+        match cores
+        {
+            0 => None,
+            n => Some(n)
+        }
+    })
+}
+
+/* return the capacity and bandwidth controllers' resources available for partitioning:
+   the number of last-level cache capacity blocks and memory bandwidth units,
+   or None if the system has no CBQRI-style quality-of-service controllers */
+pub fn get_qos_resources() -> Option<(usize, usize)>
+{
+    cbqri::resources()
+}
+
+/* program the quality-of-service controllers so that the given resource control ID
+   may only allocate into the given cache capacity blocks, and is reserved the given
+   number of memory bandwidth units
+   => rcid = resource control ID to configure
+      cache_mask = bitmask of cache capacity blocks the ID may use
+      bandwidth = memory bandwidth units reserved for the ID
+   <= Ok for success, or an error code */
+pub fn set_qos_allocation(rcid: usize, cache_mask: usize, bandwidth: usize) -> Result<(), Cause>
+{
+    if cbqri::resources().is_none()
+    {
+        return Err(Cause::QoSNotSupported);
+    }
+
+    match cbqri::set_allocation(rcid, cache_mask, bandwidth)
+    {
+        true => Ok(()),
+        false => Err(Cause::QoSControllerFailure)
+    }
+}
+
+/* return the ID the IOMMU uses to identify the DMA-capable device at the given MMIO base address,
+   or None if the system has no IOMMU or the device isn't behind one */
+pub fn get_iommu_device_id(base: PhysMemBase) -> Option<usize>
+{
+    iommu::get_device_id(base)
+}
+
+/* program the IOMMU so that the given device can only DMA into the given physical memory ranges,
+   replacing any ranges it was previously confined to
+   => device_id = IOMMU device ID of the device
+      ranges = list of (base address, size in bytes) of memory the device may access
+   <= Ok for success, or an error code */
+pub fn iommu_confine(device_id: usize, ranges: &Vec<(PhysMemBase, PhysMemSize)>) -> Result<(), Cause>
+{
+    iommu::confine(device_id, ranges)
+}
+
+/* program the IOMMU to block all DMA by the given device */
+pub fn iommu_block(device_id: usize)
+{
+    iommu::block(device_id);
+}
+
 /* return a list of the physical RAM chunks present in the system,
 or None if we can't read the available memory */
 pub fn get_phys_ram_chunks() -> Option<Vec<platform::physmem::RAMArea>>
@@ -105,6 +858,260 @@ pub fn get_phys_ram_chunks() -> Option<Vec<platform::physmem::RAMArea>>
     }
 }
 
+/* return the relative performance of the calling physical CPU core and the highest of any core,
+as described by the cores' capacity-dmips-mhz properties in the device tree, or None if not described */
+pub fn get_cpu_capacity() -> Option<(usize, usize)>
+{
+    let hart = pcore::PhysicalCore::get_hart_id();
+    with_host_dt(|fdt|
+    {
+        let mut this = None;
+        let mut highest = None;
+        for cpu in fdt.nodes().filter(|n| n.depth() == 2 && n.unit_name() == "cpu" && n.is_enabled())
+        {
+            if let Some(capacity) = cpu.property_u32("capacity-dmips-mhz").map(|c| c as usize)
+            {
+                highest = core::cmp::max(highest, Some(capacity));
+                if cpu.reg().ok().and_then(|mut r| r.next()).map(|(id, _)| id as HartID) == Some(hart)
+                {
+                    this = Some(capacity);
+                }
+            }
+        }
+
+        match (this, highest)
+        {
+            (Some(capacity), Some(highest)) => Some((capacity, highest)),
+            (_, _) => None
+        }
+    })
+}
+
+/* reboot or power off the host. this only returns if it fails
+   => reset = whether to reboot or power off
+   <= an error code if the host can't be reset that way */
+pub fn system_reset(reset: hypercall::power::Reset) -> Result<(), Cause>
+{
+    match syscon::reset(reset)
+    {
+        true => Ok(()),
+        false => Err(Cause::PowerResetUnsupported)
+    }
+}
+
+/* return the number of general-purpose I/O lines the host's GPIO controller provides,
+as described by its ngpios property in the device tree, or None if there isn't a controller */
+pub fn get_gpio_line_count() -> Option<usize>
+{
+    hostgpio::line_count()
+}
+
+/* make a GPIO line an input or an output
+   => line = host GPIO line number
+      direction = whether the line is to be read or driven
+   <= Ok for success, or an error code */
+pub fn gpio_set_direction(line: usize, direction: hypercall::gpio::Direction) -> Result<(), Cause>
+{
+    match hostgpio::set_direction(line, direction)
+    {
+        true => Ok(()),
+        false => Err(gpio_failure())
+    }
+}
+
+/* drive a GPIO output line high or low
+   => line = host GPIO line number
+      value = true for high, false for low
+   <= Ok for success, or an error code */
+pub fn gpio_write(line: usize, value: bool) -> Result<(), Cause>
+{
+    match hostgpio::write(line, value)
+    {
+        true => Ok(()),
+        false => Err(gpio_failure())
+    }
+}
+
+/* read the level of a GPIO line
+   => line = host GPIO line number
+   <= true for high, false for low, or an error code */
+pub fn gpio_read(line: usize) -> Result<bool, Cause>
+{
+    match hostgpio::read(line)
+    {
+        Some(value) => Ok(value),
+        None => Err(gpio_failure())
+    }
+}
+
+/* <= the error to return when the host GPIO controller refuses a request */
+fn gpio_failure() -> Cause
+{
+    match hostgpio::line_count()
+    {
+        Some(_) => Cause::GpioFailed,
+        None => Cause::GpioNoController
+    }
+}
+
+/* return the wall-clock time read from the host's real-time clock, in nanoseconds since
+1970-01-01 00:00:00 UTC, or None if there isn't an RTC or it hasn't been set */
+pub fn get_wall_clock() -> Option<u64>
+{
+    hostrtc::read()
+}
+
+/* return the area of memory that survives reboots set aside for the persistent alert store,
+as named by a ramoops node under the host device tree's /reserved-memory, or None if there isn't one */
+pub fn get_pstore_area() -> Option<MemoryArea>
+{
+    with_host_dt(|fdt|
+    {
+        let reserved = fdt.find("/reserved-memory")?;
+        let area = fdt.nodes()
+            .skip_while(|n| n.is_same(&reserved) == false)
+            .skip(1)
+            .take_while(|n| n.depth() > reserved.depth())
+            .find(|n| n.depth() == reserved.depth() + 1 && n.is_enabled() && PSTORE_COMPATIBLE.iter().any(|c| n.is_compatible(c)))?;
+
+        match area.reg().ok()?.next()?
+        {
+            (_, 0) => None,
+            (base, size) => Some(MemoryArea { base: base as PhysMemBase, size: size as PhysMemSize })
+        }
+    })
+}
+
+/* return the physical address ranges that devices can DMA into, as described by the dma-ranges
+of the host device tree's top-level buses, or None if there are no restrictions. an empty
+dma-ranges means the bus's devices can reach all of memory, and so restricts nothing */
+pub fn get_dma_ranges() -> Option<Vec<MemoryArea>>
+{
+    with_host_dt(|fdt|
+    {
+        let mut ranges = Vec::new();
+        for bus in fdt.nodes().filter(|n| n.depth() == 1 && n.is_enabled())
+        {
+            let cells = match bus.property_cells("dma-ranges")
+            {
+                Some(cells) => cells.collect::<Vec<u32>>(),
+                None => continue
+            };
+
+            /* each entry is the bus address, the physical address it maps to, and the size */
+            let child_cells = bus.property_u32("#address-cells").unwrap_or(2) as usize;
+            let parent_cells = bus.reg_cells().0 as usize;
+            let size_cells = bus.property_u32("#size-cells").unwrap_or(1) as usize;
+            let entry = child_cells + parent_cells + size_cells;
+            if child_cells > 2 || parent_cells == 0 || parent_cells > 2 || size_cells == 0 || size_cells > 2 || cells.len() % entry != 0
+            {
+                hvalert!("Ignoring malformed dma-ranges of {}", bus.name());
+                continue;
+            }
+
+            let number = |cells: &[u32]| cells.iter().fold(0u64, |n, cell| (n << 32) | *cell as u64);
+            for range in cells.chunks(entry)
+            {
+                ranges.push(MemoryArea
+                {
+                    base: number(&range[child_cells..child_cells + parent_cells]) as PhysMemBase,
+                    size: number(&range[child_cells + parent_cells..]) as PhysMemSize
+                });
+            }
+        }
+
+        match ranges.len()
+        {
+            0 => None,
+            _ => Some(ranges)
+        }
+    })
+}
+
+/* <= true if the hypervisor drives the given device itself: its debug port, interrupt
+      and system controllers, IOMMUs, QoS controllers, and the devices of its in-tree drivers
+   => fdt = host's device tree
+      node = the device's node
+      console = the hypervisor's debug port */
+fn driven_by_hypervisor(fdt: &Fdt, node: &Node, console: Option<&Node>) -> bool
+{
+    if console.map(|port| port.is_same(node)) == Some(true)
+    {
+        return true;
+    }
+
+    let driven = plic::COMPATIBLE.iter()
+        .chain(clint::COMPATIBLE.iter())
+        .chain(iommu::COMPATIBLE.iter())
+        .chain(cbqri::CAPACITY_COMPATIBLE.iter())
+        .chain(cbqri::BANDWIDTH_COMPATIBLE.iter())
+        .chain(jh7110::CACHE_COMPATIBLE.iter())
+        .chain([ clint::SSWI_COMPATIBLE, hostrtc::COMPATIBLE, hostgpio::COMPATIBLE ].iter())
+        .any(|c| node.is_compatible(c));
+
+    /* system controllers are written to reset the host */
+    driven || match node.phandle()
+    {
+        Some(phandle) => fdt.nodes()
+            .filter(|n| n.is_compatible(syscon::REBOOT_COMPATIBLE) || n.is_compatible(syscon::POWEROFF_COMPATIBLE))
+            .any(|n| n.property_u32("regmap") == Some(phandle)),
+        None => false
+    }
+}
+
+/* return a description of every peripheral found in the device tree, including those
+the hypervisor uses itself, or an empty list if the hardware hasn't been discovered.
+a peripheral is an enabled node with a compatible string and registers outside of
+the tree's CPU cores, RAM, and reserved memory */
+pub fn get_peripherals() -> Vec<Peripheral>
+{
+    with_host_dt(|fdt|
+    {
+        let console = serial_ports(fdt).nth(debug_serial_port(fdt));
+        let mut peripherals = Vec::new();
+
+        /* depth of the CPU or reserved memory node whose children are being skipped */
+        let mut skipping: Option<usize> = None;
+        for node in fdt.nodes()
+        {
+            if let Some(depth) = skipping
+            {
+                match node.depth() > depth
+                {
+                    true => continue,
+                    false => skipping = None
+                }
+            }
+
+            if node.depth() == 1 && PERIPHERAL_EXCLUDED_NODES.iter().any(|n| *n == node.unit_name())
+            {
+                skipping = Some(node.depth());
+                continue;
+            }
+
+            if node.is_enabled() == false || node.property("compatible").is_none()
+            {
+                continue;
+            }
+
+            if let Some((base, size)) = node.reg().ok().and_then(|mut reg| reg.next())
+            {
+                peripherals.push(Peripheral
+                {
+                    name: String::from(node.name()),
+                    compatible: String::from(node.property_strs("compatible").next().unwrap_or("")),
+                    base: base as PhysMemBase,
+                    size: size as PhysMemSize,
+                    irq: node.property_cells("interrupts").and_then(|mut cells| cells.next()).map(|irq| irq as usize),
+                    hypervisor: driven_by_hypervisor(fdt, &node, console.as_ref())
+                });
+            }
+        }
+
+        Some(peripherals)
+    }).unwrap_or(Vec::new())
+}
+
 /* return total amount of physical RAM present in the system */
 pub fn get_phys_ram_total() -> Option<usize>
 {
@@ -0,0 +1,102 @@
+/* diosix hypercall argument checks
+ *
+ * Every buffer and array a capsule passes to the hypervisor in a
+ * hypercall is checked here, against a snapshot of the capsule's memory
+ * mappings, before the hypervisor touches it. Each hypercall gives the
+ * largest buffer or array it'll accept, and says whether the hypervisor
+ * will read or write it, so that a capsule can't have the hypervisor
+ * write to memory the capsule can't write to itself, such as its code
+ * when it's run with wx_protect.
+ *
+ * The checks themselves are made by the hvalgo crate, where they're
+ * unit tested and can be fuzzed on the host. See hvalgo/src/hcargs.rs.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use platform::physmem::PhysMemBase;
+use hvalgo::hcargs::{self, AddressSpace, Window};
+use super::capsule::{self, CapsuleID};
+use super::dirty;
+use super::error::Cause;
+
+pub use hvalgo::hcargs::Access;
+
+/* a capsule's memory as seen by the argument checks: its windows, and the
+   ranges within them it can't write to, in capsule addresses */
+pub struct Space
+{
+    windows: Vec<Window>,
+    read_only: Vec<(usize, usize)>
+}
+
+impl Space
+{
+    pub fn new(windows: Vec<Window>, read_only: Vec<(usize, usize)>) -> Space
+    {
+        Space { windows, read_only }
+    }
+}
+
+impl AddressSpace for Space
+{
+    fn window(&self, addr: usize) -> Option<Window>
+    {
+        self.windows.iter().find(|w| addr >= w.base && addr - w.base < w.size).copied()
+    }
+
+    fn is_read_only(&self, addr: usize, size: usize) -> bool
+    {
+        self.read_only.iter().any(|(base, length)| addr < base.saturating_add(*length) && addr.saturating_add(size) > *base)
+    }
+}
+
+/* check a buffer passed to the hypervisor by a capsule, and translate it into host memory.
+   buffers the hypervisor writes to are marked dirty for the capsule's dirty page log
+   => cid = ID of capsule owning the buffer
+      addr = capsule address of the buffer
+      size = size of the buffer in bytes
+      max = largest buffer the hypercall accepts, in bytes
+      access = whether the hypervisor will read or write the buffer
+   <= host physical address of the buffer, or an error code */
+pub fn buffer(cid: CapsuleID, addr: usize, size: usize, max: usize, access: Access) -> Result<PhysMemBase, Cause>
+{
+    let space = capsule::get_address_space(cid)?;
+    match hcargs::bytes(&space, addr, size, max, access)
+    {
+        Ok(base) => Ok(accessed(cid, base, size, access)),
+        Err(e) => Err(hverror!(Cause::from(e), "capsule {} buffer 0x{:x} size 0x{:x} refused: {:?}", cid, addr, size, e))
+    }
+}
+
+/* check an array of T passed to the hypervisor by a capsule, and translate it into host memory.
+   the array must be aligned for T, in the capsule and in the host
+   => cid = ID of capsule owning the array
+      addr = capsule address of the array
+      count = number of elements in the array
+      max = most elements the hypercall accepts
+      access = whether the hypervisor will read or write the array
+   <= host physical address of the array, or an error code */
+pub fn array<T>(cid: CapsuleID, addr: usize, count: usize, max: usize, access: Access) -> Result<PhysMemBase, Cause>
+{
+    let (size, align) = (core::mem::size_of::<T>(), core::mem::align_of::<T>());
+    let space = capsule::get_address_space(cid)?;
+    match hcargs::array(&space, addr, count, size, align, max, access)
+    {
+        Ok(base) => Ok(accessed(cid, base, count * size, access)),
+        Err(e) => Err(hverror!(Cause::from(e), "capsule {} array 0x{:x} count {} refused: {:?}", cid, addr, count, e))
+    }
+}
+
+/* the hypervisor's writes to a capsule's memory don't trip its dirty page tracking, so log them here */
+fn accessed(cid: CapsuleID, base: PhysMemBase, size: usize, access: Access) -> PhysMemBase
+{
+    if access == Access::Write
+    {
+        dirty::touched(cid, base, size);
+    }
+    base
+}
@@ -20,8 +20,29 @@
  * preventing any races.
  * 
  * This code interfaces with Rust's global allocator API
- * so things like vec! and Box just work. Heap is
- * the underlying engine for HVallocator.
+ * so things like vec! and Box just work. The heap engine
+ * underneath HVallocator lives in the hvalgo crate so it
+ * can be unit tested on the host. This file connects it to
+ * the physical memory manager and the debug output.
+ * 
+ * With the heapaudit feature, each allocation is tagged with
+ * the module that asked for it, so that the live allocations
+ * of each module can be listed on demand. Modules claim the
+ * allocations made on their physical core using heaptag!(),
+ * which lasts until the end of the enclosing scope.
+ * 
+ * With the integritychecks feature, each block header starts
+ * with a guard word. An overrun from the block below will
+ * destroy this word before reaching the header's list link,
+ * so the list can be walked and checked safely.
+ * 
+ * With the heapguard feature, each block is also stamped with
+ * the physical core that allocated it and that core's heap
+ * generation, and freed blocks aren't reused until the core
+ * has made a number of allocations since. A block freed twice
+ * in that time is reported along with its owner and size
+ * rather than corrupting whatever reused it, and the stamps
+ * are checked with the canaries during housekeeping.
  * 
  * (c) Chris Williams, 2019-2021.
  *
@@ -30,46 +51,28 @@
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use core::mem;
-use core::fmt;
-use core::result::Result;
+#[cfg(feature = "integritychecks")]
+use super::integrity::Damage;
+#[cfg(feature = "heapaudit")]
 use core::sync::atomic::{AtomicUsize, Ordering};
-use platform::physmem::{PhysMemSize, PhysMemBase};
+use platform::physmem::PhysMemBase;
 use super::physmem::{self, alloc_region, RegionHygiene};
 use super::error::Cause;
-
-/* different states each recognized heap block can be in */
-#[derive(PartialEq, Debug, Clone, Copy)]
-enum HeapBlockMagic
-{
-    Free     = 0x0deadded,
-    InUse    = 0x0d10c0de,
-    BadMagic = 0xabad1dea
-}
-
-impl HeapBlockMagic
-{
-    pub fn from_usize(value: usize) -> Self
-    {
-        match value
-        {
-            0x0deadded => Self::Free,
-            0x0d10c0de => Self::InUse,
-            _ => Self::BadMagic
-        }
-    }
-}
-
-/* source of a heap block */
-#[derive(PartialEq, Debug, Clone, Copy)]
-enum HeapSource
-{
-    Fixed,      /* allocated during startup by platform code */
-    Temporary   /* allocated dynamically from physical memory pool */
-}
-
-/* to avoid fragmentation, allocate in block sizes of this multiple, including header */
-const HEAP_BLOCK_SIZE: usize = 128;
+#[cfg(feature = "heapaudit")]
+use super::lock::Mutex;
+#[cfg(feature = "heapaudit")]
+use alloc::vec::Vec;
+use alloc::string::String;
+use hvalgo::heap::{Memory, BlockReport};
+#[cfg(feature = "heapguard")]
+use hvalgo::heap::Misuse;
+#[cfg(feature = "heapaudit")]
+use hvalgo::heap::HEAP_TAG_UNTAGGED;
+
+pub use hvalgo::heap::{HeapBlock, HeapStats};
+
+/* each physical CPU core's heap, drawing extra memory from the physical memory manager */
+pub type Heap = hvalgo::heap::Heap<PhysMemBacking>;
 
 /* follow Rust's heap allocator API so we can drop our per-CPU allocator in and use things
 like Box. We allow the Rust toolchain to track and check pointers and object lifetimes,
@@ -82,12 +85,18 @@ unsafe impl GlobalAlloc for HVallocator
     {
         let bytes = layout.size();
 
-        match (*<super::pcore::PhysicalCore>::this()).heap.alloc::<u8>(bytes)
+        let heap = &mut (*<super::pcore::PhysicalCore>::this()).heap;
+        match heap.alloc::<u8>(bytes)
         {
             Ok(p) => p,
+            Err(hvalgo::Error::HeapCorrupted) =>
+            {
+                hvalert!("CPU private heap overwritten (0x{:x}). Halting!", heap.magic());
+                loop {} /* it's over */
+            },
             Err(e) =>
             {
-                hvalert!("HVallocator: request for {} bytes failed ({:?})", bytes, e);
+                hvalert!("HVallocator: request for {} bytes failed ({})", bytes, super::error::report(&Cause::from(e)));
                 null_mut() /* yeesh */
             }
         }
@@ -99,454 +108,271 @@ unsafe impl GlobalAlloc for HVallocator
         {
             Err(e) =>
             {
-                hvalert!("HVallocator: request to free {} bytes at {:p} failed ({:?})", layout.size(), ptr, e)
+                hvalert!("HVallocator: request to free {} bytes at {:p} failed ({})", layout.size(), ptr, super::error::report(&Cause::from(e)))
             },
             _ => ()
         }
     }
 }
 
-/* describe the layout of a per-CPU heap block */
-#[repr(C)]
-pub struct HeapBlock
-{
-    /* heap is a single-link-list to keep it simple and safe */
-    next: Option<*mut HeapBlock>,
-    /* size of this block *including* header */
-    size: PhysMemSize,
-    /* define block state using magic words */
-    magic: AtomicUsize,
-    /* define the source of the memory */
-    source: HeapSource
-    /* block contents follows... */
-}
-
-/* used to perform integrity checks */
-const HEAP_MAGIC: usize = 0xcafed00d;
-
-/* this is our own internal API for the per-CPU hypervisor heap. use high-level abstractions, such as Box,
-rather than this directly, so we get all the safety measures and lifetime checking. think of kallocator
-as the API and Heap as the engine. kallocator is built on top of Heap, and each CPU core has its own Heap. */
-#[repr(C)]
-pub struct Heap
-{
-    /* magic to ensure heap structure hasn't been overwritten */
-    magic: usize,
-    /* pointer to list of in-use and freed blocks */
-    block_list_head: *mut HeapBlock,
-    /* stash a copy of the block header size here */
-    block_header_size: PhysMemSize,
-}
+/* most modules whose allocations can be told apart. the first tag counts untagged allocations,
+and the last counts the allocations of any modules beyond the limit */
+#[cfg(feature = "heapaudit")]
+const HEAP_TAGS_MAX: usize = 64;
+#[cfg(feature = "heapaudit")]
+const HEAP_TAG_OVERFLOW: usize = HEAP_TAGS_MAX - 1;
 
-/* describe a heap by its totals */
-pub struct HeapStats
+/* the allocator can't allocate to keep its own books, so the tags and their counts are held in fixed tables */
+#[cfg(feature = "heapaudit")]
+lazy_static!
 {
-    pub free_total: usize,      /* total free space in bytes */
-    pub alloc_total: usize,     /* total bytes allocated */
-    pub largest_free: usize,    /* largest single free block in bytes */
-    pub largest_alloc: usize    /* largest allocated block in bytes */
+    static ref HEAP_TAG_NAMES: Mutex<[&'static str; HEAP_TAGS_MAX]> = Mutex::new("heap audit tags", [""; HEAP_TAGS_MAX]);
 }
-
-/* pretty print the heap's stats */
-impl fmt::Debug for Heap
+#[cfg(feature = "heapaudit")]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "heapaudit")]
+static HEAP_TAG_BLOCKS: [AtomicUsize; HEAP_TAGS_MAX] = [ZERO; HEAP_TAGS_MAX];
+#[cfg(feature = "heapaudit")]
+static HEAP_TAG_BYTES: [AtomicUsize; HEAP_TAGS_MAX] = [ZERO; HEAP_TAGS_MAX];
+
+/* attribute heap allocations made on this physical core to the calling module until the end of the scope, eg:
+   let _tag = heaptag!();
+   this does nothing without the heapaudit feature */
+macro_rules! heaptag
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    () =>
     {
-        let stats = self.calculate_stats();
-
-        write!(f, "size: {} alloc'd {} free {} largest alloc'd {} largest free {} magic 0x{:x}",
-            stats.alloc_total + stats.free_total,
-            stats.alloc_total, stats.free_total,
-            stats.largest_alloc, stats.largest_free, self.magic)
+        $crate::heap::HeapTag::enter(module_path!())
     }
 }
 
-/* clean up heap list by returning chunks of free temporary physical RAM */
-macro_rules! heaphousekeeper
+/* restores this physical core's previous heap tag when dropped */
+pub struct HeapTag
 {
-    () => ((*<super::pcore::PhysicalCore>::this()).heap.return_unused();)
+    #[cfg(feature = "heapaudit")]
+    previous: usize
 }
 
-impl Heap
+impl HeapTag
 {
-    /* initialize this heap area. start off with one giant block
-    covering all of free space, from which other blocks will be carved.
-    this initial block is assuemd to be a fixed platform-allocated area
-    of physical memory.
-    => start = pointer to start of heap area
-       size = number of available bytes in heap */
-    pub fn init(&mut self, start: *mut HeapBlock, size: PhysMemSize)
+    /* start attributing this physical core's heap allocations to the given module. use heaptag!() rather than this
+       => module = name of the module to blame for allocations
+       <= guard to hold until the module is done allocating */
+    pub fn enter(_module: &'static str) -> HeapTag
     {
-        /* start with a free block covering the available space */
-        unsafe
+        #[cfg(feature = "heapaudit")]
+        let previous =
         {
-            let block = start;
-            (*block).size = size;
-            (*block).next = None;
-            (*block).magic = AtomicUsize::new(HeapBlockMagic::Free as usize);
-            (*block).source = HeapSource::Fixed;
+            let heap = &mut super::pcore::PhysicalCore::this().heap;
+            let previous = heap.tag();
+            heap.set_tag(tag_index(_module));
+            previous
+        };
 
-            self.magic = HEAP_MAGIC;
-            self.block_header_size = mem::size_of::<HeapBlock>();
-            self.block_list_head = block;
+        HeapTag
+        {
+            #[cfg(feature = "heapaudit")]
+            previous
         }
     }
+}
 
-    /* insert a free physical memory block at the head of the list
-    => base = base address of the memory block to add
-       size = total size of the block, including header that will be automatically added
-    <= OK or error code */
-    pub fn insert_free(&mut self, base: PhysMemBase, size: PhysMemSize) -> Result<(), Cause>
+impl Drop for HeapTag
+{
+    fn drop(&mut self)
     {
-        unsafe
+        #[cfg(feature = "heapaudit")]
         {
-            /* craft free block from scratch */
-            let block = base as *mut HeapBlock;
-            (*block).size = size;
-            (*block).next = Some(self.block_list_head);
-            (*block).magic = AtomicUsize::new(HeapBlockMagic::Free as usize);
-            (*block).source = HeapSource::Temporary;
-
-            /* add the free block to the start of the list */
-            self.block_list_head = block;
+            super::pcore::PhysicalCore::this().heap.set_tag(self.previous);
         }
-
-        Ok(())
     }
+}
 
-    /* free a previously allocated block
-    => to_free = pointer previously returned by alloc()
-    <= OK or failure code */
-    pub fn free<T>(&mut self, to_free: *mut T) -> Result<(), Cause>
+/* look up, or create, the tag of the given module
+   => module = name of the module
+   <= index of the tag in the tables */
+#[cfg(feature = "heapaudit")]
+fn tag_index(module: &'static str) -> usize
+{
+    let mut names = HEAP_TAG_NAMES.lock();
+    for index in (HEAP_TAG_UNTAGGED + 1)..HEAP_TAG_OVERFLOW
     {
-        /* convert this into a raw pointer so we can find the heap block header */
-        let mut ptr = to_free as usize;
-        ptr = ptr - self.block_header_size;
-        let block = ptr as *mut HeapBlock;
-        
-        unsafe
+        if names[index] == module
         {
-            /* we should be the only one writing to this metadata, though there
-            will be readers, hence the split in reading and writing */
-            match HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst))
-            {
-                HeapBlockMagic::InUse =>
-                {
-                    (*block).magic.store(HeapBlockMagic::Free as usize, Ordering::SeqCst);
-                    Ok(())
-                },
-                /* if it's not in use, or bad magic, then bail out */
-                HeapBlockMagic::Free => Err(Cause::HeapNotInUse),
-                HeapBlockMagic::BadMagic => Err(Cause::HeapBadMagic)
-            }
+            return index;
+        }
+        if names[index].len() == 0
+        {
+            names[index] = module;
+            return index;
         }
     }
+    HEAP_TAG_OVERFLOW
+}
 
-    /* allocate memory for the given object type. the returned pointer skips
-    the heap block header, pointing to the available space,
-    just like malloc() on other platforms.
-    => T = type of object to allocate memory for
-       num = number of objects to allocate for
-    <= pointer to memory, or error code */
-    pub fn alloc<T>(&mut self, num: usize) -> Result<*mut T, Cause>
+/* return the modules with live heap allocations across all physical cores
+   <= list of module names, their number of live allocations, and the bytes they asked for, largest first */
+#[cfg(feature = "heapaudit")]
+pub fn audit() -> Vec<(&'static str, usize, usize)>
+{
+    let names = *(HEAP_TAG_NAMES.lock());
+    let mut live: Vec<(&'static str, usize, usize)> = (0..HEAP_TAGS_MAX).filter_map(|index|
     {
-        if num == 0
+        let name = match index
         {
-            return Err(Cause::HeapBadSize);
-        }
-
-        /* perform integrity check */
-        #[cfg(feature = "integritychecks")]
+            HEAP_TAG_UNTAGGED => "(untagged)",
+            HEAP_TAG_OVERFLOW => "(other)",
+            _ => names[index]
+        };
+        match HEAP_TAG_BLOCKS[index].load(Ordering::Relaxed)
         {
-            if self.magic != HEAP_MAGIC
-            {
-                hvalert!("CPU private heap overwritten (0x{:x}). Halting!", self.magic);
-                loop {}
-            }
+            0 => None,
+            blocks => Some((name, blocks, HEAP_TAG_BYTES[index].load(Ordering::Relaxed)))
         }
+    }).collect();
 
-        let mut done = false;
-        let mut extended = false;
+    live.sort_unstable_by(|a, b| b.2.cmp(&a.2));
+    live
+}
 
-        /* calculate size of block required, including header, rounded up to
-        nearest whole heap block multiple */
-        let mut size_req = (mem::size_of::<T>() * num) + self.block_header_size;
-        size_req = ((size_req / HEAP_BLOCK_SIZE) + 1) * HEAP_BLOCK_SIZE;
+/* where each physical core's heap gets its temporary blocks from, and reports problems to */
+#[derive(Default)]
+pub struct PhysMemBacking;
 
-        /* scan all blocks for first free fit */
-        let mut search_block = self.block_list_head;
-        unsafe
+impl Memory for PhysMemBacking
+{
+    /* grab a chunk of available RAM from the physical memory manager */
+    fn grow(&mut self, size: usize) -> Option<(usize, usize)>
+    {
+        match alloc_region(size)
         {
-            while !done
+            Ok(r) => Some((r.base(), r.size())),
+            Err(_e) =>
             {
-                if HeapBlockMagic::from_usize((*search_block).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free && (*search_block).size >= size_req
-                {
-                    /* we've got a winner. if the found block is equal size, or only a few bytes
-                    larger than the required size, then take the whole block */
-                    if ((*search_block).size - size_req) < HEAP_BLOCK_SIZE
-                    {
-                        (*search_block).magic.store(HeapBlockMagic::InUse as usize, Ordering::SeqCst);
-                        let found_ptr = (search_block as usize) + self.block_header_size;
-                        return Result::Ok(found_ptr as *mut T);
-                    }
-                    else
-                    {
-                        /* carve the end of a large-enough free block off to make a new block.
-                        then add this new block to the start of the list */
-                        (*search_block).size = (*search_block).size - size_req;
-                        
-                        /* skip to the new (shorter) end of the free block */
-                        let mut found_ptr = (search_block as usize) + (*search_block).size;
-
-                        /* set metadata for newly allocated block */
-                        let alloc_block = found_ptr as *mut HeapBlock;
-                        (*alloc_block).next  = Some(self.block_list_head);
-                        (*alloc_block).magic.store(HeapBlockMagic::InUse as usize, Ordering::SeqCst);
-                        (*alloc_block).size  = size_req;
-
-                        /* point the head of the list at new block */
-                        self.block_list_head = alloc_block;
-
-                        /* adjust pointer to skip the header of our new block, and we're done */
-                        found_ptr = found_ptr + self.block_header_size;
-                        return Result::Ok(found_ptr as *mut T);
-                    }
-                }
-
-                /* make sure we don't run off the end of the list.
-                also, attempt to consolidate neighboring blocks to make
-                more bytes available and reduce fragmentation. do this 
-                after we've tried searching for available blocks */
-                match (*search_block).next
-                {
-                    None => if self.consolidate() < HEAP_BLOCK_SIZE
-                    {
-                        if extended == false
-                        {
-                            /* if we can't squeeze any more bytes out of the list
-                            then grab a chunk of available RAM from the physical
-                            memory manager and add it to the free list */
-                            let region = match alloc_region(size_req)
-                            {
-                                Ok(r) => r,
-                                Err(_e) =>
-                                {
-                                    /* give up and bail out if there's no more physical memory */
-                                    hvdebug!("Failed to extend heap by {} bytes: {:?}", size_req, _e);
-                                    return Result::Err(Cause::HeapNoFreeMem);
-                                }
-                            };
-
-                            if self.insert_free(region.base(), region.size()).is_ok()
-                            {
-                                extended = true;
-
-                                /* start the search over, starting with the new block */
-                                search_block = self.block_list_head;
-                            }
-                            else
-                            {
-                                /* if we couldn't insert free block, give up */
-                                done = true;
-                            }
-                        }
-                        else
-                        {
-                            /* can't squeeze any more out of list and we've tried allocating more
-                            physical memory. give up at this point, though we shouldn't really
-                            end up here */
-                            hvdebug!("Giving up allocating {} bytes", size_req);
-                            done = true;
-                        }
-                    }
-                    else
-                    {
-                        /* start the search over */
-                        search_block = self.block_list_head;
-                    },
-                    Some(n) => search_block = n
-                };
+                /* give up and bail out if there's no more physical memory */
+                hvdebug!("Failed to extend heap by {} bytes: {:?}", size, _e);
+                None
             }
         }
-
-        return Result::Err(Cause::HeapNoFreeMem);
     }
 
-    /* deallocate any free temporary physical memory regions that are no longer needed */
-    pub fn return_unused(&mut self)
+    /* the physical memory manager will avoid fragmentation by rejecting
+    regions that are not multiples of prefered region sizes */
+    fn release(&mut self, base: usize, size: usize) -> bool
     {
-        /* ensure all blocks are gathered up */
-        loop
+        let region = physmem::Region::new(base as PhysMemBase, size, RegionHygiene::CanClean);
+        match physmem::dealloc_region(region)
         {
-            if self.consolidate() < HEAP_BLOCK_SIZE
+            Ok(()) =>
             {
-                break;
-            }
+                hvdebug!("Returning heap block 0x{:x} size {} to physical memory pool", base, size);
+                true
+            },
+            Err(_) => false
         }
+    }
 
-        /* search for unused physical memory blocks to return */
-        let mut block = self.block_list_head;
-        let mut prev_block: Option<*mut HeapBlock> = None;
-        unsafe
+    fn damaged(&self, block: BlockReport, leading: Option<usize>)
+    {
+        match leading
         {
-            loop
-            {
-                match ((*block).source, HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)))
-                {
-                    /* remove physical region from single-linked list if successfully deallocated.
-                    the physical memory manager will avoid fragmentation by rejecting regions that
-                    are not multiples of prefered region sizes */
-                    (HeapSource::Temporary, HeapBlockMagic::Free) =>
-                    {
-                        let region = physmem::Region::new(block as PhysMemBase, (*block).size, RegionHygiene::CanClean);
-                        if physmem::dealloc_region(region).is_ok()
-                        {
-                            hvdebug!("Returning heap block {:p} size {} to physical memory pool",
-                            block, (*block).size);
-
-                            /* delink the block - do not touch the contents of the
-                            deallocated block: it's back in the pool and another CPU core
-                            could grab it at any time. After dealloc_region() returns Ok,
-                            it's gone as far as this core is concerned. */
-                            match prev_block
-                            {
-                                Some(b) => (*b).next = (*block).next,
-                                None => ()
-                            };
-                        }
-                    },
-
-                    (_, _) => ()
-                }
+            Some(canary) => hvalert!("Heap block {} underrun: leading canary is 0x{:x}", describe(&block), canary),
+            None => hvalert!("Heap block {} overrun: trailing canary overwritten", describe(&block))
+        }
+    }
 
-                match (*block).next
-                {
-                    Some(n) =>
-                    {
-                        prev_block = Some(block);
-                        block = n;
-                    }
-                    None => break
-                };
-            }
+    #[cfg(feature = "heapguard")]
+    fn misused(&self, misuse: Misuse)
+    {
+        match misuse
+        {
+            Misuse::DoubleFree(block) =>
+                hvalert!("Heap block {} freed twice", describe(&block)),
+            Misuse::WrongOwner(block, owner) =>
+                hvalert!("Heap block {} found in physical CPU core {}'s heap", describe(&block), owner),
+            Misuse::BadGeneration(block, generation) =>
+                hvalert!("Heap block {} stamped with a generation beyond its heap's {}", describe(&block), generation)
         }
     }
 
-    /* pass once over the heap and try to merge adjacent free blocks
-    <= size of the largest block seen, in bytes including header */
-    fn consolidate(&mut self) -> PhysMemSize
+    /* blocks belong to the physical core that allocated them */
+    #[cfg(feature = "heapguard")]
+    fn owner(&self) -> usize
     {
-        let mut largest_merged_block: PhysMemSize = 0;
+        super::pcore::PhysicalCore::get_id()
+    }
 
-        let mut block = self.block_list_head;
-        unsafe
-        {
-            /* can't merge if we're the last block in the list */
-            while (*block).next.is_some()
-            {
-                let next = (*block).next.unwrap();
-                if HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free &&
-                    HeapBlockMagic::from_usize((*next).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free
-                {
-                    let target_ptr = (block as usize) + (*block).size;
-                    if target_ptr == next as usize
-                    {
-                        /* we're adjacent, we're both free, and we can merge */
-                        let merged_size = (*block).size + (*next).size;
-                        if merged_size > largest_merged_block
-                        {
-                            largest_merged_block = merged_size;
-                        }
-                        (*block).size = merged_size;
-                        (*block).next = (*next).next;
-                    }
-                }
-                match (*block).next
-                {
-                    Some(n) => block = n,
-                    None => break,
-                };
-            }
+    /* count blocks against the module that allocated them. blocks can be freed by any core */
+    #[cfg(feature = "heapaudit")]
+    fn tagged(&self, tag: usize, requested: usize)
+    {
+        let tag = core::cmp::min(tag, HEAP_TAG_OVERFLOW);
+        HEAP_TAG_BLOCKS[tag].fetch_add(1, Ordering::Relaxed);
+        HEAP_TAG_BYTES[tag].fetch_add(requested, Ordering::Relaxed);
+    }
 
-            /* catch corner case of there being two free blocks: the first on the
-            list is higher than the last block on the list, and they are both free */
-            if HeapBlockMagic::from_usize((*self.block_list_head).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free
-            {
-                match (*self.block_list_head).next
-                {
-                    Some(next) =>
-                    {
-                        if HeapBlockMagic::from_usize((*next).magic.load(Ordering::SeqCst)) == HeapBlockMagic::Free
-                        {
-                            if (next as usize) + (*next).size == self.block_list_head as usize
-                            {
-                                (*next).size = (*next).size + (*self.block_list_head).size;
-                                self.block_list_head = next;
-                                if (*next).size > largest_merged_block
-                                {
-                                    largest_merged_block = (*next).size;
-                                }
-                            }
-                        }
-                    },
-                    _ => ()
-                }
-            }
-        }
+    #[cfg(feature = "heapaudit")]
+    fn untagged(&self, tag: usize, requested: usize)
+    {
+        let tag = core::cmp::min(tag, HEAP_TAG_OVERFLOW);
+        HEAP_TAG_BLOCKS[tag].fetch_sub(1, Ordering::Relaxed);
+        HEAP_TAG_BYTES[tag].fetch_sub(requested, Ordering::Relaxed);
+    }
+}
 
-        return largest_merged_block;
+/* <= a damaged or misused heap block's address, size, and where known, the physical core that allocated it and when */
+fn describe(block: &BlockReport) -> String
+{
+    let mut text = format!("0x{:x} ({} bytes requested, {} byte block)", block.base, block.requested, block.size);
+    if let (Some(owner), Some(generation)) = (block.owner, block.generation)
+    {
+        text.push_str(&format!(" allocated by physical CPU core {} in generation {}", owner, generation));
     }
+    text
+}
 
-    /* generate a block of statistics describing the heap */
-    pub fn calculate_stats(&self) -> HeapStats
+/* check the heap of this physical core for damage
+   <= Ok if intact, or a description of the damage */
+#[cfg(feature = "integritychecks")]
+pub fn check_integrity(heap: &Heap) -> Result<(), Damage>
+{
+    match heap.check_integrity()
     {
-        let mut free_total = 0;
-        let mut alloc_total = 0;
-        let mut largest_free = 0;
-        let mut largest_alloc = 0;
+        Ok(()) => Ok(()),
+        Err(hvalgo::heap::Damage::Heap(found)) => Err(Damage::Heap(found)),
+        Err(hvalgo::heap::Damage::Block(block, found, below)) =>
+            Err(Damage::HeapBlock(block, found, below.map(|(below, tag)| (below, block_owner(tag)))))
+    }
+}
 
-        let mut done = false;
-        let mut block = self.block_list_head;
-        unsafe
-        {
-            while !done
-            {
-                let size = (*block).size;
-                match HeapBlockMagic::from_usize((*block).magic.load(Ordering::SeqCst))
-                {
-                    HeapBlockMagic::InUse =>
-                    {
-                        alloc_total = alloc_total + size;
-                        if size > largest_alloc
-                        {
-                            largest_alloc = size;
-                        }
-                    },
-                    HeapBlockMagic::Free =>
-                    {
-                        free_total = free_total + size;
-                        if size > largest_free
-                        {
-                            largest_free = size;
-                        }
-                    },
-                    HeapBlockMagic::BadMagic => hvdebug!("Bad magic for heap block {:p} during audit", block)
-                };
+/* name the module that allocated a block, for reporting corruption
+   => tag = tag of the in-use block to identify
+   <= module name, or a placeholder if it can't be known */
+#[cfg(all(feature = "integritychecks", feature = "heapaudit"))]
+fn block_owner(tag: usize) -> &'static str
+{
+    match tag
+    {
+        HEAP_TAG_UNTAGGED => "(untagged)",
+        tag if tag >= HEAP_TAG_OVERFLOW => "(other)",
+        tag => HEAP_TAG_NAMES.lock()[tag]
+    }
+}
 
-                match (*block).next
-                {
-                    None => done = true,
-                    Some(b) => block = b
-                };
-            }
-        }
+#[cfg(all(feature = "integritychecks", not(feature = "heapaudit")))]
+fn block_owner(_tag: usize) -> &'static str
+{
+    "(unknown: build with heapaudit to identify)"
+}
 
-        HeapStats
-        {
-            free_total,
-            alloc_total,
-            largest_alloc,
-            largest_free
-        }
+/* clean up heap list by returning chunks of free temporary physical RAM,
+and look for overwritten canaries and bad stamps if memory poisoning or heap guarding is enabled */
+macro_rules! heaphousekeeper
+{
+    () =>
+    {
+        #[cfg(any(feature = "memorypoison", feature = "heapguard"))]
+        (*<super::pcore::PhysicalCore>::this()).heap.check_canaries();
+        (*<super::pcore::PhysicalCore>::this()).heap.return_unused();
     }
 }
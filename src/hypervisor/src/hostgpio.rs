@@ -0,0 +1,163 @@
+/* diosix host GPIO controller
+ *
+ * Drive the lines of the host's general-purpose I/O controller on behalf
+ * of gpio.rs. That's currently the SiFive GPIO block found on the SiFive
+ * boards and QEMU's sifive_u, which keeps one bit per line in each of its
+ * input enable, output enable, input value, and output value registers.
+ * The number of lines comes from the controller's ngpios property.
+ *
+ * Every line shares those registers, so they're read, modified, and
+ * written back with the controller locked.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use super::lock::Mutex;
+use hvalgo::fdt::Fdt;
+use hypercall::gpio::Direction;
+
+/* compatible string of the GPIO controllers this code can drive */
+pub const COMPATIBLE: &str = "sifive,gpio0";
+
+/* number of lines to assume if the controller doesn't say, and the most the registers can hold */
+const DEFAULT_LINES: usize = 16;
+const MAX_LINES: usize = 32;
+
+/* register layout, as offsets from the controller's base address */
+const INPUT_VAL: usize = 0x00;
+const INPUT_EN: usize = 0x04;
+const OUTPUT_EN: usize = 0x08;
+const OUTPUT_VAL: usize = 0x0c;
+
+/* the host's GPIO controller */
+struct Controller
+{
+    base: usize,    /* physical base address of the registers */
+    lines: usize    /* number of lines it provides */
+}
+
+impl Controller
+{
+    /* set or clear a line's bit in a register
+       => register = offset of the register
+          line = line number
+          set = true to set the bit, false to clear it */
+    fn update(&self, register: usize, line: usize, set: bool)
+    {
+        let register = (self.base + register) as *mut u32;
+        unsafe
+        {
+            let bits = ptr::read_volatile(register);
+            ptr::write_volatile(register, match set
+            {
+                true => bits | (1 << line),
+                false => bits & !(1 << line)
+            });
+        }
+    }
+
+    /* <= whether a line's bit in a register is set */
+    fn test(&self, register: usize, line: usize) -> bool
+    {
+        unsafe { ptr::read_volatile((self.base + register) as *const u32) & (1 << line) != 0 }
+    }
+}
+
+lazy_static!
+{
+    /* the host's GPIO controller, if it has one */
+    static ref CONTROLLER: Mutex<Option<Controller>> = Mutex::new("host GPIO controller", None);
+}
+
+/* find the host's GPIO controller in the host's device tree. call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let node = match fdt.nodes().find(|n| n.is_enabled() && n.is_compatible(COMPATIBLE))
+    {
+        Some(n) => n,
+        None => return
+    };
+
+    let base = match node.reg().ok().and_then(|mut reg| reg.next())
+    {
+        Some((base, _)) => base as usize,
+        None => return
+    };
+
+    let lines = match node.property_u32("ngpios")
+    {
+        Some(count) => (count as usize).min(MAX_LINES),
+        None => DEFAULT_LINES
+    };
+
+    hvdebug!("Host GPIO controller at 0x{:x} with {} lines", base, lines);
+    *(CONTROLLER.lock()) = Some(Controller { base, lines });
+}
+
+/* <= number of lines the host's GPIO controller provides, or None if there isn't one */
+pub fn line_count() -> Option<usize>
+{
+    CONTROLLER.lock().as_ref().map(|c| c.lines)
+}
+
+/* make a line an input or an output
+   => line = host GPIO line number
+      direction = whether the line is to be read or driven
+   <= false if there's no such line */
+pub fn set_direction(line: usize, direction: Direction) -> bool
+{
+    match &*(CONTROLLER.lock())
+    {
+        Some(c) if line < c.lines =>
+        {
+            let output = match direction
+            {
+                Direction::Input => false,
+                Direction::Output => true
+            };
+
+            /* stop driving the line before listening to it, and vice versa */
+            c.update(OUTPUT_EN, line, output);
+            c.update(INPUT_EN, line, output == false);
+            true
+        },
+        _ => false
+    }
+}
+
+/* drive a line high or low
+   => line = host GPIO line number
+      value = true for high, false for low
+   <= false if there's no such line */
+pub fn write(line: usize, value: bool) -> bool
+{
+    match &*(CONTROLLER.lock())
+    {
+        Some(c) if line < c.lines =>
+        {
+            c.update(OUTPUT_VAL, line, value);
+            true
+        },
+        _ => false
+    }
+}
+
+/* read a line's level. outputs read back the level they're driving
+   => line = host GPIO line number
+   <= true for high, false for low, or None if there's no such line */
+pub fn read(line: usize) -> Option<bool>
+{
+    match &*(CONTROLLER.lock())
+    {
+        Some(c) if line < c.lines => Some(match c.test(OUTPUT_EN, line)
+        {
+            true => c.test(OUTPUT_VAL, line),
+            false => c.test(INPUT_VAL, line)
+        }),
+        _ => None
+    }
+}
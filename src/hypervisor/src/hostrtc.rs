@@ -0,0 +1,63 @@
+/* diosix host real-time clock
+ *
+ * Read the wall-clock time from the host's real-time clock, if it has
+ * one the hypervisor can drive. That's currently the Goldfish RTC found
+ * on QEMU's virt board, which counts nanoseconds since the Unix epoch in
+ * a pair of 32-bit registers. Reading the low half latches the high half,
+ * so the low half is always read first.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use super::lock::Mutex;
+use hvalgo::fdt::Fdt;
+
+/* compatible string of the RTCs this code can drive */
+pub const COMPATIBLE: &str = "google,goldfish-rtc";
+
+/* register layout, as offsets from the RTC's base address */
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+lazy_static!
+{
+    /* base address of the host's RTC, if it has one */
+    static ref RTC: Mutex<Option<usize>> = Mutex::new("host RTC", None);
+}
+
+/* find the host's RTC in the host's device tree. call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let base = fdt.nodes()
+        .find(|n| n.is_enabled() && n.is_compatible(COMPATIBLE))
+        .and_then(|n| n.reg().ok().and_then(|mut reg| reg.next()));
+
+    if let Some((base, _)) = base
+    {
+        hvdebug!("Host RTC at 0x{:x}", base);
+        *(RTC.lock()) = Some(base as usize);
+    }
+}
+
+/* <= the wall-clock time in nanoseconds since 1970-01-01 00:00:00 UTC,
+      or None if there's no RTC or it hasn't been set */
+pub fn read() -> Option<u64>
+{
+    let base = (*(RTC.lock()))?;
+    let (low, high) = unsafe
+    {
+        let low = ptr::read_volatile((base + TIME_LOW) as *const u32);
+        let high = ptr::read_volatile((base + TIME_HIGH) as *const u32);
+        (low, high)
+    };
+
+    match ((high as u64) << 32) | low as u64
+    {
+        0 => None,
+        time => Some(time)
+    }
+}
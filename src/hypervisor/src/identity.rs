@@ -0,0 +1,116 @@
+/* diosix per-capsule identity and entropy provisioning
+ *
+ * Each capsule is given a unique, random 128-bit ID when it's created,
+ * formatted as a version 4 UUID, so that guests booted from the same
+ * image have distinct machine IDs. The ID lasts for the lifetime of the
+ * capsule, including across restarts.
+ *
+ * Each time a capsule's device tree is written, it's also given a fresh
+ * random seed so that its RNG is seeded without network access or
+ * waiting for entropy to accumulate. Both are placed in the tree's
+ * /chosen node: the seed as rng-seed, and the ID as diosix,capsule-uuid.
+ *
+ * Randomness is drawn from the host's hardware RNG. If the host has
+ * none, IDs are made by stirring the timer into a mixing function
+ * instead: this keeps capsules' IDs distinct, but not unpredictable.
+ * Seeds must be unpredictable, so without a hardware RNG there's no
+ * seed, and guests gather their own entropy.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::capsule::CapsuleID;
+use super::hardware;
+
+/* number of 64-bit words in each capsule's RNG seed */
+pub const SEED_WORDS: usize = 4;
+
+/* state of the fallback generator used when there's no hardware RNG */
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static!
+{
+    /* unique IDs assigned to capsules */
+    static ref IDS: Mutex<HashMap<CapsuleID, u128>> = Mutex::new("capsule ID table", HashMap::new());
+}
+
+/* mix a 64-bit value so that similar inputs produce very different outputs (splitmix64's finalizer) */
+fn mix(mut value: u64) -> u64
+{
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+/* return a random 64-bit value, from the host's RNG if possible */
+fn random_u64() -> u64
+{
+    if let Some(value) = hardware::get_random()
+    {
+        return value;
+    }
+
+    let now = match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
+    {
+        (Some(time), Some(frequency)) => time.to_exact(frequency),
+        (_, _) => 0
+    };
+
+    let state = FALLBACK_STATE.fetch_add(0x9e3779b97f4a7c15, Ordering::SeqCst);
+    mix(state ^ mix(now))
+}
+
+/* assign a unique ID to a newly created capsule
+   => cid = capsule to provision
+   <= the capsule's ID */
+pub fn provision(cid: CapsuleID) -> u128
+{
+    let mut ids = IDS.lock();
+    loop
+    {
+        let mut id = ((random_u64() as u128) << 64) | (random_u64() as u128);
+
+        /* mark the ID as a random, RFC 4122 version 4 UUID */
+        id = (id & !(0xf << 76)) | (0x4 << 76);
+        id = (id & !(0x3 << 62)) | (0x2 << 62);
+
+        /* vanishingly unlikely, but make sure the ID really is unique */
+        if ids.values().any(|&existing| existing == id) == false
+        {
+            ids.insert(cid, id);
+            return id;
+        }
+    }
+}
+
+/* return a capsule's unique ID, provisioning one if it doesn't have one yet */
+pub fn get_id(cid: CapsuleID) -> u128
+{
+    if let Some(&id) = IDS.lock().get(&cid)
+    {
+        return id;
+    }
+    provision(cid)
+}
+
+/* return a fresh random seed for a capsule's RNG, or None if the host has no hardware RNG to provide one */
+pub fn new_seed() -> Option<[u64; SEED_WORDS]>
+{
+    let mut seed = [0; SEED_WORDS];
+    for word in seed.iter_mut()
+    {
+        *word = hardware::get_random()?;
+    }
+    Some(seed)
+}
+
+/* forget a capsule's ID when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    IDS.lock().remove(&cid);
+}
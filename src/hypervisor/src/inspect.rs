@@ -0,0 +1,137 @@
+/* diosix paused capsule inspection
+ *
+ * A paused capsule, such as one that crashed with the pause-on-crash
+ * property, can be examined by management services before it's resumed
+ * or killed. When a capsule is paused, its waiting virtual cores are
+ * parked straight from the scheduling queues, and every physical core
+ * running one of its vcores is told to switch it out and park it too.
+ *
+ * A running vcore's registers are captured from its IRQ context on the
+ * way into the hypervisor, before it can be switched out, using the
+ * same code as crash dumps. Vcores that were waiting rather
+ * than running when the capsule paused were last saved in the platform's
+ * own format, which only the platform can read, so they have no snapshot.
+ *
+ * A paused capsule's memory can also be read, as nothing in the capsule
+ * is running to change it. Snapshots are dropped when the capsule is
+ * resumed or destroyed.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty, CapsuleState};
+use super::vcore::VirtualCoreID;
+use super::pcore::PhysicalCore;
+use super::hcargs::{self, Access};
+use super::trap;
+use platform::irq::IRQContext;
+
+/* most bytes of a capsule's memory copied per call */
+const MEMORY_READ_MAX: usize = 64 * 1024;
+
+lazy_static!
+{
+    /* registers of each paused capsule's vcores, general-purpose then control and status, as captured */
+    static ref SNAPSHOTS: Mutex<HashMap<(CapsuleID, VirtualCoreID), Vec<usize>>> = Mutex::new("paused vcore registers", HashMap::new());
+}
+
+/* snapshot the registers of the vcore running on this physical core if its capsule is paused
+   and this is the vcore's first trip into the hypervisor since. call on entry to the
+   hypervisor, before the vcore can be switched out
+   => context = IRQ context holding the vcore's registers */
+pub fn capture(context: &IRQContext)
+{
+    let id = match PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => id,
+        None => return
+    };
+
+    if capsule::get_state(id.capsuleid) != Some(CapsuleState::Paused)
+    {
+        return;
+    }
+
+    let mut snapshots = SNAPSHOTS.lock();
+    if snapshots.contains_key(&(id.capsuleid, id.vcoreid)) == false
+    {
+        let state = trap::crash_registers(context);
+        let mut registers = Vec::new();
+        for (_, value) in state.general().iter().chain(state.control().iter())
+        {
+            registers.push(*value);
+        }
+        snapshots.insert((id.capsuleid, id.vcoreid), registers);
+    }
+}
+
+/* copy a paused vcore's registers into the currently running capsule's memory, general-purpose
+   registers first in the order of crash dumps, then the control and status registers.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid, vid = paused vcore whose registers are wanted
+      buffer, count = address and size in machine words of the array to fill in the running capsule
+   <= number of registers captured, or an error code if there's no snapshot */
+pub fn read_registers(cid: CapsuleID, vid: VirtualCoreID, buffer: usize, count: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+    if capsule::get_state(cid) != Some(CapsuleState::Paused)
+    {
+        return Err(Cause::CapsuleNotPaused);
+    }
+
+    /* don't hold the snapshots lock while looking up the caller's memory */
+    let registers = match SNAPSHOTS.lock().get(&(cid, vid))
+    {
+        Some(registers) => registers.clone(),
+        None => return Err(Cause::InspectNoRegisters)
+    };
+
+    let to_copy = core::cmp::min(count, registers.len());
+    if to_copy > 0
+    {
+        let base = hcargs::array::<usize>(caller, buffer, to_copy, registers.len(), Access::Write)?;
+        let target = unsafe { slice::from_raw_parts_mut(base as *mut usize, to_copy) };
+        target.copy_from_slice(&registers[..to_copy]);
+    }
+
+    Ok(registers.len())
+}
+
+/* copy part of a paused capsule's memory into the currently running capsule's memory.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = paused capsule to read
+      address = start of the memory to read, as a physical address in the paused capsule
+      buffer, size = address and size in bytes of the buffer to fill in the running capsule.
+                     at most MEMORY_READ_MAX bytes are copied per call
+   <= number of bytes copied, or an error code */
+pub fn read_memory(cid: CapsuleID, address: usize, buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+    if capsule::get_state(cid) != Some(CapsuleState::Paused)
+    {
+        return Err(Cause::CapsuleNotPaused);
+    }
+
+    let to_copy = core::cmp::min(size, MEMORY_READ_MAX);
+    if to_copy > 0
+    {
+        let source = hcargs::buffer(cid, address, to_copy, MEMORY_READ_MAX, Access::Read)?;
+        let target = hcargs::buffer(caller, buffer, to_copy, MEMORY_READ_MAX, Access::Write)?;
+        unsafe { core::ptr::copy(source as *const u8, target as *mut u8, to_copy) };
+    }
+
+    Ok(to_copy)
+}
+
+/* discard a capsule's register snapshots when it's resumed or destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    SNAPSHOTS.lock().retain(|(owner, _), _| *owner != cid);
+}
@@ -0,0 +1,80 @@
+/* diosix hypervisor integrity checks
+ *
+ * The hypervisor runs in machine mode without paging, so it can't
+ * surround its per-CPU stacks and heaps with guard pages to catch
+ * overruns as they happen. Instead, known words are placed where
+ * overruns will hit them first, and checked regularly:
+ *
+ * - the last word of each physical core's private variables, which
+ *   its private stack grows down towards, catches stack overflows
+ * - the magic word at the start of those variables catches
+ *   anything else overwriting them
+ * - a guard word at the start of each heap block header catches
+ *   an overrun from the block below it
+ *
+ * With the integritychecks feature, these are checked on entry to
+ * every IRQ, on every context switch, and during housekeeping.
+ * On finding damage, the subsystem that owns the damaged memory is
+ * reported, and the physical core is halted before it can make
+ * things worse. For heap blocks, the module that allocated the
+ * block below the damage is named if built with heapaudit.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt;
+
+/* describe damage found by an integrity check */
+#[derive(Debug)]
+pub enum Damage
+{
+    PrivateVariables(usize),    /* physical core's private variables overwritten, with the value found */
+    PrivateStack(usize),        /* physical core's private stack overflowed, with the value found */
+    Heap(usize),                /* physical core's heap structure overwritten, with the value found */
+
+    /* heap block header overwritten: address of the block, value found in its guard,
+       and the address and owning module of the in-use block below it, if one was found */
+    HeapBlock(usize, usize, Option<(usize, &'static str)>)
+}
+
+impl fmt::Display for Damage
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Damage::PrivateVariables(found) =>
+                write!(f, "physical core private variables overwritten (0x{:x})", found),
+            Damage::PrivateStack(found) =>
+                write!(f, "physical core private stack overflowed (0x{:x})", found),
+            Damage::Heap(found) =>
+                write!(f, "physical core private heap overwritten (0x{:x})", found),
+            Damage::HeapBlock(block, found, Some((below, owner))) =>
+                write!(f, "heap block 0x{:x} header overwritten (0x{:x}), likely overrun by block 0x{:x} allocated by {}",
+                    block, found, below, owner),
+            Damage::HeapBlock(block, found, None) =>
+                write!(f, "heap block 0x{:x} header overwritten (0x{:x}), no in-use block found below it", block, found)
+        }
+    }
+}
+
+/* check this physical core's stack, private variables, and heap, and halt the core if any are damaged
+   => site = where the check is being made, for reporting problems */
+#[cfg(feature = "integritychecks")]
+pub fn check(site: &'static str)
+{
+    let result = match super::pcore::PhysicalCore::integrity_check()
+    {
+        Ok(()) => super::heap::check_integrity(&super::pcore::PhysicalCore::this().heap),
+        Err(damage) => Err(damage)
+    };
+
+    if let Err(damage) = result
+    {
+        hvalert!("Integrity check failed during {} on physical CPU core {}: {}. Halting!",
+            site, super::pcore::PhysicalCore::get_id(), damage);
+        loop {}
+    }
+}
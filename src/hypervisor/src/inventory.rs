@@ -0,0 +1,238 @@
+/* diosix hardware inventory
+ *
+ * Once the hardware has been discovered at boot, describe it: each
+ * physical CPU core and its ISA, each bank of RAM, the persistent store
+ * and DMA-capable ranges, and every peripheral, along with who owns it:
+ * the hypervisor, a capsule it's been passed through to, or no one, in
+ * which case it's available for passthrough. The inventory is written
+ * to the debug log as the host boots, replacing the old one-line banner.
+ *
+ * Management capsules can read the same inventory by hypercall as
+ * machine-readable text, one item per line, each a keyword followed by
+ * space-separated fields. Addresses and sizes are in hex:
+ *
+ *   version <hypervisor version>
+ *   core <id> hart <hart ID> isa <ISA> capsules <yes|no>
+ *   memory <base> <size>
+ *   pstore <base> <size>
+ *   dma <base> <size>
+ *   peripheral <name> <base> <size> irq <number|none> owner <hypervisor|capsule N|available>
+ *
+ * Owners of peripherals are filled in as the inventory is read, so they
+ * reflect devices since passed through or released. Lines with unknown
+ * keywords should be skipped, so that more can be added later.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt::Write;
+use super::lock::Mutex;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::{self, CapsuleProperty};
+use super::hardware;
+use super::passthrough;
+use super::hcargs::{self, Access};
+use super::pcore::{PhysicalCore, PhysicalCoreID, HartID};
+
+const KILOBYTE: usize = 1024;
+const MEGABYTE: usize = KILOBYTE * KILOBYTE;
+const GIGABYTE: usize = KILOBYTE * MEGABYTE;
+
+/* a physical CPU core as it reported for duty */
+struct Core
+{
+    id: PhysicalCoreID,
+    hart: HartID,
+    isa: String,
+    capsules: bool /* true if it can run capsules' virtual cores */
+}
+
+lazy_static!
+{
+    /* the physical CPU cores that have joined the roll call, in the order they joined */
+    static ref CORES: Mutex<Vec<Core>> = Mutex::new("hardware inventory cores", Vec::new());
+}
+
+/* <= a size written out using GiB / MiB / KiB */
+fn size_text(size: usize) -> String
+{
+    if size >= GIGABYTE
+    {
+        format!("{} GiB", size / GIGABYTE)
+    }
+    else if size >= MEGABYTE
+    {
+        format!("{} MiB", size / MEGABYTE)
+    }
+    else
+    {
+        format!("{} KiB", size / KILOBYTE)
+    }
+}
+
+/* <= the owner of a peripheral, as written in the inventory. devices passed through
+   to capsules are no longer used by the hypervisor, so check those first
+   => hypervisor = true if the hypervisor drives the peripheral itself
+      base = the peripheral's physical base address */
+fn owner(hypervisor: bool, base: usize) -> String
+{
+    match (passthrough::owner(base), hypervisor)
+    {
+        (Some(cid), _) => format!("capsule {}", cid),
+        (None, true) => String::from("hypervisor"),
+        (None, false) => String::from("available")
+    }
+}
+
+/* write the host's memory, peripherals, and boot information to the debug log.
+   call on the boot core once the hardware has been discovered and physical memory registered */
+pub fn init()
+{
+    hvdebug!("Diosix {} :: Debug enabled. {} expected. Hardware inventory follows",
+        env!("CARGO_PKG_VERSION"),
+        match hardware::get_nr_cpu_cores()
+        {
+            None | Some(0) => format!("no CPU cores"),
+            Some(1) => format!("1 CPU core"),
+            Some(c) => format!("{} CPU cores", c)
+        });
+
+    match hardware::get_phys_ram_chunks()
+    {
+        Some(banks) =>
+        {
+            for (index, bank) in banks.iter().enumerate()
+            {
+                hvdebug!("RAM bank {}: 0x{:x} - 0x{:x} ({})",
+                    index, bank.base, bank.base + bank.size, size_text(bank.size));
+            }
+            hvdebug!("RAM total: {}", size_text(hardware::get_phys_ram_total().unwrap_or(0)));
+        },
+        None => hvdebug!("RAM total: none found")
+    }
+
+    if let Some(area) = hardware::get_pstore_area()
+    {
+        hvdebug!("Persistent store: 0x{:x} - 0x{:x} ({})", area.base, area.base + area.size, size_text(area.size));
+    }
+
+    if let Some(ranges) = hardware::get_dma_ranges()
+    {
+        for range in ranges
+        {
+            hvdebug!("DMA range: 0x{:x} - 0x{:x}", range.base, range.base + range.size);
+        }
+    }
+
+    for peripheral in hardware::get_peripherals()
+    {
+        hvdebug!("Peripheral {} ({}): 0x{:x} - 0x{:x}, IRQ {}, owned by {}",
+            peripheral.name, peripheral.compatible, peripheral.base, peripheral.base + peripheral.size,
+            match peripheral.irq
+            {
+                Some(irq) => format!("{}", irq),
+                None => String::from("none")
+            },
+            owner(peripheral.hypervisor, peripheral.base));
+    }
+}
+
+/* add the calling physical CPU core to the inventory and report it. call as the core joins the roll call */
+pub fn add_core()
+{
+    let core = Core
+    {
+        id: PhysicalCore::get_id(),
+        hart: PhysicalCore::get_hart_id(),
+        isa: format!("{:?}", PhysicalCore::describe()),
+        capsules: PhysicalCore::smode_supported()
+    };
+
+    hvdebug!("Physical CPU core {} (hart {}) {} ready to roll{}", core.id, core.hart, core.isa,
+        match core.capsules
+        {
+            true => "",
+            false => ", though it can't run capsules"
+        });
+
+    CORES.lock().push(core);
+}
+
+/* <= the inventory written out as machine-readable text */
+fn render() -> String
+{
+    let mut text = String::new();
+    let _ = write!(text, "version {}\n", env!("CARGO_PKG_VERSION"));
+
+    let mut cores: Vec<(PhysicalCoreID, HartID, String, bool)> = CORES.lock().iter()
+        .map(|core| (core.id, core.hart, core.isa.clone(), core.capsules)).collect();
+    cores.sort_by_key(|core| core.0);
+    for (id, hart, isa, capsules) in cores
+    {
+        let _ = write!(text, "core {} hart {} isa {} capsules {}\n", id, hart, isa,
+            match capsules
+            {
+                true => "yes",
+                false => "no"
+            });
+    }
+
+    if let Some(banks) = hardware::get_phys_ram_chunks()
+    {
+        for bank in banks
+        {
+            let _ = write!(text, "memory 0x{:x} 0x{:x}\n", bank.base, bank.size);
+        }
+    }
+
+    if let Some(area) = hardware::get_pstore_area()
+    {
+        let _ = write!(text, "pstore 0x{:x} 0x{:x}\n", area.base, area.size);
+    }
+
+    if let Some(ranges) = hardware::get_dma_ranges()
+    {
+        for range in ranges
+        {
+            let _ = write!(text, "dma 0x{:x} 0x{:x}\n", range.base, range.size);
+        }
+    }
+
+    for peripheral in hardware::get_peripherals()
+    {
+        let _ = write!(text, "peripheral {} 0x{:x} 0x{:x} irq {} owner {}\n",
+            peripheral.name, peripheral.base, peripheral.size,
+            match peripheral.irq
+            {
+                Some(irq) => format!("{}", irq),
+                None => String::from("none")
+            },
+            owner(peripheral.hypervisor, peripheral.base));
+    }
+
+    text
+}
+
+/* copy the inventory, as machine-readable text, into the currently running capsule's memory.
+   *** the currently running capsule must have the manage_capsules property ***
+   => buffer, size = address and size in bytes of the buffer in the capsule
+   <= size of the whole inventory in bytes, which may be larger than the amount copied, or an error code */
+pub fn read(buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+    let inventory = render();
+
+    let to_copy = core::cmp::min(size, inventory.len());
+    if to_copy > 0
+    {
+        let base = hcargs::buffer(caller, buffer, to_copy, inventory.len(), Access::Write)?;
+        let target = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, to_copy) };
+        target.copy_from_slice(&inventory.as_bytes()[..to_copy]);
+    }
+
+    Ok(inventory.len())
+}
@@ -0,0 +1,56 @@
+/* diosix IOMMU layer
+ *
+ * Passed-through devices capable of DMA are confined to their capsule's
+ * memory by an IOMMU, if they sit behind one the hypervisor can drive.
+ * A device names the IOMMU it sits behind, and the ID the IOMMU knows
+ * it by, in the iommus property of its device tree node.
+ *
+ * No IOMMU drivers are built in yet, so this is the no-IOMMU stub:
+ * IOMMUs found in the host's device tree are listed, but every device
+ * is reported as not being behind one. Passthrough then falls back to
+ * bouncing a capsule's DMA through hypervisor-allocated memory, rather
+ * than trusting an IOMMU that hasn't been programmed to confine it.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use hvalgo::fdt::Fdt;
+use super::error::Cause;
+
+/* compatible strings of IOMMUs that may be found in the host's device tree */
+pub const COMPATIBLE: [&str; 1] = [ "riscv,iommu" ];
+
+/* find the host's IOMMUs in its device tree. call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    for node in fdt.nodes().filter(|n| n.is_enabled() && COMPATIBLE.iter().any(|c| n.is_compatible(c)))
+    {
+        hvdebug!("Found IOMMU {} without a driver: DMA of devices behind it will be bounced", node.name());
+    }
+}
+
+/* <= the ID the IOMMU uses to identify the DMA-capable device at the given MMIO base address,
+      or None if the device isn't behind an IOMMU that can be driven */
+pub fn get_device_id(_base: PhysMemBase) -> Option<usize>
+{
+    None
+}
+
+/* program the IOMMU so that the given device can only DMA into the given physical memory ranges,
+   replacing any ranges it was previously confined to
+   => device_id = IOMMU device ID of the device
+      ranges = list of (base address, size in bytes) of memory the device may access
+   <= Ok for success, or an error code */
+pub fn confine(_device_id: usize, _ranges: &Vec<(PhysMemBase, PhysMemSize)>) -> Result<(), Cause>
+{
+    Err(Cause::PassthroughIOMMUFailure)
+}
+
+/* program the IOMMU to block all DMA by the given device
+   => device_id = IOMMU device ID of the device */
+pub fn block(_device_id: usize) {}
@@ -10,14 +10,59 @@ use super::capsule;
 use super::pcore;
 use super::hardware;
 use super::service;
-use super::error::Cause;
+use super::passthrough;
+use super::message;
+use super::abi;
+use super::transfer;
+use super::bounce;
+use super::trace;
+use super::selftest;
+use super::guestlog;
+use super::seriallink;
+use super::metrics;
+use super::failover;
+use super::wss;
+use super::warmboot;
+use super::guestpanic;
+use super::abboot;
+use super::clock;
+use super::power;
+use super::console;
+use super::gpio;
+use super::throttle;
+use super::stream;
+use super::pressure;
+use super::grant;
+use super::telemetry;
+use super::measure;
+use super::sealed;
+use super::settings;
+use super::crashdump;
+use super::inventory;
+use super::vipi;
+use super::mmio;
+use super::dirty;
+use super::template;
+use super::quiesce;
+use super::button;
+use super::accounting::{self, Activity};
+use super::inspect;
+use super::clint;
+use super::machine;
+use super::trap;
+use super::syscalls;
+#[cfg(feature = "integritychecks")]
+use super::integrity;
+use super::error::{self, Cause};
+#[cfg(feature = "sbilegacy")]
+use super::sbilegacy;
 
 /* platform-specific code must implement all this */
 use platform;
 use platform::irq::{IRQContext, IRQType, IRQCause, IRQSeverity, IRQ};
 use platform::cpu::PrivilegeMode;
 use platform::instructions::{self, EmulationResult};
-use platform::syscalls;
+use hypercall::call::ActionResult;
 use platform::timer;
 
 /* hypervisor_irq_handler
@@ -29,18 +74,47 @@ use platform::timer;
 #[no_mangle]
 pub extern "C" fn hypervisor_irq_handler(mut context: IRQContext)
 {
+    #[cfg(feature = "integritychecks")]
+    integrity::check("IRQ entry");
+
+    /* stop the clock on the capsule's time in guest mode, and start it on the hypervisor's work for it */
+    accounting::enter();
+
+    /* don't let the details of an earlier, unreported error attach themselves to one raised this time */
+    error::forget_context();
+
+    /* keep the registers of a paused capsule's vcore before anything can switch it out */
+    inspect::capture(&context);
+
     /* if dispatch() returns an IRQ context then we need to handle it here
     at the high level. if it returns None, the platform-specific code handled it.
     note: the platform library should take care of hardware specfic things like
     catching illegal instructions that can be fixed up and handled transparently */
-    if let Some(irq) = platform::irq::dispatch(context)
+    let activity = match platform::irq::dispatch(context)
     {
-        match irq.irq_type
+        Some(irq) => match irq.irq_type
         {
-            IRQType::Exception => exception(irq, &mut context),
-            IRQType::Interrupt => interrupt(irq, &mut context),
-        };
-    }
+            IRQType::Exception =>
+            {
+                let activity = match irq.cause
+                {
+                    IRQCause::SupervisorEnvironmentCall => Activity::Hypercall,
+                    _ => Activity::Fault
+                };
+                exception(irq, &mut context);
+                Some(activity)
+            },
+            IRQType::Interrupt =>
+            {
+                interrupt(irq, &mut context);
+                None
+            }
+        },
+        None => None
+    };
+
+    /* interrupts are the hypervisor's own business, and aren't charged to the capsule */
+    accounting::leave(activity);
 }
 
 /* handle software exception */
@@ -54,7 +128,7 @@ fn exception(irq: IRQ, context: &mut IRQContext)
         {
             match instructions::emulate(irq.privilege_mode, context)
             {
-                EmulationResult::Success => (), /* nothing more to do, return */
+                EmulationResult::Success => throttle::trapped(), /* nothing more to do unless it's trapping too often */
                 EmulationResult::Yield =>
                 {
                     /* instruction was some kind of sleep or pause operation.
@@ -65,24 +139,81 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                 /* if we can't handle the instruction,
                 kill the capsule and force a context switch.
                 TODO: is killing the whole capsule a little extreme? */
-                _ => fatal_exception(&irq)
+                _ => fatal_exception(&irq, context)
+            }
+        },
+
+        /* catch accesses to pages revoked to sample the capsule's working set */
+        (_, PrivilegeMode::User, IRQCause::InstructionAccessFault) |
+        (_, PrivilegeMode::User, IRQCause::LoadAccessFault) |
+        (_, PrivilegeMode::User, IRQCause::StoreAccessFault) |
+        (_, PrivilegeMode::Supervisor, IRQCause::InstructionAccessFault) |
+        (_, PrivilegeMode::Supervisor, IRQCause::LoadAccessFault) |
+        (_, PrivilegeMode::Supervisor, IRQCause::StoreAccessFault) =>
+        {
+            /* writes to pages write-protected to track changes are retried too */
+            let addr = machine::fault_address();
+            let retry = match irq.cause
+            {
+                IRQCause::StoreAccessFault => dirty::write_fault(addr) || wss::access_fault(addr),
+                _ => wss::access_fault(addr)
+            };
+
+            if retry == false
+            {
+                fatal_exception(&irq, context);
             }
         },
 
         /* catch environment calls from supervisor mode */
         (_, PrivilegeMode::Supervisor, IRQCause::SupervisorEnvironmentCall) =>
         {
-            /* determine what we need to do from the platform code's decoding */
+            /* resume after the environment call once it's been handled */
+            trap::skip_environment_call(irq.pc);
+
+            /* translate legacy SBI v0.1 calls, if enabled, before decoding the call as normal */
+            #[cfg(feature = "sbilegacy")]
+            {
+                if sbilegacy::handler(context) == true
+                {
+                    return;
+                }
+            }
+
+            /* determine what we need to do from the trapped registers */
             if let Some(action) = syscalls::handler(context)
             {
+                /* log the call if the capsule is being traced */
+                hvtrace!(Hypercall, "capsule {:?}: {:x?}", pcore::PhysicalCore::get_capsule_id(), action);
+                let traced = trace::begin(&action);
+
                 match action
                 {
+                    /* refuse calls from a newer ABI than the capsule agreed, or beyond its capabilities */
+                    _ if abi::permitted(&action) == false => syscalls::failed(context, ActionResult::Denied),
+
                     syscalls::Action::Yield => scheduler::ping(),
 
+                    /* agree the hypercall ABI version with the capsule, and tell it what it can do */
+                    syscalls::Action::NegotiateABI(version) => match abi::negotiate(version)
+                    {
+                        Ok((agreed, capabilities)) => syscalls::result_1extra(context, agreed, capabilities),
+                        Err(Cause::ABIVersionUnsupported) => syscalls::failed(context, ActionResult::BadParams),
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* let the capsule detect it's running on diosix, and which version */
+                    syscalls::Action::Identify(leaf) => match abi::identify(leaf)
+                    {
+                        Ok((first, second)) => syscalls::result_1extra(context, first, second),
+                        Err(Cause::ABIBadLeaf) => syscalls::failed(context, ActionResult::BadParams),
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
+                    },
+
                     syscalls::Action::Terminate => if let Err(_e) = capsule::destroy_current()
                     {
-                        hvalert!("BUG: Failed to terminate currently running capsule ({:?})", _e);
-                        syscalls::failed(context, syscalls::ActionResult::Failed);
+                        hvalert!("BUG: Failed to terminate currently running capsule ({})", error::report(&_e));
+                        syscalls::failed(context, ActionResult::Failed);
                     }
                     else
                     {
@@ -92,8 +223,8 @@ fn exception(irq: IRQ, context: &mut IRQContext)
 
                     syscalls::Action::Restart => if let Err(_e) = capsule::restart_current()
                     {
-                        hvalert!("BUG: Failed to restart currently running capsule ({:?})", _e);
-                        syscalls::failed(context, syscalls::ActionResult::Failed);
+                        hvalert!("BUG: Failed to restart currently running capsule ({})", error::report(&_e));
+                        syscalls::failed(context, ActionResult::Failed);
                     }
                     else
                     {
@@ -103,18 +234,276 @@ fn exception(irq: IRQ, context: &mut IRQContext)
 
                     syscalls::Action::TimerIRQAt(target) =>
                     {
-                        /* mark this virtual core as awaiting a timer IRQ and
-                        schedule a timer interrupt in anticipation */
-                        pcore::PhysicalCore::set_virtualcore_timer_target(Some(target));
-                        hardware::scheduler_timer_at(target);
+                        let target = scheduler::limit_timer_target(target);
+                        if pcore::PhysicalCore::sstc_supported() == true
+                        {
+                            /* program the supervisor's timer compare register directly so the IRQ
+                            fires without the hypervisor's involvement */
+                            if let Some(frequency) = hardware::scheduler_get_timer_frequency()
+                            {
+                                pcore::PhysicalCore::forget_supervisor_compare();
+                                machine::set_supervisor_compare(target.to_exact(frequency));
+                            }
+                        }
+                        else
+                        {
+                            /* mark this virtual core as awaiting a timer IRQ and schedule a timer
+                            interrupt in anticipation, unless one is already due around then */
+                            pcore::PhysicalCore::set_virtualcore_timer_target(Some(target));
+                            if scheduler::timer_coalesces(target) == false
+                            {
+                                hardware::scheduler_timer_at(target);
+                            }
+                        }
+                    },
+
+                    /* arm an additional timer for this virtual core, and return its ID */
+                    syscalls::Action::TimerAdd(target) => match pcore::PhysicalCore::add_virtualcore_timer(scheduler::limit_timer_target(target))
+                    {
+                        Ok(id) =>
+                        {
+                            if let Some(next) = pcore::PhysicalCore::get_virtualcore_timer_target()
+                            {
+                                if scheduler::timer_coalesces(next) == false
+                                {
+                                    hardware::scheduler_timer_at(next);
+                                }
+                            }
+                            syscalls::result(context, id);
+                        },
+                        Err(Cause::SchedTooManyTimers) => syscalls::failed(context, ActionResult::Denied),
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* disarm one of this virtual core's timers */
+                    syscalls::Action::TimerCancel(id) => if let Err(_) = pcore::PhysicalCore::cancel_virtualcore_timer(id)
+                    {
+                        syscalls::failed(context, ActionResult::BadParams);
+                    },
+
+                    /* collect the ID of the next of this virtual core's timers to have fired */
+                    syscalls::Action::TimerFiredNext => match pcore::PhysicalCore::next_virtualcore_fired_timer()
+                    {
+                        Some(id) => syscalls::result(context, id),
+                        None =>
+                        {
+                            /* all fired timers collected, so the supervisor's own timer can take over again */
+                            if pcore::PhysicalCore::sstc_supported() == true
+                            {
+                                pcore::PhysicalCore::restore_supervisor_compare();
+                            }
+                            syscalls::result(context, usize::MAX) /* -1 == none fired */
+                        }
+                    },
+
+                    /* read one of a capsule's activity counters */
+                    syscalls::Action::MetricsRead(cid, counter) =>
+                    {
+                        let result = match metrics::Counter::from_usize(counter)
+                        {
+                            Some(counter) => metrics::read(cid, counter),
+                            None => Err(Cause::MetricsBadCounter)
+                        };
+
+                        match result
+                        {
+                            Ok(value) => syscalls::result(context, value as usize),
+                            Err(e) => syscalls::failed(context, match e
+                            {
+                                Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                                Cause::CapsuleBadID | Cause::MetricsBadCounter => ActionResult::BadParams,
+                                _ => ActionResult::Failed
+                            })
+                        }
+                    },
+
+                    /* read one of the system-wide counters, such as memory leaked during teardowns.
+                       only manage_capsules capsules can call this */
+                    syscalls::Action::MetricsReadSystem(counter) =>
+                    {
+                        let result = match metrics::SystemCounter::from_usize(counter)
+                        {
+                            Some(counter) => metrics::read_system(counter),
+                            None => Err(Cause::MetricsBadCounter)
+                        };
+
+                        match result
+                        {
+                            Ok(value) => syscalls::result(context, value as usize),
+                            Err(e) => syscalls::failed(context, match e
+                            {
+                                Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                                Cause::MetricsBadCounter => ActionResult::BadParams,
+                                _ => ActionResult::Failed
+                            })
+                        }
+                    },
+
+                    /* copy a telemetry report of the hypervisor's metrics into the caller's buffer and return its full size.
+                       only manage_capsules capsules can call this */
+                    syscalls::Action::TelemetryRead(buffer, size) => match telemetry::read(buffer, size)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* write a structured record from this capsule into the hypervisor's log */
+                    syscalls::Action::GuestLog(severity, tag, message, length) =>
+                    {
+                        let result = match guestlog::Severity::from_usize(severity)
+                        {
+                            Some(severity) => guestlog::write(severity, tag, message, length),
+                            None => Err(Cause::GuestLogBadSeverity)
+                        };
+
+                        if let Err(e) = result
+                        {
+                            syscalls::failed(context, match e
+                            {
+                                Cause::GuestLogRateLimited => ActionResult::Denied,
+                                Cause::GuestLogBadSeverity | Cause::GuestLogTooLong |
+                                Cause::TransferBadDescriptor => ActionResult::BadParams,
+                                _ => ActionResult::Failed
+                            });
+                        }
+                    },
+
+                    /* report why this capsule is about to die, with an optional register dump */
+                    syscalls::Action::GuestPanic(message, length, registers, count) => if let Err(e) = guestpanic::report(message, length, registers, count)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::GuestPanicAlreadyReported => ActionResult::Denied,
+                            Cause::GuestPanicTooLong | Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        });
+                    },
+
+                    /* read a capsule's last panic message or register dump into the caller's buffer,
+                       returning the full size of either. only manage_capsules capsules can call these */
+                    syscalls::Action::CapsulePanicMessage(cid, buffer, size) => match guestpanic::read_message(cid, buffer, size)
+                    {
+                        Ok(length) => syscalls::result(context, length),
+                        Err(Cause::GuestPanicNotFound) => syscalls::result(context, usize::MAX), /* -1 == no report */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+                    syscalls::Action::CapsulePanicRegisters(cid, buffer, count) => match guestpanic::read_registers(cid, buffer, count)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(Cause::GuestPanicNotFound) => syscalls::result(context, usize::MAX), /* -1 == no report */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* read the hypervisor's dump of a capsule's registers and memory when it last crashed into
+                       the caller's buffer, returning its full size. only manage_capsules capsules can call this */
+                    syscalls::Action::CapsuleCrashDump(cid, buffer, size) => match crashdump::read(cid, buffer, size)
+                    {
+                        Ok(length) => syscalls::result(context, length),
+                        Err(Cause::CrashDumpNotFound) => syscalls::result(context, usize::MAX), /* -1 == no dump */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* read the registers of one of a paused capsule's vcores, as captured when it was parked, into the
+                       caller's array, returning how many there are. only manage_capsules capsules can call this */
+                    syscalls::Action::CapsuleReadRegisters(cid, vid, buffer, count) => match inspect::read_registers(cid, vid, buffer, count)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(Cause::InspectNoRegisters) => syscalls::result(context, usize::MAX), /* -1 == no snapshot */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::CapsuleNotPaused | Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* read part of a paused capsule's memory into the caller's buffer, returning the number of bytes
+                       copied. only manage_capsules capsules can call this */
+                    syscalls::Action::CapsuleReadMemory(cid, address, buffer, size) => match inspect::read_memory(cid, address, buffer, size)
+                    {
+                        Ok(copied) => syscalls::result(context, copied),
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::CapsuleNotPaused | Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* read the hardware inventory, as machine-readable text, into the caller's buffer, returning its
+                       full size. only manage_capsules capsules can call this */
+                    syscalls::Action::InventoryRead(buffer, size) => match inventory::read(buffer, size)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* interrupt one or all of the caller's sibling vcores */
+                    syscalls::Action::IPISend(target) => if let Err(e) = vipi::send(target)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::VirtualCoreBadID => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        });
+                    },
+
+                    /* acknowledge an IPI, returning 1 if one was pending or 0 if not */
+                    syscalls::Action::IPIClaim => match vipi::claim()
+                    {
+                        Ok(pending) => syscalls::result(context, pending as usize),
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* move bytes across virtual serial links between capsules */
+                    syscalls::Action::SerialLinkPutc(link, byte) => if let Err(e) = seriallink::putc(link, byte as u8)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::SerialLinkBadID => ActionResult::BadParams,
+                            _ => ActionResult::Failed /* not connected or full */
+                        });
+                    },
+                    syscalls::Action::SerialLinkGetc(link) => match seriallink::getc(link)
+                    {
+                        Ok(byte) => syscalls::result(context, byte as usize),
+                        Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing waiting */
+                        Err(_) => syscalls::failed(context, ActionResult::BadParams)
                     },
 
                     /* output a character to the user from this capsule
                        when a console_write capsule calls this, it writes to the console.
                        when a non-console_write capsule calls this, it writes to its console buffer */
-                    syscalls::Action::OutputChar(character) => if let Err(_) = capsule::putc(character)
+                    syscalls::Action::OutputChar(character) => match capsule::putc(character as u8)
                     {
-                        syscalls::failed(context, syscalls::ActionResult::Failed);
+                        Ok(()) => (),
+                        Err(Cause::CapsuleBufferFull) => syscalls::failed(context, ActionResult::Retry), /* try again once drained */
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
                     },
 
                     /* get a character from the user for this capsule
@@ -127,18 +516,31 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                         Ref: https://github.com/torvalds/linux/blob/master/arch/riscv/kernel/sbi.c#L92 */
                         Ok(c) => syscalls::result_as_error(context, c as usize),
                         Err(Cause::CapsuleBufferEmpty) => syscalls::result_as_error(context, usize::MAX), /* -1 == nothing to read */
-                        Err(_) => syscalls::failed(context, syscalls::ActionResult::Failed)
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
                     },
 
                     /* write a character to the given capsule's console buffer.
                        only console_write capsules can call this */
-                    syscalls::Action::ConsoleBufferWriteChar(character, capsule_id) => match capsule::console_putc(character, capsule_id)
+                    syscalls::Action::ConsoleBufferWriteChar(character, capsule_id) => match capsule::console_putc(character as u8, capsule_id)
                     {
                         Ok(_) => (),
                         Err(e) => syscalls::failed(context, match e
                         {
-                            Cause::CapsuleBadPermissions => syscalls::ActionResult::Denied,
-                            _ => syscalls::ActionResult::Failed
+                            Cause::CapsuleBadPermissions => ActionResult::Denied,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* return how many bytes of the given capsule's console output were lost to a full buffer.
+                       only console_read capsules can call this */
+                    syscalls::Action::ConsoleBufferOverflows(capsule_id) => match capsule::console_overflows(capsule_id)
+                    {
+                        Ok(count) => syscalls::result(context, count as usize),
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::CapsuleBadID => ActionResult::BadParams,
+                            _ => ActionResult::Failed
                         })
                     },
 
@@ -150,11 +552,31 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                         Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing to read */
                         Err(e) => syscalls::failed(context, match e
                         {
-                            Cause::CapsuleBadPermissions => syscalls::ActionResult::Denied,
-                            _ => syscalls::ActionResult::Failed
+                            Cause::CapsuleBadPermissions => ActionResult::Denied,
+                            _ => ActionResult::Failed
                         })
                     },
                     
+                    /* get the encoding of the given capsule's console bytes: 0 for UTF-8, 1 for raw.
+                       this lets the console service render each capsule's buffered output correctly */
+                    syscalls::Action::ConsoleEncoding(capsule_id) => match capsule::get_console_encoding(capsule_id)
+                    {
+                        Ok(encoding) => syscalls::result(context, encoding as usize),
+                        Err(_) => syscalls::failed(context, ActionResult::BadParams)
+                    },
+
+                    /* switch this capsule's console input between raw (0), delivered byte by byte, and cooked (1),
+                       in which the hypervisor echoes and edits input, delivering it a line at a time */
+                    syscalls::Action::ConsoleInputMode(mode) => match console::InputMode::from_usize(mode)
+                    {
+                        Some(mode) => match capsule::set_console_input_mode(mode)
+                        {
+                            Ok(()) => (),
+                            Err(_) => syscalls::failed(context, ActionResult::Failed)
+                        },
+                        None => syscalls::failed(context, ActionResult::BadParams)
+                    },
+
                     /* get the next available character from the hypervisor's console/log buffer
                        only console_read capsules can call this */
                     syscalls::Action::HypervisorBufferReadChar => match capsule::hypervisor_getc()
@@ -163,11 +585,490 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                         Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing to read */
                         Err(e) => syscalls::failed(context, match e
                         {
-                            Cause::CapsuleBadPermissions => syscalls::ActionResult::Denied,
-                            _ => syscalls::ActionResult::Failed
+                            Cause::CapsuleBadPermissions => ActionResult::Denied,
+                            _ => ActionResult::Failed
                         })
                     },
 
+                    /* get the next available character from the hypercall trace.
+                       only trace_read capsules can call this */
+                    syscalls::Action::TraceReadChar => match trace::read_char()
+                    {
+                        Ok(character) => syscalls::result(context, character as usize),
+                        Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing to read */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* run the hypervisor's self-tests and return the number passed and failed.
+                       only self_test capsules can call this */
+                    syscalls::Action::SelfTestRun => match capsule::current_has_property(capsule::CapsuleProperty::SelfTest)
+                    {
+                        Ok(()) =>
+                        {
+                            let (passed, failed) = selftest::run_all();
+                            syscalls::result_1extra(context, passed, failed);
+                        },
+                        Err(_) => syscalls::failed(context, ActionResult::Denied)
+                    },
+
+                    /* claim the next interrupt raised by a device passed through to this capsule */
+                    syscalls::Action::ExternalIRQClaim => match passthrough::claim_pending_irq()
+                    {
+                        Some(irq) => syscalls::result(context, irq),
+                        None => syscalls::result(context, usize::MAX) /* -1 == nothing pending */
+                    },
+
+                    /* let a device interrupt this capsule has claimed and serviced be raised again */
+                    syscalls::Action::ExternalIRQComplete(irq) => if let Err(e) = passthrough::complete_irq(irq)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::PassthroughIRQNotOwned => ActionResult::Denied,
+                            _ => ActionResult::Failed
+                        });
+                    },
+
+                    /* get the ID of the next capsule paused after crashing, so it can be inspected.
+                       only manage_capsules capsules can call this */
+                    syscalls::Action::CapsuleCrashedNext => match capsule::next_crashed()
+                    {
+                        Ok(cid) => syscalls::result(context, cid),
+                        Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == none waiting */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* resume or kill a paused capsule. only manage_capsules capsules can call these */
+                    syscalls::Action::CapsuleResume(cid) => if let Err(e) = capsule::resume(cid)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound | Cause::TemplateInUse => ActionResult::Denied,
+                            Cause::CapsuleBadID | Cause::CapsuleNotPaused => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        });
+                    },
+                    syscalls::Action::CapsuleKillPaused(cid) => if let Err(e) = capsule::kill_paused(cid)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::CapsuleBadID | Cause::CapsuleNotPaused => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        });
+                    },
+
+                    /* get the estimated working set size of a capsule, in bytes.
+                       only manage_capsules capsules can call this */
+                    syscalls::Action::CapsuleWorkingSet(cid) => match wss::estimate(cid)
+                    {
+                        Ok(bytes) => syscalls::result(context, bytes),
+                        Err(Cause::WSSNotReady) => syscalls::result(context, usize::MAX), /* -1 == no estimate yet */
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound | Cause::CapsuleBadPermissions => ActionResult::Denied,
+                            _ => ActionResult::BadParams
+                        })
+                    },
+
+                    /* destroy all capsules and recreate them from the DMFS image, without rebooting the host.
+                       only manage_capsules capsules can call this */
+                    syscalls::Action::WarmReboot => match capsule::current_has_property(capsule::CapsuleProperty::ManageCapsules)
+                    {
+                        Ok(_) => if let Err(_) = warmboot::request()
+                        {
+                            syscalls::failed(context, ActionResult::Failed);
+                        },
+                        Err(_) => syscalls::failed(context, ActionResult::Denied)
+                    },
+
+                    /* reboot or power off the whole host, giving the other capsules a grace period in milliseconds
+                       to shut down first. only host_reset capsules can call this */
+                    syscalls::Action::HostReset(reset, grace) => match capsule::current_has_property(capsule::CapsuleProperty::HostReset)
+                    {
+                        Ok(_) =>
+                        {
+                            let result = match power::Reset::from_usize(reset)
+                            {
+                                Some(reset) => power::request(reset, pcore::PhysicalCore::get_capsule_id(), grace as u64),
+                                None => Err(Cause::PowerBadType)
+                            };
+
+                            if let Err(e) = result
+                            {
+                                syscalls::failed(context, match e
+                                {
+                                    Cause::PowerBadType => ActionResult::BadParams,
+                                    _ => ActionResult::Failed
+                                });
+                            }
+                        },
+                        Err(_) => syscalls::failed(context, ActionResult::Denied)
+                    },
+
+                    /* tell this capsule whenever the host's memory pressure level changes, and return the current level */
+                    syscalls::Action::MemoryPressureSubscribe => match pcore::PhysicalCore::get_capsule_id()
+                    {
+                        Some(cid) => syscalls::result(context, pressure::subscribe(cid) as usize),
+                        None => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* grant part of this capsule's memory to another capsule, or the hypervisor, read-only (0) or read-write (1) */
+                    syscalls::Action::GrantCreate(addr, size, grantee, access) =>
+                    {
+                        let result = match grant::Access::from_usize(access)
+                        {
+                            Some(access) => grant::create(addr, size, grantee, access),
+                            None => Err(Cause::GrantBadAccess)
+                        };
+
+                        match result
+                        {
+                            Ok(id) => syscalls::result(context, id),
+                            Err(e) => syscalls::failed(context, grant_error(e))
+                        }
+                    },
+
+                    /* open up memory granted to this capsule, returning its address and size */
+                    syscalls::Action::GrantAccept(id) => match grant::accept(id)
+                    {
+                        Ok((base, size)) => syscalls::result_1extra(context, base, size),
+                        Err(e) => syscalls::failed(context, grant_error(e))
+                    },
+
+                    /* give up memory granted to this capsule */
+                    syscalls::Action::GrantRelease(id) => if let Err(e) = grant::release(id)
+                    {
+                        syscalls::failed(context, grant_error(e));
+                    },
+
+                    /* withdraw memory this capsule granted */
+                    syscalls::Action::GrantRevoke(id) => if let Err(e) = grant::revoke(id)
+                    {
+                        syscalls::failed(context, grant_error(e));
+                    },
+
+                    /* start tracking changes to a capsule's RAM, or checkpoint it again, returning the size of its dirty log.
+                       only manage_capsules capsules can call these */
+                    syscalls::Action::DirtyLogStart(cid) => match dirty::start(cid)
+                    {
+                        Ok(length) => syscalls::result(context, length),
+                        Err(e) => syscalls::failed(context, dirty_error(e))
+                    },
+
+                    /* copy a capsule's dirty log into the caller's buffer and checkpoint it again, returning the log's size */
+                    syscalls::Action::DirtyLogRead(cid, buffer, size) => match dirty::read(cid, buffer, size)
+                    {
+                        Ok(length) => syscalls::result(context, length),
+                        Err(e) => syscalls::failed(context, dirty_error(e))
+                    },
+
+                    syscalls::Action::DirtyLogStop(cid) => if let Err(e) = dirty::stop(cid)
+                    {
+                        syscalls::failed(context, dirty_error(e));
+                    },
+
+                    /* open up a physical MMIO range to this capsule. only mmio_map capsules can call this */
+                    syscalls::Action::MMIOMap(base, size) => if let Err(e) = mmio::map(base, size)
+                    {
+                        syscalls::failed(context, mmio_error(e));
+                    },
+
+                    /* close a physical MMIO range this capsule mapped */
+                    syscalls::Action::MMIOUnmap(base) => if let Err(e) = mmio::unmap(base)
+                    {
+                        syscalls::failed(context, mmio_error(e));
+                    },
+
+                    /* freeze this capsule, ready to be made a template, naming where its clones should start */
+                    syscalls::Action::TemplateQuiesce(entry) => match template::quiesce(entry)
+                    {
+                        /* park this vcore and find something else to run */
+                        Ok(_) => scheduler::ping(),
+                        Err(e) => syscalls::failed(context, template_error(e))
+                    },
+
+                    /* make a paused capsule a template, or stop it being one. only manage_capsules capsules can call these */
+                    syscalls::Action::TemplateMark(cid) => if let Err(e) = template::mark(cid)
+                    {
+                        syscalls::failed(context, template_error(e));
+                    },
+                    syscalls::Action::TemplateUnmark(cid) => if let Err(e) = template::unmark(cid)
+                    {
+                        syscalls::failed(context, template_error(e));
+                    },
+
+                    /* create and start a capsule cloned from a template, returning its ID */
+                    syscalls::Action::TemplateClone(cid) => match template::clone(cid)
+                    {
+                        Ok(clone) => syscalls::result(context, clone),
+                        Err(e) => syscalls::failed(context, template_error(e))
+                    },
+
+                    /* ask a capsule to quiesce for a snapshot within the given milliseconds, check how far it's got,
+                       and let it carry on afterwards. only manage_capsules capsules can call these */
+                    syscalls::Action::CapsuleQuiesce(cid, timeout) => if let Err(e) = quiesce::request(cid, timeout as u64)
+                    {
+                        syscalls::failed(context, quiesce_error(e));
+                    },
+                    syscalls::Action::CapsuleQuiesceStatus(cid) => match quiesce::status(cid)
+                    {
+                        Ok(status) => syscalls::result(context, status),
+                        Err(e) => syscalls::failed(context, quiesce_error(e))
+                    },
+                    syscalls::Action::CapsuleThaw(cid) => if let Err(e) = quiesce::thaw(cid)
+                    {
+                        syscalls::failed(context, quiesce_error(e));
+                    },
+
+                    /* tell the hypervisor this capsule has flushed its filesystems and paused its devices as asked */
+                    syscalls::Action::QuiesceDone => if let Err(e) = quiesce::done()
+                    {
+                        syscalls::failed(context, quiesce_error(e));
+                    },
+
+                    /* press a capsule's virtual power (0) or reboot (1) button, giving it the given milliseconds to
+                       act on it before it's killed or restarted, or 0 to leave it be. only manage_capsules capsules can call this */
+                    syscalls::Action::CapsulePressButton(cid, button, grace) =>
+                    {
+                        let result = match button::Button::from_usize(button)
+                        {
+                            Some(button) => button::press(cid, button, grace as u64),
+                            None => Err(Cause::ButtonBadType)
+                        };
+
+                        if let Err(e) = result
+                        {
+                            syscalls::failed(context, button_error(e));
+                        }
+                    },
+
+                    /* return the mask of this capsule's buttons pressed since it last asked */
+                    syscalls::Action::ButtonCollect => match button::collect()
+                    {
+                        Ok(presses) => syscalls::result(context, presses),
+                        Err(e) => syscalls::failed(context, button_error(e))
+                    },
+
+                    /* copy this capsule's measurement, taken as it was loaded, into the caller's buffer */
+                    syscalls::Action::MeasurementRead(buffer) => if let Err(e) = measure::read(buffer)
+                    {
+                        syscalls::failed(context, secret_error(e));
+                    },
+
+                    /* unseal the named secret into the caller's buffer, if it was sealed to this capsule's
+                       measurement, and return its size. nothing is copied if the buffer is too small */
+                    syscalls::Action::SecretRead(name, length, buffer, size) => match sealed::release(name, length, buffer, size)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(e) => syscalls::failed(context, secret_error(e))
+                    },
+
+                    /* read or change one of the hypervisor's live settings. only host_settings capsules can call these */
+                    syscalls::Action::SettingRead(setting) => match settings::read(setting)
+                    {
+                        Ok(value) => syscalls::result(context, value as usize),
+                        Err(e) => syscalls::failed(context, setting_error(e))
+                    },
+                    syscalls::Action::SettingWrite(setting, value) => if let Err(e) = settings::write(setting, value as u64)
+                    {
+                        syscalls::failed(context, setting_error(e));
+                    },
+
+                    /* change how much the named hypervisor module writes to the debug log: alerts only (0) or debug too (1) */
+                    syscalls::Action::SettingVerbosity(module, length, verbosity) => if let Err(e) = settings::write_verbosity(module, length, verbosity)
+                    {
+                        syscalls::failed(context, setting_error(e));
+                    },
+
+                    /* save the live settings to the persistent store, to be restored on the next boot */
+                    syscalls::Action::SettingsSave => if let Err(e) = settings::write_persistent()
+                    {
+                        syscalls::failed(context, setting_error(e));
+                    },
+
+                    /* set one of this capsule's GPIO lines to be an input (0) or an output (1) */
+                    syscalls::Action::GpioSetDirection(line, direction) =>
+                    {
+                        let result = match gpio::Direction::from_usize(direction)
+                        {
+                            Some(direction) => gpio::set_direction(line, direction),
+                            None => Err(Cause::GpioBadDirection)
+                        };
+
+                        match result
+                        {
+                            Ok(()) => (),
+                            Err(e) => syscalls::failed(context, gpio_error(e))
+                        }
+                    },
+
+                    /* drive one of this capsule's GPIO output lines low (0) or high (non-zero) */
+                    syscalls::Action::GpioWrite(line, value) => match gpio::write(line, value != 0)
+                    {
+                        Ok(()) => (),
+                        Err(e) => syscalls::failed(context, gpio_error(e))
+                    },
+
+                    /* read the level of one of this capsule's GPIO lines: 0 for low, 1 for high */
+                    syscalls::Action::GpioRead(line) => match gpio::read(line)
+                    {
+                        Ok(value) => syscalls::result(context, value as usize),
+                        Err(e) => syscalls::failed(context, gpio_error(e))
+                    },
+
+                    /* copy a summary of all capsules into the caller's buffer and return how many capsules there are */
+                    syscalls::Action::CapsuleSnapshot(buffer, count) => match capsule::copy_snapshot(buffer, count)
+                    {
+                        Ok(total) => syscalls::result(context, total),
+                        Err(e) => syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::TransferBadDescriptor => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        })
+                    },
+
+                    /* tell the hypervisor this capsule's boot image is up and running, so it isn't rolled back */
+                    syscalls::Action::CapsuleBootConfirm => if let Err(_) = abboot::confirm()
+                    {
+                        syscalls::failed(context, ActionResult::Failed);
+                    },
+
+                    /* choose the DMFS image a capsule loads when it next restarts. capsules can pick their own,
+                       and manage_capsules capsules can pick any capsule's */
+                    syscalls::Action::CapsuleSelectBootImage(cid, name, length) => if let Err(e) = capsule::select_boot_image(cid, name, length)
+                    {
+                        syscalls::failed(context, match e
+                        {
+                            Cause::CapsulePropertyNotFound => ActionResult::Denied,
+                            Cause::CapsuleBadID | Cause::TransferBadDescriptor |
+                            Cause::ManifestNoSuchAsset | Cause::ManifestNotExecutable => ActionResult::BadParams,
+                            _ => ActionResult::Failed
+                        });
+                    },
+
+                    /* currently running capsule wants to use a registered service. check it's allowed to */
+                    syscalls::Action::SelectService(stype_nr) => if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+                    {
+                        let result = match service::usize_to_service_type(stype_nr)
+                        {
+                            Ok(stype) => service::check_access(stype, cid),
+                            Err(e) => Err(e)
+                        };
+
+                        if let Err(e) = result
+                        {
+                            syscalls::failed(context, match e
+                            {
+                                Cause::ServiceAccessDenied => ActionResult::Denied,
+                                Cause::ServiceNotFound => ActionResult::BadParams,
+                                _ => ActionResult::Failed
+                            });
+                        }
+                    }
+                    else
+                    {
+                        syscalls::failed(context, ActionResult::Failed);
+                    },
+
+                    /* currently running capsule offers a list of buffers to a service for a bulk copy */
+                    syscalls::Action::TransferOffer(stype_nr, direction, list, count) =>
+                    {
+                        let result = match (service::usize_to_service_type(stype_nr), transfer::Direction::from_usize(direction))
+                        {
+                            (Ok(stype), Ok(direction)) => transfer::offer(stype, direction, list, count),
+                            (Err(e), _) | (_, Err(e)) => Err(e)
+                        };
+
+                        match result
+                        {
+                            Ok(id) => syscalls::result(context, id),
+                            Err(e) => syscalls::failed(context, transfer_error(e))
+                        }
+                    },
+
+                    /* service wants the next bulk transfer offered to it: get its ID, direction, and size */
+                    syscalls::Action::TransferNext(stype_nr) => match service::usize_to_service_type(stype_nr)
+                    {
+                        Ok(stype) => match transfer::next(stype)
+                        {
+                            Ok((id, direction, size)) => syscalls::result_2extra(context, id, direction as usize, size),
+                            Err(Cause::TransferBadID) => syscalls::result(context, usize::MAX), /* -1 == none waiting */
+                            Err(e) => syscalls::failed(context, transfer_error(e))
+                        },
+                        Err(e) => syscalls::failed(context, transfer_error(e))
+                    },
+
+                    /* service accepts a bulk transfer with its own buffers, and the hypervisor does the copy */
+                    syscalls::Action::TransferAccept(id, list, count) => match transfer::accept(id, list, count)
+                    {
+                        Ok(copied) => syscalls::result(context, copied),
+                        Err(e) => syscalls::failed(context, transfer_error(e))
+                    },
+
+                    /* bounce a buffer through DMA-safe memory for a device that can't be confined by an IOMMU */
+                    syscalls::Action::BounceMap(buffer, size, direction) =>
+                    {
+                        let result = match bounce::Direction::from_usize(direction)
+                        {
+                            Some(direction) => bounce::map(buffer, size, direction),
+                            None => Err(Cause::BounceBadDirection)
+                        };
+
+                        match result
+                        {
+                            Ok((id, address)) => syscalls::result_1extra(context, id, address),
+                            Err(e) => syscalls::failed(context, bounce_error(e))
+                        }
+                    },
+
+                    /* release a bounce buffer once the device is done with it, copying back what it wrote */
+                    syscalls::Action::BounceUnmap(id, size) => if let Err(e) = bounce::unmap(id, size)
+                    {
+                        syscalls::failed(context, bounce_error(e));
+                    },
+
+                    /* return nanoseconds since the host powered up */
+                    syscalls::Action::TimeMonotonic => match clock::monotonic()
+                    {
+                        Ok(ns) => syscalls::result(context, ns as usize),
+                        Err(_) => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* return the wall-clock time, as adjusted by the capsule, in nanoseconds since the Unix epoch */
+                    syscalls::Action::TimeWallClock => match pcore::PhysicalCore::get_capsule_id()
+                    {
+                        Some(cid) => match clock::wall_clock(cid)
+                        {
+                            Ok(ns) => syscalls::result(context, ns as usize),
+                            Err(Cause::ClockNoWallClock) => syscalls::failed(context, ActionResult::Denied),
+                            Err(_) => syscalls::failed(context, ActionResult::Failed)
+                        },
+                        None => syscalls::failed(context, ActionResult::Failed)
+                    },
+
+                    /* set the signed number of nanoseconds the capsule adds to the host's wall-clock time */
+                    syscalls::Action::TimeSetOffset(offset) => match pcore::PhysicalCore::get_capsule_id()
+                    {
+                        Some(cid) => if let Err(e) = clock::set_offset(cid, offset as i64)
+                        {
+                            syscalls::failed(context, match e
+                            {
+                                Cause::ClockNoWallClock => ActionResult::Denied,
+                                _ => ActionResult::Failed
+                            });
+                        },
+                        None => syscalls::failed(context, ActionResult::Failed)
+                    },
+
                     /* currently running capsule wants to register itself as a service so it can receive
                        and proces requests from other capsules */
                     syscalls::Action::RegisterService(stype_nr) => if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
@@ -179,14 +1080,14 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                                 Ok(_) => (),
                                 Err(e) => syscalls::failed(context, match e
                                 {
-                                    Cause::CapsuleBadPermissions => syscalls::ActionResult::Denied,
-                                    _ => syscalls::ActionResult::Failed
+                                    Cause::CapsuleBadPermissions => ActionResult::Denied,
+                                    _ => ActionResult::Failed
                                 })
                             },
                             Err(e) => syscalls::failed(context, match e
                             {
-                                Cause::ServiceNotFound => syscalls::ActionResult::BadParams,
-                                _ => syscalls::ActionResult::Failed
+                                Cause::ServiceNotFound => ActionResult::BadParams,
+                                _ => ActionResult::Failed
                             })
                         }
                     }
@@ -194,19 +1095,99 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                     {
                         /* how is this possible? can't find capsule running on this physical core
                            but we're going to try returning to it anyway? */
-                        syscalls::failed(context, syscalls::ActionResult::Failed);
+                        syscalls::failed(context, ActionResult::Failed);
                     },
 
-                    _ => if let Some(c) = pcore::PhysicalCore::get_capsule_id()
+                    /* currently running capsule wants to offer a service under a name granted to it in the manifest */
+                    syscalls::Action::RegisterServiceName(name, length) => if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
                     {
-                        hvalert!("Capsule {}: Unhandled syscall: {:x?} at 0x{:x}", c, action, irq.pc);
+                        if let Err(e) = service::read_name(cid, name, length).and_then(|name| service::register_name(&name, cid))
+                        {
+                            syscalls::failed(context, service_name_error(e));
+                        }
                     }
                     else
                     {
-                        hvdebug!("Unhandled syscall: {:x?} at 0x{:x} in unknown capsule", action, irq.pc);
+                        syscalls::failed(context, ActionResult::Failed);
+                    },
+
+                    /* currently running capsule no longer wants to offer a named service */
+                    syscalls::Action::DeregisterServiceName(name, length) => if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+                    {
+                        if let Err(e) = service::read_name(cid, name, length).and_then(|name| service::deregister_name(&name, cid))
+                        {
+                            syscalls::failed(context, service_name_error(e));
+                        }
+                    }
+                    else
+                    {
+                        syscalls::failed(context, ActionResult::Failed);
+                    },
+
+                    /* currently running capsule wants to find the capsule offering a named service */
+                    syscalls::Action::LookupServiceName(name, length) => if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+                    {
+                        match service::read_name(cid, name, length).and_then(|name| service::lookup_name(&name, cid))
+                        {
+                            Ok(owner) => syscalls::result(context, owner),
+                            Err(e) => syscalls::failed(context, service_name_error(e))
+                        }
+                    }
+                    else
+                    {
+                        syscalls::failed(context, ActionResult::Failed);
+                    },
+
+                    /* currently running capsule wants to accept stream connections to a service name it registered */
+                    syscalls::Action::StreamListen(name, length) => match stream::listen(name, length)
+                    {
+                        Ok(()) => (),
+                        Err(e) => syscalls::failed(context, stream_error(e))
+                    },
+
+                    /* currently running capsule wants a stream connection to a named service. returns the connection's ID */
+                    syscalls::Action::StreamConnect(name, length) => match stream::connect(name, length)
+                    {
+                        Ok(id) => syscalls::result(context, id),
+                        Err(e) => syscalls::failed(context, stream_error(e))
+                    },
+
+                    /* currently running capsule wants the next connection waiting for its services */
+                    syscalls::Action::StreamAccept => match stream::accept()
+                    {
+                        Ok(id) => syscalls::result(context, id),
+                        Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing waiting */
+                        Err(e) => syscalls::failed(context, stream_error(e))
+                    },
+
+                    /* send bytes over a stream connection. returns how many were taken, which may be 0 if the peer's buffer is full */
+                    syscalls::Action::StreamSend(id, buffer, length) => match stream::send(id, buffer, length)
+                    {
+                        Ok(sent) => syscalls::result(context, sent),
+                        Err(e) => syscalls::failed(context, stream_error(e))
+                    },
+
+                    /* receive bytes from a stream connection. returns how many were read, or 0 if the peer closed it */
+                    syscalls::Action::StreamRecv(id, buffer, length) => match stream::recv(id, buffer, length)
+                    {
+                        Ok(received) => syscalls::result(context, received),
+                        Err(Cause::CapsuleBufferEmpty) => syscalls::result(context, usize::MAX), /* -1 == nothing waiting */
+                        Err(e) => syscalls::failed(context, stream_error(e))
+                    },
+
+                    /* close this capsule's end of a stream connection */
+                    syscalls::Action::StreamClose(id) => match stream::close(id)
+                    {
+                        Ok(()) => (),
+                        Err(e) => syscalls::failed(context, stream_error(e))
                     }
                 }
+
+                trace::end(traced, context);
             }
+
+            /* otherwise the decoder has answered the call itself, either as a query of the
+               standard SBI or with an error code for a call we don't know about */
         },
 
         /* catch everything else, halting if fatal */
@@ -220,7 +1201,7 @@ fn exception(irq: IRQ, context: &mut IRQContext)
                 {
                     /* TODO: is it wise to blow away the whole capsule for a user exception?
                     the supervisor should really catch its user-level faults */
-                    fatal_exception(&irq);
+                    fatal_exception(&irq, context);
                 },
                 PrivilegeMode::Machine =>
                 {
@@ -237,6 +1218,153 @@ fn exception(irq: IRQ, context: &mut IRQContext)
     }
 }
 
+/* convert a bulk transfer error into a hypercall result */
+fn transfer_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::ServiceAccessDenied | Cause::ServiceNotAllowed => ActionResult::Denied,
+        Cause::ServiceNotFound | Cause::TransferBadDescriptor | Cause::TransferBadID => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a named service error into a hypercall result */
+fn service_name_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::ServiceAccessDenied | Cause::ServiceNotAllowed |
+        Cause::ServiceAlreadyRegistered | Cause::ServiceAlreadyOwner => ActionResult::Denied,
+        Cause::ServiceBadName | Cause::ServiceNotFound | Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a bounce buffer error into a hypercall result */
+fn bounce_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::BounceNotNeeded => ActionResult::Denied,
+        Cause::BounceBadSize | Cause::BounceBadDirection | Cause::BounceBadID | Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a GPIO error into a hypercall result */
+fn gpio_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::GpioBadLine | Cause::GpioBadDirection => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a stream connection error into a hypercall result */
+fn stream_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::ServiceAccessDenied | Cause::ServiceNotAllowed => ActionResult::Denied,
+        Cause::StreamBadID | Cause::ServiceBadName | Cause::ServiceNotFound |
+        Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed /* not listening, backlog full, too many connections, or closed */
+    }
+}
+
+/* convert a memory grant error into a hypercall result */
+fn grant_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::GrantBadRange | Cause::GrantBadGrantee | Cause::GrantBadAccess |
+        Cause::GrantBadID | Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed /* too many grants */
+    }
+}
+
+/* convert a direct MMIO window error into a hypercall result */
+fn mmio_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound | Cause::MMIOInUse => ActionResult::Denied,
+        Cause::MMIOBadRange | Cause::MMIONotMapped => ActionResult::BadParams,
+        _ => ActionResult::Failed /* too many windows */
+    }
+}
+
+/* convert a capsule template error into a hypercall result */
+fn template_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound => ActionResult::Denied,
+        Cause::CapsuleBadID | Cause::CapsuleNotPaused | Cause::TemplateNotFound |
+        Cause::TemplateNotClonable | Cause::TemplateBadEntry => ActionResult::BadParams,
+        _ => ActionResult::Failed /* out of memory or capsules, or the clone's devices are unavailable */
+    }
+}
+
+/* convert a guest quiesce error into a hypercall result */
+fn quiesce_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound => ActionResult::Denied,
+        Cause::CapsuleBadID | Cause::QuiesceNotRunning | Cause::QuiesceNotRequested => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a virtual button error into a hypercall result */
+fn button_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound => ActionResult::Denied,
+        Cause::CapsuleBadID | Cause::ButtonBadType | Cause::ButtonNotRunning => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a dirty page tracking error into a hypercall result */
+fn dirty_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound => ActionResult::Denied,
+        Cause::CapsuleBadID | Cause::DirtyNotTracking | Cause::DirtyNoMemory |
+        Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed
+    }
+}
+
+/* convert a sealed secret error into a hypercall result */
+fn secret_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::SecretMeasurementMismatch | Cause::SecretNotMeasured => ActionResult::Denied,
+        Cause::SecretNotFound | Cause::ServiceBadName | Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed /* no sealing key, or the secret is damaged */
+    }
+}
+
+/* convert a live settings error into a hypercall result */
+fn setting_error(e: Cause) -> syscalls::ActionResult
+{
+    match e
+    {
+        Cause::CapsulePropertyNotFound => ActionResult::Denied,
+        Cause::SettingBadName | Cause::SettingBadValue | Cause::ServiceBadName |
+        Cause::TransferBadDescriptor => ActionResult::BadParams,
+        _ => ActionResult::Failed /* no persistent store */
+    }
+}
+
 /* handle hardware interrupt */
 fn interrupt(irq: IRQ, _: &mut IRQContext)
 {
@@ -248,15 +1376,32 @@ fn interrupt(irq: IRQ, _: &mut IRQContext)
             scheduler::ping();
             check_supervisor_timer_irq();
         },
+
+        /* another physical core wants our attention */
+        IRQCause::MachineSoft =>
+        {
+            clint::acknowledge();
+            message::process_mailbox();
+        },
+
+        /* route interrupts from passed-through devices to their capsules */
+        IRQCause::MachineExternal => passthrough::route_external_irq(),
+
         _ => hvdebug!("Unhandled hardware interrupt: {:?}", irq.cause)
     }
 
+    /* the capsule we're about to return to may have device interrupts or an IPI waiting */
+    passthrough::check_pending_irq();
+    vipi::check_pending();
+
     /* clear the interrupt condition */
     platform::irq::acknowledge(irq);
 }
 
 /* is the virtual core we're about to run awaiting a timer IRQ?
-if so, and if its timer target value has been passed, generate a pending timer IRQ */
+if so, and if its timer target value has been passed, generate a pending timer IRQ.
+a timer IRQ is never raised before its target: targets are only ever delayed into
+an interrupt that's already due, by timer_coalesces(), to limit the rate of timer IRQs */
 fn check_supervisor_timer_irq()
 {
     if let Some(target) = pcore::PhysicalCore::get_virtualcore_timer_target()
@@ -268,9 +1413,29 @@ fn check_supervisor_timer_irq()
                 let current = time.to_exact(freq);
                 if current >= target.to_exact(freq)
                 {
-                    /* create a pending timer IRQ for the supervisor kernel and clear the target */
-                    timer::trigger_supervisor_irq();
-                    pcore::PhysicalCore::set_virtualcore_timer_target(None);
+                    /* create a pending timer IRQ for the supervisor kernel and expire whatever's due.
+                    with Sstc, the pending bit follows the compare register, so pull that forward instead,
+                    holding the supervisor's own compare value aside until it has collected its timers */
+                    match pcore::PhysicalCore::sstc_supported()
+                    {
+                        true => pcore::PhysicalCore::force_supervisor_compare(current),
+                        false => timer::trigger_supervisor_irq()
+                    }
+                    pcore::PhysicalCore::expire_virtualcore_timers(current, freq);
+
+                    if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+                    {
+                        metrics::count(cid, metrics::Counter::TimerIRQs);
+                    }
+                }
+
+                /* make sure whatever's due next raises an interrupt in time */
+                if let Some(next) = pcore::PhysicalCore::get_virtualcore_timer_target()
+                {
+                    if next.to_exact(freq) > current && scheduler::timer_coalesces(next) == false
+                    {
+                        hardware::scheduler_timer_at(next);
+                    }
                 }
             },
             (_, _) => ()
@@ -279,9 +1444,35 @@ fn check_supervisor_timer_irq()
 }
 
 /* kill the running capsule, alert the user, and then find something else to run.
-   if the capsule is important enough to auto-restart-on-crash, try to revive it */
-fn fatal_exception(irq: &IRQ)
+   if the capsule is important enough to auto-restart-on-crash, try to revive it.
+   if the capsule is marked pause-on-crash, freeze it for inspection instead */
+fn fatal_exception(irq: &IRQ, context: &IRQContext)
 {
+    /* capture the dying virtual core's state before it's lost */
+    crashdump::record(irq, context);
+
+    if capsule::is_current_pause_on_crash() == Some(true)
+    {
+        hvalert!("Pausing crashed capsule {} for {:?} at 0x{:x}, stack 0x{:x}",
+            match pcore::PhysicalCore::get_capsule_id()
+            {
+                Some(id) => format!("{}", id),
+                None => format!("[unknown!]")
+            }, irq.cause, irq.pc, irq.sp);
+
+        match capsule::pause_current()
+        {
+            /* park this vcore, with its registers kept for inspection, and find something else to run */
+            Ok(_) =>
+            {
+                inspect::capture(context);
+                scheduler::ping();
+                return;
+            },
+            Err(e) => hvalert!("Can't pause capsule ({}), applying crash policy instead", error::report(&e))
+        }
+    }
+
     hvalert!("Terminating running capsule {} for {:?} at 0x{:x}, stack 0x{:x}",
         match pcore::PhysicalCore::this().get_virtualcore_id()
         {
@@ -299,7 +1490,7 @@ fn fatal_exception(irq: &IRQ)
             hvalert!("Restarting capsule due to auto-restart-on-crash flag");
             if let Err(err) = capsule::restart_current()
             {
-                hvalert!("Can't restart capsule ({:?}), letting it die instead", err);
+                hvalert!("Can't restart capsule ({}), letting it die instead", error::report(&err));
                 terminate = true;
             }
             else
@@ -318,9 +1509,15 @@ fn fatal_exception(irq: &IRQ)
 
     if terminate == true
     {
+        /* hand any services the capsule was running to their standbys before it's torn down */
+        if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+        {
+            failover::promote(cid);
+        }
+
         match capsule::destroy_current()
         {
-            Err(e) => hvalert!("BUG: Failed to kill running capsule ({:?})", e),
+            Err(e) => hvalert!("BUG: Failed to kill running capsule ({})", error::report(&e)),
             _ =>
             {
                 hvdebug!("Terminated running capsule");
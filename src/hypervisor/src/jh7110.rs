@@ -0,0 +1,98 @@
+/* diosix StarFive JH7110 board support
+ *
+ * The JH7110, as found on the VisionFive 2 single-board computer, has
+ * four U74 application cores and an S7 monitor core. Most of it is
+ * described well enough by its device tree for the generic code to
+ * drive: its PLIC is compatible with sifive,plic-1.0.0, its CLINT with
+ * sifive,clint0, its UARTs are snps,dw-apb-uart devices, and its S7
+ * core, which has no supervisor mode, is never asked to run capsules.
+ * What's left is handled here, once the board is recognized by the
+ * compatible string of its device tree's root node:
+ *
+ * Its devices' DMA doesn't snoop the cores' caches, and the U74 cores
+ * don't have Zicbom. Instead, caches are flushed a line at a time
+ * through the shared composable cache controller's Flush64 register.
+ *
+ * Its UARTs are clocked through the SoC's clock controller, which a
+ * capsule given a UART can't reach, so passed-through UARTs are
+ * described to capsules with their fixed input clock frequency.
+ *
+ * If its device tree can't be parsed at all, the minimal machine the
+ * hypervisor falls back to uses the board's own debug UART, CLINT,
+ * RAM, and 4MHz timer rather than QEMU's.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use hvalgo::fdt::Fdt;
+use super::hardware::WellKnownMachine;
+use super::machine;
+
+/* compatible string of the SoC, found in the root node of its boards' device trees */
+const COMPATIBLE: &str = "starfive,jh7110";
+
+/* compatible strings of the shared cache controller */
+pub const CACHE_COMPATIBLE: [&str; 2] = [ "starfive,jh7110-ccache", "sifive,ccache0" ];
+
+/* the controller's register that writes back and discards the line holding a physical address,
+   as an offset from its base address, and the size of its lines */
+const CACHE_FLUSH64: usize = 0x200;
+const CACHE_LINE_SIZE: usize = 64;
+
+/* input clock of the UARTs, in Hz */
+pub const UART_CLOCK: u32 = 24_000_000;
+
+/* the board's well-known devices, for when its device tree can't be parsed. hart 0 is the
+   S7 monitor core, so the first application core is hart 1 */
+pub const MACHINE: WellKnownMachine = WellKnownMachine
+{
+    compatible: COMPATIBLE,
+    uart_base: 0x1000_0000,
+    uart_size: 0x1_0000,
+    uart_clock: UART_CLOCK,
+    uart_reg_shift: 2,
+    clint_base: 0x200_0000,
+    clint_size: 0x1_0000,
+    ram_base: 0x4000_0000,
+    ram_size: 2 * 1024 * 1024 * 1024,
+    timebase: 4_000_000,
+    isa: "rv64imafdc_zba_zbb",
+    first_hart: 1
+};
+
+/* <= true if the given device tree describes a JH7110-based board */
+pub fn is_board(fdt: &Fdt) -> bool
+{
+    match fdt.find("/")
+    {
+        Some(root) => root.is_compatible(COMPATIBLE),
+        None => false
+    }
+}
+
+/* set up the board's quirks if the host is a JH7110-based board. call once on the boot core,
+   after the cores' own cache operations have been looked for
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    if is_board(fdt) == false
+    {
+        return;
+    }
+
+    let controller = fdt.nodes()
+        .find(|n| n.is_enabled() && CACHE_COMPATIBLE.iter().any(|c| n.is_compatible(c)))
+        .and_then(|n| n.reg().ok().and_then(|mut reg| reg.next()));
+
+    match controller
+    {
+        Some((base, _)) =>
+        {
+            hvdebug!("StarFive JH7110 board: flushing caches through the cache controller at 0x{:x}", base);
+            machine::set_cache_flush_register(base as usize + CACHE_FLUSH64, CACHE_LINE_SIZE);
+        },
+        None => hvalert!("StarFive JH7110 board has no cache controller in its device tree: DMA may see stale data")
+    }
+}
@@ -0,0 +1,103 @@
+/* diosix capsule lifecycle state machine
+ *
+ * A capsule moves between a small number of states as it's paused,
+ * restarted, and killed. Rather than let any code set a capsule's state
+ * directly, every change is made by applying an event to the capsule's
+ * lifecycle, which looks up the event in a table of allowed transitions.
+ * Events that aren't allowed in the capsule's current state are refused
+ * with an error that callers can't ignore, so that, for example, a dying
+ * capsule can't be brought back to life as a zombie, and a capsule can't
+ * be restarted again while it's still restarting.
+ *
+ * Some events are allowed to leave a capsule in the state it's already
+ * in. This is because each of a capsule's virtual cores applies the same
+ * event as it notices the capsule is dying, restarting, or pausing.
+ *
+ * Other parts of the hypervisor can listen for capsules changing state.
+ * Listeners are called with the capsule table locked, so they must not
+ * call back into the capsule code.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+
+/* the states, events, and table of allowed transitions are kept in the hvalgo crate so they can be tested on the host */
+pub use hvalgo::lifecycle::{CapsuleState, Event, Transition};
+
+/* <= error code to give when the given event isn't allowed */
+fn refused(event: Event) -> Cause
+{
+    match event
+    {
+        Event::Kill => Cause::CapsuleCantDie,
+        Event::Restart => Cause::CapsuleCantRestart,
+        Event::Restarted => Cause::CapsuleNotRestarting,
+        Event::Pause => Cause::CapsuleCantPause,
+        Event::Resume => Cause::CapsuleNotPaused
+    }
+}
+
+/* function called when a capsule changes state
+   => cid = capsule that changed state
+      from, to = its old and new states */
+pub type Listener = fn(CapsuleID, CapsuleState, CapsuleState);
+
+lazy_static!
+{
+    static ref LISTENERS: Mutex<Vec<Listener>> = Mutex::new("capsule lifecycle listeners", Vec::new());
+}
+
+/* call the given function whenever a capsule changes state */
+pub fn listen(listener: Listener)
+{
+    LISTENERS.lock().push(listener);
+}
+
+/* a capsule's current state, which can only be changed by applying events */
+pub struct Lifecycle
+{
+    machine: hvalgo::lifecycle::Lifecycle
+}
+
+impl Lifecycle
+{
+    /* capsules start out ready to run */
+    pub fn new() -> Lifecycle
+    {
+        Lifecycle { machine: hvalgo::lifecycle::Lifecycle::new() }
+    }
+
+    /* <= the capsule's current state */
+    pub fn state(&self) -> CapsuleState { self.machine.state() }
+
+    /* apply an event to a capsule, changing its state if the event is allowed
+       and telling any listeners if the state changed
+       => cid = ID of the capsule, for the listeners
+          event = what's happening to the capsule
+       <= the transition made, or an error code if the event isn't allowed in the current state */
+    pub fn apply(&mut self, cid: CapsuleID, event: Event) -> Result<Transition, Cause>
+    {
+        let transition = match self.machine.apply(event)
+        {
+            Some(transition) => transition,
+            None => return Err(refused(event))
+        };
+
+        if transition.from != transition.to
+        {
+            hvtrace!(Lifecycle, "capsule {} {:?} -> {:?} on {:?}", cid, transition.from, transition.to, event);
+            for listener in LISTENERS.lock().iter()
+            {
+                listener(cid, transition.from, transition.to);
+            }
+        }
+
+        Ok(transition)
+    }
+}
@@ -12,10 +12,14 @@
 
 #![allow(non_camel_case_types)]
 
-use super::error::Cause;
+use super::error::{self, Cause};
+use super::machine;
+use super::message;
 use platform::cpu::Entry;
+use platform::physmem::{PhysMemSize, AccessPermissions};
 use super::physmem::Region;
 use core::mem::size_of;
+use alloc::vec::Vec;
 use xmas_elf;
 
 /* supported CPU architectures */
@@ -70,6 +74,114 @@ macro_rules! get_abs_reloc_table
     }};
 }
 
+/* copy a loadable segment into the target and zero the remainder of the segment's
+   in-memory size, which is its BSS. don't rely on the target having been cleaned:
+   regions aren't zeroed in debug builds, nor in capsules that opt out of zeroing
+   => target = bytes of RAM to write into
+      offset = offset into target of the start of the segment
+      file_bytes = the segment's contents from the binary
+      mem_size = total size of the segment in memory
+   <= Ok for success, or error code */
+fn load_segment(target: &mut [u8], offset: usize, file_bytes: &[u8], mem_size: usize) -> Result<(), Cause>
+{
+    let file_size = file_bytes.len();
+    if file_size > mem_size
+    {
+        return Err(Cause::LoaderSupervisorFileSizeTooLarge);
+    }
+
+    let end = match offset.checked_add(mem_size)
+    {
+        Some(end) if end <= target.len() => end,
+        _ => return Err(Cause::LoaderSupervisorBadPhysOffset)
+    };
+
+    target[offset..offset + file_size].copy_from_slice(file_bytes);
+    target[offset + file_size..end].fill(0);
+    Ok(())
+}
+
+/* calculate how much RAM a binary needs to be loaded, from the end of its highest loadable segment
+   => source = slice containing the binary image to parse
+   <= number of bytes needed, or error code */
+pub fn image_size(source: &[u8]) -> Result<usize, Cause>
+{
+    let elf = match xmas_elf::ElfFile::new(source)
+    {
+        Ok(elf) => elf,
+        Err(_) => return Err(Cause::LoaderUnrecognizedSupervisor)
+    };
+
+    let mut size = 0;
+    for ph_index in 0..*(&elf.header.pt2.ph_count())
+    {
+        if let Ok(ph) = &elf.program_header(ph_index)
+        {
+            if let Ok(xmas_elf::program::Type::Load) = ph.get_type()
+            {
+                match ph.physical_addr().checked_add(ph.mem_size())
+                {
+                    Some(end) => size = core::cmp::max(size, end as usize),
+                    None => return Err(Cause::LoaderSupervisorBadPhysOffset)
+                }
+            }
+        }
+    }
+
+    match size
+    {
+        0 => Err(Cause::LoaderBadEntry),
+        s => Ok(s)
+    }
+}
+
+/* list the parts of a binary's image that hold code, from its loadable segments' permissions,
+   so that the rest of the capsule's RAM can be made non-executable. code that isn't also marked
+   writeable is made read-only. adjacent parts with the same permissions are merged
+   => source = slice containing the binary image to parse
+   <= list of offset into the loaded image, size in bytes, and permissions of each part, or error code */
+pub fn code_segments(source: &[u8]) -> Result<Vec<(PhysMemSize, PhysMemSize, AccessPermissions)>, Cause>
+{
+    let elf = match xmas_elf::ElfFile::new(source)
+    {
+        Ok(elf) => elf,
+        Err(_) => return Err(Cause::LoaderUnrecognizedSupervisor)
+    };
+
+    let mut code: Vec<(PhysMemSize, PhysMemSize, bool)> = Vec::new();
+    for ph_index in 0..*(&elf.header.pt2.ph_count())
+    {
+        if let Ok(ph) = &elf.program_header(ph_index)
+        {
+            if let Ok(xmas_elf::program::Type::Load) = ph.get_type()
+            {
+                let flags = ph.flags();
+                if flags.is_execute() == true && ph.mem_size() > 0
+                {
+                    code.push((ph.physical_addr() as PhysMemSize, ph.mem_size() as PhysMemSize, flags.is_write()));
+                }
+            }
+        }
+    }
+
+    code.sort_by_key(|(offset, _, _)| *offset);
+    let mut merged: Vec<(PhysMemSize, PhysMemSize, bool)> = Vec::new();
+    for (offset, size, writeable) in code
+    {
+        match merged.last_mut()
+        {
+            Some(last) if last.0 + last.1 == offset && last.2 == writeable => last.1 = last.1 + size,
+            _ => merged.push((offset, size, writeable))
+        }
+    }
+
+    Ok(merged.into_iter().map(|(offset, size, writeable)| (offset, size, match writeable
+    {
+        true => AccessPermissions::ReadWriteExecute,
+        false => AccessPermissions::ReadExecute
+    })).collect())
+}
+
 /* load a supervisor binary into memory as required
    => target = region of RAM to write into 
       source = slice containing supervisor binary image to parse
@@ -124,7 +236,8 @@ pub fn load(target: Region, source: &[u8]) -> Result<Entry, Cause>
                         /* reject binaries with load area file sizes greater than their mem sizes */
                         if ph.file_size() > ph.mem_size()
                         {
-                            return Err(Cause::LoaderSupervisorFileSizeTooLarge);
+                            return Err(hverror!(Cause::LoaderSupervisorFileSizeTooLarge,
+                                "segment file size {} exceeds memory size {}", ph.file_size(), ph.mem_size()));
                         }
 
                         /* we're loading the header into an arbitrary-located block of physical RAM.
@@ -134,14 +247,17 @@ pub fn load(target: Region, source: &[u8]) -> Result<Entry, Cause>
                         let offset_into_target = ph.physical_addr();
                         let copy_size = ph.file_size();
 
-                        /* reject wild offsets and physical addresses */
+                        /* reject wild offsets and physical addresses. the whole of the segment
+                        in memory, including any BSS beyond the copied bytes, must fit in the target */
                         if (offset_into_image + copy_size) > source.len() as u64
                         {
-                            return Err(Cause::LoaderSupervisorBadImageOffset);
+                            return Err(hverror!(Cause::LoaderSupervisorBadImageOffset,
+                                "segment at offset 0x{:x} size 0x{:x} overruns {} byte image", offset_into_image, copy_size, source.len()));
                         }
-                        if (offset_into_target + copy_size) > target_size
+                        if (offset_into_target + ph.mem_size()) > target_size
                         {
-                            return Err(Cause::LoaderSupervisorBadPhysOffset);
+                            return Err(hverror!(Cause::LoaderSupervisorBadPhysOffset,
+                                "segment at 0x{:x} size 0x{:x} overruns 0x{:x} byte region", offset_into_target, ph.mem_size(), target_size));
                         }
 
                         /* is this program header home to the entry point? if so, calculate the physical RAM address.
@@ -152,16 +268,16 @@ pub fn load(target: Region, source: &[u8]) -> Result<Entry, Cause>
                             if addr >= target_end
                             {
                                 /* reject wild entry points */
-                                return Err(Cause::LoaderSupervisorEntryOutOfRange);
+                                return Err(hverror!(Cause::LoaderSupervisorEntryOutOfRange,
+                                    "entry point 0x{:x} beyond region end 0x{:x}", addr, target_end));
                             }
                             entry_physical = Some(addr as usize);
                         }
 
-                        /* do the copy */
-                        target_as_bytes[offset_into_target as usize..(offset_into_target + copy_size) as usize].copy_from_slice
-                        (
-                            &source[(offset_into_image as usize)..(offset_into_image + copy_size) as usize]
-                        );
+                        /* do the copy, and zero any BSS */
+                        load_segment(target_as_bytes, offset_into_target as usize,
+                            &source[(offset_into_image as usize)..(offset_into_image + copy_size) as usize],
+                            ph.mem_size() as usize)?;
                     },
 
                     /* support basic PIC ELFs by fixing up values in memory as instructed */
@@ -256,9 +372,58 @@ pub fn load(target: Region, source: &[u8]) -> Result<Entry, Cause>
         };
     }
 
+    /* the image may still be sitting in this core's data cache. write it out to memory,
+       then make sure no core fetches stale instructions from its instruction cache */
+    target.flush_cache();
+    machine::sync_instructions();
+    if let Err(_e) = message::send(message::Message::new(message::Recipient::send_to_all(), message::MessageContent::SyncInstructions)?)
+    {
+        hvalert!("Failed to tell physical CPU cores about newly loaded code: {}", error::report(&_e));
+    }
+
     match entry_physical
     {
-        None => Err(Cause::LoaderBadEntry),
+        None => Err(hverror!(Cause::LoaderBadEntry, "no loadable segment contains entry point 0x{:x}", entry_virtual)),
         Some(entry) => Ok(entry)
     }
 }
+
+#[test_case]
+fn test_load_segment_zeroes_bss()
+{
+    let mut target = [0xffu8; 32];
+    let contents = [1u8, 2, 3, 4];
+
+    assert_eq!(load_segment(&mut target, 8, &contents, 16).is_ok(), true);
+
+    /* bytes before the segment are untouched, the contents are copied, and the BSS is zeroed */
+    assert_eq!(target[..8], [0xff; 8]);
+    assert_eq!(target[8..12], contents);
+    assert_eq!(target[12..24], [0; 12]);
+    assert_eq!(target[24..], [0xff; 8]);
+}
+
+#[test_case]
+fn test_load_segment_all_bss()
+{
+    let mut target = [0xaau8; 16];
+
+    assert_eq!(load_segment(&mut target, 0, &[], 16).is_ok(), true);
+    assert_eq!(target, [0; 16]);
+}
+
+#[test_case]
+fn test_load_segment_rejects_overflowing_bss()
+{
+    let mut target = [0x55u8; 16];
+
+    /* the BSS would run off the end of the target */
+    assert_eq!(load_segment(&mut target, 8, &[1, 2], 12).is_err(), true);
+    assert_eq!(load_segment(&mut target, usize::MAX, &[], 2).is_err(), true);
+
+    /* the file contents can't be larger than the segment */
+    assert_eq!(load_segment(&mut target, 0, &[1, 2, 3], 2).is_err(), true);
+
+    /* nothing was written */
+    assert_eq!(target, [0x55; 16]);
+}
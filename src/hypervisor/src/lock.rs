@@ -1,6 +1,6 @@
 /* diosix high-level hypervisor's locking primitives
  *
- * Provides a standard spin lock and a mutex
+ * Provides a standard spin lock, a mutex, and a gate
  * 
  * The mutex is reentrant, which means when a physical
  * core holds a mutex and then tries to acquire it
@@ -13,7 +13,19 @@
  * use lock() to acquire a mutex.
  * it is unlocked when it goes out of scope.
  * the mutex also maintains accounting stats
- * and is named to aid debugging.
+ * and is named to aid debugging. the stats
+ * count how often the mutex was found held by
+ * another core, and the longest wait for it,
+ * so that contended locks can be found. cores
+ * waiting for a held mutex back off between
+ * attempts so that they don't starve the
+ * holder of the metadata spin lock it needs
+ * to release the mutex.
+ * 
+ * a gate is a one-shot rendezvous point. physical
+ * cores wait() at a closed gate in a low-power state
+ * until another core open()s it, which wakes them
+ * with an IPI via the messaging system.
  * 
  * (c) Chris Williams, 2021.
  *
@@ -24,11 +36,17 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use super::pcore::PhysicalCore;
+use super::message;
+use super::machine;
 
 /* if a lock() call spins more than DEADLOCK_THRESHOLD times
    then it's considered a deadlocked mutex */
 const DEADLOCK_THRESHOLD: usize = 1000000;
 
+/* most iterations a core waits between attempts to acquire a held mutex.
+   the wait starts at one iteration and doubles after each failed attempt */
+const BACKOFF_MAX: usize = 1024;
+
 /* define a snip lock primitive */
 pub struct SpinLock
 {
@@ -82,9 +100,21 @@ pub struct Mutex<T>
     /* accounting */
     lock_attempts: AtomicUsize,
     lock_count: AtomicUsize,
+    lock_contended: AtomicUsize,    /* acquisitions that had to wait for another core */
+    lock_spins_max: AtomicUsize,    /* most attempts made by a single acquisition */
     description: &'static str
 }
 
+/* a snapshot of a mutex's accounting */
+pub struct LockStats
+{
+    pub description: &'static str,
+    pub attempts: usize,
+    pub acquired: usize,
+    pub contended: usize,
+    pub spins_max: usize
+}
+
 /* Mutex uses the same API as std's Mutex. Create a Mutex using new() and then
    call lock() to block until mutex successfully acquired. Drop the mutex guard to release */
 impl<T> Mutex<T>
@@ -99,6 +129,8 @@ impl<T> Mutex<T>
             owner: AtomicUsize::new(0),
             lock_attempts: AtomicUsize::new(0),
             lock_count: AtomicUsize::new(0),
+            lock_contended: AtomicUsize::new(0),
+            lock_spins_max: AtomicUsize::new(0),
             description
         }
     }
@@ -107,6 +139,7 @@ impl<T> Mutex<T>
     pub fn lock(&self) -> MutexGuard<'_, T>
     {
         let mut attempts = 0;
+        let mut backoff = 1;
 
         let this_pcore_id = PhysicalCore::get_id();
         loop
@@ -138,13 +171,23 @@ impl<T> Mutex<T>
                 }
             }
 
-            /* give another core a chance to acquire the mutex */
+            /* give another core a chance to acquire the mutex, and the owner a chance to release it */
             self.owner_lock.unlock();
+            for _ in 0..backoff
+            {
+                core::hint::spin_loop();
+            }
+            backoff = core::cmp::min(backoff * 2, BACKOFF_MAX);
         }
 
         /* don't forget to unlock the metadata
            before returning a reference to the content */
         self.lock_count.fetch_add(1, Ordering::Relaxed);
+        if attempts > 1
+        {
+            self.lock_contended.fetch_add(1, Ordering::Relaxed);
+            self.lock_spins_max.fetch_max(attempts, Ordering::Relaxed);
+        }
         self.owner_lock.unlock();
         MutexGuard { mutex: &self }
     }
@@ -157,6 +200,19 @@ impl<T> Mutex<T>
         self.owner_lock.unlock();
     }
 
+    /* return a snapshot of the mutex's accounting. this doesn't acquire the mutex */
+    pub fn stats(&self) -> LockStats
+    {
+        LockStats
+        {
+            description: self.description,
+            attempts: self.lock_attempts.load(Ordering::Relaxed),
+            acquired: self.lock_count.load(Ordering::Relaxed),
+            contended: self.lock_contended.load(Ordering::Relaxed),
+            spins_max: self.lock_spins_max.load(Ordering::Relaxed)
+        }
+    }
+
     /* return true if the mutex is locked, or false if not */
     pub fn is_locked(&self) -> bool
     {
@@ -172,10 +228,12 @@ impl<T> core::fmt::Debug for MutexGuard<'_, T>
 {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result
     {
-        write!(f, "{} attempts to acquire {}, {} succeeded",
+        write!(f, "{} attempts to acquire {}, {} succeeded, {} contended, at most {} attempts",
             self.mutex.lock_attempts.load(Ordering::Relaxed),
             self.mutex.description,
-            self.mutex.lock_count.load(Ordering::Relaxed))
+            self.mutex.lock_count.load(Ordering::Relaxed),
+            self.mutex.lock_contended.load(Ordering::Relaxed),
+            self.mutex.lock_spins_max.load(Ordering::Relaxed))
     }
 }
 
@@ -210,6 +268,52 @@ impl<T> Drop for MutexGuard<'_, T>
     }
 }
 
+/* define a one-shot gate that physical cores can wait at without spinning on a lock */
+pub struct Gate
+{
+    open: AtomicBool,
+    description: &'static str
+}
+
+impl Gate
+{
+    pub fn new(description: &'static str) -> Gate
+    {
+        Gate { open: AtomicBool::new(false), description }
+    }
+
+    /* return true if the gate has been opened */
+    pub fn is_open(&self) -> bool
+    {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /* block in a low-power state until the gate is opened. a wakeup that
+       arrives between checking the gate and waiting is not lost: the
+       pending interrupt stops the core from going to sleep */
+    pub fn wait(&self)
+    {
+        while self.is_open() == false
+        {
+            machine::wait_for_interrupt();
+        }
+    }
+
+    /* open the gate and wake up any physical cores waiting at it */
+    pub fn open(&self)
+    {
+        self.open.store(true, Ordering::SeqCst);
+        match message::Message::new(message::Recipient::send_to_all(), message::MessageContent::Wakeup)
+        {
+            Ok(m) => if let Err(_e) = message::send(m)
+            {
+                hvdebug!("Failed to wake cores waiting at {} gate: {:?}", self.description, _e);
+            },
+            Err(_e) => hvdebug!("Failed to create wakeup for {} gate: {:?}", self.description, _e)
+        }
+    }
+}
+
 /* keep rustc happy */
 unsafe impl<T> Send for Mutex<T> where T: Send {}
 unsafe impl<T> Sync for Mutex<T> where T: Send {}
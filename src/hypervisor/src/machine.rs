@@ -0,0 +1,455 @@
+/* diosix RISC-V machine-level controls
+ *
+ * The platform code sets up each CPU core and drives the devices it
+ * finds. The hypervisor's own features need a few more of the core's
+ * controls, such as extra physical memory protection windows and
+ * the supervisor-level interrupt pending bits. These are driven
+ * directly here through the core's control and status registers.
+ *
+ * Protection windows are built from pairs of PMP entries in top-of-
+ * range mode. The hardware gives lower-numbered entries priority, so
+ * windows are allocated from the top entries down: window 0, the
+ * capsule's main RAM, takes the last pair and has the lowest priority,
+ * and each window after it overrides those before it. That lets a
+ * later window close off or write-protect part of an earlier one.
+ * The hypervisor itself runs in machine mode and isn't restricted by
+ * these unlocked entries. Windows beyond the number of pairs the
+ * hardware has are ignored.
+ *
+ * Cores with the Zkr extension have an entropy source, read through
+ * the seed register, which can seed guests' random number generators.
+ *
+ * Cores with the Sstc extension have a supervisor timer compare
+ * register, stimecmp, so supervisors can program their own timer IRQs
+ * without trapping into the hypervisor.
+ *
+ * Cores with the Zicbom extension can write back and discard ranges of
+ * their data caches, for sharing memory with devices that don't snoop
+ * them. Cores without it may sit behind a shared cache controller that
+ * can flush lines by physical address instead, as on the StarFive
+ * JH7110. Without either, the platform is assumed to be cache-coherent
+ * and the cache operations do nothing.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use platform::physmem::{PhysMemBase, PhysMemEnd, AccessPermissions};
+
+/* PMP entries every implementation with PMP is expected to have */
+const PMP_ENTRIES: usize = 16;
+
+/* number of protection windows available */
+pub const PROTECTION_WINDOWS: usize = PMP_ENTRIES / 2;
+
+/* PMP configuration bits */
+const PMP_READ: usize = 1 << 0;
+const PMP_WRITE: usize = 1 << 1;
+const PMP_EXEC: usize = 1 << 2;
+const PMP_TOR: usize = 1 << 3;
+
+/* menvcfg's bit that hands supervisor timer IRQs over to stimecmp (Sstc) */
+const MENVCFG_STCE: usize = 1 << 63;
+
+/* interrupt pending and enable bits */
+const IRQ_SUPERVISOR_SOFT: usize = 1 << 1;
+const IRQ_SUPERVISOR_EXTERNAL: usize = 1 << 9;
+const IRQ_MACHINE_EXTERNAL: usize = 1 << 11;
+
+/* number of control and status registers captured when code traps into the hypervisor */
+pub const TRAPPED_CONTROL_REGISTERS: usize = 11;
+
+/* number of machine-level control and status registers listed for debugging */
+pub const CONTROL_REGISTERS: usize = 14;
+
+/* where satp holds its translation mode, and the mode that turns translation off */
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_MODE_BARE: usize = 0;
+
+/* fields of the Zkr seed register, CSR 0x015 */
+const SEED_STATUS_SHIFT: usize = 30;
+const SEED_STATUS_MASK: usize = 0b11;
+const SEED_STATUS_ES16: usize = 0b10;
+const SEED_STATUS_DEAD: usize = 0b11;
+const SEED_ENTROPY_MASK: usize = 0xffff;
+
+/* times to poll the entropy source while it's busy before giving up */
+const SEED_POLLS: usize = 1000;
+
+/* size in bytes of the blocks the Zicbom cache operations work on, or 0 if they aren't available */
+static CACHE_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/* address of a shared cache controller's flush register, and the size of the lines it flushes,
+   used when the cores don't have Zicbom, or 0 if there isn't one */
+static CACHE_FLUSH_REGISTER: AtomicUsize = AtomicUsize::new(0);
+static CACHE_FLUSH_LINE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/* write to a PMP address register, which must be named in the instruction */
+macro_rules! write_pmpaddr
+{
+    ($entry:expr, $value:expr, $($n:literal),*) =>
+    {
+        match $entry
+        {
+            $($n => unsafe { asm!(concat!("csrw pmpaddr", $n, ", {0}"), in(reg) $value) },)*
+            _ => ()
+        }
+    };
+}
+
+/* read a control and status register, which must be named in the instruction */
+macro_rules! read_csr
+{
+    ($name:literal) =>
+    {
+        {
+            let value: usize;
+            unsafe { asm!(concat!("csrr {0}, ", $name), out(reg) value) };
+            value
+        }
+    };
+}
+
+/* set the address register of the given PMP entry
+   => entry = PMP entry number
+      addr = physical address, which is stored in the register as a word address */
+fn set_pmp_address(entry: usize, addr: usize)
+{
+    write_pmpaddr!(entry, addr >> 2, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+}
+
+/* set the configuration byte of the given PMP entry, leaving the other entries' bytes alone.
+   RV64 packs eight entries into each of pmpcfg0 and pmpcfg2
+   => entry = PMP entry number
+      config = PMP configuration bits */
+fn set_pmp_config(entry: usize, config: usize)
+{
+    let shift = (entry % 8) * 8;
+    let mask = 0xff << shift;
+    let bits = (config & 0xff) << shift;
+
+    unsafe
+    {
+        match entry / 8
+        {
+            0 => asm!("csrc pmpcfg0, {0}", "csrs pmpcfg0, {1}", in(reg) mask, in(reg) bits),
+            _ => asm!("csrc pmpcfg2, {0}", "csrs pmpcfg2, {1}", in(reg) mask, in(reg) bits)
+        }
+    }
+}
+
+/* <= the PMP permission bits matching the given access */
+fn pmp_permissions(permissions: AccessPermissions) -> usize
+{
+    #[allow(unreachable_patterns)]
+    match permissions
+    {
+        AccessPermissions::Read => PMP_READ,
+        AccessPermissions::ReadWrite => PMP_READ | PMP_WRITE,
+        AccessPermissions::ReadExecute => PMP_READ | PMP_EXEC,
+        AccessPermissions::ReadWriteExecute => PMP_READ | PMP_WRITE | PMP_EXEC,
+        _ => 0
+    }
+}
+
+/* program a protection window with the given PMP configuration bits
+   => window = window number
+      base, end = physical address range covered
+      config = PMP configuration bits */
+fn set_window(window: usize, base: PhysMemBase, end: PhysMemEnd, config: usize)
+{
+    if window >= PROTECTION_WINDOWS
+    {
+        /* callers must budget for the windows they need: this range is left as the lower windows set it */
+        hvdebug!("No protection window {} for 0x{:x} to 0x{:x}: only {} windows", window, base, end, PROTECTION_WINDOWS);
+        return;
+    }
+
+    /* disable the window while it's moved so it never covers a half-updated range */
+    let lower = PMP_ENTRIES - 2 - (window * 2);
+    set_pmp_config(lower + 1, 0);
+    set_pmp_address(lower, base);
+    set_pmp_address(lower + 1, end);
+    set_pmp_config(lower, 0);
+    set_pmp_config(lower + 1, config);
+}
+
+/* allow supervisor and user code to access a range of physical memory through a protection window
+   => window = window number. higher numbers take priority where windows overlap
+      base, end = physical address range to open up
+      permissions = access to grant */
+pub fn protect_window(window: usize, base: PhysMemBase, end: PhysMemEnd, permissions: AccessPermissions)
+{
+    set_window(window, base, end, PMP_TOR | pmp_permissions(permissions));
+}
+
+/* deny supervisor and user code any access to a range of physical memory through a protection window,
+   overriding the lower-numbered windows that open it up. accesses raise access faults
+   => window = window number
+      base, end = physical address range to close off */
+pub fn revoke_window(window: usize, base: PhysMemBase, end: PhysMemEnd)
+{
+    set_window(window, base, end, PMP_TOR);
+}
+
+/* close the given protection window and all those numbered after it
+   => window = first window to close */
+pub fn clear_windows_from(window: usize)
+{
+    for w in window..PROTECTION_WINDOWS
+    {
+        set_pmp_config(PMP_ENTRIES - 1 - (w * 2), 0);
+    }
+}
+
+/* <= the address that caused the exception being handled on this CPU core, such as the physical
+      address of a supervisor or user access refused by a protection window */
+pub fn fault_address() -> usize
+{
+    let addr: usize;
+    unsafe { asm!("csrr {0}, mtval", out(reg) addr) };
+    addr
+}
+
+/* <= the address of the instruction that trapped into the hypervisor on this CPU core */
+pub fn trapped_pc() -> usize
+{
+    let pc: usize;
+    unsafe { asm!("csrr {0}, mepc", out(reg) pc) };
+    pc
+}
+
+/* change where the code that trapped into the hypervisor on this CPU core resumes
+   => pc = address of the instruction to resume from */
+pub fn set_trapped_pc(pc: usize)
+{
+    unsafe { asm!("csrw mepc, {0}", in(reg) pc) };
+}
+
+/* <= the control and status registers describing the code that trapped into the hypervisor on
+   this CPU core, by name: its supervisor-level state, which is still loaded, and the machine-level
+   registers recording the trap */
+pub fn trapped_control_registers() -> [(&'static str, usize); TRAPPED_CONTROL_REGISTERS]
+{
+    [
+        ("sstatus", read_csr!("sstatus")),
+        ("sepc", read_csr!("sepc")),
+        ("scause", read_csr!("scause")),
+        ("stval", read_csr!("stval")),
+        ("stvec", read_csr!("stvec")),
+        ("sscratch", read_csr!("sscratch")),
+        ("satp", read_csr!("satp")),
+        ("mstatus", read_csr!("mstatus")),
+        ("mepc", read_csr!("mepc")),
+        ("mcause", read_csr!("mcause")),
+        ("mtval", read_csr!("mtval"))
+    ]
+}
+
+/* <= this CPU core's machine-level control and status registers, by name */
+pub fn control_registers() -> [(&'static str, usize); CONTROL_REGISTERS]
+{
+    [
+        ("mhartid", read_csr!("mhartid")),
+        ("misa", read_csr!("misa")),
+        ("mvendorid", read_csr!("mvendorid")),
+        ("marchid", read_csr!("marchid")),
+        ("mimpid", read_csr!("mimpid")),
+        ("mstatus", read_csr!("mstatus")),
+        ("mtvec", read_csr!("mtvec")),
+        ("medeleg", read_csr!("medeleg")),
+        ("mideleg", read_csr!("mideleg")),
+        ("mie", read_csr!("mie")),
+        ("mip", read_csr!("mip")),
+        ("mcounteren", read_csr!("mcounteren")),
+        ("pmpcfg0", read_csr!("pmpcfg0")),
+        ("pmpcfg2", read_csr!("pmpcfg2"))
+    ]
+}
+
+/* <= true if the given satp value turns on address translation */
+pub fn satp_translates(satp: usize) -> bool
+{
+    (satp >> SATP_MODE_SHIFT) != SATP_MODE_BARE
+}
+
+/* read 16 bits of entropy from this CPU core's Zkr entropy source, which must be present.
+   the source may need time to gather more entropy, so it's polled a limited number of times
+   <= 16 random bits, or None if the source is still busy or has failed */
+pub fn read_entropy() -> Option<u16>
+{
+    for _ in 0..SEED_POLLS
+    {
+        /* the seed register must be read with a write, which tells the source to move on */
+        let seed: usize;
+        unsafe { asm!("csrrw {0}, 0x015, x0", out(reg) seed) };
+        match (seed >> SEED_STATUS_SHIFT) & SEED_STATUS_MASK
+        {
+            SEED_STATUS_ES16 => return Some((seed & SEED_ENTROPY_MASK) as u16),
+            SEED_STATUS_DEAD => return None,
+            _ => () /* still starting up or waiting for entropy */
+        }
+    }
+    None
+}
+
+/* hand this CPU core's supervisor timer IRQs over to its timer compare register, so that
+   supervisor code can program them itself. the core must have the Sstc extension. the
+   register is set so that no IRQ fires until a supervisor programs it */
+pub fn enable_supervisor_compare()
+{
+    set_supervisor_compare(u64::MAX);
+    unsafe { asm!("csrs 0x30a, {0}", in(reg) MENVCFG_STCE) };
+}
+
+/* <= value of this CPU core's supervisor timer compare register, stimecmp */
+pub fn get_supervisor_compare() -> u64
+{
+    let value: u64;
+    unsafe { asm!("csrr {0}, 0x14d", out(reg) value) };
+    value
+}
+
+/* set this CPU core's supervisor timer compare register, stimecmp. a supervisor timer IRQ is
+   pending whenever the timer's value is at or beyond the compare value */
+pub fn set_supervisor_compare(value: u64)
+{
+    unsafe { asm!("csrw 0x14d, {0}", in(reg) value) };
+}
+
+/* enable or disable the Zicbom cache operations. call on the boot core before using them
+   => block_size = size in bytes of a cache block, as given in the device tree, or 0 if the
+                   cores don't have Zicbom. must be a power of two */
+pub fn set_cache_block_size(block_size: usize)
+{
+    CACHE_BLOCK_SIZE.store(block_size, Ordering::SeqCst);
+}
+
+/* flush caches through a shared cache controller for cores without Zicbom. call on the boot core
+   before using the cache operations
+   => register = physical address of the controller's register that writes back and discards
+                 the line holding the physical address written to it
+      line_size = size in bytes of the controller's cache lines. must be a power of two */
+pub fn set_cache_flush_register(register: usize, line_size: usize)
+{
+    CACHE_FLUSH_LINE_SIZE.store(line_size, Ordering::SeqCst);
+    CACHE_FLUSH_REGISTER.store(register, Ordering::SeqCst);
+}
+
+/* run a Zicbom cache operation on every cache block overlapping the given range, then make sure
+   it's finished before any memory access that follows. without Zicbom, each line is flushed
+   through the shared cache controller, if there is one, instead
+   => base, end = physical address range to operate on
+      op = the operation on the block at the given address */
+fn cache_blocks(base: PhysMemBase, end: PhysMemEnd, op: fn(usize))
+{
+    let block_size = CACHE_BLOCK_SIZE.load(Ordering::SeqCst);
+    if block_size == 0
+    {
+        controller_flush(base, end);
+        return;
+    }
+    if base >= end
+    {
+        return;
+    }
+
+    let mut block = base & !(block_size - 1);
+    while block < end
+    {
+        op(block);
+        block = block + block_size;
+    }
+    unsafe { asm!("fence rw, rw") };
+}
+
+/* write back and discard every line of a shared cache controller overlapping the given range.
+   does nothing if there's no controller to do it
+   => base, end = physical address range to flush */
+fn controller_flush(base: PhysMemBase, end: PhysMemEnd)
+{
+    let register = CACHE_FLUSH_REGISTER.load(Ordering::SeqCst);
+    let line_size = CACHE_FLUSH_LINE_SIZE.load(Ordering::SeqCst);
+    if register == 0 || line_size == 0 || base >= end
+    {
+        return;
+    }
+
+    /* make sure this core's stores have reached the controller before flushing them */
+    unsafe { asm!("fence rw, rw") };
+    let mut line = base & !(line_size - 1);
+    while line < end
+    {
+        unsafe { ptr::write_volatile(register as *mut u64, line as u64) };
+        line = line + line_size;
+    }
+    unsafe { asm!("fence rw, rw") };
+}
+
+/* write back any of the given range of physical memory held in this core's data cache,
+   and discard it from the cache. does nothing on cache-coherent platforms
+   => base, end = physical address range to flush */
+pub fn cache_flush(base: PhysMemBase, end: PhysMemEnd)
+{
+    /* cbo.flush, encoded by hand for assemblers that don't know Zicbom */
+    cache_blocks(base, end, |block| unsafe { asm!(".insn i 0x0f, 2, x0, {0}, 2", in(reg) block) });
+}
+
+/* discard any of the given range of physical memory held in this core's data cache without
+   writing it back, so it's next read from memory. a shared cache controller can only flush,
+   so dirty lines are written back first there. does nothing on cache-coherent platforms
+   => base, end = physical address range to invalidate. the blocks at either end are discarded
+                  whole, so any other data sharing them must have been flushed first */
+pub fn cache_invalidate(base: PhysMemBase, end: PhysMemEnd)
+{
+    /* cbo.inval, encoded by hand for assemblers that don't know Zicbom */
+    cache_blocks(base, end, |block| unsafe { asm!(".insn i 0x0f, 2, x0, {0}, 0", in(reg) block) });
+}
+
+/* set or clear the supervisor external interrupt pending bit, which machine mode can write.
+   supervisor code sees an external interrupt until the bit is cleared */
+pub fn trigger_supervisor_external_irq()
+{
+    unsafe { asm!("csrs mip, {0}", in(reg) IRQ_SUPERVISOR_EXTERNAL) };
+}
+
+pub fn clear_supervisor_external_irq()
+{
+    unsafe { asm!("csrc mip, {0}", in(reg) IRQ_SUPERVISOR_EXTERNAL) };
+}
+
+/* set or clear the supervisor software interrupt pending bit, which machine mode can write.
+   supervisor code sees a software interrupt until the bit is cleared */
+pub fn trigger_supervisor_software_irq()
+{
+    unsafe { asm!("csrs mip, {0}", in(reg) IRQ_SUPERVISOR_SOFT) };
+}
+
+pub fn clear_supervisor_software_irq()
+{
+    unsafe { asm!("csrc mip, {0}", in(reg) IRQ_SUPERVISOR_SOFT) };
+}
+
+/* allow the interrupt controller to interrupt this CPU core in machine mode */
+pub fn enable_machine_external_irq()
+{
+    unsafe { asm!("csrs mie, {0}", in(reg) IRQ_MACHINE_EXTERNAL) };
+}
+
+/* stop this CPU core in a low-power state until an interrupt is pending. this returns even if
+   the interrupt is disabled, so a core can wait for a wakeup with interrupts off. it may also
+   return early for no reason, so callers must check for whatever they're waiting for */
+pub fn wait_for_interrupt()
+{
+    unsafe { asm!("wfi") };
+}
+
+/* make sure this CPU core fetches the instructions now in memory rather than any stale copies
+   in its instruction cache. call after writing code, and on every core that may run it */
+pub fn sync_instructions()
+{
+    unsafe { asm!("fence.i") };
+}
@@ -42,6 +42,9 @@ extern crate devicetree;
 /* needed for parsing diosix manifest file-system (DMFS) images bundled with the hypervisor */
 extern crate dmfs;
 
+/* needed for the hypercall ABI values shared with the services */
+extern crate hypercall;
+
 /* needed for lazyily-allocated static variables */
 #[macro_use]
 extern crate lazy_static;
@@ -53,6 +56,8 @@ extern crate platform;
 #[macro_use]
 mod debug;      /* get us some kind of debug output, typically to a serial port */
 #[macro_use]
+mod error;      /* list of error codes, and their details */
+#[macro_use]
 mod capsule;    /* manage capsules */
 #[macro_use]
 mod heap;       /* per-CPU private heap management */
@@ -65,20 +70,88 @@ mod virtmem;    /* manage capsule virtual memory */
 mod pcore;      /* manage CPU cores */
 mod vcore;      /* virtual CPU core management... */
 mod scheduler;  /* ...and scheduling */
+mod schedpolicy; /* ...and choosing which virtual core runs next */
+mod timerwheel; /* run hypervisor-internal events at future times */
+mod workqueue;  /* carry out long-running hypervisor jobs a step at a time */
 mod loader;     /* parse and load supervisor binaries */
 mod message;    /* send messages between physical cores */
 mod service;    /* allow capsules to register services */
 mod manifest;   /* manage capsules loaded with the hypervisor */
-
-/* needed for exclusive locks */
+mod passthrough; /* hand physical peripherals to capsules */
+mod virtdt;     /* customize capsules' device trees */
+mod abi;        /* negotiate hypercall ABI versions with capsules */
+mod transfer;   /* copy bulk data between capsules and services */
+mod trace;      /* record capsules' hypercalls for debugging */
+mod qos;        /* partition the cache and memory bandwidth between capsules */
+mod selftest;   /* runtime self-tests for validating new hardware */
+mod guestlog;   /* forward capsules' structured log records into the hypervisor's log */
+mod seriallink; /* join pairs of capsules with virtual serial links */
+mod devmodel;   /* load emulated device models as plugins from the DMFS image */
+mod metrics;    /* count per-capsule activity for management services */
+mod top;        /* render a status screen over the debug port */
+mod console;    /* validate and render capsule console text */
+mod failover;   /* promote standby capsules when a service's owner dies */
+mod wss;        /* estimate capsules' working sets by sampling page accesses */
+mod identity;   /* provision capsules with unique IDs and RNG seeds */
+mod warmboot;   /* recreate all capsules without rebooting the host */
+mod guestpanic; /* keep and forward the panic reports of dying guests */
+mod crashdump;  /* dump the registers and memory of crashed capsules */
+mod inspect;    /* read the registers and memory of paused capsules */
+mod abboot;     /* try out newly selected boot images and roll back failures */
+mod boottime;   /* time each stage of bringing up capsules */
+mod pstore;     /* keep recent alerts in memory that survives reboots */
+mod admin;      /* administer the host over the debug port */
+mod bounce;     /* bounce DMA through safe memory for devices without an IOMMU */
+mod clock;      /* provide monotonic and wall-clock time to capsules */
+mod power;      /* reboot or power off the host on request */
+mod integrity;  /* catch overwritten stacks, heaps, and per-CPU variables */
+mod gpio;       /* hand individual GPIO lines to capsules */
+mod throttle;   /* throttle and quarantine capsules that trap too often */
+mod lifecycle;  /* move capsules between states by the rules */
+mod stream;     /* stream connections between capsules and services */
+mod pressure;   /* warn capsules when the host is running short of memory */
+mod grant;      /* grant parts of capsules' memory to other capsules and the hypervisor */
+mod telemetry;  /* export metrics in a format external collectors understand */
+mod sha256;     /* hash and authenticate data with SHA-256 */
+mod measure;    /* measure capsules as they're loaded */
+mod sealed;     /* release sealed secrets to capsules that measure up */
+mod settings;   /* adjust the hypervisor's settings while it's running */
+mod inventory;  /* describe the host's hardware at boot and to management capsules */
+mod vipi;       /* let a capsule's virtual cores interrupt each other */
+mod mmio;       /* map physical MMIO ranges into trusted capsules */
+mod dirty;      /* track changes to capsules' memory for snapshots and migration */
+mod template;   /* freeze booted capsules as templates and clone new capsules from them */
+mod quiesce;    /* ask guests to quiesce themselves for consistent snapshots */
+mod virtqueue;  /* share virtio queues between device models and capsules */
+mod arbiter;    /* share the debug port fairly between the hypervisor and capsules */
+mod vdevice;    /* parse capsules' virtual devices declared in the manifest */
+mod button;     /* press guests' virtual power and reboot buttons */
+mod hcargs;     /* check the buffers capsules pass in hypercalls */
+mod accounting; /* account for the time capsules spend running and in the hypervisor */
+mod rtc;        /* emulate a real-time clock for each capsule */
+mod machine;    /* drive the CPU core's machine-level controls the platform code doesn't */
+mod trap;       /* read and write the registers of trapped code */
+mod syscalls;   /* decode capsules' hypercalls and return their results */
+mod plic;       /* route device interrupts through the host's PLIC */
+mod clint;      /* interrupt physical cores through the host's CLINT */
+mod cbqri;      /* drive the host's cache and memory bandwidth QoS controllers */
+mod iommu;      /* confine passed-through devices' DMA with the host's IOMMU, where it can */
+mod jh7110;     /* work around the StarFive JH7110's quirks */
+mod hostrtc;    /* read the wall-clock time from the host's real-time clock */
+mod syscon;     /* reboot and power off the host through its system controller */
+mod hostgpio;   /* drive the lines of the host's GPIO controller */
+#[cfg(feature = "sbilegacy")]
+mod sbilegacy;  /* translate legacy SBI v0.1 calls from older guests */
+#[cfg(feature = "bringup")]
+mod bringup;    /* poke at new boards over the debug port without starting capsules */
+
+/* needed for exclusive locks and rendezvous points */
 mod lock;
-use lock::Mutex;
+use lock::{Mutex, Gate};
 
-/* list of error codes */
-mod error;
 use error::Cause;
 
-use pcore::{PhysicalCoreID, BOOT_PCORE_ID};
+use pcore::{PhysicalCoreID, HartID, BOOT_PCORE_ID};
 
 /* tell Rust to use our HVallocator to allocate and free heap memory.
 although we'll keep track of physical memory, we'll let Rust perform essential
@@ -88,16 +161,16 @@ static HV_HEAP: heap::HVallocator = heap::HVallocator;
 
 lazy_static!
 {
-    /* set to true to allow physical CPU cores to start running supervisor code */
-    static ref INIT_DONE: Mutex<bool> = Mutex::new("system bring-up", false);
+    /* opened to allow physical CPU cores to start running supervisor code */
+    static ref INIT_DONE: Gate = Gate::new("system bring-up");
 
-    /* a physical CPU core obtaining this lock when it is false must walk the DMFS, create
+    /* a physical CPU core obtaining this lock when it is false must walk the DMFS, queue the
     capsules required to run at boot time, and set the flag to true. any other core
-    obtaining it as true must release the lock and move on */
+    obtaining it as true must release the lock and help create the queued capsules */
     static ref MANIFEST_UNPACKED: Mutex<bool> = Mutex::new("dmfs unpacked", false);
 
-    /* set to true if individual cores can sound off their presence and capabilities */
-    static ref ROLL_CALL: Mutex<bool> = Mutex::new("CPU roll call", false);
+    /* opened when individual cores can sound off their presence and capabilities */
+    static ref ROLL_CALL: Gate = Gate::new("CPU roll call");
 }
 
 /* pointer sizes: stick to usize as much as possible: don't always assume it's a 64-bit machine */
@@ -105,23 +178,26 @@ lazy_static!
 /* hventry
    This is the official entry point of the Rust-level hypervisor.
    Call hvmain, which is where all the real work happens, and catch any errors.
-   => cpu_nr = this boot-assigned CPU ID number
+   => hart = hardware-assigned ID number of this CPU core, which may be non-linear
       dtb_ptr = pointer to start of device tree blob structure
       dtb_len = 32-bit big-endian length of the device tree blob
    <= return to infinite loop, awaiting interrupts */
 #[no_mangle]
-pub extern "C" fn hventry(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32)
+pub extern "C" fn hventry(hart: HartID, dtb_ptr: *const u8, dtb_len: u32)
 {
+    /* number the cores linearly as they arrive, whatever their hardware IDs */
+    let cpu_nr = pcore::assign_id();
+
     /* carry out tests if that's what we're here for */
     #[cfg(test)]
     hvtests();
 
     /* if not performing tests, start the system as normal */
-    match hvmain(cpu_nr, dtb_ptr, dtb_len)
+    match hvmain(cpu_nr, hart, dtb_ptr, dtb_len)
     {
         Err(e) =>
         {
-            hvalert!("Hypervisor failed to start. Reason: {:?}", e);
+            hvalert!("Hypervisor failed to start. Reason: {}", error::report(&e));
             debughousekeeper!(); /* attempt to flush queued debug to output */
         },
         _ => () /* continue waiting for an IRQ to come in */
@@ -145,20 +221,21 @@ pub extern "C" fn hventry(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u
    for marking some cores as more powerful than others for systems with
    a mix of performance and efficiency CPU cores.
 
-   => cpu_nr = arbitrary CPU core ID number assigned by boot code,
+   => cpu_nr = linear CPU core ID number assigned on entry,
                separate from hardware ID number.
                BOOT_PCORE_ID = boot CPU core.
+      hart = hardware-assigned ID number of this CPU core
       dtb_ptr = pointer to device tree in memory from bootlaoder
       dtb_len = 32-bit big endian size of the device tree
    <= return to infinite loop, waiting for interrupts
 */
-fn hvmain(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32) -> Result<(), Cause>
+fn hvmain(cpu_nr: PhysicalCoreID, hart: HartID, dtb_ptr: *const u8, dtb_len: u32) -> Result<(), Cause>
 {
     /* set up each physical processor core with its own private heap pool and any other resources.
     each private pool uses physical memory assigned by the pre-hvmain boot code. init() should be called
     first thing to set up each processor core, including the boot CPU, which then sets up the global
     resources. all non-boot CPUs should wait until global resources are ready. */
-    pcore::PhysicalCore::init(cpu_nr);
+    pcore::PhysicalCore::init(cpu_nr, hart);
 
     /* note that pre-physmem::init(), CPU cores rely on their pre-hventry()-assigned
     heap space. after physmem::init(), CPU cores can extend their heaps using physical memory.
@@ -167,8 +244,8 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32) -> Result<()
     match cpu_nr
     {
         /* delegate to boot CPU the welcome banner and set up global resources.
-        note: the platform code should ensure whichever CPU core is assigned
-        BOOT_PCORE_ID as its cpu_nr can initialize the hypervisor */
+        note: the first core to enter hventry() is assigned BOOT_PCORE_ID, so the
+        platform code should ensure that core can initialize the hypervisor */
         BOOT_PCORE_ID =>
         {
             /* convert the dtb pointer into a rust byte slice. assumes dtb_len is valid */
@@ -179,18 +256,33 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32) -> Result<()
             also initialize any found hardware */
             hardware::parse_and_init(dtb)?;
 
+            /* if we're bringing up a new board, stop at the debug UART and timer and hand
+            over to the diagnostic prompt. INIT_DONE is never opened, so the other cores stay idle */
+            #[cfg(feature = "bringup")]
+            bringup::run(dtb);
+
             /* register all the available physical RAM */
             physmem::init()?;
-            describe_system();
+            inventory::init();
+            pstore::init();
+            settings::init();
+            clock::init();
+            schedpolicy::init();
+            capsule::init();
+            top::init();
+            telemetry::init();
 
             /* allow other cores to continue */
-            *(INIT_DONE.lock()) = true;
+            INIT_DONE.open();
         },
 
         /* non-boot cores must wait here for early initialization to complete */
-        _ => while *(INIT_DONE.lock()) != true {}
+        _ => INIT_DONE.wait()
     }
 
+    /* now the hardware's known, let supervisors program their own timer IRQs where the core allows */
+    pcore::PhysicalCore::enable_supervisor_compare();
+
     /* Create capsules to run from the bundled DMFS image.
     the hypervisor can't make any assumptions about the underlying hardware.
     the device tree for these early capsules is derived from the host's device tree,
@@ -198,26 +290,34 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32) -> Result<()
     physical CPU core that creates it. this is more straightforward than the hypervisor
     trying to specify a hypothetical CPU core
     
-    as such, only allow supervisor-mode capable CPU cores to build capasules */
+    as such, only allow supervisor-mode capable CPU cores to build capasules. one core
+    walks the manifest, and then all of them share out the work of loading each capsule */
     if pcore::PhysicalCore::smode_supported() == true
     {
         /* only allow one core to do the unpacking */
-        let mut flag = MANIFEST_UNPACKED.lock();
-        
-        if *flag == false
         {
-            /* process the manifest and mark it as handled */
-            manifest::unpack_at_boot()?;
-            *flag = true;
+            let mut flag = MANIFEST_UNPACKED.lock();
+            if *flag == false
+            {
+                /* process the manifest and mark it as handled */
+                manifest::unpack_at_boot()?;
+                *flag = true;
+            }
+        }
 
-            /* allow all working cores to join the roll call */
-            *(ROLL_CALL.lock()) = true;
+        /* the core that finishes the last capsule allows all working cores to join the roll call */
+        if manifest::load_at_boot() == true
+        {
+            ROLL_CALL.open();
         }
     }
 
-    /* once ROLL_CALL is set to true, acknowledge we're alive and well, and report CPU core features */
-    while *(ROLL_CALL.lock()) != true {}
-    hvdebug!("Physical CPU core {:?} ready to roll", pcore::PhysicalCore::describe());
+    /* once ROLL_CALL is opened, acknowledge we're alive and well, and report CPU core features */
+    ROLL_CALL.wait();
+    inventory::add_core();
+
+    /* let passed-through devices' interrupts reach this physical CPU core */
+    plic::enable_core();
 
     /* enable timer on this physical CPU core to start scheduling and running virtual cores */
     scheduler::start()?;
@@ -229,47 +329,6 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb_ptr: *const u8, dtb_len: u32) -> Result<()
     Ok(())
 }
 
-/* dump system information to the user */
-fn describe_system()
-{
-    const KILOBYTE: usize = 1024;
-    const MEGABYTE: usize = KILOBYTE * KILOBYTE;
-    const GIGABYTE: usize = KILOBYTE * MEGABYTE;
-
-    /* say hello via the debug port with some information */
-    hvdebug!("Diosix {} :: Debug enabled. {} and {} RAM found",
-
-        /* build version number */
-        env!("CARGO_PKG_VERSION"),
-
-        /* report number of CPU cores found */
-        match hardware::get_nr_cpu_cores()
-        {
-            None | Some(0) => format!("no CPU cores"),
-            Some(1) => format!("1 CPU core"),
-            Some(c) => format!("{} CPU cores", c)
-        },
-
-        /* count up total system RAM using GiB / MiB / KiB */
-        match hardware::get_phys_ram_total()
-        {
-            Some(t) => if t >= GIGABYTE
-            {
-                format!("{} GiB", t / GIGABYTE)
-            }
-            else if t >= MEGABYTE
-            {
-                format!("{} MiB", t / MEGABYTE)
-            }
-            else
-            {
-                format!("{} KiB", t / KILOBYTE)
-            },
-
-            None => format!("no")
-    });
-}
-
 /* mandatory error handler for memory allocations */
 #[alloc_error_handler]
 fn hvalloc_error(attempt: core::alloc::Layout) -> !
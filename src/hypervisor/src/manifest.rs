@@ -6,15 +6,34 @@
  */
 
 use super::physmem;
-use super::error::Cause;
+use super::error::{self, Cause};
 use super::capsule;
-use super::hardware;
 use super::loader;
-use super::virtmem::Mapping;
+use super::passthrough;
+use super::seriallink;
+use super::gpio;
+use super::devmodel;
+use super::vdevice::{DeviceSpec, DeviceKind};
+use super::clock;
+use super::virtdt;
+use super::qos;
+use super::failover;
+use super::wss;
+use super::pressure;
+use super::measure;
+use super::boottime::{self, Stage};
+use super::service::ServiceType;
+use super::virtmem::{Mapping, SEGMENTS_MAX};
 use super::vcore::Priority;
+use super::lock::Mutex;
+use platform::cpu::Entry;
+use platform::physmem::PhysMemBase;
 use dmfs::{ManifestImageIter, ManifestObject, ManifestObjectType, ManifestObjectData};
+use hashbrown::hash_map::HashMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
 
 /* bring in the built-in dmfs image */
 use core::slice;
@@ -25,6 +44,10 @@ extern "C"
     static _binary_dmfs_img_size: u8;
 }
 
+/* most pieces a capsule's RAM can be split across when there's no free region large enough
+   to hold it in one. each piece after the first takes a protection window */
+const RAM_PIECES_MAX: usize = 4;
+
 /* convert the included dmfs image into a byte slice */
 macro_rules! get_dmfs_image
 {
@@ -41,6 +64,90 @@ macro_rules! get_dmfs_image
     }
 }
 
+/* assets whose contents had to be unpacked into the heap are staged here, so that capsules being
+   created from the same asset at the same time load from one copy rather than one each. sharing
+   the loaded images themselves isn't possible: capsules are identity mapped and fenced off from
+   each other by physical memory protection windows, so one capsule can't be shown another's
+   read-only text alongside its own private data. a staged copy is freed once its last user is done */
+lazy_static!
+{
+    static ref STAGED: Mutex<HashMap<String, Arc<Vec<u8>>>> = Mutex::new("staged DMFS assets", HashMap::new());
+}
+
+/* executables to create capsules from during boot, shared out between the physical cores so
+   that large images are copied, and their RAM cleaned, in parallel */
+struct BootQueue
+{
+    waiting: VecDeque<String>,  /* names of the assets yet to be loaded */
+    loading: usize              /* number of assets being loaded right now */
+}
+
+lazy_static!
+{
+    static ref BOOT_QUEUE: Mutex<BootQueue> = Mutex::new("boot capsule queue", BootQueue { waiting: VecDeque::new(), loading: 0 });
+}
+
+/* an asset's contents, ready to be read */
+enum Contents
+{
+    Image(&'static [u8]),           /* stored uncompressed in the DMFS image, so read in place */
+    Staged(String, Arc<Vec<u8>>)    /* name of the asset and its copy in the heap */
+}
+
+impl Contents
+{
+    fn as_slice(&self) -> &[u8]
+    {
+        match self
+        {
+            Contents::Image(bytes) => bytes,
+            Contents::Staged(_, bytes) => bytes.as_slice()
+        }
+    }
+}
+
+impl Drop for Contents
+{
+    fn drop(&mut self)
+    {
+        if let Contents::Staged(name, bytes) = self
+        {
+            /* the cache holds the other reference when this is the last user. hold the
+               cache's lock so no one can pick up the copy as it's being freed */
+            let mut staged = STAGED.lock();
+            if Arc::strong_count(bytes) == 2
+            {
+                staged.remove(name);
+            }
+        }
+    }
+}
+
+/* the read-only contents of an asset backing a virtual device, held for as long as the device exists */
+pub struct Backing(Contents);
+
+impl Backing
+{
+    pub fn as_slice(&self) -> &[u8] { self.0.as_slice() }
+}
+
+/* find an asset's contents, sharing any staged copy with others loading the same asset
+   => asset = asset to read
+   <= its contents */
+fn contents(asset: &ManifestObject) -> Contents
+{
+    match asset.get_contents()
+    {
+        ManifestObjectData::Region(r) => Contents::Image(&get_dmfs_image!()[r.start..r.end]),
+        ManifestObjectData::Bytes(b) =>
+        {
+            let name = asset.get_name();
+            let bytes = STAGED.lock().entry(name.clone()).or_insert_with(|| Arc::new(b.to_vec())).clone();
+            Contents::Staged(name, bytes)
+        }
+    }
+}
+
 /* return a list of a DMFS image's asset names and descriptions
    <= array of (names, descriptions) of image's assets */
 pub fn list_assets() ->  Result<Vec<(String, String)>, Cause>
@@ -80,11 +187,121 @@ pub fn get_named_asset(name: &str) -> Result<ManifestObject, Cause>
         }
     }
 
-    Err(Cause::ManifestNoSuchAsset)
+    Err(hverror!(Cause::ManifestNoSuchAsset, "no asset named {}", name))
+}
+
+/* check the named asset exists in the DMFS image and can be run in a capsule
+   <= Ok if so, or an error code */
+pub fn check_executable(name: &str) -> Result<(), Cause>
+{
+    match get_named_asset(name)?.get_type()
+    {
+        ManifestObjectType::SystemService | ManifestObjectType::GuestOS => Ok(()),
+        _ => Err(Cause::ManifestNotExecutable)
+    }
 }
 
-/* parse the hypervisor's bundled manifest, creating services and capsules as required,
-   and output any included boot banner messages, during system start up */
+/* replace a restarting capsule's executable with another from the DMFS image.
+   the capsule's RAM is wiped, the new executable is loaded into it,
+   and the capsule's device tree is written back into place
+   => cid = capsule being restarted
+      ram = capsule's main physical RAM region
+      name = name of the executable asset to load
+   <= entry point of the new executable, or an error code */
+pub fn reload_image(cid: capsule::CapsuleID, ram: physmem::Region, name: &str) -> Result<Entry, Cause>
+{
+    let _tag = heaptag!();
+    check_executable(name)?;
+
+    let asset = get_named_asset(name)?;
+    let staged = contents(&asset);
+    let content = staged.as_slice();
+
+    if let Some(area) = virtdt::get_area(cid)
+    {
+        check_image_fits(cid, ram, content, area)?;
+    }
+
+    /* try the image out in scratch RAM of the same size first, so that an image that fails to load
+       leaves the capsule's RAM untouched. the image can't simply be copied across afterwards as its
+       entry point and relocations depend on where it's loaded, so load it again for real */
+    let started = boottime::start();
+    let mut scratch = physmem::alloc_region(ram.size())?;
+    scratch.zero();
+    let trial = loader::load(scratch, content);
+    physmem::dealloc_region(scratch)?;
+    trial?;
+
+    /* don't leave any of the old image lying around for the new one to trip over */
+    let mut ram = ram;
+    ram.zero();
+
+    let entry = loader::load(ram, content)?;
+    protect_code(cid, content)?;
+    boottime::record(cid, Stage::Image, started);
+    measure::remeasure(cid, name, content);
+
+    let started = boottime::start();
+    virtdt::restore(cid)?;
+    boottime::record(cid, Stage::DeviceTree, started);
+    Ok(entry)
+}
+
+/* load the named device model plugin from the DMFS image into freshly allocated RAM and relocate it
+   => name = name of the device model asset
+   <= RAM holding the plugin and its entry point, or an error code */
+pub fn load_device_model(name: &str) -> Result<(physmem::Region, Entry), Cause>
+{
+    let _tag = heaptag!();
+    let asset = match get_named_asset(name)
+    {
+        Ok(a) if matches!(a.get_type(), ManifestObjectType::DeviceModel) => a,
+        _ => return Err(hverror!(Cause::DeviceModelNotFound, "no device model named {}", name))
+    };
+    let staged = contents(&asset);
+    let content = staged.as_slice();
+
+    let ram = physmem::alloc_region(loader::image_size(content)?)?;
+    match loader::load(ram, content)
+    {
+        Ok(entry) => Ok((ram, entry)),
+        Err(e) =>
+        {
+            physmem::dealloc_region(ram)?;
+            Err(e)
+        }
+    }
+}
+
+/* find the contents of the named asset to back a virtual device, such as a block device's disk image.
+   sealed secrets can't be used: they're only handed to the capsules they're sealed to
+   => name = name of the asset
+   <= the asset's contents, or an error code */
+pub fn get_backing(name: &str) -> Result<Backing, Cause>
+{
+    let asset = get_named_asset(name)?;
+    match asset.get_type()
+    {
+        ManifestObjectType::SealedSecret => Err(hverror!(Cause::DeviceBadBacking, "secret {} can't back a device", name)),
+        _ => Ok(Backing(contents(&asset)))
+    }
+}
+
+/* copy the named sealed secret, still sealed, from the DMFS image
+   => name = name of the secret's asset
+   <= the sealed secret, or an error code */
+pub fn get_sealed_secret(name: &str) -> Result<Vec<u8>, Cause>
+{
+    match get_named_asset(name)
+    {
+        Ok(a) if matches!(a.get_type(), ManifestObjectType::SealedSecret) => Ok(contents(&a).as_slice().to_vec()),
+        _ => Err(Cause::SecretNotFound)
+    }
+}
+
+/* parse the hypervisor's bundled manifest during system start up, outputting any included boot banner
+   messages and registering standby services, and queue the system services and guests to create.
+   call this on one physical core, then have every core able to run capsules call load_at_boot() */
 pub fn unpack_at_boot() -> Result<(), Cause>
 {
     let image = get_dmfs_image!();
@@ -94,14 +311,23 @@ pub fn unpack_at_boot() -> Result<(), Cause>
         Err(_) => return Err(Cause::ManifestBadFS)
     };
 
+    let mut queue = BOOT_QUEUE.lock();
     for asset in manifest
     {
         match asset.get_type()
         {
             /* only unpack and process boot messages and system services at startup */
             ManifestObjectType::BootMsg => load_asset(asset)?,
-            ManifestObjectType::SystemService => load_asset(asset)?,
-            ManifestObjectType::GuestOS => load_asset(asset)?,
+            ManifestObjectType::SystemService => match standby_for(&asset.get_properties())
+            {
+                /* standby services are only started if the capsule running their service dies */
+                Some(stype) => if let Err(_e) = failover::add_standby(stype, &asset.get_name())
+                {
+                    hvalert!("Ignoring standby system service {}: {}", asset.get_name(), error::report(&_e));
+                },
+                None => queue.waiting.push_back(asset.get_name())
+            },
+            ManifestObjectType::GuestOS => queue.waiting.push_back(asset.get_name()),
             _ => ()
         }
     }
@@ -109,19 +335,54 @@ pub fn unpack_at_boot() -> Result<(), Cause>
     Ok(())
 }
 
+/* help create the system services and guests queued by unpack_at_boot(), one asset at a time,
+   alongside any other physical cores doing the same, until there are none left to start
+   <= true if every queued asset has now been dealt with, or false if others are still being loaded */
+pub fn load_at_boot() -> bool
+{
+    loop
+    {
+        let name =
+        {
+            let mut queue = BOOT_QUEUE.lock();
+            match queue.waiting.pop_front()
+            {
+                Some(name) =>
+                {
+                    queue.loading = queue.loading + 1;
+                    name
+                },
+                None => return queue.loading == 0
+            }
+        };
+
+        match get_named_asset(&name)
+        {
+            Ok(asset) => if let Err(_e) = load_asset(asset)
+            {
+                hvalert!("Failed to load {} during boot: {}", name, error::report(&_e));
+            },
+            Err(_e) => hvalert!("Failed to find {} during boot: {}", name, error::report(&_e))
+        }
+
+        let mut queue = BOOT_QUEUE.lock();
+        queue.loading = queue.loading - 1;
+        if queue.waiting.len() == 0 && queue.loading == 0
+        {
+            return true;
+        }
+    }
+}
+
 /* process the given asset, such as printing it to the debug output stream if it's a boot message
    or parsing it and running it if it's an executable, from the given DMFS image
    => asset = manifest asset to parse and process into memory
 */
 pub fn load_asset(asset: ManifestObject) -> Result<(), Cause>
 {
-    let image = get_dmfs_image!();
     let properties = asset.get_properties();
-    let content = match asset.get_contents()
-    {
-        ManifestObjectData::Bytes(b) => b.as_slice(),
-        ManifestObjectData::Region(r) => &image[r.start..r.end]
-    };
+    let staged = contents(&asset);
+    let content = staged.as_slice();
     
     match asset.get_type()
     {
@@ -133,7 +394,8 @@ pub fn load_asset(asset: ManifestObject) -> Result<(), Cause>
         },
 
         /* create and run a system service */
-        ManifestObjectType::SystemService => match create_capsule_from_exec(content, Some(properties))
+        ManifestObjectType::SystemService => match vet_properties(&asset.get_name(), properties, false)
+            .and_then(|properties| create_capsule_from_exec(&asset.get_name(), content, Some(properties)))
         {
             Ok(cid) => hvdebug!("Created system service {} ({}) {} bytes (capsule {})",
                         asset.get_name(), asset.get_description(), asset.get_contents_size(), cid),
@@ -141,7 +403,8 @@ pub fn load_asset(asset: ManifestObject) -> Result<(), Cause>
         },
 
         /* create an included guest OS (which does not have any special permissions) */
-        ManifestObjectType::GuestOS => match create_capsule_from_exec(content, None)
+        ManifestObjectType::GuestOS => match vet_properties(&asset.get_name(), properties, true)
+            .and_then(|properties| create_capsule_from_exec(&asset.get_name(), content, Some(properties)))
         {
             Ok(cid) => hvdebug!("Created guest OS {} ({}) {} bytes (capsule {})",
                         asset.get_name(), asset.get_description(), asset.get_contents_size(), cid),
@@ -154,47 +417,314 @@ pub fn load_asset(asset: ManifestObject) -> Result<(), Cause>
     Ok(())
 }
 
+/* return the service type a system service is the standby for, or None if it isn't a standby */
+fn standby_for(properties: &Vec<String>) -> Option<ServiceType>
+{
+    for property in properties
+    {
+        if let Some(capsule::CapsuleProperty::StandbyFor(stype)) = capsule::CapsuleProperty::string_to_property(property)
+        {
+            return Some(stype);
+        }
+    }
+    None
+}
+
+/* create and run the named system service from the DMFS image, such as a standby taking over a service
+   <= ID of the service's new capsule, or an error code */
+pub fn start_service(name: &str) -> Result<capsule::CapsuleID, Cause>
+{
+    if matches!(get_named_asset(name)?.get_type(), ManifestObjectType::SystemService) == false
+    {
+        return Err(hverror!(Cause::ManifestNotExecutable, "{} is not a system service", name));
+    }
+
+    start_executable(name)
+}
+
+/* create and run the named system service or guest OS from the DMFS image, such as at an administrator's request
+   <= ID of the new capsule, or an error code */
+pub fn start_executable(name: &str) -> Result<capsule::CapsuleID, Cause>
+{
+    let asset = get_named_asset(name)?;
+    let guest = match asset.get_type()
+    {
+        ManifestObjectType::SystemService => false,
+        ManifestObjectType::GuestOS => true,
+        _ => return Err(hverror!(Cause::ManifestNotExecutable, "{} is not a system service nor guest OS", name))
+    };
+
+    let staged = contents(&asset);
+    let content = staged.as_slice();
+
+    let properties = vet_properties(&asset.get_name(), asset.get_properties(), guest)?;
+    create_capsule_from_exec(&asset.get_name(), content, Some(properties))
+}
+
+/* check the properties an asset is given in the manifest. a capsule isn't created if any of its
+   properties are malformed or unknown, so that a typo can't quietly leave a capsule without a
+   restriction or a right it was meant to have. properties a guest OS isn't allowed are stripped
+   => name = name of the asset, for reporting problems
+      properties = list of properties from the manifest
+      guest = true if the asset is a guest OS, or false for a system service
+   <= list of properties to grant the capsule, or an error code if any are invalid */
+fn vet_properties(name: &str, properties: Vec<String>, guest: bool) -> Result<Vec<String>, Cause>
+{
+    let mut granted = Vec::new();
+    let mut requested = Vec::new();
+    let mut invalid = None;
+
+    for string in properties
+    {
+        match capsule::CapsuleProperty::parse(&string)
+        {
+            Ok(property) =>
+            {
+                if guest == true && property.guest_allowed() == false
+                {
+                    hvalert!("Guest OS {} can't be granted property {}: ignoring it", name, string);
+                }
+                else
+                {
+                    granted.push(string);
+                }
+                requested.push(property);
+            },
+            Err(e) =>
+            {
+                hvalert!("Capsule {} has invalid property {}: {}", name, string, error::report(&e));
+                invalid = Some(e);
+            }
+        }
+    }
+
+    /* together, these would let a guest read and inject every capsule's console text */
+    if guest == true && requested.contains(&capsule::CapsuleProperty::ConsoleRead)
+        && requested.contains(&capsule::CapsuleProperty::ConsoleWrite)
+        && requested.contains(&capsule::CapsuleProperty::ServiceConsole)
+    {
+        hvalert!("Guest OS {} asked to run the console service with full console access. Only system services can do this", name);
+    }
+
+    match invalid
+    {
+        Some(e) => Err(e),
+        None => Ok(granted)
+    }
+}
+
 /* create a capsule from an executable in a DMFS image
-   => binary = slice containing the executable to parse and load
+   => name = name of the executable's asset
+      binary = slice containing the executable to parse and load
       properties = permissions and other properties to grant the capsule, or None
    <= Ok with capusle ID, or an error code
 */
-fn create_capsule_from_exec(binary: &[u8], properties: Option<Vec<String>>) -> Result<capsule::CapsuleID, Cause>
+fn create_capsule_from_exec(name: &str, binary: &[u8], properties: Option<Vec<String>>) -> Result<capsule::CapsuleID, Cause>
 {
+    let _tag = heaptag!();
     /* assign one virtual CPU core to the capsule */
     let cpus = 1;
 
-    /* create capsule with the given properties */
+    /* create capsule with the given properties, which are measured along with its binary */
+    let measured = measure::properties(&properties);
     let capid = capsule::create(properties, cpus)?;
+    capsule::set_name(capid, name)?;
+    attach_resources(capid)?;
 
-    /* reserve 256MB of physical RAM for the capsule */
-    let size = 256 * 1024 * 1024;
-    let ram = physmem::alloc_region(size)?;
+    /* reserve 256MB of physical RAM for the capsule, in pieces if it's too fragmented to find in one.
+    the largest piece is the capsule's main RAM, holding its executable and device tree */
+    let started = boottime::start();
+    let pieces = reserve_scattered_ram(capid, 256 * 1024 * 1024)?;
+    let ram = pieces[0];
 
-    /* create device tree blob for the virtual hardware available to the guest
-    capsule and copy into the end of the region's physical RAM.
-    a zero-length DTB indicates something went wrong */
-    let guest_dtb = hardware::clone_dtb_for_capsule(cpus, 0, ram.base(), ram.size())?;
-    if guest_dtb.len() == 0
+    /* map that physical RAM into the capsule, main RAM first */
+    for (index, piece) in pieces.iter().enumerate()
     {
-        return Err(Cause::BootDeviceTreeBad);
+        let mut mapping = Mapping::new();
+        mapping.set_physical(*piece);
+        mapping.identity_mapping()?;
+        if index > 0
+        {
+            mapping.set_scattered();
+        }
+        capsule::map_memory(capid, mapping)?;
     }
-    let guest_dtb_base = ram.fill_end(guest_dtb)?;
+    boottime::record(capid, Stage::Regions, started);
 
-    /* map that physical RAM into the capsule */
-    let mut mapping = Mapping::new();
-    mapping.set_physical(ram);
-    mapping.identity_mapping()?;
-    capsule::map_memory(capid, mapping)?;
+    /* create device tree blob for the virtual hardware available to the guest
+    capsule and copy into the end of the main RAM. it describes each piece of
+    the capsule's RAM, and can be regenerated later if the capsule's resources change */
+    let started = boottime::start();
+    let guest_dtb_base = virtdt::publish(capid, cpus, ram)?;
+    boottime::record(capid, Stage::DeviceTree, started);
 
     /* parse + copy the capsule's binary into its physical RAM */
+    check_image_fits(capid, ram, binary, guest_dtb_base)?;
+    let started = boottime::start();
     let entry = loader::load(ram, binary)?;
+    protect_code(capid, binary)?;
+    boottime::record(capid, Stage::Image, started);
+    measure::record(capid, measured, name, binary);
 
-    /* create virtual CPU cores for the capsule as required */
+    /* create virtual CPU cores for the capsule as required. capsules with deadlines
+    must have their vcores admitted by the scheduler, which may refuse them */
+    let priority = match capsule::get_deadline(capid)?
+    {
+        Some(deadline) => Priority::Deadline(deadline),
+        None => Priority::High
+    };
     for vcoreid in 0..cpus
     {
-        capsule::add_vcore(capid, vcoreid, entry, guest_dtb_base, Priority::High)?;
+        capsule::add_vcore(capid, vcoreid, entry, guest_dtb_base, priority)?;
     }
+    boottime::queued(capid);
 
     Ok(capid)
-}
\ No newline at end of file
+}
+
+/* hand a new capsule the devices and resources its manifest properties ask for.
+   do this before generating the capsule's device tree so the devices can be described in it
+   => capid = capsule to set up
+   <= Ok for success, or an error code */
+pub fn attach_resources(capid: capsule::CapsuleID) -> Result<(), Cause>
+{
+    /* hand over any physical devices the capsule is allowed to drive directly */
+    for index in capsule::get_serial_ports(capid)?
+    {
+        passthrough::assign_serial_port(capid, index)?;
+    }
+
+    /* join the capsule to any virtual serial links it shares with other capsules */
+    for link in capsule::get_serial_links(capid)?
+    {
+        seriallink::attach(capid, link)?;
+    }
+
+    /* hand over the GPIO lines the capsule may drive, without the rest of the controller */
+    gpio::attach(capid, capsule::get_gpio_lines(capid)?)?;
+
+    /* start the capsule's wall-clock time from its manifest if the host can't tell it the time */
+    if let Some(seconds) = capsule::get_rtc_epoch(capid)?
+    {
+        clock::set_epoch(capid, seconds)?;
+    }
+
+    /* create the virtual devices declared in the capsule's manifest, those at fixed addresses and interrupts first
+    and in a consistent order so the others are placed the same way each time, then any other emulated devices,
+    loading their models from the DMFS image as needed. every capsule gets a real-time clock */
+    let mut devices = capsule::get_devices(capid)?;
+    if devices.iter().any(|spec| spec.kind == DeviceKind::Rtc) == false
+    {
+        devices.push(DeviceSpec::new(DeviceKind::Rtc));
+    }
+    devices.sort_by(|a, b| (a.addr.is_none(), a.irq.is_none(), a).cmp(&(b.addr.is_none(), b.irq.is_none(), b)));
+    for spec in devices
+    {
+        devmodel::attach_declared(capid, &spec)?;
+    }
+    for model in capsule::get_device_models(capid)?
+    {
+        devmodel::attach(capid, &model)?;
+    }
+
+    /* partition the cache and memory bandwidth if requested. capsules can still run unpartitioned
+    on systems that can't, or no longer can, partition their resources */
+    match capsule::get_qos_shares(capid)?
+    {
+        (0, 0) => (),
+        (cache, bandwidth) => if let Err(_e) = qos::partition(capid, cache, bandwidth)
+        {
+            hvalert!("Can't reserve {}% cache and {}% memory bandwidth for capsule {}: {}", cache, bandwidth, capid, error::report(&_e));
+        }
+    }
+
+    /* estimate the capsule's working set if requested */
+    if let Some(pages) = capsule::get_wss_sample(capid)?
+    {
+        wss::start(capid, pages);
+    }
+
+    Ok(())
+}
+
+/* allocate a new capsule's main physical RAM, zeroed as its manifest asks
+   => capid = capsule the RAM is for
+      size = bytes of RAM to allocate
+   <= the RAM region, or an error code */
+pub fn reserve_ram(capid: capsule::CapsuleID, size: usize) -> Result<physmem::Region, Cause>
+{
+    match physmem::alloc_region_policy(size, capsule::get_zero_policy(capid)?)
+    {
+        Ok(ram) => Ok(ram),
+        Err(e) =>
+        {
+            /* ask running capsules to give back what memory they can */
+            pressure::allocation_failed();
+            Err(e)
+        }
+    }
+}
+
+/* allocate a new capsule's physical RAM, zeroed as its manifest asks, in one region if possible
+   or else split across up to RAM_PIECES_MAX discontiguous regions. without stage-2 page tables
+   the pieces can't be presented to the capsule as one contiguous block, so each is described
+   to it as a separate bank of memory at its physical address
+   => capid = capsule the RAM is for
+      size = total bytes of RAM to allocate
+   <= list of regions, largest first, or an error code */
+fn reserve_scattered_ram(capid: capsule::CapsuleID, size: usize) -> Result<Vec<physmem::Region>, Cause>
+{
+    match physmem::alloc_scattered_policy(size, capsule::get_zero_policy(capid)?, RAM_PIECES_MAX)
+    {
+        Ok(pieces) =>
+        {
+            if pieces.len() > 1
+            {
+                hvdebug!("Capsule {} RAM split across {} regions", capid, pieces.len());
+            }
+            Ok(pieces)
+        },
+        Err(e) =>
+        {
+            /* ask running capsules to give back what memory they can */
+            pressure::allocation_failed();
+            Err(e)
+        }
+    }
+}
+
+/* if a capsule asks for W^X, make its executable's code read-only and the rest of its RAM
+   non-executable, going by the permissions of the executable's loadable segments
+   => cid = capsule being loaded
+      binary = slice containing the executable
+   <= Ok for success, or an error code if the code can't be protected */
+fn protect_code(cid: capsule::CapsuleID, binary: &[u8]) -> Result<(), Cause>
+{
+    let segments = match capsule::has_property(cid, capsule::CapsuleProperty::WXProtect)?
+    {
+        true => loader::code_segments(binary)?,
+        false => Vec::new()
+    };
+
+    match capsule::set_code_segments(cid, &segments)
+    {
+        Ok(()) => Ok(()),
+        Err(e) => Err(hverror!(e, "can't protect {} code segments in capsule {}, at most {} allowed", segments.len(), cid, SEGMENTS_MAX))
+    }
+}
+
+/* make sure an executable won't overwrite the capsule's device tree when it's loaded
+   => cid = capsule being loaded, for reporting problems
+      ram = capsule's main physical RAM region
+      binary = slice containing the executable
+      dtb = base of the area holding the capsule's device tree
+   <= Ok if the executable fits below the device tree, or an error code */
+fn check_image_fits(cid: capsule::CapsuleID, ram: physmem::Region, binary: &[u8], dtb: PhysMemBase) -> Result<(), Cause>
+{
+    let size = loader::image_size(binary)?;
+    match ram.base() + size <= dtb
+    {
+        true => Ok(()),
+        false => Err(hverror!(Cause::DeviceTreeOverlapsImage, "capsule {} image needs {} bytes but its device tree is at 0x{:x}", cid, size, dtb))
+    }
+}
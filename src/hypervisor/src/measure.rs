@@ -0,0 +1,170 @@
+/* diosix capsule measurement
+ *
+ * Each capsule is measured as it's loaded: a SHA-256 digest covering the
+ * name and contents of its executable and the properties it was given in
+ * the manifest. The same image with the same properties always measures
+ * the same, on any host, and any change to either gives a different
+ * measurement. Restarting a capsule with another executable measures it
+ * again.
+ *
+ * Measurements gate the release of sealed secrets, and capsules can
+ * read their own measurement by hypercall. Each capsule's measurement is
+ * also written to the debug log as it's taken, so that secrets can be
+ * sealed to it.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt::Write;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::pcore;
+use super::hcargs::{self, Access};
+use super::sha256::{self, Digest, Sha256};
+
+/* size of a measurement in bytes, shared with the capsules */
+pub use hypercall::sealed::MEASUREMENT_SIZE;
+
+/* distinguishes capsule measurements from any other digest */
+const MEASUREMENT_DOMAIN: &[u8] = b"diosix capsule measurement v1\0";
+
+/* what's known of a capsule's measurement */
+struct Measurement
+{
+    properties: Digest, /* digest of the capsule's manifest properties, kept for when it's remeasured */
+    value: Digest       /* the measurement itself */
+}
+
+lazy_static!
+{
+    static ref MEASUREMENTS: Mutex<HashMap<CapsuleID, Measurement>> = Mutex::new("capsule measurements", HashMap::new());
+}
+
+/* return the ID of the currently running capsule, or an error code */
+fn current_capsule() -> Result<CapsuleID, Cause>
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* add a length-prefixed field to a hash, so that no two sets of fields hash the same */
+fn field(hash: &mut Sha256, bytes: &[u8])
+{
+    hash.update(&(bytes.len() as u64).to_le_bytes());
+    hash.update(bytes);
+}
+
+/* digest a capsule's properties, in the order given in the manifest. call this before
+   the properties are handed over to create the capsule
+   => properties = capsule's properties, or None
+   <= digest of the properties */
+pub fn properties(properties: &Option<Vec<String>>) -> Digest
+{
+    let mut hash = Sha256::new();
+    if let Some(properties) = properties
+    {
+        for property in properties
+        {
+            field(&mut hash, property.as_bytes());
+        }
+    }
+    hash.finish()
+}
+
+/* record a capsule's measurement as its executable is loaded
+   => cid = capsule being loaded
+      properties = digest of its properties
+      name = name of its executable
+      image = contents of its executable */
+pub fn record(cid: CapsuleID, properties: Digest, name: &str, image: &[u8])
+{
+    let mut hash = Sha256::new();
+    hash.update(MEASUREMENT_DOMAIN);
+    field(&mut hash, name.as_bytes());
+    hash.update(&sha256::digest(image));
+    hash.update(&properties);
+    let value = hash.finish();
+
+    hvdebug!("Capsule {} measured as {}", cid, hex(&value));
+    MEASUREMENTS.lock().insert(cid, Measurement { properties, value });
+}
+
+/* measure a capsule again as a new executable is loaded into it, keeping its properties
+   => cid = capsule being restarted
+      name = name of its new executable
+      image = contents of its new executable */
+pub fn remeasure(cid: CapsuleID, name: &str, image: &[u8])
+{
+    let properties = match MEASUREMENTS.lock().get(&cid)
+    {
+        Some(measurement) => measurement.properties,
+        None => return /* never measured, so it can't have been given anything sealed to it */
+    };
+    record(cid, properties, name, image);
+}
+
+/* give a capsule cloned from a template the template's measurement, as it runs the same image with the same properties
+   => template = capsule the clone was copied from
+      clone = the newly cloned capsule */
+pub fn inherit(template: CapsuleID, clone: CapsuleID)
+{
+    let mut measurements = MEASUREMENTS.lock();
+    let (properties, value) = match measurements.get(&template)
+    {
+        Some(measurement) => (measurement.properties, measurement.value),
+        None => return
+    };
+
+    hvdebug!("Capsule {} measured as {}", clone, hex(&value));
+    measurements.insert(clone, Measurement { properties, value });
+}
+
+/* <= the given capsule's measurement, or None if it hasn't been measured */
+pub fn get(cid: CapsuleID) -> Option<Digest>
+{
+    MEASUREMENTS.lock().get(&cid).map(|measurement| measurement.value)
+}
+
+/* copy the currently running capsule's measurement into its memory
+   => buffer = address of a MEASUREMENT_SIZE-byte buffer in the capsule
+   <= Ok for success, or an error code */
+pub fn read(buffer: usize) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+    let value = match get(cid)
+    {
+        Some(value) => value,
+        None => return Err(Cause::SecretNotMeasured)
+    };
+
+    let base = hcargs::buffer(cid, buffer, MEASUREMENT_SIZE, MEASUREMENT_SIZE, Access::Write)?;
+    let target = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, MEASUREMENT_SIZE) };
+    target.copy_from_slice(&value);
+    Ok(())
+}
+
+/* discard a capsule's measurement when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    MEASUREMENTS.lock().remove(&cid);
+}
+
+/* <= a digest written out in hex */
+fn hex(digest: &Digest) -> String
+{
+    let mut text = String::new();
+    for byte in digest.iter()
+    {
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
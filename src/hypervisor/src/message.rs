@@ -8,11 +8,17 @@
 use super::lock::Mutex;
 use alloc::collections::vec_deque::VecDeque;
 use alloc::string::String;
+use alloc::vec::Vec;
 use hashbrown::hash_map::HashMap;
 use super::error::Cause;
 use super::service::{self, ServiceType};
-use super::capsule::CapsuleID;
+use super::capsule::{self, CapsuleID};
 use super::pcore::{PhysicalCoreID, PhysicalCore};
+use super::vcore::VirtualCoreID;
+use super::scheduler;
+use super::warmboot;
+use super::hardware;
+use super::machine;
 
 /* here's how message passing works, depending on the target:
     * To an individual physical core:
@@ -80,7 +86,15 @@ pub enum MessageContent
 {
     HypervisorDebugStr(String),
     CapsuleConsoleStr(String),
-    DisownQueuedVirtualCore
+    DisownQueuedVirtualCore,
+    GangSchedule(CapsuleID), /* run one of this capsule's vcores alongside its siblings, if possible */
+    WarmReset, /* reset this physical core's state during a warm reboot */
+    Wakeup, /* no-op: just get the recipient out of a low-power wait */
+    Unpark, /* the recipient is no longer parked and should look for work */
+    Reenforce(CapsuleID), /* reapply this capsule's memory protection if it's running here */
+    VirtualIPI(CapsuleID, VirtualCoreID), /* deliver an IPI to this capsule's vcore if it's here */
+    Pause(CapsuleID), /* park this paused capsule's vcores that are waiting or running here */
+    SyncInstructions /* code has been written to memory: drop any stale instructions cached here */
 }
 
 #[derive(Clone)]
@@ -117,7 +131,15 @@ impl Message
                         return Err(Cause::CapsuleBadID);
                     }
                 },
-                MessageContent::DisownQueuedVirtualCore => Sender::PhysicalCore(PhysicalCore::get_id())
+                MessageContent::DisownQueuedVirtualCore => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::GangSchedule(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::WarmReset => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::Wakeup => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::Unpark => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::Reenforce(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::VirtualIPI(_, _) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::Pause(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::SyncInstructions => Sender::PhysicalCore(PhysicalCore::get_id())
             },
 
             data
@@ -128,21 +150,32 @@ impl Message
     {
         self.receiver
     }
+
+    pub fn get_sender(&self) -> Sender
+    {
+        self.sender.clone()
+    }
 }
 
 /* send the given message msg, consuming it so it can't be reused or resent */
 pub fn send(msg: Message) -> Result<(), Cause>
 {
+    let _tag = heaptag!();
     let receiver = msg.receiver;
     match receiver
     {
         /* iterate over all physical CPU cores */
         Recipient::Broadcast =>
         {
-            for (_, mailbox) in MAILBOXES.lock().iter_mut()
+            let mut pids = Vec::new();
+            for (&pid, mailbox) in MAILBOXES.lock().iter_mut()
             {
-                mailbox.push_back(msg.clone())
+                mailbox.push_back(msg.clone());
+                pids.push(pid);
             }
+
+            /* interrupt the cores together, and without holding the mailbox lock */
+            hardware::interrupt_pcores(&pids);
         },
 
         /* send to a particular physical CPU core */
@@ -151,6 +184,7 @@ pub fn send(msg: Message) -> Result<(), Cause>
             if let Some(mailbox) = MAILBOXES.lock().get_mut(&pid)
             {
                 mailbox.push_back(msg);
+                hardware::interrupt_pcore(pid);
             }
             else
             {
@@ -167,3 +201,93 @@ pub fn send(msg: Message) -> Result<(), Cause>
 
     Ok(())
 }
+
+/* send the same message to each of the given physical CPU cores, interrupting them together
+   => pids = physical CPU cores to send the message to
+      data = message to send to them
+   <= Ok for success, or an error code if any of the cores can't be sent it */
+pub fn send_to_pcores(pids: &[PhysicalCoreID], data: MessageContent) -> Result<(), Cause>
+{
+    let _tag = heaptag!();
+    let mut sent = Vec::new();
+    let mut result = Ok(());
+
+    {
+        let mut mailboxes = MAILBOXES.lock();
+        for &pid in pids
+        {
+            let msg = Message::new(Recipient::send_to_pcore(pid), data.clone())?;
+            match mailboxes.get_mut(&pid)
+            {
+                Some(mailbox) =>
+                {
+                    mailbox.push_back(msg);
+                    sent.push(pid);
+                },
+                None => result = Err(Cause::PhysicalCoreBadID)
+            }
+        }
+    }
+
+    hardware::interrupt_pcores(&sent);
+    result
+}
+
+/* empty this physical CPU core's mailbox, acting on each message in turn.
+   call this when the core is interrupted by another to check its mailbox */
+pub fn process_mailbox()
+{
+    loop
+    {
+        /* don't hold the mailbox lock while acting on the message */
+        let msg = match MAILBOXES.lock().get_mut(&PhysicalCore::get_id())
+        {
+            Some(mailbox) => mailbox.pop_front(),
+            None => None
+        };
+
+        match msg
+        {
+            Some(m) => match m.data
+            {
+                /* give up a waiting virtual core so that another physical core can run it */
+                MessageContent::DisownQueuedVirtualCore => if let Some(vcore) = PhysicalCore::dequeue()
+                {
+                    scheduler::queue(vcore);
+                    scheduler::release_vcore();
+                },
+
+                /* co-schedule a vcore of a gang-scheduled capsule */
+                MessageContent::GangSchedule(cid) => scheduler::gang_join(cid),
+
+                /* the boot core is about to recreate the capsules during a warm reboot */
+                MessageContent::WarmReset => warmboot::reset_this_core(),
+
+                /* the interrupt alone was enough */
+                MessageContent::Wakeup => (),
+
+                /* the boot core has more work for this core */
+                MessageContent::Unpark => scheduler::unparked(),
+
+                /* the memory the capsule running here can reach has changed */
+                MessageContent::Reenforce(cid) => if PhysicalCore::get_capsule_id() == Some(cid)
+                {
+                    capsule::enforce(cid);
+                },
+
+                /* a vcore that last ran here has been sent an IPI: switch it in if it's waiting.
+                   the interrupt is raised on the way back to the vcore */
+                MessageContent::VirtualIPI(cid, vid) => scheduler::wake_vcore(cid, vid),
+
+                /* a capsule has been paused: none of its vcores here should run until it's resumed */
+                MessageContent::Pause(cid) => scheduler::park_capsule(cid),
+
+                /* another core has loaded code this core may run, such as a device model plugin */
+                MessageContent::SyncInstructions => machine::sync_instructions(),
+
+                _ => ()
+            },
+            None => break
+        }
+    }
+}
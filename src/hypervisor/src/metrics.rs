@@ -0,0 +1,124 @@
+/* diosix per-capsule activity counters
+ *
+ * Count events of interest for each capsule, such as the timers it
+ * arms and how many of those the hypervisor had to rate limit, so
+ * that a management service can see which capsules are costing the
+ * host the most. This includes the time each capsule's vcores spend
+ * running, and the time the hypervisor spends working on their behalf,
+ * which are kept by accounting.rs. Counters are discarded when a capsule
+ * is destroyed.
+ *
+ * A few system-wide counters, such as the physical memory leaked when
+ * capsules are torn down, are kept for the lifetime of the hypervisor
+ * so that problems that build up over time can be spotted.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::pcore;
+
+/* things that can be counted, and how many, are shared with the services */
+pub use hypercall::metrics::{Counter, COUNTERS, SystemCounter, SYSTEM_COUNTERS};
+
+lazy_static!
+{
+    static ref COUNTS: Mutex<HashMap<CapsuleID, [u64; COUNTERS]>> = Mutex::new("capsule metrics", HashMap::new());
+    static ref SYSTEM_COUNTS: Mutex<[u64; SYSTEM_COUNTERS]> = Mutex::new("system metrics", [0; SYSTEM_COUNTERS]);
+}
+
+/* add one to the given capsule's counter */
+pub fn count(cid: CapsuleID, counter: Counter)
+{
+    let mut counts = COUNTS.lock();
+    let entry = counts.entry(cid).or_insert([0; COUNTERS]);
+    entry[counter as usize] = entry[counter as usize].wrapping_add(1);
+}
+
+/* set one of the given capsule's counters to a measured value */
+pub fn set(cid: CapsuleID, counter: Counter, value: u64)
+{
+    let mut counts = COUNTS.lock();
+    let entry = counts.entry(cid).or_insert([0; COUNTERS]);
+    entry[counter as usize] = value;
+}
+
+/* read one of a capsule's counters on behalf of the currently running capsule.
+   capsules can read their own counters. reading another's requires manage_capsules
+   => target = capsule to read, or usize::MAX for the running capsule
+      counter = counter to read
+   <= value of the counter, or an error code */
+pub fn read(target: CapsuleID, counter: Counter) -> Result<u64, Cause>
+{
+    let caller = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let target = match target
+    {
+        usize::MAX => caller,
+        cid if cid == caller => cid,
+        cid =>
+        {
+            capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+            cid
+        }
+    };
+
+    if capsule::get_state(target).is_none()
+    {
+        return Err(Cause::CapsuleBadID);
+    }
+
+    Ok(match COUNTS.lock().get(&target)
+    {
+        Some(counts) => counts[counter as usize],
+        None => 0
+    })
+}
+
+/* return a copy of all of a capsule's counters, for the hypervisor's own use
+   => cid = capsule to read
+   <= its counters, indexed by Counter, or None if it has none */
+pub fn snapshot(cid: CapsuleID) -> Option<[u64; COUNTERS]>
+{
+    COUNTS.lock().get(&cid).copied()
+}
+
+/* account for a physical memory region that couldn't be returned when its capsule was torn down
+   => bytes = size of the leaked region */
+pub fn count_leak(bytes: usize)
+{
+    let mut counts = SYSTEM_COUNTS.lock();
+    counts[SystemCounter::LeakedRegions as usize] = counts[SystemCounter::LeakedRegions as usize].wrapping_add(1);
+    counts[SystemCounter::LeakedBytes as usize] = counts[SystemCounter::LeakedBytes as usize].wrapping_add(bytes as u64);
+}
+
+/* read one of the system-wide counters on behalf of the currently running capsule.
+   *** the currently running capsule must have the manage_capsules property ***
+   => counter = counter to read
+   <= value of the counter, or an error code */
+pub fn read_system(counter: SystemCounter) -> Result<u64, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    Ok(SYSTEM_COUNTS.lock()[counter as usize])
+}
+
+/* <= the system-wide counters, indexed by SystemCounter, for the hypervisor's own reports */
+pub fn system_snapshot() -> [u64; SYSTEM_COUNTERS]
+{
+    *SYSTEM_COUNTS.lock()
+}
+
+/* discard a capsule's counters when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    COUNTS.lock().remove(&cid);
+}
@@ -0,0 +1,178 @@
+/* diosix direct MMIO windows for trusted capsules
+ *
+ * While bringing up a driver in a capsule, it's quicker to let the
+ * capsule reach a peripheral's registers directly than to describe the
+ * peripheral for passthrough. A capsule granted the mmio_map property
+ * can ask, by hypercall, for a page-aligned range of physical addresses
+ * to be opened up in its protection windows for reading and writing,
+ * and can close it again when it's done.
+ *
+ * This bypasses the device tree: the capsule must already know where
+ * the peripheral is, and its interrupts aren't routed. It's meant for
+ * trusted services, so guests can't be given the property. Even so, a
+ * range can't cover RAM, the persistent store, a peripheral the
+ * hypervisor uses itself, a device passed through to a capsule, or a
+ * window another capsule has mapped. Each window takes a protection
+ * window, so a capsule can only have a few mapped at once.
+ *
+ * Windows are tracked by the hypervisor and closed when the capsule is
+ * restarted or destroyed, so that a new boot image starts without them.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::hardware;
+use super::passthrough;
+use super::machine;
+
+/* alignment of mapped ranges, shared with the capsules */
+pub use hypercall::mmio::MMIO_ALIGNMENT;
+
+/* most windows a capsule can have mapped at any one time. each takes a protection window */
+const WINDOWS_MAX: usize = 2;
+
+lazy_static!
+{
+    /* the physical MMIO ranges each capsule has mapped */
+    static ref WINDOWS: Mutex<HashMap<CapsuleID, Vec<(PhysMemBase, PhysMemSize)>>> = Mutex::new("capsule MMIO windows", HashMap::new());
+}
+
+/* <= true if the two ranges share any bytes */
+fn overlaps(base: PhysMemBase, size: PhysMemSize, other_base: PhysMemBase, other_size: PhysMemSize) -> bool
+{
+    base < other_base + other_size && other_base < base + size
+}
+
+/* check a range of physical addresses can be handed to a capsule
+   => base, size = range to check
+   <= Ok if it's free to map, or an error code */
+fn check_range(base: PhysMemBase, size: PhysMemSize) -> Result<(), Cause>
+{
+    if size == 0 || base % MMIO_ALIGNMENT != 0 || size % MMIO_ALIGNMENT != 0 || base.checked_add(size).is_none()
+    {
+        return Err(Cause::MMIOBadRange);
+    }
+
+    /* RAM and the persistent store are never MMIO */
+    if let Some(banks) = hardware::get_phys_ram_chunks()
+    {
+        if banks.iter().any(|bank| overlaps(base, size, bank.base, bank.size))
+        {
+            return Err(Cause::MMIOBadRange);
+        }
+    }
+
+    if let Some(area) = hardware::get_pstore_area()
+    {
+        if overlaps(base, size, area.base, area.size)
+        {
+            return Err(Cause::MMIOBadRange);
+        }
+    }
+
+    /* and peripherals already in use can't be shared */
+    if hardware::get_peripherals().iter()
+        .any(|peripheral| peripheral.hypervisor == true && overlaps(base, size, peripheral.base, peripheral.size))
+    {
+        return Err(Cause::MMIOInUse);
+    }
+
+    if passthrough::claimed(base, size) == true
+    {
+        return Err(Cause::MMIOInUse);
+    }
+
+    Ok(())
+}
+
+/* open up a range of physical MMIO space to the currently running capsule
+   *** the currently running capsule must have the mmio_map property ***
+   => base, size = MMIO_ALIGNMENT-aligned physical address and size in bytes of the range
+   <= Ok for success, or an error code */
+pub fn map(base: PhysMemBase, size: PhysMemSize) -> Result<(), Cause>
+{
+    let cid = capsule::get_capsule_id_if_property(CapsuleProperty::MapMMIO)?;
+    check_range(base, size)?;
+
+    {
+        let mut windows = WINDOWS.lock();
+        if windows.values().flatten().any(|(b, s)| overlaps(base, size, *b, *s))
+        {
+            return Err(Cause::MMIOInUse);
+        }
+
+        let list = windows.entry(cid).or_insert(Vec::new());
+        if list.len() >= WINDOWS_MAX
+        {
+            return Err(Cause::MMIOTooMany);
+        }
+        list.push((base, size));
+    }
+
+    hvdebug!("Capsule {} mapped MMIO 0x{:x} - 0x{:x}", cid, base, base + size);
+    capsule::reenforce(cid);
+    Ok(())
+}
+
+/* close a range of physical MMIO space the currently running capsule mapped
+   *** the currently running capsule must have the mmio_map property ***
+   => base = physical address of the range, as mapped
+   <= Ok for success, or an error code */
+pub fn unmap(base: PhysMemBase) -> Result<(), Cause>
+{
+    let cid = capsule::get_capsule_id_if_property(CapsuleProperty::MapMMIO)?;
+
+    match WINDOWS.lock().get_mut(&cid)
+    {
+        Some(list) => match list.iter().position(|(b, _)| *b == base)
+        {
+            Some(index) =>
+            {
+                list.remove(index);
+            },
+            None => return Err(Cause::MMIONotMapped)
+        },
+        None => return Err(Cause::MMIONotMapped)
+    }
+
+    capsule::reenforce(cid);
+    Ok(())
+}
+
+/* open up the MMIO ranges mapped by a capsule. call this when switching to the capsule
+   => cid = capsule about to run
+      window = first protection window free to use
+   <= next protection window free to use */
+pub fn enforce(cid: CapsuleID, mut window: usize) -> usize
+{
+    if let Some(list) = WINDOWS.lock().get(&cid)
+    {
+        for (base, size) in list
+        {
+            machine::protect_window(window, *base, *base + *size, AccessPermissions::ReadWrite);
+            window = window + 1;
+        }
+    }
+
+    /* close any windows left open by the previous capsule */
+    machine::clear_windows_from(window);
+    window
+}
+
+/* close all of a capsule's MMIO windows when it's restarted or destroyed.
+   this is called with the capsule table locked, so the caller must pass the
+   capsule to capsule::reenforce() once it has released that lock if this returns true
+   => cid = capsule being destroyed or restarted
+   <= true if the capsule had MMIO windows to close */
+pub fn forget(cid: CapsuleID) -> bool
+{
+    WINDOWS.lock().remove(&cid).is_some()
+}
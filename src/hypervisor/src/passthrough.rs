@@ -0,0 +1,414 @@
+/* diosix physical peripheral passthrough to capsules
+ *
+ * Hand a physical peripheral, such as a UART that isn't the
+ * hypervisor's debug port, exclusively to a capsule. The device's
+ * MMIO space is opened up to the capsule when it is scheduled,
+ * and the device's interrupt is routed to the capsule as a
+ * virtual IRQ. The hypervisor stops using the device once it
+ * has been claimed.
+ *
+ * Devices capable of DMA that sit behind an IOMMU are confined
+ * so that they can only reach their capsule's memory. Their DMA
+ * is blocked until the capsule is given memory, and blocked again
+ * when the capsule is destroyed. Capsules with devices that aren't
+ * behind an IOMMU can bounce their DMA through hypervisor-allocated
+ * memory instead: see bounce.rs.
+ *
+ * Device interrupts, like the virtual interrupts the hypervisor raises,
+ * are held by the hypervisor until the capsule claims them by hypercall.
+ * A held device interrupt is masked, so that a level-triggered device
+ * doesn't keep interrupting the hypervisor, until the capsule has
+ * serviced the device and completes the interrupt by hypercall.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use hashbrown::hash_map::Entry::{Occupied, Vacant};
+use hashbrown::hash_set::HashSet;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use alloc::string::String;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::hardware;
+use super::pcore;
+use super::machine;
+
+/* platform-assigned interrupt number of a physical device */
+pub type DeviceIRQ = usize;
+
+/* types of peripheral that can be passed through */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceType
+{
+    SerialPort
+}
+
+/* describe a physical device assigned to a capsule */
+#[derive(Clone, Debug)]
+pub struct Device
+{
+    dtype: DeviceType,
+    base: PhysMemBase,
+    size: PhysMemSize,
+    irq: Option<DeviceIRQ>,
+    iommu: Option<usize>,   /* IOMMU device ID, if the device's DMA can be confined */
+    compatible: String,     /* the host's compatible strings for the device, each followed by a zero byte */
+    reg_shift: Option<u32>, /* register layout and input clock, for serial ports that need them described */
+    reg_io_width: Option<u32>,
+    clock: Option<u32>
+}
+
+impl Device
+{
+    pub fn get_type(&self) -> DeviceType { self.dtype }
+    pub fn compatible(&self) -> &String { &self.compatible }
+    pub fn base(&self) -> PhysMemBase { self.base }
+    pub fn size(&self) -> PhysMemSize { self.size }
+    pub fn irq(&self) -> Option<DeviceIRQ> { self.irq }
+    pub fn iommu(&self) -> Option<usize> { self.iommu }
+    pub fn reg_shift(&self) -> Option<u32> { self.reg_shift }
+    pub fn reg_io_width(&self) -> Option<u32> { self.reg_io_width }
+    pub fn clock(&self) -> Option<u32> { self.clock }
+}
+
+lazy_static!
+{
+    /* devices assigned to each capsule */
+    static ref ASSIGNED: Mutex<HashMap<CapsuleID, Vec<Device>>> = Mutex::new("passthrough device table", HashMap::new());
+
+    /* map physical interrupts to the capsules that own their devices */
+    static ref IRQ_ROUTES: Mutex<HashMap<DeviceIRQ, CapsuleID>> = Mutex::new("passthrough IRQ routes", HashMap::new());
+
+    /* interrupts raised by devices but not yet claimed by their capsules */
+    static ref PENDING: Mutex<HashMap<CapsuleID, VecDeque<DeviceIRQ>>> = Mutex::new("passthrough pending IRQs", HashMap::new());
+
+    /* device interrupts masked until their capsules complete them */
+    static ref MASKED: Mutex<HashSet<DeviceIRQ>> = Mutex::new("passthrough masked IRQs", HashSet::new());
+}
+
+/* take the given serial port away from the hypervisor and give it to a capsule.
+   the hypervisor's own debug port can't be handed over
+   => cid = capsule to receive the serial port
+      index = platform-defined index of the serial port, counting from zero
+   <= Ok for success, or an error code */
+pub fn assign_serial_port(cid: CapsuleID, index: usize) -> Result<(), Cause>
+{
+    let port = match hardware::get_serial_port(index)
+    {
+        Some(port) => port,
+        None => return Err(hverror!(Cause::PassthroughDeviceNotFound, "no serial port {} for capsule {}", index, cid))
+    };
+
+    /* check the port's interrupt is free before claiming the port, so that a port
+       that can't be assigned is left for someone else. hold the routing table's
+       lock until the port is claimed so the interrupt can't be taken meanwhile */
+    let mut routes = IRQ_ROUTES.lock();
+    if let Some(irq) = port.irq
+    {
+        if let Some(owner) = routes.get(&irq)
+        {
+            return Err(hverror!(Cause::PassthroughIRQInUse, "IRQ {} already routed to capsule {}", irq, owner));
+        }
+    }
+
+    if hardware::claim_serial_port(index) == false
+    {
+        return Err(hverror!(Cause::PassthroughDeviceNotFound, "serial port {} for capsule {} already claimed", index, cid));
+    }
+
+    /* route the device's interrupt, if it has one, to the capsule */
+    if let Some(irq) = port.irq
+    {
+        routes.insert(irq, cid);
+        if let Err(_e) = hardware::enable_external_irq(irq)
+        {
+            hvalert!("Can't enable IRQ {} of serial port {} for capsule {}: {:?}", irq, index, cid, _e);
+        }
+    }
+    drop(routes);
+
+    let device = Device
+    {
+        dtype: DeviceType::SerialPort,
+        base: port.base,
+        size: port.size,
+        irq: port.irq,
+        iommu: hardware::get_iommu_device_id(port.base),
+        compatible: port.compatible,
+        reg_shift: port.reg_shift,
+        reg_io_width: port.reg_io_width,
+        clock: port.clock
+    };
+
+    /* don't let the device reach any memory until the capsule has some */
+    if let Some(id) = device.iommu
+    {
+        hardware::iommu_block(id);
+    }
+
+    hvdebug!("Passed serial port {} (0x{:x}, {} bytes, IRQ {:?}) through to capsule {}", index, device.base, device.size, device.irq, cid);
+
+    match ASSIGNED.lock().entry(cid)
+    {
+        Occupied(mut list) => list.get_mut().push(device),
+        Vacant(v) =>
+        {
+            let mut list = Vec::new();
+            list.push(device);
+            v.insert(list);
+        }
+    }
+    Ok(())
+}
+
+/* confine the DMA of a capsule's devices to the capsule's memory. call this whenever
+   the capsule's memory changes
+   => cid = capsule whose devices are to be confined
+      ranges = list of (base address, size in bytes) of the capsule's physical memory
+   <= Ok for success, or an error code */
+pub fn confine_dma(cid: CapsuleID, ranges: &Vec<(PhysMemBase, PhysMemSize)>) -> Result<(), Cause>
+{
+    if let Some(list) = ASSIGNED.lock().get(&cid)
+    {
+        for device in list
+        {
+            if let Some(id) = device.iommu
+            {
+                hardware::iommu_confine(id, ranges)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/* return a copy of the list of devices assigned to the given capsule */
+pub fn get_devices(cid: CapsuleID) -> Vec<Device>
+{
+    match ASSIGNED.lock().get(&cid)
+    {
+        Some(list) => list.clone(),
+        None => Vec::new()
+    }
+}
+
+/* return the capsule a physical device has been assigned to, if any
+   => base = the device's physical base address
+   <= ID of the capsule that owns it, or None if it isn't assigned */
+pub fn owner(base: PhysMemBase) -> Option<CapsuleID>
+{
+    for (cid, list) in ASSIGNED.lock().iter()
+    {
+        if list.iter().any(|device| device.base == base)
+        {
+            return Some(*cid);
+        }
+    }
+    None
+}
+
+/* <= true if any part of the given physical address range is a device assigned to a capsule */
+pub fn claimed(base: PhysMemBase, size: PhysMemSize) -> bool
+{
+    ASSIGNED.lock().values().flatten().any(|device| base < device.base + device.size && device.base < base + size)
+}
+
+/* grant the given capsule access to the MMIO spaces of its devices.
+   call this when switching to the capsule, after its RAM has been granted
+   => cid = capsule to enforce
+      window = first free protection window to use for the devices
+   <= next free protection window */
+pub fn enforce(cid: CapsuleID, mut window: usize) -> usize
+{
+    if let Some(list) = ASSIGNED.lock().get(&cid)
+    {
+        for device in list
+        {
+            machine::protect_window(window, device.base, device.base + device.size, AccessPermissions::ReadWrite);
+            window = window + 1;
+        }
+    }
+
+    /* close any windows left open by the previous capsule */
+    machine::clear_windows_from(window);
+    window
+}
+
+/* release all devices held by a capsule when it is destroyed. the devices
+   are not returned to the hypervisor: they remain off-limits until reboot */
+pub fn release(cid: CapsuleID)
+{
+    /* stop the devices from writing into memory that's about to be reused */
+    if let Some(list) = ASSIGNED.lock().remove(&cid)
+    {
+        for device in list
+        {
+            if let Some(id) = device.iommu
+            {
+                hardware::iommu_block(id);
+            }
+        }
+    }
+
+    PENDING.lock().remove(&cid);
+
+    let mut released = Vec::new();
+    IRQ_ROUTES.lock().retain(|irq, owner| match *owner == cid
+    {
+        true =>
+        {
+            released.push(*irq);
+            false
+        },
+        false => true
+    });
+
+    /* interrupts the capsule never completed stay masked, as their devices are off-limits */
+    MASKED.lock().retain(|irq| released.contains(irq) == false);
+}
+
+/* drop the device interrupts waiting for a capsule, and complete any it claimed but didn't complete,
+   when it's restarted. its devices may interrupt again for the restarted guest to handle
+   => cid = capsule being restarted */
+pub fn reset_irqs(cid: CapsuleID)
+{
+    let irqs: Vec<DeviceIRQ> = IRQ_ROUTES.lock().iter().filter(|(_, owner)| **owner == cid).map(|(irq, _)| *irq).collect();
+    if let Some(queue) = PENDING.lock().get_mut(&cid)
+    {
+        queue.retain(|irq| irqs.contains(irq) == false);
+    }
+
+    for irq in irqs
+    {
+        if MASKED.lock().remove(&irq) == true
+        {
+            hardware::unmask_external_irq(irq);
+            hardware::complete_external_irq(irq);
+        }
+    }
+}
+
+/* handle a physical external interrupt. if it belongs to a passed-through device, mask it
+   and queue it for the owning capsule. call this from the hypervisor's IRQ handler */
+pub fn route_external_irq()
+{
+    let irq = match hardware::claim_external_irq()
+    {
+        Some(irq) => irq,
+        None => return
+    };
+
+    match IRQ_ROUTES.lock().get(&irq)
+    {
+        Some(&cid) =>
+        {
+            /* the device will keep its interrupt raised until the capsule services it */
+            hardware::mask_external_irq(irq);
+            MASKED.lock().insert(irq);
+
+            let mut pending = PENDING.lock();
+            match pending.entry(cid)
+            {
+                Occupied(mut queue) => if queue.get().contains(&irq) == false
+                {
+                    queue.get_mut().push_back(irq);
+                },
+                Vacant(v) =>
+                {
+                    let mut queue = VecDeque::new();
+                    queue.push_back(irq);
+                    v.insert(queue);
+                }
+            }
+        },
+        None =>
+        {
+            hvdebug!("Unrouted external interrupt {}", irq);
+            hardware::complete_external_irq(irq);
+        }
+    }
+}
+
+/* complete a device interrupt the currently running capsule has claimed and serviced,
+   so that its device can interrupt again
+   => irq = interrupt number claimed by the capsule
+   <= Ok for success, or an error code if the capsule doesn't own the interrupt */
+pub fn complete_irq(irq: DeviceIRQ) -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    if IRQ_ROUTES.lock().get(&irq) != Some(&cid)
+    {
+        return Err(Cause::PassthroughIRQNotOwned);
+    }
+
+    /* unmask before completing, as the PLIC ignores completions of disabled interrupts.
+       the interrupt can't be raised again until it's completed. completing an interrupt
+       that isn't masked, such as one completed already, does nothing */
+    if MASKED.lock().remove(&irq) == true
+    {
+        hardware::unmask_external_irq(irq);
+        hardware::complete_external_irq(irq);
+    }
+    Ok(())
+}
+
+/* queue an interrupt generated by the hypervisor, rather than a physical device, for a capsule.
+   the capsule claims it in the same way as a device interrupt
+   => cid = capsule to interrupt
+      irq = virtual interrupt number, which must not clash with physical interrupt numbers */
+pub fn raise_virtual_irq(cid: CapsuleID, irq: DeviceIRQ)
+{
+    let mut pending = PENDING.lock();
+    let queue = pending.entry(cid).or_insert(VecDeque::new());
+    if queue.contains(&irq) == false
+    {
+        queue.push_back(irq);
+    }
+}
+
+/* if the capsule running on this physical core has a device interrupt waiting,
+   raise a supervisor-level external interrupt so the capsule can claim it */
+pub fn check_pending_irq()
+{
+    if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+    {
+        if let Some(queue) = PENDING.lock().get(&cid)
+        {
+            if queue.len() > 0
+            {
+                machine::trigger_supervisor_external_irq();
+            }
+        }
+    }
+}
+
+/* claim the next interrupt pending for the currently running capsule
+   <= interrupt number, or None if nothing is pending */
+pub fn claim_pending_irq() -> Option<DeviceIRQ>
+{
+    let cid = pcore::PhysicalCore::get_capsule_id()?;
+    match PENDING.lock().get_mut(&cid)
+    {
+        Some(queue) =>
+        {
+            let irq = queue.pop_front();
+            if queue.len() == 0
+            {
+                machine::clear_supervisor_external_irq();
+            }
+            irq
+        },
+        None => None
+    }
+}
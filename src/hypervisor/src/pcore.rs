@@ -13,23 +13,56 @@ its own heap, reusing any blocks freed by itself or other cores.
 The hypervisor layer is unlikely to do much active allocation
 so it's OK to keep it really simple for now. */
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use super::lock::Mutex;
 use hashbrown::hash_map::HashMap;
 use platform::physmem::PhysMemSize;
 use platform::cpu::{SupervisorState, CPUFeatures};
 use platform::timer;
-use super::vcore::{VirtualCore, VirtualCoreCanonicalID};
-use super::scheduler::ScheduleQueues;
+use super::vcore::{VirtualCore, VirtualCoreCanonicalID, VirtualCoreID, TimerID, Priority};
+use super::error::{Cause, ErrorContext};
+use super::hardware;
+use super::machine;
+use super::scheduler;
+use super::schedpolicy::Policy;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use super::capsule::{self, CapsuleID};
 use super::message;
 use super::heap;
+use super::timerwheel::TimerWheel;
+use super::workqueue::WorkQueue;
+use super::boottime;
+use super::throttle;
+use super::vipi;
+use super::integrity::Damage;
+#[cfg(feature = "integritychecks")]
+use super::integrity;
 
 /* physical CPU core IDs and count */
 pub type PhysicalCoreID = usize;
 pub type PhysicalCoreCount = PhysicalCoreID;
 
+/* hardware-assigned ID of a physical CPU core, such as a RISC-V hart ID. these can have gaps,
+   eg: on multi-socket systems, so they're only used when talking to the platform code */
+pub type HartID = usize;
+
 pub const BOOT_PCORE_ID: PhysicalCoreID = 0;
+
+/* next linear physical CPU core ID to hand out */
+static NEXT_PCORE_ID: AtomicUsize = AtomicUsize::new(BOOT_PCORE_ID);
 const PCORE_MAGIC: usize = 0xc001c0de;
+const PCORE_STACK_CANARY: usize = 0x57ac0de5;
+
+/* systems may mix fast, power-hungry cores with slower, frugal ones. cores are classed by their relative
+   performance in the device tree: those matching the fastest are performance cores, and the rest are
+   efficiency cores. on systems that don't describe their cores' performance, every core is a performance core */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CoreClass
+{
+    Performance,
+    Efficiency
+}
 
 /* require some help from the underlying platform */
 extern "C"
@@ -51,6 +84,9 @@ lazy_static!
     CPU core's scheduling queue. */
     static ref VCORES: Mutex<HashMap<PhysicalCoreID, VirtualCore>> = Mutex::new("physical-virtual core table", HashMap::new());
     static ref PCORES: Mutex<HashMap<VirtualCoreCanonicalID, PhysicalCoreID>> = Mutex::new("physical-virtual core ID table", HashMap::new());
+
+    /* map the linear IDs of physical CPU cores that have started to their hardware-assigned IDs */
+    static ref HARTS: Mutex<HashMap<PhysicalCoreID, HartID>> = Mutex::new("physical core hart ID table", HashMap::new());
 }
 
 /* describe a physical CPU core - this structure is stored in the per-CPU private variable space.
@@ -63,9 +99,10 @@ pub struct PhysicalCore
     magic: usize,
 
     /* every physical CPU core has a hardware-assigned ID number that may be non-linear,
-    while the startup code assigns each core a linear ID number from zero. we keep a copy of that
-    linear runtime-assigned ID here. the hardware-assigned ID is not used in the portable code */
+    while the hypervisor assigns each core a linear ID number from zero as it starts. we keep a copy
+    of both here. the hardware-assigned ID is only passed to the platform code */
     id: PhysicalCoreID,
+    hart: HartID,
 
     /* platform-defined bitmask of ISA features this core provides. if a virtual core has a features bit set that
     is unset in a physical core's feature bitmask, the virtual core will not be allowed to run on that physical core */
@@ -74,61 +111,115 @@ pub struct PhysicalCore
     /* each physical CPU core gets its own heap that it can share, but it must manage its own */
     pub heap: heap::Heap,
 
-    /* each physical CPU gets its own set of queues of virtual CPU cores to schedule */
-    queues: ScheduleQueues,
+    /* each physical CPU gets its own set of queues of virtual CPU cores to schedule, created
+    when it starts scheduling so that they use the scheduling policy chosen at boot */
+    queues: Option<Box<dyn Policy<VirtualCore>>>,
+
+    /* ...and its own wheel of hypervisor-internal events to run in future */
+    wheel: TimerWheel,
+
+    /* ...and its own queue of long-running jobs to carry out a step at a time */
+    work: WorkQueue,
 
     /* can this run guest operating systems? or is it a system management core? true if it can run
     supervisor-mode code, false if not */
     smode: bool,
 
+    /* whether this is one of the system's fastest cores or a more power-efficient one */
+    class: CoreClass,
+
+    /* true if supervisor code can program this core's timer IRQs directly, without trapping into
+    the hypervisor, using the supervisor timer compare register (RISC-V's Sstc extension) */
+    sstc: bool,
+
     /* set when this physical core CPU core last ran a scheduling decision */
     timer_sched_last: Option<timer::TimerValue>,
 
     /* set to true when the vcore running on this physical core is doomed.
        that means it's in a capsule that was restarted or killed and
        must not be saved after a context switch */
-    vcore_doomed: bool
+    vcore_doomed: bool,
+
+    /* set to true when the vcore running on this physical core belongs to a paused
+       capsule. it must be saved and parked, rather than queued, after a context switch */
+    vcore_parked: bool,
+
+    /* set to the timer value at which the vcore running on this physical core can run again
+       when it's been throttled for trapping too often. it must be saved and held out of the
+       queues until then, after a context switch */
+    vcore_throttled: Option<u64>,
+
+    /* details of the last error raised on this physical core, until it's reported */
+    error: Option<ErrorContext>,
+
+    /* the per-CPU stack grows down towards this structure, so an overflowing stack
+    overwrites this last word first, before damaging anything else in here */
+    stack_canary: usize
 }
 
 impl PhysicalCore
 {
     /* intiialize a physical CPU core. Prepare it for running supervisor code.
-    => id = diosix-assigned CPU core ID at boot time. this is separate from the hardware-assigned
-            ID number, which may be non-linear. the runtime-generated core ID will
-            run from zero to N-1 where N is the number of available cores */
-    pub fn init(id: PhysicalCoreID)
+    => id = diosix-assigned CPU core ID at boot time, from assign_id(). this is separate from
+            the hardware-assigned ID number, which may be non-linear. the runtime-generated
+            core ID will run from zero to N-1 where N is the number of available cores
+       hart = hardware-assigned ID number of this core */
+    pub fn init(id: PhysicalCoreID, hart: HartID)
     {
         /* the pre-hvmain startup code has allocated space for per-CPU core variables.
         this function returns a pointer to that structure */
         let mut cpu = PhysicalCore::this();
 
         cpu.magic = PCORE_MAGIC;
+        cpu.stack_canary = PCORE_STACK_CANARY;
         cpu.id = id;
+        cpu.hart = hart;
         cpu.features = platform::cpu::features();
         cpu.smode = platform::cpu::features_priv_check(platform::cpu::PrivilegeMode::Supervisor);
+        cpu.sstc = false; /* until the hardware has been discovered */
+        cpu.class = CoreClass::Performance; /* until the hardware has been discovered */
         cpu.timer_sched_last = None;
         cpu.vcore_doomed = false;
+        cpu.vcore_parked = false;
+        cpu.vcore_throttled = None;
+        cpu.error = None;
 
         let (heap_ptr, heap_size) = PhysicalCore::get_heap_config();
         cpu.heap.init(heap_ptr, heap_size);
 
-        cpu.queues = ScheduleQueues::new();
+        /* this structure starts out uninitialized, so don't try to drop whatever was in here before */
+        unsafe { core::ptr::write(&mut cpu.queues, None) };
+        cpu.wheel = TimerWheel::new();
+        unsafe { core::ptr::write(&mut cpu.work, WorkQueue::new()) };
         message::create_mailbox(id);
+        HARTS.lock().insert(id, hart);
     }
 
+    /* return the details of the last error raised on this physical core, if any */
+    pub fn get_error_context(&mut self) -> &mut Option<ErrorContext> { &mut self.error }
+
+    /* return this physical core's wheel of hypervisor-internal events */
+    pub fn get_timer_wheel(&mut self) -> &mut TimerWheel { &mut self.wheel }
+
+    /* return this physical core's queue of deferred jobs */
+    pub fn get_work_queue(&mut self) -> &mut WorkQueue { &mut self.work }
+
     /* return pointer to the calling CPU core's fixed private data structure */
     pub fn this() -> &'static mut PhysicalCore
     {
         unsafe { platform_cpu_private_variables() }
     }
 
-    /* return Ok if magic hasn't been overwritten, or the overwrite value as an error code */
-    pub fn integrity_check() -> Result<(), usize>
+    /* return Ok if neither the stack canary nor the magic word has been overwritten,
+    or a description of the damage, including the overwritten value */
+    pub fn integrity_check() -> Result<(), Damage>
     {
-        match PhysicalCore::this().magic
+        let cpu = PhysicalCore::this();
+        match (cpu.stack_canary, cpu.magic)
         {
-            PCORE_MAGIC => Ok(()),
-            other => Err(other)
+            (PCORE_STACK_CANARY, PCORE_MAGIC) => Ok(()),
+            (PCORE_STACK_CANARY, other) => Err(Damage::PrivateVariables(other)),
+            (other, _) => Err(Damage::PrivateStack(other))
         }
     }
 
@@ -141,22 +232,88 @@ impl PhysicalCore
     /* return boot-assigned ID number */
     pub fn get_id() -> PhysicalCoreID { PhysicalCore::this().id }
 
+    /* return hardware-assigned ID number */
+    pub fn get_hart_id() -> HartID { PhysicalCore::this().hart }
+
+    /* hand this core's supervisor timer IRQs over to its timer compare register if it has one (Sstc).
+       call once the hardware has been discovered */
+    pub fn enable_supervisor_compare()
+    {
+        if hardware::hart_has_extension(PhysicalCore::get_hart_id(), "sstc") == true
+        {
+            machine::enable_supervisor_compare();
+            PhysicalCore::this().sstc = true;
+        }
+    }
+
     /* return features bitmask */
     pub fn get_features() -> CPUFeatures { PhysicalCore::this().features }
 
+    /* class this core by its relative performance, described in the device tree. call once the hardware has been discovered
+       <= this core's class */
+    pub fn classify() -> CoreClass
+    {
+        let class = match hardware::get_cpu_capacity()
+        {
+            Some((capacity, highest)) if capacity < highest => CoreClass::Efficiency,
+            _ => CoreClass::Performance
+        };
+
+        PhysicalCore::this().class = class;
+        class
+    }
+
+    /* return whether this is a performance or efficiency core */
+    pub fn get_class() -> CoreClass { PhysicalCore::this().class }
+
     /* return a structure describing this core */
     pub fn describe() -> platform::cpu::CPUDescription { platform::cpu::CPUDescription }
 
+    /* give this physical CPU core its queues of virtual CPU cores to run, when it starts scheduling */
+    pub fn set_queues(queues: Box<dyn Policy<VirtualCore>>)
+    {
+        PhysicalCore::this().queues = Some(queues);
+    }
+
     /* return a virtual CPU core awaiting to run on this physical CPU core */
     pub fn dequeue() -> Option<VirtualCore>
     {
-        PhysicalCore::this().queues.dequeue()
+        match PhysicalCore::this().queues.as_mut()
+        {
+            Some(queues) => queues.dequeue(),
+            None => None
+        }
+    }
+
+    /* remove a virtual CPU core belonging to the given capsule from this physical CPU's queues, if any */
+    pub fn dequeue_capsule(cid: CapsuleID) -> Option<VirtualCore>
+    {
+        match PhysicalCore::this().queues.as_mut()
+        {
+            Some(queues) => queues.dequeue_capsule(cid),
+            None => None
+        }
+    }
+
+    /* remove the given virtual core from this physical CPU's queue, or None if it isn't waiting here */
+    pub fn dequeue_vcore(cid: CapsuleID, vid: VirtualCoreID) -> Option<VirtualCore>
+    {
+        match PhysicalCore::this().queues.as_mut()
+        {
+            Some(queues) => queues.dequeue_vcore(cid, vid),
+            None => None
+        }
     }
 
-    /* move a virtual CPU core onto this physical CPU's queue of virtual cores to run */
+    /* move a virtual CPU core onto this physical CPU's queue of virtual cores to run.
+    if this core hasn't started scheduling yet, leave it in the global queue for another core */
     pub fn queue(to_queue: VirtualCore)
     {
-        PhysicalCore::this().queues.queue(to_queue)
+        match PhysicalCore::this().queues.as_mut()
+        {
+            Some(queues) => queues.queue(to_queue),
+            None => scheduler::queue(to_queue)
+        }
     }
 
     /* return true if able to run supervisor code. a system management core
@@ -166,6 +323,13 @@ impl PhysicalCore
         PhysicalCore::this().smode
     }
 
+    /* return true if supervisor code can program this core's timer IRQs directly, or false if
+    it must ask the hypervisor to raise them */
+    pub fn sstc_supported() -> bool
+    {
+        PhysicalCore::this().sstc
+    }
+
     /* return ID of capsule of the virtual CPU core this physical CPU core is running, or None for none */
     pub fn get_capsule_id() -> Option<CapsuleID>
     {
@@ -184,8 +348,27 @@ impl PhysicalCore
     the current set of vcores needs to be flushed from the scheduling system */
     pub fn doom_vcore(&mut self) { self.vcore_doomed = true; }
 
-    /* ensure the running vcore is not doomed */
-    pub fn approve_vcore(&mut self) { self.vcore_doomed = false; }
+    /* mark the running vcore as parked, meaning after it's context switched out,
+    save its state and hold it out of the scheduling queues */
+    pub fn park_vcore(&mut self) { self.vcore_parked = true; }
+
+    /* mark the running vcore as throttled until the given timer value, meaning after
+    it's context switched out, save its state and hold it out of the scheduling queues until then */
+    pub fn throttle_vcore(&mut self, release: u64) { self.vcore_throttled = Some(release); }
+
+    /* ensure the running vcore is neither doomed, parked, nor throttled */
+    pub fn approve_vcore(&mut self)
+    {
+        self.vcore_doomed = false;
+        self.vcore_parked = false;
+        self.vcore_throttled = None;
+    }
+
+    /* return true if vcore is to be parked */
+    pub fn is_vcore_parked(&self) -> bool { self.vcore_parked }
+
+    /* return the timer value until which the vcore is to be held, if it's been throttled */
+    pub fn is_vcore_throttled(&self) -> Option<u64> { self.vcore_throttled }
 
     /* return true if vcore is doomed, ie: must be discarded */
     pub fn is_vcore_doomed(&self) -> bool { self.vcore_doomed }
@@ -201,12 +384,128 @@ impl PhysicalCore
         }
     }
 
-    /* get the virtual core's timer IRQ target */
+    /* get the virtual core's timer IRQ target: the earliest of its SBI timer and its armed timers */
     pub fn get_virtualcore_timer_target() -> Option<timer::TimerValue>
     {
         if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
         {
-            return vcore.get_timer_irq_at();
+            return match (vcore.get_timer_irq_at(), vcore.get_next_timer(), hardware::scheduler_get_timer_frequency())
+            {
+                (Some(sbi), Some(next), Some(frequency)) => match sbi.to_exact(frequency) < next
+                {
+                    true => Some(sbi),
+                    false => Some(timer::TimerValue::Exact(next))
+                },
+                (Some(sbi), _, _) => Some(sbi),
+                (None, Some(next), _) => Some(timer::TimerValue::Exact(next)),
+                (None, None, _) => None
+            };
+        }
+        None
+    }
+
+    /* arm an additional timer for the running virtual core
+       => target = when the timer should fire
+       <= ID of the timer, or an error code */
+    pub fn add_virtualcore_timer(target: timer::TimerValue) -> Result<TimerID, Cause>
+    {
+        let frequency = match hardware::scheduler_get_timer_frequency()
+        {
+            Some(f) => f,
+            None => return Err(Cause::SchedNoTimer)
+        };
+
+        match VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            Some(vcore) => vcore.add_timer(target.to_exact(frequency)),
+            None => Err(Cause::VirtualCoreAWOL)
+        }
+    }
+
+    /* disarm one of the running virtual core's timers */
+    pub fn cancel_virtualcore_timer(id: TimerID) -> Result<(), Cause>
+    {
+        match VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            Some(vcore) => vcore.cancel_timer(id),
+            None => Err(Cause::VirtualCoreAWOL)
+        }
+    }
+
+    /* collect the ID of the next of the running virtual core's timers to have fired */
+    pub fn next_virtualcore_fired_timer() -> Option<TimerID>
+    {
+        match VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            Some(vcore) => vcore.next_fired_timer(),
+            None => None
+        }
+    }
+
+    /* raise a timer IRQ for the running virtual core on a core with Sstc, where the IRQ is pending
+       whenever the timer compare register's value has passed, by pulling the register forward.
+       the supervisor's own compare value is held aside to put back once it's collected its timers
+       => now = clock-on-the-wall, in exact timer ticks */
+    pub fn force_supervisor_compare(now: u64)
+    {
+        if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            vcore.hold_timer_compare(now, machine::get_supervisor_compare());
+            machine::set_supervisor_compare(now);
+        }
+    }
+
+    /* put back the running virtual core's own timer compare value held aside by force_supervisor_compare(),
+       unless the supervisor has since reprogrammed the register itself, in which case its new value stands */
+    pub fn restore_supervisor_compare()
+    {
+        if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            if let Some((forced, own)) = vcore.take_timer_compare_held()
+            {
+                if machine::get_supervisor_compare() == forced
+                {
+                    machine::set_supervisor_compare(own);
+                }
+            }
+        }
+    }
+
+    /* forget the running virtual core's own timer compare value held aside by force_supervisor_compare(),
+       as the supervisor's timer is being reprogrammed on its behalf */
+    pub fn forget_supervisor_compare()
+    {
+        if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            vcore.take_timer_compare_held();
+        }
+    }
+
+    /* expire the running virtual core's SBI timer and armed timers that are due
+       => now = clock-on-the-wall, in exact timer ticks
+          frequency = timer ticks per second */
+    pub fn expire_virtualcore_timers(now: u64, frequency: u64)
+    {
+        if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            if let Some(sbi) = vcore.get_timer_irq_at()
+            {
+                if sbi.to_exact(frequency) <= now
+                {
+                    vcore.set_timer_irq_at(None);
+                }
+            }
+            vcore.expire_timers(now);
+        }
+    }
+
+    /* get how much of the running deadline virtual core's budget is left, in timer ticks,
+       or None if the running virtual core isn't a deadline virtual core */
+    pub fn get_virtualcore_deadline_left(now: u64, frequency: u64) -> Option<u64>
+    {
+        if let Some(vcore) = VCORES.lock().get_mut(&(PhysicalCore::get_id()))
+        {
+            return vcore.deadline_budget_left(now, frequency);
         }
         None
     }
@@ -246,12 +545,78 @@ impl PhysicalCore
     }
 }
 
+/* return the ID of the capsule running on the given physical CPU core, or None if it's not running one */
+/* give the calling physical CPU core the next linear ID, in the order cores start up, so that
+   hardware-assigned IDs with gaps don't leave holes. the first core to start becomes the boot core.
+   call this before PhysicalCore::init(), as nothing else can be used until then
+   <= linear ID for the calling core */
+pub fn assign_id() -> PhysicalCoreID
+{
+    NEXT_PCORE_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/* <= hardware-assigned ID of the given physical CPU core, or None if it hasn't started */
+pub fn hart_of(pid: PhysicalCoreID) -> Option<HartID>
+{
+    HARTS.lock().get(&pid).copied()
+}
+
+/* <= linear IDs of the physical CPU cores that have started, in order */
+pub fn started() -> Vec<PhysicalCoreID>
+{
+    let mut ids: Vec<PhysicalCoreID> = HARTS.lock().keys().copied().collect();
+    ids.sort();
+    ids
+}
+
+/* <= the physical CPU core that last ran the given virtual core, and so is running it or has it queued,
+   or None if it hasn't run. this is a hint: the virtual core may have since moved to the global queue */
+pub fn last_pcore_of(cid: CapsuleID, vid: VirtualCoreID) -> Option<PhysicalCoreID>
+{
+    PCORES.lock().get(&VirtualCoreCanonicalID { capsuleid: cid, vcoreid: vid }).copied()
+}
+
+/* <= the capsule and virtual core running on the given physical CPU core, or None if it's not running one */
+pub fn get_running_vcore(pid: PhysicalCoreID) -> Option<(CapsuleID, VirtualCoreID)>
+{
+    VCORES.lock().get(&pid).map(|vcore| (vcore.get_capsule_id(), vcore.get_id()))
+}
+
+/* <= true if the given physical CPU core is running a deadline virtual core, or false if not */
+pub fn is_running_deadline(pid: PhysicalCoreID) -> bool
+{
+    match VCORES.lock().get(&pid)
+    {
+        Some(vcore) => matches!(vcore.get_priority(), Priority::Deadline(_)),
+        None => false
+    }
+}
+
+pub fn get_running_capsule(pid: PhysicalCoreID) -> Option<CapsuleID>
+{
+    match VCORES.lock().get(&pid)
+    {
+        Some(vcore) => Some(vcore.get_capsule_id()),
+        None => None
+    }
+}
+
 /* save current virtual CPU core's context, if we're running one, and load next virtual core's context.
 this should be called from an IRQ context as it preserves the interrupted code's context
 and overwrites the context with the next virtual core's context, so returning to supervisor
 mode will land us in the new context */
-pub fn context_switch(next: VirtualCore)
+pub fn context_switch(mut next: VirtualCore)
 {
+    #[cfg(feature = "integritychecks")]
+    integrity::check("context switch");
+
+    /* charge the outgoing virtual core for its time, and start the clock on the next */
+    let now = match scheduler::timer_now()
+    {
+        Some((now, _)) => Some(now),
+        None => None
+    };
+
     let next_capsule = next.get_capsule_id();
     let pcore_id = PhysicalCore::get_id();
 
@@ -261,6 +626,11 @@ pub fn context_switch(next: VirtualCore)
         Some(mut current_vcore) =>
         {
             let current_capsule = current_vcore.get_capsule_id();
+            hvtrace!(ContextSwitch, "capsule {}.{} -> {}.{}", current_capsule, current_vcore.get_id(), next_capsule, next.get_id());
+            if let Some(now) = now
+            {
+                current_vcore.stop_running(now);
+            }
 
             /* if we're switching to a virtual CPU core in another capsule then replace the
             current hardware access permissions so that we're only allowing access to the RAM assigned
@@ -277,15 +647,41 @@ pub fn context_switch(next: VirtualCore)
                 /* handle core and FP registers separately to keep rust borrow checker happy with current_vcore */
                 platform::cpu::save_supervisor_cpu_state(current_vcore.state_as_mut_ref());
                 platform::cpu::save_supervisor_fp_state(current_vcore.fp_state_as_mut_ref());
-                PhysicalCore::queue(current_vcore);
+                if PhysicalCore::sstc_supported() == true
+                {
+                    current_vcore.set_timer_compare(machine::get_supervisor_compare());
+                }
+
+                /* vcores of paused capsules are held aside, intact, until resumed */
+                if PhysicalCore::this().is_vcore_parked() == true
+                {
+                    capsule::park_vcore(current_vcore);
+                }
+                /* vcores trapping too often sit out a penalty */
+                else if let Some(release) = PhysicalCore::this().is_vcore_throttled()
+                {
+                    throttle::hold(release, current_vcore);
+                }
+                /* vcores running on the wrong class of core go back to the global queue for a better suited core */
+                else if scheduler::should_migrate(&current_vcore) == true
+                {
+                    scheduler::queue(current_vcore);
+                }
+                else
+                {
+                    PhysicalCore::queue(current_vcore);
+                }
             }
             else
             {
                 drop(current_vcore);
+                scheduler::release_vcore();
             }
         },
         None =>
         {
+            hvtrace!(ContextSwitch, "idle -> capsule {}.{}", next_capsule, next.get_id());
+
             /* if we were not running a virtual CPU core then ensure we return to supervisor mode
             rather than hypervisor mode */
             platform::cpu::prep_supervisor_return();
@@ -294,6 +690,28 @@ pub fn context_switch(next: VirtualCore)
         }
     }
 
+    if let Some(now) = now
+    {
+        next.start_running(now);
+    }
+
+    /* account for the capsule's boot time if this is the first time it's run */
+    boottime::scheduled(next_capsule);
+
+    /* the supervisor timer compare register is outside the platform's supervisor state, so
+       switch it separately. a deadline that passed while the vcore was switched out fires on return.
+       the capsule programs the register without the hypervisor's involvement, so apply its minimum
+       timer interval here, unless the hypervisor forced the value itself to raise a timer IRQ */
+    if PhysicalCore::sstc_supported() == true
+    {
+        let compare = match (next.is_timer_compare_held(), next.get_timer_compare())
+        {
+            (false, compare) if compare != u64::MAX => scheduler::limit_timer_compare(next_capsule, compare),
+            (_, compare) => compare
+        };
+        machine::set_supervisor_compare(compare);
+    }
+
     /* prepare next virtual core to run when we leave this IRQ context.
        this takes care of core registers and FP registers in one */
     platform::cpu::load_supervisor_cpu_fp_state
@@ -314,6 +732,9 @@ pub fn context_switch(next: VirtualCore)
        the previous vcore entry will be dropped */
     VCORES.lock().insert(pcore_id, next);
 
-    /* and ensure this switched-in vcore is not doomed */
+    /* and ensure this switched-in vcore is neither doomed nor parked */
     PhysicalCore::this().approve_vcore();
+
+    /* the software interrupt belongs to the virtual core, so show or hide it for the incoming one */
+    vipi::check_pending();
 }
\ No newline at end of file
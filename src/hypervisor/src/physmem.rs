@@ -27,24 +27,43 @@
  */
 
 use platform;
-use super::lock::Mutex;
+use super::lock::{Mutex, LockStats};
 use alloc::vec::Vec;
 use platform::physmem::{PhysMemBase, PhysMemEnd, PhysMemSize, AccessPermissions, validate_ram};
 use super::error::Cause;
 use super::hardware;
+use hvalgo::regions::{SortedRegions, Extent};
+#[cfg(feature = "memorypoison")]
+use hvalgo::poison::PoisonedRanges;
+use super::metrics;
+use super::workqueue::{self, Progress};
+use super::machine;
+use alloc::boxed::Box;
 
 /* needed to convert a region into a slice */
 use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /* to avoid fragmentation, round up physical memory region allocations into multiples of these totals,
 depending on the region type. this only applies when creating regions with alloc_region() */
 const PHYS_RAM_LARGE_REGION_MIN_SIZE: PhysMemSize = 64 * 1024 * 1024; /* 64MB ought to be enough for anyone */
 const PHYS_RAM_SMALL_REGION_MIN_SIZE: PhysMemSize =  1 * 1024 * 1024; /* smaller blocks are multiples of 1MB in size */
 
+/* size of the pool of DMA-safe physical memory set aside at boot for device backends */
+const PHYS_RAM_DMA_POOL_SIZE: PhysMemSize = 16 * 1024 * 1024;
+
 /* ensure large region bases are aligned down to multiples of this value
    note: region minimum size must be a non-zero multiple of region base alignment */
 const PHYS_RAM_LARGE_REGION_ALIGNMENT: PhysMemSize = 4 * 1024 * 1024; /* 4MB alignment */
 
+/* scrub freed regions this many bytes at a time, so as not to hold up the physical core for long */
+const SCRUB_STEP: PhysMemSize = 4 * 1024 * 1024;
+
+/* with the memorypoison feature, freed regions are filled with this pattern and checked for changes
+   when they're next allocated, to catch code that writes to physical memory after freeing it */
+#[cfg(feature = "memorypoison")]
+const POISON_WORD: usize = (usize::MAX / 0xff) * 0xa5;
+
 /* define whether to split a region N bytes from the top or from the bottom */
 #[derive(Clone, Copy, Debug)]
 pub enum RegionSplit
@@ -61,6 +80,16 @@ pub enum RegionHygiene
     CanClean 
 }
 
+/* define when a capsule's memory is zeroed */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ZeroPolicy
+{
+    Default, /* zero on allocation in release builds only */
+    Always,  /* zero on allocation and on free, in all builds */
+    OnFree,  /* as Default, and also scrub on free, in all builds, so no data lingers */
+    Never    /* don't zero at all: only for trusted capsules that can reuse stale data */
+}
+
 /* describe a physical memory region */
 #[derive(Copy, Clone)]
 pub struct Region
@@ -103,6 +132,27 @@ impl Region
         }
     }
 
+    /* zero a whole region regardless of build type */
+    pub fn zero(&mut self)
+    {
+        match self.hygiene
+        {
+            RegionHygiene::DontClean => hvalert!("BUG: Tried to zero don't-clean region 0x{:x}", self.base),
+            RegionHygiene::CanClean => self.as_u8_slice().fill(0x0)
+        }
+    }
+
+    /* prepare a newly allocated region for use according to the given zeroing policy */
+    fn clean_for(&mut self, policy: ZeroPolicy)
+    {
+        match policy
+        {
+            ZeroPolicy::Default | ZeroPolicy::OnFree => self.clean(),
+            ZeroPolicy::Always => self.zero(),
+            ZeroPolicy::Never => ()
+        }
+    }
+
     /* fill the end of a region with an array of bytes. thus if the array is 10 bytes long,
     the final 10 bytes of the region will be filled from that array, ascending
     => bytes = array to write into the region
@@ -128,10 +178,37 @@ impl Region
         Ok((self.base + self.size) - array_size)
     }
     
-    /* allow the currently running supervisor kernel to access this region of physical memory */
-    pub fn grant_access(&self)
+    /* allow the currently running supervisor kernel to access this region of physical memory
+       through the lowest-priority protection window, which other windows can override
+       => permissions = access to grant */
+    pub fn grant_access(&self, permissions: AccessPermissions)
     {
-        platform::physmem::protect(self.base, self.base + self.size, AccessPermissions::ReadWriteExecute);
+        machine::protect_window(0, self.base, self.base + self.size, permissions);
+    }
+
+    /* allow the currently running supervisor kernel to access this region of physical memory
+       as an additional, numbered protection window alongside its main RAM region
+       => window = platform-defined window number to use
+          permissions = access to grant */
+    pub fn grant_window_access(&self, window: usize, permissions: AccessPermissions)
+    {
+        machine::protect_window(window, self.base, self.base + self.size, permissions);
+    }
+
+    /* write any of this region's data held in the CPU caches back to memory, so that devices
+       and cores that don't snoop the caches see it, eg: before handing the region to a device
+       or running code loaded into it. this does nothing on cache-coherent platforms */
+    pub fn flush_cache(&self)
+    {
+        machine::cache_flush(self.base, self.base + self.size);
+    }
+
+    /* discard any of this region's data held in the CPU caches, without writing it back, so
+       that the region is next read from memory, eg: after a device has written into it.
+       this does nothing on cache-coherent platforms */
+    pub fn invalidate_cache(&self)
+    {
+        machine::cache_invalidate(self.base, self.base + self.size);
     }
 
     /* return or change attributes */
@@ -182,6 +259,14 @@ impl Region
     }
 }
 
+/* let free regions be kept in sorted lists, maintaining their hygiene as they're carved up and merged */
+impl Extent for Region
+{
+    fn base(&self) -> usize { self.base }
+    fn size(&self) -> usize { self.size }
+    fn with_bounds(&self, base: usize, size: usize) -> Region { Region::new(base, size, self.hygiene) }
+}
+
 /* gather up all physical RAM areas from which future capsule and heap physical
 RAM allocations will be drawn into the REGIONS list. this list is built from
 available, free physical RAM: it must *not* include any RAM areas already in use by
@@ -194,98 +279,21 @@ and available regions. if a region is in use, it must be removed from the list.
 lazy_static!
 {
     /* acquire REGIONS lock before accessing any physical RAM regions */
-    static ref REGIONS: Mutex<SortedRegions> = Mutex::new("RAM regions", SortedRegions::new());
-}
-
-/* implement a sorted list of regions */
-struct SortedRegions
-{
-    regions: Vec<Region>
-}
-
-impl SortedRegions
-{
-    /* create an empty list */
-    pub fn new() -> SortedRegions
-    {
-        SortedRegions
-        {
-            regions: Vec::new()
-        }
-    }
-
-    /* find a region that has a size equal to or greater than the required size.
-       if one is found, remove the region and return it. if one can't be found,
-       return an error code. */
-    pub fn find(&mut self, required_size: PhysMemSize) -> Result<Region, Cause>
-    {
-        for index in 0..self.regions.len()
-        {
-            if self.regions[index].size() >= required_size
-            {
-                /* remove from the list and return */
-                return Ok(self.regions.remove(index));
-            }
-        }
-
-        Err(Cause::PhysRegionNoMatch) /* can't find a region large enough */
-    }
-
-    /* insert a region into the list, sorted by base addresses, lowest first */
-    pub fn insert(&mut self, to_insert: Region) -> Result<(), Cause>
-    {
-        /* ignore zero-size inserts */
-        if to_insert.size() == 0
-        {
-            return Ok(())
-        }
-
-        for index in 0..self.regions.len()
-        {
-            if to_insert.end() <= self.regions[index].base()
-            {
-                self.regions.insert(index, to_insert);
-                return Ok(())
-            }
-
-            /* check to make sure we're not adding a region that will collide with another */
-            if to_insert.base() >= self.regions[index].base() && to_insert.base() < self.regions[index].end()
-            {
-                return Err(Cause::PhysRegionCollision);
-            }
-        }
-
-        /* insert at the end: region greater than all others */
-        self.regions.push(to_insert);
-        Ok(())
-    }
+    static ref REGIONS: Mutex<SortedRegions<Region>> = Mutex::new("RAM regions", SortedRegions::new());
 
-    /* merge all adjoining free regions. this requires the list to be sorted by base address ascending */
-    pub fn merge(&mut self)
-    {
-        let mut cursor = 0;
-        loop
-        {
-            /* prevent search from going out of bounds */
-            if (cursor + 1) >= self.regions.len()
-            {
-                break;
-            }
+    /* physically contiguous memory that devices can safely DMA into, carved out of REGIONS
+       at boot, and the bounds of that pool so regions can be returned to the right list */
+    static ref DMA_REGIONS: Mutex<SortedRegions<Region>> = Mutex::new("DMA-safe RAM regions", SortedRegions::new());
+    static ref DMA_POOL_BOUNDS: Mutex<Option<(PhysMemBase, PhysMemEnd)>> = Mutex::new("DMA pool bounds", None);
 
-            if self.regions[cursor].end() == self.regions[cursor + 1].base()
-            {
-                /* absorb the next region's size into this region */
-                self.regions[cursor].size = self.regions[cursor].size() + self.regions.remove(cursor + 1).size();
-            }
-            else
-            {
-                /* move onto next region */
-                cursor = cursor + 1;
-            }
-        }
-    }
+    /* ranges of freed physical memory filled with POISON_WORD and not yet reallocated */
+    #[cfg(feature = "memorypoison")]
+    static ref POISONED: Mutex<PoisonedRanges> = Mutex::new("poisoned RAM ranges", PoisonedRanges::new());
 }
 
+/* bytes of physical RAM in REGIONS once the boot-time reservations have been made */
+static RAM_TOTAL: AtomicUsize = AtomicUsize::new(0);
+
 /* initialize the physical memory system by registering all physical RAM available for use as allocatable regions */
 pub fn init() -> Result<(), Cause>
 {
@@ -296,17 +304,30 @@ pub fn init() -> Result<(), Cause>
         None => return Err(Cause::PhysicalCoreCountUnknown)
     };
 
-    /* the device tree defines chunks of memory that may or may not be entirely available for use */
-    let chunks = match hardware::get_phys_ram_chunks()
+    /* the device tree defines chunks of memory that may or may not be entirely available for use.
+    boards with split DDR describe each bank separately, possibly out of order and with holes between them */
+    let mut chunks = match hardware::get_phys_ram_chunks()
     {
-        Some(c) => c,
-        None => return Err(Cause::PhysNoRAMFound)
+        Some(c) if c.len() > 0 => c,
+        _ => return Err(Cause::PhysNoRAMFound)
     };
+    chunks.sort_by_key(|chunk| chunk.base);
 
     /* iterate over the physical memory chunks... */
     let mut regions = REGIONS.lock();
+    let mut previous_end = 0;
     for chunk in chunks
     {
+        /* a bank described twice, or overlapping another, can't be trusted, so skip it
+        rather than hand out the same memory twice */
+        if chunk.base < previous_end
+        {
+            hvalert!("Ignoring physical RAM bank 0x{:x}-0x{:x}: it overlaps another bank", chunk.base, chunk.base + chunk.size);
+            continue;
+        }
+        previous_end = chunk.base + chunk.size;
+        hvdebug!("Physical RAM bank 0x{:x}-0x{:x} ({} MiB)", chunk.base, chunk.base + chunk.size, chunk.size / (1024 * 1024));
+
         /* ...and let validate_ram break each chunk in sections we can safely use.
         assume the RAM is clean: the firmware or boot code should have wiped it,
         or it should contain random values */
@@ -316,9 +337,45 @@ pub fn init() -> Result<(), Cause>
         }
     }
 
+    /* set aside a pool of memory that devices can DMA into. if the device tree describes
+    dma-ranges then the pool must sit within one of them, otherwise any RAM will do */
+    let windows = match hardware::get_dma_ranges()
+    {
+        Some(ranges) if ranges.len() > 0 => ranges.iter().map(|r| (r.base, r.base + r.size)).collect(),
+        _ => vec!((0, PhysMemEnd::MAX))
+    };
+
+    for (lower, upper) in windows
+    {
+        if let Ok(pool) = regions.find_within(PHYS_RAM_DMA_POOL_SIZE, lower, upper)
+        {
+            *(DMA_POOL_BOUNDS.lock()) = Some((pool.base(), pool.end()));
+            DMA_REGIONS.lock().insert(pool)?;
+            break;
+        }
+    }
+
+    if DMA_POOL_BOUNDS.lock().is_none()
+    {
+        hvalert!("Unable to set aside {} bytes of DMA-safe physical memory", PHYS_RAM_DMA_POOL_SIZE);
+    }
+
+    RAM_TOTAL.store(regions.free(), Ordering::SeqCst);
     Ok(())
 }
 
+/* return the number of bytes of physical RAM free to allocate, and the number that were free after boot */
+pub fn free_ram() -> (PhysMemSize, PhysMemSize)
+{
+    (REGIONS.lock().free(), RAM_TOTAL.load(Ordering::SeqCst))
+}
+
+/* return the accounting of the lock protecting the free physical memory regions */
+pub fn lock_stats() -> LockStats
+{
+    REGIONS.stats()
+}
+
 /* perform housekeeping duties on idle physical CPU cores */
 macro_rules! physmemhousekeeper
 {
@@ -339,10 +396,37 @@ pub fn coalesce_regions()
      note, large type regions will have a base address aligned down to PHYS_RAM_LARGE_REGION_ALIGNMENT
      this is so that guests that require 2MB or 4MB kernel alignment (eg RV64GC Linux) work as expected
      see: https://patchwork.kernel.org/patch/10868465/
-     this code assumes the top of physically available RAM is aligned to PHYS_RAM_LARGE_REGION_ALIGNMENT
+     this code assumes the top of each bank of physically available RAM is aligned to PHYS_RAM_LARGE_REGION_ALIGNMENT.
+     free regions are never merged across the holes between banks, so a region never straddles two banks
 
    <= Region structure for the space, or an error code */
 pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
+{
+    alloc_region_policy(size, ZeroPolicy::Default)
+}
+
+/* allocate a region of physical memory, zeroing it as required by the given policy.
+   see alloc_region() for details
+   => size = number of bytes required
+      policy = when to zero the region's memory
+   <= Region structure for the space, or an error code */
+pub fn alloc_region_policy(size: PhysMemSize, policy: ZeroPolicy) -> Result<Region, Cause>
+{
+    match alloc_region_once(size, policy)
+    {
+        /* memory may be tied up waiting to be scrubbed in this core's work queue. finish
+           scrubbing it and try again. memory being scrubbed by other cores isn't waited for */
+        Err(Cause::PhysNotEnoughFreeRAM) if workqueue::pending() > 0 =>
+        {
+            workqueue::finish();
+            alloc_region_once(size, policy)
+        },
+        result => result
+    }
+}
+
+/* make one attempt to allocate a region of physical memory. see alloc_region_policy() for details */
+fn alloc_region_once(size: PhysMemSize, policy: ZeroPolicy) -> Result<Region, Cause>
 {
     /* determine where to split the free region block, and the region type */
     let (split_from, region_multiple) = if size >= PHYS_RAM_LARGE_REGION_MIN_SIZE
@@ -377,7 +461,9 @@ pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
                 (Ok((mut lower, upper)), RegionSplit::FromBottom) =>
                 {
                     regions.insert(upper)?;
-                    lower.clean();
+                    #[cfg(feature = "memorypoison")]
+                    check_poison(&lower);
+                    lower.clean_for(policy);
                     Ok(lower)
                 },
 
@@ -405,7 +491,9 @@ pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
                     };
 
                     regions.insert(adjusted_lower)?;
-                    aligned_upper.clean();
+                    #[cfg(feature = "memorypoison")]
+                    check_poison(&aligned_upper);
+                    aligned_upper.clean_for(policy);
                     Ok(aligned_upper)
                 },
 
@@ -416,6 +504,119 @@ pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
     }
 }
 
+/* allocate physical memory for a capsule as one region if possible, or else as several
+   discontiguous large regions that add up to the required size, largest first. this lets
+   capsules use RAM that's free in total but too fragmented to allocate in one piece
+   => size = number of bytes required, rounded up as for alloc_region()
+      policy = when to zero the regions' memory
+      pieces_max = most regions to split the memory across
+   <= list of regions, largest first, or an error code if the memory can't be found
+      in pieces_max regions or fewer. nothing is allocated on failure */
+pub fn alloc_scattered_policy(size: PhysMemSize, policy: ZeroPolicy, pieces_max: usize) -> Result<Vec<Region>, Cause>
+{
+    if let Ok(region) = alloc_region_policy(size, policy)
+    {
+        return Ok(vec!(region));
+    }
+
+    let mut pieces = Vec::new();
+    let mut remaining = size;
+    while remaining > 0 && pieces.len() < pieces_max
+    {
+        /* take what's left in one go if it fits, or else as much of the largest free region as
+           can be allocated in large-region multiples, allowing for alignment when it's split */
+        let piece = match alloc_region_policy(remaining, policy)
+        {
+            Ok(region) => Ok(region),
+            Err(_) =>
+            {
+                let largest = REGIONS.lock().largest();
+                let mut attempt = largest - (largest % PHYS_RAM_LARGE_REGION_MIN_SIZE);
+                let mut result = Err(Cause::PhysNotEnoughFreeRAM);
+                while attempt >= PHYS_RAM_LARGE_REGION_MIN_SIZE && attempt < remaining
+                {
+                    result = alloc_region_policy(attempt, policy);
+                    if result.is_ok()
+                    {
+                        break;
+                    }
+                    attempt = attempt - PHYS_RAM_LARGE_REGION_MIN_SIZE;
+                }
+                result
+            }
+        };
+
+        match piece
+        {
+            Ok(region) =>
+            {
+                remaining = remaining.saturating_sub(region.size());
+                pieces.push(region);
+            },
+            Err(_) => break
+        }
+    }
+
+    if remaining > 0
+    {
+        for piece in pieces
+        {
+            if let Err(_e) = dealloc_region(piece)
+            {
+                hvalert!("Can't return scattered region 0x{:x} size 0x{:x}: {:?}", piece.base(), piece.size(), _e);
+            }
+        }
+        return Err(Cause::PhysNotEnoughFreeRAM);
+    }
+
+    pieces.sort_by(|a, b| b.size().cmp(&a.size()));
+    Ok(pieces)
+}
+
+/* allocate a physically contiguous region of DMA-safe memory for hypervisor device models
+   or for granting to capsules. regions are rounded up to multiples of PHYS_RAM_SMALL_REGION_MIN_SIZE.
+   return them using dealloc_region() as normal
+   => size = number of bytes required
+   <= Region structure for the space, or an error code */
+pub fn alloc_dma_region(size: PhysMemSize) -> Result<Region, Cause>
+{
+    let adjusted_size = match size % PHYS_RAM_SMALL_REGION_MIN_SIZE
+    {
+        0 => size,
+        d => (size - d) + PHYS_RAM_SMALL_REGION_MIN_SIZE
+    };
+
+    let mut regions = DMA_REGIONS.lock();
+    match regions.find(adjusted_size)
+    {
+        Ok(found) =>
+        {
+            let (mut lower, upper) = found.split(adjusted_size, RegionSplit::FromBottom)?;
+            regions.insert(upper)?;
+            #[cfg(feature = "memorypoison")]
+            check_poison(&lower);
+
+            /* drop any stale cached copies of the region before scrubbing it, so none can later
+               be written back over it, then make sure devices see the scrubbed contents */
+            lower.invalidate_cache();
+            lower.clean();
+            lower.flush_cache();
+            Ok(lower)
+        },
+        Err(_) => Err(Cause::PhysNotEnoughFreeDMARAM)
+    }
+}
+
+/* return true if the given region was allocated from the DMA-safe pool */
+pub fn is_dma_region(region: &Region) -> bool
+{
+    match *(DMA_POOL_BOUNDS.lock())
+    {
+        Some((base, end)) => region.base() >= base && region.end() <= end,
+        None => false
+    }
+}
+
 /* deallocate a region so that its physical RAM can be reallocated.
    only accept samll regions that are multiples of PHYS_RAM_SMALL_REGION_MIN_SIZE
    and large regions that are multiples of PHYS_RAM_LARGE_REGION_MIN_SIZE
@@ -423,7 +624,113 @@ pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
    <= Ok for success, or an error code for failure */
 pub fn dealloc_region(to_free: Region) -> Result<(), Cause>
 {
+    dealloc_region_policy(to_free, ZeroPolicy::Default)
+}
+
+/* deallocate a region, scrubbing it first if required by the given policy.
+   see dealloc_region() for details
+   => to_free = region to deallocate
+      policy = the zeroing policy of the region's owner
+   <= Ok for success, or an error code for failure */
+pub fn dealloc_region_policy(mut to_free: Region, policy: ZeroPolicy) -> Result<(), Cause>
+{
+    /* wipe sensitive data before the memory can be handed to anyone else.
+       large regions are scrubbed a step at a time in the background */
+    match (policy, to_free.hygiene)
+    {
+        (ZeroPolicy::Always, RegionHygiene::CanClean) | (ZeroPolicy::OnFree, RegionHygiene::CanClean) if to_free.size() > SCRUB_STEP =>
+        {
+            scrub_then_free(to_free);
+            Ok(())
+        },
+        (ZeroPolicy::Always, _) | (ZeroPolicy::OnFree, _) =>
+        {
+            let dma = is_dma_region(&to_free);
+            if dma == true
+            {
+                to_free.invalidate_cache();
+            }
+            to_free.zero();
+            if dma == true
+            {
+                to_free.flush_cache();
+            }
+            free_region(to_free)
+        },
+        _ => free_region(to_free)
+    }
+}
+
+/* queue a region to be scrubbed, SCRUB_STEP bytes at a time, and returned to the free list once it's clean.
+   the hypervisor may be waiting on the memory, so this is high priority
+   => to_free = region to scrub and deallocate */
+fn scrub_then_free(to_free: Region)
+{
+    let mut scrubbed = 0;
+    let dma = is_dma_region(&to_free);
+    let step = Box::new(move ||
+    {
+        let end = core::cmp::min(scrubbed + SCRUB_STEP, to_free.size());
+        let (base, top) = (to_free.base() + scrubbed, to_free.base() + end);
+
+        /* devices may have written to DMA memory behind the caches' back, so drop any stale copies
+           of each step before scrubbing it, and write the zeroes out to memory once it's done */
+        if dma == true
+        {
+            machine::cache_invalidate(base, top);
+        }
+        to_free.as_u8_slice()[scrubbed..end].fill(0x0);
+        if dma == true
+        {
+            machine::cache_flush(base, top);
+        }
+        scrubbed = end;
+
+        match scrubbed < to_free.size()
+        {
+            true => Progress::Again,
+            false => Progress::Done(free_region(to_free))
+        }
+    });
+
+    /* a region that can't be freed once scrubbed is lost until the host is rebooted */
     let size = to_free.size();
+    let done = Box::new(move |result: Result<(), Cause>| if result.is_err()
+    {
+        metrics::count_leak(size);
+    });
+
+    workqueue::submit(workqueue::Priority::High, "scrub freed region", step, Some(done));
+}
+
+/* return a region, scrubbed if necessary, to its free list
+   => to_free = region to deallocate
+   <= Ok for success, or an error code for failure */
+fn free_region(to_free: Region) -> Result<(), Cause>
+{
+    let size = to_free.size();
+
+    /* DMA-safe regions go back to their own pool */
+    if is_dma_region(&to_free) == true
+    {
+        if size % PHYS_RAM_SMALL_REGION_MIN_SIZE != 0
+        {
+            return Err(Cause::PhysRegionSmallNotMultiple);
+        }
+
+        /* a device may have written to the region behind the caches' back. drop any stale
+           copies of its contents so they can't later be written back over the region. regions
+           scrubbed on the way here have already been flushed out to memory */
+        to_free.invalidate_cache();
+
+        #[cfg(feature = "memorypoison")]
+        poison(&to_free);
+
+        let mut dma_regions = DMA_REGIONS.lock();
+        dma_regions.insert(to_free)?;
+        dma_regions.merge();
+        return Ok(());
+    }
 
     /* police the size of the region */
     if size < PHYS_RAM_LARGE_REGION_MIN_SIZE
@@ -441,5 +748,56 @@ pub fn dealloc_region(to_free: Region) -> Result<(), Cause>
         }
     }
 
-    REGIONS.lock().insert(to_free)
+    #[cfg(feature = "memorypoison")]
+    poison(&to_free);
+
+    Ok(REGIONS.lock().insert(to_free)?)
+}
+
+/* fill a region being freed with POISON_WORD and remember it so the poison can be checked later.
+   regions that mustn't be cleaned aren't poisoned */
+#[cfg(feature = "memorypoison")]
+fn poison(region: &Region)
+{
+    if let RegionHygiene::DontClean = region.hygiene
+    {
+        return;
+    }
+
+    for word in region.as_usize_slice().iter_mut()
+    {
+        *word = POISON_WORD;
+    }
+    let tracked = POISONED.lock().add(region.base(), region.end());
+    if tracked == false
+    {
+        hvdebug!("Too many poisoned RAM ranges to check 0x{:x}-0x{:x} when reused", region.base(), region.end());
+    }
+}
+
+/* check any poisoned memory within a newly allocated region is untouched, warning if it isn't,
+   and stop tracking that memory as poisoned. nothing is allocated here, as this is called
+   with the allocator's locks held */
+#[cfg(feature = "memorypoison")]
+fn check_poison(region: &Region)
+{
+    let word_size = core::mem::size_of::<usize>();
+    let mut overwritten = None;
+    POISONED.lock().take(region.base(), region.end(), |lower, upper|
+    {
+        let words = unsafe { slice::from_raw_parts(lower as *const usize, (upper - lower) / word_size) };
+        if let Some(index) = words.iter().position(|word| *word != POISON_WORD)
+        {
+            if overwritten.is_none()
+            {
+                overwritten = Some((lower + (index * word_size), words[index]));
+            }
+        }
+    });
+
+    /* report the first overwrite found once the list is unlocked, as logging can allocate */
+    if let Some((address, value)) = overwritten
+    {
+        hvalert!("Physical memory at 0x{:x} was written to after it was freed (found 0x{:x})", address, value);
+    }
 }
@@ -0,0 +1,211 @@
+/* diosix RISC-V platform-level interrupt controller
+ *
+ * The platform code handles the core-local timer and software
+ * interrupts it needs. Interrupts from devices passed through to
+ * capsules arrive through the host's PLIC, which is driven here:
+ * each device's interrupt is given a priority and enabled for every
+ * physical core's machine-mode context, and whichever core takes it
+ * claims it from the PLIC, masks it, and queues it for the capsule that
+ * owns the device. Device interrupts are usually level-triggered, and
+ * stay raised until the capsule's driver has serviced the device, so
+ * the interrupt is only unmasked and completed once the capsule says
+ * it's done with it. Until then, the PLIC won't raise it again.
+ *
+ * The PLIC's contexts are numbered by the order of the pairs in its
+ * interrupts-extended property, each pair naming a core's interrupt
+ * controller and the interrupt it raises there. A machine external
+ * interrupt is number 11.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use alloc::vec::Vec;
+use super::lock::Mutex;
+use hvalgo::fdt::Fdt;
+use super::pcore::{PhysicalCore, HartID};
+use super::machine;
+
+/* register layout, as offsets from the PLIC's base address */
+const PRIORITY_BASE: usize = 0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0;
+const CONTEXT_CLAIM: usize = 4;
+
+/* compatible strings of the PLICs this code can drive */
+pub const COMPATIBLE: [&str; 2] = [ "riscv,plic0", "sifive,plic-1.0.0" ];
+
+/* interrupt number of a machine external interrupt in a core's local interrupt controller */
+const MACHINE_EXTERNAL: u32 = 11;
+
+/* priority given to passed-through devices' interrupts. any non-zero priority beats the zero threshold */
+const DEVICE_PRIORITY: u32 = 1;
+
+struct Plic
+{
+    base: usize,
+    sources: usize,                 /* interrupt numbers run from 1 to this inclusive */
+    contexts: Vec<(HartID, usize)>  /* machine-mode context of each core's hardware ID */
+}
+
+impl Plic
+{
+    fn read(&self, offset: usize) -> u32
+    {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32)
+    {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /* enable or disable a device's interrupt in every core's machine-mode context
+       => irq = device's interrupt number, which must be valid
+          enabled = true to let the interrupt through, false to mask it */
+    fn set_enabled(&self, irq: usize, enabled: bool)
+    {
+        for (_, context) in self.contexts.iter()
+        {
+            let word = ENABLE_BASE + (context * ENABLE_STRIDE) + ((irq / 32) * 4);
+            let bits = self.read(word);
+            self.write(word, match enabled
+            {
+                true => bits | (1 << (irq % 32)),
+                false => bits & !(1 << (irq % 32))
+            });
+        }
+    }
+
+    /* <= the machine-mode context of the given core, if it has one */
+    fn context_of(&self, hart: HartID) -> Option<usize>
+    {
+        self.contexts.iter().find(|(h, _)| *h == hart).map(|(_, context)| *context)
+    }
+}
+
+lazy_static!
+{
+    /* the host's PLIC, if it has one */
+    static ref PLIC: Mutex<Option<Plic>> = Mutex::new("PLIC", None);
+}
+
+/* find the host's PLIC and its cores' machine-mode contexts in the host's device tree.
+   call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let node = match fdt.nodes().find(|n| n.is_enabled() && COMPATIBLE.iter().any(|c| n.is_compatible(c)))
+    {
+        Some(n) => n,
+        None => return
+    };
+
+    let base = match node.reg().ok().and_then(|mut reg| reg.next())
+    {
+        Some((base, _)) => base as usize,
+        None => return
+    };
+
+    /* find the contexts that deliver machine external interrupts to each core */
+    let contexts: Vec<(HartID, usize)> = fdt.hart_contexts(&node, MACHINE_EXTERNAL)
+        .map(|(hart, context)| (hart as HartID, context)).collect();
+
+    let sources = node.property_u32("riscv,ndev").unwrap_or(0) as usize;
+    hvdebug!("PLIC at 0x{:x} with {} interrupt sources and {} machine-mode contexts", base, sources, contexts.len());
+    *(PLIC.lock()) = Some(Plic { base, sources, contexts });
+}
+
+/* let the PLIC interrupt this physical core, if there is a PLIC. call on each core during startup */
+pub fn enable_core()
+{
+    if let Some(plic) = &*(PLIC.lock())
+    {
+        if let Some(context) = plic.context_of(PhysicalCore::get_hart_id())
+        {
+            plic.write(CONTEXT_BASE + (context * CONTEXT_STRIDE) + CONTEXT_THRESHOLD, 0);
+            machine::enable_machine_external_irq();
+        }
+    }
+}
+
+/* allow a device's interrupt to reach any physical core
+   => irq = device's interrupt number
+   <= true for success, or false if there's no PLIC or no such interrupt */
+pub fn enable(irq: usize) -> bool
+{
+    match &*(PLIC.lock())
+    {
+        Some(plic) if irq > 0 && irq <= plic.sources =>
+        {
+            plic.write(PRIORITY_BASE + (irq * 4), DEVICE_PRIORITY);
+            plic.set_enabled(irq, true);
+            true
+        },
+        _ => false
+    }
+}
+
+/* stop a device's interrupt from reaching any physical core, until it's unmasked
+   => irq = device's interrupt number */
+pub fn mask(irq: usize)
+{
+    if let Some(plic) = &*(PLIC.lock())
+    {
+        if irq > 0 && irq <= plic.sources
+        {
+            plic.set_enabled(irq, false);
+        }
+    }
+}
+
+/* let a masked device's interrupt reach any physical core again
+   => irq = device's interrupt number */
+pub fn unmask(irq: usize)
+{
+    if let Some(plic) = &*(PLIC.lock())
+    {
+        if irq > 0 && irq <= plic.sources
+        {
+            plic.set_enabled(irq, true);
+        }
+    }
+}
+
+/* claim the highest priority device interrupt waiting for this physical core
+   <= the interrupt number, or None if there's nothing waiting or no PLIC */
+pub fn claim() -> Option<usize>
+{
+    match &*(PLIC.lock())
+    {
+        Some(plic) =>
+        {
+            let context = plic.context_of(PhysicalCore::get_hart_id())?;
+            match plic.read(CONTEXT_BASE + (context * CONTEXT_STRIDE) + CONTEXT_CLAIM)
+            {
+                0 => None,
+                irq => Some(irq as usize)
+            }
+        },
+        None => None
+    }
+}
+
+/* tell the PLIC the given interrupt has been handled, so that it can be raised again. the PLIC
+   ignores completions of interrupts that aren't enabled for this core, so unmask it first
+   => irq = interrupt number returned by claim() */
+pub fn complete(irq: usize)
+{
+    if let Some(plic) = &*(PLIC.lock())
+    {
+        if let Some(context) = plic.context_of(PhysicalCore::get_hart_id())
+        {
+            plic.write(CONTEXT_BASE + (context * CONTEXT_STRIDE) + CONTEXT_CLAIM, irq as u32);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+/* diosix host reboot and power off
+ *
+ * Let a management capsule granted the host_reset property reboot or
+ * power off the whole host, so that remote systems can be power-cycled
+ * from within a guest. Unlike a warm reboot, this goes back through the
+ * firmware, so hardware held back from earlier capsules is recovered.
+ *
+ * The other capsules are first sent VIRQ_HOST_RESET, and have their
+ * virtual power or reboot button pressed, so that they can shut down
+ * cleanly, and are given a grace period, chosen by the
+ * requesting capsule, in which to exit. Once they've all gone, or the
+ * grace period has run out, the debug log is flushed and the host is
+ * reset or powered off through the system controller register named in
+ * its device tree. See syscon.rs.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID};
+use super::passthrough::{self, DeviceIRQ};
+use super::hardware;
+use super::scheduler;
+use super::button::{self, Button};
+use platform::timer::TimerValue;
+
+/* what can be done to the host, shared with the capsules */
+pub use hypercall::power::Reset;
+
+/* virtual interrupt raised when the host is about to be reset */
+pub const VIRQ_HOST_RESET: DeviceIRQ = hypercall::irq::VIRQ_HOST_RESET;
+
+/* longest grace period a capsule can give the others, in milliseconds */
+const GRACE_PERIOD_MAX: u64 = 60 * 1000;
+
+/* a reset waiting for its grace period to end */
+struct Pending
+{
+    reset: Reset,
+    requester: Option<CapsuleID>,   /* capsule that asked for the reset, if any */
+    deadline: u64                   /* timer value when the grace period ends, in exact ticks */
+}
+
+lazy_static!
+{
+    static ref PENDING: Mutex<Option<Pending>> = Mutex::new("pending host reset", None);
+}
+
+/* start rebooting or powering off the host, warning the capsules first
+   => reset = whether to reboot or power off
+      requester = capsule asking for the reset, which isn't warned, or None for the hypervisor
+      grace = milliseconds to give the other capsules to shut down
+   <= Ok for success, or an error code if a reset is already under way */
+pub fn request(reset: Reset, requester: Option<CapsuleID>, grace: u64) -> Result<(), Cause>
+{
+    let grace = core::cmp::min(grace, GRACE_PERIOD_MAX);
+    let deadline = match scheduler::timer_now()
+    {
+        Some((now, frequency)) => now + TimerValue::Milliseconds(grace).to_exact(frequency),
+        None => 0 /* without a timer, there's no grace period */
+    };
+
+    {
+        let mut pending = PENDING.lock();
+        if pending.is_some()
+        {
+            return Err(Cause::PowerResetInProgress);
+        }
+        *pending = Some(Pending { reset, requester, deadline });
+    }
+
+    /* let the other capsules know so they can finish up */
+    for summary in capsule::snapshot()
+    {
+        if Some(summary.id()) != requester
+        {
+            passthrough::raise_virtual_irq(summary.id(), VIRQ_HOST_RESET);
+        }
+    }
+    button::press_all(match reset
+    {
+        Reset::Reboot => Button::Reboot,
+        Reset::PowerOff => Button::Power
+    }, requester);
+
+    hvalert!("Host {} requested by {}: {} ms for capsules to shut down",
+        match reset
+        {
+            Reset::Reboot => "reboot",
+            Reset::PowerOff => "power off"
+        },
+        match requester
+        {
+            Some(cid) => format!("capsule {}", cid),
+            None => format!("the hypervisor")
+        },
+        grace);
+    Ok(())
+}
+
+/* reset the host once the other capsules have shut down or their grace period is over.
+   call this regularly from the boot core's housekeeping */
+pub fn housekeeper()
+{
+    let (reset, requester, deadline) = match &*(PENDING.lock())
+    {
+        Some(p) => (p.reset, p.requester, p.deadline),
+        None => return
+    };
+
+    let remaining = capsule::snapshot().iter().filter(|summary| Some(summary.id()) != requester).count();
+    let expired = match scheduler::timer_now()
+    {
+        Some((now, _)) => now >= deadline,
+        None => true
+    };
+
+    if remaining > 0 && expired == false
+    {
+        return;
+    }
+
+    if remaining > 0
+    {
+        hvalert!("Grace period over with {} capsule(s) still running", remaining);
+    }
+
+    /* make sure the final words are seen, as nothing runs after this */
+    hvalert!("Resetting host");
+    debughousekeeper!();
+
+    if let Err(_e) = hardware::system_reset(reset)
+    {
+        hvalert!("Failed to reset host: {}", error::report(&_e));
+        *(PENDING.lock()) = None;
+    }
+}
@@ -0,0 +1,157 @@
+/* diosix host memory pressure notifications
+ *
+ * Capsules are given their physical RAM up front, so once the host runs
+ * short, the only way to make room for new capsules is for running ones
+ * to give some back. Capsules with balloon drivers, or caches they can
+ * shrink, can subscribe to memory pressure notifications with a
+ * hypercall, which also returns the current pressure level. Subscribers
+ * are sent VIRQ_MEMORY_PRESSURE whenever the level changes, and can call
+ * the hypercall again to read the new level.
+ *
+ * The level is worked out from the share of the physical RAM free after
+ * boot that's still free, during housekeeping. It's raised as soon as
+ * free RAM drops below a threshold, and only lowered again once free RAM
+ * is comfortably back above it, so that subscribers aren't flooded with
+ * notifications when free RAM hovers around a threshold. If a new
+ * capsule can't be given its RAM, the level is raised to critical and
+ * held there for a few housekeeping periods, whatever the share of free
+ * RAM, as there may be plenty free but not in one large enough piece.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_set::HashSet;
+use alloc::vec::Vec;
+use super::capsule::CapsuleID;
+use super::physmem;
+use super::passthrough::{self, DeviceIRQ};
+
+/* how short the host is of free physical memory, shared with the capsules */
+pub use hypercall::memory::Pressure;
+
+/* virtual interrupt raised when the memory pressure level changes */
+pub const VIRQ_MEMORY_PRESSURE: DeviceIRQ = hypercall::irq::VIRQ_MEMORY_PRESSURE;
+
+/* pressure is low or critical when the free share of physical RAM drops below these, in parts per thousand */
+const LOW_FREE_PERMILLE: usize = 200;
+const CRITICAL_FREE_PERMILLE: usize = 50;
+
+/* the free share must rise this many parts per thousand above a threshold before the pressure is lowered */
+const HYSTERESIS_PERMILLE: usize = 25;
+
+/* housekeeping periods to hold the pressure at critical after failing to allocate RAM for a capsule */
+const ALLOC_FAILED_HOLD_PERIODS: usize = 3;
+
+/* current pressure level, as a Pressure value */
+static LEVEL: AtomicUsize = AtomicUsize::new(Pressure::Normal as usize);
+
+/* housekeeping periods left before the pressure can be lowered after an allocation failure */
+static HOLD: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static!
+{
+    /* capsules to tell when the pressure level changes */
+    static ref SUBSCRIBERS: Mutex<HashSet<CapsuleID>> = Mutex::new("memory pressure subscribers", HashSet::new());
+}
+
+/* <= the current memory pressure level */
+pub fn level() -> Pressure
+{
+    Pressure::from_usize(LEVEL.load(Ordering::SeqCst)).unwrap_or(Pressure::Normal)
+}
+
+/* tell a capsule whenever the memory pressure level changes
+   => cid = capsule to tell
+   <= the current pressure level */
+pub fn subscribe(cid: CapsuleID) -> Pressure
+{
+    SUBSCRIBERS.lock().insert(cid);
+    level()
+}
+
+/* stop telling a capsule about memory pressure when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    SUBSCRIBERS.lock().remove(&cid);
+}
+
+/* work out the pressure level from the share of RAM that's free
+   => permille = free RAM, in parts per thousand of the RAM free after boot
+      threshold = amount added to the thresholds
+   <= the pressure level for that share */
+fn level_for(permille: usize, threshold: usize) -> Pressure
+{
+    if permille < CRITICAL_FREE_PERMILLE + threshold
+    {
+        Pressure::Critical
+    }
+    else if permille < LOW_FREE_PERMILLE + threshold
+    {
+        Pressure::Low
+    }
+    else
+    {
+        Pressure::Normal
+    }
+}
+
+/* change the pressure level, telling subscribers if it's different */
+fn set_level(new: Pressure)
+{
+    let old = LEVEL.swap(new as usize, Ordering::SeqCst);
+    if old == new as usize
+    {
+        return;
+    }
+
+    hvdebug!("Host memory pressure now {:?}", new);
+
+    /* don't hold the subscribers lock while raising interrupts */
+    let subscribers: Vec<CapsuleID> = SUBSCRIBERS.lock().iter().cloned().collect();
+    for cid in subscribers
+    {
+        passthrough::raise_virtual_irq(cid, VIRQ_MEMORY_PRESSURE);
+    }
+}
+
+/* raise the pressure to critical after failing to find physical RAM for a capsule */
+pub fn allocation_failed()
+{
+    HOLD.store(ALLOC_FAILED_HOLD_PERIODS, Ordering::SeqCst);
+    set_level(Pressure::Critical);
+}
+
+/* reassess the memory pressure level. called by the boot core during housekeeping */
+pub fn housekeeper()
+{
+    if HOLD.load(Ordering::SeqCst) > 0
+    {
+        HOLD.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    let (free, total) = physmem::free_ram();
+    if total == 0
+    {
+        return;
+    }
+
+    let permille = (free * 1000) / total;
+    let current = level();
+    let rising = level_for(permille, 0);
+
+    /* raise the level straight away, but lower it only by as much as the hysteresis allows */
+    set_level(match rising as usize > current as usize
+    {
+        true => rising,
+        false => match level_for(permille, HYSTERESIS_PERMILLE)
+        {
+            falling if (falling as usize) < current as usize => falling,
+            _ => current
+        }
+    });
+}
@@ -0,0 +1,262 @@
+/* diosix persistent alert store
+ *
+ * Mirror the most recent alerts, including those describing a panic,
+ * into an area of memory that survives a reboot, such as battery-backed
+ * RAM, named in the host's device tree. On the next boot, whatever was
+ * left in the area is printed before it's reused, so crashes on headless
+ * boards in the field can be diagnosed after the fact.
+ *
+ * The store is optional: it's only used if the device tree names an
+ * area for it. The area holds a small header followed by a ring buffer
+ * of the last PSTORE_CAPACITY_MAX bytes of alerts, or less if the area
+ * is smaller. The header is checked on boot so that random contents
+ * left after a cold power-on aren't mistaken for a previous log.
+ *
+ * If the area is large enough, its last SETTINGS_SLOT_SIZE bytes are set
+ * aside for the hypervisor's saved settings, which are kept, rather than
+ * cleared, from one boot to the next.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::mem::size_of;
+use core::ptr;
+use super::lock::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use super::hardware;
+use super::error::Cause;
+use super::sha256;
+use super::machine;
+use platform::physmem::PhysMemBase;
+
+/* most bytes of alerts kept across reboots */
+const PSTORE_CAPACITY_MAX: usize = 16 * 1024;
+
+/* identifies a valid store: "dxpstore" in ASCII */
+const PSTORE_MAGIC: u64 = 0x6572_6f74_7370_7864;
+
+/* bytes at the end of the area set aside for saved settings, including their header */
+const SETTINGS_SLOT_SIZE: usize = 1024;
+
+/* identifies valid saved settings: "dxsettng" in ASCII */
+const SETTINGS_MAGIC: u64 = 0x676e_7474_6573_7864;
+
+/* the area's header, followed by its ring buffer */
+#[repr(C)]
+struct Header
+{
+    magic: u64,
+    head: u64,      /* offset into the ring buffer of the next byte to write */
+    length: u64,    /* number of valid bytes in the ring buffer */
+    check: u64      /* magic ^ head ^ length, to catch a partially updated or garbage header */
+}
+
+/* the header of the saved settings, followed by the settings as text */
+#[repr(C)]
+struct SettingsHeader
+{
+    magic: u64,
+    length: u64,    /* number of bytes of text */
+    check: u64      /* first eight bytes of the text's SHA-256 digest, to catch garbage */
+}
+
+/* the persistent area in use */
+struct Store
+{
+    header: *mut Header,
+    ring: *mut u8,
+    capacity: usize,
+    settings: Option<*mut SettingsHeader> /* saved settings, if there's room for them */
+}
+
+/* the area is only ever accessed with the lock held */
+unsafe impl Send for Store {}
+
+lazy_static!
+{
+    static ref PSTORE: Mutex<Option<Store>> = Mutex::new("persistent alert store", None);
+}
+
+impl Store
+{
+    /* <= true if the header describes a valid log */
+    fn is_valid(&self) -> bool
+    {
+        let header = unsafe { ptr::read_volatile(self.header) };
+        header.magic == PSTORE_MAGIC &&
+            header.check == header.magic ^ header.head ^ header.length &&
+            (header.head as usize) < self.capacity &&
+            (header.length as usize) <= self.capacity
+    }
+
+    /* <= the bytes in the ring buffer, oldest first */
+    fn read(&self) -> Vec<u8>
+    {
+        let header = unsafe { ptr::read_volatile(self.header) };
+        let length = header.length as usize;
+        let start = (header.head as usize + self.capacity - length) % self.capacity;
+
+        (0..length).map(|offset| unsafe { ptr::read_volatile(self.ring.add((start + offset) % self.capacity)) }).collect()
+    }
+
+    /* append a line of text to the ring buffer, overwriting the oldest text if it's full */
+    fn write_line(&mut self, line: &[u8])
+    {
+        let mut header = unsafe { ptr::read_volatile(self.header) };
+        for byte in line.iter().chain(b"\n".iter())
+        {
+            unsafe { ptr::write_volatile(self.ring.add(header.head as usize), *byte) };
+            header.head = ((header.head as usize + 1) % self.capacity) as u64;
+        }
+        header.length = core::cmp::min(header.length as usize + line.len() + 1, self.capacity) as u64;
+        self.update(header.head, header.length);
+    }
+
+    /* rewrite the header, and make sure it and the ring buffer reach the persistent memory */
+    fn update(&mut self, head: u64, length: u64)
+    {
+        let header = Header { magic: PSTORE_MAGIC, head, length, check: PSTORE_MAGIC ^ head ^ length };
+        unsafe { ptr::write_volatile(self.header, header) };
+
+        let base = self.header as PhysMemBase;
+        machine::cache_flush(base, base + size_of::<Header>() + self.capacity);
+    }
+}
+
+/* find the persistent area, print anything left in it by the previous boot, and start mirroring
+   alerts into it. call on the boot core once the hardware has been discovered */
+pub fn init()
+{
+    let area = match hardware::get_pstore_area()
+    {
+        Some(a) => a,
+        None => return
+    };
+
+    if area.size <= size_of::<Header>()
+    {
+        hvalert!("Persistent store at 0x{:x} is too small to use ({} bytes)", area.base, area.size);
+        return;
+    }
+
+    /* only set aside room for settings if it leaves at least as much for alerts */
+    let (available, settings) = match area.size - size_of::<Header>() >= SETTINGS_SLOT_SIZE * 2
+    {
+        true => (area.size - size_of::<Header>() - SETTINGS_SLOT_SIZE,
+                 Some((area.base + area.size - SETTINGS_SLOT_SIZE) as *mut SettingsHeader)),
+        false => (area.size - size_of::<Header>(), None)
+    };
+
+    let mut store = Store
+    {
+        header: area.base as *mut Header,
+        ring: (area.base + size_of::<Header>()) as *mut u8,
+        capacity: core::cmp::min(available, PSTORE_CAPACITY_MAX),
+        settings
+    };
+
+    /* the memory may have been cached before the reboot, so read what's really there */
+    machine::cache_invalidate(area.base, area.base + area.size);
+
+    if store.is_valid() == true
+    {
+        let recovered = store.read();
+        if recovered.len() > 0
+        {
+            hvalert!("Recovered {} bytes of alerts from before the last reboot:", recovered.len());
+            for line in String::from_utf8_lossy(&recovered).lines()
+            {
+                hvprintln!("[!] ... {}", line);
+            }
+            hvalert!("End of recovered alerts");
+        }
+    }
+
+    /* start afresh */
+    store.update(0, 0);
+    hvdebug!("Mirroring alerts into persistent store at 0x{:x}, {} bytes", area.base, store.capacity);
+    *(PSTORE.lock()) = Some(store);
+}
+
+/* <= the check value of saved settings' text */
+fn settings_check(text: &[u8]) -> u64
+{
+    let digest = sha256::digest(text);
+    let mut check = [0u8; 8];
+    check.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(check)
+}
+
+/* save the hypervisor's settings so they can be restored on the next boot
+   => text = settings written as text
+   <= Ok for success, or an error code if there's no room for them */
+pub fn save_settings(text: &[u8]) -> Result<(), Cause>
+{
+    /* hold the lock so that saves from different cores don't interleave */
+    let store = PSTORE.lock();
+    let slot = match &*store
+    {
+        Some(Store { settings: Some(slot), .. }) => *slot,
+        _ => return Err(Cause::SettingNoStore)
+    };
+
+    if text.len() > SETTINGS_SLOT_SIZE - size_of::<SettingsHeader>()
+    {
+        return Err(Cause::SettingNoStore);
+    }
+
+    let base = slot as PhysMemBase;
+    let content = (base + size_of::<SettingsHeader>()) as *mut u8;
+    for (offset, byte) in text.iter().enumerate()
+    {
+        unsafe { ptr::write_volatile(content.add(offset), *byte) };
+    }
+
+    let header = SettingsHeader { magic: SETTINGS_MAGIC, length: text.len() as u64, check: settings_check(text) };
+    unsafe { ptr::write_volatile(slot, header) };
+    machine::cache_flush(base, base + SETTINGS_SLOT_SIZE);
+    Ok(())
+}
+
+/* <= settings saved during a previous boot, as text, or None if there aren't any */
+pub fn load_settings() -> Option<Vec<u8>>
+{
+    let slot = match &*(PSTORE.lock())
+    {
+        Some(Store { settings: Some(slot), .. }) => *slot,
+        _ => return None
+    };
+
+    let header = unsafe { ptr::read_volatile(slot) };
+    if header.magic != SETTINGS_MAGIC || header.length as usize > SETTINGS_SLOT_SIZE - size_of::<SettingsHeader>()
+    {
+        return None;
+    }
+
+    let content = (slot as PhysMemBase + size_of::<SettingsHeader>()) as *const u8;
+    let text: Vec<u8> = (0..header.length as usize).map(|offset| unsafe { ptr::read_volatile(content.add(offset)) }).collect();
+    match settings_check(&text) == header.check
+    {
+        true => Some(text),
+        false => None
+    }
+}
+
+/* copy an alert into the persistent area, if there is one. this is called by hvalert!()
+   => alert = text of the alert, without a line ending */
+pub fn mirror(alert: &str)
+{
+    /* don't deadlock if an alert is raised, eg: by a panic, while the store is being updated */
+    if PSTORE.is_locked() == true
+    {
+        return;
+    }
+
+    if let Some(store) = &mut *(PSTORE.lock())
+    {
+        store.write_line(alert.as_bytes());
+    }
+}
@@ -0,0 +1,185 @@
+/* diosix last-level cache and memory bandwidth partitioning
+ *
+ * On systems with RISC-V CBQRI-style capacity and bandwidth controllers,
+ * capsules can be given a private share of the last-level cache and a
+ * reserved share of memory bandwidth, using the cache_share=N and
+ * bandwidth_share=N properties, where N is a percentage. This stops noisy
+ * neighbours from evicting a real-time guest's working set or starving it
+ * of memory bandwidth.
+ *
+ * Each partitioned capsule is given its own resource control ID (RCID),
+ * tagged onto the physical CPU core whenever the capsule is scheduled.
+ * Everything else shares RCID 0, which is left with the cache blocks and
+ * bandwidth not reserved by partitioned capsules.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::error::{self, Cause};
+use super::capsule::CapsuleID;
+use super::hardware;
+use super::cbqri;
+
+/* resource control ID shared by unpartitioned capsules and the hypervisor */
+const RCID_DEFAULT: usize = 0;
+
+/* maximum number of resource control IDs we'll hand out, including the default */
+const RCIDS_MAX: usize = 16;
+
+/* describe a capsule's partition */
+#[derive(Clone, Copy)]
+struct Partition
+{
+    rcid: usize,
+    cache_mask: usize,
+    bandwidth: usize
+}
+
+lazy_static!
+{
+    /* partitions assigned to capsules */
+    static ref PARTITIONS: Mutex<HashMap<CapsuleID, Partition>> = Mutex::new("QoS partitions", HashMap::new());
+}
+
+/* find a contiguous run of free cache blocks, as controllers expect, searching down from the top
+   => used = bitmask of blocks already allocated
+      total = number of blocks
+      needed = number of blocks to find
+   <= bitmask of found blocks, or None if there's no space */
+fn find_blocks(used: usize, total: usize, needed: usize) -> Option<usize>
+{
+    if needed == 0 || needed > total
+    {
+        return None;
+    }
+
+    let run = if needed >= usize::BITS as usize { usize::MAX } else { (1 << needed) - 1 };
+    for shift in (0..=(total - needed)).rev()
+    {
+        let mask = run << shift;
+        if mask & used == 0
+        {
+            return Some(mask);
+        }
+    }
+
+    None
+}
+
+/* give a capsule its own share of the cache and memory bandwidth
+   => cid = capsule to partition
+      cache_percent = percentage of the last-level cache to reserve, or 0 for none
+      bandwidth_percent = percentage of memory bandwidth to reserve, or 0 for none
+   <= Ok for success, or an error code if the system can't partition or has run out of resources */
+pub fn partition(cid: CapsuleID, cache_percent: usize, bandwidth_percent: usize) -> Result<(), Cause>
+{
+    let (blocks, units) = match hardware::get_qos_resources()
+    {
+        Some(resources) => resources,
+        None => return Err(Cause::QoSNotSupported)
+    };
+
+    if cache_percent > 100 || bandwidth_percent > 100
+    {
+        return Err(Cause::QoSBadShare);
+    }
+
+    let blocks = core::cmp::min(blocks, usize::BITS as usize);
+    let mut partitions = PARTITIONS.lock();
+
+    /* work out what's already taken */
+    let mut used_blocks = 0;
+    let mut used_units = 0;
+    let mut used_rcids = 1; /* the default ID is always taken */
+    for (_, p) in partitions.iter()
+    {
+        used_blocks = used_blocks | p.cache_mask;
+        used_units = used_units + p.bandwidth;
+        used_rcids = used_rcids + 1;
+    }
+
+    if used_rcids >= RCIDS_MAX
+    {
+        return Err(Cause::QoSExhausted);
+    }
+
+    /* round up so that any non-zero share gets at least one block or unit */
+    let cache_mask = match (blocks * cache_percent + 99) / 100
+    {
+        0 => 0,
+        needed => match find_blocks(used_blocks, blocks, needed)
+        {
+            Some(mask) => mask,
+            None => return Err(Cause::QoSExhausted)
+        }
+    };
+    let bandwidth = (units * bandwidth_percent + 99) / 100;
+
+    /* always leave something for everyone else */
+    if (cache_mask != 0 && (used_blocks | cache_mask).count_ones() as usize >= blocks) ||
+       (bandwidth != 0 && used_units + bandwidth >= units)
+    {
+        return Err(Cause::QoSExhausted);
+    }
+
+    /* pick the lowest free resource control ID */
+    let rcid = match (1..RCIDS_MAX).find(|id| partitions.values().all(|p| p.rcid != *id))
+    {
+        Some(id) => id,
+        None => return Err(Cause::QoSExhausted)
+    };
+
+    hardware::set_qos_allocation(rcid, cache_mask, bandwidth)?;
+    partitions.insert(cid, Partition { rcid, cache_mask, bandwidth });
+    update_default(&partitions, blocks, units)
+}
+
+/* return a capsule's cache blocks and bandwidth to the default partition when it's destroyed */
+pub fn release(cid: CapsuleID)
+{
+    let mut partitions = PARTITIONS.lock();
+    if partitions.remove(&cid).is_none()
+    {
+        return;
+    }
+
+    if let Some((blocks, units)) = hardware::get_qos_resources()
+    {
+        if let Err(_e) = update_default(&partitions, core::cmp::min(blocks, usize::BITS as usize), units)
+        {
+            hvalert!("Failed to return capsule {}'s cache and bandwidth partition: {}", cid, error::report(&_e));
+        }
+    }
+}
+
+/* give the default resource control ID whatever isn't reserved */
+fn update_default(partitions: &HashMap<CapsuleID, Partition>, blocks: usize, units: usize) -> Result<(), Cause>
+{
+    let all = if blocks >= usize::BITS as usize { usize::MAX } else { (1 << blocks) - 1 };
+    let mut used_blocks = 0;
+    let mut used_units = 0;
+    for (_, p) in partitions.iter()
+    {
+        used_blocks = used_blocks | p.cache_mask;
+        used_units = used_units + p.bandwidth;
+    }
+
+    hardware::set_qos_allocation(RCID_DEFAULT, all & !used_blocks, units - used_units)
+}
+
+/* tag this physical CPU core with the given capsule's resource control ID.
+   call this when switching to the capsule */
+pub fn enforce(cid: CapsuleID)
+{
+    let rcid = match PARTITIONS.lock().get(&cid)
+    {
+        Some(p) => p.rcid,
+        None => RCID_DEFAULT
+    };
+
+    cbqri::set_qos_id(rcid);
+}
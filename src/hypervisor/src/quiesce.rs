@@ -0,0 +1,207 @@
+/* diosix guest quiesce protocol for consistent snapshots
+ *
+ * A copy of a running capsule's RAM is only as consistent as the guest
+ * leaves it: filesystems may have writes cached but not yet written
+ * back, and devices may be part way through DMA. So before taking the
+ * final copy of a snapshot or migration, a management capsule can ask
+ * a guest to quiesce, giving it a timeout in milliseconds.
+ *
+ * The guest is sent VIRQ_QUIESCE. A cooperative guest flushes its caches
+ * and filesystems, stops starting new device activity, waits for what's
+ * in flight to finish, and then tells the hypervisor it's quiesced by
+ * hypercall. It carries on running, but should leave its memory alone
+ * as far as it can until it's thawed. A guest that doesn't respond in
+ * time is treated as uncooperative, and is paused where it stands, as
+ * management capsules would otherwise have to do themselves: its memory
+ * then stops changing, though its filesystems and devices may be left
+ * inconsistent. It isn't reported as crashed.
+ *
+ * The management capsule polls the guest's quiesce status, takes its
+ * final copy, such as the last pass over the dirty log, and then thaws
+ * the guest. A guest that quiesced itself is sent VIRQ_THAW so it can
+ * resume its device activity, and one that was paused is resumed.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID, CapsuleProperty, CapsuleState};
+use super::passthrough::{self, DeviceIRQ};
+use super::scheduler;
+use super::pcore;
+use platform::timer::TimerValue;
+
+/* virtual interrupts raised to ask a guest to quiesce, and to tell it it can carry on */
+pub const VIRQ_QUIESCE: DeviceIRQ = hypercall::irq::VIRQ_QUIESCE;
+pub const VIRQ_THAW: DeviceIRQ = hypercall::irq::VIRQ_THAW;
+
+/* how far a quiesce has got, shared with the capsules */
+pub use hypercall::quiesce::{QUIESCE_PENDING, QUIESCE_DONE, QUIESCE_FORCED};
+
+/* longest a guest can be given to quiesce, in milliseconds */
+const QUIESCE_TIMEOUT_MAX: u64 = 60 * 1000;
+
+/* how far a guest has got */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Status
+{
+    Pending,    /* asked to quiesce, and yet to respond */
+    Done,       /* quiesced itself */
+    Forced      /* didn't respond in time, so was paused */
+}
+
+/* a guest asked to quiesce */
+struct Request
+{
+    status: Status,
+    deadline: u64   /* timer value when the guest's time to respond runs out, in exact ticks */
+}
+
+lazy_static!
+{
+    static ref REQUESTS: Mutex<HashMap<CapsuleID, Request>> = Mutex::new("guest quiesce requests", HashMap::new());
+}
+
+/* ask a running capsule to quiesce itself ready for a snapshot
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to quiesce
+      timeout = milliseconds to give it to respond before it's paused
+   <= Ok for success, or an error code */
+pub fn request(cid: CapsuleID, timeout: u64) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match capsule::get_state(cid)
+    {
+        Some(CapsuleState::Valid) => (),
+        Some(_) => return Err(Cause::QuiesceNotRunning),
+        None => return Err(Cause::CapsuleBadID)
+    }
+
+    let timeout = core::cmp::min(timeout, QUIESCE_TIMEOUT_MAX);
+    let deadline = match scheduler::timer_now()
+    {
+        Some((now, frequency)) => now + TimerValue::Milliseconds(timeout).to_exact(frequency),
+        None => 0 /* without a timer, the guest can't be timed out */
+    };
+
+    {
+        let mut requests = REQUESTS.lock();
+        if requests.contains_key(&cid)
+        {
+            return Err(Cause::QuiesceInProgress);
+        }
+        requests.insert(cid, Request { status: Status::Pending, deadline });
+    }
+
+    passthrough::raise_virtual_irq(cid, VIRQ_QUIESCE);
+    hvdebug!("Capsule {} asked to quiesce within {} ms", cid, timeout);
+    Ok(())
+}
+
+/* tell the hypervisor the currently running capsule has quiesced itself
+   <= Ok for success, or an error code if it wasn't asked to */
+pub fn done() -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    match REQUESTS.lock().get_mut(&cid)
+    {
+        Some(request) if request.status == Status::Pending => request.status = Status::Done,
+        _ => return Err(Cause::QuiesceNotRequested)
+    }
+
+    hvdebug!("Capsule {} quiesced", cid);
+    Ok(())
+}
+
+/* check how far a capsule has got with quiescing
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule asked to quiesce
+   <= QUIESCE_PENDING, QUIESCE_DONE, or QUIESCE_FORCED, or an error code if it wasn't asked to */
+pub fn status(cid: CapsuleID) -> Result<usize, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match REQUESTS.lock().get(&cid)
+    {
+        Some(request) => Ok(match request.status
+        {
+            Status::Pending => QUIESCE_PENDING,
+            Status::Done => QUIESCE_DONE,
+            Status::Forced => QUIESCE_FORCED
+        }),
+        None => Err(Cause::QuiesceNotRequested)
+    }
+}
+
+/* let a capsule carry on after it was asked to quiesce, whether or not it has yet
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to thaw
+   <= Ok for success, or an error code if it wasn't asked to quiesce */
+pub fn thaw(cid: CapsuleID) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    let status = match REQUESTS.lock().remove(&cid)
+    {
+        Some(request) => request.status,
+        None => return Err(Cause::QuiesceNotRequested)
+    };
+
+    match status
+    {
+        /* a paused capsule may already have been resumed or killed by a management capsule */
+        Status::Forced => match capsule::resume(cid)
+        {
+            Ok(()) | Err(Cause::CapsuleNotPaused) => (),
+            Err(e) => return Err(e)
+        },
+        _ => passthrough::raise_virtual_irq(cid, VIRQ_THAW)
+    }
+
+    hvdebug!("Capsule {} thawed", cid);
+    Ok(())
+}
+
+/* pause capsules that haven't quiesced in time. call this regularly from the boot core */
+pub fn housekeeper()
+{
+    let now = match scheduler::timer_now()
+    {
+        Some((now, _)) => now,
+        None => return
+    };
+
+    let mut expired = Vec::new();
+    for (cid, request) in REQUESTS.lock().iter_mut()
+    {
+        if request.status == Status::Pending && now >= request.deadline
+        {
+            request.status = Status::Forced;
+            expired.push(*cid);
+        }
+    }
+
+    /* don't hold the requests lock while pausing the capsules */
+    for cid in expired
+    {
+        hvalert!("Capsule {} didn't quiesce in time: pausing it", cid);
+        if let Err(_e) = capsule::pause(cid)
+        {
+            hvalert!("Can't pause capsule {}: {}", cid, error::report(&_e));
+        }
+    }
+}
+
+/* discard a capsule's quiesce request when it's restarted or destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    REQUESTS.lock().remove(&cid);
+}
@@ -0,0 +1,281 @@
+/* diosix emulated real-time clock for capsules
+ *
+ * Give each capsule a goldfish RTC, the simple MMIO clock Qemu's virt
+ * machine provides and Linux drives out of the box, so that guests can
+ * read the wall-clock time without a diosix-specific driver. The clock
+ * is built into the hypervisor as a device model, and is described in
+ * each capsule's device tree like any other emulated device.
+ *
+ * The clock reads the capsule's wall-clock time from the time service:
+ * the host's RTC if it has one, or else the epoch set by the capsule's
+ * rtc_epoch property, either running on with the monotonic counter. If
+ * neither is available, the clock counts from the Unix epoch at power
+ * up. Setting the clock only moves this capsule's RTC, and not the time
+ * given out by the time service's hypercalls.
+ *
+ * The device's registers are 32 bits wide:
+ *
+ *   0x00  time low        read for the bottom half of the time in nanoseconds
+ *                         since 1970, latching the top half. write to set it
+ *   0x04  time high       top half of the time latched by reading time low.
+ *                         write to set it
+ *   0x08  alarm low       write to set the bottom half of the alarm time and arm it
+ *   0x0c  alarm high      top half of the alarm time
+ *   0x10  irq enabled     1 to interrupt the capsule when the alarm fires
+ *   0x14  clear alarm     write to disarm the alarm
+ *   0x18  alarm status    1 if the alarm is armed
+ *   0x1c  clear interrupt write to acknowledge the alarm's interrupt
+ *
+ * Alarms are checked during housekeeping, and so fire up to a
+ * housekeeping period late, or straight away if they're set in the past.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::PhysMemSize;
+use super::capsule::CapsuleID;
+use super::passthrough::{self, DeviceIRQ};
+use super::devmodel::Config;
+use super::clock;
+
+/* name of the device model, its device tree compatible string, and the size of its registers */
+pub const MODEL_NAME: &str = "goldfish-rtc";
+pub const COMPATIBLE: &str = "google,goldfish-rtc";
+pub const WINDOW_SIZE: PhysMemSize = 0x1000;
+
+/* register offsets */
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+/* width of each register in bytes */
+const REGISTER_WIDTH: usize = 4;
+
+/* returned by create functions that fail */
+const CREATE_FAILED: usize = usize::MAX;
+
+/* an instance of the clock attached to a capsule */
+struct Rtc
+{
+    cid: CapsuleID,
+    irq: Option<DeviceIRQ>, /* interrupt raised when the alarm fires, if the clock has one */
+    offset: i128,           /* nanoseconds the capsule has moved its clock by */
+    time_high: u32,         /* top half of the time, latched when the bottom half is read */
+    alarm: u64,             /* nanoseconds since 1970 at which the alarm fires */
+    armed: bool,            /* alarm will fire when due */
+    irq_enabled: bool,      /* interrupt the capsule when the alarm fires */
+    irq_pending: bool       /* alarm has fired and the capsule hasn't acknowledged it */
+}
+
+impl Rtc
+{
+    /* <= the time this clock reads, in nanoseconds since 1970 */
+    fn now(&self) -> u64
+    {
+        let host = match clock::wall_clock(self.cid)
+        {
+            Ok(now) => now,
+            Err(_) => clock::monotonic().unwrap_or(0)
+        };
+
+        let now = host as i128 + self.offset;
+        match now < 0
+        {
+            true => 0,
+            false => core::cmp::min(now, u64::MAX as i128) as u64
+        }
+    }
+
+    /* move the clock so it reads the given time
+       => time = nanoseconds since 1970 the clock should read now */
+    fn set(&mut self, time: u64)
+    {
+        self.offset = self.offset + (time as i128 - self.now() as i128);
+    }
+
+    /* fire the alarm if it's due
+       <= true to interrupt the capsule */
+    fn fire_if_due(&mut self) -> bool
+    {
+        if self.armed == true && self.now() >= self.alarm
+        {
+            self.armed = false;
+            self.irq_pending = true;
+            return self.irq_enabled;
+        }
+        false
+    }
+}
+
+/* handles given out to the device model code for each instance */
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static!
+{
+    static ref CLOCKS: Mutex<HashMap<usize, Rtc>> = Mutex::new("emulated RTCs", HashMap::new());
+}
+
+/* interrupt the capsule owning the given clock, if it has an interrupt */
+fn interrupt(cid: CapsuleID, irq: Option<DeviceIRQ>)
+{
+    if let Some(irq) = irq
+    {
+        passthrough::raise_virtual_irq(cid, irq);
+    }
+}
+
+/* create an instance of the clock for a capsule
+   => cid = capsule the clock is attached to
+      irq = interrupt to raise when the alarm fires, or None for none
+   <= handle of the new instance */
+fn create_instance(cid: CapsuleID, irq: Option<DeviceIRQ>) -> usize
+{
+    let instance = NEXT_INSTANCE.fetch_add(1, Ordering::SeqCst);
+    if instance == CREATE_FAILED
+    {
+        return CREATE_FAILED;
+    }
+
+    CLOCKS.lock().insert(instance, Rtc
+    {
+        cid, irq, offset: 0, time_high: 0, alarm: 0, armed: false, irq_enabled: false, irq_pending: false
+    });
+    instance
+}
+
+/* the device model interface, called by the device model code as it would a plugin's */
+
+pub extern "C" fn create(cid: usize) -> usize
+{
+    create_instance(cid, None)
+}
+
+pub extern "C" fn create_configured(cid: usize, config: *const Config) -> usize
+{
+    match unsafe { (*config).irq }
+    {
+        usize::MAX => create_instance(cid, None),
+        irq => create_instance(cid, Some(irq))
+    }
+}
+
+pub extern "C" fn destroy(instance: usize)
+{
+    CLOCKS.lock().remove(&instance);
+}
+
+pub extern "C" fn read(instance: usize, offset: usize, width: usize) -> usize
+{
+    let mut clocks = CLOCKS.lock();
+    let rtc = match clocks.get_mut(&instance)
+    {
+        Some(rtc) if width == REGISTER_WIDTH => rtc,
+        _ => return 0
+    };
+
+    match offset
+    {
+        TIME_LOW =>
+        {
+            let now = rtc.now();
+            rtc.time_high = (now >> 32) as u32;
+            now as u32 as usize
+        },
+        TIME_HIGH => rtc.time_high as usize,
+        ALARM_LOW => rtc.alarm as u32 as usize,
+        ALARM_HIGH => (rtc.alarm >> 32) as usize,
+        IRQ_ENABLED => rtc.irq_enabled as usize,
+        ALARM_STATUS => rtc.armed as usize,
+        _ => 0
+    }
+}
+
+pub extern "C" fn write(instance: usize, offset: usize, width: usize, value: usize)
+{
+    let value = value as u32 as u64;
+    let (cid, irq, raise) =
+    {
+        let mut clocks = CLOCKS.lock();
+        let rtc = match clocks.get_mut(&instance)
+        {
+            Some(rtc) if width == REGISTER_WIDTH => rtc,
+            _ => return
+        };
+
+        let raise = match offset
+        {
+            TIME_LOW =>
+            {
+                let now = rtc.now();
+                rtc.set((now & !0xffffffff) | value);
+                false
+            },
+            TIME_HIGH =>
+            {
+                let now = rtc.now();
+                rtc.set((now & 0xffffffff) | (value << 32));
+                false
+            },
+            ALARM_LOW =>
+            {
+                rtc.alarm = (rtc.alarm & !0xffffffff) | value;
+                rtc.armed = true;
+                rtc.fire_if_due()
+            },
+            ALARM_HIGH =>
+            {
+                rtc.alarm = (rtc.alarm & 0xffffffff) | (value << 32);
+                false
+            },
+            IRQ_ENABLED =>
+            {
+                let enabled = value & 1 == 1;
+                let raise = enabled == true && rtc.irq_enabled == false && rtc.irq_pending == true;
+                rtc.irq_enabled = enabled;
+                raise
+            },
+            CLEAR_ALARM =>
+            {
+                rtc.armed = false;
+                false
+            },
+            CLEAR_INTERRUPT =>
+            {
+                rtc.irq_pending = false;
+                false
+            },
+            _ => false
+        };
+
+        (rtc.cid, rtc.irq, raise)
+    };
+
+    if raise == true
+    {
+        interrupt(cid, irq);
+    }
+}
+
+/* fire any alarms that have fallen due. call this regularly from the boot core */
+pub fn housekeeper()
+{
+    let fired: Vec<(CapsuleID, Option<DeviceIRQ>)> = CLOCKS.lock().values_mut()
+        .filter_map(|rtc| if rtc.fire_if_due() == true { Some((rtc.cid, rtc.irq)) } else { None })
+        .collect();
+
+    for (cid, irq) in fired
+    {
+        interrupt(cid, irq);
+    }
+}
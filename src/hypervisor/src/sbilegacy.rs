@@ -0,0 +1,101 @@
+/* diosix compatibility shim for legacy SBI v0.1 calls
+ *
+ * Older guest kernels use the legacy SBI v0.1 console, timer,
+ * and shutdown calls, which have a different calling convention
+ * to the current SBI: the extension ID selects the function,
+ * and the result is returned in the first argument register.
+ * The timer and console calls are decoded as ordinary hypercalls
+ * in syscalls.rs whether or not this is built. This shim goes
+ * further, translating these calls and shutdown into the
+ * hypervisor's existing console, timer, and capsule paths before
+ * they're decoded, and returning results the way the legacy
+ * calls do, so these guests can boot unmodified.
+ *
+ * This is only built with the sbilegacy feature enabled.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use platform::irq::IRQContext;
+use super::syscalls;
+use super::trap::{self, REG_A0, REG_A7};
+use platform::timer::TimerValue;
+use super::error::{self, Cause};
+use super::capsule;
+use super::hardware;
+use super::pcore;
+use super::scheduler;
+
+/* legacy SBI v0.1 extension IDs */
+const LEGACY_SET_TIMER: usize = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+const LEGACY_SHUTDOWN: usize = 0x08;
+
+/* highest extension ID of the legacy calls. the current SBI's extension IDs are all above this */
+const LEGACY_LAST: usize = 0x08;
+
+/* legacy calls return -2 for not supported */
+const LEGACY_NOT_SUPPORTED: usize = usize::MAX - 1;
+
+/* handle a supervisor environment call if it's a legacy SBI call we can translate
+   => context = context of the environment call
+   <= true if the call was handled here, false to pass it on to the usual syscall handler */
+pub fn handler(context: &mut IRQContext) -> bool
+{
+    /* legacy calls pass their extension ID in a7 and their only argument in a0 */
+    let eid = trap::register(context, REG_A7);
+    if eid > LEGACY_LAST
+    {
+        return false;
+    }
+    let arg = trap::register(context, REG_A0);
+
+    match eid
+    {
+        /* program the next supervisor timer interrupt */
+        LEGACY_SET_TIMER =>
+        {
+            let target = scheduler::limit_timer_target(TimerValue::Exact(arg as u64));
+            pcore::PhysicalCore::set_virtualcore_timer_target(Some(target));
+            if scheduler::timer_coalesces(target) == false
+            {
+                hardware::scheduler_timer_at(target);
+            }
+            syscalls::result_as_error(context, 0);
+        },
+
+        /* write a character to the capsule's console */
+        LEGACY_CONSOLE_PUTCHAR => match capsule::putc(arg as u8)
+        {
+            Ok(_) => syscalls::result_as_error(context, 0),
+            Err(_) => syscalls::result_as_error(context, usize::MAX)
+        },
+
+        /* read a character from the capsule's console, or -1 for none */
+        LEGACY_CONSOLE_GETCHAR => match capsule::getc()
+        {
+            Ok(c) => syscalls::result_as_error(context, c as usize),
+            Err(Cause::CapsuleBufferEmpty) => syscalls::result_as_error(context, usize::MAX),
+            Err(_) => syscalls::result_as_error(context, usize::MAX)
+        },
+
+        /* legacy shutdown kills the whole capsule */
+        LEGACY_SHUTDOWN => if let Err(_e) = capsule::destroy_current()
+        {
+            hvalert!("BUG: Failed to terminate capsule via legacy SBI shutdown ({})", error::report(&_e));
+            syscalls::result_as_error(context, usize::MAX);
+        }
+        else
+        {
+            scheduler::ping();
+        },
+
+        /* IPIs and remote fences aren't translated */
+        _ => syscalls::result_as_error(context, LEGACY_NOT_SUPPORTED)
+    }
+
+    true
+}
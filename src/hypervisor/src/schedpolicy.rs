@@ -0,0 +1,115 @@
+/* diosix virtual CPU scheduling policies
+ *
+ * The scheduler in scheduler.rs decides when to make a scheduling
+ * decision, and moves virtual cores between physical cores to balance
+ * their workloads. Which waiting virtual core runs next is left to a
+ * policy, which keeps each physical core's queue of waiting virtual
+ * cores, and the global queue, in whatever order it sees fit. This
+ * means new policies can be tried out without touching the rest of
+ * the scheduler. Each policy implements the Policy trait and is listed
+ * in POLICIES by name. The policies themselves live in the hvalgo crate
+ * so they can be unit tested on the host: this file describes virtual
+ * cores and the hypervisor's timer and settings to them.
+ *
+ * The same policy is used by every queue. It's the two-level round-robin
+ * policy, rr, unless built with the schedfifo feature, and can be chosen
+ * at boot time by adding diosix.sched=<name> to the boot arguments.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use hvalgo::queues::{Schedulable, Environment, Urgency, RoundRobin, Fifo};
+use super::vcore::{VirtualCore, Priority};
+use super::pcore::CoreClass;
+use super::scheduler;
+use super::hardware;
+use super::settings::{self, Setting};
+
+pub use hvalgo::queues::Policy;
+
+/* boot argument prefix that selects a policy by name, eg: diosix.sched=fifo */
+const POLICY_BOOTARG: &str = "diosix.sched=";
+
+/* the available policies, by name */
+const POLICIES: [(&str, fn() -> Box<dyn Policy<VirtualCore>>); 2] =
+[
+    ("rr", || Box::new(RoundRobin::<VirtualCore, HostEnvironment>::new(HostEnvironment))),
+    ("fifo", || Box::new(Fifo::<VirtualCore>::new()))
+];
+
+/* index into POLICIES of the policy in use */
+#[cfg(not(feature = "schedfifo"))]
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "schedfifo")]
+static SELECTED: AtomicUsize = AtomicUsize::new(1);
+
+/* choose the policy from the boot arguments, if one is given there. call this on the
+   boot core before any queues are created, so that they all use the same policy */
+pub fn init()
+{
+    if let Some(args) = hardware::get_boot_args()
+    {
+        if let Some(name) = args.split_whitespace().find_map(|arg| arg.strip_prefix(POLICY_BOOTARG))
+        {
+            match POLICIES.iter().position(|(policy, _)| *policy == name)
+            {
+                Some(index) => SELECTED.store(index, Ordering::SeqCst),
+                None => hvalert!("Unknown scheduling policy {}, using {}", name, POLICIES[SELECTED.load(Ordering::SeqCst)].0)
+            }
+        }
+    }
+
+    hvdebug!("Scheduling virtual CPU cores with the {} policy", POLICIES[SELECTED.load(Ordering::SeqCst)].0);
+}
+
+/* <= a new, empty set of queues using the selected policy */
+pub fn create() -> Box<dyn Policy<VirtualCore>>
+{
+    (POLICIES[SELECTED.load(Ordering::SeqCst)].1)()
+}
+
+/* describe a virtual core to the policies */
+impl Schedulable for VirtualCore
+{
+    type Class = CoreClass;
+
+    fn urgency(&self) -> Urgency
+    {
+        match self.get_priority()
+        {
+            Priority::High => Urgency::High,
+            Priority::Normal => Urgency::Normal,
+            Priority::Deadline(_) => Urgency::Deadline
+        }
+    }
+
+    fn capsule_id(&self) -> usize { self.get_capsule_id() }
+    fn vcore_id(&self) -> usize { self.get_id() }
+    fn class(&self) -> CoreClass { self.get_class() }
+
+    fn deadline_budget_left(&mut self, now: u64, frequency: u64) -> Option<u64>
+    {
+        VirtualCore::deadline_budget_left(self, now, frequency)
+    }
+
+    fn deadline_period_end(&self, frequency: u64) -> Option<u64>
+    {
+        VirtualCore::deadline_period_end(self, frequency)
+    }
+}
+
+/* give the policies the time and their tunables from the hypervisor's timer and settings */
+pub struct HostEnvironment;
+
+impl Environment for HostEnvironment
+{
+    fn timer_now(&self) -> Option<(u64, u64)> { scheduler::timer_now() }
+
+    /* prevent physical CPU time starvation: allow a normal virtual core to run after this number of timeslices
+    have been spent running high priority virtual cores */
+    fn high_priority_run(&self) -> u64 { settings::get(Setting::HighPriorityRun) }
+}
@@ -8,44 +8,269 @@
  * See LICENSE for usage and copying.
  */
 
-use super::lock::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use super::lock::{Mutex, LockStats};
 use alloc::collections::vec_deque::VecDeque;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use hashbrown::hash_map::HashMap;
+use hashbrown::hash_set::HashSet;
 use platform::timer::TimerValue;
-use super::error::Cause;
-use super::vcore::{VirtualCore, Priority};
-use super::pcore::{self, PhysicalCore, PhysicalCoreID};
+use super::error::{self, Cause};
+use super::vcore::{VirtualCore, VirtualCoreID, Deadline};
+use super::pcore::{self, PhysicalCore, PhysicalCoreID, CoreClass};
 use super::hardware;
 use super::message;
-use super::capsule::{self, CapsuleState};
-
-pub type TimesliceCount = u64;
-
-/* prevent physical CPU time starvation: allow a normal virtual core to run after this number of timeslices
-have been spent running high priority virtual cores */
-const HIGH_PRIO_TIMESLICES_MAX: TimesliceCount = 10;
+use super::capsule::{self, CapsuleID, CapsuleState};
+use super::metrics;
+use super::top;
+use super::timerwheel;
+use super::workqueue;
+use super::wss;
+use super::warmboot;
+use super::abboot;
+use super::quiesce;
+use super::button;
+use super::rtc;
+use super::accounting;
+use super::power;
+use super::pressure;
+use super::settings::{self, Setting};
+use super::schedpolicy::{self, Policy};
+#[cfg(feature = "integritychecks")]
+use super::integrity;
+
+/* limit the share of each physical CPU core's time that can be promised to deadline virtual cores,
+in parts per thousand, so that normal and high priority virtual cores can still make progress.
+deadline virtual cores can run on any physical core, so the total that can be promised is this
+share of every core in the system. no one deadline virtual core can have more than this share, though,
+as it only ever runs on one physical core at a time */
+const DEADLINE_UTILIZATION_MAX: u64 = 700;
 
 /* max how long a virtual core is allowed to run before a scheduling decision is made */
-const TIMESLICE_LENGTH: TimerValue = TimerValue::Milliseconds(50);
+fn timeslice_length() -> TimerValue
+{
+    TimerValue::Milliseconds(settings::get(Setting::Timeslice))
+}
 
 /* define the shortest time between now and another interrupt and rescheduling decision.
 this is to stop supervisor kernels spamming the scheduling system with lots of short reschedulings */
 const TIMESLICE_MIN_LENGTH: TimerValue = TimerValue::Milliseconds(5);
 
+/* shortest time allowed between arming a capsule's timer and its firing, in microseconds, unless the
+capsule is given a longer interval with timer_min_interval. this is to stop supervisor kernels
+flooding the host with timer interrupts. capsules can't ask for an interval below the floor */
+const TIMER_MIN_INTERVAL_DEFAULT: u64 = 100;
+const TIMER_MIN_INTERVAL_FLOOR: u64 = 10;
+
+/* timer IRQs due within this long before an already-armed timer interrupt are delivered with it */
+pub const TIMER_COALESCE_WINDOW: TimerValue = TimerValue::Milliseconds(1);
+
 /* duration a system maintence core (one that can't run supervisor code) must wait
 before looking for fixed work to do. also the length in between application cores can
 attempt to perform housekeeping */
-const MAINTENANCE_LENGTH: TimerValue = TimerValue::Seconds(5);
+fn maintenance_length() -> TimerValue
+{
+    TimerValue::Milliseconds(settings::get(Setting::HousekeepingPeriod))
+}
+
+/* physical cores that can run virtual cores are parked in a low-power wait when there's too little
+work to go round, and woken when there's more. boot argument that keeps every core active */
+const PARKING_OFF_BOOTARG: &str = "diosix.noparking";
+
+/* wake a parked core when there are more than this many virtual cores per active physical core */
+const UNPARK_VCORES_PER_PCORE: usize = 2;
+
+/* park an idle core when the other active cores would have no more than this many virtual cores each */
+const PARK_VCORES_PER_PCORE: usize = 1;
 
-/* these are the global wait queues. while each physical CPU core gets its own pair
-of high-normal wait queues, virtual cores waiting to be assigned to a physical CPU sit in these global queues.
+/* after a core is parked or woken, wait this many housekeeping periods before parking another */
+const PARK_HOLD_PERIODS: usize = 3;
+
+/* housekeeping periods left before another core can be parked */
+static PARK_HOLD: AtomicUsize = AtomicUsize::new(PARK_HOLD_PERIODS);
+
+/* these are the global wait queues. while each physical CPU core gets its own set
+of wait queues, virtual cores waiting to be assigned to a physical CPU sit in these global queues.
 when a physical CPU runs out of queued virtual cores, it pulls one from these global queues.
 a physical CPU core can ask fellow CPUs to push virtual cores onto the global queues via messages */
 lazy_static!
 {
-    static ref GLOBAL_QUEUES: Mutex<ScheduleQueues> = Mutex::new("global scheduler queue", ScheduleQueues::new());
+    static ref GLOBAL_QUEUES: Mutex<Box<dyn Policy<VirtualCore>>> = Mutex::new("global scheduler queue", schedpolicy::create());
     static ref WORKLOAD: Mutex<HashMap<PhysicalCoreID, usize>> = Mutex::new("workload balancer", HashMap::new());
-    static ref LAST_HOUSEKEEP_CHECK: Mutex<TimerValue> = Mutex::new("housekeeper tracking", TimerValue::Exact(0));
+
+    /* new virtual cores placed directly onto physical cores, waiting to be adopted into their queues */
+    static ref PLACED: Mutex<HashMap<PhysicalCoreID, VecDeque<VirtualCore>>> = Mutex::new("placed vcore table", HashMap::new());
+    static ref DEADLINE_UTILIZATION: Mutex<u64> = Mutex::new("deadline admission control", 0);
+
+    /* physical cores parked in a low-power wait. these are left out of WORKLOAD so nothing is placed on them */
+    static ref PARKED: Mutex<HashSet<PhysicalCoreID>> = Mutex::new("parked physical core set", HashSet::new());
+
+    /* performance class of each physical core able to run virtual cores */
+    static ref CORE_CLASSES: Mutex<HashMap<PhysicalCoreID, CoreClass>> = Mutex::new("physical core classes", HashMap::new());
+}
+
+/* set once an efficiency core starts scheduling. until then, every core is a performance
+   core and virtual cores' class preferences can be ignored without taking any locks */
+static MIXED_CLASSES: AtomicBool = AtomicBool::new(false);
+
+/* when system-wide housekeeping is next due, in exact timer ticks, and whether a physical core is carrying it out */
+static SYSTEM_HOUSEKEEPING_DUE: AtomicU64 = AtomicU64::new(0);
+static SYSTEM_HOUSEKEEPING_BUSY: AtomicBool = AtomicBool::new(false);
+
+/* calculate the share of a physical CPU core's time a deadline needs, in parts per thousand, rounding up
+   => deadline = period and budget requested by the virtual core
+   <= the share, or an error code if the period is zero or the values are too large to calculate it */
+fn deadline_utilization(deadline: Deadline) -> Result<u64, Cause>
+{
+    if deadline.period == 0
+    {
+        return Err(Cause::SchedDeadlineBad);
+    }
+
+    match deadline.budget.checked_mul(1000).and_then(|scaled| scaled.checked_add(deadline.period - 1))
+    {
+        Some(rounded) => Ok(rounded / deadline.period),
+        None => Err(Cause::SchedDeadlineBad)
+    }
+}
+
+/* admit a deadline virtual core if its budget can be guaranteed alongside those already admitted
+   across the system's physical cores
+   => deadline = period and budget requested by the virtual core
+   <= Ok if admitted, or an error code */
+pub fn admit_deadline(deadline: Deadline) -> Result<(), Cause>
+{
+    if deadline.period == 0 || deadline.budget == 0 || deadline.budget > deadline.period
+    {
+        return Err(Cause::SchedDeadlineBad);
+    }
+
+    let needed = deadline_utilization(deadline)?;
+    if needed > DEADLINE_UTILIZATION_MAX
+    {
+        return Err(Cause::SchedDeadlineRejected);
+    }
+
+    let cores = match hardware::get_nr_cpu_cores()
+    {
+        Some(cores) if cores > 0 => cores as u64,
+        _ => 1
+    };
+
+    let mut total = DEADLINE_UTILIZATION.lock();
+    if *total + needed > DEADLINE_UTILIZATION_MAX * cores
+    {
+        return Err(Cause::SchedDeadlineRejected);
+    }
+
+    *total = *total + needed;
+    Ok(())
+}
+
+/* release the share of CPU time held by a deadline virtual core */
+pub fn release_deadline(deadline: Deadline)
+{
+    let mut total = DEADLINE_UTILIZATION.lock();
+    /* a deadline that was admitted had its share calculated without overflowing */
+    *total = total.saturating_sub(deadline_utilization(deadline).unwrap_or(0));
+}
+
+/* return the current time and timer frequency in exact ticks, or None if there's no timer */
+pub fn timer_now() -> Option<(u64, u64)>
+{
+    match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
+    {
+        (Some(now), Some(frequency)) => Some((now.to_exact(frequency), frequency)),
+        (_, _) => None
+    }
+}
+
+/* <= the earliest a timer requested by the given capsule may fire, in exact timer ticks, which is
+      the capsule's minimum timer interval from now, or None if the timer isn't available */
+fn earliest_timer_target(cid: CapsuleID) -> Option<u64>
+{
+    let (now, frequency) = timer_now()?;
+    let interval = match capsule::get_timer_min_interval(cid)
+    {
+        Ok(Some(us)) => core::cmp::max(us, TIMER_MIN_INTERVAL_FLOOR),
+        _ => TIMER_MIN_INTERVAL_DEFAULT
+    };
+
+    Some(now + ((interval * frequency) / 1000000))
+}
+
+/* push back a timer target requested by the running capsule so that it doesn't fire sooner than
+   the capsule's minimum timer interval from now, and account for it
+   => target = when the capsule wants the timer to fire
+   <= when the timer should fire */
+pub fn limit_timer_target(target: TimerValue) -> TimerValue
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return target
+    };
+    metrics::count(cid, metrics::Counter::TimerRequests);
+
+    let (earliest, frequency) = match (earliest_timer_target(cid), timer_now())
+    {
+        (Some(earliest), Some((_, frequency))) => (earliest, frequency),
+        (_, _) => return target
+    };
+
+    if target.to_exact(frequency) < earliest
+    {
+        metrics::count(cid, metrics::Counter::TimerClamped);
+        return TimerValue::Exact(earliest);
+    }
+
+    target
+}
+
+/* push back a supervisor timer compare value, programmed by a capsule directly into the register
+   on a core with Sstc, so that it doesn't fire sooner than the capsule's minimum timer interval
+   from now. this is applied as the capsule's virtual core is switched in
+   => cid = capsule that programmed the compare value
+      compare = the compare value, in exact timer ticks
+   <= compare value to load into the register */
+pub fn limit_timer_compare(cid: CapsuleID, compare: u64) -> u64
+{
+    match earliest_timer_target(cid)
+    {
+        Some(earliest) if compare < earliest =>
+        {
+            metrics::count(cid, metrics::Counter::TimerClamped);
+            earliest
+        },
+        _ => compare
+    }
+}
+
+/* check whether the physical core's next timer interrupt is due at or shortly after the given
+   timer target, in which case the target can be delivered with that interrupt rather than by
+   arming another. a target is only ever delayed into an interrupt this way, never brought forward
+   => target = when the running capsule's timer should fire
+   <= true if no new timer interrupt is needed */
+pub fn timer_coalesces(target: TimerValue) -> bool
+{
+    let (next, frequency) = match (hardware::scheduler_get_timer_next_at(), hardware::scheduler_get_timer_frequency())
+    {
+        (Some(next), Some(frequency)) => (next.to_exact(frequency), frequency),
+        (_, _) => return false
+    };
+
+    let target = target.to_exact(frequency);
+    if next >= target && next <= target + TIMER_COALESCE_WINDOW.to_exact(frequency)
+    {
+        if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+        {
+            metrics::count(cid, metrics::Counter::TimerCoalesced);
+        }
+        return true;
+    }
+
+    false
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -61,11 +286,240 @@ pub fn queue(to_queue: VirtualCore)
     GLOBAL_QUEUES.lock().queue(to_queue);
 }
 
+/* place a new virtual core directly onto the least-loaded physical core able to run it,
+   rather than leave it in the global queue for whichever core looks there first. this cuts
+   the time before a new capsule runs its first instruction. each placement counts towards
+   the chosen core's workload so that a burst of new vcores is spread across the system.
+   if no physical cores have started scheduling yet, the vcore is queued globally */
+pub fn place(vcore: VirtualCore)
+{
+    let target =
+    {
+        /* prefer the least-loaded core of the class the vcore wants, if there are any running */
+        let mut workloads = WORKLOAD.lock();
+        let classes = CORE_CLASSES.lock();
+        let least = workloads.iter()
+            .filter(|(pid, _)| classes.get(pid) == Some(&vcore.get_class()))
+            .min_by_key(|(_, count)| **count)
+            .or_else(|| workloads.iter().min_by_key(|(_, count)| **count))
+            .map(|(pid, _)| *pid);
+        drop(classes);
+        if let Some(pid) = least
+        {
+            if let Some(count) = workloads.get_mut(&pid)
+            {
+                *count = *count + 1;
+            }
+        }
+        least
+    };
+
+    let pid = match target
+    {
+        Some(pid) => pid,
+        None =>
+        {
+            queue(vcore);
+            return;
+        }
+    };
+
+    PLACED.lock().entry(pid).or_insert(VecDeque::new()).push_back(vcore);
+
+    /* bring in a parked core straight away if the active ones are now too busy */
+    unpark_if_busy();
+
+    /* get the chosen core's attention if it's idle */
+    if pid != PhysicalCore::get_id()
+    {
+        if let Ok(m) = message::Message::new(message::Recipient::send_to_pcore(pid), message::MessageContent::Wakeup)
+        {
+            if let Err(_e) = message::send(m)
+            {
+                hvalert!("Failed to wake physical CPU {} for a new virtual core: {}", pid, error::report(&_e));
+            }
+        }
+    }
+}
+
+/* decide whether a virtual core descheduled from this physical core should be returned to the global
+   queue, rather than this core's queue, so that a core of the class it prefers can pick it up
+   => vcore = virtual core being descheduled
+   <= true if it's on the wrong class of core and an active core of its preferred class exists */
+pub fn should_migrate(vcore: &VirtualCore) -> bool
+{
+    if MIXED_CLASSES.load(Ordering::Relaxed) == false || vcore.get_class() == PhysicalCore::get_class()
+    {
+        return false;
+    }
+
+    let workloads = WORKLOAD.lock();
+    let classes = CORE_CLASSES.lock();
+    workloads.keys().any(|pid| classes.get(pid) == Some(&vcore.get_class()))
+}
+
+/* zero every physical core's workload count, such as when all capsules have been destroyed during a warm reboot */
+pub fn reset_workload()
+{
+    for (_, count) in WORKLOAD.lock().iter_mut()
+    {
+        *count = 0;
+    }
+}
+
+/* stop counting a virtual core towards this physical core's workload, such as when it's
+   destroyed or handed back to the global queue */
+pub fn release_vcore()
+{
+    if let Some(count) = WORKLOAD.lock().get_mut(&PhysicalCore::get_id())
+    {
+        *count = count.saturating_sub(1);
+    }
+}
+
+/* return the accounting of the scheduler's global queue and workload balancer locks */
+pub fn lock_stats() -> [LockStats; 2]
+{
+    [GLOBAL_QUEUES.stats(), WORKLOAD.stats()]
+}
+
+/* return the number of virtual cores running or waiting to run, and the number of active physical cores */
+fn demand() -> (usize, usize)
+{
+    let queued = GLOBAL_QUEUES.lock().total_queued();
+    let workloads = WORKLOAD.lock();
+    (workloads.values().sum::<usize>() + queued, workloads.len())
+}
+
+/* wake a parked physical core if there are more virtual cores than the active cores should handle */
+fn unpark_if_busy()
+{
+    if settings::get(Setting::Parking) == 0
+    {
+        return;
+    }
+
+    let (vcores, active) = demand();
+    if vcores <= active * UNPARK_VCORES_PER_PCORE
+    {
+        return;
+    }
+
+    let pid =
+    {
+        let mut parked = PARKED.lock();
+        match parked.iter().min().cloned()
+        {
+            Some(pid) =>
+            {
+                parked.remove(&pid);
+                pid
+            },
+            None => return
+        }
+    };
+
+    WORKLOAD.lock().insert(pid, 0);
+    PARK_HOLD.store(PARK_HOLD_PERIODS, Ordering::SeqCst);
+    hvdebug!("Waking parked physical CPU core {} for {} virtual cores", pid, vcores);
+
+    match message::Message::new(message::Recipient::send_to_pcore(pid), message::MessageContent::Unpark)
+    {
+        Ok(m) => if let Err(_e) = message::send(m)
+        {
+            hvalert!("Failed to wake parked physical CPU {}: {}", pid, error::report(&_e));
+        },
+        Err(_e) => hvalert!("Failed to wake parked physical CPU {}: {}", pid, error::report(&_e))
+    }
+}
+
+/* park an idle physical core if the other active cores can handle the virtual cores between them.
+   the boot core is never parked as it carries out the system's housekeeping */
+fn park_if_idle()
+{
+    if settings::get(Setting::Parking) == 0
+    {
+        return;
+    }
+
+    /* don't change the set of active cores too often */
+    if PARK_HOLD.load(Ordering::SeqCst) > 0
+    {
+        PARK_HOLD.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    let (vcores, active) = demand();
+    if active < 2 || vcores > (active - 1) * PARK_VCORES_PER_PCORE
+    {
+        return;
+    }
+
+    let queued = GLOBAL_QUEUES.lock().total_queued();
+    let mut workloads = WORKLOAD.lock();
+    let idle = workloads.iter()
+        .filter(|&(&pid, &count)| count == 0 && pid != pcore::BOOT_PCORE_ID && pcore::get_running_capsule(pid).is_none())
+        .map(|(&pid, _)| pid)
+        .max();
+
+    /* leave a core free to pick up any waiting virtual cores */
+    if let (Some(pid), 0) = (idle, queued)
+    {
+        workloads.remove(&pid);
+        PARKED.lock().insert(pid);
+        PARK_HOLD.store(PARK_HOLD_PERIODS, Ordering::SeqCst);
+        hvdebug!("Parking idle physical CPU core {}", pid);
+    }
+}
+
+/* return true if this physical core is parked */
+fn is_parked() -> bool
+{
+    PARKED.lock().contains(&PhysicalCore::get_id())
+}
+
+/* get this physical core looking for work again after being unparked. call when asked to by the boot core */
+pub fn unparked()
+{
+    hardware::scheduler_timer_next_in(TIMESLICE_MIN_LENGTH);
+}
+
 /* activate preemptive multitasking. each physical CPU core should call this
    to start running workloads - be them user/supervisor or management tasks
    <= returns OK, or error code on failure */
 pub fn start() -> Result<(), Cause>
 {
+    /* carry out housekeeping every housekeeping period. the period can be changed at any time,
+       so each round schedules the next rather than repeat at a fixed period */
+    timerwheel::schedule_in(maintenance_length(), housekeep);
+
+    /* queue virtual cores waiting to run on this physical core using the policy chosen at boot */
+    PhysicalCore::set_queues(schedpolicy::create());
+
+    /* let new virtual cores be placed on this physical core if it can run them */
+    if pcore::PhysicalCore::smode_supported() == true
+    {
+        let class = PhysicalCore::classify();
+        CORE_CLASSES.lock().insert(PhysicalCore::get_id(), class);
+        if class == CoreClass::Efficiency
+        {
+            MIXED_CLASSES.store(true, Ordering::SeqCst);
+        }
+
+        WORKLOAD.lock().entry(PhysicalCore::get_id()).or_insert(0);
+    }
+
+    if PhysicalCore::get_id() == pcore::BOOT_PCORE_ID
+    {
+        if let Some(args) = hardware::get_boot_args()
+        {
+            if args.split_whitespace().any(|arg| arg == PARKING_OFF_BOOTARG)
+            {
+                let _ = settings::set(Setting::Parking, 0);
+            }
+        }
+    }
+
     hardware::scheduler_timer_start();
     Ok(())
 }
@@ -75,6 +529,9 @@ pub fn start() -> Result<(), Cause>
    ping() is called when a scheduler timer IRQ comes in */
 pub fn ping()
 {
+    top::sample();
+    telemetry::sample();
+
     let time_now = hardware::scheduler_get_timer_now();
     let frequency = hardware::scheduler_get_timer_frequency();
     if time_now.is_none() || frequency.is_none()
@@ -96,7 +553,7 @@ pub fn ping()
     {
         (Some(v), false) =>
         {
-            let timeslice_length = TIMESLICE_LENGTH.to_exact(frequency);
+            let timeslice_length = timeslice_length().to_exact(frequency);
             let mut last_scheduled_at = v.to_exact(frequency);
 
             /* if the capsule we're running in is valid then perform a time slice check.
@@ -109,9 +566,11 @@ pub fn ping()
                 Some(CapsuleState::Valid) =>
                 {
                     /* check to see if we've reached the end of this physical CPU core's
-                    time slice. a virtual code has the pcore for TIMESLICE_LENGTH of time
-                    before a mandatory scheduling decision is made */
-                    if time_now - last_scheduled_at >= timeslice_length
+                    time slice. a virtual code has the pcore for a timeslice's length of time
+                    before a mandatory scheduling decision is made. a deadline virtual core
+                    that's used up its budget must also make way */
+                    let budget_exhausted = pcore::PhysicalCore::get_virtualcore_deadline_left(time_now, frequency) == Some(0);
+                    if time_now - last_scheduled_at >= timeslice_length || budget_exhausted == true
                     {
                         /* it's been a while since we last made a decision, so force one now */
                         run_next(SearchMode::CheckOnce);
@@ -121,16 +580,17 @@ pub fn ping()
                 },
                 _ =>
                 {
-                    /* it is safe to call destroy_current() and restart_current() multiple times
-                       per vcore until the capsule is dead or restarted */
+                    /* it is safe to call destroy_current(), restart_current(), and pause_current()
+                       multiple times per vcore until the capsule is dead, restarted, or frozen */
                     if let Err(_e) = match capsule_state
                     {
                         Some(CapsuleState::Dying) => capsule::destroy_current(),
                         Some(CapsuleState::Restarting) => capsule::restart_current(),
+                        Some(CapsuleState::Paused) => capsule::pause_current(),
                         _ => Ok(())
                     }
                     {
-                        hvalert!("BUG: Capsule update failure {:?} in scheduler ({:?})", _e, capsule_state)
+                        hvalert!("BUG: Capsule update failure {} in scheduler ({:?})", error::report(&_e), capsule_state)
                     }
 
                     /* capsule we're running in is no longer valid so force a reschedule */
@@ -149,7 +609,9 @@ pub fn ping()
                 let mut timer_target = timer_target.to_exact(frequency);
 
                 /* avoid skipping over any pending supervisor timer IRQ: reduce latency between
-                capsule timer interrupts being raised and capsule cores scheduled to pick up said IRQs */
+                capsule timer interrupts being raised and capsule cores scheduled to pick up said IRQs.
+                on cores with Sstc, the supervisor's own timer fires without us, so only
+                the virtual core's additional timers need waking up for */
                 if let Some(supervisor_target) = pcore::PhysicalCore::get_virtualcore_timer_target()
                 {
                     timer_target = supervisor_target.to_exact(frequency);
@@ -162,6 +624,15 @@ pub fn ping()
                     timer_target = last_scheduled_at + timeslice_length;
                 }
 
+                /* and don't let a deadline virtual core overrun its budget */
+                if let Some(left) = pcore::PhysicalCore::get_virtualcore_deadline_left(time_now, frequency)
+                {
+                    if left > 0 && time_now + left < timer_target
+                    {
+                        timer_target = time_now + left;
+                    }
+                }
+
                 hardware::scheduler_timer_at(TimerValue::Exact(timer_target));
             }
         },
@@ -191,18 +662,38 @@ fn run_next(search_mode: SearchMode)
        because there's no supervisor mode support */
     if pcore::PhysicalCore::smode_supported() == true
     {
+        let previous_capsule = PhysicalCore::get_capsule_id();
+
         /* check for something to do */
         loop
         {
             let mut something_found = true;
 
+            /* adopt any new virtual cores placed directly on this physical core */
+            if let Some(placed) = PLACED.lock().remove(&PhysicalCore::get_id())
+            {
+                for vcore in placed
+                {
+                    PhysicalCore::queue(vcore);
+                }
+            }
+
             /* check to see if there's anything waiting to be picked up for this
-            physical CPU from a global queue. if so, then adopt it so it can get a chance to run */
-            match GLOBAL_QUEUES.lock().dequeue()
+            physical CPU from a global queue. if so, then adopt it so it can get a chance to run.
+            parked cores leave the global queue to the active ones. vcores that prefer this
+            class of core are picked up first */
+            let orphan = match is_parked()
+            {
+                true => None,
+                false => GLOBAL_QUEUES.lock().dequeue_preferring(PhysicalCore::get_class())
+            };
+
+            match orphan
             {
                 /* we've found a virtual CPU core to run, so switch to that */
                 Some(orphan) =>
                 {   
+                    hvtrace!(Schedule, "took capsule {}.{} from the global queue", orphan.get_capsule_id(), orphan.get_id());
                     let mut workloads = WORKLOAD.lock();
                     let pcore_id = PhysicalCore::get_id();
 
@@ -223,7 +714,12 @@ fn run_next(search_mode: SearchMode)
                 /* otherwise, try to take a virtual CPU core waiting for this physical CPU core and run it */
                 _ => match PhysicalCore::dequeue()
                 {
-                    Some(virtcore) => pcore::context_switch(virtcore), /* waiting virtual CPU core found, queuing now */
+                    Some(virtcore) =>
+                    {
+                        /* waiting virtual CPU core found, queuing now */
+                        hvtrace!(Schedule, "took capsule {}.{} from the local queue", virtcore.get_capsule_id(), virtcore.get_id());
+                        pcore::context_switch(virtcore)
+                    },
                     _ => something_found = false /* nothing else to run */
                 }
             }
@@ -231,19 +727,151 @@ fn run_next(search_mode: SearchMode)
             /* if we've found something, or only searching once, exit the search loop */
             if something_found == true || search_mode == SearchMode::CheckOnce
             {
+                if something_found == false
+                {
+                    hvtrace!(Schedule, "nothing new to run");
+                }
                 break;
             }
 
             /* still here? see if there's a capsule waiting to be restarted and give us something to do */
             capsulehousekeeper!();
+
+            /* nothing to run, so get on with any long-running jobs between checks */
+            workqueue::run_one();
+        }
+
+        /* if we've switched to a different capsule, try to bring its sibling vcores along */
+        match PhysicalCore::get_capsule_id()
+        {
+            Some(cid) if Some(cid) != previous_capsule => gang_kick(cid),
+            _ => ()
         }
 
-        /* at this point, we've got a virtual core to run. tell the timer system to call us back soon */
-        hardware::scheduler_timer_next_in(TIMESLICE_LENGTH);
+        /* at this point, we've got a virtual core to run. tell the timer system to call us back soon.
+        a parked core with nothing to run can wait in a low-power state until it's woken or housekeeping is due */
+        match is_parked() && PhysicalCore::get_capsule_id().is_none()
+        {
+            true => hardware::scheduler_timer_next_in(maintenance_length()),
+            false => hardware::scheduler_timer_next_in(timeslice_length())
+        }
     }
     else
     {
-        hardware::scheduler_timer_next_in(MAINTENANCE_LENGTH); /* we'll be back some time later */
+        hardware::scheduler_timer_next_in(maintenance_length()); /* we'll be back some time later */
+    }
+}
+
+/* ask other physical CPU cores to run the given capsule's sibling vcores, if the capsule is gang
+   scheduled. this reduces the time a vcore spends spinning on a lock held by a descheduled sibling.
+   it's a best effort: a physical core only joins in if it can find a sibling queued locally or globally */
+fn gang_kick(cid: CapsuleID)
+{
+    match capsule::get_gang_size(cid)
+    {
+        Some(vcores) if vcores > 1 => (),
+        _ => return
+    }
+
+    let this = PhysicalCore::get_id();
+    let pids: Vec<PhysicalCoreID> = pcore::started().into_iter()
+        .filter(|pid| *pid != this && pcore::get_running_capsule(*pid) != Some(cid) && pcore::is_running_deadline(*pid) == false)
+        .collect();
+
+    /* kick the cores together so the gang starts as close to the same time as possible */
+    if let Err(_e) = message::send_to_pcores(&pids, message::MessageContent::GangSchedule(cid))
+    {
+        hvalert!("Failed to message physical CPUs during gang scheduling: {}", error::report(&_e));
+    }
+}
+
+/* switch this physical CPU core to the given vcore, if it's waiting here or in the global queue, so that it can
+   take an IPI promptly. to keep capsules from taking time from each other this way, only do so if this core is
+   idle or already running one of the capsule's vcores. call this when asked to deliver an IPI */
+pub fn wake_vcore(cid: CapsuleID, vid: VirtualCoreID)
+{
+    if pcore::PhysicalCore::smode_supported() == false
+    {
+        return;
+    }
+
+    match PhysicalCore::this().get_virtualcore_id()
+    {
+        None => (),
+        Some(running) if running.capsuleid == cid && running.vcoreid != vid => (),
+        _ => return /* already running the vcore, or busy with another capsule */
+    }
+
+    let target = match PhysicalCore::dequeue_vcore(cid, vid)
+    {
+        Some(vcore) => Some(vcore),
+        None => GLOBAL_QUEUES.lock().dequeue_vcore(cid, vid)
+    };
+
+    if let Some(vcore) = target
+    {
+        pcore::context_switch(vcore);
+        pcore::PhysicalCore::this().set_timer_sched_last(hardware::scheduler_get_timer_now());
+        hardware::scheduler_timer_next_in(timeslice_length());
+    }
+}
+
+/* switch this physical CPU core to one of the given capsule's vcores, if one is waiting
+   and this core isn't already running the capsule. a deadline vcore is never switched out
+   to make way: its guarantee comes before the gang. call this when asked to join a gang */
+pub fn gang_join(cid: CapsuleID)
+{
+    if pcore::PhysicalCore::smode_supported() == false || PhysicalCore::get_capsule_id() == Some(cid) ||
+       pcore::is_running_deadline(PhysicalCore::get_id()) == true
+    {
+        return;
+    }
+
+    let sibling = match PhysicalCore::dequeue_capsule(cid)
+    {
+        Some(vcore) => Some(vcore),
+        None => GLOBAL_QUEUES.lock().dequeue_capsule(cid)
+    };
+
+    if let Some(vcore) = sibling
+    {
+        /* give the sibling a full timeslice alongside the rest of its gang */
+        pcore::context_switch(vcore);
+        pcore::PhysicalCore::this().set_timer_sched_last(hardware::scheduler_get_timer_now());
+        hardware::scheduler_timer_next_in(timeslice_length());
+    }
+}
+
+/* park the vcores of a paused capsule waiting in the global queue
+   => cid = paused capsule */
+pub fn park_queued(cid: CapsuleID)
+{
+    loop
+    {
+        /* don't hold the queue lock while parking */
+        let waiting = GLOBAL_QUEUES.lock().dequeue_capsule(cid);
+        match waiting
+        {
+            Some(vcore) => capsule::park_vcore(vcore),
+            None => break
+        }
+    }
+}
+
+/* park the vcores of a paused capsule waiting in this physical core's queue, and switch out
+   and park its vcore if it's running here. call when told the capsule has been paused
+   => cid = paused capsule */
+pub fn park_capsule(cid: CapsuleID)
+{
+    while let Some(vcore) = PhysicalCore::dequeue_capsule(cid)
+    {
+        capsule::park_vcore(vcore);
+    }
+
+    /* the scheduler parks the running vcore when it finds its capsule paused */
+    if PhysicalCore::get_capsule_id() == Some(cid)
+    {
+        ping();
     }
 }
 
@@ -252,61 +880,87 @@ fn housekeeping()
 {
     /* perform integrity checks */
     #[cfg(feature = "integritychecks")]
+    integrity::check("housekeeping");
+
+    /* run any of this physical core's events that are due, including housekeeping */
+    timerwheel::run_due();
+
+    /* and make some progress on any long-running jobs */
+    workqueue::run_one();
+
+    /* until the first round of housekeeping, keep the debug output flowing */
+    match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
     {
-        if let Err(val) = pcore::PhysicalCore::integrity_check()
+        (Some(time_now), Some(frequency)) => if time_now.to_exact(frequency) <= maintenance_length().to_exact(frequency)
         {
-            hvalert!("CPU private stack overflowed (0x{:x}). Halting!", val);
-            loop {}
-        }
+            debughousekeeper!();
+        },
+        (_, _) => debughousekeeper!() /* no timer. output debug */
     }
+}
 
-    /* avoid blocking on the house keeping lock */
-    if LAST_HOUSEKEEP_CHECK.is_locked() == true
+/* carry out housekeeping duties, called every housekeeping period by each physical core.
+   each core tidies its own heap. system-wide duties are carried out once a period
+   by whichever core gets to them first, so they carry on if any one core is held up */
+fn housekeep()
+{
+    timerwheel::schedule_in(maintenance_length(), housekeep);
+    heaphousekeeper!(); /* return any unused regions of physical memory */
+
+    if claim_system_housekeeping() == true
     {
-        return;
+        system_housekeeping();
+        SYSTEM_HOUSEKEEPING_BUSY.store(false, Ordering::SeqCst);
     }
+}
 
-    let mut last_check = LAST_HOUSEKEEP_CHECK.lock();
+/* decide whether this physical core should carry out this period's system-wide housekeeping.
+   without a timer to tell when it's due, it's left to the boot core
+   <= true if this core must carry it out, and then clear SYSTEM_HOUSEKEEPING_BUSY */
+fn claim_system_housekeeping() -> bool
+{
+    let (now, frequency) = match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
+    {
+        (Some(now), Some(frequency)) => (now.to_exact(frequency), frequency),
+        (_, _) => return PhysicalCore::get_id() == pcore::BOOT_PCORE_ID
+    };
 
-    /* only perform housekeeping once every MAINTENANCE_LENGTH-long period */
-    match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
+    if now < SYSTEM_HOUSEKEEPING_DUE.load(Ordering::SeqCst) ||
+       SYSTEM_HOUSEKEEPING_BUSY.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err()
     {
-        (Some(time_now), Some(frequency)) =>
-        {
-            let time_now = time_now.to_exact(frequency);
-            let last_check_value = (*last_check).to_exact(frequency);
-            let maintence_length = MAINTENANCE_LENGTH.to_exact(frequency);
+        return false;
+    }
 
-            /* wait until we're at least MAINTENANCE_LENGTH into boot */
-            if time_now > maintence_length
-            {
-                if time_now - last_check_value < maintence_length
-                {
-                    /* not enough MAINTENANCE_LENGTH time has passed */
-                    return;
-                }
-                /* mark when we last performed housekeeping */
-                *last_check = TimerValue::Exact(time_now);
-            }
-            else
-            {
-                /* flush debug and bail out */
-                debughousekeeper!();
-                return;
-            }
-        },
-        (_, _) =>
-        {
-            /* no timer. output debug and bail out */
-            debughousekeeper!();
-            return;
-        }
+    /* another core may have finished this period's round since the check above */
+    if now < SYSTEM_HOUSEKEEPING_DUE.load(Ordering::SeqCst)
+    {
+        SYSTEM_HOUSEKEEPING_BUSY.store(false, Ordering::SeqCst);
+        return false;
     }
 
+    SYSTEM_HOUSEKEEPING_DUE.store(now + maintenance_length().to_exact(frequency), Ordering::SeqCst);
+    true
+}
+
+/* carry out the system-wide housekeeping duties. only one physical core does this at a time */
+fn system_housekeeping()
+{
+    top::housekeeper(); /* redraw the status screen, if it's on, before the debug logs are drained */
     debughousekeeper!(); /* drain the debug logs to the debug hardware port */
-    heaphousekeeper!(); /* return any unused regions of physical memory */
     physmemhousekeeper!(); /* tidy up any physical memory structures */
     capsulehousekeeper!(); /* restart capsules that crashed or rebooted */
+    wss::housekeeper(); /* update capsules' working set estimates and sample afresh */
+    warmboot::housekeeper(); /* recreate the capsules once they've all stopped during a warm reboot */
+    power::housekeeper(); /* reboot or power off the host once the capsules have had time to shut down */
+    abboot::housekeeper(); /* roll back boot images that haven't confirmed they're running in time */
+    quiesce::housekeeper(); /* pause capsules that haven't quiesced for a snapshot in time */
+    button::housekeeper(); /* kill or restart guests that ignored their power or reboot buttons */
+    rtc::housekeeper(); /* fire capsules' real-time clock alarms that have fallen due */
+    pressure::housekeeper(); /* warn capsules that subscribed if free physical memory is running low */
+    accounting::housekeeper(); /* publish the time capsules have spent running and in the hypervisor */
+    telemetry::housekeeper(); /* print a telemetry report over the debug port if one is due */
+    unpark_if_busy(); /* wake a parked core if the active ones have too much to do */
+    park_if_idle(); /* or park an idle one if there's too little */
 
     /* if the global queues are empty then work out which physical CPU core
     has the most number of virtual cores and is therefore the busiest */
@@ -336,7 +990,7 @@ fn housekeeping()
                 {
                     match message::send(m)
                     {
-                        Err(e) => hvalert!("Failed to message physical CPU {} during load balancing: {:?}", pid, e),
+                        Err(e) => hvalert!("Failed to message physical CPU {} during load balancing: {}", pid, error::report(&e)),
                         Ok(()) => ()
                     };
                 }
@@ -344,84 +998,3 @@ fn housekeeping()
         }
     }
 }
-
-/* maintain a simple two-level round-robin scheduler per physical CPU core. we can make it more fancy later.
-the hypervisor tries to dish out physical CPU time fairly among capsules, and let the
-capsule supervisors work out how best to allocate their time to userspace code.
-picking the next virtual CPU core to run should be O(1) or as close as possible to it. */
-pub struct ScheduleQueues
-{
-    high: VecDeque<VirtualCore>,
-    low: VecDeque<VirtualCore>,
-    high_timeslices: TimesliceCount
-}
-
-impl ScheduleQueues
-{
-    /* initialize a new set of scheduler queues */
-    pub fn new() -> ScheduleQueues
-    {
-        ScheduleQueues
-        {
-            high: VecDeque::<VirtualCore>::new(),
-            low: VecDeque::<VirtualCore>::new(),
-            high_timeslices: 0
-        }
-    }
-
-    /* run the given virtual core by switching to its supervisor context.
-    this also updates NORM_PRIO_TICKS. if the current physical CPU was already running a
-    virtual core, that virtual core is queued up in the waiting list by context_switch() */
-    pub fn run(&mut self, to_run: VirtualCore)
-    {
-        /* if we're about to run a normal virtual core, then reset counter since a normal virtual core ran.
-        if we're running a non-normal virtual core, then increase the count. */
-        match to_run.get_priority()
-        {
-            Priority::Normal => self.high_timeslices = 0,
-            Priority::High => self.high_timeslices = self.high_timeslices + 1
-        };
-
-        pcore::context_switch(to_run);
-    }
-
-    /* add the given virtual core to the appropriate waiting queue. put it to the back
-    so that other virtual cores get a chance to run */
-    pub fn queue(&mut self, to_queue: VirtualCore)
-    {
-        match to_queue.get_priority()
-        {
-            Priority::High => self.high.push_back(to_queue),
-            Priority::Normal => self.low.push_back(to_queue)
-        }
-    }
-
-    /* remove a virtual core from the waiting list queues, selected by priority with safeguards to
-    prevent CPU time starvation. Returns selected virtual core or None for no other virtual cores waiting */
-    pub fn dequeue(&mut self) -> Option<VirtualCore>
-    {
-        /* has a normal virtual core been waiting for ages? */
-        if self.high_timeslices > HIGH_PRIO_TIMESLICES_MAX
-        {
-            match self.low.pop_front()
-            {
-                Some(t) => return Some(t),
-                None => ()
-            };
-        }
-
-        /* check the high priority queue for anything waiting.
-        if not, then try the normal priority queue */
-        match self.high.pop_front()
-        {
-            Some(t) => Some(t),
-            None => self.low.pop_front()
-        }
-    }
-
-    /* return the total number of virtual cores queued */
-    pub fn total_queued(&self) -> usize
-    {
-        self.high.len() + self.low.len()
-    }
-}
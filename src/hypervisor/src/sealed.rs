@@ -0,0 +1,175 @@
+/* diosix sealed secrets
+ *
+ * Secrets, such as disk encryption keys and credentials, can be bundled
+ * in the DMFS image sealed to the measurement of the capsule meant to
+ * have them. A capsule asks for a secret by name with a hypercall, and
+ * is only given it if its own measurement, taken as it was loaded,
+ * matches the one the secret was sealed to. A capsule whose executable
+ * or manifest properties have been changed, or any other capsule, gets
+ * nothing.
+ *
+ * A sealed secret is laid out as:
+ *
+ *   magic        8 bytes, SEALED_MAGIC
+ *   measurement  32 bytes, of the capsule it's sealed to
+ *   nonce        16 bytes, random and unique to this secret
+ *   ciphertext   the secret, encrypted
+ *   tag          32 bytes, authenticating all of the above
+ *
+ * Keys are derived from the host's sealing key, which never leaves the
+ * hypervisor: K = HMAC-SHA256(sealing key, "diosix sealing" || measurement),
+ * the encryption key is HMAC-SHA256(K, "enc"), and the authentication
+ * key is HMAC-SHA256(K, "mac"). The secret is encrypted by XORing it with
+ * a keystream whose nth 32-byte block is HMAC-SHA256(encryption key,
+ * nonce || n as a 64-bit little-endian number), and the tag is the
+ * HMAC-SHA256, under the authentication key, of everything before it. A
+ * tool sealing secrets for the DMFS image must do the same.
+ *
+ * The 32-byte sealing key is placed in the diosix,sealing-key property
+ * of the host device tree's /chosen node by an earlier boot stage, ideally
+ * derived by the boot ROM from a device-unique secret and the measurement
+ * of the hypervisor it started. Secrets sealed on one host then can't be
+ * unsealed on another, nor by a modified hypervisor. Hosts without a
+ * sealing key can't release any secrets.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::hardware;
+use super::manifest;
+use super::measure;
+use super::pcore;
+use super::hcargs::{self, Access};
+use super::service;
+use super::sha256::{self, Digest, DIGEST_SIZE};
+
+/* layout of a sealed secret, shared with the capsules and tools */
+pub use hypercall::sealed::{SEALED_MAGIC, SEALED_NONCE_SIZE, SEALED_TAG_SIZE, MEASUREMENT_SIZE};
+
+/* bytes before the ciphertext */
+const HEADER_SIZE: usize = SEALED_MAGIC.len() + MEASUREMENT_SIZE + SEALED_NONCE_SIZE;
+
+/* return the ID of the currently running capsule, or an error code */
+fn current_capsule() -> Result<CapsuleID, Cause>
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* overwrite a copy of a key or secret before it's dropped */
+fn wipe(bytes: &mut [u8])
+{
+    for byte in bytes.iter_mut()
+    {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+}
+
+/* decrypt and authenticate a sealed secret for a capsule
+   => sealed = the sealed secret
+      measurement = measurement of the capsule asking for it
+   <= the secret, or an error code */
+fn unseal(sealed: &[u8], measurement: &Digest) -> Result<Vec<u8>, Cause>
+{
+    if sealed.len() < HEADER_SIZE + SEALED_TAG_SIZE || &sealed[..SEALED_MAGIC.len()] != SEALED_MAGIC
+    {
+        return Err(Cause::SecretBadFormat);
+    }
+
+    let (body, tag) = sealed.split_at(sealed.len() - SEALED_TAG_SIZE);
+    let (header, ciphertext) = body.split_at(HEADER_SIZE);
+    let expected = &header[SEALED_MAGIC.len()..SEALED_MAGIC.len() + MEASUREMENT_SIZE];
+    let nonce = &header[SEALED_MAGIC.len() + MEASUREMENT_SIZE..];
+
+    if sha256::equal(expected, measurement) == false
+    {
+        return Err(Cause::SecretMeasurementMismatch);
+    }
+
+    let mut sealing = match hardware::get_sealing_key()
+    {
+        Some(key) => key,
+        None => return Err(Cause::SecretNoKey)
+    };
+
+    let mut derived = sha256::hmac(&sealing, &[b"diosix sealing", expected]);
+    let mut encryption = sha256::hmac(&derived, &[b"enc"]);
+    let mut authentication = sha256::hmac(&derived, &[b"mac"]);
+    wipe(&mut sealing);
+    wipe(&mut derived);
+
+    /* check nothing's been changed before decrypting anything */
+    let authentic = sha256::equal(&sha256::hmac(&authentication, &[body]), tag);
+    wipe(&mut authentication);
+    if authentic == false
+    {
+        wipe(&mut encryption);
+        return Err(Cause::SecretTampered);
+    }
+
+    let mut secret = Vec::with_capacity(ciphertext.len());
+    for (counter, block) in ciphertext.chunks(DIGEST_SIZE).enumerate()
+    {
+        let mut keystream = sha256::hmac(&encryption, &[nonce, &(counter as u64).to_le_bytes()]);
+        secret.extend(block.iter().zip(keystream.iter()).map(|(c, k)| c ^ k));
+        wipe(&mut keystream);
+    }
+    wipe(&mut encryption);
+
+    Ok(secret)
+}
+
+/* unseal a named secret and copy it into the currently running capsule's memory. nothing is
+   copied if the buffer is too small, so call again with a buffer at least as large as the size returned
+   => name, length = address and length in bytes of the secret's name in the capsule
+      buffer, size = address and size in bytes of the buffer in the capsule
+   <= size of the secret in bytes, or an error code */
+pub fn release(name: usize, length: usize, buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let cid = current_capsule()?;
+    let name = service::read_text(cid, name, length)?;
+
+    let measurement = match measure::get(cid)
+    {
+        Some(measurement) => measurement,
+        None => return Err(Cause::SecretNotMeasured)
+    };
+
+    let sealed = manifest::get_sealed_secret(&name)?;
+    let mut secret = match unseal(&sealed, &measurement)
+    {
+        Ok(secret) => secret,
+        Err(e) =>
+        {
+            hvalert!("Refused to release sealed secret {} to capsule {}: {:?}", name, cid, e);
+            return Err(e);
+        }
+    };
+
+    let result = match secret.len() > 0 && secret.len() <= size
+    {
+        true => match hcargs::buffer(cid, buffer, secret.len(), size, Access::Write)
+        {
+            Ok(base) =>
+            {
+                let target = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, secret.len()) };
+                target.copy_from_slice(&secret);
+                hvdebug!("Released sealed secret {} to capsule {}", name, cid);
+                Ok(secret.len())
+            },
+            Err(e) => Err(e)
+        },
+        false => Ok(secret.len()) /* empty, or too large for the buffer */
+    };
+
+    wipe(&mut secret);
+    result
+}
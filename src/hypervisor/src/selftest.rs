@@ -0,0 +1,247 @@
+/* diosix hypervisor runtime self-tests
+ *
+ * These checks exercise the heap, physical memory allocator, scheduler,
+ * and locks on live hardware, so that a new board bring-up can be
+ * validated without an external test rig. They're run on request by a
+ * capsule granted the self_test property, typically the console service,
+ * on whichever physical CPU core handles the request. Each result is
+ * written to the hypervisor's log as a line of the form:
+ *
+ *   selftest: <name>: pass
+ *   selftest: <name>: fail (<reason>)
+ *
+ * followed by a summary line, and the totals are returned to the caller.
+ * Unlike the unit tests, these run in a normal, booted system.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use super::lock::Mutex;
+use super::error::Cause;
+use super::physmem;
+use super::scheduler;
+use super::vcore::Deadline;
+use super::pcore;
+
+/* define the size and number of things to throw at each test */
+const HEAP_STRESS_ROUNDS: usize = 64;
+const HEAP_STRESS_MAX_WORDS: usize = 512;
+const REGION_CYCLES: usize = 16;
+const REGION_CYCLE_SIZE: usize = 1024 * 1024;
+
+/* a self-test returns Ok for pass, or a static description of what went wrong */
+type SelfTest = fn() -> Result<(), &'static str>;
+
+/* the list of self-tests to run, in order */
+const SELF_TESTS: [(&'static str, SelfTest); 4] =
+[
+    ("heap stress", heap_stress),
+    ("region alloc/free cycles", region_cycles),
+    ("scheduler sanity", scheduler_sanity),
+    ("lock checks", lock_checks)
+];
+
+/* run every self-test, logging the results
+   <= number of tests passed and failed */
+pub fn run_all() -> (usize, usize)
+{
+    let (mut passed, mut failed) = (0, 0);
+
+    for (name, test) in SELF_TESTS.iter()
+    {
+        match test()
+        {
+            Ok(()) =>
+            {
+                hvdebug!("selftest: {}: pass", name);
+                passed = passed + 1;
+            },
+            Err(_reason) =>
+            {
+                hvdebug!("selftest: {}: fail ({})", name, _reason);
+                failed = failed + 1;
+            }
+        }
+    }
+
+    hvdebug!("selftest: {} passed, {} failed on physical CPU core {}", passed, failed, pcore::PhysicalCore::get_id());
+    (passed, failed)
+}
+
+/* allocate blocks of varying sizes, fill them with patterns, check the patterns survive
+   neighbouring allocations, and free them all, checking the heap's free space is restored */
+fn heap_stress() -> Result<(), &'static str>
+{
+    let before = pcore::PhysicalCore::this().heap.calculate_stats();
+
+    {
+        let mut blocks: Vec<Box<[usize]>> = Vec::new();
+        for round in 0..HEAP_STRESS_ROUNDS
+        {
+            let words = ((round * 37) % HEAP_STRESS_MAX_WORDS) + 1;
+            let mut block = Vec::with_capacity(words);
+            for word in 0..words
+            {
+                block.push(round ^ word);
+            }
+            blocks.push(block.into_boxed_slice());
+        }
+
+        for (round, block) in blocks.iter().enumerate()
+        {
+            for (word, value) in block.iter().enumerate()
+            {
+                if *value != round ^ word
+                {
+                    return Err("heap block corrupted");
+                }
+            }
+        }
+    }
+
+    let after = pcore::PhysicalCore::this().heap.calculate_stats();
+    if after.free_total < before.free_total
+    {
+        return Err("heap free space not restored");
+    }
+
+    Ok(())
+}
+
+/* repeatedly allocate and free physical memory regions, checking they're usable and don't overlap */
+fn region_cycles() -> Result<(), &'static str>
+{
+    for _ in 0..REGION_CYCLES
+    {
+        let first = match physmem::alloc_region(REGION_CYCLE_SIZE)
+        {
+            Ok(r) => r,
+            Err(_) => return Err("region allocation failed")
+        };
+        let second = match physmem::alloc_region(REGION_CYCLE_SIZE)
+        {
+            Ok(r) => r,
+            Err(_) =>
+            {
+                let _ = physmem::dealloc_region(first);
+                return Err("second region allocation failed");
+            }
+        };
+
+        let overlap = first.base() < second.end() && second.base() < first.end();
+        let small = first.size() < REGION_CYCLE_SIZE || second.size() < REGION_CYCLE_SIZE;
+
+        /* write to the first and last words of each region */
+        let mut writable = true;
+        for region in [first, second].iter()
+        {
+            let words = region.as_usize_slice();
+            let last = words.len() - 1;
+            words[0] = region.base();
+            words[last] = region.end();
+            if words[0] != region.base() || words[last] != region.end()
+            {
+                writable = false;
+            }
+        }
+
+        /* free both regions whatever happened, so a failed check doesn't leak them */
+        let first_freed = physmem::dealloc_region(first).is_ok();
+        let second_freed = physmem::dealloc_region(second).is_ok();
+        if first_freed == false || second_freed == false
+        {
+            return Err("region free failed");
+        }
+        if writable == false
+        {
+            return Err("region memory not writable");
+        }
+        if overlap == true
+        {
+            return Err("allocated regions overlap");
+        }
+        if small == true
+        {
+            return Err("allocated region too small");
+        }
+    }
+
+    physmem::coalesce_regions();
+    Ok(())
+}
+
+/* check the scheduler's timer runs forwards and that deadline admission control behaves */
+fn scheduler_sanity() -> Result<(), &'static str>
+{
+    let (first, frequency) = match scheduler::timer_now()
+    {
+        Some(t) => t,
+        None => return Err("no scheduler timer")
+    };
+    if frequency == 0
+    {
+        return Err("scheduler timer frequency is zero");
+    }
+
+    match scheduler::timer_now()
+    {
+        Some((second, _)) if second >= first => (),
+        _ => return Err("scheduler timer went backwards")
+    }
+
+    /* impossible deadlines must be refused, and admitted ones must be releasable */
+    match scheduler::admit_deadline(Deadline { period: 10, budget: 20 })
+    {
+        Err(Cause::SchedDeadlineBad) => (),
+        _ => return Err("impossible deadline admitted")
+    }
+    match scheduler::admit_deadline(Deadline { period: 1000, budget: 800 })
+    {
+        Err(Cause::SchedDeadlineRejected) => (),
+        Ok(()) => return Err("deadline needing more than one core's share admitted"),
+        Err(_) => return Err("greedy deadline refused for the wrong reason")
+    }
+    let modest = Deadline { period: 1000, budget: 1 };
+    match scheduler::admit_deadline(modest)
+    {
+        Ok(()) => scheduler::release_deadline(modest),
+        Err(Cause::SchedDeadlineRejected) => (), /* already fully committed to other capsules */
+        Err(_) => return Err("modest deadline refused")
+    }
+
+    Ok(())
+}
+
+/* check locks are reported held and released correctly */
+fn lock_checks() -> Result<(), &'static str>
+{
+    let lock = Mutex::new("self-test lock", 0usize);
+    if lock.is_locked() == true
+    {
+        return Err("new lock reported held");
+    }
+
+    {
+        let mut value = lock.lock();
+        if lock.is_locked() == false
+        {
+            return Err("held lock reported free");
+        }
+        *value = 42;
+    }
+
+    if lock.is_locked() == true
+    {
+        return Err("released lock reported held");
+    }
+    if *lock.lock() != 42
+    {
+        return Err("lock lost its contents");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+/* diosix virtual serial links between pairs of capsules
+ *
+ * A virtual serial link is a simple character device joining two capsules.
+ * Each capsule declares the link in the manifest with the serial_link=N
+ * property, where N is the link's system-wide number, and the first two
+ * capsules to declare the same number are joined. Bytes written into one
+ * end of the link are held in a bounded buffer until the capsule at the
+ * other end reads them. This lets guest stacks be composed from smaller
+ * capsules without needing full networking.
+ *
+ * The capsule at the receiving end is sent VIRQ_SERIAL_LINK_DATA when data
+ * arrives in its empty buffer. Links are described in each capsule's
+ * device tree as diosix,serial-link nodes.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::passthrough::{self, DeviceIRQ};
+use super::pcore;
+
+pub type LinkID = usize;
+
+/* virtual interrupt raised when data arrives for a capsule on one of its links */
+pub const VIRQ_SERIAL_LINK_DATA: DeviceIRQ = hypercall::irq::VIRQ_SERIAL_LINK_DATA;
+
+/* maximum number of bytes waiting to be read from one end of a link. writes fail when full */
+const LINK_BUFFER_MAX: usize = 4096;
+
+/* a link's two ends. each end has the capsule attached to it, if any,
+   and the bytes waiting to be read by that capsule */
+struct Link
+{
+    ends: [Option<CapsuleID>; 2],
+    waiting: [VecDeque<u8>; 2]
+}
+
+impl Link
+{
+    pub fn new() -> Link
+    {
+        Link
+        {
+            ends: [None, None],
+            waiting: [VecDeque::new(), VecDeque::new()]
+        }
+    }
+
+    /* return the index of the given capsule's end of the link, or None if it's not attached */
+    fn end_of(&self, cid: CapsuleID) -> Option<usize>
+    {
+        self.ends.iter().position(|end| *end == Some(cid))
+    }
+}
+
+lazy_static!
+{
+    static ref LINKS: Mutex<HashMap<LinkID, Link>> = Mutex::new("virtual serial links", HashMap::new());
+}
+
+/* attach a capsule to one end of a link, creating the link if needed
+   => cid = capsule to attach
+      link = link number declared in the manifest
+   <= Ok for success, or an error code if both ends are taken */
+pub fn attach(cid: CapsuleID, link: LinkID) -> Result<(), Cause>
+{
+    let mut links = LINKS.lock();
+    let entry = links.entry(link).or_insert(Link::new());
+
+    if entry.end_of(cid).is_some()
+    {
+        return Ok(());
+    }
+
+    match entry.ends.iter().position(|end| end.is_none())
+    {
+        Some(index) =>
+        {
+            entry.ends[index] = Some(cid);
+            entry.waiting[index].clear();
+            Ok(())
+        },
+        None => Err(Cause::SerialLinkFull)
+    }
+}
+
+/* detach a capsule from all of its links when it's destroyed, discarding any data waiting for it */
+pub fn detach(cid: CapsuleID)
+{
+    let mut links = LINKS.lock();
+    for (_, link) in links.iter_mut()
+    {
+        if let Some(index) = link.end_of(cid)
+        {
+            link.ends[index] = None;
+            link.waiting[index].clear();
+        }
+    }
+
+    links.retain(|_, link| link.ends.iter().any(|end| end.is_some()));
+}
+
+/* return the links the given capsule is attached to */
+pub fn get_links(cid: CapsuleID) -> Vec<LinkID>
+{
+    LINKS.lock().iter().filter(|(_, link)| link.end_of(cid).is_some()).map(|(id, _)| *id).collect()
+}
+
+/* return the ID of the currently running capsule, or an error code */
+fn current_capsule() -> Result<CapsuleID, Cause>
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* write a byte from the currently running capsule into a link, to be read by the other end
+   => link = link to write into, which the capsule must be attached to
+      byte = byte to write
+   <= Ok for success, or an error code */
+pub fn putc(link: LinkID, byte: u8) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+    let mut links = LINKS.lock();
+    let link = match links.get_mut(&link)
+    {
+        Some(l) => l,
+        None => return Err(Cause::SerialLinkBadID)
+    };
+
+    let end = match link.end_of(cid)
+    {
+        Some(index) => index,
+        None => return Err(Cause::SerialLinkBadID)
+    };
+
+    let other = 1 - end;
+    let peer = match link.ends[other]
+    {
+        Some(peer) => peer,
+        None => return Err(Cause::SerialLinkNotConnected)
+    };
+
+    let buffer = &mut link.waiting[other];
+    if buffer.len() >= LINK_BUFFER_MAX
+    {
+        return Err(Cause::SerialLinkBufferFull);
+    }
+
+    buffer.push_back(byte);
+    if buffer.len() == 1
+    {
+        passthrough::raise_virtual_irq(peer, VIRQ_SERIAL_LINK_DATA);
+    }
+
+    Ok(())
+}
+
+/* read the next byte waiting for the currently running capsule on a link
+   => link = link to read from, which the capsule must be attached to
+   <= byte read, or an error code */
+pub fn getc(link: LinkID) -> Result<u8, Cause>
+{
+    let cid = current_capsule()?;
+    match LINKS.lock().get_mut(&link)
+    {
+        Some(link) => match link.end_of(cid)
+        {
+            Some(end) => match link.waiting[end].pop_front()
+            {
+                Some(byte) => Ok(byte),
+                None => Err(Cause::CapsuleBufferEmpty)
+            },
+            None => Err(Cause::SerialLinkBadID)
+        },
+        None => Err(Cause::SerialLinkBadID)
+    }
+}
@@ -1,6 +1,13 @@
 /* diosix capsule-provided service management
-
- * (c) Chris Williams, 2019-2020.
+ *
+ * Services built into the hypervisor's protocol, such as the console
+ * interface, are known by type. Other services are registered by name,
+ * so that third-party services can be offered and found without changing
+ * the hypervisor. A capsule can only register the names it's granted in
+ * the manifest, and named services can be restricted to capsules granted
+ * access to them, in the same way as typed services.
+ *
+ * (c) Chris Williams, 2019-2021.
  *
  * See LICENSE for usage and copying.
  */
@@ -9,12 +16,14 @@ use super::lock::Mutex;
 use hashbrown::hash_map::{HashMap, Entry};
 use alloc::collections::vec_deque::VecDeque;
 use alloc::vec::Vec;
+use alloc::string::String;
 use super::message;
 use super::error::Cause;
-use super::capsule::{self, CapsuleID};
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::hcargs::{self, Access};
 
 /* available type of services that can be offered by a capsule */
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum ServiceType
 {
     ConsoleInterface = 0 /* act as the console interface manager */
@@ -29,6 +38,89 @@ pub fn usize_to_service_type(stype: usize) -> Result<ServiceType, Cause>
     }
 }
 
+/* convert a service name used in the manifest into a service type */
+pub fn name_to_service_type(name: &str) -> Result<ServiceType, Cause>
+{
+    if name.eq_ignore_ascii_case("console")
+    {
+        return Ok(ServiceType::ConsoleInterface);
+    }
+
+    Err(Cause::ServiceNotFound)
+}
+
+/* service names are two or more labels separated by dots, eg: acme.storage. the first label is
+   the name's namespace. labels are made of lowercase letters, digits, hyphens, and underscores */
+const SERVICE_NAME_MAX_LEN: usize = 64;
+
+/* namespace kept for the hypervisor's own services, which are known by type rather than name */
+const RESERVED_NAMESPACE: &str = "diosix";
+
+/* check a service name follows the naming rules
+   => name = service name to check
+   <= Ok if it's valid, or an error code if not */
+pub fn check_name(name: &str) -> Result<(), Cause>
+{
+    let labels: Vec<&str> = name.split('.').collect();
+    if name.len() > SERVICE_NAME_MAX_LEN || labels.len() < 2 || labels[0] == RESERVED_NAMESPACE
+    {
+        return Err(Cause::ServiceBadName);
+    }
+
+    for label in labels
+    {
+        if label.len() == 0 || label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') == false
+        {
+            return Err(Cause::ServiceBadName);
+        }
+    }
+
+    Ok(())
+}
+
+/* <= true if the given service name is in the namespace kept for the hypervisor's own services */
+pub fn in_reserved_namespace(name: &str) -> bool
+{
+    match name.split_once('.')
+    {
+        Some((namespace, service)) => namespace == RESERVED_NAMESPACE && service.len() > 0,
+        None => false
+    }
+}
+
+/* check a pattern granting access to service names, used in the manifest. a pattern is
+   either a service name, or a namespace followed by .* to cover all the names in it
+   => pattern = pattern to check
+   <= Ok if it's valid, or an error code if not */
+pub fn check_name_pattern(pattern: &str) -> Result<(), Cause>
+{
+    match pattern.strip_suffix(".*")
+    {
+        /* check the namespace using a placeholder name within it */
+        Some(namespace) if namespace.contains('.') == false => check_name(&format!("{}.x", namespace)),
+        Some(_) => Err(Cause::ServiceBadName),
+        None => check_name(pattern)
+    }
+}
+
+/* <= true if the given service name is covered by the given pattern */
+pub fn name_matches(pattern: &str, name: &str) -> bool
+{
+    match pattern.strip_suffix(".*")
+    {
+        Some(namespace) => name.len() > namespace.len() + 1 && name.starts_with(namespace) && name.as_bytes()[namespace.len()] == b'.',
+        None => pattern == name
+    }
+}
+
+/* patterns of the service names a capsule is granted in the manifest */
+pub struct NameRights
+{
+    pub register: Vec<String>,  /* names the capsule may register */
+    pub restrict: Vec<String>,  /* names only capsules granted access may look up */
+    pub access: Vec<String>     /* restricted names the capsule may look up */
+}
+
 /* select either a particular service or all services */
 pub enum SelectService
 {
@@ -42,10 +134,11 @@ that privileged / trusted capsules can register.
 then other capsules can message those services
 to access those underlying resources. */
 
-/* maintain a table of registered services */
+/* maintain tables of registered services */
 lazy_static!
 {
     static ref SERVICES: Mutex<HashMap<ServiceType, Service>> = Mutex::new("system service table", HashMap::new());
+    static ref NAMED_SERVICES: Mutex<HashMap<String, NamedService>> = Mutex::new("named service table", HashMap::new());
 }
 
 /* return true if the given service type is registered */
@@ -55,13 +148,37 @@ pub fn is_registered(stype: ServiceType) -> bool
     tbl.contains_key(&stype)
 }
 
+/* return the ID of the capsule that owns the given service, or None if it's not registered */
+pub fn get_owner(stype: ServiceType) -> Option<CapsuleID>
+{
+    match SERVICES.lock().get(&stype)
+    {
+        Some(service) => Some(service.get_capsule_id()),
+        None => None
+    }
+}
+
+/* return the service types registered by the given capsule */
+pub fn owned_by(cid: CapsuleID) -> Vec<ServiceType>
+{
+    SERVICES.lock().iter().filter(|(_, service)| service.get_capsule_id() == cid).map(|(stype, _)| *stype).collect()
+}
+
 /* describe an individual service */
 struct Service
 {
     capsuleid: CapsuleID,       /* capsule that's registered this service */
+    restricted: bool,           /* true if only capsules granted access may use this service */
     msgs: VecDeque<message::Message>  /* queue of messages to deliver to service */
 }
 
+/* describe a service registered by name */
+struct NamedService
+{
+    capsuleid: CapsuleID,       /* capsule that's registered this service */
+    restricted: bool            /* true if only capsules granted access may look up this service */
+}
+
 impl Service
 {
     pub fn queue(&mut self, msg: message::Message)
@@ -70,6 +187,7 @@ impl Service
     }
 
     pub fn get_capsule_id(&self) -> CapsuleID { self.capsuleid }
+    pub fn is_restricted(&self) -> bool { self.restricted }
 }
 
 /* register a service for a capsule. this will fail if the
@@ -92,6 +210,7 @@ pub fn register(stype: ServiceType, cid: CapsuleID) -> Result<(), Cause>
     let service = Service
     {
         capsuleid: cid,
+        restricted: capsule::has_property(cid, CapsuleProperty::ServiceRestrict(stype))?,
         msgs: VecDeque::new()
     };
 
@@ -147,11 +266,74 @@ pub fn deregister(stype: SelectService, cid: CapsuleID) -> Result<(), Cause>
     {
         tbl.remove(&victim);
     }
+    drop(tbl);
+
+    /* a capsule giving up all its services gives up its named ones too */
+    if let SelectService::AllServices = stype
+    {
+        NAMED_SERVICES.lock().retain(|_, service| service.capsuleid != cid);
+    }
 
     Ok(())
 }
 
-/* send the given message msg to a registered service */
+/* hand a registered service, and any messages queued for it, from one capsule to another
+   => stype = service to hand over
+      from = ID of capsule that currently owns the service
+      to = ID of capsule to take over the service
+   <= Ok for success, or an error code */
+pub fn transfer(stype: ServiceType, from: CapsuleID, to: CapsuleID) -> Result<(), Cause>
+{
+    /* don't hold the services lock while looking up capsule properties */
+    if capsule::is_service_allowed(to, stype)? == false
+    {
+        return Err(Cause::ServiceNotAllowed);
+    }
+    let restricted = capsule::has_property(to, CapsuleProperty::ServiceRestrict(stype))?;
+
+    match SERVICES.lock().get_mut(&stype)
+    {
+        Some(service) if service.get_capsule_id() == from =>
+        {
+            service.capsuleid = to;
+            service.restricted = restricted;
+            Ok(())
+        },
+        Some(_) => Err(Cause::ServiceAlreadyRegistered),
+        None => Err(Cause::ServiceNotFound)
+    }
+}
+
+/* check whether a capsule may select and call a registered service. the capsule
+   that owns the service always has access. if the service's owner was granted
+   service_restrict for the service type in the manifest, only capsules granted
+   service_access for that type may use it. otherwise the service is open to all
+   => stype = service to check
+      cid = ID of capsule wishing to use the service
+   <= Ok if access is allowed, or an error code */
+pub fn check_access(stype: ServiceType, cid: CapsuleID) -> Result<(), Cause>
+{
+    /* don't hold the services lock while looking up capsule properties */
+    let (owner, restricted) = match SERVICES.lock().get(&stype)
+    {
+        Some(service) => (service.get_capsule_id(), service.is_restricted()),
+        None => return Err(Cause::ServiceNotFound)
+    };
+
+    if owner == cid || restricted == false
+    {
+        return Ok(());
+    }
+
+    match capsule::has_property(cid, CapsuleProperty::ServiceAccess(stype))?
+    {
+        true => Ok(()),
+        false => Err(Cause::ServiceAccessDenied)
+    }
+}
+
+/* send the given message msg to a registered service.
+   messages from capsules are subject to the service's access control list */
 pub fn send(msg: message::Message) -> Result<(), Cause>
 {
     let stype = match msg.get_receiver()
@@ -160,6 +342,11 @@ pub fn send(msg: message::Message) -> Result<(), Cause>
         _ => return Err(Cause::MessageBadType)
     };
 
+    if let message::Sender::Capsule(cid) = msg.get_sender()
+    {
+        check_access(stype, cid)?;
+    }
+
     if let Some(service) = SERVICES.lock().get_mut(&stype)
     {
         service.queue(msg);
@@ -169,4 +356,114 @@ pub fn send(msg: message::Message) -> Result<(), Cause>
     {
         return Err(Cause::ServiceNotAllowed)
     }
-}
\ No newline at end of file
+}
+
+/* copy a service name out of a capsule's memory and check it follows the naming rules
+   => cid = ID of capsule holding the name
+      name = address of the name in the capsule, in UTF-8 without a terminating zero
+      length = length of the name in bytes
+   <= the name, or an error code */
+pub fn read_name(cid: CapsuleID, name: usize, length: usize) -> Result<String, Cause>
+{
+    let name = read_text(cid, name, length)?;
+    check_name(&name)?;
+    Ok(name)
+}
+
+/* copy a service name out of a capsule's memory without checking it follows the naming rules,
+   for names that may be in the hypervisor's reserved namespace
+   => cid = ID of capsule holding the name
+      name = address of the name in the capsule, in UTF-8 without a terminating zero
+      length = length of the name in bytes
+   <= the name, or an error code */
+pub fn read_text(cid: CapsuleID, name: usize, length: usize) -> Result<String, Cause>
+{
+    if length == 0 || length > SERVICE_NAME_MAX_LEN
+    {
+        return Err(Cause::ServiceBadName);
+    }
+
+    let base = hcargs::buffer(cid, name, length, SERVICE_NAME_MAX_LEN, Access::Read)?;
+    let bytes = unsafe { core::slice::from_raw_parts(base as *const u8, length) };
+    match core::str::from_utf8(bytes)
+    {
+        Ok(s) => Ok(String::from(s)),
+        Err(_) => Err(Cause::ServiceBadName)
+    }
+}
+
+/* register a service by name for a capsule. this will fail if the capsule wasn't granted
+   the name in the manifest, or if another capsule has already registered the name. as with
+   typed services, a restarted capsule registering its names again gets ServiceAlreadyOwner
+   => name = valid name of the service to register
+      cid = ID of capsule to handle this service
+   <= Ok for success, or an error code */
+pub fn register_name(name: &str, cid: CapsuleID) -> Result<(), Cause>
+{
+    /* don't hold the services lock while looking up capsule properties */
+    let rights = capsule::get_service_names(cid)?;
+    if rights.register.iter().any(|pattern| name_matches(pattern, name)) == false
+    {
+        return Err(Cause::ServiceNotAllowed);
+    }
+    let restricted = rights.restrict.iter().any(|pattern| name_matches(pattern, name));
+
+    match NAMED_SERVICES.lock().entry(String::from(name))
+    {
+        Entry::Vacant(v) =>
+        {
+            v.insert(NamedService { capsuleid: cid, restricted });
+            Ok(())
+        },
+        Entry::Occupied(o) => match o.get().capsuleid == cid
+        {
+            true => Err(Cause::ServiceAlreadyOwner),
+            false => Err(Cause::ServiceAlreadyRegistered)
+        }
+    }
+}
+
+/* stop a capsule providing a named service
+   => name = name of the service
+      cid = ID of capsule that registered it
+   <= Ok for success, or an error code */
+pub fn deregister_name(name: &str, cid: CapsuleID) -> Result<(), Cause>
+{
+    let mut tbl = NAMED_SERVICES.lock();
+    match tbl.get(name)
+    {
+        Some(service) if service.capsuleid == cid =>
+        {
+            tbl.remove(name);
+            Ok(())
+        },
+        Some(_) => Err(Cause::ServiceNotAllowed),
+        None => Err(Cause::ServiceNotFound)
+    }
+}
+
+/* find the capsule providing a named service. if its owner was granted service_name_restrict
+   for the name, only capsules granted service_name_access for it may look it up
+   => name = name of the service to find
+      cid = ID of capsule looking up the service
+   <= ID of the capsule providing the service, or an error code */
+pub fn lookup_name(name: &str, cid: CapsuleID) -> Result<CapsuleID, Cause>
+{
+    /* don't hold the services lock while looking up capsule properties */
+    let (owner, restricted) = match NAMED_SERVICES.lock().get(name)
+    {
+        Some(service) => (service.capsuleid, service.restricted),
+        None => return Err(Cause::ServiceNotFound)
+    };
+
+    if owner == cid || restricted == false
+    {
+        return Ok(owner);
+    }
+
+    match capsule::get_service_names(cid)?.access.iter().any(|pattern| name_matches(pattern, name))
+    {
+        true => Ok(owner),
+        false => Err(Cause::ServiceAccessDenied)
+    }
+}
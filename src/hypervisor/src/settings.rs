@@ -0,0 +1,327 @@
+/* diosix live hypervisor settings
+ *
+ * Keep the knobs that would otherwise be compiled in, such as the
+ * housekeeping period, the scheduler's timeslice, and how much each part
+ * of the hypervisor writes to its debug log, in one registry that can be
+ * changed while the hypervisor is running. Settings are read lock-free,
+ * so subsystems can check them on hot paths, such as every scheduling
+ * decision, and pick up changes straight away.
+ *
+ * Settings can be changed from the hypervisor's command prompt, or by a
+ * capsule granted the host_settings property using hypercalls. Each
+ * setting has a range of allowed values, and changes outside it are
+ * refused. Settings can also be saved to the persistent store, if the
+ * host has one, and are then restored on the next boot. Boot arguments,
+ * such as diosix.noparking, take priority over saved settings.
+ *
+ * Debug log verbosity is set per module, named as in the hypervisor's
+ * source, eg: capsule or scheduler, with a default for the rest. As
+ * hvdebug!() is compiled out of release builds, this only quietens debug
+ * builds. Alerts are always written.
+ *
+ * The tracepoints setting is a mask of the tracepoints, numbered as in
+ * the hypercall crate, that record into the trace ring. Tracepoints are
+ * only built in with the tracepoints feature, so without it this
+ * setting does nothing. See trace.rs.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::{self, CapsuleProperty};
+use super::pstore;
+use super::service;
+
+/* the settings and verbosity levels, shared with the capsules */
+pub use hypercall::settings::{Setting, Verbosity, SETTINGS};
+use hypercall::tracepoint::TRACEPOINTS;
+
+/* name of the verbosity used by modules without one of their own */
+const DEFAULT_MODULE: &str = "default";
+
+/* names of settings written as log.<module>=<verbosity> */
+const LOG_PREFIX: &str = "log.";
+
+/* longest module name accepted */
+const MODULE_NAME_MAX_LEN: usize = 32;
+
+/* the range of values each setting can take, and its value at boot */
+struct Limits
+{
+    min: u64,
+    max: u64,
+    default: u64
+}
+
+const LIMITS: [Limits; SETTINGS] =
+[
+    Limits { min: 100, max: 60000, default: 5000 }, /* HousekeepingPeriod */
+    Limits { min: 5, max: 1000, default: 50 },      /* Timeslice */
+    Limits { min: 1, max: 1000, default: 10 },      /* HighPriorityRun */
+    Limits { min: 0, max: 1, default: 1 },          /* Parking */
+    Limits { min: 0, max: (1 << TRACEPOINTS) - 1, default: 0 } /* Tracepoints */
+];
+
+/* the current value of each setting */
+static VALUES: [AtomicU64; SETTINGS] =
+[
+    AtomicU64::new(LIMITS[0].default),
+    AtomicU64::new(LIMITS[1].default),
+    AtomicU64::new(LIMITS[2].default),
+    AtomicU64::new(LIMITS[3].default),
+    AtomicU64::new(LIMITS[4].default)
+];
+
+/* verbosity of modules without one of their own, as a Verbosity value */
+static DEFAULT_VERBOSITY: AtomicUsize = AtomicUsize::new(Verbosity::Debug as usize);
+
+/* set once any module has been given a verbosity of its own, so that the common case needs no lock */
+static OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+
+lazy_static!
+{
+    /* modules given a verbosity of their own */
+    static ref VERBOSITY: Mutex<HashMap<String, Verbosity>> = Mutex::new("debug log verbosity", HashMap::new());
+}
+
+/* restore any settings saved in the persistent store. call on the boot core once the store is up */
+pub fn init()
+{
+    let saved = match pstore::load_settings()
+    {
+        Some(s) => s,
+        None => return
+    };
+
+    for line in String::from_utf8_lossy(&saved).lines()
+    {
+        let (name, value) = match line.split_once('=')
+        {
+            Some(pair) => pair,
+            None => continue
+        };
+
+        if let Err(_e) = set_by_name(name, value)
+        {
+            hvalert!("Ignoring saved setting {}: {:?}", line, _e);
+        }
+    }
+    hvdebug!("Restored settings from the persistent store");
+}
+
+/* <= the current value of a setting */
+pub fn get(setting: Setting) -> u64
+{
+    VALUES[setting as usize].load(Ordering::Relaxed)
+}
+
+/* change a setting
+   => setting = setting to change
+      value = its new value
+   <= Ok for success, or an error code if the value is out of range */
+pub fn set(setting: Setting, value: u64) -> Result<(), Cause>
+{
+    let limits = &LIMITS[setting as usize];
+    if value < limits.min || value > limits.max
+    {
+        return Err(Cause::SettingBadValue);
+    }
+
+    if VALUES[setting as usize].swap(value, Ordering::SeqCst) != value
+    {
+        hvdebug!("Setting {} changed to {}", setting.name(), value);
+    }
+    Ok(())
+}
+
+/* <= the last part of a module path, eg: scheduler for diosix::scheduler */
+fn module_name(path: &str) -> &str
+{
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+/* change how much a module writes to the debug log
+   => module = name of the module, or "default" for modules without a verbosity of their own
+      verbosity = how much it should write
+   <= Ok for success, or an error code if the name is bad */
+pub fn set_verbosity(module: &str, verbosity: Verbosity) -> Result<(), Cause>
+{
+    if module == DEFAULT_MODULE
+    {
+        DEFAULT_VERBOSITY.store(verbosity as usize, Ordering::SeqCst);
+        return Ok(());
+    }
+
+    if module.len() == 0 || module.len() > MODULE_NAME_MAX_LEN ||
+       module.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') == false
+    {
+        return Err(Cause::SettingBadName);
+    }
+
+    VERBOSITY.lock().insert(String::from(module), verbosity);
+    OVERRIDDEN.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/* <= the verbosity of modules without one of their own */
+fn default_verbosity() -> Verbosity
+{
+    Verbosity::from_usize(DEFAULT_VERBOSITY.load(Ordering::Relaxed)).unwrap_or(Verbosity::Debug)
+}
+
+/* check whether a module's debugging information should be written out. this is called by hvdebug!()
+   => path = module path of the code writing the information
+   <= true to write it, or false to drop it */
+pub fn debug_enabled(path: &str) -> bool
+{
+    /* don't deadlock if information is written, eg: by a stuck lock, while the verbosities are being changed */
+    let verbosity = match OVERRIDDEN.load(Ordering::Relaxed) == false || VERBOSITY.is_locked() == true
+    {
+        true => default_verbosity(),
+        false => match VERBOSITY.lock().get(module_name(path))
+        {
+            Some(verbosity) => *verbosity,
+            None => default_verbosity()
+        }
+    };
+
+    verbosity == Verbosity::Debug
+}
+
+/* <= a verbosity's name */
+fn verbosity_name(verbosity: Verbosity) -> &'static str
+{
+    match verbosity
+    {
+        Verbosity::Alerts => "alerts",
+        Verbosity::Debug => "debug"
+    }
+}
+
+/* change a setting or a module's verbosity by name, as written by list() and the command prompt
+   => name = name of the setting, or log.<module> for a module's verbosity
+      value = value of the setting, or alerts or debug for a verbosity
+   <= Ok for success, or an error code */
+pub fn set_by_name(name: &str, value: &str) -> Result<(), Cause>
+{
+    let (name, value) = (name.trim(), value.trim());
+
+    if let Some(module) = name.strip_prefix(LOG_PREFIX)
+    {
+        return set_verbosity(module, match value
+        {
+            "alerts" => Verbosity::Alerts,
+            "debug" => Verbosity::Debug,
+            _ => return Err(Cause::SettingBadValue)
+        });
+    }
+
+    for index in 0..SETTINGS
+    {
+        if let Some(setting) = Setting::from_usize(index)
+        {
+            if setting.name() == name
+            {
+                return match value.parse::<u64>()
+                {
+                    Ok(value) => set(setting, value),
+                    Err(_) => Err(Cause::SettingBadValue)
+                };
+            }
+        }
+    }
+
+    Err(Cause::SettingBadName)
+}
+
+/* <= every setting and module verbosity as name=value lines, in the form set_by_name() accepts */
+pub fn list() -> String
+{
+    let mut text = String::new();
+    for index in 0..SETTINGS
+    {
+        if let Some(setting) = Setting::from_usize(index)
+        {
+            let _ = write!(text, "{}={}\n", setting.name(), get(setting));
+        }
+    }
+
+    let _ = write!(text, "{}{}={}\n", LOG_PREFIX, DEFAULT_MODULE, verbosity_name(default_verbosity()));
+
+    let mut modules: Vec<(String, Verbosity)> = VERBOSITY.lock().iter().map(|(m, v)| (m.clone(), *v)).collect();
+    modules.sort_by(|a, b| a.0.cmp(&b.0));
+    for (module, verbosity) in modules
+    {
+        let _ = write!(text, "{}{}={}\n", LOG_PREFIX, module, verbosity_name(verbosity));
+    }
+    text
+}
+
+/* save the current settings to the persistent store, to be restored on the next boot
+   <= Ok for success, or an error code if there's no store or no room in it */
+pub fn save() -> Result<(), Cause>
+{
+    pstore::save_settings(list().as_bytes())
+}
+
+/* read one of the settings for the currently running capsule.
+   *** the currently running capsule must have the host_settings property ***
+   => setting = number of the setting
+   <= its value, or an error code */
+pub fn read(setting: usize) -> Result<u64, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::HostSettings)?;
+    match Setting::from_usize(setting)
+    {
+        Some(setting) => Ok(get(setting)),
+        None => Err(Cause::SettingBadName)
+    }
+}
+
+/* change one of the settings for the currently running capsule.
+   *** the currently running capsule must have the host_settings property ***
+   => setting = number of the setting
+      value = its new value
+   <= Ok for success, or an error code */
+pub fn write(setting: usize, value: u64) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::HostSettings)?;
+    match Setting::from_usize(setting)
+    {
+        Some(setting) => set(setting, value),
+        None => Err(Cause::SettingBadName)
+    }
+}
+
+/* change a module's debug log verbosity for the currently running capsule.
+   *** the currently running capsule must have the host_settings property ***
+   => module, length = address and length in bytes of the module's name in the capsule
+      verbosity = number of the verbosity
+   <= Ok for success, or an error code */
+pub fn write_verbosity(module: usize, length: usize, verbosity: usize) -> Result<(), Cause>
+{
+    let cid = capsule::get_capsule_id_if_property(CapsuleProperty::HostSettings)?;
+    let module = service::read_text(cid, module, length)?;
+    match Verbosity::from_usize(verbosity)
+    {
+        Some(verbosity) => set_verbosity(&module, verbosity),
+        None => Err(Cause::SettingBadValue)
+    }
+}
+
+/* save the current settings to the persistent store for the currently running capsule.
+   *** the currently running capsule must have the host_settings property ***
+   <= Ok for success, or an error code */
+pub fn write_persistent() -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::HostSettings)?;
+    save()
+}
@@ -0,0 +1,237 @@
+/* diosix SHA-256 and HMAC-SHA256
+ *
+ * A small, self-contained implementation of SHA-256 (FIPS 180-4) and
+ * HMAC-SHA256 (RFC 2104), used to measure capsules' boot images and to
+ * unseal secrets bound to those measurements. Speed isn't a concern:
+ * it's only run as capsules are created and when secrets are released.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+/* size of a digest in bytes */
+pub const DIGEST_SIZE: usize = 32;
+
+/* size of a block of input in bytes */
+const BLOCK_SIZE: usize = 64;
+
+pub type Digest = [u8; DIGEST_SIZE];
+
+const K: [u32; 64] =
+[
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+const INITIAL: [u32; 8] =
+[
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+/* a hash in progress */
+#[derive(Clone)]
+pub struct Sha256
+{
+    state: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    used: usize,    /* bytes waiting in block */
+    length: u64     /* total bytes hashed so far */
+}
+
+impl Sha256
+{
+    pub fn new() -> Sha256
+    {
+        Sha256
+        {
+            state: INITIAL,
+            block: [0; BLOCK_SIZE],
+            used: 0,
+            length: 0
+        }
+    }
+
+    /* add bytes to the hash */
+    pub fn update(&mut self, mut data: &[u8])
+    {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        while data.len() > 0
+        {
+            let take = core::cmp::min(BLOCK_SIZE - self.used, data.len());
+            self.block[self.used..self.used + take].copy_from_slice(&data[..take]);
+            self.used = self.used + take;
+            data = &data[take..];
+
+            if self.used == BLOCK_SIZE
+            {
+                self.compress();
+                self.used = 0;
+            }
+        }
+    }
+
+    /* <= the digest of everything added to the hash */
+    pub fn finish(mut self) -> Digest
+    {
+        let bits = self.length.wrapping_mul(8);
+
+        /* pad with a one bit, then zeroes up to the length in the last eight bytes of a block */
+        self.block[self.used] = 0x80;
+        self.used = self.used + 1;
+        if self.used > BLOCK_SIZE - 8
+        {
+            self.block[self.used..].iter_mut().for_each(|b| *b = 0);
+            self.compress();
+            self.used = 0;
+        }
+        self.block[self.used..BLOCK_SIZE - 8].iter_mut().for_each(|b| *b = 0);
+        self.block[BLOCK_SIZE - 8..].copy_from_slice(&bits.to_be_bytes());
+        self.compress();
+
+        let mut digest = [0; DIGEST_SIZE];
+        for (word, bytes) in self.state.iter().zip(digest.chunks_mut(4))
+        {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /* mix a full block into the state */
+    fn compress(&mut self)
+    {
+        let mut w = [0u32; 64];
+        for (i, bytes) in self.block.chunks(4).enumerate()
+        {
+            w[i] = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64
+        {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64
+        {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter())
+        {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+/* <= the SHA-256 digest of the given bytes */
+pub fn digest(data: &[u8]) -> Digest
+{
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+/* <= the HMAC-SHA256 of the given parts, concatenated, under the given key */
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> Digest
+{
+    /* keys longer than a block are hashed down first */
+    let mut padded = [0u8; BLOCK_SIZE];
+    match key.len() > BLOCK_SIZE
+    {
+        true => padded[..DIGEST_SIZE].copy_from_slice(&digest(key)),
+        false => padded[..key.len()].copy_from_slice(key)
+    }
+
+    let mut ipad = [0u8; BLOCK_SIZE];
+    let mut opad = [0u8; BLOCK_SIZE];
+    for i in 0..BLOCK_SIZE
+    {
+        ipad[i] = padded[i] ^ 0x36;
+        opad[i] = padded[i] ^ 0x5c;
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&ipad);
+    for part in parts
+    {
+        inner.update(part);
+    }
+    let inner = inner.finish();
+
+    let mut outer = Sha256::new();
+    outer.update(&opad);
+    outer.update(&inner);
+
+    /* don't leave copies of the key lying around */
+    for buffer in [&mut padded, &mut ipad, &mut opad].iter_mut()
+    {
+        buffer.iter_mut().for_each(|b| *b = 0);
+    }
+    outer.finish()
+}
+
+/* compare two byte strings in time that depends only on their lengths, so that
+   comparing secret values, such as authentication tags, leaks nothing about them
+   <= true if they're the same */
+pub fn equal(a: &[u8], b: &[u8]) -> bool
+{
+    if a.len() != b.len()
+    {
+        return false;
+    }
+
+    let mut difference = 0;
+    for (x, y) in a.iter().zip(b.iter())
+    {
+        difference = difference | (x ^ y);
+    }
+    difference == 0
+}
+
+#[test_case]
+fn test_sha256_known_answers()
+{
+    /* FIPS 180-4 and RFC 4231 test vectors */
+    let abc: Digest =
+    [
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+        0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad
+    ];
+    assert_eq!(digest(b"abc"), abc);
+
+    let two_blocks: Digest =
+    [
+        0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60, 0x39,
+        0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb, 0x06, 0xc1
+    ];
+    assert_eq!(digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), two_blocks);
+
+    let jefe: Digest =
+    [
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+        0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43
+    ];
+    assert_eq!(hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]), jefe);
+}
@@ -0,0 +1,483 @@
+/* diosix stream connections between capsules and services
+ *
+ * Single-character serial links and fixed-size messages are fine for
+ * simple exchanges, but file and network proxies need to move streams
+ * of bytes. A stream connection joins a capsule to a named service,
+ * either one offered by another capsule or one built into the hypervisor.
+ *
+ * A capsule offering a service registers its name as usual, then
+ * listens for connections on it. Other capsules connect by name, subject
+ * to the same access rights as looking up the name, and the listening
+ * capsule accepts each connection from its backlog. Names in the diosix
+ * namespace are services built into the hypervisor, which are connected
+ * to straight away. A capsule can hold many connections at once, each
+ * identified by the number returned when it was made or accepted.
+ *
+ * Each direction of a connection has its own bounded buffer. A send
+ * takes only as many bytes as there's room for in the receiver's buffer
+ * and returns how many it took, so a fast sender can't swamp a slow
+ * receiver. The capsule at the other end is sent VIRQ_STREAM when a
+ * connection arrives for it, when data arrives in one of its empty
+ * buffers, when room is made for a send it couldn't complete, and when
+ * its peer closes the connection. Reading from a connection closed by
+ * the peer, once its buffer is empty, returns zero bytes.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::service;
+use super::passthrough::{self, DeviceIRQ};
+use super::pcore;
+use super::hcargs::{self, Access};
+
+pub type ConnectionID = usize;
+
+/* virtual interrupt raised when something happens to one of a capsule's connections */
+pub const VIRQ_STREAM: DeviceIRQ = hypercall::irq::VIRQ_STREAM;
+
+/* maximum number of bytes waiting to be read from each direction of a connection */
+pub const STREAM_BUFFER_MAX: usize = hypercall::stream::STREAM_BUFFER_MAX;
+
+/* maximum number of connections waiting to be accepted by each listening service */
+const BACKLOG_MAX: usize = 16;
+
+/* maximum number of open connections a capsule can have */
+const CONNECTIONS_MAX: usize = 64;
+
+/* a service built into the hypervisor is a function that consumes bytes
+   sent to it and writes its replies, limited to STREAM_BUFFER_MAX bytes
+   => input = bytes sent to the service, to be removed as they're handled
+      output = bytes waiting to be read by the connected capsule */
+type HostService = fn(&mut VecDeque<u8>, &mut VecDeque<u8>);
+
+/* services built into the hypervisor, by name */
+const HOST_SERVICES: [(&str, HostService); 1] =
+[
+    ("diosix.echo", echo)
+];
+
+/* send back everything received, so guests can test their stream code */
+fn echo(input: &mut VecDeque<u8>, output: &mut VecDeque<u8>)
+{
+    while output.len() < STREAM_BUFFER_MAX
+    {
+        match input.pop_front()
+        {
+            Some(byte) => output.push_back(byte),
+            None => break
+        }
+    }
+}
+
+/* what's at one end of a connection */
+#[derive(Clone, Copy)]
+enum End
+{
+    Capsule(CapsuleID),
+    Hypervisor(HostService)
+}
+
+/* a connection's two ends: the connecting capsule at index 0, and the service at index 1.
+   each end has the bytes waiting to be read by it, and whether it has closed the
+   connection, and whether its last send was cut short by a full buffer */
+struct Connection
+{
+    ends: [End; 2],
+    waiting: [VecDeque<u8>; 2],
+    closed: [bool; 2],
+    blocked: [bool; 2]
+}
+
+impl Connection
+{
+    pub fn new(client: CapsuleID, server: End) -> Connection
+    {
+        Connection
+        {
+            ends: [End::Capsule(client), server],
+            waiting: [VecDeque::new(), VecDeque::new()],
+            closed: [false, false],
+            blocked: [false, false]
+        }
+    }
+
+    /* return the index of the given capsule's end of the connection, or None if it doesn't have an open end */
+    fn end_of(&self, cid: CapsuleID) -> Option<usize>
+    {
+        (0..2).find(|index| match self.ends[*index]
+        {
+            End::Capsule(owner) => owner == cid && self.closed[*index] == false,
+            End::Hypervisor(_) => false
+        })
+    }
+
+    /* raise VIRQ_STREAM for the capsule at the given end, if there is one */
+    fn notify(&self, end: usize)
+    {
+        if let End::Capsule(cid) = self.ends[end]
+        {
+            passthrough::raise_virtual_irq(cid, VIRQ_STREAM);
+        }
+    }
+
+    /* let a service built into the hypervisor handle the bytes sent to it,
+       telling the connected capsule if replies arrive in its empty buffer */
+    fn run_host_service(&mut self)
+    {
+        if let End::Hypervisor(service) = self.ends[1]
+        {
+            let [to_client, to_service] = &mut self.waiting;
+            let was_empty = to_client.len() == 0;
+            service(to_service, to_client);
+            if was_empty && to_client.len() > 0
+            {
+                self.notify(0);
+            }
+        }
+    }
+}
+
+/* a capsule listening for connections to a service name it registered */
+struct Listener
+{
+    cid: CapsuleID,
+    backlog: VecDeque<ConnectionID>
+}
+
+struct Streams
+{
+    next_id: ConnectionID,
+    listeners: HashMap<String, Listener>,
+    connections: HashMap<ConnectionID, Connection>
+}
+
+impl Streams
+{
+    /* close one end of a connection, removing the connection if neither end is left,
+       otherwise telling the capsule at the other end */
+    fn close(&mut self, id: ConnectionID, end: usize)
+    {
+        let remove = match self.connections.get_mut(&id)
+        {
+            Some(connection) =>
+            {
+                let other = 1 - end;
+                connection.closed[end] = true;
+                connection.waiting[end].clear();
+                match connection.ends[other]
+                {
+                    End::Hypervisor(_) => true,
+                    End::Capsule(_) if connection.closed[other] => true,
+                    End::Capsule(_) =>
+                    {
+                        connection.notify(other);
+                        false
+                    }
+                }
+            },
+            None => return
+        };
+
+        if remove
+        {
+            self.connections.remove(&id);
+            for listener in self.listeners.values_mut()
+            {
+                listener.backlog.retain(|waiting| *waiting != id);
+            }
+        }
+    }
+}
+
+lazy_static!
+{
+    static ref STREAMS: Mutex<Streams> = Mutex::new("stream connections", Streams
+    {
+        next_id: 1,
+        listeners: HashMap::new(),
+        connections: HashMap::new()
+    });
+}
+
+/* read the name of a service to listen on or connect to from a capsule
+   => cid = ID of capsule holding the name
+      name, length = address and length in bytes of the name in the capsule
+   <= the name, or an error code */
+fn read_name(cid: CapsuleID, name: usize, length: usize) -> Result<String, Cause>
+{
+    let name = service::read_text(cid, name, length)?;
+    if service::in_reserved_namespace(&name) == false
+    {
+        service::check_name(&name)?;
+    }
+    Ok(name)
+}
+
+/* return the ID of the currently running capsule, or an error code */
+fn current_capsule() -> Result<CapsuleID, Cause>
+{
+    match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => Ok(cid),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* listen for connections to a service name registered by the currently running capsule
+   => name, length = address and length in bytes of the name in the capsule
+   <= Ok for success, or an error code */
+pub fn listen(name: usize, length: usize) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+    let name = read_name(cid, name, length)?;
+    if service::in_reserved_namespace(&name) == true || service::lookup_name(&name, cid)? != cid
+    {
+        return Err(Cause::ServiceNotAllowed);
+    }
+
+    /* the name may have changed hands since it was last listened on. drop connections still waiting for the old owner */
+    let mut streams = STREAMS.lock();
+    let stale = match streams.listeners.get(&name)
+    {
+        Some(listener) if listener.cid == cid => return Ok(()),
+        Some(listener) => listener.backlog.clone(),
+        None => VecDeque::new()
+    };
+
+    for id in stale
+    {
+        streams.close(id, 1);
+    }
+
+    streams.listeners.insert(name, Listener { cid, backlog: VecDeque::new() });
+    Ok(())
+}
+
+/* connect the currently running capsule to a named service
+   => name, length = address and length in bytes of the service's name in the capsule
+   <= ID of the new connection, or an error code */
+pub fn connect(name: usize, length: usize) -> Result<ConnectionID, Cause>
+{
+    let cid = current_capsule()?;
+    let name = read_name(cid, name, length)?;
+    let server = match service::in_reserved_namespace(&name)
+    {
+        true => match HOST_SERVICES.iter().find(|(host, _)| *host == name)
+        {
+            Some((_, service)) => End::Hypervisor(*service),
+            None => return Err(Cause::ServiceNotFound)
+        },
+
+        /* don't hold the streams lock while checking the capsule can use the name */
+        false => End::Capsule(service::lookup_name(&name, cid)?)
+    };
+
+    let mut streams = STREAMS.lock();
+    let open = streams.connections.values().filter(|connection| connection.end_of(cid).is_some()).count();
+    if open >= CONNECTIONS_MAX
+    {
+        return Err(Cause::StreamTooMany);
+    }
+
+    let id = streams.next_id;
+    if let End::Capsule(owner) = server
+    {
+        match streams.listeners.get_mut(&name)
+        {
+            Some(listener) if listener.cid == owner =>
+            {
+                if listener.backlog.len() >= BACKLOG_MAX
+                {
+                    return Err(Cause::StreamBacklogFull);
+                }
+                listener.backlog.push_back(id);
+                passthrough::raise_virtual_irq(owner, VIRQ_STREAM);
+            },
+            _ => return Err(Cause::StreamNotListening)
+        }
+    }
+
+    streams.next_id = id + 1;
+    streams.connections.insert(id, Connection::new(cid, server));
+    Ok(id)
+}
+
+/* accept the next connection waiting for any of the services the currently running capsule is listening on
+   <= ID of the connection, or CapsuleBufferEmpty if none are waiting */
+pub fn accept() -> Result<ConnectionID, Cause>
+{
+    let cid = current_capsule()?;
+    for listener in STREAMS.lock().listeners.values_mut()
+    {
+        if listener.cid == cid
+        {
+            if let Some(id) = listener.backlog.pop_front()
+            {
+                return Ok(id);
+            }
+        }
+    }
+
+    Err(Cause::CapsuleBufferEmpty)
+}
+
+/* send bytes from the currently running capsule over a connection, taking only as many as there's room for
+   => id = connection to send over
+      buffer, length = address and length in bytes of the data in the capsule
+   <= number of bytes sent, which may be zero if the receiver's buffer is full, or an error code */
+pub fn send(id: ConnectionID, buffer: usize, length: usize) -> Result<usize, Cause>
+{
+    let cid = current_capsule()?;
+    if length == 0
+    {
+        return Ok(0);
+    }
+
+    let length = core::cmp::min(length, STREAM_BUFFER_MAX);
+    let base = hcargs::buffer(cid, buffer, length, STREAM_BUFFER_MAX, Access::Read)?;
+    let bytes = unsafe { core::slice::from_raw_parts(base as *const u8, length) };
+
+    let mut streams = STREAMS.lock();
+    let connection = match streams.connections.get_mut(&id)
+    {
+        Some(c) => c,
+        None => return Err(Cause::StreamBadID)
+    };
+
+    let end = match connection.end_of(cid)
+    {
+        Some(index) => index,
+        None => return Err(Cause::StreamBadID)
+    };
+
+    let other = 1 - end;
+    if connection.closed[other] == true
+    {
+        return Err(Cause::StreamClosed);
+    }
+
+    let receiver = &mut connection.waiting[other];
+    let was_empty = receiver.len() == 0;
+    let sent = core::cmp::min(length, STREAM_BUFFER_MAX - receiver.len());
+    receiver.extend(bytes[..sent].iter());
+    connection.blocked[end] = sent < length;
+
+    match connection.ends[other]
+    {
+        End::Hypervisor(_) => connection.run_host_service(),
+        End::Capsule(_) => if was_empty && sent > 0
+        {
+            connection.notify(other);
+        }
+    }
+
+    Ok(sent)
+}
+
+/* receive bytes waiting for the currently running capsule on a connection
+   => id = connection to receive from
+      buffer, length = address and size in bytes of the buffer in the capsule to fill
+   <= number of bytes received, which is zero if the peer has closed the connection and
+      nothing is left to read, or CapsuleBufferEmpty if nothing is waiting, or an error code */
+pub fn recv(id: ConnectionID, buffer: usize, length: usize) -> Result<usize, Cause>
+{
+    let cid = current_capsule()?;
+    if length == 0
+    {
+        return Ok(0);
+    }
+
+    let length = core::cmp::min(length, STREAM_BUFFER_MAX);
+    let base = hcargs::buffer(cid, buffer, length, STREAM_BUFFER_MAX, Access::Write)?;
+    let bytes = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, length) };
+
+    let mut streams = STREAMS.lock();
+    let connection = match streams.connections.get_mut(&id)
+    {
+        Some(c) => c,
+        None => return Err(Cause::StreamBadID)
+    };
+
+    let end = match connection.end_of(cid)
+    {
+        Some(index) => index,
+        None => return Err(Cause::StreamBadID)
+    };
+
+    let other = 1 - end;
+    let waiting = &mut connection.waiting[end];
+    if waiting.len() == 0
+    {
+        return match connection.closed[other]
+        {
+            true => Ok(0),
+            false => Err(Cause::CapsuleBufferEmpty)
+        };
+    }
+
+    let received = core::cmp::min(length, waiting.len());
+    for (index, byte) in waiting.drain(..received).enumerate()
+    {
+        bytes[index] = byte;
+    }
+
+    /* now there's room, let the other end carry on sending */
+    match connection.ends[other]
+    {
+        End::Hypervisor(_) => connection.run_host_service(),
+        End::Capsule(_) => if connection.blocked[other] == true
+        {
+            connection.blocked[other] = false;
+            connection.notify(other);
+        }
+    }
+
+    Ok(received)
+}
+
+/* close the currently running capsule's end of a connection, discarding any data waiting for it
+   => id = connection to close
+   <= Ok for success, or an error code */
+pub fn close(id: ConnectionID) -> Result<(), Cause>
+{
+    let cid = current_capsule()?;
+    let mut streams = STREAMS.lock();
+    let end = match streams.connections.get(&id)
+    {
+        Some(connection) => match connection.end_of(cid)
+        {
+            Some(index) => index,
+            None => return Err(Cause::StreamBadID)
+        },
+        None => return Err(Cause::StreamBadID)
+    };
+
+    streams.close(id, end);
+    Ok(())
+}
+
+/* close all of a capsule's connections and stop it listening when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    let mut streams = STREAMS.lock();
+    streams.listeners.retain(|_, listener| listener.cid != cid);
+
+    /* this includes connections still waiting to be accepted by the capsule */
+    let ends: Vec<(ConnectionID, usize)> = streams.connections.iter()
+        .filter_map(|(id, connection)| connection.end_of(cid).map(|end| (*id, end)))
+        .collect();
+
+    for (id, end) in ends
+    {
+        streams.close(id, end);
+    }
+}
@@ -0,0 +1,428 @@
+/* diosix hypercall decoding
+ *
+ * Capsules make hypercalls with environment calls, following the
+ * convention set out in the hypercall crate: the hypervisor's SBI
+ * extension ID in a7, the number of the call in a6, and its parameters
+ * in a0 to a5. The call's result code is returned in a0, and any values
+ * it produces in a1 to a3. Calls are decoded here from the registers
+ * stacked in the trap's IRQ context, and their results written back to
+ * them, so that every call can be reached whatever the platform code
+ * knows about.
+ *
+ * Unmodified guests, such as the bundled Linux kernels, use the
+ * standard SBI instead. Its base extension is answered here, reporting
+ * SBI v0.2 and which extensions are present, and its TIME extension and
+ * the legacy v0.1 timer and console calls are decoded into the same
+ * actions as the hypervisor's own timer and console calls. Any other
+ * standard extension is refused with the SBI's not-supported error.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use platform::irq::IRQContext;
+use platform::timer::TimerValue;
+use hypercall::call::{self, Call};
+use super::trap::{self, REG_A0, REG_A1, REG_A2, REG_A3, REG_A6, REG_A7};
+use super::abi;
+use super::pcore;
+
+pub use hypercall::call::ActionResult;
+
+/* number of parameters a hypercall can take, in a0 to a5 */
+const PARAMETERS: usize = 6;
+
+/* standard SBI extensions decoded alongside the hypervisor's own */
+const SBI_EXT_LEGACY_SET_TIMER: usize = 0x00;
+const SBI_EXT_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const SBI_EXT_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+#[cfg(feature = "sbilegacy")]
+const SBI_EXT_LEGACY_SHUTDOWN: usize = 0x08;
+const SBI_EXT_BASE: usize = 0x10;
+const SBI_EXT_TIME: usize = 0x5449_4d45; /* "TIME" */
+
+/* functions of the base and TIME extensions, passed in a6 */
+const SBI_BASE_GET_SPEC_VERSION: usize = 0;
+const SBI_BASE_GET_IMPL_ID: usize = 1;
+const SBI_BASE_GET_IMPL_VERSION: usize = 2;
+const SBI_BASE_PROBE_EXTENSION: usize = 3;
+const SBI_BASE_GET_MVENDORID: usize = 4;
+const SBI_BASE_GET_MARCHID: usize = 5;
+const SBI_BASE_GET_MIMPID: usize = 6;
+const SBI_TIME_SET_TIMER: usize = 0;
+
+/* SBI version implemented, v0.2, with the major version in bits 24 to 30 and the minor in bits 0 to 23,
+   and diosix's implementation ID as registered in the SBI specification */
+const SBI_SPEC_VERSION: usize = 2;
+const SBI_IMPL_ID: usize = 5;
+
+/* standard SBI error codes, returned in a0 */
+const SBI_SUCCESS: usize = 0;
+const SBI_ERR_NOT_SUPPORTED: usize = -2isize as usize;
+
+/* what a capsule has asked the hypervisor to do, and the parameters it passed */
+#[derive(Debug)]
+pub enum Action
+{
+    Yield,
+    NegotiateABI(usize),                            /* version */
+    Identify(usize),                                /* leaf */
+    Terminate,
+    Restart,
+    TimerIRQAt(TimerValue),                         /* target */
+    TimerAdd(TimerValue),                           /* target */
+    TimerCancel(usize),                             /* id */
+    TimerFiredNext,
+    MetricsRead(usize, usize),                      /* cid, counter */
+    MetricsReadSystem(usize),                       /* counter */
+    TelemetryRead(usize, usize),                    /* buffer, size */
+    GuestLog(usize, usize, usize, usize),           /* severity, tag, message, length */
+    GuestPanic(usize, usize, usize, usize),         /* message, length, registers, count */
+    CapsulePanicMessage(usize, usize, usize),       /* cid, buffer, size */
+    CapsulePanicRegisters(usize, usize, usize),     /* cid, buffer, count */
+    CapsuleCrashDump(usize, usize, usize),          /* cid, buffer, size */
+    CapsuleReadRegisters(usize, usize, usize, usize), /* cid, vid, buffer, count */
+    CapsuleReadMemory(usize, usize, usize, usize),  /* cid, address, buffer, size */
+    InventoryRead(usize, usize),                    /* buffer, size */
+    IPISend(usize),                                 /* target */
+    IPIClaim,
+    SerialLinkPutc(usize, usize),                   /* link, byte */
+    SerialLinkGetc(usize),                          /* link */
+    OutputChar(usize),                              /* character */
+    InputChar,
+    ConsoleBufferWriteChar(usize, usize),           /* character, capsule_id */
+    ConsoleBufferOverflows(usize),                  /* capsule_id */
+    ConsoleBufferReadChar,
+    ConsoleEncoding(usize),                         /* capsule_id */
+    ConsoleInputMode(usize),                        /* mode */
+    HypervisorBufferReadChar,
+    TraceReadChar,
+    SelfTestRun,
+    ExternalIRQClaim,
+    CapsuleCrashedNext,
+    CapsuleResume(usize),                           /* cid */
+    CapsuleKillPaused(usize),                       /* cid */
+    CapsuleWorkingSet(usize),                       /* cid */
+    WarmReboot,
+    HostReset(usize, usize),                        /* reset, grace */
+    MemoryPressureSubscribe,
+    GrantCreate(usize, usize, usize, usize),        /* addr, size, grantee, access */
+    GrantAccept(usize),                             /* id */
+    GrantRelease(usize),                            /* id */
+    GrantRevoke(usize),                             /* id */
+    DirtyLogStart(usize),                           /* cid */
+    DirtyLogRead(usize, usize, usize),              /* cid, buffer, size */
+    DirtyLogStop(usize),                            /* cid */
+    MMIOMap(usize, usize),                          /* base, size */
+    MMIOUnmap(usize),                               /* base */
+    TemplateQuiesce(usize),                         /* entry */
+    TemplateMark(usize),                            /* cid */
+    TemplateUnmark(usize),                          /* cid */
+    TemplateClone(usize),                           /* cid */
+    CapsuleQuiesce(usize, usize),                   /* cid, timeout */
+    CapsuleQuiesceStatus(usize),                    /* cid */
+    CapsuleThaw(usize),                             /* cid */
+    QuiesceDone,
+    CapsulePressButton(usize, usize, usize),        /* cid, button, grace */
+    ButtonCollect,
+    MeasurementRead(usize),                         /* buffer */
+    SecretRead(usize, usize, usize, usize),         /* name, length, buffer, size */
+    SettingRead(usize),                             /* setting */
+    SettingWrite(usize, usize),                     /* setting, value */
+    SettingVerbosity(usize, usize, usize),          /* module, length, verbosity */
+    SettingsSave,
+    GpioSetDirection(usize, usize),                 /* line, direction */
+    GpioWrite(usize, usize),                        /* line, value */
+    GpioRead(usize),                                /* line */
+    CapsuleSnapshot(usize, usize),                  /* buffer, count */
+    CapsuleBootConfirm,
+    CapsuleSelectBootImage(usize, usize, usize),    /* cid, name, length */
+    SelectService(usize),                           /* stype_nr */
+    TransferOffer(usize, usize, usize, usize),      /* stype_nr, direction, list, count */
+    TransferNext(usize),                            /* stype_nr */
+    TransferAccept(usize, usize, usize),            /* id, list, count */
+    BounceMap(usize, usize, usize),                 /* buffer, size, direction */
+    BounceUnmap(usize, usize),                      /* id, size */
+    TimeMonotonic,
+    TimeWallClock,
+    TimeSetOffset(usize),                           /* offset */
+    RegisterService(usize),                         /* stype_nr */
+    RegisterServiceName(usize, usize),              /* name, length */
+    DeregisterServiceName(usize, usize),            /* name, length */
+    LookupServiceName(usize, usize),                /* name, length */
+    StreamListen(usize, usize),                     /* name, length */
+    StreamConnect(usize, usize),                    /* name, length */
+    StreamAccept,
+    StreamSend(usize, usize, usize),                /* id, buffer, length */
+    StreamRecv(usize, usize, usize),                /* id, buffer, length */
+    StreamClose(usize),                             /* id */
+    ExternalIRQComplete(usize)                      /* irq */
+}
+
+/* decode a capsule's environment call into a hypercall. a decoded call is marked as successful
+   until its handler says otherwise
+   => context = IRQ context of the environment call
+   <= the call to carry out, or None if the call has been answered here, either because it's a
+      query of the standard SBI's base extension or because it isn't a call this hypervisor knows
+      about, in which case the appropriate error code has been returned to the capsule */
+pub fn handler(context: &mut IRQContext) -> Option<Action>
+{
+    let extension = trap::register(context, REG_A7);
+    let function = trap::register(context, REG_A6);
+
+    let mut args = [0; PARAMETERS];
+    for (index, arg) in args.iter_mut().enumerate()
+    {
+        *arg = trap::register(context, REG_A0 + index);
+    }
+
+    let action = match (extension, function)
+    {
+        (call::SBI_EXTENSION, number) => match Call::from_usize(number)
+        {
+            Some(number) => decode(number, &args),
+            None =>
+            {
+                unknown(extension, function);
+                failed(context, ActionResult::BadParams);
+                return None;
+            }
+        },
+
+        /* the legacy calls ignore a6 and return their result in a0 */
+        (SBI_EXT_LEGACY_SET_TIMER, _) | (SBI_EXT_TIME, SBI_TIME_SET_TIMER) => Action::TimerIRQAt(TimerValue::Exact(args[0] as u64)),
+        (SBI_EXT_LEGACY_CONSOLE_PUTCHAR, _) => Action::OutputChar(args[0]),
+        (SBI_EXT_LEGACY_CONSOLE_GETCHAR, _) => Action::InputChar,
+
+        (SBI_EXT_BASE, function) =>
+        {
+            base(context, function, args[0]);
+            return None;
+        },
+
+        _ =>
+        {
+            unknown(extension, function);
+            trap::set_register(context, REG_A0, SBI_ERR_NOT_SUPPORTED);
+            return None;
+        }
+    };
+
+    trap::set_register(context, REG_A0, ActionResult::Success as usize);
+    Some(action)
+}
+
+/* note a call this hypervisor doesn't know about
+   => extension, function = the extension and function called */
+fn unknown(_extension: usize, _function: usize)
+{
+    if let Some(_c) = pcore::PhysicalCore::get_capsule_id()
+    {
+        hvdebug!("Capsule {}: Unknown hypercall: extension 0x{:x} function 0x{:x}", _c, _extension, _function);
+    }
+}
+
+/* answer a call to the standard SBI's base extension
+   => context = IRQ context of the environment call
+      function = function of the base extension called
+      arg = its parameter, from a0 */
+fn base(context: &mut IRQContext, function: usize, arg: usize)
+{
+    let value = match function
+    {
+        SBI_BASE_GET_SPEC_VERSION => SBI_SPEC_VERSION,
+        SBI_BASE_GET_IMPL_ID => SBI_IMPL_ID,
+        SBI_BASE_GET_IMPL_VERSION => abi::packed_version(),
+        SBI_BASE_PROBE_EXTENSION => match probe(arg)
+        {
+            true => 1,
+            false => 0
+        },
+
+        /* capsules run on virtual cores that don't describe the physical ones */
+        SBI_BASE_GET_MVENDORID | SBI_BASE_GET_MARCHID | SBI_BASE_GET_MIMPID => 0,
+
+        _ =>
+        {
+            trap::set_register(context, REG_A0, SBI_ERR_NOT_SUPPORTED);
+            return;
+        }
+    };
+
+    trap::set_register(context, REG_A0, SBI_SUCCESS);
+    trap::set_register(context, REG_A1, value);
+}
+
+/* <= true if the given SBI extension can be called
+   => extension = extension ID to check */
+fn probe(extension: usize) -> bool
+{
+    match extension
+    {
+        call::SBI_EXTENSION | SBI_EXT_BASE | SBI_EXT_TIME |
+        SBI_EXT_LEGACY_SET_TIMER | SBI_EXT_LEGACY_CONSOLE_PUTCHAR | SBI_EXT_LEGACY_CONSOLE_GETCHAR => true,
+
+        /* translated by sbilegacy.rs when it's built */
+        #[cfg(feature = "sbilegacy")]
+        SBI_EXT_LEGACY_SHUTDOWN => true,
+
+        _ => false
+    }
+}
+
+/* decode one of the hypervisor's own hypercalls
+   => number = the call made
+      args = its parameters, from a0 to a5
+   <= the call to carry out */
+fn decode(number: Call, args: &[usize; PARAMETERS]) -> Action
+{
+    match number
+    {
+        Call::Yield => Action::Yield,
+        Call::NegotiateABI => Action::NegotiateABI(args[0]),
+        Call::Identify => Action::Identify(args[0]),
+        Call::Terminate => Action::Terminate,
+        Call::Restart => Action::Restart,
+        Call::TimerIRQAt => Action::TimerIRQAt(TimerValue::Exact(args[0] as u64)),
+        Call::TimerAdd => Action::TimerAdd(TimerValue::Exact(args[0] as u64)),
+        Call::TimerCancel => Action::TimerCancel(args[0]),
+        Call::TimerFiredNext => Action::TimerFiredNext,
+        Call::MetricsRead => Action::MetricsRead(args[0], args[1]),
+        Call::MetricsReadSystem => Action::MetricsReadSystem(args[0]),
+        Call::TelemetryRead => Action::TelemetryRead(args[0], args[1]),
+        Call::GuestLog => Action::GuestLog(args[0], args[1], args[2], args[3]),
+        Call::GuestPanic => Action::GuestPanic(args[0], args[1], args[2], args[3]),
+        Call::CapsulePanicMessage => Action::CapsulePanicMessage(args[0], args[1], args[2]),
+        Call::CapsulePanicRegisters => Action::CapsulePanicRegisters(args[0], args[1], args[2]),
+        Call::CapsuleCrashDump => Action::CapsuleCrashDump(args[0], args[1], args[2]),
+        Call::CapsuleReadRegisters => Action::CapsuleReadRegisters(args[0], args[1], args[2], args[3]),
+        Call::CapsuleReadMemory => Action::CapsuleReadMemory(args[0], args[1], args[2], args[3]),
+        Call::InventoryRead => Action::InventoryRead(args[0], args[1]),
+        Call::IPISend => Action::IPISend(args[0]),
+        Call::IPIClaim => Action::IPIClaim,
+        Call::SerialLinkPutc => Action::SerialLinkPutc(args[0], args[1]),
+        Call::SerialLinkGetc => Action::SerialLinkGetc(args[0]),
+        Call::OutputChar => Action::OutputChar(args[0]),
+        Call::InputChar => Action::InputChar,
+        Call::ConsoleBufferWriteChar => Action::ConsoleBufferWriteChar(args[0], args[1]),
+        Call::ConsoleBufferOverflows => Action::ConsoleBufferOverflows(args[0]),
+        Call::ConsoleBufferReadChar => Action::ConsoleBufferReadChar,
+        Call::ConsoleEncoding => Action::ConsoleEncoding(args[0]),
+        Call::ConsoleInputMode => Action::ConsoleInputMode(args[0]),
+        Call::HypervisorBufferReadChar => Action::HypervisorBufferReadChar,
+        Call::TraceReadChar => Action::TraceReadChar,
+        Call::SelfTestRun => Action::SelfTestRun,
+        Call::ExternalIRQClaim => Action::ExternalIRQClaim,
+        Call::CapsuleCrashedNext => Action::CapsuleCrashedNext,
+        Call::CapsuleResume => Action::CapsuleResume(args[0]),
+        Call::CapsuleKillPaused => Action::CapsuleKillPaused(args[0]),
+        Call::CapsuleWorkingSet => Action::CapsuleWorkingSet(args[0]),
+        Call::WarmReboot => Action::WarmReboot,
+        Call::HostReset => Action::HostReset(args[0], args[1]),
+        Call::MemoryPressureSubscribe => Action::MemoryPressureSubscribe,
+        Call::GrantCreate => Action::GrantCreate(args[0], args[1], args[2], args[3]),
+        Call::GrantAccept => Action::GrantAccept(args[0]),
+        Call::GrantRelease => Action::GrantRelease(args[0]),
+        Call::GrantRevoke => Action::GrantRevoke(args[0]),
+        Call::DirtyLogStart => Action::DirtyLogStart(args[0]),
+        Call::DirtyLogRead => Action::DirtyLogRead(args[0], args[1], args[2]),
+        Call::DirtyLogStop => Action::DirtyLogStop(args[0]),
+        Call::MMIOMap => Action::MMIOMap(args[0], args[1]),
+        Call::MMIOUnmap => Action::MMIOUnmap(args[0]),
+        Call::TemplateQuiesce => Action::TemplateQuiesce(args[0]),
+        Call::TemplateMark => Action::TemplateMark(args[0]),
+        Call::TemplateUnmark => Action::TemplateUnmark(args[0]),
+        Call::TemplateClone => Action::TemplateClone(args[0]),
+        Call::CapsuleQuiesce => Action::CapsuleQuiesce(args[0], args[1]),
+        Call::CapsuleQuiesceStatus => Action::CapsuleQuiesceStatus(args[0]),
+        Call::CapsuleThaw => Action::CapsuleThaw(args[0]),
+        Call::QuiesceDone => Action::QuiesceDone,
+        Call::CapsulePressButton => Action::CapsulePressButton(args[0], args[1], args[2]),
+        Call::ButtonCollect => Action::ButtonCollect,
+        Call::MeasurementRead => Action::MeasurementRead(args[0]),
+        Call::SecretRead => Action::SecretRead(args[0], args[1], args[2], args[3]),
+        Call::SettingRead => Action::SettingRead(args[0]),
+        Call::SettingWrite => Action::SettingWrite(args[0], args[1]),
+        Call::SettingVerbosity => Action::SettingVerbosity(args[0], args[1], args[2]),
+        Call::SettingsSave => Action::SettingsSave,
+        Call::GpioSetDirection => Action::GpioSetDirection(args[0], args[1]),
+        Call::GpioWrite => Action::GpioWrite(args[0], args[1]),
+        Call::GpioRead => Action::GpioRead(args[0]),
+        Call::CapsuleSnapshot => Action::CapsuleSnapshot(args[0], args[1]),
+        Call::CapsuleBootConfirm => Action::CapsuleBootConfirm,
+        Call::CapsuleSelectBootImage => Action::CapsuleSelectBootImage(args[0], args[1], args[2]),
+        Call::SelectService => Action::SelectService(args[0]),
+        Call::TransferOffer => Action::TransferOffer(args[0], args[1], args[2], args[3]),
+        Call::TransferNext => Action::TransferNext(args[0]),
+        Call::TransferAccept => Action::TransferAccept(args[0], args[1], args[2]),
+        Call::BounceMap => Action::BounceMap(args[0], args[1], args[2]),
+        Call::BounceUnmap => Action::BounceUnmap(args[0], args[1]),
+        Call::TimeMonotonic => Action::TimeMonotonic,
+        Call::TimeWallClock => Action::TimeWallClock,
+        Call::TimeSetOffset => Action::TimeSetOffset(args[0]),
+        Call::RegisterService => Action::RegisterService(args[0]),
+        Call::RegisterServiceName => Action::RegisterServiceName(args[0], args[1]),
+        Call::DeregisterServiceName => Action::DeregisterServiceName(args[0], args[1]),
+        Call::LookupServiceName => Action::LookupServiceName(args[0], args[1]),
+        Call::StreamListen => Action::StreamListen(args[0], args[1]),
+        Call::StreamConnect => Action::StreamConnect(args[0], args[1]),
+        Call::StreamAccept => Action::StreamAccept,
+        Call::StreamSend => Action::StreamSend(args[0], args[1], args[2]),
+        Call::StreamRecv => Action::StreamRecv(args[0], args[1], args[2]),
+        Call::StreamClose => Action::StreamClose(args[0]),
+        Call::ExternalIRQComplete => Action::ExternalIRQComplete(args[0])
+    }
+}
+
+/* return a failure code to the capsule
+   => context = IRQ context of the environment call
+      reason = why the call failed */
+pub fn failed(context: &mut IRQContext, reason: ActionResult)
+{
+    trap::set_register(context, REG_A0, reason as usize);
+}
+
+/* return a successful result to the capsule
+   => context = IRQ context of the environment call
+      value = value to return in a1 */
+pub fn result(context: &mut IRQContext, value: usize)
+{
+    trap::set_register(context, REG_A0, ActionResult::Success as usize);
+    trap::set_register(context, REG_A1, value);
+}
+
+/* return a successful result and an extra value to the capsule
+   => context = IRQ context of the environment call
+      value = value to return in a1
+      extra = value to return in a2 */
+pub fn result_1extra(context: &mut IRQContext, value: usize, extra: usize)
+{
+    result(context, value);
+    trap::set_register(context, REG_A2, extra);
+}
+
+/* return a successful result and two extra values to the capsule
+   => context = IRQ context of the environment call
+      value = value to return in a1
+      extra1, extra2 = values to return in a2 and a3 */
+pub fn result_2extra(context: &mut IRQContext, value: usize, extra1: usize, extra2: usize)
+{
+    result_1extra(context, value, extra1);
+    trap::set_register(context, REG_A3, extra2);
+}
+
+/* return a bare value in a0, in place of a result code, for calls that predate them
+   => context = IRQ context of the environment call
+      value = value to return */
+pub fn result_as_error(context: &mut IRQContext, value: usize)
+{
+    trap::set_register(context, REG_A0, value);
+}
+
+/* <= the result code and first value returned to the capsule so far, from a0 and a1
+   => context = IRQ context of the environment call */
+pub fn get_result(context: &IRQContext) -> (usize, usize)
+{
+    (trap::register(context, REG_A0), trap::register(context, REG_A1))
+}
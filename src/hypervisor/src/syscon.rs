@@ -0,0 +1,113 @@
+/* diosix system controller reset and power off
+ *
+ * The hypervisor runs beneath the supervisor binary interface rather
+ * than on top of it, so it resets the host itself. Boards describe how
+ * in their device trees with syscon-reboot and syscon-poweroff nodes,
+ * each naming a system controller by phandle in its regmap property,
+ * the offset of the register within it, and the value to write there,
+ * optionally under a mask. QEMU's virt board, for one, describes its
+ * test device this way.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::ptr;
+use super::lock::Mutex;
+use hvalgo::fdt::{Fdt, Node};
+use hypercall::power::Reset;
+
+/* compatible strings of the nodes describing how to reboot and power off the host */
+pub const REBOOT_COMPATIBLE: &str = "syscon-reboot";
+pub const POWEROFF_COMPATIBLE: &str = "syscon-poweroff";
+
+/* times to check the host is still running after asking it to reset, before giving up */
+const RESET_WAIT_LOOPS: usize = 10_000_000;
+
+/* a register that resets the host when written */
+#[derive(Clone, Copy)]
+struct Trigger
+{
+    register: usize,    /* physical address of the register */
+    value: u32,         /* value to write */
+    mask: u32           /* bits of the register the value is written to */
+}
+
+lazy_static!
+{
+    /* the host's reboot and power off registers, if it has them */
+    static ref REBOOT: Mutex<Option<Trigger>> = Mutex::new("reboot register", None);
+    static ref POWEROFF: Mutex<Option<Trigger>> = Mutex::new("power off register", None);
+}
+
+/* describe the register written by a syscon-reboot or syscon-poweroff node
+   => fdt = host's device tree
+      node = the reboot or power off node
+   <= the register, or None if the node doesn't describe one */
+fn trigger(fdt: &Fdt, node: &Node) -> Option<Trigger>
+{
+    let controller = fdt.find_phandle(node.property_u32("regmap")?)?;
+    let (base, _) = controller.reg().ok()?.next()?;
+
+    Some(Trigger
+    {
+        register: base as usize + node.property_u32("offset")? as usize,
+        value: node.property_u32("value")?,
+        mask: node.property_u32("mask").unwrap_or(u32::MAX)
+    })
+}
+
+/* find the host's reboot and power off registers in the host's device tree. call once on the boot core
+   => fdt = host's device tree */
+pub fn init(fdt: &Fdt)
+{
+    let find = |compatible| fdt.nodes()
+        .find(|n| n.is_enabled() && n.is_compatible(compatible))
+        .and_then(|n| trigger(fdt, &n));
+
+    let reboot = find(REBOOT_COMPATIBLE);
+    let poweroff = find(POWEROFF_COMPATIBLE);
+    hvdebug!("Host can{} be rebooted and can{} be powered off by the hypervisor",
+        match reboot.is_some() { true => "", false => "'t" },
+        match poweroff.is_some() { true => "", false => "'t" });
+
+    *(REBOOT.lock()) = reboot;
+    *(POWEROFF.lock()) = poweroff;
+}
+
+/* reboot or power off the host. this only returns if it fails
+   => reset = whether to reboot or power off
+   <= false if the host can't be reset that way, or didn't reset when asked */
+pub fn reset(reset: Reset) -> bool
+{
+    let trigger = match reset
+    {
+        Reset::Reboot => *(REBOOT.lock()),
+        Reset::PowerOff => *(POWEROFF.lock())
+    };
+
+    let trigger = match trigger
+    {
+        Some(t) => t,
+        None => return false
+    };
+
+    unsafe
+    {
+        let register = trigger.register as *mut u32;
+        let bits = match trigger.mask
+        {
+            u32::MAX => trigger.value,
+            mask => (ptr::read_volatile(register) & !mask) | (trigger.value & mask)
+        };
+        ptr::write_volatile(register, bits);
+    }
+
+    /* the reset may take a moment to take hold */
+    for _ in 0..RESET_WAIT_LOOPS
+    {
+        core::hint::spin_loop();
+    }
+    false
+}
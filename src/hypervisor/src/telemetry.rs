@@ -0,0 +1,283 @@
+/* diosix telemetry export
+ *
+ * Render the hypervisor's metrics in the Prometheus text exposition
+ * format, so that external collectors can scrape headless devices
+ * without having to know the hypercall ABI. Each report describes
+ * itself: every metric is preceded by its type and a line of help,
+ * per-capsule metrics are labeled with the capsule's ID and name, and
+ * the report ends with a # EOF line, as OpenMetrics does, so that
+ * collectors reading a stream can tell where one report ends.
+ *
+ * Reports cover host uptime, CPU cores, physical memory and its
+ * pressure level, the busiest locks, the system-wide counters, and for
+ * each capsule, its state, virtual cores, memory, how often it was
+ * found running when a scheduling decision was made, and its activity
+ * counters. Counters only ever go up, from boot or from the capsule's
+ * creation, so collectors can work out rates themselves.
+ *
+ * Passing diosix.telemetry=N in the host's boot arguments prints a
+ * report over the debug port every N seconds. Management capsules can
+ * also read a fresh report with a hypercall, to forward it over their
+ * own console or network connection.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::lock::{Mutex, LockStats};
+use hashbrown::hash_map::HashMap;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::hardware;
+use super::pcore;
+use super::physmem;
+use super::pressure;
+use super::scheduler;
+use super::metrics::{self, Counter, COUNTERS, SystemCounter, SYSTEM_COUNTERS};
+use super::clock;
+use super::hcargs::{self, Access};
+
+/* boot argument that sets the number of seconds between reports printed over the debug port */
+const TELEMETRY_BOOTARG: &str = "diosix.telemetry=";
+
+const NANOSECONDS_PER_SECOND: u64 = 1000 * 1000 * 1000;
+
+/* nanoseconds between reports printed over the debug port, or zero for none */
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/* time the last report was printed, in nanoseconds since boot */
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/* scheduling decisions seen since boot */
+struct Samples
+{
+    running: HashMap<CapsuleID, u64>, /* times each capsule was found running */
+    total: u64                        /* total samples, including idle ones */
+}
+
+lazy_static!
+{
+    static ref SAMPLES: Mutex<Samples> = Mutex::new("telemetry samples", Samples { running: HashMap::new(), total: 0 });
+}
+
+/* start printing reports if the host's boot arguments ask for them. call during system start up */
+pub fn init()
+{
+    if let Some(args) = hardware::get_boot_args()
+    {
+        if let Some(seconds) = args.split_whitespace().find_map(|arg| arg.strip_prefix(TELEMETRY_BOOTARG))
+        {
+            match seconds.parse::<u64>()
+            {
+                Ok(seconds) if seconds > 0 =>
+                {
+                    INTERVAL.store(seconds * NANOSECONDS_PER_SECOND, Ordering::SeqCst);
+                    hvdebug!("Printing telemetry every {} seconds", seconds);
+                },
+                _ => hvalert!("Bad telemetry interval {}, not printing telemetry", seconds)
+            }
+        }
+    }
+}
+
+/* note which capsule, if any, this physical core is running. call when making a scheduling decision */
+pub fn sample()
+{
+    let mut samples = SAMPLES.lock();
+    samples.total = samples.total.wrapping_add(1);
+    if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+    {
+        let count = samples.running.entry(cid).or_insert(0);
+        *count = count.wrapping_add(1);
+    }
+}
+
+/* discard a capsule's samples when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    SAMPLES.lock().running.remove(&cid);
+}
+
+/* print a report over the debug port if one is due. call during housekeeping */
+pub fn housekeeper()
+{
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    if interval == 0
+    {
+        return;
+    }
+
+    if let Ok(now) = clock::monotonic()
+    {
+        if now.saturating_sub(LAST.load(Ordering::SeqCst)) >= interval
+        {
+            LAST.store(now, Ordering::SeqCst);
+            hvprint!("{}", render());
+        }
+    }
+}
+
+/* copy a fresh report into the currently running capsule's memory.
+   *** the currently running capsule must have the manage_capsules property ***
+   => buffer, size = address and size in bytes of the buffer in the capsule
+   <= size of the whole report in bytes, which may be larger than the amount copied, or an error code */
+pub fn read(buffer: usize, size: usize) -> Result<usize, Cause>
+{
+    let caller = capsule::get_capsule_id_if_property(CapsuleProperty::ManageCapsules)?;
+    let report = render();
+
+    let to_copy = core::cmp::min(size, report.len());
+    if to_copy > 0
+    {
+        let base = hcargs::buffer(caller, buffer, to_copy, report.len(), Access::Write)?;
+        let target = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, to_copy) };
+        target.copy_from_slice(&report.as_bytes()[..to_copy]);
+    }
+
+    Ok(report.len())
+}
+
+/* write the type and help lines that describe a metric */
+fn describe(report: &mut String, name: &str, kind: &str, help: &str)
+{
+    let _ = write!(report, "# HELP diosix_{} {}\n# TYPE diosix_{} {}\n", name, help, name, kind);
+}
+
+/* write a metric that has a single value */
+fn single(report: &mut String, name: &str, kind: &str, help: &str, value: u64)
+{
+    describe(report, name, kind, help);
+    let _ = write!(report, "diosix_{} {}\n", name, value);
+}
+
+/* <= a capsule's name made safe to use as a label value */
+fn escape(name: &[u8]) -> String
+{
+    let mut escaped = String::new();
+    for c in String::from_utf8_lossy(name).chars()
+    {
+        match c
+        {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/* <= the metric type of a capsule's activity counter: the boot timings are measurements, not counts */
+fn counter_kind(counter: Counter) -> &'static str
+{
+    match counter
+    {
+        Counter::BootRegions | Counter::BootImage | Counter::BootDeviceTree | Counter::BootFirstSchedule => "gauge",
+        _ => "counter"
+    }
+}
+
+/* <= a complete report of the hypervisor's metrics */
+pub fn render() -> String
+{
+    let mut report = String::new();
+
+    single(&mut report, "uptime_seconds", "gauge", "Seconds since the host powered up",
+        clock::monotonic().unwrap_or(0) / NANOSECONDS_PER_SECOND);
+    single(&mut report, "cpu_cores", "gauge", "Physical CPU cores in the host",
+        hardware::get_nr_cpu_cores().unwrap_or(0) as u64);
+
+    let (free, total) = physmem::free_ram();
+    single(&mut report, "memory_total_bytes", "gauge", "Physical RAM available to capsules after boot", total as u64);
+    single(&mut report, "memory_free_bytes", "gauge", "Physical RAM not yet allocated", free as u64);
+    single(&mut report, "memory_pressure", "gauge", "Host memory pressure level: 0 normal, 1 low, 2 critical",
+        pressure::level() as u64);
+
+    let system = metrics::system_snapshot();
+    for index in 0..SYSTEM_COUNTERS
+    {
+        if let Some(counter) = SystemCounter::from_usize(index)
+        {
+            single(&mut report, &format!("{}_total", counter.name()), "counter", "System-wide counter", system[index]);
+        }
+    }
+
+    let (running, decisions) =
+    {
+        let samples = SAMPLES.lock();
+        (samples.running.clone(), samples.total)
+    };
+    single(&mut report, "scheduling_decisions_total", "counter", "Scheduling decisions made by all physical CPU cores", decisions);
+
+    /* per-capsule metrics, grouped by metric as the format requires */
+    let capsules = capsule::snapshot();
+    let labels: Vec<String> = capsules.iter()
+        .map(|summary| format!("{{capsule=\"{}\",name=\"{}\"}}", summary.id(), escape(summary.name_bytes())))
+        .collect();
+
+    describe(&mut report, "capsule_state", "gauge", "Capsule state: 0 valid, 1 dying, 2 restarting, 3 paused");
+    for (summary, label) in capsules.iter().zip(labels.iter())
+    {
+        let _ = write!(report, "diosix_capsule_state{} {}\n", label, summary.state());
+    }
+
+    describe(&mut report, "capsule_vcores", "gauge", "Virtual CPU cores in the capsule");
+    for (summary, label) in capsules.iter().zip(labels.iter())
+    {
+        let _ = write!(report, "diosix_capsule_vcores{} {}\n", label, summary.vcores());
+    }
+
+    describe(&mut report, "capsule_memory_bytes", "gauge", "Physical RAM mapped into the capsule");
+    for (summary, label) in capsules.iter().zip(labels.iter())
+    {
+        let _ = write!(report, "diosix_capsule_memory_bytes{} {}\n", label, summary.memory());
+    }
+
+    describe(&mut report, "capsule_scheduled_total", "counter", "Scheduling decisions that found the capsule running");
+    for (summary, label) in capsules.iter().zip(labels.iter())
+    {
+        let _ = write!(report, "diosix_capsule_scheduled_total{} {}\n", label, running.get(&summary.id()).unwrap_or(&0));
+    }
+
+    let counts: Vec<[u64; COUNTERS]> = capsules.iter()
+        .map(|summary| metrics::snapshot(summary.id()).unwrap_or([0; COUNTERS]))
+        .collect();
+
+    for index in 0..COUNTERS
+    {
+        if let Some(counter) = Counter::from_usize(index)
+        {
+            let kind = counter_kind(counter);
+            let suffix = if kind == "counter" { "_total" } else { "" };
+            describe(&mut report, &format!("capsule_{}{}", counter.name(), suffix), kind, "Capsule activity counter");
+            for (values, label) in counts.iter().zip(labels.iter())
+            {
+                let _ = write!(report, "diosix_capsule_{}{}{} {}\n", counter.name(), suffix, label, values[index]);
+            }
+        }
+    }
+
+    /* show how often the busiest locks made cores wait, since boot */
+    let [queues, workload] = scheduler::lock_stats();
+    let locks: [LockStats; 4] = [capsule::lock_stats(), physmem::lock_stats(), queues, workload];
+
+    describe(&mut report, "lock_acquired_total", "counter", "Times the lock was acquired");
+    for stats in locks.iter()
+    {
+        let _ = write!(report, "diosix_lock_acquired_total{{lock=\"{}\"}} {}\n", stats.description, stats.acquired);
+    }
+
+    describe(&mut report, "lock_contended_total", "counter", "Times the lock was found held by another physical CPU core");
+    for stats in locks.iter()
+    {
+        let _ = write!(report, "diosix_lock_contended_total{{lock=\"{}\"}} {}\n", stats.description, stats.contended);
+    }
+
+    report.push_str("# EOF\n");
+    report
+}
@@ -0,0 +1,243 @@
+/* diosix capsule templates and fast cloning
+ *
+ * Starting a fleet of identical guests one at a time means unpacking,
+ * relocating, and measuring the same image from the DMFS for each of
+ * them, and then waiting for each to boot to the same idle state. Instead,
+ * one capsule can be booted, frozen, and used as a template from which
+ * new capsules are cloned.
+ *
+ * A capsule that has finished booting quiesces itself by hypercall,
+ * naming the entry point its clones should start from, or
+ * TEMPLATE_ENTRY_ORIGINAL for the one it was loaded with. Its virtual
+ * cores are parked as if it had been paused, though management capsules
+ * aren't told it has crashed. A management capsule then marks it as a
+ * template, and can clone it as many times as it likes. A capsule paused
+ * after a crash can be marked too, in which case its clones start from
+ * its original entry point. A template can't be resumed while it's
+ * marked, so that its memory doesn't change under its clones: unmark it
+ * first to resume it. Resuming a capsule discards its chosen entry point.
+ *
+ * Each clone is a new capsule with the template's properties, name, and
+ * measurement, and its own ID and unique machine ID. It's given the
+ * devices and resources the template's properties ask for, a copy of the
+ * template's RAM, and a device tree generated afresh for it. Its virtual
+ * cores start at the template's chosen entry point with the usual
+ * arguments: the virtual core's ID and the address of its device tree.
+ *
+ * Capsules' RAM is mapped at its physical address, so a clone's RAM is
+ * somewhere other than its template's, and the template's virtual cores
+ * can't simply be resumed in the clone. The chosen entry point must
+ * therefore be position-independent, and find the state its template set
+ * up before quiescing relative to where it's running. Without page tables,
+ * RAM is copied in full rather than shared until written to. On hosts with
+ * the hypervisor extension, clones can share their template's pages
+ * copy-on-write using stage-2 page tables, with the same interface.
+ *
+ * Physical devices can't be shared, so capsules that have serial ports
+ * or GPIO lines passed through to them can't be templates.
+ * Only a capsule's main RAM is copied, so capsules whose RAM had to be
+ * allocated in pieces can't be templates either.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use platform::physmem::PhysMemSize;
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty, CapsuleState};
+use super::physmem::Region;
+use super::virtmem::Mapping;
+use super::manifest;
+use super::virtdt;
+use super::measure;
+use super::boottime::{self, Stage};
+use super::pcore;
+
+/* start clones from the template's original entry point, shared with the capsules */
+pub use hypercall::template::TEMPLATE_ENTRY_ORIGINAL;
+
+/* a capsule that has quiesced itself or been marked as a template */
+struct Template
+{
+    entry: Option<PhysMemSize>, /* offset into the capsule's RAM where clones start, or None for its original entry point */
+    marked: bool                /* true once a management capsule has made it a template */
+}
+
+lazy_static!
+{
+    static ref TEMPLATES: Mutex<HashMap<CapsuleID, Template>> = Mutex::new("capsule templates", HashMap::new());
+}
+
+/* return a capsule's main RAM, or an error code if it has none to copy */
+fn main_ram(cid: CapsuleID) -> Result<Region, Cause>
+{
+    match capsule::get_memory_mappings(cid)?.first().and_then(|m| m.get_physical())
+    {
+        Some(ram) => Ok(ram),
+        None => Err(Cause::TemplateNotClonable)
+    }
+}
+
+/* freeze the currently running capsule, ready to be made a template. it's on the
+   caller to reschedule another vcore to run if this succeeds
+   => entry = physical address in the capsule's RAM where its clones should start,
+              or TEMPLATE_ENTRY_ORIGINAL for the entry point it was loaded with
+   <= Ok for success, or an error code */
+pub fn quiesce(entry: usize) -> Result<(), Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* record the entry point relative to the RAM, as each clone's RAM is elsewhere */
+    let ram = main_ram(cid)?;
+    let entry = match entry
+    {
+        TEMPLATE_ENTRY_ORIGINAL => None,
+        addr if addr >= ram.base() && addr < ram.end() => Some(addr - ram.base()),
+        _ => return Err(Cause::TemplateBadEntry)
+    };
+
+    TEMPLATES.lock().insert(cid, Template { entry, marked: false });
+    if let Err(e) = capsule::quiesce_current()
+    {
+        TEMPLATES.lock().remove(&cid);
+        return Err(e);
+    }
+
+    hvdebug!("Capsule {} quiesced, ready to be a template", cid);
+    Ok(())
+}
+
+/* make a paused capsule a template that new capsules can be cloned from.
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = capsule to make a template
+   <= Ok for success, or an error code */
+pub fn mark(cid: CapsuleID) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match capsule::get_state(cid)
+    {
+        Some(CapsuleState::Paused) => (),
+        Some(_) => return Err(Cause::CapsuleNotPaused),
+        None => return Err(Cause::CapsuleBadID)
+    }
+
+    if capsule::get_serial_ports(cid)?.len() > 0 || capsule::get_gpio_lines(cid)?.len() > 0
+    {
+        return Err(Cause::TemplateNotClonable);
+    }
+    main_ram(cid)?;
+
+    /* only the main RAM is copied into clones, so capsules whose RAM is in pieces can't be cloned */
+    if capsule::get_memory_mappings(cid)?.iter().any(|m| m.is_scattered())
+    {
+        return Err(Cause::TemplateNotClonable);
+    }
+
+    TEMPLATES.lock().entry(cid).or_insert(Template { entry: None, marked: false }).marked = true;
+    hvdebug!("Capsule {} marked as a template", cid);
+    Ok(())
+}
+
+/* stop a capsule being a template so that it can be resumed. capsules already cloned from it are unaffected
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = template to unmark
+   <= Ok for success, or an error code */
+pub fn unmark(cid: CapsuleID) -> Result<(), Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    match TEMPLATES.lock().get_mut(&cid)
+    {
+        Some(template) if template.marked == true => template.marked = false,
+        _ => return Err(Cause::TemplateNotFound)
+    }
+    Ok(())
+}
+
+/* create and start a new capsule cloned from a template
+   *** the currently running capsule must have the manage_capsules property ***
+   => cid = template to clone
+   <= ID of the new capsule, or an error code */
+pub fn clone(cid: CapsuleID) -> Result<CapsuleID, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::ManageCapsules)?;
+    let entry = match TEMPLATES.lock().get(&cid)
+    {
+        Some(template) if template.marked == true => template.entry,
+        _ => return Err(Cause::TemplateNotFound)
+    };
+
+    let source = main_ram(cid)?;
+    let segments = match capsule::get_memory_mappings(cid)?.first()
+    {
+        Some(mapping) => mapping.get_segments(),
+        None => return Err(Cause::TemplateNotClonable)
+    };
+    let inits = capsule::get_vcore_inits(cid)?;
+    let cpus = capsule::get_max_vcores(cid)?;
+
+    /* create the clone with the template's properties, and give it the devices they ask for */
+    let clone = capsule::duplicate(cid)?;
+    manifest::attach_resources(clone)?;
+
+    let started = boottime::start();
+    let ram = manifest::reserve_ram(clone, source.size())?;
+    boottime::record(clone, Stage::Regions, started);
+
+    /* copy the template's memory image in place of loading one from the DMFS */
+    let started = boottime::start();
+    let words = source.as_usize_slice();
+    ram.as_usize_slice()[..words.len()].copy_from_slice(words);
+    boottime::record(clone, Stage::Image, started);
+    measure::inherit(cid, clone);
+
+    /* write the clone's own device tree over the template's copy, describing its ID and devices */
+    let started = boottime::start();
+    let dtb = virtdt::publish(clone, cpus, ram)?;
+    boottime::record(clone, Stage::DeviceTree, started);
+
+    let started = boottime::start();
+    let mut mapping = Mapping::new();
+    mapping.set_physical(ram);
+    mapping.identity_mapping()?;
+    mapping.set_segments(&segments)?;
+    capsule::map_memory(clone, mapping)?;
+    boottime::record(clone, Stage::Regions, started);
+
+    /* start the clone's virtual cores where the template asked, relative to the clone's RAM */
+    for (vid, original, priority) in inits
+    {
+        let offset = match entry
+        {
+            Some(offset) => offset,
+            None => original - source.base()
+        };
+        capsule::add_vcore(clone, vid, ram.base() + offset, dtb, priority)?;
+    }
+    boottime::queued(clone);
+
+    hvdebug!("Capsule {} cloned from template {}", clone, cid);
+    Ok(clone)
+}
+
+/* <= true if the given capsule is marked as a template */
+pub fn is_template(cid: CapsuleID) -> bool
+{
+    match TEMPLATES.lock().get(&cid)
+    {
+        Some(template) => template.marked,
+        None => false
+    }
+}
+
+/* discard what's known of a capsule as a template when it's resumed or destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    TEMPLATES.lock().remove(&cid);
+}
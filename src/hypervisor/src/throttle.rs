@@ -0,0 +1,226 @@
+/* diosix trap rate limiting and quarantine
+ *
+ * A buggy guest that traps into the hypervisor at a high rate, such as
+ * by executing instructions that must be emulated over and over, can tie
+ * up a whole physical CPU core in hypervisor time. Each capsule's
+ * emulated instructions are counted over short windows, and a capsule
+ * exceeding its limit has the offending virtual core held out of the
+ * scheduling queues for a penalty interval.
+ *
+ * A capsule that keeps exceeding its limit, window after window, is
+ * quarantined: it's paused with an alert, as if it had crashed, so that
+ * a management capsule can inspect it and decide what to do. Each
+ * capsule's limit, in traps per second, can be set with the trap_limit
+ * property. A limit of zero turns off rate limiting for the capsule.
+ * Hypercalls aren't counted, as they're how capsules are meant to ask
+ * the hypervisor for things, and nor are faults the hypervisor causes
+ * itself, such as when sampling working sets. Traps the platform code
+ * handles before they reach the hypervisor, such as misaligned accesses,
+ * can't be seen and so aren't counted.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use super::error;
+use super::capsule::{self, CapsuleID};
+use super::vcore::VirtualCore;
+use super::pcore;
+use super::scheduler;
+use super::timerwheel;
+use super::metrics;
+use platform::timer::TimerValue;
+
+/* traps per second a capsule can make before being throttled, unless set with trap_limit */
+pub const TRAP_LIMIT_DEFAULT: u64 = 20000;
+
+/* length of each window over which traps are counted, in milliseconds */
+const TRAP_WINDOW_MS: u64 = 100;
+
+/* how long a throttled virtual core is held out of the scheduling queues */
+const PENALTY_LENGTH: TimerValue = TimerValue::Milliseconds(50);
+
+/* when to look for throttled virtual cores to release. the timer wheel only has
+   millisecond precision, so allow an extra millisecond so none are found early */
+const RELEASE_DELAY: TimerValue = TimerValue::Milliseconds(51);
+
+/* number of windows in a row a capsule can exceed its limit before it's quarantined */
+const STRIKES_MAX: usize = 5;
+
+/* a capsule's recent traps */
+struct TrapRate
+{
+    limit: u64,         /* traps allowed per window, or 0 for no limit */
+    window_start: u64,  /* timer value when the current window started, in exact ticks */
+    traps: u64,         /* traps so far in the current window */
+    strikes: usize      /* windows in a row in which the limit was exceeded */
+}
+
+lazy_static!
+{
+    static ref RATES: Mutex<HashMap<CapsuleID, TrapRate>> = Mutex::new("capsule trap rates", HashMap::new());
+
+    /* throttled virtual cores, and the timer values at which they can be scheduled again */
+    static ref PENALTY_BOX: Mutex<Vec<(u64, VirtualCore)>> = Mutex::new("throttled virtual cores", Vec::new());
+}
+
+/* what to do about a trap */
+enum Verdict
+{
+    Continue,       /* the capsule is within its limit */
+    Throttle(u64),  /* hold the running vcore out of the queues until the given timer value */
+    Quarantine      /* pause the whole capsule */
+}
+
+/* count a trap by the currently running capsule
+   <= what to do about it */
+fn count(cid: CapsuleID, now: u64, frequency: u64) -> Verdict
+{
+    /* look up the capsule's limit the first time it traps, without holding the rates lock */
+    if RATES.lock().contains_key(&cid) == false
+    {
+        let per_second = match capsule::get_trap_limit(cid)
+        {
+            Ok(limit) => limit,
+            Err(_) => return Verdict::Continue
+        };
+
+        RATES.lock().entry(cid).or_insert(TrapRate
+        {
+            limit: match per_second
+            {
+                0 => 0,
+                _ => core::cmp::max(1, (per_second * TRAP_WINDOW_MS) / 1000)
+            },
+            window_start: now,
+            traps: 0,
+            strikes: 0
+        });
+    }
+
+    let mut rates = RATES.lock();
+    let rate = match rates.get_mut(&cid)
+    {
+        Some(r) => r,
+        None => return Verdict::Continue
+    };
+
+    if rate.limit == 0
+    {
+        return Verdict::Continue;
+    }
+
+    /* a window that ends within the limit breaks the capsule's run of strikes */
+    if now >= rate.window_start + TimerValue::Milliseconds(TRAP_WINDOW_MS).to_exact(frequency)
+    {
+        rate.window_start = now;
+        rate.traps = 0;
+        rate.strikes = 0;
+    }
+
+    rate.traps = rate.traps + 1;
+    if rate.traps <= rate.limit
+    {
+        return Verdict::Continue;
+    }
+
+    rate.strikes = rate.strikes + 1;
+    if rate.strikes >= STRIKES_MAX
+    {
+        /* give the capsule a clean slate if it's resumed */
+        rates.remove(&cid);
+        return Verdict::Quarantine;
+    }
+
+    /* start the next window once the penalty is over */
+    let release = now + PENALTY_LENGTH.to_exact(frequency);
+    rate.window_start = release;
+    rate.traps = 0;
+    Verdict::Throttle(release)
+}
+
+/* account for a trap, such as an emulated instruction, by the currently running capsule, throttling
+   or quarantining the capsule if it's trapping too often. call after the trap has been handled */
+pub fn trapped()
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return
+    };
+
+    let (now, frequency) = match scheduler::timer_now()
+    {
+        Some(t) => t,
+        None => return
+    };
+
+    match count(cid, now, frequency)
+    {
+        Verdict::Continue => (),
+        Verdict::Throttle(release) =>
+        {
+            metrics::count(cid, metrics::Counter::TrapThrottles);
+            pcore::PhysicalCore::this().throttle_vcore(release);
+            timerwheel::schedule_in(RELEASE_DELAY, release_due);
+            scheduler::ping();
+        },
+        Verdict::Quarantine =>
+        {
+            hvalert!("Quarantining capsule {}: over its trap limit {} times in a row", cid, STRIKES_MAX);
+            match capsule::pause_current()
+            {
+                Ok(_) => scheduler::ping(),
+                Err(_e) => hvalert!("Can't quarantine capsule {} ({})", cid, error::report(&_e))
+            }
+        }
+    }
+}
+
+/* hold a throttled virtual core that's been switched out until its penalty is over
+   => release = timer value at which the vcore can be scheduled again
+      vcore = virtual core to hold */
+pub fn hold(release: u64, vcore: VirtualCore)
+{
+    PENALTY_BOX.lock().push((release, vcore));
+}
+
+/* return throttled virtual cores whose penalties are over to the scheduling queues */
+fn release_due()
+{
+    let now = match scheduler::timer_now()
+    {
+        Some((now, _)) => now,
+        None => 0
+    };
+
+    let mut released = Vec::new();
+    {
+        let mut penalty_box = PENALTY_BOX.lock();
+        let mut index = 0;
+        while index < penalty_box.len()
+        {
+            match penalty_box[index].0 <= now
+            {
+                true => released.push(penalty_box.remove(index).1),
+                false => index = index + 1
+            }
+        }
+    }
+
+    for vcore in released
+    {
+        scheduler::queue(vcore);
+    }
+}
+
+/* discard a capsule's trap rate when it's destroyed. any of its vcores
+   still being held are released as usual and then torn down */
+pub fn forget(cid: CapsuleID)
+{
+    RATES.lock().remove(&cid);
+}
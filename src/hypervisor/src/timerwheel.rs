@@ -0,0 +1,334 @@
+/* diosix per-physical-core timer wheel for hypervisor-internal events
+ *
+ * Each physical CPU core keeps its own hierarchical timer wheel of
+ * events, such as periodic housekeeping, that the hypervisor wants to
+ * run at some point in the future. As the wheel belongs to one core,
+ * scheduling and running events needs no global lock.
+ *
+ * The wheel has WHEEL_LEVELS levels of WHEEL_SLOTS slots. Each slot in
+ * the lowest level covers one wheel tick, each slot in the next level
+ * up covers WHEEL_SLOTS ticks, and so on. Events are placed in the
+ * lowest level that can hold them, and are cascaded down the levels as
+ * they draw near. Events too far in the future for the wheel sit in an
+ * overflow list until they come into range. Events are run, with a
+ * wheel tick's precision, when the physical core next carries out
+ * housekeeping, which happens at least once per timeslice.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use platform::timer::TimerValue;
+use super::pcore::PhysicalCore;
+use super::hardware;
+
+/* function called when an event fires */
+pub type EventHandler = fn();
+
+/* length of a wheel tick, in milliseconds */
+const WHEEL_TICK_MS: u64 = 1;
+
+/* shape of the wheel */
+const WHEEL_SLOT_BITS: u64 = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_SLOT_BITS;
+const WHEEL_LEVELS: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Event
+{
+    due: u64,               /* wheel tick at which to fire */
+    period: Option<u64>,    /* ticks between firings for repeating events, or None for one-shot events */
+    handler: EventHandler
+}
+
+pub struct TimerWheel
+{
+    slots: Vec<Vec<Event>>, /* WHEEL_LEVELS levels of WHEEL_SLOTS slots, lowest level first */
+    overflow: Vec<Event>,   /* events beyond the reach of the top level */
+    now: u64,               /* last wheel tick processed */
+    pending: usize          /* number of events in the wheel */
+}
+
+impl TimerWheel
+{
+    pub fn new() -> TimerWheel
+    {
+        let mut slots = Vec::new();
+        for _ in 0..(WHEEL_LEVELS * WHEEL_SLOTS)
+        {
+            slots.push(Vec::new());
+        }
+
+        TimerWheel
+        {
+            slots,
+            overflow: Vec::new(),
+            now: 0,
+            pending: 0
+        }
+    }
+
+    /* put an event in the lowest level that can hold it. events cascaded down from a higher
+       level may be due on the current tick, and are fired before the wheel moves on */
+    fn place(&mut self, event: Event)
+    {
+        let delta = event.due - self.now;
+        for level in 0..WHEEL_LEVELS
+        {
+            let shift = WHEEL_SLOT_BITS * level as u64;
+            if delta < (1 << (shift + WHEEL_SLOT_BITS))
+            {
+                let slot = ((event.due >> shift) as usize) & (WHEEL_SLOTS - 1);
+                self.slots[(level * WHEEL_SLOTS) + slot].push(event);
+                return;
+            }
+        }
+
+        self.overflow.push(event);
+    }
+
+    /* add an event to the wheel */
+    fn add(&mut self, mut event: Event)
+    {
+        /* events already due fire on the next tick */
+        if event.due <= self.now
+        {
+            event.due = self.now + 1;
+        }
+
+        self.pending = self.pending + 1;
+        self.place(event);
+    }
+
+    /* <= the next tick after the last one processed at which an event may fire, or at which a slot
+          in a higher level must be cascaded down, so the wheel can skip the ticks in between.
+          only meaningful while events are pending */
+    fn next_tick(&self) -> u64
+    {
+        /* the lowest level holds events due within the next WHEEL_SLOTS ticks */
+        let mut next = u64::MAX;
+        for delta in 1..(WHEEL_SLOTS as u64)
+        {
+            let slot = ((self.now + delta) as usize) & (WHEEL_SLOTS - 1);
+            if self.slots[slot].len() > 0
+            {
+                next = self.now + delta;
+                break;
+            }
+        }
+
+        /* each higher level's slots are cascaded as the wheel crosses into them */
+        for level in 1..WHEEL_LEVELS
+        {
+            let shift = WHEEL_SLOT_BITS * level as u64;
+            let first = ((self.now >> shift) + 1) << shift;
+            for crossing in 0..(WHEEL_SLOTS as u64)
+            {
+                let at = first + (crossing << shift);
+                if at >= next
+                {
+                    break;
+                }
+
+                let slot = ((at >> shift) as usize) & (WHEEL_SLOTS - 1);
+                if self.slots[(level * WHEEL_SLOTS) + slot].len() > 0
+                {
+                    next = at;
+                    break;
+                }
+            }
+        }
+
+        /* overflowed events are brought in as the wheel crosses into a new top-level slot */
+        if self.overflow.len() > 0
+        {
+            let shift = WHEEL_SLOT_BITS * (WHEEL_LEVELS - 1) as u64;
+            next = core::cmp::min(next, ((self.now >> shift) + 1) << shift);
+        }
+
+        next
+    }
+
+    /* move the wheel forward to the given tick, jumping straight between the ticks at which
+       something happens rather than stepping through every tick in between
+       <= list of events that fell due, to be run by the caller */
+    fn advance(&mut self, to: u64) -> Vec<Event>
+    {
+        let mut fired = Vec::new();
+
+        while self.now < to
+        {
+            /* skip straight to the end if there's nothing to fire before then */
+            let next = match self.pending
+            {
+                0 => u64::MAX,
+                _ => self.next_tick()
+            };
+            if next > to
+            {
+                self.now = to;
+                break;
+            }
+
+            self.now = next;
+
+            /* when crossing into a new slot in a higher level, cascade its events down */
+            for level in 1..WHEEL_LEVELS
+            {
+                let shift = WHEEL_SLOT_BITS * level as u64;
+                if self.now & ((1 << shift) - 1) != 0
+                {
+                    break;
+                }
+
+                let slot = ((self.now >> shift) as usize) & (WHEEL_SLOTS - 1);
+                for event in core::mem::take(&mut self.slots[(level * WHEEL_SLOTS) + slot])
+                {
+                    self.place(event);
+                }
+
+                /* bring in any overflowed events that are now within reach */
+                if level == WHEEL_LEVELS - 1
+                {
+                    for event in core::mem::take(&mut self.overflow)
+                    {
+                        self.place(event);
+                    }
+                }
+            }
+
+            let slot = (self.now as usize) & (WHEEL_SLOTS - 1);
+            for event in core::mem::take(&mut self.slots[slot])
+            {
+                self.pending = self.pending - 1;
+                fired.push(event);
+            }
+        }
+
+        fired
+    }
+}
+
+/* return the current time in wheel ticks, or None if there's no timer */
+fn ticks_now() -> Option<u64>
+{
+    match (hardware::scheduler_get_timer_now(), hardware::scheduler_get_timer_frequency())
+    {
+        (Some(now), Some(frequency)) if frequency >= 1000 => Some(now.to_exact(frequency) / ((frequency / 1000) * WHEEL_TICK_MS)),
+        (_, _) => None
+    }
+}
+
+/* convert a duration into wheel ticks, rounding up so events never fire early */
+fn to_ticks(duration: TimerValue) -> Option<u64>
+{
+    let frequency = hardware::scheduler_get_timer_frequency()?;
+    if frequency < 1000
+    {
+        return None;
+    }
+
+    let tick = (frequency / 1000) * WHEEL_TICK_MS;
+    Some((duration.to_exact(frequency) + tick - 1) / tick)
+}
+
+/* queue an event on this physical core's wheel
+   => delay = how long from now the event should first fire
+      period = how often the event should fire thereafter, or None to fire once
+      handler = function to call when the event fires
+   <= true if scheduled, or false if there's no timer to drive the wheel */
+fn schedule(delay: TimerValue, period: Option<TimerValue>, handler: EventHandler) -> bool
+{
+    let (now, delay) = match (ticks_now(), to_ticks(delay))
+    {
+        (Some(now), Some(delay)) => (now, delay),
+        (_, _) => return false
+    };
+
+    let period = match period
+    {
+        Some(p) => match to_ticks(p)
+        {
+            Some(ticks) => Some(core::cmp::max(ticks, 1)),
+            None => return false
+        },
+        None => None
+    };
+
+    let wheel = PhysicalCore::this().get_timer_wheel();
+
+    /* don't treat the whole of uptime as overdue the first time the wheel is used */
+    if wheel.pending == 0 && wheel.now < now
+    {
+        wheel.now = now;
+    }
+
+    wheel.add(Event { due: now + delay, period, handler });
+    true
+}
+
+/* run the handler once on this physical core after the given delay
+   <= true if scheduled, or false if there's no timer to drive the wheel */
+pub fn schedule_in(delay: TimerValue, handler: EventHandler) -> bool
+{
+    schedule(delay, None, handler)
+}
+
+/* run the handler on this physical core every period, starting one period from now
+   <= true if scheduled, or false if there's no timer to drive the wheel */
+pub fn schedule_every(period: TimerValue, handler: EventHandler) -> bool
+{
+    schedule(period, Some(period), handler)
+}
+
+/* run all the events on this physical core's wheel that have fallen due,
+   requeuing repeating events. call this regularly, such as during housekeeping */
+pub fn run_due()
+{
+    let now = match ticks_now()
+    {
+        Some(t) => t,
+        None => return
+    };
+
+    let fired = PhysicalCore::this().get_timer_wheel().advance(now);
+
+    /* handlers may queue events of their own, so run them once the wheel's been updated */
+    for event in fired
+    {
+        if let Some(period) = event.period
+        {
+            /* skip any firings missed while the core was busy rather than trying to catch up */
+            let due = match event.due + period
+            {
+                due if due <= now => now + period,
+                due => due
+            };
+            PhysicalCore::this().get_timer_wheel().add(Event { due, ..event });
+        }
+        (event.handler)();
+    }
+}
+
+#[test_case]
+fn test_advance_fires_on_time_across_levels()
+{
+    fn handler() {}
+
+    /* one event per level, plus one beyond the top level in the overflow list */
+    let mut wheel = TimerWheel::new();
+    let dues = [ 5, 100, 5000, 300000, 20000000 ];
+    for due in dues.iter()
+    {
+        wheel.add(Event { due: *due, period: None, handler });
+    }
+
+    for due in dues.iter()
+    {
+        assert_eq!(wheel.advance(*due - 1).len(), 0);
+        assert_eq!(wheel.advance(*due).len(), 1);
+    }
+    assert_eq!(wheel.pending, 0);
+}
@@ -0,0 +1,165 @@
+/* diosix built-in status screen
+ *
+ * Periodically render a top-style summary of the system over the
+ * debug port: each capsule's state, virtual cores, memory, and share
+ * of CPU time. This lets headless boards be monitored without a
+ * console service capsule.
+ *
+ * The screen is off by default. It is switched on by passing
+ * diosix.top in the host's boot arguments, or toggled by pressing
+ * ctrl-t on the debug port when no console service is running.
+ * Keypresses are offered to the administration command prompt first.
+ * It's redrawn whenever the scheduler carries out housekeeping.
+ *
+ * CPU time is estimated by sampling which capsule each physical core
+ * is running whenever it makes a scheduling decision. Below the
+ * capsules, the screen shows how often the hypervisor's busiest locks
+ * kept physical cores waiting.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::string::String;
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use super::capsule::{self, CapsuleID};
+use super::service::{self, ServiceType};
+use super::hardware;
+use super::pcore;
+use super::warmboot;
+use super::error;
+use super::physmem;
+use super::scheduler;
+use super::admin;
+
+/* key on the debug port that toggles the screen: ctrl-t. the port's other hotkeys are handled here too */
+const TOP_HOTKEY: char = '\x14';
+
+/* boot argument that switches the screen on at startup */
+const TOP_BOOTARG: &str = "diosix.top";
+
+/* is the screen being drawn? */
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/* scheduling decisions seen since the screen was last drawn */
+struct Samples
+{
+    running: HashMap<CapsuleID, u64>, /* times each capsule was found running */
+    total: u64                        /* total samples, including idle ones */
+}
+
+lazy_static!
+{
+    static ref SAMPLES: Mutex<Samples> = Mutex::new("status screen samples", Samples { running: HashMap::new(), total: 0 });
+}
+
+/* switch the screen on if the host's boot arguments ask for it. call during system start up */
+pub fn init()
+{
+    if let Some(args) = hardware::get_boot_args()
+    {
+        if args.split_whitespace().any(|arg| arg == TOP_BOOTARG)
+        {
+            ENABLED.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/* note which capsule, if any, this physical core is running. call when making a scheduling decision */
+pub fn sample()
+{
+    if ENABLED.load(Ordering::Relaxed) == false
+    {
+        return;
+    }
+
+    let mut samples = SAMPLES.lock();
+    samples.total = samples.total + 1;
+    if let Some(cid) = pcore::PhysicalCore::get_capsule_id()
+    {
+        *samples.running.entry(cid).or_insert(0) += 1;
+    }
+}
+
+/* check for the hotkey and redraw the screen if it's on. call during housekeeping */
+pub fn housekeeper()
+{
+    /* don't steal keypresses from the console service */
+    if service::is_registered(ServiceType::ConsoleInterface) == false
+    {
+        while let Some(c) = hardware::read_debug_char()
+        {
+            /* keypresses typed into the command prompt aren't hotkeys */
+            if admin::input(c) == true
+            {
+                continue;
+            }
+
+            if c == TOP_HOTKEY
+            {
+                let enabled = ENABLED.load(Ordering::SeqCst);
+                ENABLED.store(!enabled, Ordering::SeqCst);
+            }
+            else if c == warmboot::WARM_REBOOT_HOTKEY
+            {
+                if let Err(_e) = warmboot::request()
+                {
+                    hvalert!("Can't warm reboot: {}", error::report(&_e));
+                }
+            }
+        }
+    }
+
+    if ENABLED.load(Ordering::SeqCst) == true
+    {
+        render();
+    }
+}
+
+/* draw the screen and start a fresh sampling period */
+fn render()
+{
+    const MEGABYTE: usize = 1024 * 1024;
+
+    let (running, total) =
+    {
+        let mut samples = SAMPLES.lock();
+        let running = samples.running.clone();
+        let total = samples.total;
+        samples.running.clear();
+        samples.total = 0;
+        (running, total)
+    };
+
+    let capsules = capsule::snapshot();
+
+    /* clear the screen and home the cursor before drawing */
+    hvprint!("\x1b[2J\x1b[H");
+    hvprintln!("diosix {} :: {} capsules, {} CPU cores",
+        env!("CARGO_PKG_VERSION"), capsules.len(), hardware::get_nr_cpu_cores().unwrap_or(0));
+    hvprintln!("{:>6} {:<10} {:>6} {:>8} {:>4}  {}", "ID", "STATE", "VCORES", "MEMORY", "CPU", "NAME");
+
+    for summary in capsules
+    {
+        let cpu = match (running.get(&summary.id()), total)
+        {
+            (Some(count), t) if t > 0 => (count * 100) / t,
+            (_, _) => 0
+        };
+
+        hvprintln!("{:>6} {:<10} {:>6} {:>4} MiB {:>3}%  {}",
+            summary.id(), summary.state_name(), summary.vcores(), summary.memory() / MEGABYTE, cpu, String::from_utf8_lossy(summary.name_bytes()));
+    }
+
+    /* show how often the busiest locks made cores wait, since boot */
+    hvprintln!("");
+    hvprintln!("{:>10} {:>10} {:>10}  {}", "ACQUIRED", "CONTENDED", "MAX SPINS", "LOCK");
+    let [queues, workload] = scheduler::lock_stats();
+    for stats in [capsule::lock_stats(), physmem::lock_stats(), queues, workload].iter()
+    {
+        hvprintln!("{:>10} {:>10} {:>10}  {}", stats.acquired, stats.contended, stats.spins_max, stats.description);
+    }
+}
@@ -0,0 +1,205 @@
+/* diosix per-capsule hypercall tracing and tracepoints
+ *
+ * Capsules granted the trace_hypercalls property have each of their
+ * hypercalls, with its arguments and results, recorded in a bounded ring.
+ * A debug capsule granted the trace_read property can read the ring
+ * back as lines of text, one character at a time, much like the
+ * hypervisor's log. This lets developers strace their guest kernel's
+ * interaction with the hypervisor without attaching a debugger.
+ *
+ * The hypervisor also has tracepoints, placed with hvtrace!(), in its
+ * scheduler, context switching, capsule lifecycle, and hypercall
+ * handling. They're compiled out unless the tracepoints feature is
+ * enabled. When they're built in, each can be switched on and off
+ * while the hypervisor runs with the tracepoints setting, and those
+ * switched on record into the same ring. A tracepoint that's switched
+ * off costs a lock-free load and a test.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use platform::irq::IRQContext;
+use super::syscalls::{self, Action};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID, CapsuleProperty};
+use super::vcore::VirtualCoreID;
+use super::pcore;
+#[cfg(feature = "tracepoints")]
+use super::pcore::PhysicalCoreID;
+#[cfg(feature = "tracepoints")]
+use super::settings::{self, Setting};
+
+/* the tracepoints, shared with the capsules that switch them on and off */
+pub use hypercall::tracepoint::Tracepoint;
+
+/* maximum number of hypercalls held in the ring. the oldest are discarded first */
+const TRACE_RING_MAX: usize = 512;
+
+/* describe a single traced hypercall or tracepoint */
+struct TraceRecord
+{
+    seq: usize,     /* system-wide sequence number of this record */
+    traced: Traced  /* what happened */
+}
+
+enum Traced
+{
+    Hypercall
+    {
+        cid: CapsuleID,                 /* capsule that made the call */
+        vid: VirtualCoreID,             /* virtual core that made the call */
+        call: String,                   /* decoded hypercall and its arguments */
+        result: Option<(usize, usize)>  /* values returned to the caller, or None if not returned yet */
+    },
+    #[cfg(feature = "tracepoints")]
+    Point
+    {
+        pcore: PhysicalCoreID,          /* physical core that passed the tracepoint */
+        point: Tracepoint,              /* tracepoint passed */
+        detail: String                  /* what the tracepoint recorded */
+    }
+}
+
+/* needed to number records */
+static TRACE_SEQ_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static!
+{
+    /* ring of traced hypercalls */
+    static ref TRACE_RING: Mutex<VecDeque<TraceRecord>> = Mutex::new("hypercall trace ring", VecDeque::new());
+
+    /* text of the record being read out */
+    static ref TRACE_TEXT: Mutex<VecDeque<char>> = Mutex::new("hypercall trace text", VecDeque::new());
+}
+
+/* record the start of a hypercall if the running capsule is being traced
+   => action = decoded hypercall
+   <= sequence number to pass to end(), or None if the call isn't being traced */
+pub fn begin(action: &Action) -> Option<usize>
+{
+    let _tag = heaptag!();
+    if capsule::current_has_property(CapsuleProperty::TraceHypercalls).is_err()
+    {
+        return None;
+    }
+
+    let (cid, vid) = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => (id.capsuleid, id.vcoreid),
+        None => return None
+    };
+
+    Some(record(Traced::Hypercall { cid, vid, call: format!("{:x?}", action), result: None }))
+}
+
+/* add a record to the ring, discarding the oldest if it's full
+   => traced = what to record
+   <= the record's sequence number */
+fn record(traced: Traced) -> usize
+{
+    let seq = TRACE_SEQ_NEXT.fetch_add(1, Ordering::SeqCst);
+    let mut ring = TRACE_RING.lock();
+    if ring.len() >= TRACE_RING_MAX
+    {
+        ring.pop_front();
+    }
+    ring.push_back(TraceRecord { seq, traced });
+    seq
+}
+
+/* <= true if the given tracepoint is switched on. use hvtrace!() rather than calling this directly */
+#[cfg(feature = "tracepoints")]
+pub fn is_enabled(point: Tracepoint) -> bool
+{
+    settings::get(Setting::Tracepoints) & point.mask() != 0
+}
+
+/* record a tracepoint passed on this physical core. use hvtrace!() rather than calling this directly
+   => point = tracepoint passed
+      detail = what happened */
+#[cfg(feature = "tracepoints")]
+pub fn point(point: Tracepoint, detail: String)
+{
+    let _tag = heaptag!();
+    record(Traced::Point { pcore: pcore::PhysicalCore::get_id(), point, detail });
+}
+
+/* record the results of a traced hypercall. if the hypercall caused a
+   context switch to another virtual core, the results aren't available
+   => seq = sequence number returned by begin(), or None if not traced
+      context = context about to be returned to */
+pub fn end(seq: Option<usize>, context: &IRQContext)
+{
+    let seq = match seq
+    {
+        Some(s) => s,
+        None => return
+    };
+
+    let running = pcore::PhysicalCore::this().get_virtualcore_id();
+    let mut ring = TRACE_RING.lock();
+    if let Some(record) = ring.iter_mut().rev().find(|r| r.seq == seq)
+    {
+        match (running, &mut record.traced)
+        {
+            (Some(id), Traced::Hypercall { cid, vid, result, .. }) if id.capsuleid == *cid && id.vcoreid == *vid =>
+                *result = Some(syscalls::get_result(context)),
+            (_, _) => ()
+        }
+    }
+}
+
+/* forget any hypercall records belonging to a destroyed capsule. tracepoint records are kept */
+pub fn forget(cid: CapsuleID)
+{
+    TRACE_RING.lock().retain(|r| match r.traced
+    {
+        Traced::Hypercall { cid: owner, .. } => owner != cid,
+        #[cfg(feature = "tracepoints")]
+        Traced::Point { .. } => true
+    });
+}
+
+/* return the next character of the hypercall trace, or an error.
+   *** the currently running capsule must have the trace_read property *** */
+pub fn read_char() -> Result<char, Cause>
+{
+    capsule::current_has_property(CapsuleProperty::TraceRead)?;
+
+    let mut text = TRACE_TEXT.lock();
+    if text.len() == 0
+    {
+        /* format the oldest record into a line of text */
+        let line = match TRACE_RING.lock().pop_front()
+        {
+            Some(r) => match r.traced
+            {
+                Traced::Hypercall { cid, vid, call, result: Some((value, extra)) } =>
+                    format!("[{}] capsule {}.{}: {} -> 0x{:x}, 0x{:x}\n", r.seq, cid, vid, call, value, extra),
+                Traced::Hypercall { cid, vid, call, result: None } =>
+                    format!("[{}] capsule {}.{}: {} -> (switched away)\n", r.seq, cid, vid, call),
+                #[cfg(feature = "tracepoints")]
+                Traced::Point { pcore, point, detail } =>
+                    format!("[{}] CPU {}: {}: {}\n", r.seq, pcore, point.name(), detail)
+            },
+            None => return Err(Cause::CapsuleBufferEmpty)
+        };
+
+        for c in line.chars()
+        {
+            text.push_back(c);
+        }
+    }
+
+    match text.pop_front()
+    {
+        Some(c) => Ok(c),
+        None => Err(Cause::CapsuleBufferEmpty)
+    }
+}
@@ -0,0 +1,285 @@
+/* diosix hypervisor-mediated bulk data transfers between capsules and services
+ *
+ * Single-character console hypercalls are too slow for file and network
+ * services. Instead, a client capsule can offer a list of buffers to a
+ * registered service. The service is told of the offer, and accepts it
+ * by supplying its own list of buffers. The hypervisor then copies the
+ * data between the two lists, checking every buffer lies within the
+ * memory of the capsule that supplied it. No memory is shared between
+ * the capsules, so neither can reach into the other's RAM.
+ *
+ * Buffer lists are arrays of descriptors in the supplying capsule's memory.
+ * Each descriptor is a pair of machine words: the buffer's address in
+ * the capsule and its size in bytes.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::service::{self, ServiceType};
+use super::pcore;
+use super::hcargs::{self, Access};
+
+pub type TransferID = usize;
+
+/* limit the number of buffers in a single transfer to keep copies bounded */
+const SEGMENTS_MAX: usize = 64;
+
+/* words per buffer descriptor: address then size */
+const DESCRIPTOR_WORDS: usize = 2;
+
+/* which way the data flows */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction
+{
+    ToService,   /* client writes data to the service */
+    FromService  /* client reads data from the service */
+}
+
+impl Direction
+{
+    pub fn from_usize(value: usize) -> Result<Direction, Cause>
+    {
+        match value
+        {
+            0 => Ok(Direction::ToService),
+            1 => Ok(Direction::FromService),
+            _ => Err(Cause::TransferBadDescriptor)
+        }
+    }
+}
+
+/* a contiguous block of host physical memory belonging to a capsule */
+#[derive(Clone, Copy)]
+struct Segment
+{
+    base: PhysMemBase,
+    size: PhysMemSize
+}
+
+/* a transfer offered by a client and waiting for its service to accept it */
+struct Transfer
+{
+    client: CapsuleID,
+    stype: ServiceType,
+    direction: Direction,
+    segments: Vec<Segment>
+}
+
+impl Transfer
+{
+    /* total number of bytes described by the client */
+    pub fn size(&self) -> usize
+    {
+        self.segments.iter().fold(0, |total, s| total + s.size)
+    }
+}
+
+/* needed to assign system-wide unique transfer ID numbers */
+static TRANSFER_ID_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static!
+{
+    /* transfers offered and not yet accepted */
+    static ref TRANSFERS: Mutex<HashMap<TransferID, Transfer>> = Mutex::new("bulk transfer table", HashMap::new());
+
+    /* queue of transfer IDs waiting for each service */
+    static ref WAITING: Mutex<HashMap<ServiceType, VecDeque<TransferID>>> = Mutex::new("bulk transfer queues", HashMap::new());
+}
+
+/* read and check a list of buffer descriptors from a capsule's memory
+   => cid = capsule supplying the list
+      list = address of the descriptor array in the capsule
+      count = number of descriptors in the array
+      access = whether the hypervisor will read or write the buffers described
+   <= list of host physical memory segments, or an error code */
+fn read_descriptors(cid: CapsuleID, list: usize, count: usize, access: Access) -> Result<Vec<Segment>, Cause>
+{
+    if count == 0 || count > SEGMENTS_MAX
+    {
+        return Err(Cause::TransferBadDescriptor);
+    }
+
+    let list_base = hcargs::array::<usize>(cid, list, count * DESCRIPTOR_WORDS, SEGMENTS_MAX * DESCRIPTOR_WORDS, Access::Read)?;
+    let words = unsafe { slice::from_raw_parts(list_base as *const usize, count * DESCRIPTOR_WORDS) };
+
+    let mut segments = Vec::new();
+    for descriptor in words.chunks(DESCRIPTOR_WORDS)
+    {
+        let (addr, size) = (descriptor[0], descriptor[1]);
+        if size == 0
+        {
+            return Err(Cause::TransferBadDescriptor);
+        }
+
+        /* every buffer must lie entirely within the capsule's memory */
+        let base = hcargs::buffer(cid, addr, size, usize::MAX, access)?;
+        segments.push(Segment { base, size });
+    }
+
+    Ok(segments)
+}
+
+/* copy bytes from one list of segments to another, stopping when either runs out.
+   the segments may overlap, eg: if a capsule offers a buffer to a service it owns itself
+   <= number of bytes copied */
+fn copy_segments(from: &Vec<Segment>, to: &Vec<Segment>) -> usize
+{
+    let (mut from_index, mut from_offset) = (0, 0);
+    let (mut to_index, mut to_offset) = (0, 0);
+    let mut copied = 0;
+
+    while from_index < from.len() && to_index < to.len()
+    {
+        let src = from[from_index];
+        let dest = to[to_index];
+        let chunk = core::cmp::min(src.size - from_offset, dest.size - to_offset);
+
+        unsafe
+        {
+            core::ptr::copy((src.base + from_offset) as *const u8, (dest.base + to_offset) as *mut u8, chunk);
+        }
+
+        copied = copied + chunk;
+        from_offset = from_offset + chunk;
+        to_offset = to_offset + chunk;
+
+        if from_offset == src.size
+        {
+            from_index = from_index + 1;
+            from_offset = 0;
+        }
+        if to_offset == dest.size
+        {
+            to_index = to_index + 1;
+            to_offset = 0;
+        }
+    }
+
+    copied
+}
+
+/* offer a list of buffers from the currently running capsule to a service
+   => stype = service to receive the offer
+      direction = whether the service will read from or write to the buffers
+      list, count = address in the capsule of the buffer descriptor array, and number of descriptors
+   <= ID of the transfer, or an error code */
+pub fn offer(stype: ServiceType, direction: Direction, list: usize, count: usize) -> Result<TransferID, Cause>
+{
+    let _tag = heaptag!();
+    let client = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    service::check_access(stype, client)?;
+    let access = match direction
+    {
+        Direction::ToService => Access::Read,
+        Direction::FromService => Access::Write
+    };
+    let segments = read_descriptors(client, list, count, access)?;
+
+    let id = TRANSFER_ID_NEXT.fetch_add(1, Ordering::SeqCst);
+    TRANSFERS.lock().insert(id, Transfer { client, stype, direction, segments });
+    WAITING.lock().entry(stype).or_insert(VecDeque::new()).push_back(id);
+
+    Ok(id)
+}
+
+/* get the next transfer waiting for the given service, which must be owned by the running capsule
+   <= transfer ID, its direction, and total size in bytes, or an error code */
+pub fn next(stype: ServiceType) -> Result<(TransferID, Direction, usize), Cause>
+{
+    check_owner(stype)?;
+
+    let id = match WAITING.lock().get_mut(&stype)
+    {
+        Some(queue) => match queue.pop_front()
+        {
+            Some(id) => id,
+            None => return Err(Cause::TransferBadID)
+        },
+        None => return Err(Cause::TransferBadID)
+    };
+
+    match TRANSFERS.lock().get(&id)
+    {
+        Some(transfer) => Ok((id, transfer.direction, transfer.size())),
+        None => Err(Cause::TransferBadID)
+    }
+}
+
+/* accept a transfer, supplying the service's buffers, and perform the copy.
+   the transfer is completed and forgotten, even if the two lists differ in size
+   => id = transfer to accept
+      list, count = address in the service's capsule of its buffer descriptor array, and number of descriptors
+   <= number of bytes copied, or an error code */
+pub fn accept(id: TransferID, list: usize, count: usize) -> Result<usize, Cause>
+{
+    let (stype, access) = match TRANSFERS.lock().get(&id)
+    {
+        Some(transfer) => (transfer.stype, match transfer.direction
+        {
+            Direction::ToService => Access::Write,
+            Direction::FromService => Access::Read
+        }),
+        None => return Err(Cause::TransferBadID)
+    };
+
+    let server = check_owner(stype)?;
+    let service_segments = read_descriptors(server, list, count, access)?;
+
+    let transfer = match TRANSFERS.lock().remove(&id)
+    {
+        Some(transfer) => transfer,
+        None => return Err(Cause::TransferBadID)
+    };
+
+    Ok(match transfer.direction
+    {
+        Direction::ToService => copy_segments(&transfer.segments, &service_segments),
+        Direction::FromService => copy_segments(&service_segments, &transfer.segments)
+    })
+}
+
+/* cancel any transfers offered by a capsule. call this when the capsule
+   is destroyed or restarted so its memory is no longer referenced */
+pub fn cancel(cid: CapsuleID)
+{
+    let mut transfers = TRANSFERS.lock();
+    transfers.retain(|_, transfer| transfer.client != cid);
+
+    for (_, queue) in WAITING.lock().iter_mut()
+    {
+        queue.retain(|id| transfers.contains_key(id));
+    }
+}
+
+/* check the running capsule owns the given service
+   <= ID of the running capsule, or an error code */
+fn check_owner(stype: ServiceType) -> Result<CapsuleID, Cause>
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(cid) => cid,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    match service::get_owner(stype)
+    {
+        Some(owner) if owner == cid => Ok(cid),
+        _ => Err(Cause::ServiceNotAllowed)
+    }
+}
@@ -0,0 +1,157 @@
+/* diosix trapped register access
+ *
+ * The platform's trap entry code stacks the interrupted code's
+ * general-purpose registers, x0 to x31 in order, on the hypervisor's
+ * stack, calls hypervisor_irq_handler() with the address of that frame,
+ * and reloads the registers from the frame when the handler returns.
+ * The handler takes the frame as an IRQContext by value, and as the
+ * RISC-V calling convention passes structures larger than two registers
+ * by reference, that parameter is the stacked frame itself rather than
+ * a copy of it. So the registers are only ever reached through a
+ * reference to the handler's IRQContext parameter, never through a
+ * copy, such as the one handed to the platform's dispatch(). Reading a
+ * register here reads what the interrupted code had in it, and writing
+ * one changes what it will see when it resumes, which is how hypercalls
+ * pick up their parameters and return their results. The IRQContext
+ * type must be at least as large as the frame: that's checked below as
+ * the hypervisor is built.
+ *
+ * The address of the instruction that trapped is held in the core's
+ * machine exception PC, which is driven through machine.rs. The trapped
+ * code's supervisor control and status registers are still loaded in
+ * the core at this point, so crash dumps and paused capsule inspection
+ * capture them alongside the stacked registers.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use platform::irq::IRQContext;
+use super::machine;
+
+/* number of general-purpose registers stacked in an IRQ context */
+pub const REGISTERS: usize = 32;
+
+/* registers of the calling convention that hypercalls use */
+pub const REG_A0: usize = 10;
+pub const REG_A1: usize = 11;
+pub const REG_A2: usize = 12;
+pub const REG_A3: usize = 13;
+pub const REG_A4: usize = 14;
+pub const REG_A5: usize = 15;
+pub const REG_A6: usize = 16;
+pub const REG_A7: usize = 17;
+
+/* names of the general-purpose registers in the calling convention, by register number */
+const REGISTER_NAMES: [&str; REGISTERS] =
+[
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+/* size in bytes of an environment call instruction, which has no compressed form */
+const ECALL_SIZE: usize = 4;
+
+/* refuse to build if the platform's IRQ context can't hold the stacked registers read and written here */
+const _: () = assert!(core::mem::size_of::<IRQContext>() >= REGISTERS * core::mem::size_of::<usize>());
+
+/* <= the stacked general-purpose registers of the given IRQ context */
+fn stacked(context: &IRQContext) -> &[usize; REGISTERS]
+{
+    unsafe { &*(context as *const IRQContext as *const [usize; REGISTERS]) }
+}
+
+fn stacked_mut(context: &mut IRQContext) -> &mut [usize; REGISTERS]
+{
+    unsafe { &mut *(context as *mut IRQContext as *mut [usize; REGISTERS]) }
+}
+
+/* read a general-purpose register of the interrupted code
+   => context = IRQ context holding the registers
+      reg = register number, from 0 to 31
+   <= the register's value */
+pub fn register(context: &IRQContext, reg: usize) -> usize
+{
+    match reg
+    {
+        0 => 0, /* x0 is hardwired to zero whatever was stacked for it */
+        r => stacked(context)[r % REGISTERS]
+    }
+}
+
+/* change a general-purpose register of the interrupted code, to take effect when it resumes.
+   writes to x0 are ignored
+   => context = IRQ context holding the registers
+      reg = register number, from 1 to 31
+      value = value to put in the register */
+pub fn set_register(context: &mut IRQContext, reg: usize, value: usize)
+{
+    if reg > 0
+    {
+        stacked_mut(context)[reg % REGISTERS] = value;
+    }
+}
+
+/* move the trapped code on past the environment call it made, so that it doesn't make the call
+   again when it resumes. the resume address is set from the call's own address rather than
+   advanced, so it's right whether or not the platform has already moved past the call.
+   call before any context switch, so that the switched-out code is saved with the right resume address
+   => pc = address of the environment call instruction */
+pub fn skip_environment_call(pc: usize)
+{
+    machine::set_trapped_pc(pc + ECALL_SIZE);
+}
+
+/* every register of code that trapped into the hypervisor, captured for a crash dump or inspection */
+pub struct CrashRegisters
+{
+    general: [(&'static str, usize); REGISTERS],
+    control: [(&'static str, usize); machine::TRAPPED_CONTROL_REGISTERS]
+}
+
+impl CrashRegisters
+{
+    /* <= the general-purpose registers by name, in register number order */
+    pub fn general(&self) -> &[(&'static str, usize)]
+    {
+        &self.general
+    }
+
+    /* <= the control and status registers by name */
+    pub fn control(&self) -> &[(&'static str, usize)]
+    {
+        &self.control
+    }
+
+    /* <= true if the trapped code was using address translation */
+    pub fn translated(&self) -> bool
+    {
+        match self.control.iter().find(|(name, _)| *name == "satp")
+        {
+            Some((_, satp)) => machine::satp_translates(*satp),
+            None => false
+        }
+    }
+}
+
+/* capture the registers of the code that trapped into the hypervisor on this CPU core.
+   call before it's switched out, while its control and status registers are still loaded
+   => context = IRQ context holding its general-purpose registers
+   <= the captured registers */
+pub fn crash_registers(context: &IRQContext) -> CrashRegisters
+{
+    let mut general = [("", 0); REGISTERS];
+    for (reg, entry) in general.iter_mut().enumerate()
+    {
+        *entry = (REGISTER_NAMES[reg], register(context, reg));
+    }
+
+    CrashRegisters
+    {
+        general,
+        control: machine::trapped_control_registers()
+    }
+}
@@ -5,9 +5,13 @@
  * See LICENSE for usage and copying.
  */
 
+use core::cmp::Reverse;
+use alloc::collections::binary_heap::BinaryHeap;
+use alloc::collections::vec_deque::VecDeque;
 use super::error::Cause;
 use super::capsule::{self, CapsuleID};
 use super::scheduler;
+use super::pcore::CoreClass;
 use platform::cpu::{SupervisorState, SupervisorFPState, Entry};
 use platform::physmem::PhysMemBase;
 use platform::timer;
@@ -16,12 +20,30 @@ use platform::timer;
 pub enum Priority
 {
     High,
-    Normal
+    Normal,
+    Deadline(Deadline) /* guaranteed a budget of CPU time every period, then scheduled as Normal */
+}
+
+/* a deadline vcore is guaranteed budget milliseconds of physical CPU time every period milliseconds */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Deadline
+{
+    pub period: u64,
+    pub budget: u64
 }
 
 /* virtual core ID unique to its capsule */
 pub type VirtualCoreID = usize;
 
+/* timer ID unique to its virtual core */
+pub type TimerID = usize;
+
+/* maximum number of timers a virtual core can have armed at any one time, on top of its SBI timer */
+const VCORE_TIMERS_MAX: usize = 16;
+
+/* maximum number of expired timer IDs held for the virtual core to collect */
+const VCORE_FIRED_MAX: usize = 64;
+
 /* pair a virtual core with its parent capsule using their ID numbers */
 #[derive(PartialEq, Eq, Hash)]
 pub struct VirtualCoreCanonicalID
@@ -37,9 +59,18 @@ pub struct VirtualCore
 {
     id: VirtualCoreCanonicalID,
     priority: Priority,
+    class: CoreClass,           /* class of physical core this virtual core would rather run on */
     state: SupervisorState,
     fp_state: SupervisorFPState,
-    timer_irq_at: Option<timer::TimerValue>
+    timer_irq_at: Option<timer::TimerValue>,
+    timer_compare: u64,         /* saved supervisor timer compare register, used when the CPU has Sstc */
+    timer_compare_held: Option<(u64, u64)>, /* compare value forced by the hypervisor, and the supervisor's own held aside */
+    timers: BinaryHeap<Reverse<(u64, TimerID)>>, /* armed timers, earliest first, in exact timer ticks */
+    timers_fired: VecDeque<TimerID>, /* expired timers not yet collected by the virtual core */
+    timer_id_next: TimerID,
+    period_start: u64,          /* when the current deadline period began, in timer ticks */
+    budget_used: u64,           /* ticks of CPU time used so far in this period */
+    running_since: Option<u64>  /* when this vcore was last switched in, if it's running */
 }
 
 impl VirtualCore
@@ -56,7 +87,24 @@ impl VirtualCore
     pub fn create(capsuleid: CapsuleID, core: VirtualCoreID, entry: Entry, dtb: PhysMemBase, priority: Priority) -> Result<(), Cause>
     {
         let max_vcores = capsule::get_max_vcores(capsuleid)?;
-        
+
+        /* unless the manifest says otherwise, background vcores are happy on efficiency cores */
+        let class = match capsule::get_core_class(capsuleid)?
+        {
+            Some(class) => class,
+            None => match priority
+            {
+                Priority::Normal => CoreClass::Efficiency,
+                _ => CoreClass::Performance
+            }
+        };
+
+        /* deadline vcores must be admitted before they can be created */
+        if let Priority::Deadline(deadline) = priority
+        {
+            scheduler::admit_deadline(deadline)?;
+        }
+
         let new_vcore = VirtualCore
         {
             id: VirtualCoreCanonicalID
@@ -65,13 +113,22 @@ impl VirtualCore
                 vcoreid: core
             },
             priority,
+            class,
             state: platform::cpu::init_supervisor_cpu_state(core, max_vcores, entry, dtb),
             fp_state: platform::cpu::init_supervisor_fp_state(),
-            timer_irq_at: None
+            timer_irq_at: None,
+            timer_compare: u64::MAX, /* don't fire until the supervisor programs its timer */
+            timer_compare_held: None,
+            timers: BinaryHeap::new(),
+            timers_fired: VecDeque::new(),
+            timer_id_next: 0,
+            period_start: 0,
+            budget_used: 0,
+            running_since: None
         };
 
-        /* add virtual CPU core to the global waiting list queue */
-        scheduler::queue(new_vcore);
+        /* place the virtual CPU core on the least-loaded physical CPU core */
+        scheduler::place(new_vcore);
         Ok(())
     }
 
@@ -90,6 +147,9 @@ impl VirtualCore
     /* return this virtual core's ID within its capsule */
     pub fn get_id(&self) -> VirtualCoreID { self.id.vcoreid }
 
+    /* return the class of physical core this virtual core prefers to run on */
+    pub fn get_class(&self) -> CoreClass { self.class }
+
     /* return virtual CPU core capsule's ID */
     pub fn get_capsule_id(&self) -> CapsuleID { self.id.capsuleid }
 
@@ -108,4 +168,184 @@ impl VirtualCore
     {
         self.timer_irq_at
     }
+
+    /* save the supervisor timer compare register's value when switching this core out.
+       only meaningful on physical cores that let supervisors program their own timer IRQs (Sstc) */
+    pub fn set_timer_compare(&mut self, value: u64)
+    {
+        self.timer_compare = value;
+    }
+
+    /* return the supervisor timer compare register's value to restore when switching this core in */
+    pub fn get_timer_compare(&self) -> u64
+    {
+        self.timer_compare
+    }
+
+    /* hold aside the supervisor's own timer compare value while the hypervisor forces its own value
+       into the register to raise a timer IRQ. if a value's already held, it stays held
+       => forced = value the hypervisor is putting in the compare register
+          own = value the supervisor last put in the compare register */
+    pub fn hold_timer_compare(&mut self, forced: u64, own: u64)
+    {
+        let own = match self.timer_compare_held
+        {
+            Some((_, held)) => held,
+            None => own
+        };
+        self.timer_compare_held = Some((forced, own));
+    }
+
+    /* <= the compare value forced by the hypervisor and the supervisor's own value held aside,
+          or None if nothing's held. the held value is forgotten */
+    pub fn take_timer_compare_held(&mut self) -> Option<(u64, u64)>
+    {
+        self.timer_compare_held.take()
+    }
+
+    /* <= true if the supervisor's own timer compare value is held aside */
+    pub fn is_timer_compare_held(&self) -> bool
+    {
+        self.timer_compare_held.is_some()
+    }
+
+    /* arm an additional timer for this core, alongside its SBI timer
+       => at = value of the clock-on-the-wall, in exact timer ticks, at which the timer fires
+       <= ID of the timer, or an error code if too many timers are armed */
+    pub fn add_timer(&mut self, at: u64) -> Result<TimerID, Cause>
+    {
+        if self.timers.len() >= VCORE_TIMERS_MAX
+        {
+            return Err(Cause::SchedTooManyTimers);
+        }
+
+        let id = self.timer_id_next;
+        self.timer_id_next = self.timer_id_next.wrapping_add(1);
+        self.timers.push(Reverse((at, id)));
+        Ok(id)
+    }
+
+    /* disarm a timer before it fires
+       <= Ok if the timer was armed, or an error code if not */
+    pub fn cancel_timer(&mut self, id: TimerID) -> Result<(), Cause>
+    {
+        let before = self.timers.len();
+        self.timers = self.timers.drain().filter(|Reverse((_, timer))| *timer != id).collect();
+        match self.timers.len() == before
+        {
+            true => Err(Cause::SchedBadTimer),
+            false => Ok(())
+        }
+    }
+
+    /* return when the earliest armed timer will fire, in exact timer ticks, or None for no timers */
+    pub fn get_next_timer(&self) -> Option<u64>
+    {
+        match self.timers.peek()
+        {
+            Some(Reverse((at, _))) => Some(*at),
+            None => None
+        }
+    }
+
+    /* move timers that have expired onto the fired list so that they're delivered together in a single interrupt
+       => now = clock-on-the-wall, in exact timer ticks */
+    pub fn expire_timers(&mut self, now: u64)
+    {
+        while let Some(Reverse((at, id))) = self.timers.peek().cloned()
+        {
+            if at > now
+            {
+                break;
+            }
+
+            self.timers.pop();
+            if self.timers_fired.len() >= VCORE_FIRED_MAX
+            {
+                self.timers_fired.pop_front();
+            }
+            self.timers_fired.push_back(id);
+        }
+    }
+
+    /* return the ID of the next timer to have fired, or None if there are none */
+    pub fn next_fired_timer(&mut self) -> Option<TimerID>
+    {
+        self.timers_fired.pop_front()
+    }
+
+    /* record that this virtual core has been switched onto a physical core at time now, in timer ticks */
+    pub fn start_running(&mut self, now: u64)
+    {
+        self.running_since = Some(now);
+    }
+
+    /* record that this virtual core has been switched off its physical core at time now, in timer ticks,
+       and charge the time it ran to its deadline budget */
+    pub fn stop_running(&mut self, now: u64)
+    {
+        if let Some(since) = self.running_since.take()
+        {
+            self.budget_used = self.budget_used + now.saturating_sub(since);
+        }
+    }
+
+    /* calculate how much of a deadline virtual core's budget is left in its current period,
+       starting a fresh period if the last one has ended
+       => now = current time in timer ticks
+          frequency = timer ticks per second
+       <= ticks of budget remaining, or None if this isn't a deadline virtual core */
+    pub fn deadline_budget_left(&mut self, now: u64, frequency: u64) -> Option<u64>
+    {
+        let deadline = match self.priority
+        {
+            Priority::Deadline(d) => d,
+            _ => return None
+        };
+
+        let period = timer::TimerValue::Milliseconds(deadline.period).to_exact(frequency);
+        let budget = timer::TimerValue::Milliseconds(deadline.budget).to_exact(frequency);
+
+        /* replenish the budget at the start of each period */
+        if now >= self.period_start + period
+        {
+            self.period_start = now;
+            self.budget_used = 0;
+            if self.running_since.is_some()
+            {
+                self.running_since = Some(now);
+            }
+        }
+
+        /* include time spent running but not yet charged */
+        let running = match self.running_since
+        {
+            Some(since) => now.saturating_sub(since),
+            None => 0
+        };
+
+        Some(budget.saturating_sub(self.budget_used + running))
+    }
+
+    /* return when this deadline virtual core's current period ends, in timer ticks, or None if not a deadline vcore */
+    pub fn deadline_period_end(&self, frequency: u64) -> Option<u64>
+    {
+        match self.priority
+        {
+            Priority::Deadline(d) => Some(self.period_start + timer::TimerValue::Milliseconds(d.period).to_exact(frequency)),
+            _ => None
+        }
+    }
+}
+
+/* give back a deadline virtual core's share of CPU time when it's destroyed */
+impl Drop for VirtualCore
+{
+    fn drop(&mut self)
+    {
+        if let Priority::Deadline(deadline) = self.priority
+        {
+            scheduler::release_deadline(deadline);
+        }
+    }
 }
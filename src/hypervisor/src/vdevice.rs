@@ -0,0 +1,223 @@
+/* diosix virtual devices declared in the manifest
+ *
+ * Rather than naming device model plugins with device_model= and
+ * taking whatever address and interrupt the hypervisor picks, a
+ * capsule's manifest can declare each virtual device it's given,
+ * one device= property per device, eg:
+ *
+ *   device=console
+ *   device=rng,irq=0x11001
+ *   device=block,asset=rootfs,addr=0x4000010000,queue=128,rate=1000
+ *   device=net,switch=lan0
+ *   device=rtc,addr=0x4000020000
+ *
+ * The kind of device comes first, followed by any options:
+ *
+ *   asset=name   DMFS asset holding the contents of a block device, which
+ *                is read-only. block devices must have one
+ *   switch=name  virtual switch a network device is plugged into. network
+ *                devices must have one. names are made of lowercase
+ *                letters, digits, - and _
+ *   model=name   device model plugin to use rather than the kind's default
+ *   addr=n       base of the device's registers in the capsule's physical
+ *                address space, as described in its device tree
+ *   irq=n        the device's interrupt number in the capsule's device tree
+ *   queue=n      most entries in each of the device's virtqueues, a power of two
+ *   rate=n       most requests the device handles per second
+ *
+ * Numbers can be written in decimal or, prefixed with 0x, hexadecimal.
+ * Addresses and interrupts not given are chosen by the hypervisor.
+ * Identical declarations describe the same device, so to give a capsule
+ * two devices of the same kind, give them different options. A
+ * malformed device= property stops the capsule being created, like any
+ * other bad property. The devices are created by the device model code
+ * from the parsed declarations.
+ *
+ * Every capsule is given a real-time clock, emulated by the hypervisor,
+ * whether or not it declares one. Declaring it sets its address or
+ * interrupt.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::string::String;
+use platform::physmem::PhysMemBase;
+use super::passthrough::DeviceIRQ;
+use super::devmodel;
+use super::rtc;
+
+/* longest virtual switch name accepted, in bytes */
+const SWITCH_NAME_MAX_LEN: usize = 32;
+
+/* interrupts of declared devices are numbered within this range */
+pub const VIRQ_DEVICE_BASE: DeviceIRQ = hypercall::irq::VIRQ_DEVICE_BASE;
+pub const VIRQ_DEVICE_COUNT: usize = hypercall::irq::VIRQ_DEVICE_COUNT;
+
+/* largest virtqueue a device can be limited to */
+const QUEUE_MAX: usize = 32768;
+
+/* kinds of virtual device */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum DeviceKind
+{
+    Console,
+    Rng,
+    Block,
+    Net,
+    Rtc
+}
+
+impl DeviceKind
+{
+    /* <= name of the device model that emulates this kind of device by default */
+    pub fn default_model(&self) -> &'static str
+    {
+        match self
+        {
+            DeviceKind::Console => "virtio-console",
+            DeviceKind::Rng => "virtio-rng",
+            DeviceKind::Block => "virtio-blk",
+            DeviceKind::Net => "virtio-net",
+            DeviceKind::Rtc => rtc::MODEL_NAME
+        }
+    }
+}
+
+/* a virtual device declared in a capsule's manifest */
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DeviceSpec
+{
+    pub kind: DeviceKind,
+    pub model: Option<String>,      /* plugin to use, if not the kind's default */
+    pub asset: Option<String>,      /* DMFS asset backing a block device */
+    pub switch: Option<String>,     /* virtual switch a network device is plugged into */
+    pub addr: Option<PhysMemBase>,  /* base of the device's window, or None to choose one */
+    pub irq: Option<DeviceIRQ>,     /* device's interrupt, or None to choose one */
+    pub queue: Option<usize>,       /* most entries per virtqueue, or None for the model's default */
+    pub rate: Option<usize>         /* most requests per second, or None for no limit */
+}
+
+impl DeviceSpec
+{
+    /* <= a device of the given kind with no options, placed wherever the hypervisor chooses */
+    pub fn new(kind: DeviceKind) -> DeviceSpec
+    {
+        DeviceSpec
+        {
+            kind, model: None, asset: None, switch: None, addr: None, irq: None, queue: None, rate: None
+        }
+    }
+
+    /* parse the value of a device= property
+       => value = kind of device followed by comma-separated options, eg: block,asset=rootfs
+       <= the declared device, or None if the value is malformed */
+    pub fn parse(value: &str) -> Option<DeviceSpec>
+    {
+        let mut fields = value.split(',');
+        let kind = fields.next()?.trim();
+        let kind = match kind
+        {
+            k if k.eq_ignore_ascii_case("console") => DeviceKind::Console,
+            k if k.eq_ignore_ascii_case("rng") => DeviceKind::Rng,
+            k if k.eq_ignore_ascii_case("block") => DeviceKind::Block,
+            k if k.eq_ignore_ascii_case("net") => DeviceKind::Net,
+            k if k.eq_ignore_ascii_case("rtc") => DeviceKind::Rtc,
+            _ => return None
+        };
+
+        let mut spec = DeviceSpec::new(kind);
+
+        for field in fields
+        {
+            let mut parts = field.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next())
+            {
+                (Some(name), Some(value)) if value.trim().len() > 0 => (name.trim(), value.trim()),
+                (_, _) => return None
+            };
+
+            /* each option can only be given once */
+            match name
+            {
+                n if n.eq_ignore_ascii_case("model") && spec.model.is_none() => spec.model = Some(String::from(value)),
+                n if n.eq_ignore_ascii_case("asset") && spec.asset.is_none() => spec.asset = Some(String::from(value)),
+                n if n.eq_ignore_ascii_case("switch") && spec.switch.is_none() =>
+                {
+                    if value.len() > SWITCH_NAME_MAX_LEN || value.chars().all(|c| c.is_ascii_lowercase()
+                        || c.is_ascii_digit() || c == '-' || c == '_') == false
+                    {
+                        return None;
+                    }
+                    spec.switch = Some(String::from(value));
+                },
+                n if n.eq_ignore_ascii_case("addr") && spec.addr.is_none() =>
+                {
+                    let addr = parse_number(value)?;
+                    if devmodel::is_valid_window(addr) == false
+                    {
+                        return None;
+                    }
+                    spec.addr = Some(addr);
+                },
+                n if n.eq_ignore_ascii_case("irq") && spec.irq.is_none() =>
+                {
+                    let irq = parse_number(value)?;
+                    if irq < VIRQ_DEVICE_BASE || irq >= VIRQ_DEVICE_BASE + VIRQ_DEVICE_COUNT
+                    {
+                        return None;
+                    }
+                    spec.irq = Some(irq);
+                },
+                n if n.eq_ignore_ascii_case("queue") && spec.queue.is_none() =>
+                {
+                    let entries = parse_number(value)?;
+                    if entries == 0 || entries > QUEUE_MAX || entries.is_power_of_two() == false
+                    {
+                        return None;
+                    }
+                    spec.queue = Some(entries);
+                },
+                n if n.eq_ignore_ascii_case("rate") && spec.rate.is_none() =>
+                {
+                    match parse_number(value)?
+                    {
+                        0 => return None,
+                        rate => spec.rate = Some(rate)
+                    }
+                },
+                _ => return None
+            }
+        }
+
+        /* block devices need something to read, and network devices somewhere to send packets.
+           neither option means anything to other kinds of device */
+        if spec.asset.is_some() != (kind == DeviceKind::Block) || spec.switch.is_some() != (kind == DeviceKind::Net)
+        {
+            return None;
+        }
+
+        Some(spec)
+    }
+
+    /* <= name of the device model plugin to emulate this device */
+    pub fn model(&self) -> &str
+    {
+        match &self.model
+        {
+            Some(model) => model.as_str(),
+            None => self.kind.default_model()
+        }
+    }
+}
+
+/* parse a number written in decimal, or hexadecimal prefixed with 0x */
+fn parse_number(value: &str) -> Option<usize>
+{
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse::<usize>().ok()
+    }
+}
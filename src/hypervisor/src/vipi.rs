@@ -0,0 +1,139 @@
+/* diosix virtual inter-processor interrupts
+ *
+ * Let a capsule's virtual cores interrupt each other, such as to ask
+ * siblings to flush their TLBs or to look at a shared work queue,
+ * without relying on the SBI's IPI extension. Any supervisor kernel
+ * can use it, not just those that follow Linux's SBI conventions.
+ *
+ * A virtual core sends an IPI to one of its siblings, or to all of
+ * them, by hypercall. Each virtual core has an IPI pending flag. While
+ * it's set, the virtual core sees a supervisor-level software interrupt
+ * whenever it runs, until it claims the IPI by hypercall, which clears
+ * the flag and the interrupt. IPIs sent to a virtual core before it
+ * claims the last one are merged into it, so supervisors should check
+ * for all the work their IPIs signal when they claim one.
+ *
+ * The target's physical core is asked to deliver the IPI straight away.
+ * If the target isn't running, such as when it's sleeping in a wait for
+ * interrupt, and its physical core is idle or running one of its siblings,
+ * it's switched in to take the IPI. Otherwise it takes the IPI when it
+ * next runs, at the end of the current timeslice, so that capsules can't
+ * use IPIs to take time from each other.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_set::HashSet;
+use alloc::vec::Vec;
+use super::error::{self, Cause};
+use super::capsule::{self, CapsuleID};
+use super::vcore::VirtualCoreID;
+use super::pcore;
+use super::message;
+use super::hardware;
+use super::machine;
+
+/* send to all of a capsule's virtual cores other than the sender, shared with the capsules */
+pub use hypercall::ipi::IPI_ALL_OTHERS;
+
+lazy_static!
+{
+    /* virtual cores with an IPI waiting to be claimed */
+    static ref PENDING: Mutex<HashSet<(CapsuleID, VirtualCoreID)>> = Mutex::new("virtual IPI pending set", HashSet::new());
+}
+
+/* send an IPI from the currently running virtual core to one or all of its siblings
+   => target = ID of the virtual core to interrupt, or IPI_ALL_OTHERS for all of them but the sender
+   <= Ok for success, or an error code */
+pub fn send(target: usize) -> Result<(), Cause>
+{
+    let sender = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => id,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let cid = sender.capsuleid;
+    let vcores = capsule::get_max_vcores(cid)?;
+    let targets: Vec<VirtualCoreID> = match target
+    {
+        IPI_ALL_OTHERS => (0..vcores).filter(|vid| *vid != sender.vcoreid).collect(),
+        vid if vid < vcores => (vid..vid + 1).collect(),
+        _ => return Err(Cause::VirtualCoreBadID)
+    };
+
+    for vid in targets
+    {
+        PENDING.lock().insert((cid, vid));
+
+        /* a virtual core can interrupt itself, though it won't see the IPI until it's back from this hypercall */
+        if vid == sender.vcoreid
+        {
+            check_pending();
+            continue;
+        }
+
+        /* otherwise, ask the physical core that last ran the target to deliver it.
+           virtual cores that have never run will see it when they first do */
+        if let Some(pid) = pcore::last_pcore_of(cid, vid)
+        {
+            /* if the target is running, hosts with an ACLINT SSWI device can interrupt it without
+               interrupting its physical core's hypervisor. should the core switch virtual cores in
+               the meantime, the interrupt is cleared or raised again for the incoming one */
+            if pcore::get_running_vcore(pid) == Some((cid, vid)) && hardware::raise_supervisor_soft_irq(pid) == true
+            {
+                continue;
+            }
+
+            match message::Message::new(message::Recipient::send_to_pcore(pid), message::MessageContent::VirtualIPI(cid, vid))
+            {
+                Ok(m) => if let Err(_e) = message::send(m)
+                {
+                    hvalert!("Failed to message physical CPU {} with an IPI for capsule {}: {}", pid, cid, error::report(&_e));
+                },
+                Err(_e) => hvalert!("Failed to create IPI message for capsule {}: {}", cid, error::report(&_e))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/* claim the currently running virtual core's IPI, clearing its software interrupt
+   <= true if an IPI was pending, or false if not, or an error code */
+pub fn claim() -> Result<bool, Cause>
+{
+    let id = match pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        Some(id) => id,
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let pending = PENDING.lock().remove(&(id.capsuleid, id.vcoreid));
+    machine::clear_supervisor_software_irq();
+    Ok(pending)
+}
+
+/* raise or clear a supervisor-level software interrupt for the virtual core about to run on
+   this physical core, depending on whether it has an IPI waiting. call this before returning
+   to a virtual core, including after switching to another one */
+pub fn check_pending()
+{
+    if let Some(id) = pcore::PhysicalCore::this().get_virtualcore_id()
+    {
+        match PENDING.lock().contains(&(id.capsuleid, id.vcoreid))
+        {
+            true => machine::trigger_supervisor_software_irq(),
+            false => machine::clear_supervisor_software_irq()
+        }
+    }
+}
+
+/* discard any IPIs waiting for a capsule's virtual cores when it's destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    PENDING.lock().retain(|(owner, _)| *owner != cid);
+}
@@ -0,0 +1,573 @@
+/* diosix capsule device tree customization
+ *
+ * The platform code generates a base device tree for each
+ * capsule describing its virtual CPU cores and RAM. This
+ * code adds the hypervisor's own, platform-agnostic
+ * details to that tree, such as passed-through devices,
+ * before it is handed to the capsule.
+ *
+ * The tree is published in an area reserved in the capsule's
+ * RAM, and its address is passed to the capsule's boot vcore
+ * in a register, as the RISC-V boot protocol expects. By
+ * default, the area is placed at a random, page-aligned spot
+ * in the top half of the capsule's RAM so that its location
+ * can't be guessed. The manifest can instead fix it at the
+ * top of RAM, or at a given offset, for guests with unusual
+ * memory layout expectations.
+ *
+ * The area holds two copies of the tree, in slots of equal
+ * size. The boot tree is in the first slot, at the start of
+ * the area. If the capsule's resources change while it runs,
+ * the tree is regenerated off to the side, in the hypervisor's
+ * own memory, and then copied into the slot the capsule isn't
+ * using, leaving the current tree intact. The new copy's magic
+ * number is written last, so the copy only becomes a valid
+ * tree once it's complete. Each copy carries its generation
+ * number in /chosen/diosix,dt-generation, and the capsule is
+ * sent a virtual interrupt so it can reread the valid copy
+ * with the highest generation.
+ *
+ * Memory given to the capsule for DMA is described in the
+ * tree's /reserved-memory node as a shared DMA pool, so that
+ * the guest's drivers can use it for DMA buffers without the
+ * guest handing it out as general-purpose RAM.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{fence, Ordering};
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use alloc::string::String;
+use devicetree::{DeviceTree, DeviceTreeBlob, DeviceTreeProperty};
+use hvalgo::fdt::Fdt;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID};
+use super::hardware;
+use super::seriallink;
+use super::gpio;
+use super::devmodel;
+use super::abi;
+use super::failover;
+use super::identity;
+use super::clock;
+use super::power;
+use super::stream;
+use super::pressure;
+use super::grant;
+use super::button;
+use super::physmem::{self, Region};
+use super::passthrough::{self, DeviceType, DeviceIRQ};
+
+/* bytes reserved in a capsule's RAM for each copy of its device tree, including room to grow */
+const DTB_SLOT_SIZE: PhysMemSize = 64 * 1024;
+
+/* the area holds two copies of the tree so that one can be written while the other is in use */
+const DTB_SLOTS: usize = 2;
+const DTB_AREA_SIZE: PhysMemSize = DTB_SLOT_SIZE * DTB_SLOTS;
+
+/* bytes at the start of a device tree blob holding its magic number */
+const DTB_MAGIC_SIZE: usize = 4;
+
+/* alignment of the device tree's area within the capsule's RAM */
+const DTB_AREA_ALIGN: PhysMemSize = 4096;
+
+/* where to put a capsule's device tree in its RAM */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Placement
+{
+    Top,                    /* at the very top of RAM */
+    Random,                 /* somewhere in the top half of RAM, chosen at random */
+    Offset(PhysMemSize)     /* this many bytes from the start of RAM */
+}
+
+/* virtual interrupt raised when a capsule's device tree has been republished.
+   this lies outside the range of physical interrupt numbers */
+pub const VIRQ_DEVICE_TREE_CHANGED: DeviceIRQ = hypercall::irq::VIRQ_DEVICE_TREE_CHANGED;
+
+/* record what's needed to regenerate a capsule's device tree */
+struct Published
+{
+    cpus: usize,        /* virtual cores described in the tree */
+    ram: Region,        /* capsule's main RAM, holding the tree */
+    area: PhysMemBase,  /* base of the area in RAM holding the tree */
+    slot: usize,        /* slot in the area holding the current copy of the tree */
+    generation: usize   /* number of times the tree has been republished */
+}
+
+lazy_static!
+{
+    /* device trees published to capsules */
+    static ref PUBLISHED: Mutex<HashMap<CapsuleID, Published>> = Mutex::new("published device trees", HashMap::new());
+}
+
+/* convert a device tree blob into an editable tree */
+fn blob_to_tree(blob: &Vec<u8>) -> Result<DeviceTree, Cause>
+{
+    match DeviceTreeBlob::from_slice(blob.as_slice())
+    {
+        Ok(dtb) => match dtb.to_tree()
+        {
+            Ok(tree) => Ok(tree),
+            Err(_) => Err(Cause::DeviceTreeBad)
+        },
+        Err(_) => Err(Cause::DeviceTreeBad)
+    }
+}
+
+/* convert an edited tree back into a blob */
+fn tree_to_blob(tree: &DeviceTree) -> Result<Vec<u8>, Cause>
+{
+    match tree.to_blob()
+    {
+        Ok(blob) => Ok(blob),
+        Err(_) => Err(Cause::DeviceTreeBad)
+    }
+}
+
+/* add the hypervisor's details to a capsule's platform-generated device tree
+   => cid = capsule the device tree is for
+      blob = platform-generated device tree blob
+   <= customized device tree blob, or an error code */
+pub fn customize(cid: CapsuleID, blob: Vec<u8>) -> Result<Vec<u8>, Cause>
+{
+    let mut tree = blob_to_tree(&blob)?;
+
+    add_hypervisor_node(cid, &mut tree);
+    add_identity(cid, &mut tree);
+    add_passthrough_devices(cid, &blob, &mut tree)?;
+    add_cpu_topology(cid, &mut tree)?;
+    add_extra_memory(cid, &mut tree)?;
+    add_serial_links(cid, &mut tree);
+    add_gpio_lines(cid, &mut tree);
+    add_device_models(cid, &mut tree);
+
+    tree_to_blob(&tree)
+}
+
+/* generate a capsule's device tree and write it into the area reserved for it in its RAM
+   => cid = capsule the device tree is for
+      cpus = number of virtual cores to describe
+      ram = the capsule's main RAM
+   <= physical address of the device tree, or an error code */
+pub fn publish(cid: CapsuleID, cpus: usize, ram: Region) -> Result<PhysMemBase, Cause>
+{
+    let _tag = heaptag!();
+    let area = choose_area(cid, ram)?;
+    write_tree(cid, cpus, ram, area, 0, 0)?;
+    PUBLISHED.lock().insert(cid, Published { cpus, ram, area, slot: 0, generation: 0 });
+    Ok(area)
+}
+
+/* regenerate and republish a capsule's device tree after its resources have changed,
+   and tell the capsule so it can reread the tree.
+   => cid = capsule whose resources have changed
+   <= Ok for success, or an error code */
+pub fn regenerate(cid: CapsuleID) -> Result<(), Cause>
+{
+    let _tag = heaptag!();
+    let (cpus, ram, area, slot, generation) = match PUBLISHED.lock().get(&cid)
+    {
+        Some(published) => (published.cpus, published.ram, published.area, published.slot, published.generation),
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* write the new tree into the slot the capsule isn't using */
+    let (slot, generation) = ((slot + 1) % DTB_SLOTS, generation + 1);
+    write_tree(cid, cpus, ram, area, slot, generation)?;
+
+    if let Some(published) = PUBLISHED.lock().get_mut(&cid)
+    {
+        published.slot = slot;
+        published.generation = generation;
+    }
+
+    passthrough::raise_virtual_irq(cid, VIRQ_DEVICE_TREE_CHANGED);
+    Ok(())
+}
+
+/* rewrite a capsule's current device tree into its RAM, for when that RAM has been wiped.
+   the tree's content is unchanged so the capsule isn't notified */
+pub fn restore(cid: CapsuleID) -> Result<(), Cause>
+{
+    let (cpus, ram, area, slot, generation) = match PUBLISHED.lock().get(&cid)
+    {
+        Some(published) => (published.cpus, published.ram, published.area, published.slot, published.generation),
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    write_tree(cid, cpus, ram, area, slot, generation)?;
+    Ok(())
+}
+
+/* return the base of the area holding a capsule's device tree, or None if it has none */
+pub fn get_area(cid: CapsuleID) -> Option<PhysMemBase>
+{
+    match PUBLISHED.lock().get(&cid)
+    {
+        Some(published) => Some(published.area),
+        None => None
+    }
+}
+
+/* return the number of times a capsule's device tree has been republished, or None if it has none */
+pub fn get_generation(cid: CapsuleID) -> Option<usize>
+{
+    match PUBLISHED.lock().get(&cid)
+    {
+        Some(published) => Some(published.generation),
+        None => None
+    }
+}
+
+/* forget a capsule's device tree when the capsule is destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    PUBLISHED.lock().remove(&cid);
+}
+
+/* pick where to put a capsule's device tree in its RAM, following its manifest's wishes
+   => cid = capsule the device tree is for
+      ram = the capsule's main RAM
+   <= base of the area to hold the tree, or an error code */
+fn choose_area(cid: CapsuleID, ram: Region) -> Result<PhysMemBase, Cause>
+{
+    if ram.size() < DTB_AREA_SIZE
+    {
+        return Err(Cause::PhysRegionTooSmall);
+    }
+    let top = (ram.end() - DTB_AREA_SIZE) & !(DTB_AREA_ALIGN - 1);
+
+    match capsule::get_dtb_placement(cid)?
+    {
+        Placement::Top => Ok(top),
+        Placement::Offset(offset) => match offset % DTB_AREA_ALIGN == 0 && offset + DTB_AREA_SIZE <= ram.size()
+        {
+            true => Ok(ram.base() + offset),
+            false => Err(hverror!(Cause::DeviceTreeBadPlacement, "capsule {} tree can't be placed at offset 0x{:x}", cid, offset))
+        },
+        Placement::Random =>
+        {
+            /* leave the bottom half of RAM to the capsule's image */
+            let lowest = (ram.base() + (ram.size() / 2) + DTB_AREA_ALIGN - 1) & !(DTB_AREA_ALIGN - 1);
+            if lowest >= top
+            {
+                return Ok(top);
+            }
+
+            let slots = ((top - lowest) / DTB_AREA_ALIGN) + 1;
+            match hardware::get_random()
+            {
+                Some(random) => Ok(lowest + ((random as usize % slots) * DTB_AREA_ALIGN)),
+                None => Ok(top)
+            }
+        }
+    }
+}
+
+/* generate a capsule's device tree in the hypervisor's memory and then copy it into a slot
+   in the reserved area in the capsule's RAM
+   => cid = capsule the device tree is for
+      cpus = number of virtual cores to describe
+      ram = the capsule's main RAM
+      area_base = base of the area to hold the tree, within the RAM
+      slot = slot in the area to write the tree to
+      generation = number of times the tree has been republished
+   <= Ok for success, or an error code */
+fn write_tree(cid: CapsuleID, cpus: usize, ram: Region, area_base: PhysMemBase, slot: usize, generation: usize) -> Result<(), Cause>
+{
+    /* a zero-length DTB indicates something went wrong */
+    let blob = hardware::clone_dtb_for_capsule(cpus, 0, ram.base(), ram.size())?;
+    if blob.len() == 0
+    {
+        return Err(Cause::BootDeviceTreeBad);
+    }
+
+    let mut tree = blob_to_tree(&blob)?;
+    add_reserved_area(&mut tree, area_base, generation);
+    let blob = customize(cid, tree_to_blob(&tree)?)?;
+
+    if blob.len() > DTB_SLOT_SIZE || blob.len() < DTB_MAGIC_SIZE
+    {
+        return Err(hverror!(Cause::DeviceTreeTooLarge, "capsule {} tree is {} bytes, slot is {} bytes", cid, blob.len(), DTB_SLOT_SIZE));
+    }
+
+    /* invalidate the copy in the slot before overwriting it, and only make the new
+       copy valid, by writing its magic number, once the rest of it is in place */
+    let offset = (area_base - ram.base()) + (slot * DTB_SLOT_SIZE);
+    let target = &mut ram.as_u8_slice()[offset..offset + blob.len()];
+    target[..DTB_MAGIC_SIZE].copy_from_slice(&[0; DTB_MAGIC_SIZE]);
+    fence(Ordering::SeqCst);
+    target[DTB_MAGIC_SIZE..].copy_from_slice(&blob[DTB_MAGIC_SIZE..]);
+    fence(Ordering::SeqCst);
+    target[..DTB_MAGIC_SIZE].copy_from_slice(&blob[..DTB_MAGIC_SIZE]);
+    Ok(())
+}
+
+/* create the node describing reserved parts of the capsule's memory, if it's not already there */
+fn prepare_reserved_memory(tree: &mut DeviceTree)
+{
+    let node = String::from("/reserved-memory");
+    tree.edit_property(&node, &String::from("#address-cells"), DeviceTreeProperty::UnsignedInt32(2));
+    tree.edit_property(&node, &String::from("#size-cells"), DeviceTreeProperty::UnsignedInt32(2));
+    tree.edit_property(&node, &String::from("ranges"), DeviceTreeProperty::Empty);
+}
+
+/* stop the capsule from using the area that holds its device tree, and tell it which
+   virtual interrupt signals that the tree has changed and which copy of the tree this is */
+fn add_reserved_area(tree: &mut DeviceTree, base: PhysMemBase, generation: usize)
+{
+    prepare_reserved_memory(tree);
+
+    let area = format!("/reserved-memory/diosix-dtb@{:x}", base);
+    tree.edit_property(&area, &String::from("reg"),
+        DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((base as u64, DTB_AREA_SIZE as u64))));
+    tree.edit_property(&area, &String::from("no-map"), DeviceTreeProperty::Empty);
+
+    tree.edit_property(&String::from("/chosen"), &String::from("diosix,dt-changed-irq"),
+        DeviceTreeProperty::UnsignedInt32(VIRQ_DEVICE_TREE_CHANGED as u32));
+    tree.edit_property(&String::from("/chosen"), &String::from("diosix,dt-generation"),
+        DeviceTreeProperty::UnsignedInt32(generation as u32));
+}
+
+/* tell the capsule it's running on diosix, and which version of the hypervisor and its ABI */
+fn add_hypervisor_node(cid: CapsuleID, tree: &mut DeviceTree)
+{
+    let node = String::from("/hypervisor");
+    tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from(abi::HYPERVISOR_COMPATIBLE)));
+    tree.edit_property(&node, &String::from("version"), DeviceTreeProperty::Text(String::from(env!("CARGO_PKG_VERSION"))));
+    tree.edit_property(&node, &String::from("diosix,abi-min"), DeviceTreeProperty::UnsignedInt32(abi::ABI_VERSION_MIN as u32));
+    tree.edit_property(&node, &String::from("diosix,abi-max"), DeviceTreeProperty::UnsignedInt32(abi::ABI_VERSION_MAX as u32));
+    tree.edit_property(&node, &String::from("diosix,service-failover-irq"),
+        DeviceTreeProperty::UnsignedInt32(failover::VIRQ_SERVICE_FAILOVER as u32));
+    tree.edit_property(&node, &String::from("diosix,host-reset-irq"),
+        DeviceTreeProperty::UnsignedInt32(power::VIRQ_HOST_RESET as u32));
+    tree.edit_property(&node, &String::from("diosix,stream-irq"),
+        DeviceTreeProperty::UnsignedInt32(stream::VIRQ_STREAM as u32));
+    tree.edit_property(&node, &String::from("diosix,memory-pressure-irq"),
+        DeviceTreeProperty::UnsignedInt32(pressure::VIRQ_MEMORY_PRESSURE as u32));
+    tree.edit_property(&node, &String::from("diosix,grant-revoked-irq"),
+        DeviceTreeProperty::UnsignedInt32(grant::VIRQ_GRANT_REVOKED as u32));
+    tree.edit_property(&node, &String::from("diosix,button-irq"),
+        DeviceTreeProperty::UnsignedInt32(button::VIRQ_BUTTON as u32));
+
+    /* describe the time service so guests know whether to ask it for the wall-clock time */
+    let node = String::from("/hypervisor/clock");
+    tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("diosix,clock")));
+    tree.edit_property(&node, &String::from("diosix,wall-clock"), DeviceTreeProperty::UnsignedInt32(clock::has_wall_clock(cid) as u32));
+}
+
+/* give the capsule its unique ID and, if the host has a hardware RNG, a fresh seed for its RNG.
+   both are stored as runs of big-endian bytes, so the 64-bit pairs here are simply a way to write them out */
+fn add_identity(cid: CapsuleID, tree: &mut DeviceTree)
+{
+    let node = String::from("/chosen");
+    let id = identity::get_id(cid);
+    tree.edit_property(&node, &String::from("diosix,capsule-uuid"),
+        DeviceTreeProperty::MultipleUnsignedInt64_64(vec!(((id >> 64) as u64, id as u64))));
+
+    if let Some(seed) = identity::new_seed()
+    {
+        tree.edit_property(&node, &String::from("rng-seed"),
+            DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((seed[0], seed[1]), (seed[2], seed[3]))));
+    }
+}
+
+/* describe any memory granted to the capsule on top of its main RAM. memory for DMA is
+   reserved as a shared DMA pool for the guest's drivers rather than described as general RAM */
+fn add_extra_memory(cid: CapsuleID, tree: &mut DeviceTree) -> Result<(), Cause>
+{
+    for mapping in capsule::get_memory_mappings(cid)?.iter().skip(1)
+    {
+        if let Some(region) = mapping.get_physical()
+        {
+            if physmem::is_dma_region(&region) == true
+            {
+                prepare_reserved_memory(tree);
+                let node = format!("/reserved-memory/diosix-dma@{:x}", region.base());
+                tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("shared-dma-pool")));
+                tree.edit_property(&node, &String::from("reg"),
+                    DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((region.base() as u64, region.size() as u64))));
+                tree.edit_property(&node, &String::from("no-map"), DeviceTreeProperty::Empty);
+                continue;
+            }
+
+            let node = format!("/memory@{:x}", region.base());
+            tree.edit_property(&node, &String::from("device_type"), DeviceTreeProperty::Text(String::from("memory")));
+            tree.edit_property(&node, &String::from("reg"),
+                DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((region.base() as u64, region.size() as u64))));
+        }
+    }
+
+    Ok(())
+}
+
+/* describe the virtual serial links the capsule is attached to */
+fn add_serial_links(cid: CapsuleID, tree: &mut DeviceTree)
+{
+    for link in seriallink::get_links(cid)
+    {
+        let node = format!("/diosix-serial-link@{}", link);
+        tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("diosix,serial-link")));
+        tree.edit_property(&node, &String::from("diosix,link"), DeviceTreeProperty::UnsignedInt32(link as u32));
+        tree.edit_property(&node, &String::from("interrupts"),
+            DeviceTreeProperty::UnsignedInt32(seriallink::VIRQ_SERIAL_LINK_DATA as u32));
+    }
+}
+
+/* describe the GPIO lines given to the capsule, if any, which it drives using hypercalls */
+fn add_gpio_lines(cid: CapsuleID, tree: &mut DeviceTree)
+{
+    let count = gpio::count(cid);
+    if count > 0
+    {
+        let node = String::from("/hypervisor/gpio");
+        tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("diosix,gpio")));
+        tree.edit_property(&node, &String::from("ngpios"), DeviceTreeProperty::UnsignedInt32(count as u32));
+    }
+}
+
+/* describe each emulated device given to the capsule by a device model, including its real-time clock */
+fn add_device_models(cid: CapsuleID, tree: &mut DeviceTree)
+{
+    for window in devmodel::get_windows(cid)
+    {
+        let node = format!("/soc/diosix-device@{:x}", window.base);
+        tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(window.compatible));
+        tree.edit_property(&node, &String::from("reg"),
+            DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((window.base as u64, window.size as u64))));
+
+        /* interrupts are delivered by the hypervisor, as they are for passed-through devices */
+        if let Some(irq) = window.irq
+        {
+            tree.edit_property(&node, &String::from("interrupts"), DeviceTreeProperty::UnsignedInt32(irq as u32));
+        }
+        if let Some(entries) = window.queue_max
+        {
+            tree.edit_property(&node, &String::from("diosix,queue-size-max"), DeviceTreeProperty::UnsignedInt32(entries as u32));
+        }
+    }
+}
+
+/* phandles assigned by the hypervisor start here to avoid colliding with the platform's */
+const PHANDLE_CPU_BASE: u32 = 0xd1000000;
+const PHANDLE_CACHE_BASE: u32 = 0xd1100000;
+
+/* describe how the capsule's virtual cores are grouped into clusters that share a cache.
+   mirror the host's cluster size so that the guest's scheduler makes decisions that
+   match the physical cores its virtual cores are likely to run on. single-core capsules
+   and hosts that don't describe their topology are left alone */
+fn add_cpu_topology(cid: CapsuleID, tree: &mut DeviceTree) -> Result<(), Cause>
+{
+    let vcores = capsule::get_max_vcores(cid)?;
+    let cluster_size = match hardware::get_cpu_cluster_size()
+    {
+        Some(size) if size > 0 && vcores > 1 => size,
+        _ => return Ok(())
+    };
+
+    for vcore in 0..vcores
+    {
+        let cluster = vcore / cluster_size;
+        let cpu_node = format!("/cpus/cpu@{}", vcore);
+        let cpu_phandle = PHANDLE_CPU_BASE + vcore as u32;
+        let cache_phandle = PHANDLE_CACHE_BASE + cluster as u32;
+
+        /* link each vcore to its cluster's shared cache */
+        tree.edit_property(&cpu_node, &String::from("phandle"), DeviceTreeProperty::UnsignedInt32(cpu_phandle));
+        tree.edit_property(&cpu_node, &String::from("next-level-cache"), DeviceTreeProperty::UnsignedInt32(cache_phandle));
+
+        /* and place the vcore in the cpu-map */
+        let map_node = format!("/cpus/cpu-map/cluster{}/core{}", cluster, vcore % cluster_size);
+        tree.edit_property(&map_node, &String::from("cpu"), DeviceTreeProperty::UnsignedInt32(cpu_phandle));
+
+        /* describe the cluster's shared cache once, when its first vcore is seen */
+        if vcore % cluster_size == 0
+        {
+            let cache_node = format!("/cpus/l2-cache{}", cluster);
+            tree.edit_property(&cache_node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("cache")));
+            tree.edit_property(&cache_node, &String::from("cache-level"), DeviceTreeProperty::UnsignedInt32(2));
+            tree.edit_property(&cache_node, &String::from("cache-unified"), DeviceTreeProperty::Empty);
+            tree.edit_property(&cache_node, &String::from("phandle"), DeviceTreeProperty::UnsignedInt32(cache_phandle));
+        }
+    }
+
+    Ok(())
+}
+
+/* devices are described under /soc with two cells for each address and size. make sure the
+   platform-generated tree's /soc bus agrees, adding the bus if it's missing, rather than
+   write reg properties that the guest would misread
+   => blob = platform-generated device tree blob
+      tree = editable tree made from the blob
+   <= Ok for success, or an error code if /soc uses different cell counts */
+fn prepare_soc(blob: &Vec<u8>, tree: &mut DeviceTree) -> Result<(), Cause>
+{
+    let fdt = Fdt::new(blob.as_slice())?;
+    match fdt.find("/soc")
+    {
+        Some(soc) => match (soc.property_u32("#address-cells"), soc.property_u32("#size-cells"))
+        {
+            (Some(2), Some(2)) => Ok(()),
+            (address, size) => Err(hverror!(Cause::DeviceTreeBad, "/soc has {:?} address and {:?} size cells, not 2 and 2", address, size))
+        },
+        None =>
+        {
+            let node = String::from("/soc");
+            tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(String::from("simple-bus")));
+            tree.edit_property(&node, &String::from("#address-cells"), DeviceTreeProperty::UnsignedInt32(2));
+            tree.edit_property(&node, &String::from("#size-cells"), DeviceTreeProperty::UnsignedInt32(2));
+            tree.edit_property(&node, &String::from("ranges"), DeviceTreeProperty::Empty);
+            Ok(())
+        }
+    }
+}
+
+/* describe each physical device passed through to the capsule, with the host's compatible strings */
+fn add_passthrough_devices(cid: CapsuleID, blob: &Vec<u8>, tree: &mut DeviceTree) -> Result<(), Cause>
+{
+    let devices = passthrough::get_devices(cid);
+    if devices.len() > 0
+    {
+        prepare_soc(blob, tree)?;
+    }
+
+    for device in devices
+    {
+        let name = match device.get_type()
+        {
+            DeviceType::SerialPort => "serial"
+        };
+
+        /* a text property's string is stored with a zero byte after it, so the host's
+           zero-separated list, less its final zero, is written out as the same list */
+        let mut compatible = device.compatible().clone();
+        compatible.pop();
+
+        let node = format!("/soc/{}@{:x}", name, device.base());
+        tree.edit_property(&node, &String::from("compatible"), DeviceTreeProperty::Text(compatible));
+        tree.edit_property(&node, &String::from("reg"),
+            DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((device.base() as u64, device.size() as u64))));
+
+        /* describe the register layout and clock the guest's driver can't find out for itself */
+        for (name, value) in [ ("reg-shift", device.reg_shift()), ("reg-io-width", device.reg_io_width()),
+                               ("clock-frequency", device.clock()) ].iter()
+        {
+            if let Some(value) = value
+            {
+                tree.edit_property(&node, &String::from(*name), DeviceTreeProperty::UnsignedInt32(*value));
+            }
+        }
+
+        /* interrupts are delivered by the hypervisor rather than an emulated interrupt controller */
+        if let Some(irq) = device.irq()
+        {
+            tree.edit_property(&node, &String::from("diosix,passthrough-irq"), DeviceTreeProperty::UnsignedInt32(irq as u32));
+        }
+    }
+    Ok(())
+}
@@ -5,17 +5,33 @@
  * See LICENSE for usage and copying.
  */
 
-use platform::physmem::PhysMemBase;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize, AccessPermissions};
 use platform::virtmem::VirtMemBase;
 use super::physmem::Region;
 use super::error::Cause;
 
+/* most parts of a mapping that can have their own access permissions. each takes a protection window */
+pub const SEGMENTS_MAX: usize = 2;
+
+/* part of a mapping's physical region with its own access permissions, such as a kernel's code */
+#[derive(Clone, Copy)]
+struct Segment
+{
+    offset: PhysMemSize, /* from the start of the physical region */
+    size: PhysMemSize,
+    permissions: AccessPermissions
+}
+
 /* map a capsule's virtual memory to a host physical memory region */
 #[derive(Clone, Copy)]
 pub struct Mapping
 {
     virtual_base: Option<VirtMemBase>,
-    physical_region: Option<Region>
+    physical_region: Option<Region>,
+    segments: [Option<Segment>; SEGMENTS_MAX], /* if any are set, the rest of the region is read-write but not executable */
+    retained: bool, /* true if the region isn't freed when the capsule is torn down */
+    scattered: bool /* true if the region is part of the capsule's RAM allocated apart from the rest */
 }
 
 impl Mapping
@@ -26,10 +42,68 @@ impl Mapping
         Mapping
         {
             virtual_base: None,
-            physical_region: None
+            physical_region: None,
+            segments: [None; SEGMENTS_MAX],
+            retained: false,
+            scattered: false
+        }
+    }
+
+    /* give parts of the physical region their own access permissions, replacing any given before.
+       an empty list makes the whole region readable, writeable, and executable again
+       => segments = list of offset into the region, size in bytes, and permissions of each part
+       <= Ok for success, or an error code if there are too many parts or they don't fit in the region */
+    pub fn set_segments(&mut self, segments: &[(PhysMemSize, PhysMemSize, AccessPermissions)]) -> Result<(), Cause>
+    {
+        if segments.len() > SEGMENTS_MAX
+        {
+            return Err(Cause::VirtMemTooManySegments);
+        }
+
+        if let Some(region) = self.physical_region
+        {
+            if segments.iter().any(|(offset, size, _)| offset.checked_add(*size).map_or(true, |end| end > region.size()))
+            {
+                return Err(Cause::VirtMemBadSegment);
+            }
         }
+
+        self.segments = [None; SEGMENTS_MAX];
+        for (slot, (offset, size, permissions)) in self.segments.iter_mut().zip(segments.iter())
+        {
+            *slot = Some(Segment { offset: *offset, size: *size, permissions: *permissions });
+        }
+        Ok(())
     }
 
+    /* <= list of offset into the region, size in bytes, and permissions of each part of the
+       physical region with its own access permissions, so they can be copied to another mapping */
+    pub fn get_segments(&self) -> Vec<(PhysMemSize, PhysMemSize, AccessPermissions)>
+    {
+        self.segments.iter().flatten().map(|s| (s.offset, s.size, s.permissions)).collect()
+    }
+
+    /* <= list of physical base address, size in bytes, and permissions of each part of the
+       physical region with its own access permissions. empty if there are none or the region isn't set */
+    pub fn get_physical_segments(&self) -> Vec<(PhysMemBase, PhysMemSize, AccessPermissions)>
+    {
+        match self.physical_region
+        {
+            Some(region) => self.segments.iter().flatten().map(|s| (region.base() + s.offset, s.size, s.permissions)).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /* mark the physical region as owned elsewhere, eg: shared with the capsule by another,
+       so that it isn't freed when the capsule is torn down */
+    pub fn set_retained(&mut self) { self.retained = true; }
+    pub fn is_retained(&self) -> bool { self.retained }
+
+    /* mark the physical region as part of the capsule's RAM that couldn't be allocated in one
+       piece, so that it's treated like the rest of its RAM rather than like a granted buffer */
+    pub fn set_scattered(&mut self) { self.scattered = true; }
+    pub fn is_scattered(&self) -> bool { self.scattered }
+
     /* define the virtual base address and corresponding physical RAM region */
     pub fn set_virtual(&mut self, vbase: VirtMemBase) { self.virtual_base = Some(vbase); }
     pub fn set_physical(&mut self, region: Region) { self.physical_region = Some(region); }
@@ -0,0 +1,123 @@
+/* diosix virtio queues for device models
+ *
+ * Each virtio device model, whether network, block, console, or entropy,
+ * talks to its capsule through one or more virtqueues. Rather than each
+ * model parsing the rings itself, they all use the split ring engine in
+ * the hvalgo crate, which is unit tested on the host. This binds that
+ * engine to a capsule: every buffer the guest describes is checked to lie
+ * within the capsule's memory, and buffers the device will write to must
+ * not be in the capsule's read-only code.
+ *
+ * A model creates a VirtQueue once the guest has told it where the
+ * queue's areas are, pops chains of buffers as it has room for them, and
+ * pushes each one back when it's done, interrupting the guest if push()
+ * says so. A model that's busy can disable notifications and leave chains
+ * waiting, then re-enable notifications and take any that arrived while
+ * it wasn't listening. See src/hvalgo/src/virtqueue.rs for the details.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use hvalgo::virtqueue::{GuestMemory, SplitQueue};
+use super::error::Cause;
+use super::capsule::CapsuleID;
+use super::hcargs::{self, Access};
+
+pub use hvalgo::virtqueue::{Buffer, Chain, Stats};
+
+/* a capsule's memory, as seen by its device models */
+struct CapsuleMemory
+{
+    cid: CapsuleID
+}
+
+impl GuestMemory for CapsuleMemory
+{
+    fn translate(&self, addr: u64, size: usize, write: bool) -> Option<usize>
+    {
+        let access = match write
+        {
+            true => Access::Write,
+            false => Access::Read
+        };
+
+        match hcargs::buffer(self.cid, addr as usize, size, usize::MAX, access)
+        {
+            Ok(base) => Some(base),
+            Err(_) =>
+            {
+                super::error::forget_context();
+                None
+            }
+        }
+    }
+}
+
+/* a virtqueue shared with a capsule */
+pub struct VirtQueue
+{
+    memory: CapsuleMemory,
+    queue: SplitQueue
+}
+
+impl VirtQueue
+{
+    /* set up a queue from the areas a capsule has given a device model
+       => cid = capsule that owns the queue
+          size = number of entries in the queue, a power of two
+          desc, avail, used = addresses in the capsule of the descriptor table, available ring, and used ring
+          event_idx = true if VIRTIO_F_EVENT_IDX was negotiated
+       <= queue, or an error code if the size or any area is bad */
+    pub fn new(cid: CapsuleID, size: u16, desc: u64, avail: u64, used: u64, event_idx: bool) -> Result<VirtQueue, Cause>
+    {
+        let memory = CapsuleMemory { cid };
+        match SplitQueue::new(&memory, size, desc, avail, used, event_idx)
+        {
+            Ok(queue) => Ok(VirtQueue { memory, queue }),
+            Err(e) => Err(hverror!(Cause::from(e), "capsule {} virtqueue size {} at 0x{:x} 0x{:x} 0x{:x}", cid, size, desc, avail, used))
+        }
+    }
+
+    /* take the next chain of buffers the capsule has made available
+       <= Some chain, None if there are none waiting, or an error code if the next one is bad.
+          a bad chain must still be returned with push() and zero bytes written */
+    pub fn pop(&mut self) -> Result<Option<Chain>, Cause>
+    {
+        match self.queue.pop(&self.memory)
+        {
+            Ok(chain) => Ok(chain),
+            Err(e) =>
+            {
+                hvdebug!("Capsule {} made a bad virtqueue chain available: {:?}", self.memory.cid, e);
+                Err(Cause::from(e))
+            }
+        }
+    }
+
+    /* hand a chain back to the capsule
+       => head = index of the chain's first descriptor
+          written = number of bytes written to the chain's writable buffers
+       <= true if the capsule should be interrupted */
+    pub fn push(&mut self, head: u16, written: u32) -> bool
+    {
+        self.queue.push(head, written)
+    }
+
+    /* ask the capsule not to notify the device model of new chains while it's busy */
+    pub fn disable_notifications(&mut self)
+    {
+        self.queue.disable_notifications();
+    }
+
+    /* ask the capsule to notify the device model of new chains again
+       <= true if chains arrived in the meantime, which must be taken now, or an error code */
+    pub fn enable_notifications(&mut self) -> Result<bool, Cause>
+    {
+        Ok(self.queue.enable_notifications()?)
+    }
+
+    /* <= counts of the queue's activity so far */
+    pub fn stats(&self) -> Stats { self.queue.stats() }
+}
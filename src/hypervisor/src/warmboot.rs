@@ -0,0 +1,100 @@
+/* diosix warm reboot
+ *
+ * Restart the system's capsules without going back through the
+ * firmware, which can be slow on some development boards. A warm
+ * reboot is requested by a capsule with the manage_capsules property
+ * via a hypercall, or by pressing ctrl-r on the debug port when no
+ * console service is running.
+ *
+ * Every capsule is marked as dying and torn down as its virtual cores
+ * are next scheduled. Once all of them are gone, the scheduler's
+ * workload counts are reset, each physical core discards its error
+ * details and returns its unused heap memory, and the capsules in the
+ * hypervisor's bundled DMFS image are unpacked and started afresh,
+ * just as they were at boot. The DMFS image and the host hardware's
+ * state are kept as they are. As devices released by a capsule are
+ * held back until the host is fully rebooted, capsules that are passed
+ * physical devices will fail to be recreated.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::error::{self, Cause};
+use super::capsule;
+use super::manifest;
+use super::failover;
+use super::message;
+use super::scheduler;
+
+/* key on the debug port that requests a warm reboot: ctrl-r */
+pub const WARM_REBOOT_HOTKEY: char = '\x12';
+
+/* is a warm reboot under way? */
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/* start a warm reboot by killing every capsule
+   <= Ok for success, or an error code if a warm reboot is already under way */
+pub fn request() -> Result<(), Cause>
+{
+    if REQUESTED.swap(true, Ordering::SeqCst) == true
+    {
+        return Err(Cause::WarmRebootInProgress);
+    }
+
+    hvalert!("Warm reboot requested: stopping {} capsule(s)", capsule::kill_all());
+    Ok(())
+}
+
+/* once all capsules are gone, reset the physical cores and recreate the capsules from the DMFS image.
+   call this regularly from the boot core's housekeeping */
+pub fn housekeeper()
+{
+    if REQUESTED.load(Ordering::SeqCst) == false
+    {
+        return;
+    }
+
+    /* capsules that were restarting when the reboot was requested can only be killed once they're back */
+    if capsule::kill_all() > 0
+    {
+        return;
+    }
+
+    hvalert!("Warm reboot: all capsules stopped, reinitializing");
+
+    /* standbys will be declared again by the manifest */
+    failover::reset();
+
+    /* the recreated capsules' vcores should be spread across the system from scratch */
+    scheduler::reset_workload();
+
+    /* have every physical core, including this one, reset its own state */
+    match message::Message::new(message::Recipient::send_to_all(), message::MessageContent::WarmReset)
+    {
+        Ok(m) => if let Err(_e) = message::send(m)
+        {
+            hvalert!("Warm reboot: failed to reset physical CPU cores: {}", error::report(&_e));
+        },
+        Err(_e) => hvalert!("Warm reboot: failed to reset physical CPU cores: {}", error::report(&_e))
+    }
+
+    if let Err(_e) = manifest::unpack_at_boot()
+    {
+        hvalert!("Warm reboot: failed to unpack capsules from the DMFS image: {}", error::report(&_e));
+    }
+
+    /* the other physical cores are back in their scheduling loops, so load the capsules here */
+    manifest::load_at_boot();
+
+    REQUESTED.store(false, Ordering::SeqCst);
+}
+
+/* reset this physical core's state ahead of the capsules being recreated. call when asked to by the boot core */
+pub fn reset_this_core()
+{
+    error::forget_context();
+    heaphousekeeper!();
+}
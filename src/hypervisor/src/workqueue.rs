@@ -0,0 +1,154 @@
+/* diosix per-physical-core deferred work queues
+ *
+ * Some of the hypervisor's work takes too long to do in one go from an
+ * interrupt or hypercall handler without holding up the capsule that
+ * caused it, or the capsule that happens to be running: scrubbing the
+ * RAM of a destroyed capsule, for example, or in future, decompressing
+ * images and taking snapshots. That work is queued instead, and carried
+ * out a step at a time.
+ *
+ * Each physical CPU core has its own queue of jobs, and runs them itself,
+ * so queuing and running jobs needs no global lock. A job is a function
+ * that does a bounded amount of work each time it's called, and says
+ * whether it's done or wants calling again. Jobs are either high or low
+ * priority: high priority jobs are run before any low priority ones, and
+ * otherwise jobs take turns, a step each, in the order they were queued.
+ *
+ * A core runs one step each time it carries out housekeeping, which
+ * happens at least once per timeslice, and runs steps back to back while
+ * it has no virtual core to run. A job can be given a function to call
+ * with its result once it's done.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use super::error::{self, Cause};
+use super::pcore::PhysicalCore;
+
+/* how urgent a job is */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority
+{
+    High,   /* such as work others are waiting on */
+    Low     /* such as tidying up */
+}
+
+/* what a job says after each step */
+pub enum Progress
+{
+    Again,                      /* call again to do more */
+    Done(Result<(), Cause>)     /* finished, successfully or not */
+}
+
+/* a step of a job, and what to call once it's done */
+pub type Step = Box<dyn FnMut() -> Progress + Send>;
+pub type Completion = Box<dyn FnOnce(Result<(), Cause>) + Send>;
+
+struct Job
+{
+    name: &'static str, /* for reporting problems */
+    step: Step,
+    done: Option<Completion>
+}
+
+pub struct WorkQueue
+{
+    high: VecDeque<Job>,
+    low: VecDeque<Job>
+}
+
+impl WorkQueue
+{
+    pub fn new() -> WorkQueue
+    {
+        WorkQueue
+        {
+            high: VecDeque::new(),
+            low: VecDeque::new()
+        }
+    }
+
+    /* take the next job to run a step of */
+    fn next(&mut self) -> Option<(Job, Priority)>
+    {
+        match self.high.pop_front()
+        {
+            Some(job) => Some((job, Priority::High)),
+            None => self.low.pop_front().map(|job| (job, Priority::Low))
+        }
+    }
+
+    /* put a job at the back of its queue */
+    fn push(&mut self, job: Job, priority: Priority)
+    {
+        match priority
+        {
+            Priority::High => self.high.push_back(job),
+            Priority::Low => self.low.push_back(job)
+        }
+    }
+
+    /* <= number of jobs waiting */
+    fn len(&self) -> usize
+    {
+        self.high.len() + self.low.len()
+    }
+}
+
+/* queue a job on this physical core
+   => priority = how urgent the job is
+      name = short description of the job, for reporting problems
+      step = function to call to do some of the job, until it says it's done
+      done = function to call with the job's result once it's done, or None */
+pub fn submit(priority: Priority, name: &'static str, step: Step, done: Option<Completion>)
+{
+    PhysicalCore::this().get_work_queue().push(Job { name, step, done }, priority);
+}
+
+/* run one step of the most urgent job on this physical core's queue.
+   the job is taken off the queue while it runs, so it can queue jobs of its own
+   <= true if there's more work waiting, or false if the queue is empty */
+pub fn run_one() -> bool
+{
+    let (mut job, priority) = match PhysicalCore::this().get_work_queue().next()
+    {
+        Some(next) => next,
+        None => return false
+    };
+
+    match (job.step)()
+    {
+        Progress::Again => PhysicalCore::this().get_work_queue().push(job, priority),
+        Progress::Done(result) =>
+        {
+            if let Err(ref _e) = result
+            {
+                hvalert!("Deferred job '{}' failed: {}", job.name, error::report(_e));
+            }
+
+            if let Some(done) = job.done.take()
+            {
+                done(result);
+            }
+        }
+    }
+
+    pending() > 0
+}
+
+/* run every job on this physical core's queue to completion, such as when the
+   memory they're freeing up is needed straight away */
+pub fn finish()
+{
+    while run_one() == true {}
+}
+
+/* <= number of jobs waiting on this physical core */
+pub fn pending() -> usize
+{
+    PhysicalCore::this().get_work_queue().len()
+}
@@ -0,0 +1,256 @@
+/* diosix capsule working set size estimation
+ *
+ * Capsules granted the wss_sample=N property are profiled to estimate
+ * how much of their RAM they're actively using, which can inform
+ * ballooning and placement decisions. Each sampling period, N pages of
+ * the capsule's main RAM are picked at random and access to them is
+ * revoked using spare physical memory protection windows. The first
+ * access to a revoked page faults into the hypervisor, which records
+ * the page as touched, restores access, and retries the access.
+ *
+ * At the end of each period, the fraction of sampled pages that were
+ * touched, scaled up to the size of the capsule's RAM, is folded into
+ * a running estimate of the capsule's working set. Overhead is bounded
+ * by N, which is capped at WSS_SAMPLE_MAX pages per period, as each
+ * sampled page costs at most one fault per physical core per period.
+ * Fewer pages are sampled if there aren't enough windows left over once
+ * the capsule's other memory and devices have been given theirs: the
+ * pages that can't be revoked are dropped from the period's sample, so
+ * they don't count as untouched.
+ *
+ * Pages are sampled once per scheduler housekeeping period.
+ *
+ * (c) Chris Williams, 2021.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use super::lock::Mutex;
+use hashbrown::hash_map::HashMap;
+use alloc::vec::Vec;
+use platform::physmem::{PhysMemBase, PhysMemSize};
+use super::error::Cause;
+use super::capsule::{self, CapsuleID};
+use super::pcore;
+use super::machine;
+
+/* granularity of sampling */
+const WSS_PAGE_SIZE: PhysMemSize = 4096;
+
+/* most pages that can be sampled per capsule per period. each needs its own protection window */
+pub const WSS_SAMPLE_MAX: usize = 4;
+
+/* how a capsule's memory accesses are being sampled */
+struct Profile
+{
+    pages: usize,               /* pages to sample per period */
+    armed: Vec<PhysMemBase>,    /* base addresses of pages sampled this period */
+    touched: Vec<PhysMemBase>,  /* sampled pages accessed this period */
+    estimate: Option<PhysMemSize>, /* smoothed working set size in bytes, or None if not yet known */
+    seed: u64                   /* state of the pseudo-random page picker */
+}
+
+impl Profile
+{
+    /* return the next pseudo-random number from this profile's xorshift generator */
+    fn next_random(&mut self) -> u64
+    {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+}
+
+lazy_static!
+{
+    /* capsules being profiled */
+    static ref PROFILES: Mutex<HashMap<CapsuleID, Profile>> = Mutex::new("working set profiles", HashMap::new());
+}
+
+/* start profiling a capsule's memory accesses
+   => cid = capsule to profile
+      pages = number of pages to sample per period, capped at WSS_SAMPLE_MAX */
+pub fn start(cid: CapsuleID, pages: usize)
+{
+    let pages = core::cmp::min(pages, WSS_SAMPLE_MAX);
+    if pages == 0
+    {
+        return;
+    }
+
+    PROFILES.lock().insert(cid, Profile
+    {
+        pages,
+        armed: Vec::new(),
+        touched: Vec::new(),
+        estimate: None,
+        seed: 0x9e3779b97f4a7c15 ^ (cid as u64 + 1)
+    });
+}
+
+/* stop profiling a capsule. call when the capsule is destroyed */
+pub fn forget(cid: CapsuleID)
+{
+    PROFILES.lock().remove(&cid);
+}
+
+/* return the estimated working set size of a capsule, in bytes.
+   *** the currently running capsule must have the manage_capsules property ***
+   <= estimate, or an error code if the capsule isn't profiled or no estimate is ready yet */
+pub fn estimate(cid: CapsuleID) -> Result<PhysMemSize, Cause>
+{
+    capsule::current_has_property(capsule::CapsuleProperty::ManageCapsules)?;
+    match PROFILES.lock().get(&cid)
+    {
+        Some(profile) => match profile.estimate
+        {
+            Some(bytes) => Ok(bytes),
+            None => Err(Cause::WSSNotReady)
+        },
+        None => Err(Cause::WSSNotProfiled)
+    }
+}
+
+/* revoke access to the given capsule's sampled pages that haven't yet been touched.
+   call this when switching to the capsule, after its other protections are in place.
+   sampled pages there aren't enough windows left for are dropped from the sample
+   => cid = capsule to enforce
+      window = first free protection window to use */
+pub fn enforce(cid: CapsuleID, mut window: usize)
+{
+    if let Some(profile) = PROFILES.lock().get_mut(&cid)
+    {
+        let Profile { armed, touched, .. } = profile;
+        let mut available = machine::PROTECTION_WINDOWS.saturating_sub(window);
+        armed.retain(|page| match (touched.contains(page), available)
+        {
+            (true, _) => true,
+            (false, 0) => false,
+            (false, _) =>
+            {
+                available = available - 1;
+                true
+            }
+        });
+
+        for &page in armed.iter().filter(|page| touched.contains(page) == false)
+        {
+            machine::revoke_window(window, page, page + WSS_PAGE_SIZE);
+            window = window + 1;
+        }
+    }
+
+    /* close any windows left open by the previous capsule */
+    machine::clear_windows_from(window);
+}
+
+/* handle a memory access fault raised by the running capsule
+   => addr = physical address the capsule tried to access
+   <= true if the fault was caused by sampling and the access should be retried,
+      or false if it's a genuine fault */
+pub fn access_fault(addr: PhysMemBase) -> bool
+{
+    let cid = match pcore::PhysicalCore::get_capsule_id()
+    {
+        Some(c) => c,
+        None => return false
+    };
+
+    /* don't hold the profiles lock while looking up the capsule's RAM */
+    let page = addr & !(WSS_PAGE_SIZE - 1);
+    let in_ram = in_main_ram(cid, page);
+
+    {
+        let mut profiles = PROFILES.lock();
+        let profile = match profiles.get_mut(&cid)
+        {
+            Some(p) => p,
+            None => return false
+        };
+
+        if profile.touched.contains(&page) == false
+        {
+            /* this core may still be enforcing a page sampled in an earlier period.
+               as long as the page is in the capsule's own RAM, let it through */
+            if profile.armed.contains(&page) == false && in_ram == false
+            {
+                return false;
+            }
+            profile.touched.push(page);
+        }
+    }
+
+    /* drop the revoked window and retry the access */
+    capsule::enforce(cid);
+    true
+}
+
+/* return true if the given address lies within the capsule's main RAM */
+fn in_main_ram(cid: CapsuleID, addr: PhysMemBase) -> bool
+{
+    match main_ram(cid)
+    {
+        Some((base, size)) => addr >= base && addr < base + size,
+        None => false
+    }
+}
+
+/* return the base and size of a capsule's main RAM, or None if it has none */
+fn main_ram(cid: CapsuleID) -> Option<(PhysMemBase, PhysMemSize)>
+{
+    match capsule::get_memory_mappings(cid)
+    {
+        Ok(mappings) => mappings.first().and_then(|m| m.get_physical()).map(|r| (r.base(), r.size())),
+        Err(_) => None
+    }
+}
+
+/* fold the last period's samples into each profiled capsule's estimate and pick fresh pages to sample.
+   call this once per sampling period */
+pub fn housekeeper()
+{
+    /* don't hold the profiles lock while looking up capsules' RAM */
+    let cids: Vec<CapsuleID> = PROFILES.lock().keys().cloned().collect();
+    for cid in cids
+    {
+        let (base, size) = match main_ram(cid)
+        {
+            Some(ram) => ram,
+            None => continue
+        };
+
+        let mut profiles = PROFILES.lock();
+        let profile = match profiles.get_mut(&cid)
+        {
+            Some(p) => p,
+            None => continue
+        };
+
+        if profile.armed.len() > 0
+        {
+            /* ignore stragglers from earlier periods let through by other cores */
+            let hits = profile.touched.iter().filter(|page| profile.armed.contains(page)).count();
+            let seen = (size / profile.armed.len()) * hits;
+            profile.estimate = Some(match profile.estimate
+            {
+                /* smooth out noise from sampling so few pages */
+                Some(previous) => ((previous / 4) * 3) + (seen / 4),
+                None => seen
+            });
+        }
+
+        profile.armed.clear();
+        profile.touched.clear();
+
+        let nr_pages = size / WSS_PAGE_SIZE;
+        while nr_pages > 0 && profile.armed.len() < core::cmp::min(profile.pages, nr_pages)
+        {
+            let page = base + ((profile.next_random() as usize % nr_pages) * WSS_PAGE_SIZE);
+            if profile.armed.contains(&page) == false
+            {
+                profile.armed.push(page);
+            }
+        }
+    }
+}